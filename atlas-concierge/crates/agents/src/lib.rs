@@ -4,10 +4,10 @@ use std::time::Instant;
 use anyhow::Result;
 use atlas_core::intent::needs_clarification;
 use atlas_core::{
-    build_ops_checklist, build_trip_plan, classify_intent_rules, compose_chat_reply, detect_locale,
-    normalize_text, ChatInput, ConciergeReply, ConversationSession, ConversationTurn, Intent,
-    Locale, OpsChecklist, OpsChecklistType, PolicyEngine, PolicySet, RetrievedChunk,
-    TripPlanRequest, TripPlanResponse,
+    build_ops_checklist, build_trip_plan, classify_intent_rules, compose_chat_reply,
+    contextualize_follow_up, detect_locale, normalize_text, ChatInput, ConciergeReply,
+    ConversationSession, ConversationTurn, Intent, Locale, OpsChecklist, OpsChecklistType,
+    PolicyEngine, PolicySet, RetrievedChunk, TripPlanRequest, TripPlanResponse,
 };
 use atlas_ml::AtlasMlStack;
 use atlas_observability::AppMetrics;
@@ -57,9 +57,10 @@ where
         let normalized = normalize_text(&input.text);
         let explicit_locale = Locale::from_optional_str(input.locale.as_deref());
         let locale = detect_locale(Some(explicit_locale), &normalized);
+        let contextual = contextualize_follow_up(&normalized, &input.context_turns);
 
-        let rule_intent = classify_intent_rules(&normalized);
-        let ml_prediction = self.ml_stack.classifier.predict(&normalized);
+        let rule_intent = classify_intent_rules(&contextual);
+        let ml_prediction = self.ml_stack.classifier.predict(&contextual);
         self.metrics.inc_ml_inference();
 
         let intent = match rule_intent {
@@ -67,11 +68,11 @@ where
             _ => rule_intent,
         };
 
-        let retrieved = self.retriever.search(&normalized, 5);
+        let retrieved = self.retriever.search(&contextual, 5);
         self.metrics.add_retrieval_hits(retrieved.len());
 
         let mut clarifying_questions = Vec::new();
-        if needs_clarification(intent, &normalized) {
+        if needs_clarification(intent, &contextual) {
             clarifying_questions = clarifying_questions_for(intent, locale);
         }
 
@@ -109,6 +110,10 @@ where
 
         if let Some(payload_obj) = reply.json_payload.as_object_mut() {
             payload_obj.insert("session_id".to_string(), serde_json::json!(session_id));
+            payload_obj.insert(
+                "context_turns".to_string(),
+                serde_json::json!(input.context_turns.len()),
+            );
             payload_obj.insert(
                 "classifier".to_string(),
                 serde_json::json!({
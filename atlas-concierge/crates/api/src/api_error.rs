@@ -0,0 +1,112 @@
+//! The error body shared by every handler: `{ "error": <code>, "message": <text>, "details"? }`.
+//! `error` is a stable snake_case code clients can branch on; `message` is human-readable and
+//! may change. Anything else a client needs (subscription state, action telemetry, per-row CSV
+//! errors) goes under `details`.
+
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use serde::Serialize;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ApiError {
+    #[serde(skip)]
+    status: StatusCode,
+    error: String,
+    message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    details: Option<serde_json::Value>,
+}
+
+impl ApiError {
+    pub fn new(status: StatusCode, error: impl Into<String>, message: impl Into<String>) -> Self {
+        Self {
+            status,
+            error: error.into(),
+            message: message.into(),
+            details: None,
+        }
+    }
+
+    pub fn bad_request(error: impl Into<String>, message: impl Into<String>) -> Self {
+        Self::new(StatusCode::BAD_REQUEST, error, message)
+    }
+
+    pub fn unauthorized(error: impl Into<String>, message: impl Into<String>) -> Self {
+        Self::new(StatusCode::UNAUTHORIZED, error, message)
+    }
+
+    /// The usual answer when a route needs a signed-in session and there is none.
+    pub fn not_authenticated() -> Self {
+        Self::unauthorized("not_authenticated", "sign in first")
+    }
+
+    pub fn payment_required(error: impl Into<String>, message: impl Into<String>) -> Self {
+        Self::new(StatusCode::PAYMENT_REQUIRED, error, message)
+    }
+
+    pub fn forbidden(error: impl Into<String>, message: impl Into<String>) -> Self {
+        Self::new(StatusCode::FORBIDDEN, error, message)
+    }
+
+    pub fn not_found(error: impl Into<String>, message: impl Into<String>) -> Self {
+        Self::new(StatusCode::NOT_FOUND, error, message)
+    }
+
+    pub fn too_many_requests(error: impl Into<String>, message: impl Into<String>) -> Self {
+        Self::new(StatusCode::TOO_MANY_REQUESTS, error, message)
+    }
+
+    pub fn internal(error: impl Into<String>, message: impl Into<String>) -> Self {
+        Self::new(StatusCode::INTERNAL_SERVER_ERROR, error, message)
+    }
+
+    pub fn bad_gateway(error: impl Into<String>, message: impl Into<String>) -> Self {
+        Self::new(StatusCode::BAD_GATEWAY, error, message)
+    }
+
+    pub fn service_unavailable(error: impl Into<String>, message: impl Into<String>) -> Self {
+        Self::new(StatusCode::SERVICE_UNAVAILABLE, error, message)
+    }
+
+    /// Attaches structured context. A value that fails to serialize is dropped rather than
+    /// turning the error response itself into a failure.
+    pub fn with_details(mut self, details: impl Serialize) -> Self {
+        self.details = serde_json::to_value(details).ok();
+        self
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        (self.status, Json(self)).into_response()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::body::to_bytes;
+    use axum::http::StatusCode;
+    use axum::response::IntoResponse;
+
+    use super::ApiError;
+
+    #[tokio::test]
+    async fn details_are_only_serialized_when_present() {
+        let plain = ApiError::not_authenticated().into_response();
+        assert_eq!(plain.status(), StatusCode::UNAUTHORIZED);
+        let body = to_bytes(plain.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(
+            serde_json::from_slice::<serde_json::Value>(&body).unwrap(),
+            serde_json::json!({ "error": "not_authenticated", "message": "sign in first" })
+        );
+
+        let detailed = ApiError::bad_request("invalid_note", "title is required")
+            .with_details(serde_json::json!({ "field": "title" }))
+            .into_response();
+        let body = to_bytes(detailed.into_body(), usize::MAX).await.unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(json["details"]["field"], "title");
+        assert_eq!(json.as_object().unwrap().len(), 3);
+    }
+}
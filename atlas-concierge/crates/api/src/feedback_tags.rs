@@ -0,0 +1,147 @@
+use atlas_core::Locale;
+
+use crate::text_match::contains_word_prefix;
+
+/// English needles are always checked; the other lists only for their own locale, or all of
+/// them when the locale is unknown.
+pub struct FeedbackTagRule {
+    pub tag: &'static str,
+    pub en: &'static [&'static str],
+    pub he: &'static [&'static str],
+    pub ar: &'static [&'static str],
+    pub ru: &'static [&'static str],
+    pub fr: &'static [&'static str],
+}
+
+pub static FEEDBACK_TAG_RULES: &[FeedbackTagRule] = &[
+    FeedbackTagRule {
+        tag: "crash",
+        en: &[
+            "crash",
+            "froze",
+            "freez",
+            "force close",
+            "stopped working",
+            "keeps closing",
+        ],
+        he: &["קורס", "קרס", "קריסה", "נתקע"],
+        ar: &["تعطل", "يتعطل", "انهيار", "يتوقف"],
+        ru: &["вылет", "завис", "краш", "падает"],
+        fr: &["plantage", "planté", "se plante", "figé", "bloqué"],
+    },
+    FeedbackTagRule {
+        tag: "slow",
+        en: &[
+            "slow",
+            "laggy",
+            "lagging",
+            "takes forever",
+            "timeout",
+            "timed out",
+        ],
+        he: &["איטי", "איטית", "לוקח הרבה זמן", "טעינה ארוכה"],
+        ar: &["بطيء", "بطء", "يستغرق وقتا"],
+        ru: &["медленн", "тормоз", "долго грузит"],
+        fr: &["lent", "lenteur", "temps de chargement"],
+    },
+    FeedbackTagRule {
+        tag: "billing",
+        en: &[
+            "billing",
+            "billed",
+            "charged",
+            "overcharg",
+            "refund",
+            "invoice",
+            "payment",
+            "subscription",
+        ],
+        he: &["חיוב", "חויבתי", "תשלום", "החזר כספי", "מנוי", "חשבונית"],
+        ar: &["فاتورة", "الدفع", "اشتراك", "استرداد"],
+        ru: &["оплат", "списал", "подписк", "возврат денег"],
+        fr: &["factur", "paiement", "abonnement", "rembours", "débité"],
+    },
+    FeedbackTagRule {
+        tag: "auth",
+        en: &[
+            "login",
+            "log in",
+            "logged out",
+            "sign in",
+            "signin",
+            "password",
+            "passkey",
+            "recovery code",
+        ],
+        he: &["התחברות", "להתחבר", "סיסמה", "סיסמא", "כניסה לחשבון"],
+        ar: &["تسجيل الدخول", "كلمة المرور", "كلمة السر"],
+        ru: &["войти", "вход в аккаунт", "парол", "авториз"],
+        fr: &["connexion", "me connecter", "mot de passe", "identifiant"],
+    },
+];
+
+/// Derived triage tags in `FEEDBACK_TAG_RULES` order, so the same message always yields the
+/// same tags.
+pub fn feedback_tags_for_message(message: &str, locale: Locale) -> Vec<&'static str> {
+    let lower = message.trim().to_lowercase();
+    if lower.is_empty() {
+        return Vec::new();
+    }
+    // English, Russian and French needles must start a word; Hebrew and Arabic attach prefixes
+    // (ה/ו/ב/ל, ال/و) to words, so those stay substring.
+    FEEDBACK_TAG_RULES
+        .iter()
+        .filter(|rule| {
+            let word_prefix = |needles: &[&str]| {
+                needles
+                    .iter()
+                    .any(|needle| contains_word_prefix(lower.as_str(), needle))
+            };
+            let substring = |needles: &[&str]| needles.iter().any(|needle| lower.contains(needle));
+            let any_locale = locale == Locale::Unknown;
+            word_prefix(rule.en)
+                || ((any_locale || locale == Locale::He) && substring(rule.he))
+                || ((any_locale || locale == Locale::Ar) && substring(rule.ar))
+                || ((any_locale || locale == Locale::Ru) && word_prefix(rule.ru))
+                || ((any_locale || locale == Locale::Fr) && word_prefix(rule.fr))
+        })
+        .map(|rule| rule.tag)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::feedback_tags_for_message;
+    use atlas_core::Locale;
+
+    #[test]
+    fn feedback_tags_follow_message_keywords_and_locale() {
+        assert_eq!(
+            feedback_tags_for_message(
+                "The app crashed after login and it was slow before that",
+                Locale::En
+            ),
+            vec!["crash", "slow", "auth"]
+        );
+        assert_eq!(
+            feedback_tags_for_message("חויבתי פעמיים על המנוי", Locale::He),
+            vec!["billing"]
+        );
+        assert_eq!(
+            feedback_tags_for_message(
+                "Impossible de me connecter, mot de passe refusé",
+                Locale::Fr
+            ),
+            vec!["auth"]
+        );
+        assert_eq!(
+            feedback_tags_for_message("Приложение тормозит", Locale::Unknown),
+            vec!["slow"]
+        );
+        // Other locales' lists are skipped once the locale is known.
+        assert!(feedback_tags_for_message("Приложение тормозит", Locale::He).is_empty());
+        assert!(feedback_tags_for_message("Please relog the blogin widget", Locale::En).is_empty());
+        assert!(feedback_tags_for_message("My phone charger icon is tiny", Locale::En).is_empty());
+        assert!(feedback_tags_for_message("", Locale::Unknown).is_empty());
+    }
+}
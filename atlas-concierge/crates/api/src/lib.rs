@@ -1,23 +1,34 @@
+mod api_error;
+mod feedback_tags;
+mod memory_classifier;
+mod openapi;
+mod pii_scrub;
+#[cfg(feature = "postgres")]
+mod postgres_state;
 mod rate_limit;
+mod recovery_codes;
+mod schema_migrations;
+mod shared_auth;
+mod text_match;
 
 use std::collections::{HashMap, HashSet};
 use std::env;
 use std::path::Path;
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use anyhow::{Context, Result};
 use atlas_agents::ConciergeAgent;
-use atlas_core::{ChatInput, TripPlanRequest};
+use atlas_core::{ChatContextTurn, ChatInput, ConciergeReply, TripPlanRequest};
 use atlas_ml::AtlasMlStack;
 use atlas_observability::AppMetrics;
 use atlas_retrieval::HybridRetriever;
 use atlas_storage::Store;
-use axum::extract::{Form, Json, Path as AxumPath, Query, State};
+use axum::extract::{ConnectInfo, DefaultBodyLimit, Form, Json, Path as AxumPath, Query, State};
 use axum::http::{header, HeaderMap, HeaderValue, Method, Request, StatusCode};
 use axum::middleware::{self, Next};
 use axum::response::{IntoResponse, Redirect, Response};
-use axum::routing::{get, post};
+use axum::routing::{delete, get, post};
 use axum::{body::Body, Router};
 use base64::engine::general_purpose::URL_SAFE_NO_PAD;
 use base64::Engine as _;
@@ -29,9 +40,13 @@ use ring::signature::{RsaPublicKeyComponents, RSA_PKCS1_2048_8192_SHA256};
 use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
 use sqlx::{Row, SqlitePool};
+use tower_http::compression::predicate::{DefaultPredicate, Predicate, SizeAbove};
+use tower_http::compression::CompressionLayer;
 use tower_http::cors::{AllowOrigin, CorsLayer};
-use tower_http::limit::RequestBodyLimitLayer;
 use tower_http::request_id::{MakeRequestUuid, PropagateRequestIdLayer, SetRequestIdLayer};
+use tower_http::sensitive_headers::{
+    SetSensitiveRequestHeadersLayer, SetSensitiveResponseHeadersLayer,
+};
 use tower_http::trace::TraceLayer;
 use url::Url;
 use webauthn_rs::prelude::{
@@ -39,21 +54,63 @@ use webauthn_rs::prelude::{
     RegisterPublicKeyCredential, Webauthn, WebauthnBuilder,
 };
 
-use crate::rate_limit::IpRateLimiter;
+use crate::api_error::ApiError;
+use crate::feedback_tags::feedback_tags_for_message;
+use crate::memory_classifier::{
+    classify_chat_memory, classify_horizon_from_text, classify_survey_memory,
+};
+use crate::pii_scrub::{redact_email_addresses, scrub_pii, PiiOriginalKey};
+use crate::rate_limit::{
+    resolve_client_ip, EmailLoginLockouts, IpRateLimiter, RateLimiter, TrustedProxies,
+};
+use crate::recovery_codes::{
+    fingerprint_recovery_code, generate_recovery_code, hash_recovery_code, verify_recovery_code,
+};
+use crate::schema_migrations::{apply_migrations, MIGRATIONS};
+use crate::shared_auth::{
+    SharedAuthStore, CHALLENGE_OAUTH, CHALLENGE_PASSKEY_AUTHENTICATION,
+    CHALLENGE_PASSKEY_REGISTRATION,
+};
 
 const MAX_PROFILE_FIELD_LEN: usize = 64;
 const MAX_NOTE_TITLE_LEN: usize = 160;
+// Default for `ApiState::note_max_content_chars`, which every note write path checks against.
 const MAX_NOTE_CONTENT_LEN: usize = 8_000;
+const MAX_NOTE_UPSERT_CONTENT_LEN: usize = 200_000;
 const MAX_NOTE_TAGS: usize = 16;
 const MAX_NOTE_TAG_LEN: usize = 32;
 const MAX_REWRITE_INSTRUCTION_LEN: usize = 400;
+const MAX_REWRITE_SECTION_ITEMS: usize = 12;
+const NOTE_REWRITE_PREVIEW_TTL_MINUTES: i64 = 30;
 const MAX_MEMORY_IMPORT_ITEMS: usize = 250;
 const MAX_NOTES_PER_USER: usize = 5_000;
+const NOTE_VERSION_HISTORY_LIMIT: usize = 10;
 const MAX_MEMORY_TEXT_LEN: usize = 800;
 const MAX_MEMORY_RECORDS_PER_USER: usize = 3_000;
+const MAX_CHAT_TURNS_PER_SESSION: usize = 40;
+const MAX_CHAT_TURN_TEXT_LEN: usize = 8_000;
+const MAX_CHAT_CONTEXT_TURNS: usize = 4;
+const MAX_CHAT_SESSIONS_PER_USER: usize = 20;
+const MAX_CHAT_HISTORY_USERS: usize = 10_000;
 const DEFAULT_MEMORY_RETRIEVAL_LIMIT: usize = 12;
+const DIGEST_PERIOD_DAYS: i64 = 7;
+const DIGEST_TOP_TASKS: usize = 3;
+const DIGEST_FOCUS_TASK_PREFIXES: &[&str] = &["checkin-daily-", "checkin-mid-", "checkin-long-"];
 const MAX_MEMORY_RETRIEVAL_LIMIT: usize = 64;
 const TRANSIENT_MEMORY_TTL_DAYS: i64 = 14;
+const EPHEMERAL_MEMORY_TTL_HOURS: i64 = 12;
+const MEMORY_TRASH_TTL_HOURS: i64 = 24;
+const MEMORY_TYPES: &[&str] = &[
+    "preference",
+    "mood",
+    "goal",
+    "constraint",
+    "insight",
+    "friction",
+    "identity",
+    "task",
+];
+const MEMORY_REINFORCEMENT_RATE: f32 = 0.1;
 const MAX_REMINDER_TITLE_LEN: usize = 180;
 const MAX_REMINDER_DETAILS_LEN: usize = 1_500;
 const MAX_REMINDER_DETAILS_FOR_URL: usize = 480;
@@ -63,45 +120,209 @@ const MAX_REMINDER_DURATION_MINUTES: u32 = 8 * 60;
 const MAX_SHORTCUTS_URL_LEN: usize = 1_900;
 const MAX_FEEDBACK_MESSAGE_LEN: usize = 2_000;
 const MAX_FEEDBACK_TAGS: usize = 20;
+// Credentials that must never reach logs. Bodies are never logged at all: chat text, notes and
+// feedback are user content.
+const REDACTED_LOG_HEADERS: &[&str] = &[
+    "cookie",
+    "authorization",
+    "x-api-key",
+    "x-csrf-token",
+    "stripe-signature",
+];
+const FEEDBACK_STATUSES: &[&str] = &["new", "triaged", "in_progress", "resolved"];
 const MAX_FEEDBACK_TAG_LEN: usize = 40;
 const DEFAULT_STRIPE_WEBHOOK_TOLERANCE_SECONDS: u64 = 300;
 const DEFAULT_SUBSCRIPTION_BYPASS_EMAILS: &str = "ceo@atlasmasa.com";
+const DEFAULT_AI_MONTHLY_CALL_CAP: u32 = 600;
+const DEFAULT_AI_CONTEXT_MAX_BYTES: usize = 48 * 1024;
+const DEFAULT_OPENAI_BASE_URL: &str = "https://api.openai.com/v1";
+const MIN_LOCALE_CHECK_LETTERS: usize = 20;
+const DEFAULT_PREMIUM_SYSTEM_PROMPT: &str = "You are Atlas/אטלס Executive Intelligence. Speak with refined, high-class language and clear structure. Act like a strategic chief-of-staff for a high-performing traveler-builder. Prioritize execution, safety, resilience, and momentum.";
+const DEFAULT_ANTHROPIC_BASE_URL: &str = "https://api.anthropic.com/v1";
+const ANTHROPIC_API_VERSION: &str = "2023-06-01";
+const DEFAULT_RETURN_TO: &str = "/concierge-local.html";
+const RETURN_TO_ALLOWED_PREFIXES: &[&str] = &[
+    "/concierge-local.html",
+    "/signin.html",
+    "/signup.html",
+    "/tool-",
+];
+const MAX_COMPANY_STATUS_FIELD_LEN: usize = 160;
+const MAX_COMPANY_STATUS_MESSAGE_LEN: usize = 1_000;
+const MAX_COMPANY_STATUS_ITEMS: usize = 12;
+const DEFAULT_COMPRESSION_MIN_BYTES: u16 = 1024;
+const JSON_FORMAT_REPLY_MARKER: &str = "[formatted_response]";
+const MEMORY_SOURCES: &[&str] = &[
+    "note",
+    "note_rewrite",
+    "survey",
+    "feedback",
+    "chat",
+    "import",
+    "manual",
+    "system",
+];
+const MAX_SPOKEN_SUMMARY_CHARS: usize = 280;
+const MIN_REPLY_CHARS: usize = 80;
+const DEFAULT_CHAT_TIMEOUT_SECONDS: u64 = 30;
+const AI_HEALTHCHECK_TIMEOUT_SECONDS: u64 = 8;
+const SERVER_TIME_HEADER: &str = "x-server-time";
+const REASONING_EFFORT_LEVELS: &[&str] = &["low", "medium", "high"];
+const DEFAULT_FEED_MAX_ITEMS: usize = 6;
+const MIN_UTC_OFFSET_MINUTES: i32 = -12 * 60;
+const MAX_UTC_OFFSET_MINUTES: i32 = 14 * 60;
+const REMINDER_SNOOZE_OPTIONS: &[&str] = &["plus_1h", "tonight", "tomorrow_morning"];
+const SNOOZE_TONIGHT_HOUR: u32 = 20;
+const SNOOZE_MORNING_HOUR: u32 = 9;
+const DEFAULT_WORKING_HOURS_START: &str = "09:00";
+const DEFAULT_WORKING_HOURS_END: &str = "18:00";
+// Signals the proactive feed turns into a memory relevance query (ATLAS_FEED_MEMORY_QUERY).
+const FEED_MEMORY_QUERY_SIGNALS: &[&str] = &["focus", "chat"];
+const MAX_FEED_MEMORY_QUERY_CHARS: usize = 280;
+const DEFAULT_CORS_MAX_AGE_SECONDS: u64 = 600;
+const MAX_CORS_MAX_AGE_SECONDS: u64 = 86_400;
+const DEFAULT_CORS_ALLOWED_METHODS: &[&str] = &["GET", "POST", "DELETE", "OPTIONS"];
+// Always allowed, whatever ATLAS_CORS_ALLOWED_HEADERS says: the first-party frontend sends them.
+const REQUIRED_CORS_ALLOWED_HEADERS: &[&str] = &["content-type", "x-api-key", "x-csrf-token"];
+const PLACEHOLDER_EMAIL_DOMAIN: &str = "@atlasmasa.local";
+const DEFAULT_RECOVERY_CODE_COUNT: usize = 10;
+const MAX_RECOVERY_CODE_COUNT: usize = 16;
+const MAX_FEED_MAX_ITEMS: usize = 20;
+const DEFAULT_EXECUTION_CANDIDATES_LIMIT: usize = 50;
+const MAX_EXECUTION_CANDIDATES_LIMIT: usize = 200;
+const MAX_REPLY_CHARS: usize = 8000;
+const DEFAULT_SHORTCUT_REMINDER_NAME: &str = "AtlasMasaReminder";
+const DEFAULT_SHORTCUT_ALARM_NAME: &str = "AtlasMasaAlarm";
+const MAX_SHORTCUT_NAME_LEN: usize = 80;
+const DEFAULT_SESSION_REFRESH_THRESHOLD_SECONDS: u64 = 60 * 60 * 24 * 7;
+const DEFAULT_BODY_LIMIT_BYTES: usize = 64 * 1024;
+const DEFAULT_AUTH_BODY_LIMIT_BYTES: usize = 16 * 1024;
+const DEFAULT_BULK_BODY_LIMIT_BYTES: usize = 1024 * 1024;
+// Room for the title, tags, ids and JSON punctuation around a note body.
+const NOTE_UPSERT_ENVELOPE_BYTES: usize = 8 * 1024;
+const GUEST_COOKIE_NAME: &str = "atlas_guest";
+const GUEST_ID_PREFIX: &str = "guest-";
+const DEFAULT_GUEST_TTL_SECONDS: u64 = 60 * 60 * 24;
+const DEFAULT_LOGIN_EMAIL_MAX_ATTEMPTS: usize = 10;
+const DEFAULT_MEMORY_PRUNE_INTERVAL_SECONDS: u64 = 60 * 60;
+const DEFAULT_LOGIN_EMAIL_WINDOW_SECONDS: u64 = 15 * 60;
+const DEFAULT_LOGIN_EMAIL_LOCKOUT_SECONDS: u64 = 60;
+const MAX_LOGIN_EMAIL_LOCKOUT_SECONDS: u64 = 60 * 60;
 
 #[derive(Clone)]
 #[allow(private_interfaces)]
 pub struct ApiState {
     pub agent: Arc<ConciergeAgent<Store>>,
+    chat_agent: Arc<dyn ChatAgent>,
     pub metrics: Arc<AppMetrics>,
     pub api_key: String,
-    pub limiter: IpRateLimiter,
-    pub auth_limiter: IpRateLimiter,
+    pub scoped_api_keys: Arc<Vec<ScopedApiKey>>,
+    pub limiter: RateLimiter,
+    pub auth_limiter: RateLimiter,
+    pub passkey_email_limiter: RateLimiter,
+    pub chat_memory_limiter: RateLimiter,
+    pub login_email_lockouts: EmailLoginLockouts,
     pub http_client: Client,
     pub db_pool: Option<SqlitePool>,
+    #[cfg(feature = "postgres")]
+    pub pg_pool: Option<sqlx::PgPool>,
+    pub shared_auth: Option<SharedAuthStore>,
     pub users: Arc<RwLock<HashMap<String, UserRecord>>>,
+    /// Normalised email to the ids of every account carrying it; kept in step by `remember_user`.
+    pub user_ids_by_email: Arc<RwLock<HashMap<String, Vec<String>>>>,
     pub sessions: Arc<RwLock<HashMap<String, SessionRecord>>>,
     pub studio_preferences: Arc<RwLock<HashMap<String, StudioPreferencesRecord>>>,
     pub survey_states: Arc<RwLock<HashMap<String, SurveyStateRecord>>>,
     pub feedback_items: Arc<RwLock<Vec<FeedbackRecord>>>,
     pub user_notes: Arc<RwLock<HashMap<String, Vec<UserNoteRecord>>>>,
+    /// Earlier copies of notes, per user, oldest first; capped per note.
+    pub note_versions: Arc<RwLock<HashMap<String, Vec<NoteVersionRecord>>>>,
+    /// Rewrites handed out by `/v1/notes/rewrite_preview`, keyed by preview id, until accepted
+    /// or expired. In-process only.
+    pub note_rewrite_previews: Arc<RwLock<HashMap<String, NoteRewritePreviewRecord>>>,
+    /// Per-user notes revision, bumped on every note write, import and delete. The notes list
+    /// ETag is derived from it. Mirrored to `notes_revisions` when a database is configured.
+    pub notes_revisions: Arc<RwLock<HashMap<String, NotesRevision>>>,
     pub user_memories: Arc<RwLock<HashMap<String, Vec<MemoryRecord>>>>,
+    /// Memories removed by `/v1/memory/clear`, kept until restored or swept. Mirrored to
+    /// `deleted_memories` when a database is configured.
+    pub deleted_memories: Arc<RwLock<HashMap<String, Vec<TrashedMemory>>>>,
+    pub chat_turns: Arc<RwLock<HashMap<String, Vec<ChatTurnRecord>>>>,
     pub execution_checkins: Arc<RwLock<HashMap<String, Vec<ExecutionCheckinRecord>>>>,
+    /// Digest narratives by user, reused for the rest of the UTC day. In-process only.
+    pub digest_narratives: Arc<RwLock<HashMap<String, DigestNarrativeRecord>>>,
     pub execution_controls: Arc<RwLock<HashMap<String, ExecutionControlsRecord>>>,
     pub oauth_states: Arc<RwLock<HashMap<String, OAuthStateRecord>>>,
     pub google_oauth: Option<GoogleOAuthConfig>,
     pub apple_oauth: Option<AppleOAuthConfig>,
     pub openai_runtime: Option<OpenAiRuntimeConfig>,
+    pub ai_runtime: Option<AiProviderRuntime>,
+    pub premium_system_prompts: PremiumSystemPrompts,
+    pub ai_usage_counters: Arc<RwLock<HashMap<String, AiUsageCounterRecord>>>,
+    pub ai_monthly_call_cap: u32,
+    /// Byte budget for the serialized context JSON sent with premium chat turns.
+    pub ai_context_max_bytes: usize,
+    pub ai_debug: bool,
     pub billing_runtime: Option<BillingRuntimeConfig>,
     pub webauthn_runtime: Option<WebauthnRuntimeConfig>,
     pub passkey_registrations: Arc<RwLock<HashMap<String, PasskeyRegistrationStateRecord>>>,
     pub passkey_authentications: Arc<RwLock<HashMap<String, PasskeyAuthenticationStateRecord>>>,
     pub passkeys_by_user: Arc<RwLock<HashMap<String, Vec<PasskeyRecord>>>>,
+    pub linked_identities: Arc<RwLock<HashMap<String, LinkedIdentityRecord>>>,
+    pub recovery_codes: Arc<RwLock<HashMap<String, Vec<RecoveryCodeRecord>>>>,
+    pub recovery_code_key: String,
+    pub passkey_decoy_secret: String,
     pub allowed_origins: Arc<Vec<String>>,
-    pub company_status: CompanyStatusRecord,
+    pub cors: CorsSettings,
+    pub company_status: Arc<RwLock<CompanyStatusRecord>>,
+    pub ml_capabilities: MlCapabilities,
     pub session_ttl: Duration,
+    pub session_refresh_threshold: Option<Duration>,
     pub cookie_name: String,
     pub cookie_domain: String,
     pub cookie_secure: bool,
     pub cookie_same_site: String,
+    pub cookie_partitioned: bool,
+    pub guest_sessions: Arc<RwLock<HashMap<String, chrono::DateTime<chrono::Utc>>>>,
+    pub guest_ttl: Duration,
+    pub shortcut_reminder_name: String,
+    pub chat_timeout: Duration,
+    pub shortcut_alarm_name: String,
+    pub trusted_proxy_header: Option<String>,
+    pub trusted_region_header: Option<String>,
+    pub trusted_proxies: TrustedProxies,
+    pub body_limits: BodyLimits,
+    pub response_compression: Option<u16>,
+    pub feed_max_items: usize,
+    pub feed_memory_query_signals: Vec<String>,
+    /// Memory types kept in the working set with a short TTL but never written to the database.
+    pub ephemeral_memory_types: Vec<String>,
+    /// Mask emails, phone numbers and card numbers in feedback and memory text before storing it.
+    pub scrub_pii: bool,
+    /// Add keyword-derived triage tags (`crash`, `slow`, `billing`, `auth`) to submitted feedback.
+    pub feedback_auto_tags: bool,
+    /// Longest note body `/v1/notes/upsert` accepts, in characters.
+    pub note_max_content_chars: usize,
+    /// When set alongside `scrub_pii`, the unscrubbed text is kept sealed next to the record.
+    pub pii_original_key: Option<Arc<PiiOriginalKey>>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct BodyLimits {
+    default: usize,
+    auth: usize,
+    bulk: usize,
+}
+
+#[derive(Debug, Clone)]
+struct ScopedApiKey {
+    key: String,
+    route_prefixes: Vec<String>,
+}
+
+enum ApiKeyScope<'a> {
+    Full,
+    Routes(&'a [String]),
 }
 
 #[derive(Debug, Serialize)]
@@ -135,13 +356,94 @@ struct HealthCapabilities {
     passkey: bool,
     billing: bool,
     deep_personalization: bool,
+    ml: MlCapabilities,
+}
+
+// Captured once at startup from the stack that actually loaded, so a silent fallback to the
+// hash embedder or rule classifier is visible on /health.
+#[derive(Debug, Clone, Serialize)]
+struct MlCapabilities {
+    embedder_model: &'static str,
+    embedder_loaded: bool,
+    intent_classifier: &'static str,
+    intent_classifier_fallback: bool,
+    vector_search: bool,
+    retriever_docs: usize,
+    retriever_chunks: usize,
 }
 
 #[derive(Debug, Clone)]
 struct OpenAiRuntimeConfig {
     api_key: String,
+    base_url: String,
     model: String,
     default_reasoning_effort: String,
+    max_reasoning_effort: String,
+}
+
+#[derive(Debug, Clone)]
+struct AnthropicRuntimeConfig {
+    api_key: String,
+    base_url: String,
+    model: String,
+    max_tokens: u32,
+}
+
+#[derive(Debug, Clone)]
+enum AiProviderRuntime {
+    OpenAi(OpenAiRuntimeConfig),
+    Anthropic(AnthropicRuntimeConfig),
+}
+
+/// Base system prompt for premium chat replies plus optional per-locale replacements.
+#[derive(Debug, Clone)]
+struct PremiumSystemPrompts {
+    base: String,
+    by_locale: HashMap<String, String>,
+}
+
+#[derive(Debug, Clone)]
+struct ChatBackendPrompt {
+    system_prompt: String,
+    user_messages: Vec<String>,
+    /// Per-turn override of the runtime's default effort; backends without the knob ignore it.
+    reasoning_effort: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+struct ChatBackendReply {
+    text: String,
+    response_id: Option<String>,
+    usage: Option<serde_json::Value>,
+}
+
+#[allow(async_fn_in_trait)]
+trait ChatBackend {
+    fn backend_name(&self) -> &'static str;
+    fn model(&self) -> &str;
+    async fn complete(
+        &self,
+        client: &Client,
+        prompt: &ChatBackendPrompt,
+    ) -> Result<ChatBackendReply>;
+}
+
+/// What `/v1/chat` needs from the agent. In production this is the concierge agent; tests put a
+/// slow or failing stand-in on `ApiState::chat_agent`.
+trait ChatAgent: Send + Sync {
+    fn handle_chat(
+        &self,
+        input: ChatInput,
+    ) -> futures::future::BoxFuture<'_, Result<ConciergeReply>>;
+}
+
+impl ChatAgent for ConciergeAgent<Store> {
+    fn handle_chat(
+        &self,
+        input: ChatInput,
+    ) -> futures::future::BoxFuture<'_, Result<ConciergeReply>> {
+        Box::pin(ConciergeAgent::handle_chat(self, input))
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -157,6 +459,8 @@ struct BillingRuntimeConfig {
 #[derive(Debug, Clone)]
 struct WebauthnRuntimeConfig {
     webauthn: Arc<Webauthn>,
+    require_user_verification: bool,
+    attestation: String,
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -185,26 +489,61 @@ struct AppleOAuthCallbackQuery {
     error_description: Option<String>,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 struct OAuthStateRecord {
     provider: String,
     code_verifier: Option<String>,
     nonce: Option<String>,
     return_to: String,
+    // Set when the flow was started from /v1/auth/link; the callback then attaches the provider
+    // identity to this user instead of signing in.
+    link_user_id: Option<String>,
+    // The session that started the link. The callback only links when the browser finishing the
+    // flow still presents it, so a victim cannot be walked through someone else's link.
+    #[serde(default)]
+    link_session_id: Option<String>,
     expires_at: chrono::DateTime<chrono::Utc>,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Deserialize)]
+struct AuthLinkStartQuery {
+    return_to: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct LinkedIdentityRecord {
+    provider: String,
+    // The provider's stable account id (`sub`); emails can be reassigned or changed.
+    subject: String,
+    email: String,
+    user_id: String,
+    linked_at: String,
+}
+
+// A provider account vouched for by a completed OAuth exchange.
+struct ProviderIdentity {
+    provider: &'static str,
+    subject: String,
+    email: String,
+    email_verified: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 struct PasskeyRegistrationStateRecord {
     user_id: String,
     state: PasskeyRegistration,
     expires_at: chrono::DateTime<chrono::Utc>,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 struct PasskeyAuthenticationStateRecord {
     user_id: Option<String>,
     state: PasskeyAuthentication,
+    // Decoy challenges are handed out for emails without passkeys and can never finish.
+    decoy: bool,
+    // The email the sign-in was started for; failed finishes count against its lockout.
+    #[serde(default)]
+    email: Option<String>,
     expires_at: chrono::DateTime<chrono::Utc>,
 }
 
@@ -217,6 +556,13 @@ struct ProfileUpsertRequest {
     locale: Option<String>,
 }
 
+#[derive(Debug, Clone, Serialize)]
+struct CoercedField {
+    field: &'static str,
+    provided: String,
+    applied: String,
+}
+
 #[derive(Debug, Clone, Deserialize)]
 struct ChatRequest {
     session_id: Option<String>,
@@ -227,6 +573,24 @@ struct ChatRequest {
     response_depth: Option<String>,
     response_tone: Option<String>,
     include_proactive: Option<bool>,
+    max_reply_chars: Option<usize>,
+    reasoning_effort: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct ChatTurnRecord {
+    turn_id: String,
+    session_id: String,
+    user_id: String,
+    user_text: String,
+    assistant_text: String,
+    created_at: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct ChatHistoryQuery {
+    session_id: String,
+    user_id: Option<String>,
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -239,6 +603,10 @@ struct StudioPreferencesUpsertRequest {
     reminders_app: Option<String>,
     alarms_app: Option<String>,
     voice_mode: Option<String>,
+    utc_offset_minutes: Option<i32>,
+    working_hours_start: Option<String>,
+    working_hours_end: Option<String>,
+    working_days: Option<Vec<String>>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -251,6 +619,16 @@ struct StudioPreferencesRecord {
     reminders_app: String,
     alarms_app: String,
     voice_mode: String,
+    // Minutes east of UTC, used to place snooze times such as "tonight" in the user's day.
+    #[serde(default)]
+    utc_offset_minutes: i32,
+    // Local "HH:MM" window and days suggested reminders are snapped into.
+    #[serde(default = "default_working_hours_start")]
+    working_hours_start: String,
+    #[serde(default = "default_working_hours_end")]
+    working_hours_end: String,
+    #[serde(default = "default_working_days")]
+    working_days: Vec<String>,
     updated_at: String,
 }
 
@@ -323,6 +701,62 @@ struct ProactiveFeedResponse {
     company_status: CompanyStatusRecord,
 }
 
+#[derive(Debug, Clone, Serialize)]
+struct ExecutionDigestFocus {
+    title: String,
+    detail: String,
+    horizon: String,
+    checked_in_at: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct EnergyReading {
+    checked_in_at: String,
+    level: u8,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct EnergyTrend {
+    readings: Vec<EnergyReading>,
+    average: Option<f32>,
+    trend: &'static str,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct ExecutionWeekSummary {
+    checkin_count: usize,
+    completed_focuses: Vec<ExecutionDigestFocus>,
+    pending_focuses: Vec<ExecutionDigestFocus>,
+    energy: EnergyTrend,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct ExecutionDigestQuery {
+    user_id: Option<String>,
+    locale: Option<String>,
+    #[serde(default)]
+    narrative: bool,
+}
+
+#[derive(Debug, Clone)]
+struct DigestNarrativeRecord {
+    day: chrono::NaiveDate,
+    locale: String,
+    text: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct ExecutionDigestResponse {
+    generated_at: String,
+    period_start: String,
+    period_end: String,
+    #[serde(flatten)]
+    summary: ExecutionWeekSummary,
+    feed_ready: bool,
+    top_tasks: Vec<ProactiveFeedItem>,
+    narrative: Option<String>,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 struct ExecutionCheckinRecord {
     checkin_id: String,
@@ -371,7 +805,7 @@ struct ExecutionControlsUpsertRequest {
     include_reminder_suggestions: Option<bool>,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize)]
 struct ExecutionTaskCandidate {
     task_id: String,
     title: String,
@@ -392,6 +826,45 @@ struct ExecutionFeedContext<'a> {
     controls: &'a ExecutionControlsRecord,
     memories: &'a [MemoryRetrievedItem],
     latest_checkin: Option<&'a ExecutionCheckinRecord>,
+    max_items: usize,
+}
+
+/// Everything the execution feed reads for one user, loaded once so the feed and the admin
+/// candidates view work from the same inputs.
+struct ExecutionFeedInputs {
+    company_status: CompanyStatusRecord,
+    user: UserRecord,
+    prefs: StudioPreferencesRecord,
+    survey: Option<SurveyStateRecord>,
+    notes: Vec<UserNoteRecord>,
+    controls: ExecutionControlsRecord,
+    memories: Vec<MemoryRetrievedItem>,
+    latest_checkin: Option<ExecutionCheckinRecord>,
+}
+
+impl ExecutionFeedInputs {
+    fn context(&self, max_items: usize) -> ExecutionFeedContext<'_> {
+        ExecutionFeedContext {
+            company_status: &self.company_status,
+            user: &self.user,
+            prefs: Some(&self.prefs),
+            survey: self.survey.as_ref(),
+            notes: Some(self.notes.as_slice()),
+            controls: &self.controls,
+            memories: self.memories.as_slice(),
+            latest_checkin: self.latest_checkin.as_ref(),
+            max_items,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct ExecutionCandidatesQuery {
+    user_id: String,
+    source: Option<String>,
+    horizon: Option<String>,
+    limit: Option<usize>,
+    offset: Option<usize>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -418,6 +891,7 @@ struct FeedbackSubmitRequest {
     tags: Option<Vec<String>>,
     target_employee: Option<String>,
     source: Option<String>,
+    locale: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -432,6 +906,30 @@ struct FeedbackRecord {
     source: String,
     status: String,
     created_at: String,
+    /// Set when the item moves to `resolved`; cleared again if it is reopened.
+    #[serde(default)]
+    resolved_at: Option<String>,
+    #[serde(default)]
+    resolved_by: Option<String>,
+    #[serde(default)]
+    resolution_note: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    message_original_sealed: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct FeedbackStatusRequest {
+    status: String,
+    resolved_by: Option<String>,
+    note: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct FeedbackResolveAllRequest {
+    resolved_by: Option<String>,
+    note: Option<String>,
+    category: Option<String>,
+    tag: Option<String>,
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -448,6 +946,19 @@ struct ReminderActionRequest {
     reminders_app: Option<String>,
 }
 
+#[derive(Debug, Clone, Deserialize)]
+struct ReminderSnoozeRequest {
+    reminder: ReminderActionRequest,
+    snooze: String,
+    utc_offset_minutes: Option<i32>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct ReminderSnoozeOption {
+    snooze: &'static str,
+    due_at_utc: String,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 struct ActionTelemetry {
     trace_id: String,
@@ -459,6 +970,8 @@ struct ActionTelemetry {
     primary_target: Option<String>,
     warnings: Vec<String>,
     generated_at: String,
+    #[serde(default)]
+    scheduled_start_utc: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -496,6 +1009,32 @@ struct AlarmActionResponse {
     telemetry: ActionTelemetry,
 }
 
+struct StudioReply {
+    text: String,
+    formatted_response: Option<serde_json::Value>,
+}
+
+#[derive(Debug, Serialize)]
+struct StudioPreferenceOption {
+    field: &'static str,
+    allowed: &'static [&'static str],
+    #[serde(rename = "default")]
+    default_value: &'static str,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct ActionPlanRequest {
+    reminder: ReminderActionRequest,
+    alarm: AlarmActionRequest,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct ActionPlanResponse {
+    trace_id: String,
+    reminder: ReminderActionResponse,
+    alarm: AlarmActionResponse,
+}
+
 #[derive(Debug, Clone, Deserialize, Default)]
 struct BillingCheckoutRequest {}
 
@@ -524,6 +1063,15 @@ struct SubscriptionAccessRecord {
     cloud_storage_enabled: bool,
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct AiUsageCounterRecord {
+    user_id: String,
+    period: String,
+    call_count: u32,
+    token_estimate: u64,
+    updated_at: String,
+}
+
 #[derive(Debug, Clone, Deserialize)]
 struct PasskeyRegistrationStartRequest {
     email: Option<String>,
@@ -560,6 +1108,27 @@ struct PasskeyLoginFinishRequest {
     credential: PublicKeyCredential,
 }
 
+#[derive(Debug, Clone, Deserialize, Default)]
+struct RecoveryCodesGenerateRequest {
+    count: Option<usize>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct RecoveryCodeRedeemRequest {
+    email: String,
+    code: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct RecoveryCodeRecord {
+    code_id: String,
+    code_hash: String,
+    // Keyed lookup id, so a redeem only runs argon2 against the code that was typed.
+    code_fingerprint: String,
+    created_at: String,
+    used_at: Option<String>,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 struct PasskeyRecord {
     passkey_id: String,
@@ -577,6 +1146,31 @@ struct UserNoteRecord {
     content: String,
     tags: Vec<String>,
     updated_at: String,
+    #[serde(default)]
+    structured: Option<StructuredNoteRewrite>,
+}
+
+/// A note as it was before an upsert, rewrite or restore replaced it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct NoteVersionRecord {
+    version_id: String,
+    note_id: String,
+    user_id: String,
+    title: String,
+    content: String,
+    tags: Vec<String>,
+    #[serde(default)]
+    structured: Option<StructuredNoteRewrite>,
+    updated_at: String,
+    replaced_at: String,
+    replaced_by: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct StructuredNoteRewrite {
+    immediate_tasks: Vec<String>,
+    mid_term: Vec<String>,
+    long_term: Vec<String>,
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -593,11 +1187,33 @@ struct NotesQuery {
     user_id: Option<String>,
 }
 
+#[derive(Debug, Clone, Deserialize)]
+struct NoteDeleteRequest {
+    user_id: Option<String>,
+    note_id: String,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+struct NoteVersionRestoreRequest {
+    user_id: Option<String>,
+}
+
 #[derive(Debug, Clone, Deserialize)]
 struct NoteRewriteRequest {
     user_id: Option<String>,
     note_id: String,
     instruction: Option<String>,
+    // Id returned by `/v1/notes/rewrite_preview`; when set, that preview is saved instead of
+    // running the model again. Clients cannot submit rewrite text of their own.
+    preview_id: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+struct NoteRewritePreviewRecord {
+    user_id: String,
+    note_id: String,
+    structured: StructuredNoteRewrite,
+    expires_at: chrono::DateTime<chrono::Utc>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -615,6 +1231,8 @@ struct MemoryRecord {
     updated_at: String,
     expires_at: Option<String>,
     fingerprint: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    text_original_sealed: Option<String>,
 }
 
 #[derive(Debug, Clone)]
@@ -634,6 +1252,24 @@ struct MemoryRecordsQuery {
     user_id: Option<String>,
     q: Option<String>,
     limit: Option<usize>,
+    sources: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct MemorySearchRequest {
+    user_id: Option<String>,
+    query: String,
+    limit: Option<usize>,
+    sources: Option<Vec<String>>,
+    types: Option<Vec<String>>,
+    min_score: Option<f32>,
+}
+
+/// Filters for `/v1/memory/search`; an empty list means "any".
+struct MemorySearchFilters<'a> {
+    sources: &'a [String],
+    types: &'a [String],
+    min_score: f32,
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -654,10 +1290,61 @@ struct MemoryDeleteRequest {
     memory_id: String,
 }
 
+#[derive(Debug, Clone, Deserialize)]
+struct MemoryUserQuery {
+    user_id: Option<String>,
+}
+
 #[derive(Debug, Clone, Deserialize)]
 struct MemoryClearRequest {
     user_id: Option<String>,
     scope: Option<String>,
+    tags: Option<Vec<String>>,
+    tag_match: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct NotesRevision {
+    revision: u64,
+    modified_at: chrono::DateTime<chrono::Utc>,
+}
+
+#[derive(Debug, Clone)]
+struct TrashedMemory {
+    memory: MemoryRecord,
+    restore_id: String,
+    deleted_at: chrono::DateTime<chrono::Utc>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct MemoryRestoreRequest {
+    user_id: Option<String>,
+    restore_id: Option<String>,
+}
+
+// Tag filters narrow a clear: `tag_match: "any"` (default) removes memories carrying at least
+// one of the tags, `"all"` only those carrying every tag. The stability scope still applies.
+struct MemoryClearFilter<'a> {
+    scope: &'a str,
+    tags: &'a [String],
+    match_all_tags: bool,
+}
+
+impl MemoryClearFilter<'_> {
+    fn matches(&self, record: &MemoryRecord) -> bool {
+        let in_scope = match self.scope {
+            "permanent" | "transient" => record.stability == self.scope,
+            _ => true,
+        };
+        let has_tag = |tag: &String| record.tags.contains(tag);
+        let tags_match = self.tags.is_empty()
+            || if self.match_all_tags {
+                self.tags.iter().all(has_tag)
+            } else {
+                self.tags.iter().any(has_tag)
+            };
+        in_scope && tags_match
+    }
 }
 
 #[derive(Debug, Clone, Serialize)]
@@ -688,6 +1375,32 @@ struct MemoryImportItem {
 struct MemoryImportRequest {
     user_id: Option<String>,
     items: Vec<MemoryImportItem>,
+    #[serde(default)]
+    dry_run: bool,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct MemoryImportPreviewItem {
+    index: usize,
+    title: String,
+    would_import: bool,
+    reason: &'static str,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct MemoryImportCsvQuery {
+    user_id: Option<String>,
+}
+
+struct ParsedMemoryCsv {
+    items: Vec<(u64, MemoryImportItem, Option<chrono::DateTime<chrono::Utc>>)>,
+    row_errors: Vec<CsvRowError>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct CsvRowError {
+    row: u64,
+    reason: String,
 }
 
 #[derive(Debug, Clone, serde::Serialize)]
@@ -710,6 +1423,8 @@ struct UserRecord {
     passkey_user_handle: Option<String>,
     created_at: String,
     updated_at: String,
+    last_login_at: Option<String>,
+    login_count: u64,
 }
 
 #[derive(Debug, Clone)]
@@ -717,6 +1432,18 @@ struct SessionRecord {
     user_id: String,
     expires_at: chrono::DateTime<chrono::Utc>,
     created_at: chrono::DateTime<chrono::Utc>,
+    client_network: Option<String>,
+    region: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct SessionSummary {
+    current: bool,
+    created_at: String,
+    expires_at: String,
+    client_network: Option<String>,
+    region: Option<String>,
+    new_location: bool,
 }
 
 #[derive(Default)]
@@ -727,13 +1454,39 @@ struct PersistedState {
     survey_states: HashMap<String, SurveyStateRecord>,
     feedback_items: Vec<FeedbackRecord>,
     user_notes: HashMap<String, Vec<UserNoteRecord>>,
+    note_versions: HashMap<String, Vec<NoteVersionRecord>>,
+    notes_revisions: HashMap<String, NotesRevision>,
     user_memories: HashMap<String, Vec<MemoryRecord>>,
+    deleted_memories: HashMap<String, Vec<TrashedMemory>>,
+    chat_turns: HashMap<String, Vec<ChatTurnRecord>>,
     execution_checkins: HashMap<String, Vec<ExecutionCheckinRecord>>,
     execution_controls: HashMap<String, ExecutionControlsRecord>,
     passkeys_by_user: HashMap<String, Vec<PasskeyRecord>>,
+    linked_identities: HashMap<String, LinkedIdentityRecord>,
+    recovery_codes: HashMap<String, Vec<RecoveryCodeRecord>>,
+    ai_usage_counters: HashMap<String, AiUsageCounterRecord>,
+    company_status: Option<CompanyStatusRecord>,
 }
 
+/// Builds the router without background tasks; the server binary starts those itself with
+/// [`spawn_background_tasks`].
 pub async fn build_app(kb_root: impl AsRef<Path>) -> Result<Router> {
+    Ok(build_router(build_state(kb_root).await?))
+}
+
+/// Starts the periodic memory sweep for a serving process. Returns `None` when there is
+/// nothing to sweep: no database is configured, or `ATLAS_MEMORY_PRUNE_INTERVAL_SECONDS` is `0`.
+pub fn spawn_background_tasks(state: &ApiState) -> Option<tokio::task::JoinHandle<()>> {
+    state.db_pool.as_ref()?;
+    let memory_prune_interval = env::var("ATLAS_MEMORY_PRUNE_INTERVAL_SECONDS")
+        .ok()
+        .and_then(|value| value.trim().parse::<u64>().ok())
+        .unwrap_or(DEFAULT_MEMORY_PRUNE_INTERVAL_SECONDS);
+    (memory_prune_interval > 0)
+        .then(|| spawn_memory_prune_task(state.clone(), Duration::from_secs(memory_prune_interval)))
+}
+
+pub async fn build_state(kb_root: impl AsRef<Path>) -> Result<ApiState> {
     let metrics = AppMetrics::shared();
     let ml_stack = AtlasMlStack::load_default();
 
@@ -741,40 +1494,127 @@ pub async fn build_app(kb_root: impl AsRef<Path>) -> Result<Router> {
         HybridRetriever::from_kb_dir(kb_root, Some(ml_stack.embedder.clone()))
             .context("failed to initialize retriever")?,
     );
+    let retrieval_stats = retriever.stats();
+    let embedder_model = ml_stack.embedder.model_name();
+    let ml_capabilities = MlCapabilities {
+        embedder_model,
+        embedder_loaded: ml_stack.burn_enabled && embedder_model != "hash-fallback",
+        intent_classifier: ml_stack.classifier_model,
+        intent_classifier_fallback: !ml_stack.classifier_model.ends_with("centroid-intent"),
+        vector_search: retrieval_stats.vector_enabled,
+        retriever_docs: retrieval_stats.docs_loaded,
+        retriever_chunks: retrieval_stats.chunks_loaded,
+    };
 
     let policy_set = atlas_core::PolicySet::default();
 
     let store = if let Ok(database_url) = env::var("ATLAS_DATABASE_URL") {
-        Store::sqlite(&database_url).await?
+        Store::connect(&database_url).await?
     } else {
         Store::memory()
     };
     let db_pool = match &store {
         Store::Sqlite(sqlite) => Some(sqlite.pool().clone()),
-        Store::Memory(_) => None,
+        _ => None,
     };
     if let Some(pool) = db_pool.as_ref() {
         ensure_app_schema(pool).await?;
     }
-    let persisted_state = load_persistent_state(db_pool.as_ref()).await?;
-
-    let store = Arc::new(store);
-
-    let agent = Arc::new(ConciergeAgent::new(
-        retriever,
-        ml_stack,
-        policy_set,
-        store,
-        metrics.clone(),
-    ));
-
-    let api_key = env::var("ATLAS_API_KEY").unwrap_or_else(|_| "dev-atlas-key".to_string());
-    let session_ttl = Duration::from_secs(
-        env::var("ATLAS_SESSION_TTL_SECONDS")
-            .ok()
-            .and_then(|value| value.parse::<u64>().ok())
+    let mut persisted_state = load_persistent_state(db_pool.as_ref()).await?;
+    let shared_auth_requested = env::var("ATLAS_SHARED_AUTH_STATE")
+        .map(|value| matches!(value.trim(), "1" | "true"))
+        .unwrap_or(false);
+    #[allow(unused_mut)]
+    let mut shared_auth = if shared_auth_requested {
+        db_pool.clone().map(SharedAuthStore::Sqlite)
+    } else {
+        None
+    };
+    #[cfg(feature = "postgres")]
+    let pg_pool = match &store {
+        Store::Postgres(postgres) => Some(postgres.pool().clone()),
+        _ => None,
+    };
+    #[cfg(feature = "postgres")]
+    if let Some(pool) = pg_pool.as_ref() {
+        postgres_state::ensure_schema(pool).await?;
+        postgres_state::load_auth_state(pool, &mut persisted_state).await?;
+        if shared_auth_requested {
+            shared_auth = Some(SharedAuthStore::Postgres(pool.clone()));
+        }
+        tracing::warn!(
+            "postgres persists users, sessions, passkeys, recovery codes, linked identities, ai usage and billing only; notes, memories, check-ins and other app state stay in memory"
+        );
+    }
+    if shared_auth_requested && shared_auth.is_none() {
+        anyhow::bail!("ATLAS_SHARED_AUTH_STATE requires ATLAS_DATABASE_URL");
+    }
+    #[allow(unused_mut)]
+    let mut recovery_code_key =
+        load_or_create_secret(db_pool.as_ref(), "recovery_code_key").await?;
+    // Recovery codes are stored as keyed fingerprints, so the key must outlive the process
+    // wherever the codes do.
+    #[cfg(feature = "postgres")]
+    if let Some(pool) = pg_pool.as_ref() {
+        recovery_code_key =
+            postgres_state::load_or_create_secret(pool, "recovery_code_key").await?;
+    }
+    // Decoy credential ids must not be computable from anything public (such as the dev API
+    // key), or an attacker could tell decoys from real credentials.
+    // They must also stay stable across restarts and instances, or comparing answers over time
+    // would separate decoys from real ids just as well.
+    #[allow(unused_mut)]
+    let mut passkey_decoy_secret = match env::var("ATLAS_PASSKEY_DECOY_SECRET") {
+        Ok(value) if !value.trim().is_empty() => Some(value.trim().to_string()),
+        _ => None,
+    };
+    #[cfg(feature = "postgres")]
+    if let (None, Some(pool)) = (passkey_decoy_secret.as_ref(), pg_pool.as_ref()) {
+        passkey_decoy_secret =
+            Some(postgres_state::load_or_create_secret(pool, "passkey_decoy_secret").await?);
+    }
+    let passkey_decoy_secret = match passkey_decoy_secret {
+        Some(value) => value,
+        None => load_or_create_secret(db_pool.as_ref(), "passkey_decoy_secret").await?,
+    };
+    // Guest buckets are short-lived and never persisted; drop any shared legacy `guest` rows.
+    persisted_state
+        .survey_states
+        .retain(|user_id, _| !is_guest_user_id(user_id));
+
+    let store = Arc::new(store);
+
+    let agent = Arc::new(ConciergeAgent::new(
+        retriever,
+        ml_stack,
+        policy_set,
+        store,
+        metrics.clone(),
+    ));
+
+    let api_key = env::var("ATLAS_API_KEY").unwrap_or_else(|_| "dev-atlas-key".to_string());
+    let scoped_api_keys = env::var("ATLAS_SCOPED_API_KEYS")
+        .ok()
+        .map(|value| parse_scoped_api_keys(value.as_str()))
+        .unwrap_or_default();
+    let session_ttl = Duration::from_secs(
+        env::var("ATLAS_SESSION_TTL_SECONDS")
+            .ok()
+            .and_then(|value| value.parse::<u64>().ok())
             .unwrap_or(60 * 60 * 24 * 30),
     );
+    let session_refresh_threshold = env::var("ATLAS_SESSION_SLIDING")
+        .map(|value| matches!(value.trim(), "1" | "true"))
+        .unwrap_or(false)
+        .then(|| {
+            Duration::from_secs(
+                env::var("ATLAS_SESSION_REFRESH_THRESHOLD_SECONDS")
+                    .ok()
+                    .and_then(|value| value.parse::<u64>().ok())
+                    .unwrap_or(DEFAULT_SESSION_REFRESH_THRESHOLD_SECONDS),
+            )
+            .min(session_ttl)
+        });
     let cookie_name =
         env::var("ATLAS_SESSION_COOKIE_NAME").unwrap_or_else(|_| "atlas_session".to_string());
     let cookie_domain = env::var("ATLAS_SESSION_COOKIE_DOMAIN")
@@ -790,6 +1630,98 @@ pub async fn build_app(kb_root: impl AsRef<Path>) -> Result<Router> {
         &["strict", "lax", "none"],
         "strict",
     );
+    let cookie_partitioned = env::var("ATLAS_COOKIE_PARTITIONED")
+        .map(|value| matches!(value.trim(), "1" | "true"))
+        .unwrap_or(false);
+    if cookie_partitioned && (cookie_same_site != "none" || !cookie_secure) {
+        anyhow::bail!(
+            "ATLAS_COOKIE_PARTITIONED requires ATLAS_COOKIE_SAMESITE=none and a Secure cookie"
+        );
+    }
+    let guest_ttl = Duration::from_secs(
+        env::var("ATLAS_GUEST_TTL_SECONDS")
+            .ok()
+            .and_then(|value| value.parse::<u64>().ok())
+            .unwrap_or(DEFAULT_GUEST_TTL_SECONDS),
+    );
+    let chat_timeout = Duration::from_secs(
+        env::var("ATLAS_CHAT_TIMEOUT_SECONDS")
+            .ok()
+            .and_then(|value| value.parse::<u64>().ok())
+            .filter(|value| *value > 0)
+            .unwrap_or(DEFAULT_CHAT_TIMEOUT_SECONDS),
+    );
+    let feed_max_items = env::var("ATLAS_FEED_MAX_ITEMS")
+        .ok()
+        .and_then(|value| value.parse::<usize>().ok())
+        .filter(|value| *value > 0)
+        .unwrap_or(DEFAULT_FEED_MAX_ITEMS)
+        .min(MAX_FEED_MAX_ITEMS);
+    let feed_memory_query_signals =
+        parse_feed_memory_query_signals(env::var("ATLAS_FEED_MEMORY_QUERY").ok().as_deref());
+    let ephemeral_memory_types =
+        parse_ephemeral_memory_types(env::var("ATLAS_EPHEMERAL_MEMORY_TYPES").ok().as_deref());
+    let note_max_content_chars = env::var("ATLAS_NOTE_MAX_CONTENT_CHARS")
+        .ok()
+        .and_then(|value| value.trim().parse::<usize>().ok())
+        .filter(|value| *value > 0)
+        .unwrap_or(MAX_NOTE_CONTENT_LEN)
+        .min(MAX_NOTE_UPSERT_CONTENT_LEN);
+    let feedback_auto_tags = env::var("ATLAS_FEEDBACK_AUTO_TAGS")
+        .map(|value| !matches!(value.trim(), "0" | "false"))
+        .unwrap_or(true);
+    let scrub_pii = env::var("ATLAS_SCRUB_PII")
+        .map(|value| matches!(value.trim(), "1" | "true"))
+        .unwrap_or(false);
+    let pii_original_key = if scrub_pii {
+        match env::var("ATLAS_PII_ORIGINALS_KEY") {
+            Ok(value) if !value.trim().is_empty() => Some(Arc::new(
+                PiiOriginalKey::from_base64(value.as_str())
+                    .context("ATLAS_PII_ORIGINALS_KEY must be 32 bytes, base64-encoded")?,
+            )),
+            _ => None,
+        }
+    } else {
+        None
+    };
+    let shortcut_name_from_env = |key: &str, default_name: &str| {
+        env::var(key)
+            .ok()
+            .map(|value| sanitize_limited_text(value.as_str(), MAX_SHORTCUT_NAME_LEN))
+            .filter(|value| !value.is_empty())
+            .unwrap_or_else(|| default_name.to_string())
+    };
+    let shortcut_reminder_name = shortcut_name_from_env(
+        "ATLAS_SHORTCUT_REMINDER_NAME",
+        DEFAULT_SHORTCUT_REMINDER_NAME,
+    );
+    let shortcut_alarm_name =
+        shortcut_name_from_env("ATLAS_SHORTCUT_ALARM_NAME", DEFAULT_SHORTCUT_ALARM_NAME);
+    let trusted_proxy_header = env::var("ATLAS_TRUSTED_PROXY_HEADER")
+        .ok()
+        .and_then(|value| sanitize_header_name(value.as_str()));
+    let trusted_region_header = env::var("ATLAS_TRUSTED_REGION_HEADER")
+        .ok()
+        .and_then(|value| sanitize_header_name(value.as_str()));
+    let trusted_proxies = TrustedProxies::parse(
+        env::var("ATLAS_TRUSTED_PROXIES")
+            .unwrap_or_default()
+            .as_str(),
+    );
+    let body_limits = BodyLimits {
+        default: env_body_limit("ATLAS_BODY_LIMIT_BYTES", DEFAULT_BODY_LIMIT_BYTES),
+        auth: env_body_limit("ATLAS_AUTH_BODY_LIMIT_BYTES", DEFAULT_AUTH_BODY_LIMIT_BYTES),
+        bulk: env_body_limit("ATLAS_BULK_BODY_LIMIT_BYTES", DEFAULT_BULK_BODY_LIMIT_BYTES),
+    };
+    let response_compression = env::var("ATLAS_RESPONSE_COMPRESSION")
+        .map(|value| !matches!(value.trim(), "0" | "false"))
+        .unwrap_or(true)
+        .then(|| {
+            env::var("ATLAS_COMPRESSION_MIN_BYTES")
+                .ok()
+                .and_then(|value| value.parse::<u16>().ok())
+                .unwrap_or(DEFAULT_COMPRESSION_MIN_BYTES)
+        });
     let api_rate_limit_window = Duration::from_secs(
         env::var("ATLAS_API_RATE_LIMIT_WINDOW_SECONDS")
             .ok()
@@ -810,59 +1742,228 @@ pub async fn build_app(kb_root: impl AsRef<Path>) -> Result<Router> {
         .ok()
         .and_then(|value| value.parse::<usize>().ok())
         .unwrap_or(12);
+    let passkey_email_rate_limit_max = env::var("ATLAS_PASSKEY_EMAIL_RATE_LIMIT_MAX")
+        .ok()
+        .and_then(|value| value.parse::<usize>().ok())
+        .unwrap_or(5);
+    let login_email_lockouts = EmailLoginLockouts::new(
+        env::var("ATLAS_LOGIN_EMAIL_MAX_ATTEMPTS")
+            .ok()
+            .and_then(|value| value.trim().parse::<usize>().ok())
+            .filter(|value| *value > 0)
+            .unwrap_or(DEFAULT_LOGIN_EMAIL_MAX_ATTEMPTS),
+        Duration::from_secs(
+            env::var("ATLAS_LOGIN_EMAIL_WINDOW_SECONDS")
+                .ok()
+                .and_then(|value| value.trim().parse::<u64>().ok())
+                .filter(|value| *value > 0)
+                .unwrap_or(DEFAULT_LOGIN_EMAIL_WINDOW_SECONDS),
+        ),
+        Duration::from_secs(
+            env::var("ATLAS_LOGIN_EMAIL_LOCKOUT_SECONDS")
+                .ok()
+                .and_then(|value| value.trim().parse::<u64>().ok())
+                .filter(|value| *value > 0)
+                .unwrap_or(DEFAULT_LOGIN_EMAIL_LOCKOUT_SECONDS),
+        ),
+        Duration::from_secs(MAX_LOGIN_EMAIL_LOCKOUT_SECONDS),
+    );
+    let chat_memory_ingest_max = env::var("ATLAS_CHAT_MEMORY_INGEST_MAX_PER_HOUR")
+        .ok()
+        .and_then(|value| value.parse::<usize>().ok())
+        .unwrap_or(30);
     let allowed_origins = parse_allowed_origins();
+    let cors = parse_cors_settings(
+        env::var("ATLAS_CORS_ALLOWED_METHODS").ok().as_deref(),
+        env::var("ATLAS_CORS_ALLOWED_HEADERS").ok().as_deref(),
+        env::var("ATLAS_CORS_MAX_AGE_SECONDS").ok().as_deref(),
+    );
     let google_oauth = build_google_oauth_config();
     let apple_oauth = build_apple_oauth_config();
     let openai_runtime = build_openai_runtime_config();
+    let ai_runtime = build_ai_provider_runtime(openai_runtime.as_ref());
+    let premium_system_prompt_file = match env::var("ATLAS_OPENAI_SYSTEM_PROMPT_FILE") {
+        Ok(path) if !path.trim().is_empty() => {
+            Some(std::fs::read_to_string(path.trim()).with_context(|| {
+                format!("failed to read ATLAS_OPENAI_SYSTEM_PROMPT_FILE {path}")
+            })?)
+        }
+        _ => None,
+    };
+    let premium_system_prompts = parse_premium_system_prompts(
+        env::var("ATLAS_OPENAI_SYSTEM_PROMPT").ok().as_deref(),
+        premium_system_prompt_file.as_deref(),
+    );
+    let ai_debug = env::var("ATLAS_DEBUG_AI")
+        .map(|value| matches!(value.trim(), "1" | "true"))
+        .unwrap_or(false);
+    let ai_context_max_bytes = env::var("ATLAS_AI_CONTEXT_MAX_BYTES")
+        .ok()
+        .and_then(|value| value.trim().parse::<usize>().ok())
+        .filter(|value| *value > 0)
+        .unwrap_or(DEFAULT_AI_CONTEXT_MAX_BYTES);
+    let ai_monthly_call_cap = env::var("ATLAS_AI_MONTHLY_CALL_CAP")
+        .ok()
+        .and_then(|value| value.parse::<u32>().ok())
+        .unwrap_or(DEFAULT_AI_MONTHLY_CALL_CAP);
     let billing_runtime = build_billing_runtime_config();
-    let webauthn_runtime = build_webauthn_runtime();
+    let webauthn_runtime = build_webauthn_runtime()?;
+    let redis_connection = connect_rate_limit_redis().await?;
 
     let state = ApiState {
+        chat_agent: agent.clone(),
         agent,
         metrics,
         api_key,
-        limiter: IpRateLimiter::new(api_rate_limit_window, api_rate_limit_max),
-        auth_limiter: IpRateLimiter::new(auth_rate_limit_window, auth_rate_limit_max),
+        scoped_api_keys: Arc::new(scoped_api_keys),
+        limiter: build_rate_limiter(
+            &redis_connection,
+            "api",
+            api_rate_limit_window,
+            api_rate_limit_max,
+        ),
+        auth_limiter: build_rate_limiter(
+            &redis_connection,
+            "auth",
+            auth_rate_limit_window,
+            auth_rate_limit_max,
+        ),
+        passkey_email_limiter: build_rate_limiter(
+            &redis_connection,
+            "passkey_email",
+            auth_rate_limit_window,
+            passkey_email_rate_limit_max,
+        ),
+        chat_memory_limiter: build_rate_limiter(
+            &redis_connection,
+            "chat_memory",
+            Duration::from_secs(60 * 60),
+            chat_memory_ingest_max,
+        ),
+        login_email_lockouts,
         http_client: Client::builder()
             .connect_timeout(Duration::from_secs(6))
             .timeout(Duration::from_secs(20))
             .build()
             .context("failed to build HTTP client")?,
         db_pool,
+        #[cfg(feature = "postgres")]
+        pg_pool,
+        shared_auth,
+        user_ids_by_email: Arc::new(RwLock::new(index_user_emails(
+            persisted_state.users.values(),
+        ))),
         users: Arc::new(RwLock::new(persisted_state.users)),
         sessions: Arc::new(RwLock::new(persisted_state.sessions)),
         studio_preferences: Arc::new(RwLock::new(persisted_state.studio_preferences)),
         survey_states: Arc::new(RwLock::new(persisted_state.survey_states)),
         feedback_items: Arc::new(RwLock::new(persisted_state.feedback_items)),
         user_notes: Arc::new(RwLock::new(persisted_state.user_notes)),
+        notes_revisions: Arc::new(RwLock::new(persisted_state.notes_revisions)),
+        note_versions: Arc::new(RwLock::new(persisted_state.note_versions)),
+        note_rewrite_previews: Arc::new(RwLock::new(HashMap::new())),
         user_memories: Arc::new(RwLock::new(persisted_state.user_memories)),
+        deleted_memories: Arc::new(RwLock::new(persisted_state.deleted_memories)),
+        chat_turns: Arc::new(RwLock::new(persisted_state.chat_turns)),
         execution_checkins: Arc::new(RwLock::new(persisted_state.execution_checkins)),
+        digest_narratives: Arc::new(RwLock::new(HashMap::new())),
         execution_controls: Arc::new(RwLock::new(persisted_state.execution_controls)),
         oauth_states: Arc::new(RwLock::new(HashMap::new())),
         google_oauth,
         apple_oauth,
         openai_runtime,
+        ai_runtime,
+        premium_system_prompts,
+        ai_usage_counters: Arc::new(RwLock::new(persisted_state.ai_usage_counters)),
+        ai_monthly_call_cap,
+        ai_context_max_bytes,
+        ai_debug,
         billing_runtime,
         webauthn_runtime,
         passkey_registrations: Arc::new(RwLock::new(HashMap::new())),
         passkey_authentications: Arc::new(RwLock::new(HashMap::new())),
         passkeys_by_user: Arc::new(RwLock::new(persisted_state.passkeys_by_user)),
+        linked_identities: Arc::new(RwLock::new(persisted_state.linked_identities)),
+        recovery_codes: Arc::new(RwLock::new(persisted_state.recovery_codes)),
+        recovery_code_key,
+        passkey_decoy_secret,
         allowed_origins: Arc::new(allowed_origins),
-        company_status: default_company_status(),
+        cors,
+        ml_capabilities,
+        company_status: Arc::new(RwLock::new(initial_company_status(
+            persisted_state.company_status,
+            env::var("ATLAS_COMPANY_STATUS_FILE").ok().as_deref(),
+        ))),
         session_ttl,
+        session_refresh_threshold,
         cookie_name,
         cookie_domain,
         cookie_secure,
         cookie_same_site,
+        cookie_partitioned,
+        guest_sessions: Arc::new(RwLock::new(HashMap::new())),
+        guest_ttl,
+        shortcut_reminder_name,
+        chat_timeout,
+        shortcut_alarm_name,
+        trusted_proxy_header,
+        trusted_region_header,
+        trusted_proxies,
+        body_limits,
+        response_compression,
+        feed_max_items,
+        feed_memory_query_signals,
+        ephemeral_memory_types,
+        scrub_pii,
+        feedback_auto_tags,
+        note_max_content_chars,
+        pii_original_key,
     };
+    Ok(state)
+}
 
-    Ok(build_router(state))
+// Ingest only prunes the user it is writing for, so users who go quiet would keep expired
+// memories forever without this sweep.
+fn spawn_memory_prune_task(state: ApiState, period: Duration) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+        ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            ticker.tick().await;
+            let pruned = prune_expired_memories_everywhere(&state).await;
+            sweep_expired_memory_trash(&state, chrono::Utc::now()).await;
+            if !pruned.is_empty() {
+                tracing::info!(
+                    users = pruned.len(),
+                    removed = pruned.iter().map(|(_, removed)| removed).sum::<usize>(),
+                    "pruned expired memories"
+                );
+            }
+        }
+    })
 }
 
 pub fn build_router(state: ApiState) -> Router {
+    // Sits inside the trace/request-id layers so logged responses and x-request-id reflect the
+    // final (possibly compressed) body; small payloads stay uncompressed.
+    let compress = state.response_compression.is_some();
+    let compression = CompressionLayer::new()
+        .gzip(compress)
+        .br(compress)
+        .compress_when(
+            DefaultPredicate::new().and(SizeAbove::new(
+                state
+                    .response_compression
+                    .unwrap_or(DEFAULT_COMPRESSION_MIN_BYTES),
+            )),
+        );
+
     Router::new()
         .route("/health", get(health))
+        .route("/openapi.json", get(openapi_spec))
         .route("/v1/chat", post(chat))
+        .route("/v1/chat/validate", post(chat_validate))
+        .route("/v1/chat/history", get(chat_history))
         .route("/v1/plan_trip", post(plan_trip))
         .route("/v1/auth/google/start", get(auth_google_start))
         .route("/v1/auth/google/callback", get(auth_google_callback))
@@ -887,18 +1988,41 @@ pub fn build_router(state: ApiState) -> Router {
             "/v1/auth/passkey/login/finish",
             post(auth_passkey_login_finish),
         )
+        .route("/v1/auth/link/:provider/start", post(auth_link_start))
+        .route(
+            "/v1/auth/recovery_codes/generate",
+            post(auth_recovery_codes_generate),
+        )
+        .route(
+            "/v1/auth/recovery_codes/redeem",
+            post(auth_recovery_codes_redeem),
+        )
         .route("/v1/auth/social_login", post(social_login))
         .route("/v1/auth/logout", post(auth_logout))
         .route("/v1/profile/upsert", post(profile_upsert))
         .route("/v1/auth/me", get(auth_me))
+        .route("/v1/auth/sessions", get(auth_sessions_list))
         .route("/v1/notes", get(notes_list))
         .route("/v1/notes/upsert", post(note_upsert))
         .route("/v1/notes/rewrite", post(note_rewrite))
+        .route("/v1/notes/rewrite_preview", post(note_rewrite_preview))
+        .route("/v1/notes/delete", post(note_delete))
+        .route("/v1/notes/:note_id", delete(note_delete_by_id))
+        .route("/v1/notes/:note_id/versions", get(note_versions_list))
+        .route(
+            "/v1/notes/:note_id/versions/:version_id/restore",
+            post(note_version_restore),
+        )
         .route("/v1/memory/import", post(memory_import))
+        .route("/v1/memory/import_csv", post(memory_import_csv))
         .route("/v1/memory/records", get(memory_records_list))
+        .route("/v1/memory/export.jsonl", get(memory_export_jsonl))
+        .route("/v1/memory/search", post(memory_search))
         .route("/v1/memory/upsert", post(memory_upsert))
         .route("/v1/memory/delete", post(memory_delete))
+        .route("/v1/memory/:memory_id", delete(memory_delete_by_id))
         .route("/v1/memory/clear", post(memory_clear))
+        .route("/v1/memory/restore", post(memory_restore))
         .route(
             "/v1/billing/create_checkout_session",
             post(billing_create_checkout_session),
@@ -908,40 +2032,93 @@ pub fn build_router(state: ApiState) -> Router {
             "/v1/studio/preferences",
             get(studio_preferences_get).post(studio_preferences_upsert),
         )
+        .route(
+            "/v1/studio/preferences/defaults",
+            get(studio_preferences_defaults),
+        )
         .route("/v1/survey/next", get(survey_next))
         .route("/v1/survey/answer", post(survey_answer))
         .route("/v1/feed/proactive", get(feed_proactive))
         .route("/v1/execution/checkin", post(execution_checkin_submit))
         .route("/v1/execution/refresh", post(execution_refresh))
+        .route("/v1/execution/digest", get(execution_digest))
         .route(
             "/v1/execution/controls",
             get(execution_controls_get).post(execution_controls_upsert),
         )
         .route("/v1/company/status", get(company_status))
+        .route(
+            "/v1/admin/company_status",
+            post(admin_company_status_update),
+        )
+        .route("/v1/admin/ai_healthcheck", get(admin_ai_healthcheck))
+        .route(
+            "/v1/admin/memory/prune_expired",
+            post(admin_memory_prune_expired),
+        )
+        .route(
+            "/v1/admin/execution/candidates",
+            get(admin_execution_candidates),
+        )
         .route("/v1/feedback/submit", post(feedback_submit))
         .route(
-            "/v1/feedback/employee/{employee}",
+            "/v1/feedback/employee/:employee",
             get(feedback_for_employee),
         )
+        .route(
+            "/v1/feedback/employee/:employee/resolve_all",
+            post(feedback_resolve_all),
+        )
+        .route(
+            "/v1/feedback/:feedback_id/status",
+            post(feedback_status_update),
+        )
         .route("/v1/actions/reminder", post(action_reminder))
+        .route("/v1/actions/reminder/snooze", post(action_reminder_snooze))
         .route("/v1/actions/alarm", post(action_alarm))
-        .layer(build_cors_layer(&state.allowed_origins))
+        .route("/v1/actions/plan", post(action_plan))
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            guest_session_middleware,
+        ))
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            session_sliding_middleware,
+        ))
+        .layer(compression)
+        .layer(build_cors_layer(&state.allowed_origins, &state.cors))
         .layer(middleware::from_fn_with_state(
             state.clone(),
             security_headers_middleware,
         ))
+        .layer(middleware::from_fn(server_time_middleware))
         .layer(middleware::from_fn_with_state(
             state.clone(),
             csrf_origin_middleware,
         ))
-        .layer(TraceLayer::new_for_http())
-        .layer(SetRequestIdLayer::x_request_id(MakeRequestUuid))
+        .layer(SetSensitiveResponseHeadersLayer::new([header::SET_COOKIE]))
+        .layer(TraceLayer::new_for_http().make_span_with(request_span))
+        .layer(SetSensitiveRequestHeadersLayer::new(
+            REDACTED_LOG_HEADERS
+                .iter()
+                .map(|name| header::HeaderName::from_static(name)),
+        ))
+        // Propagate must sit inside Set so generated ids are copied onto the response too.
         .layer(PropagateRequestIdLayer::x_request_id())
-        .layer(RequestBodyLimitLayer::new(64 * 1024))
+        .layer(SetRequestIdLayer::x_request_id(MakeRequestUuid))
+        .layer(DefaultBodyLimit::disable())
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            body_limit_middleware,
+        ))
         .layer(middleware::from_fn_with_state(
             state.clone(),
             api_key_middleware,
         ))
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            shared_session_middleware,
+        ))
         .layer(middleware::from_fn_with_state(
             state.clone(),
             rate_limit_middleware,
@@ -949,6 +2126,10 @@ pub fn build_router(state: ApiState) -> Router {
         .with_state(state)
 }
 
+async fn openapi_spec(State(state): State<ApiState>) -> impl IntoResponse {
+    Json(openapi::spec(state.cookie_name.as_str()))
+}
+
 async fn health(State(state): State<ApiState>) -> impl IntoResponse {
     let payload = HealthResponse {
         status: "ok",
@@ -960,6 +2141,7 @@ async fn health(State(state): State<ApiState>) -> impl IntoResponse {
             passkey: state.webauthn_runtime.is_some(),
             billing: state.billing_runtime.is_some(),
             deep_personalization: true,
+            ml: state.ml_capabilities.clone(),
         },
     };
     (StatusCode::OK, Json(payload))
@@ -972,6 +2154,7 @@ struct GoogleTokenResponse {
 
 #[derive(Debug, Deserialize)]
 struct GoogleUserInfoResponse {
+    id: Option<String>,
     email: String,
     verified_email: Option<bool>,
     name: Option<String>,
@@ -1007,6 +2190,7 @@ impl JwtAudienceClaim {
 
 #[derive(Debug, Deserialize)]
 struct AppleIdTokenClaims {
+    sub: Option<String>,
     aud: Option<JwtAudienceClaim>,
     iss: Option<String>,
     exp: Option<i64>,
@@ -1034,37 +2218,55 @@ async fn auth_google_start(
     State(state): State<ApiState>,
     Query(query): Query<GoogleOAuthStartQuery>,
 ) -> impl IntoResponse {
+    begin_google_oauth(&state, query.return_to.as_deref(), None, None).await
+}
+
+async fn begin_google_oauth(
+    state: &ApiState,
+    return_to: Option<&str>,
+    link_user_id: Option<String>,
+    link_session_id: Option<String>,
+) -> Response {
     let Some(config) = state.google_oauth.as_ref() else {
-        return (
-            StatusCode::SERVICE_UNAVAILABLE,
-            Json(serde_json::json!({
-                "error": "oauth_unavailable",
-                "message": "Google OAuth is not configured"
-            })),
+        return ApiError::service_unavailable(
+            "oauth_unavailable",
+            "Google OAuth is not configured",
         )
-            .into_response();
+        .into_response();
     };
 
     let state_token = generate_urlsafe_token(24);
     let code_verifier = generate_urlsafe_token(64);
     let code_challenge = URL_SAFE_NO_PAD.encode(Sha256::digest(code_verifier.as_bytes()));
-    let return_to = sanitize_return_to(
-        query
-            .return_to
-            .as_deref()
-            .unwrap_or("/concierge-local.html"),
-    );
+    let return_to = sanitize_return_to(return_to.unwrap_or(DEFAULT_RETURN_TO));
+
+    let challenge = OAuthStateRecord {
+        provider: "google".to_string(),
+        code_verifier: Some(code_verifier),
+        nonce: None,
+        return_to,
+        link_user_id,
+        link_session_id,
+        expires_at: chrono::Utc::now() + chrono::Duration::minutes(12),
+    };
 
-    state.oauth_states.write().insert(
-        state_token.clone(),
-        OAuthStateRecord {
-            provider: "google".to_string(),
-            code_verifier: Some(code_verifier),
-            nonce: None,
-            return_to,
-            expires_at: chrono::Utc::now() + chrono::Duration::minutes(12),
-        },
-    );
+    if stash_shared_challenge(
+        state,
+        CHALLENGE_OAUTH,
+        &state_token,
+        &challenge,
+        challenge.expires_at,
+    )
+    .await
+    .is_err()
+    {
+        return shared_challenge_unavailable_response();
+    }
+
+    state
+        .oauth_states
+        .write()
+        .insert(state_token.clone(), challenge);
 
     let authorize_url = format!(
         "https://accounts.google.com/o/oauth2/v2/auth?client_id={}&redirect_uri={}&response_type=code&scope={}&state={}&code_challenge={}&code_challenge_method=S256&prompt=select_account",
@@ -1086,67 +2288,83 @@ async fn auth_google_start(
 
 async fn auth_google_callback(
     State(state): State<ApiState>,
+    connect_info: Option<ConnectInfo<std::net::SocketAddr>>,
+    headers: HeaderMap,
     Query(query): Query<GoogleOAuthCallbackQuery>,
 ) -> impl IntoResponse {
+    let peer = connect_info.map(|ConnectInfo(addr)| addr.ip());
     let Some(config) = state.google_oauth.as_ref() else {
         return Redirect::to("/").into_response();
     };
+    let request_id = request_id_from_headers(&headers);
 
     if let Some(error) = query.error.as_ref() {
-        let target = format!(
-            "{}{}?auth=error&reason={}",
-            config.frontend_origin,
+        return auth_failure_redirect(
+            "google",
+            request_id.as_str(),
+            config.frontend_origin.as_str(),
             "/concierge-local.html",
-            pct_encode(query.error_description.as_deref().unwrap_or(error.as_str()))
+            pct_encode(query.error_description.as_deref().unwrap_or(error.as_str())).as_str(),
         );
-        return Redirect::to(target.as_str()).into_response();
     }
 
     let Some(state_token) = query.state.as_deref() else {
-        let target = format!(
-            "{}{}?auth=error&reason=missing_state",
-            config.frontend_origin, "/concierge-local.html"
+        return auth_failure_redirect(
+            "google",
+            request_id.as_str(),
+            config.frontend_origin.as_str(),
+            "/concierge-local.html",
+            "missing_state",
         );
-        return Redirect::to(target.as_str()).into_response();
     };
 
-    let Some(pending) = state.oauth_states.write().remove(state_token) else {
-        let target = format!(
-            "{}{}?auth=error&reason=invalid_state",
-            config.frontend_origin, "/concierge-local.html"
+    let pending = state.oauth_states.write().remove(state_token);
+    let Some(pending) = take_shared_challenge(&state, CHALLENGE_OAUTH, state_token, pending).await
+    else {
+        return auth_failure_redirect(
+            "google",
+            request_id.as_str(),
+            config.frontend_origin.as_str(),
+            "/concierge-local.html",
+            "invalid_state",
         );
-        return Redirect::to(target.as_str()).into_response();
     };
     if pending.expires_at <= chrono::Utc::now() {
-        let target = format!(
-            "{}{}?auth=error&reason=state_expired",
-            config.frontend_origin, "/concierge-local.html"
+        return auth_failure_redirect(
+            "google",
+            request_id.as_str(),
+            config.frontend_origin.as_str(),
+            "/concierge-local.html",
+            "state_expired",
         );
-        return Redirect::to(target.as_str()).into_response();
     }
     if pending.provider != "google" {
-        let target = format!(
-            "{}{}?auth=error&reason=provider_mismatch",
-            config.frontend_origin, "/concierge-local.html"
+        return auth_failure_redirect(
+            "google",
+            request_id.as_str(),
+            config.frontend_origin.as_str(),
+            "/concierge-local.html",
+            "provider_mismatch",
         );
-        return Redirect::to(target.as_str()).into_response();
     }
     let Some(code_verifier) = pending.code_verifier.as_deref() else {
-        let target = format!(
-            "{}{}?auth=error&reason=missing_pkce_verifier",
-            config.frontend_origin,
-            pending.return_to.as_str()
+        return auth_failure_redirect(
+            "google",
+            request_id.as_str(),
+            config.frontend_origin.as_str(),
+            pending.return_to.as_str(),
+            "missing_pkce_verifier",
         );
-        return Redirect::to(target.as_str()).into_response();
     };
 
     let Some(code) = query.code.as_deref() else {
-        let target = format!(
-            "{}{}?auth=error&reason=missing_code",
-            config.frontend_origin,
-            pending.return_to.as_str()
+        return auth_failure_redirect(
+            "google",
+            request_id.as_str(),
+            config.frontend_origin.as_str(),
+            pending.return_to.as_str(),
+            "missing_code",
         );
-        return Redirect::to(target.as_str()).into_response();
     };
 
     let token = match state
@@ -1167,31 +2385,33 @@ async fn auth_google_callback(
             match response.json::<GoogleTokenResponse>().await {
                 Ok(payload) => payload,
                 Err(_) => {
-                    let target = format!(
-                        "{}{}?auth=error&reason=token_parse_failed",
-                        config.frontend_origin,
-                        pending.return_to.as_str()
+                    return auth_failure_redirect(
+                        "google",
+                        request_id.as_str(),
+                        config.frontend_origin.as_str(),
+                        pending.return_to.as_str(),
+                        "token_parse_failed",
                     );
-                    return Redirect::to(target.as_str()).into_response();
                 }
             }
         }
         Ok(response) => {
-            let target = format!(
-                "{}{}?auth=error&reason=token_exchange_failed_{}",
-                config.frontend_origin,
+            return auth_failure_redirect(
+                "google",
+                request_id.as_str(),
+                config.frontend_origin.as_str(),
                 pending.return_to.as_str(),
-                response.status().as_u16()
+                format!("token_exchange_failed_{}", response.status().as_u16()).as_str(),
             );
-            return Redirect::to(target.as_str()).into_response();
         }
         Err(_) => {
-            let target = format!(
-                "{}{}?auth=error&reason=token_exchange_network_failed",
-                config.frontend_origin,
-                pending.return_to.as_str()
+            return auth_failure_redirect(
+                "google",
+                request_id.as_str(),
+                config.frontend_origin.as_str(),
+                pending.return_to.as_str(),
+                "token_exchange_network_failed",
             );
-            return Redirect::to(target.as_str()).into_response();
         }
     };
 
@@ -1206,39 +2426,75 @@ async fn auth_google_callback(
             match response.json::<GoogleUserInfoResponse>().await {
                 Ok(payload) => payload,
                 Err(_) => {
-                    let target = format!(
-                        "{}{}?auth=error&reason=userinfo_parse_failed",
-                        config.frontend_origin,
-                        pending.return_to.as_str()
+                    return auth_failure_redirect(
+                        "google",
+                        request_id.as_str(),
+                        config.frontend_origin.as_str(),
+                        pending.return_to.as_str(),
+                        "userinfo_parse_failed",
                     );
-                    return Redirect::to(target.as_str()).into_response();
                 }
             }
         }
         _ => {
-            let target = format!(
-                "{}{}?auth=error&reason=userinfo_failed",
-                config.frontend_origin,
-                pending.return_to.as_str()
+            return auth_failure_redirect(
+                "google",
+                request_id.as_str(),
+                config.frontend_origin.as_str(),
+                pending.return_to.as_str(),
+                "userinfo_failed",
             );
-            return Redirect::to(target.as_str()).into_response();
         }
     };
 
-    if !userinfo.verified_email.unwrap_or(true) {
-        let target = format!(
-            "{}{}?auth=error&reason=email_not_verified",
-            config.frontend_origin,
-            pending.return_to.as_str()
+    // A missing claim means Google did not vouch for the email.
+    if !userinfo.verified_email.unwrap_or(false) {
+        return auth_failure_redirect(
+            "google",
+            request_id.as_str(),
+            config.frontend_origin.as_str(),
+            pending.return_to.as_str(),
+            "email_not_verified",
         );
-        return Redirect::to(target.as_str()).into_response();
+    }
+
+    let Some(subject) = userinfo.id.clone().filter(|value| !value.trim().is_empty()) else {
+        return auth_failure_redirect(
+            "google",
+            request_id.as_str(),
+            config.frontend_origin.as_str(),
+            pending.return_to.as_str(),
+            "missing_subject",
+        );
+    };
+    if pending.link_user_id.is_some() {
+        let identity = ProviderIdentity {
+            provider: "google",
+            subject,
+            email: userinfo.email.to_lowercase(),
+            email_verified: userinfo.verified_email.unwrap_or(false),
+        };
+        return complete_provider_link(
+            &state,
+            &headers,
+            &pending,
+            identity,
+            request_id.as_str(),
+            config.frontend_origin.as_str(),
+        )
+        .await;
     }
 
     let now = chrono::Utc::now().to_rfc3339();
-    let user = find_or_create_user_by_email(
+    let identity = ProviderIdentity {
+        provider: "google",
+        subject,
+        email: userinfo.email.to_lowercase(),
+        email_verified: userinfo.verified_email.unwrap_or(false),
+    };
+    let mut user = find_or_create_user_by_email(
         &state,
-        "google",
-        userinfo.email.to_lowercase(),
+        &identity,
         userinfo
             .name
             .unwrap_or_else(|| "Atlas/אטלס User".to_string()),
@@ -1247,17 +2503,26 @@ async fn auth_google_callback(
     )
     .await;
 
-    let session_id = match issue_session_for_user(&state, &user).await {
+    let session_id = match issue_session_for_user(&state, &mut user, peer, &headers).await {
         Ok(value) => value,
         Err(_) => {
-            let target = format!(
-                "{}{}?auth=error&reason=session_issue_failed",
-                config.frontend_origin,
-                pending.return_to.as_str()
+            return auth_failure_redirect(
+                "google",
+                request_id.as_str(),
+                config.frontend_origin.as_str(),
+                pending.return_to.as_str(),
+                "session_issue_failed",
             );
-            return Redirect::to(target.as_str()).into_response();
         }
     };
+    log_auth_event(
+        "auth.login",
+        "success",
+        "google",
+        request_id.as_str(),
+        Some(&user),
+        None,
+    );
 
     let target = format!(
         "{}{}?auth=success",
@@ -1271,6 +2536,7 @@ async fn auth_google_callback(
         state.session_ttl.as_secs(),
         state.cookie_secure,
         state.cookie_same_site.as_str(),
+        state.cookie_partitioned,
         state.cookie_domain.as_str(),
     );
     if let Ok(header_value) = HeaderValue::from_str(&cookie_value) {
@@ -1281,40 +2547,85 @@ async fn auth_google_callback(
     response
 }
 
+async fn auth_link_start(
+    State(state): State<ApiState>,
+    headers: HeaderMap,
+    AxumPath(provider): AxumPath<String>,
+    Query(query): Query<AuthLinkStartQuery>,
+) -> Response {
+    let (Some(user), Some(session_id)) = (
+        session_user_from_headers(&state, &headers),
+        read_cookie_value(&headers, &state.cookie_name),
+    ) else {
+        return ApiError::unauthorized(
+            "not_authenticated",
+            "sign in before linking another provider",
+        )
+        .into_response();
+    };
+    let return_to = query.return_to.as_deref();
+    match provider.trim().to_lowercase().as_str() {
+        "google" => {
+            begin_google_oauth(&state, return_to, Some(user.user_id), Some(session_id)).await
+        }
+        "apple" => begin_apple_oauth(&state, return_to, Some(user.user_id), Some(session_id)).await,
+        _ => ApiError::bad_request("unsupported_provider", "provider must be google or apple")
+            .into_response(),
+    }
+}
+
 async fn auth_apple_start(
     State(state): State<ApiState>,
     Query(query): Query<AppleOAuthStartQuery>,
 ) -> impl IntoResponse {
+    begin_apple_oauth(&state, query.return_to.as_deref(), None, None).await
+}
+
+async fn begin_apple_oauth(
+    state: &ApiState,
+    return_to: Option<&str>,
+    link_user_id: Option<String>,
+    link_session_id: Option<String>,
+) -> Response {
     let Some(config) = state.apple_oauth.as_ref() else {
-        return (
-            StatusCode::SERVICE_UNAVAILABLE,
-            Json(serde_json::json!({
-                "error": "oauth_unavailable",
-                "message": "Apple Sign In is not configured"
-            })),
+        return ApiError::service_unavailable(
+            "oauth_unavailable",
+            "Apple Sign In is not configured",
         )
-            .into_response();
+        .into_response();
     };
 
     let state_token = generate_urlsafe_token(24);
     let nonce = generate_urlsafe_token(24);
-    let return_to = sanitize_return_to(
-        query
-            .return_to
-            .as_deref()
-            .unwrap_or("/concierge-local.html"),
-    );
+    let return_to = sanitize_return_to(return_to.unwrap_or(DEFAULT_RETURN_TO));
+
+    let challenge = OAuthStateRecord {
+        provider: "apple".to_string(),
+        code_verifier: None,
+        nonce: Some(nonce.clone()),
+        return_to,
+        link_user_id,
+        link_session_id,
+        expires_at: chrono::Utc::now() + chrono::Duration::minutes(12),
+    };
 
-    state.oauth_states.write().insert(
-        state_token.clone(),
-        OAuthStateRecord {
-            provider: "apple".to_string(),
-            code_verifier: None,
-            nonce: Some(nonce.clone()),
-            return_to,
-            expires_at: chrono::Utc::now() + chrono::Duration::minutes(12),
-        },
-    );
+    if stash_shared_challenge(
+        state,
+        CHALLENGE_OAUTH,
+        &state_token,
+        &challenge,
+        challenge.expires_at,
+    )
+    .await
+    .is_err()
+    {
+        return shared_challenge_unavailable_response();
+    }
+
+    state
+        .oauth_states
+        .write()
+        .insert(state_token.clone(), challenge);
 
     let authorize_url = format!(
         "https://appleid.apple.com/auth/authorize?client_id={}&redirect_uri={}&response_type=code&response_mode=form_post&scope={}&state={}&nonce={}",
@@ -1336,71 +2647,93 @@ async fn auth_apple_start(
 
 async fn auth_apple_callback_get(
     State(state): State<ApiState>,
+    connect_info: Option<ConnectInfo<std::net::SocketAddr>>,
+    headers: HeaderMap,
     Query(query): Query<AppleOAuthCallbackQuery>,
 ) -> impl IntoResponse {
-    auth_apple_callback_inner(state, query).await
+    let peer = connect_info.map(|ConnectInfo(addr)| addr.ip());
+    auth_apple_callback_inner(state, peer, headers, query).await
 }
 
 async fn auth_apple_callback_post(
     State(state): State<ApiState>,
+    connect_info: Option<ConnectInfo<std::net::SocketAddr>>,
+    headers: HeaderMap,
     Form(form): Form<AppleOAuthCallbackQuery>,
 ) -> impl IntoResponse {
-    auth_apple_callback_inner(state, form).await
+    let peer = connect_info.map(|ConnectInfo(addr)| addr.ip());
+    auth_apple_callback_inner(state, peer, headers, form).await
 }
 
-async fn auth_apple_callback_inner(state: ApiState, query: AppleOAuthCallbackQuery) -> Response {
+async fn auth_apple_callback_inner(
+    state: ApiState,
+    peer: Option<std::net::IpAddr>,
+    headers: HeaderMap,
+    query: AppleOAuthCallbackQuery,
+) -> Response {
     let Some(config) = state.apple_oauth.as_ref() else {
         return Redirect::to("/").into_response();
     };
+    let request_id = request_id_from_headers(&headers);
 
     if let Some(error) = query.error.as_ref() {
-        let target = format!(
-            "{}{}?auth=error&reason={}",
-            config.frontend_origin,
+        return auth_failure_redirect(
+            "apple",
+            request_id.as_str(),
+            config.frontend_origin.as_str(),
             "/concierge-local.html",
-            pct_encode(query.error_description.as_deref().unwrap_or(error.as_str()))
+            pct_encode(query.error_description.as_deref().unwrap_or(error.as_str())).as_str(),
         );
-        return Redirect::to(target.as_str()).into_response();
     }
 
     let Some(state_token) = query.state.as_deref() else {
-        let target = format!(
-            "{}{}?auth=error&reason=missing_state",
-            config.frontend_origin, "/concierge-local.html"
+        return auth_failure_redirect(
+            "apple",
+            request_id.as_str(),
+            config.frontend_origin.as_str(),
+            "/concierge-local.html",
+            "missing_state",
         );
-        return Redirect::to(target.as_str()).into_response();
     };
 
-    let Some(pending) = state.oauth_states.write().remove(state_token) else {
-        let target = format!(
-            "{}{}?auth=error&reason=invalid_state",
-            config.frontend_origin, "/concierge-local.html"
+    let pending = state.oauth_states.write().remove(state_token);
+    let Some(pending) = take_shared_challenge(&state, CHALLENGE_OAUTH, state_token, pending).await
+    else {
+        return auth_failure_redirect(
+            "apple",
+            request_id.as_str(),
+            config.frontend_origin.as_str(),
+            "/concierge-local.html",
+            "invalid_state",
         );
-        return Redirect::to(target.as_str()).into_response();
     };
     if pending.expires_at <= chrono::Utc::now() {
-        let target = format!(
-            "{}{}?auth=error&reason=state_expired",
-            config.frontend_origin, "/concierge-local.html"
+        return auth_failure_redirect(
+            "apple",
+            request_id.as_str(),
+            config.frontend_origin.as_str(),
+            "/concierge-local.html",
+            "state_expired",
         );
-        return Redirect::to(target.as_str()).into_response();
     }
     if pending.provider != "apple" {
-        let target = format!(
-            "{}{}?auth=error&reason=provider_mismatch",
-            config.frontend_origin,
-            pending.return_to.as_str()
+        return auth_failure_redirect(
+            "apple",
+            request_id.as_str(),
+            config.frontend_origin.as_str(),
+            pending.return_to.as_str(),
+            "provider_mismatch",
         );
-        return Redirect::to(target.as_str()).into_response();
     }
 
     let Some(code) = query.code.as_deref() else {
-        let target = format!(
-            "{}{}?auth=error&reason=missing_code",
-            config.frontend_origin,
-            pending.return_to.as_str()
+        return auth_failure_redirect(
+            "apple",
+            request_id.as_str(),
+            config.frontend_origin.as_str(),
+            pending.return_to.as_str(),
+            "missing_code",
         );
-        return Redirect::to(target.as_str()).into_response();
     };
 
     let token = match state
@@ -1420,31 +2753,33 @@ async fn auth_apple_callback_inner(state: ApiState, query: AppleOAuthCallbackQue
             match response.json::<AppleTokenResponse>().await {
                 Ok(payload) => payload,
                 Err(_) => {
-                    let target = format!(
-                        "{}{}?auth=error&reason=token_parse_failed",
-                        config.frontend_origin,
-                        pending.return_to.as_str()
+                    return auth_failure_redirect(
+                        "apple",
+                        request_id.as_str(),
+                        config.frontend_origin.as_str(),
+                        pending.return_to.as_str(),
+                        "token_parse_failed",
                     );
-                    return Redirect::to(target.as_str()).into_response();
                 }
             }
         }
         Ok(response) => {
-            let target = format!(
-                "{}{}?auth=error&reason=token_exchange_failed_{}",
-                config.frontend_origin,
+            return auth_failure_redirect(
+                "apple",
+                request_id.as_str(),
+                config.frontend_origin.as_str(),
                 pending.return_to.as_str(),
-                response.status().as_u16()
+                format!("token_exchange_failed_{}", response.status().as_u16()).as_str(),
             );
-            return Redirect::to(target.as_str()).into_response();
         }
         Err(_) => {
-            let target = format!(
-                "{}{}?auth=error&reason=token_exchange_network_failed",
-                config.frontend_origin,
-                pending.return_to.as_str()
+            return auth_failure_redirect(
+                "apple",
+                request_id.as_str(),
+                config.frontend_origin.as_str(),
+                pending.return_to.as_str(),
+                "token_exchange_network_failed",
             );
-            return Redirect::to(target.as_str()).into_response();
         }
     };
 
@@ -1457,12 +2792,13 @@ async fn auth_apple_callback_inner(state: ApiState, query: AppleOAuthCallbackQue
     {
         Ok(value) => value,
         Err(_) => {
-            let target = format!(
-                "{}{}?auth=error&reason=id_token_verification_failed",
-                config.frontend_origin,
-                pending.return_to.as_str()
+            return auth_failure_redirect(
+                "apple",
+                request_id.as_str(),
+                config.frontend_origin.as_str(),
+                pending.return_to.as_str(),
+                "id_token_verification_failed",
             );
-            return Redirect::to(target.as_str()).into_response();
         }
     };
 
@@ -1472,41 +2808,45 @@ async fn auth_apple_callback_inner(state: ApiState, query: AppleOAuthCallbackQue
         .map(|aud| aud.includes(config.client_id.as_str()))
         .unwrap_or(false)
     {
-        let target = format!(
-            "{}{}?auth=error&reason=invalid_audience",
-            config.frontend_origin,
-            pending.return_to.as_str()
+        return auth_failure_redirect(
+            "apple",
+            request_id.as_str(),
+            config.frontend_origin.as_str(),
+            pending.return_to.as_str(),
+            "invalid_audience",
         );
-        return Redirect::to(target.as_str()).into_response();
     }
 
     if claims.iss.as_deref() != Some("https://appleid.apple.com") {
-        let target = format!(
-            "{}{}?auth=error&reason=invalid_issuer",
-            config.frontend_origin,
-            pending.return_to.as_str()
+        return auth_failure_redirect(
+            "apple",
+            request_id.as_str(),
+            config.frontend_origin.as_str(),
+            pending.return_to.as_str(),
+            "invalid_issuer",
         );
-        return Redirect::to(target.as_str()).into_response();
     }
 
     let now_ts = chrono::Utc::now().timestamp();
     if claims.exp.unwrap_or(0) <= now_ts {
-        let target = format!(
-            "{}{}?auth=error&reason=id_token_expired",
-            config.frontend_origin,
-            pending.return_to.as_str()
+        return auth_failure_redirect(
+            "apple",
+            request_id.as_str(),
+            config.frontend_origin.as_str(),
+            pending.return_to.as_str(),
+            "id_token_expired",
         );
-        return Redirect::to(target.as_str()).into_response();
     }
 
     if let Some(expected_nonce) = pending.nonce.as_deref() {
         if claims.nonce.as_deref() != Some(expected_nonce) {
-            let target = format!(
-                "{}{}?auth=error&reason=nonce_mismatch",
-                config.frontend_origin,
-                pending.return_to.as_str()
+            return auth_failure_redirect(
+                "apple",
+                request_id.as_str(),
+                config.frontend_origin.as_str(),
+                pending.return_to.as_str(),
+                "nonce_mismatch",
             );
-            return Redirect::to(target.as_str()).into_response();
         }
     }
 
@@ -1515,12 +2855,13 @@ async fn auth_apple_callback_inner(state: ApiState, query: AppleOAuthCallbackQue
         .as_deref()
         .map(|value| value.trim().to_lowercase())
     else {
-        let target = format!(
-            "{}{}?auth=error&reason=missing_email",
-            config.frontend_origin,
-            pending.return_to.as_str()
+        return auth_failure_redirect(
+            "apple",
+            request_id.as_str(),
+            config.frontend_origin.as_str(),
+            pending.return_to.as_str(),
+            "missing_email",
         );
-        return Redirect::to(target.as_str()).into_response();
     };
     let verified = claims
         .email_verified
@@ -1528,25 +2869,53 @@ async fn auth_apple_callback_inner(state: ApiState, query: AppleOAuthCallbackQue
         .and_then(bool_from_jsonish)
         .unwrap_or(false);
     if !verified {
-        let target = format!(
-            "{}{}?auth=error&reason=email_not_verified",
-            config.frontend_origin,
-            pending.return_to.as_str()
+        return auth_failure_redirect(
+            "apple",
+            request_id.as_str(),
+            config.frontend_origin.as_str(),
+            pending.return_to.as_str(),
+            "email_not_verified",
+        );
+    }
+
+    let Some(subject) = claims.sub.clone().filter(|value| !value.trim().is_empty()) else {
+        return auth_failure_redirect(
+            "apple",
+            request_id.as_str(),
+            config.frontend_origin.as_str(),
+            pending.return_to.as_str(),
+            "missing_subject",
         );
-        return Redirect::to(target.as_str()).into_response();
+    };
+    let identity = ProviderIdentity {
+        provider: "apple",
+        subject,
+        email,
+        email_verified: verified,
+    };
+    if pending.link_user_id.is_some() {
+        return complete_provider_link(
+            &state,
+            &headers,
+            &pending,
+            identity,
+            request_id.as_str(),
+            config.frontend_origin.as_str(),
+        )
+        .await;
     }
 
-    let display_name = email
+    let display_name = identity
+        .email
         .split('@')
         .next()
         .unwrap_or("Atlas/אטלס User")
         .trim()
         .to_string();
     let now = chrono::Utc::now().to_rfc3339();
-    let user = find_or_create_user_by_email(
+    let mut user = find_or_create_user_by_email(
         &state,
-        "apple",
-        email,
+        &identity,
         if display_name.is_empty() {
             "Atlas/אטלס User".to_string()
         } else {
@@ -1557,17 +2926,26 @@ async fn auth_apple_callback_inner(state: ApiState, query: AppleOAuthCallbackQue
     )
     .await;
 
-    let session_id = match issue_session_for_user(&state, &user).await {
+    let session_id = match issue_session_for_user(&state, &mut user, peer, &headers).await {
         Ok(value) => value,
         Err(_) => {
-            let target = format!(
-                "{}{}?auth=error&reason=session_issue_failed",
-                config.frontend_origin,
-                pending.return_to.as_str()
+            return auth_failure_redirect(
+                "apple",
+                request_id.as_str(),
+                config.frontend_origin.as_str(),
+                pending.return_to.as_str(),
+                "session_issue_failed",
             );
-            return Redirect::to(target.as_str()).into_response();
         }
     };
+    log_auth_event(
+        "auth.login",
+        "success",
+        "apple",
+        request_id.as_str(),
+        Some(&user),
+        None,
+    );
 
     let target = format!(
         "{}{}?auth=success",
@@ -1581,6 +2959,7 @@ async fn auth_apple_callback_inner(state: ApiState, query: AppleOAuthCallbackQue
         state.session_ttl.as_secs(),
         state.cookie_secure,
         state.cookie_same_site.as_str(),
+        state.cookie_partitioned,
         state.cookie_domain.as_str(),
     );
     if let Ok(header_value) = HeaderValue::from_str(&cookie_value) {
@@ -1597,14 +2976,11 @@ async fn auth_passkey_register_start(
     Json(input): Json<PasskeyRegistrationStartRequest>,
 ) -> impl IntoResponse {
     let Some(runtime) = state.webauthn_runtime.as_ref() else {
-        return (
-            StatusCode::SERVICE_UNAVAILABLE,
-            Json(serde_json::json!({
-                "error": "passkey_unavailable",
-                "message": "Passkey auth is not configured"
-            })),
+        return ApiError::service_unavailable(
+            "passkey_unavailable",
+            "Passkey auth is not configured",
         )
-            .into_response();
+        .into_response();
     };
 
     let requested_email = input
@@ -1623,9 +2999,19 @@ async fn auth_passkey_register_start(
         existing
     } else {
         let email = requested_email.unwrap_or_else(|| {
-            format!("passkey-{}@atlasmasa.local", uuid::Uuid::new_v4().simple())
+            format!(
+                "passkey-{}{}",
+                uuid::Uuid::new_v4().simple(),
+                PLACEHOLDER_EMAIL_DOMAIN
+            )
         });
-        find_or_create_user_by_email(&state, "passkey", email, display_name, locale, now).await
+        let identity = ProviderIdentity {
+            provider: "passkey",
+            subject: String::new(),
+            email,
+            email_verified: false,
+        };
+        find_or_create_user_by_email(&state, &identity, display_name, locale, now).await
     };
 
     if user.passkey_user_handle.is_none() {
@@ -1654,74 +3040,99 @@ async fn auth_passkey_register_start(
     let (creation_response, registration_state) = match registration {
         Ok(value) => value,
         Err(error) => {
-            return (
-                StatusCode::BAD_REQUEST,
-                Json(serde_json::json!({
-                    "error": "passkey_registration_start_failed",
-                    "message": error.to_string()
-                })),
-            )
+            return ApiError::bad_request("passkey_registration_start_failed", error.to_string())
                 .into_response()
         }
     };
 
     let request_id = uuid::Uuid::new_v4().to_string();
-    state.passkey_registrations.write().insert(
-        request_id.clone(),
-        PasskeyRegistrationStateRecord {
-            user_id: user.user_id.clone(),
-            state: registration_state,
-            expires_at: chrono::Utc::now() + chrono::Duration::minutes(10),
-        },
-    );
-
-    (
-        StatusCode::OK,
-        Json(PasskeyRegistrationStartResponse {
-            request_id,
-            options: serde_json::to_value(creation_response)
-                .unwrap_or_else(|_| serde_json::json!({})),
-        }),
-    )
+    let challenge = PasskeyRegistrationStateRecord {
+        user_id: user.user_id.clone(),
+        state: registration_state,
+        expires_at: chrono::Utc::now() + chrono::Duration::minutes(10),
+    };
+    if stash_shared_challenge(
+        &state,
+        CHALLENGE_PASSKEY_REGISTRATION,
+        &request_id,
+        &challenge,
+        challenge.expires_at,
+    )
+    .await
+    .is_err()
+    {
+        return shared_challenge_unavailable_response();
+    }
+    state
+        .passkey_registrations
+        .write()
+        .insert(request_id.clone(), challenge);
+
+    (
+        StatusCode::OK,
+        Json(PasskeyRegistrationStartResponse {
+            request_id,
+            options: apply_webauthn_registration_policy(
+                serde_json::to_value(creation_response).unwrap_or_else(|_| serde_json::json!({})),
+                runtime,
+            ),
+        }),
+    )
         .into_response()
 }
 
 async fn auth_passkey_register_finish(
     State(state): State<ApiState>,
+    headers: HeaderMap,
     Json(input): Json<PasskeyRegistrationFinishRequest>,
 ) -> impl IntoResponse {
+    let request_id = request_id_from_headers(&headers);
     let Some(runtime) = state.webauthn_runtime.as_ref() else {
-        return (
-            StatusCode::SERVICE_UNAVAILABLE,
-            Json(serde_json::json!({
-                "error": "passkey_unavailable"
-            })),
+        return ApiError::service_unavailable(
+            "passkey_unavailable",
+            "passkey sign-in is not configured",
         )
-            .into_response();
+        .into_response();
     };
 
-    let Some(pending) = state
+    let pending = state
         .passkey_registrations
         .write()
-        .remove(input.request_id.as_str())
+        .remove(input.request_id.as_str());
+    let Some(pending) = take_shared_challenge(
+        &state,
+        CHALLENGE_PASSKEY_REGISTRATION,
+        input.request_id.as_str(),
+        pending,
+    )
+    .await
     else {
-        return (
-            StatusCode::BAD_REQUEST,
-            Json(serde_json::json!({
-                "error": "invalid_request_id"
-            })),
-        )
+        log_auth_event(
+            "auth.passkey_register",
+            "failure",
+            "passkey",
+            request_id.as_str(),
+            None,
+            Some("invalid_request_id"),
+        );
+        return ApiError::bad_request("invalid_request_id", "unknown or already used request_id")
             .into_response();
     };
 
     if pending.expires_at <= chrono::Utc::now() {
-        return (
-            StatusCode::BAD_REQUEST,
-            Json(serde_json::json!({
-                "error": "request_expired"
-            })),
+        log_auth_event(
+            "auth.passkey_register",
+            "failure",
+            "passkey",
+            request_id.as_str(),
+            None,
+            Some("request_expired"),
+        );
+        return ApiError::bad_request(
+            "request_expired",
+            "the passkey request expired; start again",
         )
-            .into_response();
+        .into_response();
     }
 
     let credential = match runtime
@@ -1730,14 +3141,16 @@ async fn auth_passkey_register_finish(
     {
         Ok(value) => value,
         Err(error) => {
-            return (
-                StatusCode::BAD_REQUEST,
-                Json(serde_json::json!({
-                    "error": "passkey_registration_finish_failed",
-                    "message": error.to_string()
-                })),
-            )
-                .into_response()
+            log_auth_event(
+                "auth.passkey_register",
+                "failure",
+                "passkey",
+                request_id.as_str(),
+                None,
+                Some("passkey_registration_finish_failed"),
+            );
+            return ApiError::bad_request("passkey_registration_finish_failed", error.to_string())
+                .into_response();
         }
     };
 
@@ -1755,6 +3168,15 @@ async fn auth_passkey_register_finish(
         .or_default()
         .push(entry.clone());
     let _ = persist_passkeys_if_configured(&state, pending.user_id.as_str()).await;
+    let registered_user = state.users.read().get(&pending.user_id).cloned();
+    log_auth_event(
+        "auth.passkey_register",
+        "success",
+        "passkey",
+        request_id.as_str(),
+        registered_user.as_ref(),
+        None,
+    );
 
     (
         StatusCode::OK,
@@ -1768,16 +3190,16 @@ async fn auth_passkey_register_finish(
 
 async fn auth_passkey_login_start(
     State(state): State<ApiState>,
+    connect_info: Option<ConnectInfo<std::net::SocketAddr>>,
+    headers: HeaderMap,
     Json(input): Json<PasskeyLoginStartRequest>,
 ) -> impl IntoResponse {
     let Some(runtime) = state.webauthn_runtime.as_ref() else {
-        return (
-            StatusCode::SERVICE_UNAVAILABLE,
-            Json(serde_json::json!({
-                "error": "passkey_unavailable"
-            })),
+        return ApiError::service_unavailable(
+            "passkey_unavailable",
+            "passkey sign-in is not configured",
         )
-            .into_response();
+        .into_response();
     };
 
     let requested_email = input
@@ -1786,33 +3208,44 @@ async fn auth_passkey_login_start(
         .map(|value| value.trim().to_lowercase())
         .filter(|value| !value.is_empty());
 
+    // Looking up by email is the enumeration-prone path, so it gets its own tighter limiter and
+    // answers unknown emails with a decoy challenge shaped like a real one.
+    let mut decoy_email = None;
+    let login_email = requested_email.clone();
     let (user_id, passkeys) = if let Some(email) = requested_email {
-        let Some(user) = state
+        let peer = connect_info.map(|ConnectInfo(addr)| addr.ip());
+        let ip = resolve_client_ip(peer, &headers, &state.trusted_proxies)
+            .map(|ip| ip.to_string())
+            .unwrap_or_else(|| "local".to_string());
+        if !state
+            .passkey_email_limiter
+            .allow(&format!("passkey_email:{ip}"))
+            .await
+        {
+            return ApiError::too_many_requests(
+                "auth_rate_limited",
+                "too many authentication attempts from this IP. wait and retry.",
+            )
+            .into_response();
+        }
+        load_shared_accounts_by_email(&state, email.as_str()).await;
+        let user = state
             .users
             .read()
             .values()
             .find(|value| value.email == email)
-            .cloned()
-        else {
-            return (
-                StatusCode::NOT_FOUND,
-                Json(serde_json::json!({
-                    "error": "user_not_found"
-                })),
-            )
-                .into_response();
-        };
-
-        let passkeys = state
-            .passkeys_by_user
-            .read()
-            .get(&user.user_id)
-            .cloned()
+            .cloned();
+        let passkeys = user
+            .as_ref()
+            .and_then(|user| state.passkeys_by_user.read().get(&user.user_id).cloned())
             .unwrap_or_default()
             .into_iter()
             .map(|entry| entry.credential)
             .collect::<Vec<_>>();
-        (Some(user.user_id), passkeys)
+        if passkeys.is_empty() {
+            decoy_email = Some(email);
+        }
+        (user.map(|user| user.user_id), passkeys)
     } else {
         let passkeys = state
             .passkeys_by_user
@@ -1823,14 +3256,11 @@ async fn auth_passkey_login_start(
         (None, passkeys)
     };
 
+    if let Some(email) = decoy_email {
+        return start_decoy_passkey_authentication(&state, runtime, email.as_str()).await;
+    }
     if passkeys.is_empty() {
-        return (
-            StatusCode::BAD_REQUEST,
-            Json(serde_json::json!({
-                "error": "no_passkeys_registered"
-            })),
-        )
-            .into_response();
+        return no_passkeys_registered_response();
     }
 
     let authentication = runtime
@@ -1839,129 +3269,300 @@ async fn auth_passkey_login_start(
     let (request, auth_state) = match authentication {
         Ok(value) => value,
         Err(error) => {
-            return (
-                StatusCode::BAD_REQUEST,
-                Json(serde_json::json!({
-                    "error": "passkey_login_start_failed",
-                    "message": error.to_string()
-                })),
-            )
+            return ApiError::bad_request("passkey_login_start_failed", error.to_string())
                 .into_response()
         }
     };
 
     let request_id = uuid::Uuid::new_v4().to_string();
-    state.passkey_authentications.write().insert(
-        request_id.clone(),
-        PasskeyAuthenticationStateRecord {
-            user_id,
-            state: auth_state,
-            expires_at: chrono::Utc::now() + chrono::Duration::minutes(8),
-        },
-    );
+    let challenge = PasskeyAuthenticationStateRecord {
+        user_id,
+        state: auth_state,
+        decoy: false,
+        email: login_email,
+        expires_at: chrono::Utc::now() + chrono::Duration::minutes(8),
+    };
+    if stash_shared_challenge(
+        &state,
+        CHALLENGE_PASSKEY_AUTHENTICATION,
+        &request_id,
+        &challenge,
+        challenge.expires_at,
+    )
+    .await
+    .is_err()
+    {
+        return shared_challenge_unavailable_response();
+    }
+    state
+        .passkey_authentications
+        .write()
+        .insert(request_id.clone(), challenge);
 
     (
         StatusCode::OK,
         Json(PasskeyLoginStartResponse {
             request_id,
-            options: serde_json::to_value(request).unwrap_or_else(|_| serde_json::json!({})),
+            options: apply_webauthn_login_policy(
+                serde_json::to_value(request).unwrap_or_else(|_| serde_json::json!({})),
+                runtime,
+            ),
         }),
     )
         .into_response()
 }
 
-async fn auth_passkey_login_finish(
+async fn auth_recovery_codes_generate(
     State(state): State<ApiState>,
-    Json(input): Json<PasskeyLoginFinishRequest>,
+    headers: HeaderMap,
+    input: Option<Json<RecoveryCodesGenerateRequest>>,
 ) -> impl IntoResponse {
-    let Some(runtime) = state.webauthn_runtime.as_ref() else {
-        return (
-            StatusCode::SERVICE_UNAVAILABLE,
-            Json(serde_json::json!({
-                "error": "passkey_unavailable"
-            })),
+    let request_id = request_id_from_headers(&headers);
+    let Some(user) = session_user_from_headers(&state, &headers) else {
+        return ApiError::unauthorized(
+            "not_authenticated",
+            "sign in before generating recovery codes",
         )
-            .into_response();
+        .into_response();
     };
+    let count = input
+        .and_then(|Json(value)| value.count)
+        .unwrap_or(DEFAULT_RECOVERY_CODE_COUNT)
+        .clamp(1, MAX_RECOVERY_CODE_COUNT);
 
-    let Some(pending) = state
-        .passkey_authentications
-        .write()
-        .remove(input.request_id.as_str())
-    else {
-        return (
-            StatusCode::BAD_REQUEST,
-            Json(serde_json::json!({
-                "error": "invalid_request_id"
-            })),
+    let codes = (0..count)
+        .map(|_| generate_recovery_code())
+        .collect::<Vec<_>>();
+    let to_hash = codes.clone();
+    let key = state.recovery_code_key.clone();
+    let hashes = tokio::task::spawn_blocking(move || {
+        to_hash
+            .iter()
+            .map(|code| {
+                Some((
+                    hash_recovery_code(code)?,
+                    fingerprint_recovery_code(key.as_str(), code)?,
+                ))
+            })
+            .collect::<Option<Vec<_>>>()
+    })
+    .await
+    .ok()
+    .flatten();
+    let Some(hashes) = hashes else {
+        return ApiError::internal(
+            "recovery_code_generation_failed",
+            "could not hash recovery codes",
         )
-            .into_response();
+        .into_response();
     };
 
-    if pending.expires_at <= chrono::Utc::now() {
-        return (
-            StatusCode::BAD_REQUEST,
-            Json(serde_json::json!({
-                "error": "request_expired"
-            })),
-        )
+    // Generating a new set always revokes the previous one.
+    let now = chrono::Utc::now().to_rfc3339();
+    let records = hashes
+        .into_iter()
+        .map(|(code_hash, code_fingerprint)| RecoveryCodeRecord {
+            code_id: uuid::Uuid::new_v4().to_string(),
+            code_hash,
+            code_fingerprint,
+            created_at: now.clone(),
+            used_at: None,
+        })
+        .collect::<Vec<_>>();
+    let previous = state
+        .recovery_codes
+        .write()
+        .insert(user.user_id.clone(), records);
+    // Codes the database never saw would stop working after a restart while the old set kept
+    // working, so nothing is shown unless the new set is stored.
+    if let Err(error) = persist_recovery_codes_if_configured(&state, user.user_id.as_str()).await {
+        {
+            let mut stored = state.recovery_codes.write();
+            match previous {
+                Some(previous) => stored.insert(user.user_id.clone(), previous),
+                None => stored.remove(&user.user_id),
+            };
+        }
+        log_auth_event(
+            "auth.recovery_codes_generate",
+            "failure",
+            "recovery_code",
+            request_id.as_str(),
+            Some(&user),
+            Some("recovery_code_persist_failed"),
+        );
+        return ApiError::internal("recovery_code_persist_failed", error.to_string())
             .into_response();
     }
+    log_auth_event(
+        "auth.recovery_codes_generate",
+        "success",
+        "recovery_code",
+        request_id.as_str(),
+        Some(&user),
+        None,
+    );
 
-    let auth_result: AuthenticationResult = match runtime
-        .webauthn
-        .finish_passkey_authentication(&input.credential, &pending.state)
+    let mut response = (
+        StatusCode::OK,
+        Json(serde_json::json!({
+            "ok": true,
+            "count": codes.len(),
+            "codes": codes,
+            "generated_at": now
+        })),
+    )
+        .into_response();
+    response
+        .headers_mut()
+        .insert(header::CACHE_CONTROL, HeaderValue::from_static("no-store"));
+    response
+}
+
+async fn auth_recovery_codes_redeem(
+    State(state): State<ApiState>,
+    connect_info: Option<ConnectInfo<std::net::SocketAddr>>,
+    headers: HeaderMap,
+    Json(input): Json<RecoveryCodeRedeemRequest>,
+) -> impl IntoResponse {
+    let peer = connect_info.map(|ConnectInfo(addr)| addr.ip());
+    let request_id = request_id_from_headers(&headers);
+    let email = input.email.trim().to_lowercase();
+    if let Some(remaining) = state
+        .login_email_lockouts
+        .locked_for(email.as_str(), Instant::now())
     {
-        Ok(value) => value,
-        Err(error) => {
-            return (
-                StatusCode::UNAUTHORIZED,
-                Json(serde_json::json!({
-                    "error": "passkey_authentication_failed",
-                    "message": error.to_string()
-                })),
-            )
-                .into_response()
-        }
+        return login_locked_response(remaining);
+    }
+    // Codes are found by their keyed fingerprint, so a request costs exactly one argon2 run
+    // whether or not the email exists or has codes (a throwaway hash stands in when nothing
+    // matches).
+    let fingerprint = fingerprint_recovery_code(state.recovery_code_key.as_str(), &input.code);
+    load_shared_accounts_by_email(&state, email.as_str()).await;
+    let candidates = {
+        let user_ids = if is_placeholder_email(email.as_str()) {
+            Vec::new()
+        } else {
+            state
+                .user_ids_by_email
+                .read()
+                .get(email.as_str())
+                .cloned()
+                .unwrap_or_default()
+        };
+        let codes = state.recovery_codes.read();
+        user_ids
+            .into_iter()
+            .flat_map(|user_id| {
+                codes
+                    .get(&user_id)
+                    .into_iter()
+                    .flatten()
+                    .filter(|record| {
+                        record.used_at.is_none()
+                            && fingerprint.as_deref() == Some(record.code_fingerprint.as_str())
+                    })
+                    .map(move |record| {
+                        (
+                            user_id.clone(),
+                            record.code_id.clone(),
+                            record.code_hash.clone(),
+                        )
+                    })
+            })
+            .collect::<Vec<_>>()
     };
-    let resolved_user_id = pending.user_id.or_else(|| {
-        resolve_user_id_for_passkey_credential(&state, auth_result.cred_id().as_slice())
+
+    let code = input.code;
+    let matched = tokio::task::spawn_blocking(move || {
+        if candidates.is_empty() {
+            let _ = hash_recovery_code(code.as_str());
+            return None;
+        }
+        candidates
+            .into_iter()
+            .find(|(_, _, code_hash)| verify_recovery_code(code.as_str(), code_hash.as_str()))
+            .map(|(user_id, code_id, _)| (user_id, code_id))
+    })
+    .await
+    .ok()
+    .flatten();
+
+    // Marking the code used under the write lock makes a concurrent second redeem fail.
+    let redeemed = matched.filter(|(user_id, code_id)| {
+        set_recovery_code_used(
+            &state,
+            user_id,
+            code_id,
+            Some(chrono::Utc::now().to_rfc3339()),
+        )
     });
-    let Some(user_id) = resolved_user_id else {
-        return (
-            StatusCode::NOT_FOUND,
-            Json(serde_json::json!({
-                "error": "user_not_found"
-            })),
+    let Some(mut user) = redeemed
+        .as_ref()
+        .and_then(|(user_id, _)| state.users.read().get(user_id).cloned())
+    else {
+        log_auth_event(
+            "auth.login",
+            "failure",
+            "recovery_code",
+            request_id.as_str(),
+            None,
+            Some("invalid_recovery_code"),
+        );
+        if let Some(response) = count_login_attempt_for_email(
+            &state,
+            email.as_str(),
+            "recovery_code",
+            request_id.as_str(),
+        ) {
+            return response;
+        }
+        return ApiError::unauthorized(
+            "invalid_recovery_code",
+            "recovery code is invalid or already used",
         )
-            .into_response();
+        .into_response();
     };
-    let Some(user) = state.users.read().get(&user_id).cloned() else {
-        return (
-            StatusCode::NOT_FOUND,
-            Json(serde_json::json!({
-                "error": "user_not_found"
-            })),
-        )
+    // A used code that never reaches the database would redeem again after a restart.
+    if let Err(error) = persist_recovery_codes_if_configured(&state, user.user_id.as_str()).await {
+        if let Some((user_id, code_id)) = redeemed.as_ref() {
+            set_recovery_code_used(&state, user_id, code_id, None);
+        }
+        log_auth_event(
+            "auth.login",
+            "failure",
+            "recovery_code",
+            request_id.as_str(),
+            Some(&user),
+            Some("recovery_code_persist_failed"),
+        );
+        return ApiError::internal("recovery_code_persist_failed", error.to_string())
             .into_response();
-    };
+    }
 
-    let session_id = match issue_session_for_user(&state, &user).await {
+    let session_id = match issue_session_for_user(&state, &mut user, peer, &headers).await {
         Ok(value) => value,
         Err(error) => {
-            return (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                Json(serde_json::json!({
-                    "error": "session_issue_failed",
-                    "message": error.to_string()
-                })),
-            )
-                .into_response()
+            log_auth_event(
+                "auth.login",
+                "failure",
+                "recovery_code",
+                request_id.as_str(),
+                Some(&user),
+                Some("session_issue_failed"),
+            );
+            return ApiError::internal("session_issue_failed", error.to_string()).into_response();
         }
     };
-
-    update_passkey_credential_usage(&state, user.user_id.as_str(), &auth_result);
-    let _ = persist_passkeys_if_configured(&state, user.user_id.as_str()).await;
+    state.login_email_lockouts.reset(user.email.as_str());
+    log_auth_event(
+        "auth.login",
+        "success",
+        "recovery_code",
+        request_id.as_str(),
+        Some(&user),
+        None,
+    );
 
     let token = format!("session-{}", session_id);
     let mut response = (
@@ -1981,6 +3582,7 @@ async fn auth_passkey_login_finish(
         state.session_ttl.as_secs(),
         state.cookie_secure,
         state.cookie_same_site.as_str(),
+        state.cookie_partitioned,
         state.cookie_domain.as_str(),
     );
     if let Ok(header_value) = HeaderValue::from_str(&cookie_value) {
@@ -1991,55 +3593,441 @@ async fn auth_passkey_login_finish(
     response
 }
 
-async fn chat(
-    State(state): State<ApiState>,
-    headers: HeaderMap,
-    Json(mut request): Json<ChatRequest>,
-) -> impl IntoResponse {
-    let session_user = session_user_from_headers(&state, &headers);
-    if let Some(user) = session_user.as_ref() {
-        request.user_id = Some(user.user_id.clone());
-    }
-    let request_user_id = request.user_id.clone();
-    let include_proactive = request.include_proactive.unwrap_or(true);
-    if let Some(user_id) = session_user
-        .as_ref()
-        .map(|user| user.user_id.clone())
-        .or(request_user_id.clone())
+// webauthn-rs passkey registration always requires user verification and verifies whatever
+// attestation statement comes back (without a CA list), so only the conveyance preference is
+// rewritten here.
+fn apply_webauthn_registration_policy(
+    mut options: serde_json::Value,
+    runtime: &WebauthnRuntimeConfig,
+) -> serde_json::Value {
+    if let Some(public_key) = options
+        .get_mut("publicKey")
+        .and_then(|value| value.as_object_mut())
     {
-        let (memory_type, stability, weight) = classify_chat_memory(request.text.as_str());
-        let _ = ingest_memory_event_for_user(
-            &state,
-            user_id.as_str(),
-            MemoryIngestEvent {
-                memory_type,
-                stability,
-                source: "chat".to_string(),
-                text: request.text.clone(),
-                weight,
-                tags: Vec::new(),
-                happened_at: Some(chrono::Utc::now()),
-                expires_at: None,
-            },
-        )
-        .await;
+        public_key.insert(
+            "attestation".to_string(),
+            serde_json::json!(runtime.attestation),
+        );
     }
+    options
+}
 
-    let input = ChatInput {
-        session_id: request.session_id.clone(),
-        text: request.text.clone(),
-        locale: request.locale.clone(),
-        user_id: request.user_id.clone(),
-    };
-
-    match state.agent.handle_chat(input).await {
-        Ok(mut response) => {
-            let resolved_user = session_user.clone().or_else(|| {
-                request_user_id
-                    .as_ref()
-                    .and_then(|user_id| state.users.read().get(user_id).cloned())
-            });
+fn apply_webauthn_login_policy(
+    mut options: serde_json::Value,
+    runtime: &WebauthnRuntimeConfig,
+) -> serde_json::Value {
+    if runtime.require_user_verification {
+        if let Some(public_key) = options
+            .get_mut("publicKey")
+            .and_then(|value| value.as_object_mut())
+        {
+            public_key.insert(
+                "userVerification".to_string(),
+                serde_json::json!("required"),
+            );
+        }
+    }
+    options
+}
 
+fn no_passkeys_registered_response() -> Response {
+    ApiError::bad_request(
+        "no_passkeys_registered",
+        "no passkeys are registered for this account",
+    )
+    .into_response()
+}
+
+// The decoy is a real challenge for some registered passkey with its credential list swapped for
+// one derived from the email, so repeated lookups for the same email stay consistent. With no
+// passkeys registered anywhere, every email gets the same `no_passkeys_registered` answer.
+async fn start_decoy_passkey_authentication(
+    state: &ApiState,
+    runtime: &WebauthnRuntimeConfig,
+    email: &str,
+) -> Response {
+    let template = state
+        .passkeys_by_user
+        .read()
+        .values()
+        .flat_map(|entries| entries.iter().map(|entry| entry.credential.clone()))
+        .next();
+    let Some(template) = template else {
+        return no_passkeys_registered_response();
+    };
+    let Ok((request, auth_state)) = runtime
+        .webauthn
+        .start_passkey_authentication(std::slice::from_ref(&template))
+    else {
+        return no_passkeys_registered_response();
+    };
+
+    let Some(decoy_ids) = decoy_credential_ids(state.passkey_decoy_secret.as_str(), email) else {
+        return no_passkeys_registered_response();
+    };
+    let mut options = serde_json::to_value(request).unwrap_or_else(|_| serde_json::json!({}));
+    if let Some(credentials) = options.pointer_mut("/publicKey/allowCredentials") {
+        let entry = credentials
+            .get(0)
+            .cloned()
+            .unwrap_or_else(|| serde_json::json!({ "type": "public-key" }));
+        *credentials = serde_json::Value::Array(
+            decoy_ids
+                .iter()
+                .map(|id| {
+                    let mut credential = entry.clone();
+                    credential["id"] = serde_json::json!(URL_SAFE_NO_PAD.encode(id));
+                    credential
+                })
+                .collect(),
+        );
+    }
+
+    let request_id = uuid::Uuid::new_v4().to_string();
+    let challenge = PasskeyAuthenticationStateRecord {
+        user_id: None,
+        state: auth_state,
+        decoy: true,
+        email: Some(email.to_string()),
+        expires_at: chrono::Utc::now() + chrono::Duration::minutes(8),
+    };
+    if stash_shared_challenge(
+        state,
+        CHALLENGE_PASSKEY_AUTHENTICATION,
+        &request_id,
+        &challenge,
+        challenge.expires_at,
+    )
+    .await
+    .is_err()
+    {
+        return shared_challenge_unavailable_response();
+    }
+    state
+        .passkey_authentications
+        .write()
+        .insert(request_id.clone(), challenge);
+    (
+        StatusCode::OK,
+        Json(PasskeyLoginStartResponse {
+            request_id,
+            options: apply_webauthn_login_policy(options, runtime),
+        }),
+    )
+        .into_response()
+}
+
+// Real accounts hold one to three passkeys, with credential ids from 16 bytes (platform
+// authenticators) up to 64 (security keys). Count and lengths are keyed on the email, so a
+// decoy looks like some real account and stays the same on every lookup.
+const DECOY_CREDENTIAL_ID_LENGTHS: [usize; 5] = [16, 20, 32, 48, 64];
+
+fn decoy_credential_ids(secret: &str, email: &str) -> Option<Vec<Vec<u8>>> {
+    let shape = decoy_mac(secret, email, b"shape", 0)?;
+    let count = 1 + usize::from(shape[0] % 3);
+    (0..count)
+        .map(|index| {
+            let len = DECOY_CREDENTIAL_ID_LENGTHS
+                [usize::from(shape[index + 1]) % DECOY_CREDENTIAL_ID_LENGTHS.len()];
+            let mut bytes = Vec::with_capacity(len);
+            let mut block = 0_u32;
+            while bytes.len() < len {
+                let counter = ((index as u32) << 16) | block;
+                bytes.extend_from_slice(&decoy_mac(secret, email, b"id", counter)?);
+                block += 1;
+            }
+            bytes.truncate(len);
+            Some(bytes)
+        })
+        .collect()
+}
+
+fn decoy_mac(secret: &str, email: &str, label: &[u8], counter: u32) -> Option<[u8; 32]> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).ok()?;
+    mac.update(b"atlas-passkey-decoy:");
+    mac.update(label);
+    mac.update(&counter.to_be_bytes());
+    mac.update(email.as_bytes());
+    Some(mac.finalize().into_bytes().into())
+}
+
+async fn auth_passkey_login_finish(
+    State(state): State<ApiState>,
+    connect_info: Option<ConnectInfo<std::net::SocketAddr>>,
+    headers: HeaderMap,
+    Json(input): Json<PasskeyLoginFinishRequest>,
+) -> impl IntoResponse {
+    let peer = connect_info.map(|ConnectInfo(addr)| addr.ip());
+    let request_id = request_id_from_headers(&headers);
+    let Some(runtime) = state.webauthn_runtime.as_ref() else {
+        return ApiError::service_unavailable(
+            "passkey_unavailable",
+            "passkey sign-in is not configured",
+        )
+        .into_response();
+    };
+
+    let pending = state
+        .passkey_authentications
+        .write()
+        .remove(input.request_id.as_str());
+    let Some(pending) = take_shared_challenge(
+        &state,
+        CHALLENGE_PASSKEY_AUTHENTICATION,
+        input.request_id.as_str(),
+        pending,
+    )
+    .await
+    else {
+        log_auth_event(
+            "auth.login",
+            "failure",
+            "passkey",
+            request_id.as_str(),
+            None,
+            Some("invalid_request_id"),
+        );
+        return ApiError::bad_request("invalid_request_id", "unknown or already used request_id")
+            .into_response();
+    };
+
+    if pending.expires_at <= chrono::Utc::now() {
+        log_auth_event(
+            "auth.login",
+            "failure",
+            "passkey",
+            request_id.as_str(),
+            None,
+            Some("request_expired"),
+        );
+        return ApiError::bad_request(
+            "request_expired",
+            "the passkey request expired; start again",
+        )
+        .into_response();
+    }
+
+    // The lockout is only consulted once verification has failed: a valid assertion always
+    // signs in, so failures posted against someone's email cannot keep them out.
+    let login_email = pending.email.as_deref();
+    if pending.decoy {
+        return passkey_login_failure(
+            &state,
+            login_email,
+            request_id.as_str(),
+            "passkey_authentication_failed",
+            "passkey authentication failed".to_string(),
+        );
+    }
+
+    let auth_result: AuthenticationResult = match runtime
+        .webauthn
+        .finish_passkey_authentication(&input.credential, &pending.state)
+    {
+        Ok(value) => value,
+        Err(error) => {
+            return passkey_login_failure(
+                &state,
+                login_email,
+                request_id.as_str(),
+                "passkey_authentication_failed",
+                error.to_string(),
+            );
+        }
+    };
+    if runtime.require_user_verification && !auth_result.user_verified() {
+        return passkey_login_failure(
+            &state,
+            login_email,
+            request_id.as_str(),
+            "user_verification_required",
+            "this deployment requires a user-verified passkey".to_string(),
+        );
+    }
+    let resolved_user_id = pending.user_id.or_else(|| {
+        resolve_user_id_for_passkey_credential(&state, auth_result.cred_id().as_slice())
+    });
+    let Some(user_id) = resolved_user_id else {
+        log_auth_event(
+            "auth.login",
+            "failure",
+            "passkey",
+            request_id.as_str(),
+            None,
+            Some("user_not_found"),
+        );
+        return ApiError::not_found("user_not_found", "user not found").into_response();
+    };
+    let Some(mut user) = load_shared_account(&state, &user_id).await else {
+        return ApiError::not_found("user_not_found", "user not found").into_response();
+    };
+
+    let session_id = match issue_session_for_user(&state, &mut user, peer, &headers).await {
+        Ok(value) => value,
+        Err(error) => {
+            log_auth_event(
+                "auth.login",
+                "failure",
+                "passkey",
+                request_id.as_str(),
+                None,
+                Some("session_issue_failed"),
+            );
+            return ApiError::internal("session_issue_failed", error.to_string()).into_response();
+        }
+    };
+
+    state.login_email_lockouts.reset(user.email.as_str());
+    log_auth_event(
+        "auth.login",
+        "success",
+        "passkey",
+        request_id.as_str(),
+        Some(&user),
+        None,
+    );
+    update_passkey_credential_usage(&state, user.user_id.as_str(), &auth_result);
+    let _ = persist_passkeys_if_configured(&state, user.user_id.as_str()).await;
+
+    let token = format!("session-{}", session_id);
+    let mut response = (
+        StatusCode::OK,
+        Json(AuthResponse {
+            token,
+            user,
+            session_expires_at: (chrono::Utc::now()
+                + chrono::Duration::seconds(state.session_ttl.as_secs() as i64))
+            .to_rfc3339(),
+        }),
+    )
+        .into_response();
+    let cookie_value = build_session_cookie(
+        &state.cookie_name,
+        session_id.as_str(),
+        state.session_ttl.as_secs(),
+        state.cookie_secure,
+        state.cookie_same_site.as_str(),
+        state.cookie_partitioned,
+        state.cookie_domain.as_str(),
+    );
+    if let Ok(header_value) = HeaderValue::from_str(&cookie_value) {
+        response
+            .headers_mut()
+            .insert(header::SET_COOKIE, header_value);
+    }
+    response
+}
+
+async fn chat(
+    State(state): State<ApiState>,
+    headers: HeaderMap,
+    Json(mut request): Json<ChatRequest>,
+) -> impl IntoResponse {
+    match normalize_reasoning_effort(request.reasoning_effort.as_deref()) {
+        Ok(effort) => request.reasoning_effort = effort,
+        Err(error) => return error.into_response(),
+    }
+    let session_user = session_user_from_headers(&state, &headers);
+    if let Some(user) = session_user.as_ref() {
+        request.user_id = Some(user.user_id.clone());
+    }
+    let request_user_id = request.user_id.clone();
+    let include_proactive = request.include_proactive.unwrap_or(true);
+    let mut chat_memory_throttled = None;
+    if let Some(user_id) = session_user
+        .as_ref()
+        .map(|user| user.user_id.clone())
+        .or(request_user_id.clone())
+        .filter(|user_id| !user_opted_out_of_memory(&state, user_id))
+    {
+        // Scripted clients must not be able to churn the memory store, so chat-sourced
+        // ingestion is windowed per user while the reply itself is still served.
+        let throttled = !state.chat_memory_limiter.allow(user_id.as_str()).await;
+        chat_memory_throttled = Some(throttled);
+        if !throttled {
+            let (memory_type, stability, weight) = classify_chat_memory(request.text.as_str());
+            let _ = ingest_memory_event_for_user(
+                &state,
+                user_id.as_str(),
+                MemoryIngestEvent {
+                    memory_type,
+                    stability,
+                    source: "chat".to_string(),
+                    text: request.text.clone(),
+                    weight,
+                    tags: Vec::new(),
+                    happened_at: Some(chrono::Utc::now()),
+                    expires_at: None,
+                },
+            )
+            .await;
+        }
+    }
+
+    // Prior turns are replayed only for the signed-in user; a `user_id` in the body alone
+    // would let any caller read someone else's conversation back through the reply.
+    let context_turns = match (session_user.as_ref(), request.session_id.as_deref()) {
+        (Some(user), Some(session_id)) => recent_chat_context_turns(
+            &state,
+            user.user_id.as_str(),
+            session_id,
+            MAX_CHAT_CONTEXT_TURNS,
+        ),
+        _ => Vec::new(),
+    };
+    let input = ChatInput {
+        session_id: request.session_id.clone(),
+        text: request.text.clone(),
+        locale: request.locale.clone(),
+        user_id: request.user_id.clone(),
+        context_turns,
+    };
+
+    let chat_result =
+        match chat_with_deadline(state.chat_timeout, run_chat_agent(&state, input)).await {
+            Ok(result) => result,
+            Err(timeout_response) => return timeout_response,
+        };
+
+    match chat_result {
+        Ok(mut response) => {
+            if let (Some(throttled), Some(payload_obj)) =
+                (chat_memory_throttled, response.json_payload.as_object_mut())
+            {
+                payload_obj.insert(
+                    "chat_memory_ingest".to_string(),
+                    serde_json::json!({ "throttled": throttled }),
+                );
+            }
+            let resolved_user = session_user.clone().or_else(|| {
+                request_user_id
+                    .as_ref()
+                    .and_then(|user_id| state.users.read().get(user_id).cloned())
+            });
+            // Retrieved once per request: the payload, the proactive feed and the premium
+            // prompt all rank against the same query, and rescoring a full memory vector
+            // each time is a measurable cost for users near the cap. `memory_context` is only
+            // sent when it has items: opted-out users and users with nothing relevant get no
+            // key at all, so clients never render an empty memory panel that suggests memory
+            // is active.
+            let memory_context = resolved_user
+                .as_ref()
+                .filter(|user| user.memory_opt_in)
+                .map(|user| {
+                    retrieve_user_memory_context(
+                        &state,
+                        user.user_id.as_str(),
+                        request.text.as_str(),
+                        DEFAULT_MEMORY_RETRIEVAL_LIMIT,
+                        None,
+                    )
+                })
+                .unwrap_or_default();
+
+            // Holds the undecorated reply while voice mode is on; the spoken summary must not
+            // read out the studio scaffolding that `apply_studio_format` adds.
+            let mut spoken_source = None;
+            let mut formatted_response: Option<serde_json::Value>;
             if let Some(user) = resolved_user {
                 let stored_studio_pref = state
                     .studio_preferences
@@ -2050,14 +4038,20 @@ async fn chat(
                 let effective_studio_pref = merge_studio_preferences(
                     stored_studio_pref,
                     request_overrides_to_studio(&request),
+                    &mut Vec::new(),
                 );
+                if effective_studio_pref.voice_mode == "enabled" {
+                    spoken_source = Some(response.reply_text.clone());
+                }
 
-                response.reply_text = apply_studio_format(
+                let studio_reply = apply_studio_format(
                     response.reply_text,
                     &effective_studio_pref,
                     response.locale,
                     &user,
                 );
+                response.reply_text = studio_reply.text;
+                formatted_response = studio_reply.formatted_response;
 
                 let survey_state = state.survey_states.read().get(&user.user_id).cloned();
                 let survey_hints = survey_state
@@ -2072,27 +4066,31 @@ async fn chat(
                     .unwrap_or_default();
                 let execution_controls = get_execution_controls(&state, &user.user_id);
                 let latest_checkin = latest_execution_checkin(&state, &user.user_id);
-                let memory_context = retrieve_user_memory_context(
-                    &state,
-                    user.user_id.as_str(),
-                    request.text.as_str(),
-                    DEFAULT_MEMORY_RETRIEVAL_LIMIT,
-                );
 
                 // Base suggested actions that make daily follow-through easier.
-                response.suggested_actions.push(atlas_core::SuggestedAction {
-                    action_type: "create_reminder".to_string(),
-                    label: match response.locale {
-                        atlas_core::Locale::He => "יצירת תזכורת".to_string(),
-                        _ => "Create reminder".to_string(),
-                    },
-                    payload: serde_json::json!({
-                        "title": "Atlas/אטלס follow-up",
-                        "details": "Review plan and execute first action",
-                        "due_at_utc": (chrono::Utc::now() + chrono::Duration::hours(2)).to_rfc3339(),
-                        "reminders_app": effective_studio_pref.reminders_app
-                    }),
-                });
+                response
+                    .suggested_actions
+                    .push(atlas_core::SuggestedAction {
+                        action_type: "create_reminder".to_string(),
+                        label: match response.locale {
+                            atlas_core::Locale::He => "יצירת תזכורת".to_string(),
+                            _ => "Create reminder".to_string(),
+                        },
+                        payload: serde_json::json!({
+                            "title": "Atlas/אטלס follow-up",
+                            "details": "Review plan and execute first action",
+                            "due_at_utc": snap_to_working_hours(
+                                chrono::Utc::now() + chrono::Duration::hours(2),
+                                &effective_studio_pref
+                            )
+                            .to_rfc3339(),
+                            "reminders_app": effective_studio_pref.reminders_app,
+                            "snooze_options": reminder_snooze_options(
+                                chrono::Utc::now(),
+                                effective_studio_pref.utc_offset_minutes
+                            )
+                        }),
+                    });
                 response
                     .suggested_actions
                     .push(atlas_core::SuggestedAction {
@@ -2109,6 +4107,7 @@ async fn chat(
                         }),
                     });
 
+                let company_status = state.company_status.read().clone();
                 if let Some(payload_obj) = response.json_payload.as_object_mut() {
                     payload_obj
                         .insert("input_user_id".to_string(), serde_json::json!(user.user_id));
@@ -2118,16 +4117,18 @@ async fn chat(
                         serde_json::json!(effective_studio_pref),
                     );
                     payload_obj.insert("survey_hints".to_string(), serde_json::json!(survey_hints));
-                    payload_obj.insert(
-                        "memory_context".to_string(),
-                        serde_json::json!(memory_context.clone()),
-                    );
+                    if !memory_context.is_empty() {
+                        payload_obj.insert(
+                            "memory_context".to_string(),
+                            serde_json::json!(memory_context),
+                        );
+                    }
                     if include_proactive {
                         payload_obj.insert(
                             "proactive_feed".to_string(),
                             serde_json::json!(build_orchestrated_proactive_feed(
                                 &ExecutionFeedContext {
-                                    company_status: &state.company_status,
+                                    company_status: &company_status,
                                     user: &user,
                                     prefs: Some(&effective_studio_pref),
                                     survey: survey_state.as_ref(),
@@ -2135,6 +4136,7 @@ async fn chat(
                                     controls: &execution_controls,
                                     memories: memory_context.as_slice(),
                                     latest_checkin: latest_checkin.as_ref(),
+                                    max_items: state.feed_max_items,
                                 }
                             )),
                         );
@@ -2145,22 +4147,38 @@ async fn chat(
                 let guest_pref = merge_studio_preferences(
                     default_studio_preferences("guest"),
                     request_overrides_to_studio(&request),
+                    &mut Vec::new(),
                 );
-                response.reply_text =
+                if guest_pref.voice_mode == "enabled" {
+                    spoken_source = Some(response.reply_text.clone());
+                }
+                let studio_reply =
                     apply_studio_format_guest(response.reply_text, &guest_pref, response.locale);
-                response.suggested_actions.push(atlas_core::SuggestedAction {
-                    action_type: "create_reminder".to_string(),
-                    label: match response.locale {
-                        atlas_core::Locale::He => "יצירת תזכורת".to_string(),
-                        _ => "Create reminder".to_string(),
-                    },
-                    payload: serde_json::json!({
-                        "title": "Atlas/אטלס guest follow-up",
-                        "details": "Execute your next step",
-                        "due_at_utc": (chrono::Utc::now() + chrono::Duration::hours(2)).to_rfc3339(),
-                        "reminders_app": guest_pref.reminders_app
-                    }),
-                });
+                response.reply_text = studio_reply.text;
+                formatted_response = studio_reply.formatted_response;
+                response
+                    .suggested_actions
+                    .push(atlas_core::SuggestedAction {
+                        action_type: "create_reminder".to_string(),
+                        label: match response.locale {
+                            atlas_core::Locale::He => "יצירת תזכורת".to_string(),
+                            _ => "Create reminder".to_string(),
+                        },
+                        payload: serde_json::json!({
+                            "title": "Atlas/אטלס guest follow-up",
+                            "details": "Execute your next step",
+                            "due_at_utc": snap_to_working_hours(
+                                chrono::Utc::now() + chrono::Duration::hours(2),
+                                &guest_pref
+                            )
+                            .to_rfc3339(),
+                            "reminders_app": guest_pref.reminders_app,
+                            "snooze_options": reminder_snooze_options(
+                                chrono::Utc::now(),
+                                guest_pref.utc_offset_minutes
+                            )
+                        }),
+                    });
                 response
                     .suggested_actions
                     .push(atlas_core::SuggestedAction {
@@ -2178,6 +4196,9 @@ async fn chat(
                     });
             }
 
+            // History belongs to the signed-in user only, for the same reason context turns are
+            // only replayed for them: a body `user_id` must not write into someone's history.
+            let history_owner = session_user.as_ref().map(|user| user.user_id.clone());
             let premium_user = session_user.or_else(|| {
                 request_user_id
                     .as_ref()
@@ -2217,7 +4238,18 @@ async fn chat(
                 }
             }
 
-            if state.openai_runtime.is_some() && cloud_compute_enabled {
+            let ai_budget_exhausted = premium_user
+                .as_ref()
+                .map(|user| ai_budget_exhausted(&state, user))
+                .unwrap_or(false);
+            if state.ai_runtime.is_some() && cloud_compute_enabled && ai_budget_exhausted {
+                if let Some(payload_obj) = response.json_payload.as_object_mut() {
+                    payload_obj.insert(
+                        "ai_backend".to_string(),
+                        serde_json::json!("budget_exhausted"),
+                    );
+                }
+            } else if state.ai_runtime.is_some() && cloud_compute_enabled {
                 let survey_state = premium_user
                     .as_ref()
                     .and_then(|user| state.survey_states.read().get(&user.user_id).cloned());
@@ -2232,84 +4264,336 @@ async fn chat(
                             .unwrap_or_default()
                     })
                     .unwrap_or_default();
-                let memory_context = premium_user
-                    .as_ref()
-                    .map(|user| {
-                        retrieve_user_memory_context(
-                            &state,
-                            user.user_id.as_str(),
-                            request.text.as_str(),
-                            DEFAULT_MEMORY_RETRIEVAL_LIMIT,
-                        )
-                    })
-                    .unwrap_or_default();
-                if let Ok(premium_reply) = generate_premium_openai_reply(
+                let premium_result = generate_premium_reply(
                     &state,
                     &request,
+                    response.locale,
                     premium_user.as_ref(),
                     survey_state.as_ref(),
                     &notes,
                     memory_context.as_slice(),
                     response.reply_text.as_str(),
                 )
-                .await
-                {
-                    response.reply_text = premium_reply;
+                .await;
+                let locale_mismatch = premium_result.as_ref().is_ok_and(|reply| {
+                    !premium_reply_usable(
+                        response.locale,
+                        request.text.as_str(),
+                        reply.text.as_str(),
+                    )
+                });
+                // A reply that is thrown away for being in the wrong language is not charged.
+                if let Some(user) = premium_user.as_ref().filter(|_| !locale_mismatch) {
+                    let token_estimate = premium_result
+                        .as_ref()
+                        .map(|reply| {
+                            reply
+                                .usage
+                                .as_ref()
+                                .and_then(usage_total_tokens)
+                                .unwrap_or_else(|| {
+                                    estimate_ai_tokens(request.text.as_str(), reply.text.as_str())
+                                })
+                        })
+                        .unwrap_or_default();
+                    let _ = record_ai_usage_for_user(&state, user.user_id.as_str(), token_estimate)
+                        .await;
+                }
+                let premium_result = if locale_mismatch {
+                    tracing::warn!(
+                        locale = response.locale.as_code(),
+                        "premium reply came back in the wrong language; keeping the local reply"
+                    );
                     if let Some(payload_obj) = response.json_payload.as_object_mut() {
                         payload_obj.insert(
                             "ai_backend".to_string(),
-                            serde_json::json!("openai_responses"),
+                            serde_json::json!("locale_mismatch"),
                         );
+                    }
+                    Err(anyhow::anyhow!("premium reply language mismatch"))
+                } else {
+                    premium_result
+                };
+                if let Ok(premium_reply) = premium_result {
+                    if spoken_source.is_some() {
+                        spoken_source = Some(premium_reply.text.clone());
+                    }
+                    match formatted_response.as_mut() {
+                        Some(formatted) => {
+                            formatted["response"] = serde_json::json!(premium_reply.text);
+                        }
+                        None => response.reply_text = premium_reply.text,
+                    }
+                    if let (Some(runtime), Some(payload_obj)) = (
+                        state.ai_runtime.as_ref(),
+                        response.json_payload.as_object_mut(),
+                    ) {
                         payload_obj.insert(
-                            "ai_model".to_string(),
-                            serde_json::json!(state
-                                .openai_runtime
-                                .as_ref()
-                                .map(|cfg| cfg.model.clone())
-                                .unwrap_or_default()),
+                            "ai_backend".to_string(),
+                            serde_json::json!(runtime.backend_name()),
                         );
+                        payload_obj
+                            .insert("ai_model".to_string(), serde_json::json!(runtime.model()));
+                        if state.ai_debug {
+                            payload_obj.insert(
+                                "ai_response_id".to_string(),
+                                serde_json::json!(premium_reply.response_id),
+                            );
+                            payload_obj.insert(
+                                "ai_usage".to_string(),
+                                serde_json::json!(premium_reply.usage),
+                            );
+                        }
                     }
                 }
-            } else if state.openai_runtime.is_some() {
+            } else if state.ai_runtime.is_some() {
                 if let Some(payload_obj) = response.json_payload.as_object_mut() {
                     payload_obj.insert("ai_backend".to_string(), serde_json::json!("local_only"));
                 }
             }
 
-            (StatusCode::OK, Json(response)).into_response()
-        }
-        Err(error) => (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            Json(serde_json::json!({
-                "error": "chat_failed",
-                "message": error.to_string()
-            })),
-        )
-            .into_response(),
-    }
-}
+            let recorded_reply = formatted_response
+                .as_ref()
+                .and_then(|formatted| formatted["response"].as_str())
+                .unwrap_or(response.reply_text.as_str())
+                .to_string();
+            if let (Some(formatted), Some(payload_obj)) =
+                (formatted_response, response.json_payload.as_object_mut())
+            {
+                payload_obj.insert("formatted_response".to_string(), formatted);
+            }
+            if let (Some(source), Some(payload_obj)) =
+                (spoken_source, response.json_payload.as_object_mut())
+            {
+                payload_obj.insert(
+                    "spoken_summary".to_string(),
+                    serde_json::json!(build_spoken_summary(source.as_str())),
+                );
+            }
+
+            // Only `reply_text` is capped; structured fields stay complete. The untruncated reply
+            // is what gets recorded, so signed-in clients that keep chat history can fetch it.
+            if let Some(max_reply_chars) = request.max_reply_chars {
+                let max_reply_chars = max_reply_chars.clamp(MIN_REPLY_CHARS, MAX_REPLY_CHARS);
+                let truncated =
+                    truncate_on_word_boundary(response.reply_text.as_str(), max_reply_chars);
+                let reply_truncated = truncated.is_some();
+                if let Some(truncated) = truncated {
+                    response.reply_text = truncated;
+                }
+                let full_reply_available = reply_truncated
+                    && premium_user
+                        .as_ref()
+                        .is_some_and(|user| user_memory_opt_in(&state, user.user_id.as_str()))
+                    && response.json_payload.get("session_id").is_some();
+                if let Some(payload_obj) = response.json_payload.as_object_mut() {
+                    payload_obj.insert(
+                        "reply_truncated".to_string(),
+                        serde_json::json!(reply_truncated),
+                    );
+                    payload_obj.insert(
+                        "full_reply_available".to_string(),
+                        serde_json::json!(full_reply_available),
+                    );
+                }
+            }
+
+            dedupe_suggested_actions(&mut response.suggested_actions);
+
+            if let (Some(owner_id), Some(session_id)) = (
+                history_owner.as_deref(),
+                response
+                    .json_payload
+                    .get("session_id")
+                    .and_then(|value| value.as_str()),
+            ) {
+                let _ = record_chat_turn_for_user(
+                    &state,
+                    owner_id,
+                    session_id,
+                    request.text.as_str(),
+                    recorded_reply.as_str(),
+                )
+                .await;
+            }
+
+            let content_language = response.locale;
+            let mut http_response = (StatusCode::OK, Json(response)).into_response();
+            if content_language != atlas_core::Locale::Unknown {
+                http_response.headers_mut().insert(
+                    header::CONTENT_LANGUAGE,
+                    HeaderValue::from_static(content_language.as_code()),
+                );
+            }
+            http_response
+        }
+        Err(error) => ApiError::internal("chat_failed", error.to_string()).into_response(),
+    }
+}
+
+// Dry run for client authors: applies the same interpretation `/v1/chat` does (user
+// resolution, locale, studio overrides, reply length clamp, reasoning effort) and reports it,
+// without running the agent, touching memory, or calling a model.
+async fn chat_validate(
+    State(state): State<ApiState>,
+    headers: HeaderMap,
+    Json(request): Json<ChatRequest>,
+) -> impl IntoResponse {
+    let mut errors = Vec::new();
+    let mut coerced_fields = Vec::new();
+
+    let reasoning_effort = match normalize_reasoning_effort(request.reasoning_effort.as_deref()) {
+        Ok(effort) => effort,
+        Err(error) => {
+            errors.push(error);
+            None
+        }
+    };
+    // Only the session decides whose preferences are shown; a `user_id` in the body alone
+    // must not reveal another account's settings or whether it exists.
+    let user_id = resolve_user_id_or_guest(&state, &headers, request.user_id.clone());
+    let resolved_user = if is_guest_user_id(&user_id) {
+        None
+    } else {
+        state.users.read().get(&user_id).cloned()
+    };
+    let base_prefs = match resolved_user.as_ref() {
+        Some(user) => state
+            .studio_preferences
+            .read()
+            .get(&user.user_id)
+            .cloned()
+            .unwrap_or_else(|| default_studio_preferences(&user.user_id)),
+        None => default_studio_preferences("guest"),
+    };
+    let studio_preferences = merge_studio_preferences(
+        base_prefs,
+        request_overrides_to_studio(&request),
+        &mut coerced_fields,
+    );
+    let max_reply_chars = request.max_reply_chars.map(|requested| {
+        let applied = requested.clamp(MIN_REPLY_CHARS, MAX_REPLY_CHARS);
+        if applied != requested {
+            coerced_fields.push(CoercedField {
+                field: "max_reply_chars",
+                provided: requested.to_string(),
+                applied: applied.to_string(),
+            });
+        }
+        applied
+    });
+    let normalized_text = atlas_core::normalize_text(request.text.as_str());
+    let explicit_locale = atlas_core::Locale::from_optional_str(request.locale.as_deref());
+    if request.locale.is_some() && explicit_locale == atlas_core::Locale::Unknown {
+        coerced_fields.push(CoercedField {
+            field: "locale",
+            provided: request.locale.clone().unwrap_or_default(),
+            applied: "detected".to_string(),
+        });
+    }
+    let locale = atlas_core::detect_locale(Some(explicit_locale), normalized_text.as_str());
+
+    let mut warnings = Vec::new();
+    if normalized_text.is_empty() {
+        warnings.push("text_empty");
+    }
+    if request.user_id.is_some() && resolved_user.is_none() {
+        warnings.push("user_not_found_guest_formatting");
+    }
 
-async fn social_login(State(_state): State<ApiState>) -> impl IntoResponse {
     (
-        StatusCode::GONE,
+        StatusCode::OK,
         Json(serde_json::json!({
-            "error": "legacy_auth_retired",
-            "message": "Legacy /v1/auth/social_login is permanently disabled in strict passwordless mode.",
-            "allowed_methods": [
-                "/v1/auth/google/start",
-                "/v1/auth/apple/start",
-                "/v1/auth/passkey/register/start",
-                "/v1/auth/passkey/login/start"
-            ]
+            "valid": errors.is_empty(),
+            "errors": errors,
+            "coerced_fields": coerced_fields,
+            "warnings": warnings,
+            "interpreted": {
+                "user_id": resolved_user.as_ref().map(|user| user.user_id.clone()),
+                "session_id": request.session_id,
+                "locale": locale,
+                "text_chars": normalized_text.chars().count(),
+                "include_proactive": request.include_proactive.unwrap_or(true),
+                "max_reply_chars": max_reply_chars,
+                "reasoning_effort": reasoning_effort,
+                "studio_preferences": studio_preferences
+            }
+        })),
+    )
+        .into_response()
+}
+
+async fn chat_history(
+    State(state): State<ApiState>,
+    headers: HeaderMap,
+    Query(query): Query<ChatHistoryQuery>,
+) -> impl IntoResponse {
+    let user_id = match resolve_user_id(&state, &headers, query.user_id.clone()) {
+        Some(value) => value,
+        None => return ApiError::not_authenticated().into_response(),
+    };
+
+    let session_id = query.session_id.trim().to_string();
+    if session_id.is_empty() {
+        return ApiError::bad_request("invalid_session_id", "session_id is required")
+            .into_response();
+    }
+
+    let turns = state
+        .chat_turns
+        .read()
+        .get(&user_id)
+        .map(|items| {
+            items
+                .iter()
+                .filter(|turn| turn.session_id == session_id)
+                .cloned()
+                .collect::<Vec<_>>()
+        })
+        .unwrap_or_default();
+
+    (
+        StatusCode::OK,
+        Json(serde_json::json!({
+            "session_id": session_id,
+            "turns": turns
         })),
     )
         .into_response()
 }
 
+async fn social_login(State(_state): State<ApiState>) -> impl IntoResponse {
+    ApiError::new(
+        StatusCode::GONE,
+        "legacy_auth_retired",
+        "Legacy /v1/auth/social_login is permanently disabled in strict passwordless mode.",
+    )
+    .with_details(serde_json::json!({
+        "allowed_methods": [
+            "/v1/auth/google/start",
+            "/v1/auth/apple/start",
+            "/v1/auth/passkey/register/start",
+            "/v1/auth/passkey/login/start"
+        ]
+    }))
+    .into_response()
+}
+
 async fn auth_logout(State(state): State<ApiState>, headers: HeaderMap) -> impl IntoResponse {
     if let Some(session_id) = read_cookie_value(&headers, &state.cookie_name) {
-        state.sessions.write().remove(&session_id);
-        let _ = persist_sessions_if_configured(&state).await;
+        let revoked = state.sessions.write().remove(&session_id);
+        let _ = persist_session_if_configured(&state, &session_id).await;
+        let user = revoked.and_then(|session| state.users.read().get(&session.user_id).cloned());
+        log_auth_event(
+            "auth.logout",
+            "success",
+            user.as_ref()
+                .map(|value| value.provider.as_str())
+                .unwrap_or("session"),
+            request_id_from_headers(&headers).as_str(),
+            user.as_ref(),
+            None,
+        );
     }
 
     let mut response = (
@@ -2323,6 +4607,7 @@ async fn auth_logout(State(state): State<ApiState>, headers: HeaderMap) -> impl
         &state.cookie_name,
         state.cookie_secure,
         state.cookie_same_site.as_str(),
+        state.cookie_partitioned,
         state.cookie_domain.as_str(),
     );
     if let Ok(header_value) = HeaderValue::from_str(&clear_cookie) {
@@ -2341,14 +4626,11 @@ async fn profile_upsert(
     let session_user = session_user_from_headers(&state, &headers);
     if let (Some(from_session), Some(from_body)) = (session_user.as_ref(), input.user_id.as_ref()) {
         if from_session.user_id != *from_body {
-            return (
-                StatusCode::FORBIDDEN,
-                Json(serde_json::json!({
-                    "error": "user_mismatch",
-                    "message": "signed-in user does not match requested user_id"
-                })),
+            return ApiError::forbidden(
+                "user_mismatch",
+                "signed-in user does not match requested user_id",
             )
-                .into_response();
+            .into_response();
         }
     }
 
@@ -2358,46 +4640,37 @@ async fn profile_upsert(
         .or(input.user_id.clone());
 
     let Some(target_user_id) = target_user_id else {
-        return (
-            StatusCode::UNAUTHORIZED,
-            Json(serde_json::json!({
-                "error": "not_authenticated",
-                "message": "sign in first"
-            })),
-        )
-            .into_response();
+        return ApiError::not_authenticated().into_response();
     };
 
+    let mut coerced_fields = Vec::new();
     let user_clone = {
         let mut users = state.users.write();
         let Some(user) = users.get_mut(&target_user_id) else {
-            return (
-                StatusCode::NOT_FOUND,
-                Json(serde_json::json!({
-                    "error": "user_not_found",
-                    "message": "sign in first"
-                })),
-            )
-                .into_response();
+            return ApiError::not_found("user_not_found", "sign in first").into_response();
         };
 
         if let Some(style) = input.trip_style {
             let style = sanitize_limited_text(style.as_str(), MAX_PROFILE_FIELD_LEN);
             if !style.is_empty() {
-                user.trip_style = Some(sanitize_enum_value(
+                user.trip_style = Some(sanitize_enum_field(
+                    "trip_style",
                     style.as_str(),
                     &["mixed", "beach", "north", "desert", "business", "nature"],
                     "mixed",
+                    &mut coerced_fields,
                 ));
             }
         }
         if let Some(risk) = input.risk_preference {
             let risk = sanitize_limited_text(risk.as_str(), MAX_PROFILE_FIELD_LEN);
             if !risk.is_empty() {
-                user.risk_preference = Some(sanitize_enum_value(
+                user.risk_preference = Some(sanitize_enum_field(
+                    "risk_preference",
                     risk.as_str(),
                     &["low", "medium", "high"],
                     "medium",
+                    &mut coerced_fields,
                 ));
             }
         }
@@ -2407,8 +4680,13 @@ async fn profile_upsert(
         if let Some(locale) = input.locale {
             let locale = sanitize_limited_text(locale.as_str(), MAX_PROFILE_FIELD_LEN);
             if !locale.is_empty() {
-                user.locale =
-                    sanitize_enum_value(locale.as_str(), &["he", "en", "ar", "ru", "fr"], "he");
+                user.locale = sanitize_enum_field(
+                    "locale",
+                    locale.as_str(),
+                    &["he", "en", "ar", "ru", "fr"],
+                    "he",
+                    &mut coerced_fields,
+                );
             }
         }
         user.updated_at = chrono::Utc::now().to_rfc3339();
@@ -2417,13 +4695,15 @@ async fn profile_upsert(
     let _ = persist_user_if_configured(&state, &user_clone).await;
     if !user_clone.memory_opt_in {
         let _ = clear_user_memories_by_scope(&state, user_clone.user_id.as_str(), "all").await;
+        let _ = purge_chat_turns_for_user(&state, user_clone.user_id.as_str()).await;
     }
 
     (
         StatusCode::OK,
         Json(serde_json::json!({
             "ok": true,
-            "user": user_clone
+            "user": user_clone,
+            "coerced_fields": coerced_fields
         })),
     )
         .into_response()
@@ -2431,13 +4711,7 @@ async fn profile_upsert(
 
 async fn auth_me(State(state): State<ApiState>, headers: HeaderMap) -> impl IntoResponse {
     let Some(user) = session_user_from_headers(&state, &headers) else {
-        return (
-            StatusCode::UNAUTHORIZED,
-            Json(serde_json::json!({
-                "error": "not_authenticated"
-            })),
-        )
-            .into_response();
+        return ApiError::not_authenticated().into_response();
     };
 
     let subscription = subscription_access_for_user(&state, &user).await;
@@ -2452,6 +4726,75 @@ async fn auth_me(State(state): State<ApiState>, headers: HeaderMap) -> impl Into
         .into_response()
 }
 
+async fn auth_sessions_list(
+    State(state): State<ApiState>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    let Some(user) = session_user_from_headers(&state, &headers) else {
+        return ApiError::not_authenticated().into_response();
+    };
+    let current_session_id = read_cookie_value(&headers, &state.cookie_name).unwrap_or_default();
+
+    let now = chrono::Utc::now();
+    let stored = match state.shared_auth.as_ref() {
+        Some(shared) => match shared.sessions_for_user(&user.user_id).await {
+            Ok(sessions) => sessions,
+            Err(err) => {
+                tracing::warn!(error = %err, "failed to load shared sessions");
+                return ApiError::service_unavailable(
+                    "sessions_unavailable",
+                    "sessions could not be loaded",
+                )
+                .into_response();
+            }
+        },
+        None => state
+            .sessions
+            .read()
+            .iter()
+            .map(|(session_id, session)| (session_id.clone(), session.clone()))
+            .collect(),
+    };
+    let mut sessions = stored
+        .into_iter()
+        .filter(|(_, session)| session.user_id == user.user_id && session.expires_at > now)
+        .map(|(session_id, session)| (session_id == current_session_id, session))
+        .collect::<Vec<_>>();
+    sessions.sort_by_key(|(_, session)| session.created_at);
+
+    let mut seen_locations = Vec::new();
+    let mut summaries = Vec::with_capacity(sessions.len());
+    for (current, session) in sessions {
+        let location = session_location_key(&session);
+        let new_location = location
+            .as_ref()
+            .map(|key| !seen_locations.is_empty() && !seen_locations.contains(key))
+            .unwrap_or(false);
+        if let Some(key) = location {
+            if !seen_locations.contains(&key) {
+                seen_locations.push(key);
+            }
+        }
+        summaries.push(SessionSummary {
+            current,
+            created_at: session.created_at.to_rfc3339(),
+            expires_at: session.expires_at.to_rfc3339(),
+            client_network: session.client_network,
+            region: session.region,
+            new_location,
+        });
+    }
+    summaries.reverse();
+
+    (
+        StatusCode::OK,
+        Json(serde_json::json!({
+            "sessions": summaries
+        })),
+    )
+        .into_response()
+}
+
 async fn subscription_access_for_user(
     state: &ApiState,
     user: &UserRecord,
@@ -2481,7 +4824,69 @@ async fn subscription_access_for_user(
     }
 }
 
+fn current_usage_period(now: chrono::DateTime<chrono::Utc>) -> String {
+    now.format("%Y-%m").to_string()
+}
+
+fn estimate_ai_tokens(prompt: &str, reply: &str) -> u64 {
+    // Rough heuristic (~4 chars per token) for when the upstream omits usage.
+    ((prompt.chars().count() + reply.chars().count()) as u64).div_ceil(4)
+}
+
+fn ai_budget_exhausted(state: &ApiState, user: &UserRecord) -> bool {
+    if is_subscription_bypass_email(user.email.as_str()) {
+        return false;
+    }
+    let period = current_usage_period(chrono::Utc::now());
+    state
+        .ai_usage_counters
+        .read()
+        .get(&user.user_id)
+        .filter(|counter| counter.period == period)
+        .map(|counter| counter.call_count >= state.ai_monthly_call_cap)
+        .unwrap_or(false)
+}
+
+async fn record_ai_usage_for_user(
+    state: &ApiState,
+    user_id: &str,
+    token_estimate: u64,
+) -> Result<()> {
+    let now = chrono::Utc::now();
+    let period = current_usage_period(now);
+    let counter = {
+        let mut counters = state.ai_usage_counters.write();
+        let counter = counters
+            .entry(user_id.to_string())
+            .or_insert_with(|| AiUsageCounterRecord {
+                user_id: user_id.to_string(),
+                period: period.clone(),
+                call_count: 0,
+                token_estimate: 0,
+                updated_at: now.to_rfc3339(),
+            });
+        if counter.period != period {
+            counter.period = period;
+            counter.call_count = 0;
+            counter.token_estimate = 0;
+        }
+        counter.call_count = counter.call_count.saturating_add(1);
+        counter.token_estimate = counter.token_estimate.saturating_add(token_estimate);
+        counter.updated_at = now.to_rfc3339();
+        counter.clone()
+    };
+    persist_ai_usage_if_configured(state, &counter).await
+}
+
 async fn user_has_active_subscription(state: &ApiState, user_id: &str) -> Result<bool> {
+    #[cfg(feature = "postgres")]
+    if let Some(pool) = state.pg_pool.as_ref() {
+        let status = postgres_state::subscription_status(pool, user_id)
+            .await?
+            .unwrap_or_default()
+            .to_ascii_lowercase();
+        return Ok(matches!(status.as_str(), "active" | "owner_bypass"));
+    }
     let Some(pool) = state.db_pool.as_ref() else {
         return Ok(false);
     };
@@ -2505,16 +4910,7 @@ async fn notes_list(
 ) -> impl IntoResponse {
     let user_id = match resolve_user_id(&state, &headers, query.user_id.clone()) {
         Some(value) => value,
-        None => {
-            return (
-                StatusCode::UNAUTHORIZED,
-                Json(serde_json::json!({
-                    "error": "not_authenticated",
-                    "message": "sign in first"
-                })),
-            )
-                .into_response()
-        }
+        None => return ApiError::not_authenticated().into_response(),
     };
 
     let items = state
@@ -2523,8 +4919,51 @@ async fn notes_list(
         .get(&user_id)
         .cloned()
         .unwrap_or_default();
+    let Some(revision) = state.notes_revisions.read().get(&user_id).copied() else {
+        return (StatusCode::OK, Json(serde_json::json!({ "notes": items }))).into_response();
+    };
+
+    let etag = notes_etag(revision);
+    let mut response = if if_none_match_matches(&headers, etag.as_str()) {
+        StatusCode::NOT_MODIFIED.into_response()
+    } else {
+        (StatusCode::OK, Json(serde_json::json!({ "notes": items }))).into_response()
+    };
+    if let Ok(header_value) = HeaderValue::from_str(etag.as_str()) {
+        response.headers_mut().insert(header::ETAG, header_value);
+    }
+    response.headers_mut().insert(
+        header::CACHE_CONTROL,
+        HeaderValue::from_static("private, no-cache"),
+    );
+    response
+}
+
+// The timestamp keeps the tag unique when revisions restart from zero, e.g. an in-memory
+// deployment that restarted and lost its notes along with their revisions.
+fn notes_etag(revision: NotesRevision) -> String {
+    format!(
+        "\"{}-{}\"",
+        revision.revision,
+        revision.modified_at.timestamp_micros()
+    )
+}
 
-    (StatusCode::OK, Json(serde_json::json!({ "notes": items }))).into_response()
+/// Rejects rather than truncates: a note that silently loses its tail is data loss.
+fn note_length_error(field: &str, value: &str, limit: usize) -> Option<ApiError> {
+    let submitted_chars = value.trim().chars().count();
+    (submitted_chars > limit).then(|| {
+        ApiError::new(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            "note_too_long",
+            format!("note {field} is {submitted_chars} characters; the limit is {limit}"),
+        )
+        .with_details(serde_json::json!({
+            "field": field,
+            "limit": limit,
+            "submitted_chars": submitted_chars
+        }))
+    })
 }
 
 async fn note_upsert(
@@ -2534,29 +4973,25 @@ async fn note_upsert(
 ) -> impl IntoResponse {
     let user_id = match resolve_user_id(&state, &headers, input.user_id.clone()) {
         Some(value) => value,
-        None => {
-            return (
-                StatusCode::UNAUTHORIZED,
-                Json(serde_json::json!({
-                    "error": "not_authenticated",
-                    "message": "sign in first"
-                })),
-            )
-                .into_response()
-        }
+        None => return ApiError::not_authenticated().into_response(),
     };
 
+    if let Some(error) = note_length_error("title", input.title.as_str(), MAX_NOTE_TITLE_LEN)
+        .or_else(|| {
+            note_length_error(
+                "content",
+                input.content.as_str(),
+                state.note_max_content_chars,
+            )
+        })
+    {
+        return error.into_response();
+    }
     let title = sanitize_limited_text(input.title.as_str(), MAX_NOTE_TITLE_LEN);
-    let content = sanitize_limited_text(input.content.as_str(), MAX_NOTE_CONTENT_LEN);
+    let content = sanitize_limited_text(input.content.as_str(), state.note_max_content_chars);
 
     if title.is_empty() || content.is_empty() {
-        return (
-            StatusCode::BAD_REQUEST,
-            Json(serde_json::json!({
-                "error": "invalid_note",
-                "message": "title and content are required"
-            })),
-        )
+        return ApiError::bad_request("invalid_note", "title and content are required")
             .into_response();
     }
 
@@ -2570,19 +5005,10 @@ async fn note_upsert(
         content,
         tags: sanitize_note_tags(input.tags.unwrap_or_default()),
         updated_at: chrono::Utc::now().to_rfc3339(),
+        structured: None,
     };
 
-    {
-        let mut notes_map = state.user_notes.write();
-        let notes = notes_map.entry(user_id.clone()).or_default();
-        if let Some(existing) = notes.iter_mut().find(|entry| entry.note_id == note_id) {
-            *existing = note.clone();
-        } else {
-            notes.push(note.clone());
-        }
-        notes.sort_by(|lhs, rhs| rhs.updated_at.cmp(&lhs.updated_at));
-    }
-    let _ = persist_notes_if_configured(&state, user_id.as_str()).await;
+    store_note_with_history(&state, user_id.as_str(), note.clone(), "upsert").await;
     let note_memory_text = format!("{}: {}", note.title, note.content);
     let _ = ingest_memory_event_for_user(
         &state,
@@ -2617,100 +5043,42 @@ async fn note_rewrite(
     headers: HeaderMap,
     Json(input): Json<NoteRewriteRequest>,
 ) -> impl IntoResponse {
-    let user_id = match resolve_user_id(&state, &headers, input.user_id.clone()) {
-        Some(value) => value,
-        None => {
-            return (
-                StatusCode::UNAUTHORIZED,
-                Json(serde_json::json!({
-                    "error": "not_authenticated"
-                })),
-            )
-                .into_response()
-        }
-    };
-
-    let note = state.user_notes.read().get(&user_id).and_then(|list| {
-        list.iter()
-            .find(|entry| entry.note_id == input.note_id)
-            .cloned()
-    });
-    let Some(note) = note else {
-        return (
-            StatusCode::NOT_FOUND,
-            Json(serde_json::json!({
-                "error": "note_not_found"
-            })),
-        )
-            .into_response();
-    };
-
-    let Some(user) = state.users.read().get(&user_id).cloned() else {
-        return (
-            StatusCode::NOT_FOUND,
-            Json(serde_json::json!({
-                "error": "user_not_found"
-            })),
-        )
-            .into_response();
+    let (user_id, user, note) = match authorize_note_rewrite(&state, &headers, &input).await {
+        Ok(value) => value,
+        Err(response) => return response,
     };
-    let subscription = subscription_access_for_user(&state, &user).await;
-    if !subscription.cloud_compute_enabled {
-        return (
-            StatusCode::PAYMENT_REQUIRED,
-            Json(serde_json::json!({
-                "error": "subscription_required_for_cloud_compute",
-                "message": "Cloud note rewrite requires an active subscription.",
-                "subscription": subscription
-            })),
-        )
-            .into_response();
-    }
 
-    let instruction = sanitize_limited_text(
-        input
-            .instruction
-            .unwrap_or_else(|| {
-                "Rewrite this note into an executive action brief with immediate tasks, mid-term strategy, and long-term mission alignment.".to_string()
-            })
-            .as_str(),
-        MAX_REWRITE_INSTRUCTION_LEN,
-    );
-    let rewritten = match rewrite_note_with_openai(&state, &note, instruction.as_str()).await {
-        Ok(value) => value,
-        Err(error) => {
-            return (
-                StatusCode::BAD_GATEWAY,
-                Json(serde_json::json!({
-                    "error": "note_rewrite_failed",
-                    "message": error.to_string()
-                })),
-            )
-                .into_response()
+    // A previewed rewrite can be accepted as-is without paying for a second model call.
+    let structured = match input.preview_id.as_deref() {
+        Some(preview_id) => {
+            match take_note_rewrite_preview(&state, preview_id, &user_id, &note.note_id) {
+                Some(structured) => structured,
+                None => {
+                    return ApiError::not_found(
+                        "rewrite_preview_not_found",
+                        "rewrite preview not found or expired",
+                    )
+                    .into_response();
+                }
+            }
         }
+        None => match run_note_rewrite_model(&state, &user, &note, input.instruction.clone()).await
+        {
+            Ok(value) => value,
+            Err(response) => return response,
+        },
     };
 
     let rewritten_note = UserNoteRecord {
         note_id: note.note_id.clone(),
         user_id: note.user_id.clone(),
         title: note.title.clone(),
-        content: rewritten,
+        content: render_structured_note(&structured),
         tags: note.tags.clone(),
         updated_at: chrono::Utc::now().to_rfc3339(),
+        structured: Some(structured),
     };
-    {
-        let mut notes_map = state.user_notes.write();
-        let notes = notes_map.entry(user_id.clone()).or_default();
-        if let Some(existing) = notes
-            .iter_mut()
-            .find(|entry| entry.note_id == rewritten_note.note_id)
-        {
-            *existing = rewritten_note.clone();
-        } else {
-            notes.push(rewritten_note.clone());
-        }
-    }
-    let _ = persist_notes_if_configured(&state, user_id.as_str()).await;
+    store_note_with_history(&state, user_id.as_str(), rewritten_note.clone(), "rewrite").await;
     let rewritten_memory_text = format!("{}: {}", rewritten_note.title, rewritten_note.content);
     let _ = ingest_memory_event_for_user(
         &state,
@@ -2740,1429 +5108,1603 @@ async fn note_rewrite(
         .into_response()
 }
 
-async fn memory_import(
+async fn note_versions_list(
     State(state): State<ApiState>,
     headers: HeaderMap,
-    Json(input): Json<MemoryImportRequest>,
+    AxumPath(note_id): AxumPath<String>,
+    Query(query): Query<NotesQuery>,
 ) -> impl IntoResponse {
-    let user_id = match resolve_user_id(&state, &headers, input.user_id.clone()) {
-        Some(value) => value,
-        None => {
-            return (
-                StatusCode::UNAUTHORIZED,
-                Json(serde_json::json!({
-                    "error": "not_authenticated",
-                    "message": "sign in first"
-                })),
-            )
-                .into_response()
-        }
+    let Some(user_id) = resolve_user_id(&state, &headers, query.user_id.clone()) else {
+        return ApiError::not_authenticated().into_response();
     };
 
-    if input.items.is_empty() {
-        return (
-            StatusCode::BAD_REQUEST,
-            Json(serde_json::json!({
-                "error": "memory_items_required",
-                "message": "at least one memory item is required"
-            })),
-        )
-            .into_response();
-    }
-    if input.items.len() > MAX_MEMORY_IMPORT_ITEMS {
-        return (
-            StatusCode::BAD_REQUEST,
-            Json(serde_json::json!({
-                "error": "memory_batch_too_large",
-                "message": format!("max {} items per import request", MAX_MEMORY_IMPORT_ITEMS)
-            })),
-        )
-            .into_response();
-    }
-
-    let now = chrono::Utc::now();
-    let mut imported = Vec::new();
-    for item in input.items {
-        let title = sanitize_limited_text(item.title.as_str(), MAX_NOTE_TITLE_LEN);
-        let content = sanitize_limited_text(item.content.as_str(), MAX_NOTE_CONTENT_LEN);
-        if title.is_empty() || content.is_empty() {
-            continue;
-        }
-
-        let mut tags = sanitize_note_tags(item.tags.unwrap_or_default());
-        if let Some(source) = item.source {
-            let source_tag = normalize_tag(source.as_str());
-            if !source_tag.is_empty() {
-                tags.push(format!("source_{}", source_tag));
-            }
-        }
-        tags = sanitize_note_tags(tags);
-
-        imported.push(UserNoteRecord {
-            note_id: uuid::Uuid::new_v4().to_string(),
-            user_id: user_id.clone(),
-            title,
-            content,
-            tags,
-            updated_at: parse_or_default_utc(item.happened_at.as_deref(), now).to_rfc3339(),
-        });
-    }
-
-    if imported.is_empty() {
-        return (
-            StatusCode::BAD_REQUEST,
-            Json(serde_json::json!({
-                "error": "no_valid_memory_items",
-                "message": "all imported items were empty after sanitization"
-            })),
-        )
-            .into_response();
-    }
-
-    let imported_count = imported.len();
-    let imported_snapshot = imported.clone();
-    {
-        let mut notes_map = state.user_notes.write();
-        let notes = notes_map.entry(user_id.clone()).or_default();
-        notes.extend(imported);
-        notes.sort_by(|lhs, rhs| rhs.updated_at.cmp(&lhs.updated_at));
-        notes.truncate(MAX_NOTES_PER_USER);
-    }
-
-    let _ = persist_notes_if_configured(&state, user_id.as_str()).await;
-    for note in imported_snapshot {
-        let memory_text = format!("{}: {}", note.title, note.content);
-        let _ = ingest_memory_event_for_user(
-            &state,
-            user_id.as_str(),
-            MemoryIngestEvent {
-                memory_type: "insight".to_string(),
-                stability: "permanent".to_string(),
-                source: "import".to_string(),
-                text: memory_text,
-                weight: 0.72,
-                tags: note.tags.clone(),
-                happened_at: chrono::DateTime::parse_from_rfc3339(note.updated_at.as_str())
-                    .ok()
-                    .map(|value| value.with_timezone(&chrono::Utc)),
-                expires_at: None,
-            },
-        )
-        .await;
-    }
-    let total_notes = state
+    let mut versions = state
+        .note_versions
+        .read()
+        .get(&user_id)
+        .map(|list| {
+            list.iter()
+                .filter(|entry| entry.note_id == note_id)
+                .cloned()
+                .collect::<Vec<_>>()
+        })
+        .unwrap_or_default();
+    let note_exists = state
         .user_notes
         .read()
         .get(&user_id)
-        .map(|items| items.len())
-        .unwrap_or(0);
+        .is_some_and(|list| list.iter().any(|entry| entry.note_id == note_id));
+    if versions.is_empty() && !note_exists {
+        return ApiError::not_found("note_not_found", "note not found").into_response();
+    }
+    versions.reverse();
 
     (
         StatusCode::OK,
         Json(serde_json::json!({
-            "ok": true,
-            "imported": imported_count,
-            "total_notes": total_notes
+            "note_id": note_id,
+            "count": versions.len(),
+            "versions": versions
         })),
     )
         .into_response()
 }
 
-async fn memory_records_list(
+async fn note_version_restore(
     State(state): State<ApiState>,
     headers: HeaderMap,
-    Query(query): Query<MemoryRecordsQuery>,
+    AxumPath((note_id, version_id)): AxumPath<(String, String)>,
+    input: Option<Json<NoteVersionRestoreRequest>>,
 ) -> impl IntoResponse {
-    let user_id = match resolve_user_id(&state, &headers, query.user_id.clone()) {
-        Some(value) => value,
-        None => {
-            return (
-                StatusCode::UNAUTHORIZED,
-                Json(serde_json::json!({
-                    "error": "not_authenticated",
-                    "message": "sign in first"
-                })),
-            )
-                .into_response();
-        }
+    let input = input.map(|Json(value)| value).unwrap_or_default();
+    let Some(user_id) = resolve_user_id(&state, &headers, input.user_id) else {
+        return ApiError::not_authenticated().into_response();
     };
 
-    let opt_in = user_memory_opt_in(&state, user_id.as_str());
-    if !opt_in {
-        let empty_items: Vec<MemoryRetrievedItem> = Vec::new();
-        return (
-            StatusCode::OK,
-            Json(serde_json::json!({
-                "memory_opt_in": false,
-                "count": 0,
-                "items": empty_items
-            })),
-        )
+    let version = state.note_versions.read().get(&user_id).and_then(|list| {
+        list.iter()
+            .find(|entry| entry.note_id == note_id && entry.version_id == version_id)
+            .cloned()
+    });
+    let Some(version) = version else {
+        return ApiError::not_found("note_version_not_found", "note version not found")
             .into_response();
-    }
+    };
 
-    let limit = query
-        .limit
-        .unwrap_or(DEFAULT_MEMORY_RETRIEVAL_LIMIT)
-        .clamp(1, MAX_MEMORY_RETRIEVAL_LIMIT);
-    let search = query.q.unwrap_or_default();
-    let items = retrieve_user_memory_context(&state, user_id.as_str(), search.as_str(), limit);
+    let restored = UserNoteRecord {
+        note_id: version.note_id,
+        user_id: user_id.clone(),
+        title: version.title,
+        content: version.content,
+        tags: version.tags,
+        updated_at: chrono::Utc::now().to_rfc3339(),
+        structured: version.structured,
+    };
+    store_note_with_history(&state, user_id.as_str(), restored.clone(), "restore").await;
+    let _ = ingest_memory_event_for_user(
+        &state,
+        user_id.as_str(),
+        MemoryIngestEvent {
+            memory_type: "insight".to_string(),
+            stability: "permanent".to_string(),
+            source: "note".to_string(),
+            text: format!("{}: {}", restored.title, restored.content),
+            weight: 0.78,
+            tags: restored.tags.clone(),
+            happened_at: Some(chrono::Utc::now()),
+            expires_at: None,
+        },
+    )
+    .await;
 
     (
         StatusCode::OK,
         Json(serde_json::json!({
-            "memory_opt_in": true,
-            "count": items.len(),
-            "items": items
+            "ok": true,
+            "restored_version_id": version_id,
+            "note": restored
         })),
     )
         .into_response()
 }
 
-async fn memory_upsert(
+async fn note_delete(
     State(state): State<ApiState>,
     headers: HeaderMap,
-    Json(input): Json<MemoryUpsertRequest>,
-) -> impl IntoResponse {
-    let user_id = match resolve_user_id(&state, &headers, input.user_id.clone()) {
-        Some(value) => value,
-        None => {
-            return (
-                StatusCode::UNAUTHORIZED,
-                Json(serde_json::json!({
-                    "error": "not_authenticated",
-                    "message": "sign in first"
-                })),
-            )
-                .into_response();
-        }
-    };
+    Json(input): Json<NoteDeleteRequest>,
+) -> Response {
+    delete_note_for_caller(&state, &headers, input.user_id, input.note_id.as_str()).await
+}
 
-    if !user_memory_opt_in(&state, user_id.as_str()) {
-        return (
-            StatusCode::FORBIDDEN,
-            Json(serde_json::json!({
-                "error": "memory_opt_out",
-                "message": "memory ingestion is disabled for this profile"
-            })),
-        )
-            .into_response();
-    }
+async fn note_delete_by_id(
+    State(state): State<ApiState>,
+    headers: HeaderMap,
+    AxumPath(note_id): AxumPath<String>,
+    Query(query): Query<NotesQuery>,
+) -> Response {
+    delete_note_for_caller(&state, &headers, query.user_id, note_id.as_str()).await
+}
 
-    let event = MemoryIngestEvent {
-        memory_type: sanitize_memory_type(
-            input
-                .memory_type
-                .unwrap_or_else(|| "insight".to_string())
-                .as_str(),
-        ),
-        stability: sanitize_memory_stability(
-            input
-                .stability
-                .unwrap_or_else(|| "permanent".to_string())
-                .as_str(),
-        ),
-        source: sanitize_memory_source(
-            input
-                .source
-                .unwrap_or_else(|| "manual".to_string())
-                .as_str(),
-        ),
-        text: input.text,
-        weight: input.weight.unwrap_or(0.8),
-        tags: sanitize_note_tags(input.tags.unwrap_or_default()),
-        happened_at: Some(chrono::Utc::now()),
-        expires_at: input
-            .expires_at
-            .as_deref()
-            .and_then(|value| chrono::DateTime::parse_from_rfc3339(value).ok())
-            .map(|value| value.with_timezone(&chrono::Utc)),
+// The note's version history goes with it, so a deleted note cannot be restored.
+async fn delete_note_for_caller(
+    state: &ApiState,
+    headers: &HeaderMap,
+    explicit_user_id: Option<String>,
+    note_id: &str,
+) -> Response {
+    let Some(user_id) = resolve_user_id(state, headers, explicit_user_id) else {
+        return ApiError::not_authenticated().into_response();
     };
 
-    let ingested = ingest_memory_event_for_user(&state, user_id.as_str(), event).await;
-    if let Some(record) = ingested {
-        return (
-            StatusCode::OK,
-            Json(serde_json::json!({
-                "ok": true,
-                "memory": record
-            })),
-        )
-            .into_response();
+    let note_id = sanitize_limited_text(note_id, 96);
+    if note_id.is_empty() {
+        return ApiError::bad_request("invalid_note_id", "note_id is required").into_response();
+    }
+
+    let deleted = state
+        .user_notes
+        .write()
+        .get_mut(&user_id)
+        .is_some_and(|notes| {
+            let before = notes.len();
+            notes.retain(|entry| entry.note_id != note_id);
+            before != notes.len()
+        });
+    let versions_dropped = state
+        .note_versions
+        .write()
+        .get_mut(&user_id)
+        .is_some_and(|versions| {
+            let before = versions.len();
+            versions.retain(|entry| entry.note_id != note_id);
+            before != versions.len()
+        });
+    if deleted {
+        let _ = commit_notes_change(state, user_id.as_str()).await;
+    }
+    if versions_dropped {
+        let _ = persist_note_versions_if_configured(state, user_id.as_str()).await;
     }
 
     (
-        StatusCode::BAD_REQUEST,
+        StatusCode::OK,
         Json(serde_json::json!({
-            "error": "invalid_memory",
-            "message": "text is required"
+            "ok": true,
+            "deleted": deleted
         })),
     )
         .into_response()
 }
 
-async fn memory_delete(
-    State(state): State<ApiState>,
-    headers: HeaderMap,
-    Json(input): Json<MemoryDeleteRequest>,
-) -> impl IntoResponse {
-    let user_id = match resolve_user_id(&state, &headers, input.user_id.clone()) {
-        Some(value) => value,
-        None => {
-            return (
-                StatusCode::UNAUTHORIZED,
-                Json(serde_json::json!({
-                    "error": "not_authenticated",
-                    "message": "sign in first"
-                })),
-            )
-                .into_response();
-        }
+async fn store_note_with_history(
+    state: &ApiState,
+    user_id: &str,
+    note: UserNoteRecord,
+    replaced_by: &str,
+) {
+    let archived = {
+        let mut notes_map = state.user_notes.write();
+        let mut versions_map = state.note_versions.write();
+        let notes = notes_map.entry(user_id.to_string()).or_default();
+        let versions = versions_map.entry(user_id.to_string()).or_default();
+        let archived =
+            replace_note_keeping_history(notes, versions, note, replaced_by, chrono::Utc::now());
+        notes.sort_by(|lhs, rhs| rhs.updated_at.cmp(&lhs.updated_at));
+        archived
     };
+    let _ = commit_notes_change(state, user_id).await;
+    if archived {
+        let _ = persist_note_versions_if_configured(state, user_id).await;
+    }
+}
 
-    let memory_id = sanitize_limited_text(input.memory_id.as_str(), 96);
-    if memory_id.is_empty() {
-        return (
-            StatusCode::BAD_REQUEST,
-            Json(serde_json::json!({
-                "error": "invalid_memory_id"
-            })),
-        )
-            .into_response();
+/// Swaps `note` in for the note with the same id. The previous copy is archived only when the
+/// title, content, tags or structure actually changed, and each note keeps at most
+/// `NOTE_VERSION_HISTORY_LIMIT` versions. Returns whether anything was archived.
+fn replace_note_keeping_history(
+    notes: &mut Vec<UserNoteRecord>,
+    versions: &mut Vec<NoteVersionRecord>,
+    note: UserNoteRecord,
+    replaced_by: &str,
+    now: chrono::DateTime<chrono::Utc>,
+) -> bool {
+    let Some(existing) = notes.iter_mut().find(|entry| entry.note_id == note.note_id) else {
+        notes.push(note);
+        return false;
+    };
+    let unchanged = existing.title == note.title
+        && existing.content == note.content
+        && existing.tags == note.tags
+        && existing.structured == note.structured;
+    let previous = std::mem::replace(existing, note);
+    if unchanged {
+        return false;
     }
 
-    let deleted = {
-        let mut memories_map = state.user_memories.write();
-        if let Some(records) = memories_map.get_mut(&user_id) {
-            let before = records.len();
-            records.retain(|entry| entry.memory_id != memory_id);
-            before != records.len()
-        } else {
-            false
+    versions.push(NoteVersionRecord {
+        version_id: uuid::Uuid::new_v4().to_string(),
+        note_id: previous.note_id,
+        user_id: previous.user_id,
+        title: previous.title,
+        content: previous.content,
+        tags: previous.tags,
+        structured: previous.structured,
+        updated_at: previous.updated_at,
+        replaced_at: now.to_rfc3339(),
+        replaced_by: replaced_by.to_string(),
+    });
+    let note_id = versions[versions.len() - 1].note_id.clone();
+    let kept = versions
+        .iter()
+        .filter(|entry| entry.note_id == note_id)
+        .count();
+    let mut excess = kept.saturating_sub(NOTE_VERSION_HISTORY_LIMIT);
+    versions.retain(|entry| {
+        if excess > 0 && entry.note_id == note_id {
+            excess -= 1;
+            return false;
         }
+        true
+    });
+    true
+}
+
+async fn note_rewrite_preview(
+    State(state): State<ApiState>,
+    headers: HeaderMap,
+    Json(input): Json<NoteRewriteRequest>,
+) -> impl IntoResponse {
+    let (user_id, user, note) = match authorize_note_rewrite(&state, &headers, &input).await {
+        Ok(value) => value,
+        Err(response) => return response,
     };
-    if deleted {
-        let _ = persist_memories_if_configured(&state, user_id.as_str()).await;
-    }
+    let structured =
+        match run_note_rewrite_model(&state, &user, &note, input.instruction.clone()).await {
+            Ok(value) => value,
+            Err(response) => return response,
+        };
+    let preview_id = store_note_rewrite_preview(&state, user_id, note.note_id.clone(), &structured);
 
     (
         StatusCode::OK,
         Json(serde_json::json!({
             "ok": true,
-            "deleted": deleted
+            "note_id": note.note_id,
+            "preview_id": preview_id,
+            "preview": {
+                "title": note.title,
+                "content": render_structured_note(&structured),
+                "structured": structured
+            }
         })),
     )
         .into_response()
 }
 
-async fn memory_clear(
-    State(state): State<ApiState>,
-    headers: HeaderMap,
-    Json(input): Json<MemoryClearRequest>,
-) -> impl IntoResponse {
-    let user_id = match resolve_user_id(&state, &headers, input.user_id.clone()) {
-        Some(value) => value,
-        None => {
-            return (
-                StatusCode::UNAUTHORIZED,
-                Json(serde_json::json!({
-                    "error": "not_authenticated",
-                    "message": "sign in first"
-                })),
-            )
-                .into_response();
-        }
-    };
-
-    let scope = sanitize_enum_value(
-        input.scope.unwrap_or_else(|| "all".to_string()).as_str(),
-        &["all", "permanent", "transient"],
-        "all",
+fn store_note_rewrite_preview(
+    state: &ApiState,
+    user_id: String,
+    note_id: String,
+    structured: &StructuredNoteRewrite,
+) -> String {
+    let now = chrono::Utc::now();
+    let preview_id = uuid::Uuid::new_v4().to_string();
+    let mut previews = state.note_rewrite_previews.write();
+    previews.retain(|_, entry| entry.expires_at > now);
+    previews.insert(
+        preview_id.clone(),
+        NoteRewritePreviewRecord {
+            user_id,
+            note_id,
+            structured: structured.clone(),
+            expires_at: now + chrono::Duration::minutes(NOTE_REWRITE_PREVIEW_TTL_MINUTES),
+        },
     );
-    let cleared = clear_user_memories_by_scope(&state, user_id.as_str(), scope.as_str()).await;
+    preview_id
+}
 
-    (
-        StatusCode::OK,
-        Json(serde_json::json!({
-            "ok": true,
-            "scope": scope,
-            "cleared": cleared
-        })),
-    )
-        .into_response()
+/// Removes and returns a preview if it belongs to this user and note and has not expired.
+/// Previews are single use; a mismatched id is left alone for its owner.
+fn take_note_rewrite_preview(
+    state: &ApiState,
+    preview_id: &str,
+    user_id: &str,
+    note_id: &str,
+) -> Option<StructuredNoteRewrite> {
+    let mut previews = state.note_rewrite_previews.write();
+    let entry = previews.get(preview_id)?;
+    if entry.user_id != user_id || entry.note_id != note_id {
+        return None;
+    }
+    let entry = previews.remove(preview_id)?;
+    (entry.expires_at > chrono::Utc::now()).then_some(entry.structured)
 }
 
-async fn billing_create_checkout_session(
-    State(state): State<ApiState>,
-    headers: HeaderMap,
-    Json(_input): Json<BillingCheckoutRequest>,
-) -> impl IntoResponse {
-    let Some(user) = session_user_from_headers(&state, &headers) else {
-        return (
-            StatusCode::UNAUTHORIZED,
-            Json(serde_json::json!({
-                "error": "not_authenticated",
-                "message": "sign in first"
-            })),
-        )
-            .into_response();
+async fn authorize_note_rewrite(
+    state: &ApiState,
+    headers: &HeaderMap,
+    input: &NoteRewriteRequest,
+) -> std::result::Result<(String, UserRecord, UserNoteRecord), Response> {
+    let Some(user_id) = resolve_user_id(state, headers, input.user_id.clone()) else {
+        return Err(ApiError::not_authenticated().into_response());
     };
 
-    if is_subscription_bypass_email(user.email.as_str()) {
-        let now = chrono::Utc::now().to_rfc3339();
-        let billing = BillingStatusRecord {
-            user_id: user.user_id.clone(),
-            stripe_customer_id: None,
-            stripe_subscription_id: None,
-            status: "owner_bypass".to_string(),
-            current_period_end: None,
-            updated_at: now,
-        };
-        let _ = persist_billing_status_if_configured(&state, &billing).await;
+    let note = state.user_notes.read().get(&user_id).and_then(|list| {
+        list.iter()
+            .find(|entry| entry.note_id == input.note_id)
+            .cloned()
+    });
+    let Some(note) = note else {
+        return Err(ApiError::not_found("note_not_found", "note not found").into_response());
+    };
 
-        return (
-            StatusCode::OK,
-            Json(serde_json::json!({
-                "checkout_url": "https://atlasmasa.com/concierge-local.html?billing=owner_bypass",
-                "checkout_session_id": "owner-bypass",
-                "bypass": true
-            })),
+    let Some(user) = state.users.read().get(&user_id).cloned() else {
+        return Err(ApiError::not_found("user_not_found", "user not found").into_response());
+    };
+    let subscription = subscription_access_for_user(state, &user).await;
+    if !subscription.cloud_compute_enabled {
+        return Err(ApiError::payment_required(
+            "subscription_required_for_cloud_compute",
+            "Cloud note rewrite requires an active subscription.",
         )
-            .into_response();
+        .with_details(serde_json::json!({ "subscription": subscription }))
+        .into_response());
     }
 
-    let Some(runtime) = state.billing_runtime.as_ref() else {
-        return (
-            StatusCode::SERVICE_UNAVAILABLE,
-            Json(serde_json::json!({
-                "error": "billing_unavailable",
-                "message": "Stripe billing is not configured"
-            })),
+    Ok((user_id, user, note))
+}
+
+async fn run_note_rewrite_model(
+    state: &ApiState,
+    user: &UserRecord,
+    note: &UserNoteRecord,
+    instruction: Option<String>,
+) -> std::result::Result<StructuredNoteRewrite, Response> {
+    let instruction = sanitize_limited_text(
+        instruction
+            .unwrap_or_else(|| {
+                "Rewrite this note into an executive action brief with immediate tasks, mid-term strategy, and long-term mission alignment.".to_string()
+            })
+            .as_str(),
+        MAX_REWRITE_INSTRUCTION_LEN,
+    );
+    if ai_budget_exhausted(state, user) {
+        return Err(ApiError::too_many_requests(
+            "ai_budget_exhausted",
+            "Monthly premium AI budget reached. It resets at the start of next month.",
         )
-            .into_response();
-    };
+        .into_response());
+    }
+    let rewrite_result = rewrite_note_with_openai(state, note, instruction.as_str()).await;
+    let token_estimate = rewrite_result
+        .as_ref()
+        .map(|value| {
+            estimate_ai_tokens(
+                note.content.as_str(),
+                render_structured_note(value).as_str(),
+            )
+        })
+        .unwrap_or_default();
+    let _ = record_ai_usage_for_user(state, user.user_id.as_str(), token_estimate).await;
+    let structured = rewrite_result.map_err(|error| {
+        ApiError::bad_gateway("note_rewrite_failed", error.to_string()).into_response()
+    })?;
+    // A rewrite that no longer fits is refused rather than cut short, like a direct upsert.
+    if let Some(error) = note_length_error(
+        "content",
+        render_structured_note(&structured).as_str(),
+        state.note_max_content_chars,
+    ) {
+        return Err(error.into_response());
+    }
+    Ok(structured)
+}
 
-    // Authoritative billing config is server-side only. Do not allow client overrides
-    // for Stripe price IDs or redirect URLs to prevent plan tampering/open redirects.
-    let price_id = runtime.monthly_price_id.clone();
-    let success_url = runtime.success_url.clone();
-    let cancel_url = runtime.cancel_url.clone();
+/// Validates the whole batch before importing anything: one item with a bad `happened_at` or
+/// over-long content rejects the request. `memory_import_csv` skips such rows instead, since a
+/// spreadsheet export is usually worth importing even with a few bad lines.
+async fn memory_import(
+    State(state): State<ApiState>,
+    headers: HeaderMap,
+    Json(input): Json<MemoryImportRequest>,
+) -> impl IntoResponse {
+    let user_id = match resolve_user_id(&state, &headers, input.user_id.clone()) {
+        Some(value) => value,
+        None => return ApiError::not_authenticated().into_response(),
+    };
 
-    let response = match state
-        .http_client
-        .post("https://api.stripe.com/v1/checkout/sessions")
-        .bearer_auth(runtime.stripe_secret_key.as_str())
-        .form(&[
-            ("mode", "subscription"),
-            ("line_items[0][price]", price_id.as_str()),
-            ("line_items[0][quantity]", "1"),
-            ("payment_method_types[0]", "card"),
-            ("success_url", success_url.as_str()),
-            ("cancel_url", cancel_url.as_str()),
-            ("allow_promotion_codes", "true"),
-            ("automatic_tax[enabled]", "true"),
-            ("customer_email", user.email.as_str()),
-            ("client_reference_id", user.user_id.as_str()),
-            ("metadata[user_id]", user.user_id.as_str()),
-            ("metadata[product]", "atlas_masa_pro"),
-            (
-                "subscription_data[metadata][user_id]",
-                user.user_id.as_str(),
-            ),
-        ])
-        .send()
-        .await
-    {
-        Ok(value) => value,
-        Err(error) => {
-            return (
-                StatusCode::BAD_GATEWAY,
-                Json(serde_json::json!({
-                    "error": "stripe_network_failed",
-                    "message": error.to_string()
-                })),
-            )
-                .into_response()
+    if input.items.is_empty() {
+        return ApiError::bad_request(
+            "memory_items_required",
+            "at least one memory item is required",
+        )
+        .into_response();
+    }
+    if input.items.len() > MAX_MEMORY_IMPORT_ITEMS {
+        return ApiError::bad_request(
+            "memory_batch_too_large",
+            format!("max {} items per import request", MAX_MEMORY_IMPORT_ITEMS),
+        )
+        .into_response();
+    }
+    let mut happened_at = Vec::with_capacity(input.items.len());
+    for (index, item) in input.items.iter().enumerate() {
+        let content_field = format!("items[{index}].content");
+        if let Some(error) = note_length_error(
+            content_field.as_str(),
+            item.content.as_str(),
+            state.note_max_content_chars,
+        ) {
+            return error.into_response();
         }
-    };
+        let field = format!("items[{index}].happened_at");
+        match parse_rfc3339_or_error(field.as_str(), item.happened_at.as_deref()) {
+            Ok(value) => happened_at.push(value),
+            Err(error) => return error.into_response(),
+        }
+    }
+    let items = input.items.into_iter().zip(happened_at).collect::<Vec<_>>();
 
-    let status = response.status();
-    let body = response.text().await.unwrap_or_default();
-    if !status.is_success() {
+    let now = chrono::Utc::now();
+    if input.dry_run {
+        let opt_in = user_memory_opt_in(&state, user_id.as_str());
+        let existing_fingerprints = state
+            .user_memories
+            .read()
+            .get(&user_id)
+            .map(|records| {
+                records
+                    .iter()
+                    .map(|record| record.fingerprint.clone())
+                    .collect::<HashSet<_>>()
+            })
+            .unwrap_or_default();
+        let preview = preview_memory_import(
+            user_id.as_str(),
+            items,
+            &existing_fingerprints,
+            opt_in,
+            state.scrub_pii,
+            state.note_max_content_chars,
+            now,
+        );
+        let would_import = preview.iter().filter(|item| item.would_import).count();
         return (
-            StatusCode::BAD_GATEWAY,
+            StatusCode::OK,
             Json(serde_json::json!({
-                "error": "stripe_checkout_failed",
-                "status": status.as_u16(),
-                "response": body
+                "ok": true,
+                "dry_run": true,
+                "would_import": would_import,
+                "would_skip": preview.len() - would_import,
+                "items": preview
             })),
         )
             .into_response();
     }
 
-    let parsed: serde_json::Value = serde_json::from_str(body.as_str()).unwrap_or_default();
-    let checkout_url = parsed
-        .get("url")
-        .and_then(|value| value.as_str())
-        .unwrap_or_default()
-        .to_string();
-    let session_id = parsed
-        .get("id")
-        .and_then(|value| value.as_str())
-        .unwrap_or_default()
-        .to_string();
+    let imported: Vec<UserNoteRecord> = items
+        .into_iter()
+        .filter_map(|(item, happened_at)| {
+            memory_import_note(
+                user_id.as_str(),
+                item,
+                happened_at,
+                state.note_max_content_chars,
+                now,
+            )
+        })
+        .collect();
 
-    if checkout_url.is_empty() || session_id.is_empty() {
-        return (
-            StatusCode::BAD_GATEWAY,
-            Json(serde_json::json!({
-                "error": "stripe_checkout_parse_failed"
-            })),
+    if imported.is_empty() {
+        return ApiError::bad_request(
+            "no_valid_memory_items",
+            "all imported items were empty after sanitization",
         )
-            .into_response();
+        .into_response();
     }
 
+    let outcome = match commit_memory_import(&state, user_id.as_str(), imported).await {
+        Ok(value) => value,
+        Err(error) => {
+            return ApiError::internal("memory_import_persist_failed", error.to_string())
+                .into_response();
+        }
+    };
+
     (
         StatusCode::OK,
-        Json(BillingCheckoutResponse {
-            checkout_url,
-            checkout_session_id: session_id,
-        }),
+        Json(serde_json::json!({
+            "ok": true,
+            "imported": outcome.imported,
+            "over_limit": outcome.over_limit,
+            "total_notes": outcome.total_notes
+        })),
     )
         .into_response()
 }
 
-async fn billing_stripe_webhook(
+/// Row-by-row counterpart of `memory_import`: malformed or over-long rows are skipped and listed
+/// in `row_errors`, and the request only fails when no row can be imported.
+async fn memory_import_csv(
     State(state): State<ApiState>,
     headers: HeaderMap,
+    Query(query): Query<MemoryImportCsvQuery>,
     body: String,
 ) -> impl IntoResponse {
-    let Some(runtime) = state.billing_runtime.as_ref() else {
-        return StatusCode::SERVICE_UNAVAILABLE.into_response();
+    let user_id = match resolve_user_id(&state, &headers, query.user_id.clone()) {
+        Some(value) => value,
+        None => return ApiError::not_authenticated().into_response(),
     };
 
-    if let Some(secret) = runtime.stripe_webhook_secret.as_ref() {
-        let signature = headers
-            .get("stripe-signature")
-            .and_then(|value| value.to_str().ok())
-            .unwrap_or_default();
-        if !verify_stripe_webhook_signature(
-            signature,
-            body.as_str(),
-            secret.as_str(),
-            runtime.stripe_webhook_tolerance_seconds,
+    let is_csv = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.split(';').next())
+        .map(|value| value.trim().eq_ignore_ascii_case("text/csv"))
+        .unwrap_or(false);
+    if !is_csv {
+        return ApiError::new(
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            "csv_required",
+            "send the file with content-type text/csv",
+        )
+        .into_response();
+    }
+
+    let ParsedMemoryCsv {
+        items,
+        mut row_errors,
+    } = match parse_memory_import_csv(body.as_str()) {
+        Ok(parsed) => parsed,
+        Err(message) => {
+            return ApiError::bad_request("invalid_csv_header", message).into_response();
+        }
+    };
+    if items.len() > MAX_MEMORY_IMPORT_ITEMS {
+        return ApiError::bad_request(
+            "memory_batch_too_large",
+            format!("max {} items per import request", MAX_MEMORY_IMPORT_ITEMS),
+        )
+        .into_response();
+    }
+
+    let now = chrono::Utc::now();
+    let mut imported = Vec::new();
+    for (row, item, happened_at) in items {
+        let content_chars = item.content.trim().chars().count();
+        if content_chars > state.note_max_content_chars {
+            row_errors.push(CsvRowError {
+                row,
+                reason: format!(
+                    "content is {content_chars} characters; the limit is {}",
+                    state.note_max_content_chars
+                ),
+            });
+            continue;
+        }
+        match memory_import_note(
+            user_id.as_str(),
+            item,
+            happened_at,
+            state.note_max_content_chars,
+            now,
         ) {
-            return StatusCode::UNAUTHORIZED.into_response();
+            Some(note) => imported.push(note),
+            None => row_errors.push(CsvRowError {
+                row,
+                reason: "title and content are required".to_string(),
+            }),
         }
     }
+    row_errors.sort_by_key(|error| error.row);
 
-    let event: serde_json::Value = match serde_json::from_str(body.as_str()) {
+    if imported.is_empty() {
+        return ApiError::bad_request("no_valid_memory_items", "no CSV rows could be imported")
+            .with_details(serde_json::json!({ "row_errors": row_errors }))
+            .into_response();
+    }
+
+    let outcome = match commit_memory_import(&state, user_id.as_str(), imported).await {
         Ok(value) => value,
-        Err(_) => return StatusCode::BAD_REQUEST.into_response(),
+        Err(error) => {
+            return ApiError::internal("memory_import_persist_failed", error.to_string())
+                .into_response();
+        }
     };
 
-    let event_type = event
-        .get("type")
-        .and_then(|value| value.as_str())
-        .unwrap_or_default();
-    let object = event
-        .get("data")
-        .and_then(|value| value.get("object"))
-        .cloned()
-        .unwrap_or_default();
+    (
+        StatusCode::OK,
+        Json(serde_json::json!({
+            "ok": true,
+            "imported": outcome.imported,
+            "over_limit": outcome.over_limit,
+            "total_notes": outcome.total_notes,
+            "row_errors": row_errors
+        })),
+    )
+        .into_response()
+}
 
-    match event_type {
-        "checkout.session.completed" => {
-            let user_id = object
-                .get("metadata")
-                .and_then(|value| value.get("user_id"))
-                .and_then(|value| value.as_str())
-                .map(|value| value.to_string())
-                .or_else(|| {
-                    object
-                        .get("customer_details")
-                        .and_then(|value| value.get("email"))
-                        .and_then(|value| value.as_str())
-                        .and_then(|email| {
-                            state
-                                .users
-                                .read()
-                                .values()
-                                .find(|user| user.email == email.to_lowercase())
-                                .map(|user| user.user_id.clone())
-                        })
-                });
+/// `happened_at` is the item's timestamp as validated by the caller; the raw string on `item` is
+/// not read again.
+fn memory_import_note(
+    user_id: &str,
+    item: MemoryImportItem,
+    happened_at: Option<chrono::DateTime<chrono::Utc>>,
+    max_content_chars: usize,
+    now: chrono::DateTime<chrono::Utc>,
+) -> Option<UserNoteRecord> {
+    let title = sanitize_limited_text(item.title.as_str(), MAX_NOTE_TITLE_LEN);
+    let content = sanitize_limited_text(item.content.as_str(), max_content_chars);
+    if title.is_empty() || content.is_empty() {
+        return None;
+    }
 
-            if let Some(user_id) = user_id {
-                let billing = BillingStatusRecord {
-                    user_id: user_id.clone(),
-                    stripe_customer_id: object
-                        .get("customer")
-                        .and_then(|value| value.as_str())
-                        .map(|value| value.to_string()),
-                    stripe_subscription_id: object
-                        .get("subscription")
-                        .and_then(|value| value.as_str())
-                        .map(|value| value.to_string()),
-                    status: "active".to_string(),
-                    current_period_end: None,
-                    updated_at: chrono::Utc::now().to_rfc3339(),
-                };
-                let _ = persist_billing_status_if_configured(&state, &billing).await;
-            }
+    let mut tags = sanitize_note_tags(item.tags.unwrap_or_default());
+    if let Some(source) = item.source {
+        let source_tag = normalize_tag(source.as_str());
+        if !source_tag.is_empty() {
+            tags.push(format!("source_{}", source_tag));
         }
-        "customer.subscription.updated" | "customer.subscription.deleted" => {
-            let subscription_id = object
-                .get("id")
-                .and_then(|value| value.as_str())
-                .unwrap_or_default()
-                .to_string();
-            let customer_id = object
-                .get("customer")
-                .and_then(|value| value.as_str())
-                .map(|value| value.to_string());
-            let status = object
-                .get("status")
-                .and_then(|value| value.as_str())
-                .unwrap_or("unknown")
-                .to_string();
-            let period_end = object
-                .get("current_period_end")
-                .and_then(|value| value.as_i64())
-                .and_then(|epoch| chrono::DateTime::<chrono::Utc>::from_timestamp(epoch, 0))
-                .map(|value| value.to_rfc3339());
+    }
+    tags = sanitize_note_tags(tags);
 
-            let user_id_from_customer = if let Some(customer) = customer_id.as_ref() {
-                resolve_user_id_by_customer(&state, customer.as_str()).await
+    Some(UserNoteRecord {
+        note_id: uuid::Uuid::new_v4().to_string(),
+        user_id: user_id.to_string(),
+        title,
+        content,
+        tags,
+        updated_at: happened_at.unwrap_or(now).to_rfc3339(),
+        structured: None,
+    })
+}
+
+// Mirrors `memory_import_note` + `commit_memory_import` without touching state: notes are always
+// appended, so the reason only explains what happens on the long-term memory side. Stored memory
+// text is scrubbed before it is fingerprinted, so the preview scrubs the same way.
+fn preview_memory_import(
+    user_id: &str,
+    items: Vec<(MemoryImportItem, Option<chrono::DateTime<chrono::Utc>>)>,
+    existing_fingerprints: &HashSet<String>,
+    memory_opt_in: bool,
+    scrub: bool,
+    max_content_chars: usize,
+    now: chrono::DateTime<chrono::Utc>,
+) -> Vec<MemoryImportPreviewItem> {
+    let mut batch_fingerprints = HashSet::new();
+    items
+        .into_iter()
+        .enumerate()
+        .map(|(index, (item, happened_at))| {
+            let raw_title = item.title.clone();
+            let Some(note) = memory_import_note(user_id, item, happened_at, max_content_chars, now)
+            else {
+                return MemoryImportPreviewItem {
+                    index,
+                    title: sanitize_limited_text(raw_title.as_str(), MAX_NOTE_TITLE_LEN),
+                    would_import: false,
+                    reason: "empty_after_sanitization",
+                };
+            };
+            let mut memory_text = format!("{}: {}", note.title, note.content);
+            if scrub {
+                memory_text = scrub_pii(memory_text.as_str());
+            }
+            let memory_text = sanitize_limited_text(memory_text.as_str(), MAX_MEMORY_TEXT_LEN);
+            let fingerprint = memory_fingerprint("insight", "permanent", memory_text.as_str());
+            let reason = if !memory_opt_in {
+                "note_only_memory_opt_out"
+            } else if existing_fingerprints.contains(&fingerprint) {
+                "reinforces_existing_memory"
+            } else if !batch_fingerprints.insert(fingerprint) {
+                "duplicate_in_batch"
             } else {
-                None
+                "new_memory"
             };
-            if let Some(user_id) = user_id_from_customer {
-                let billing = BillingStatusRecord {
-                    user_id,
-                    stripe_customer_id: customer_id,
-                    stripe_subscription_id: Some(subscription_id),
-                    status,
-                    current_period_end: period_end,
-                    updated_at: chrono::Utc::now().to_rfc3339(),
-                };
-                let _ = persist_billing_status_if_configured(&state, &billing).await;
+            MemoryImportPreviewItem {
+                index,
+                title: note.title,
+                would_import: true,
+                reason,
             }
-        }
-        _ => {}
+        })
+        .collect()
+}
+
+struct MemoryImportOutcome {
+    imported: usize,
+    over_limit: usize,
+    total_notes: usize,
+}
+
+// Notes past `MAX_NOTES_PER_USER` (the oldest, which may include imported ones) are dropped, and
+// only imported notes that survived become memories, so no memory outlives its note.
+async fn commit_memory_import(
+    state: &ApiState,
+    user_id: &str,
+    imported: Vec<UserNoteRecord>,
+) -> Result<MemoryImportOutcome> {
+    let submitted = imported.len();
+    let imported_ids = imported
+        .iter()
+        .map(|note| note.note_id.clone())
+        .collect::<HashSet<_>>();
+    let (kept, total_notes) = {
+        let mut notes_map = state.user_notes.write();
+        let notes = notes_map.entry(user_id.to_string()).or_default();
+        notes.extend(imported);
+        notes.sort_by(|lhs, rhs| rhs.updated_at.cmp(&lhs.updated_at));
+        notes.truncate(MAX_NOTES_PER_USER);
+        let kept = notes
+            .iter()
+            .filter(|note| imported_ids.contains(&note.note_id))
+            .cloned()
+            .collect::<Vec<_>>();
+        (kept, notes.len())
+    };
+
+    commit_notes_change(state, user_id).await?;
+    let mut ingested_any = false;
+    for note in &kept {
+        let memory_text = format!("{}: {}", note.title, note.content);
+        ingested_any |= ingest_memory_event_in_memory(
+            state,
+            user_id,
+            MemoryIngestEvent {
+                memory_type: "insight".to_string(),
+                stability: "permanent".to_string(),
+                source: "import".to_string(),
+                text: memory_text,
+                weight: 0.72,
+                tags: note.tags.clone(),
+                happened_at: chrono::DateTime::parse_from_rfc3339(note.updated_at.as_str())
+                    .ok()
+                    .map(|value| value.with_timezone(&chrono::Utc)),
+                expires_at: None,
+            },
+        )
+        .is_some();
     }
+    if ingested_any {
+        persist_memories_if_configured(state, user_id).await?;
+    }
+    Ok(MemoryImportOutcome {
+        imported: kept.len(),
+        over_limit: submitted - kept.len(),
+        total_notes,
+    })
+}
 
-    StatusCode::OK.into_response()
+// Columns are matched by header name so spreadsheet exports with reordered or extra columns
+// still import. Rows are numbered by their line in the file (header = 1) so users can find them.
+fn parse_memory_import_csv(text: &str) -> std::result::Result<ParsedMemoryCsv, String> {
+    let mut reader = csv::ReaderBuilder::new()
+        .flexible(true)
+        .trim(csv::Trim::All)
+        .from_reader(text.trim_start_matches('\u{feff}').as_bytes());
+    let header_row = reader
+        .headers()
+        .map_err(|err| format!("unreadable header row: {}", err))?
+        .clone();
+    let column = |name: &str| {
+        header_row
+            .iter()
+            .position(|value| value.eq_ignore_ascii_case(name))
+    };
+    let (Some(title_column), Some(content_column)) = (column("title"), column("content")) else {
+        return Err("header row must include title and content columns".to_string());
+    };
+    let tags_column = column("tags");
+    let source_column = column("source");
+    let happened_at_column = column("happened_at");
+
+    let mut items = Vec::new();
+    let mut row_errors = Vec::new();
+    for record in reader.records() {
+        let record = match record {
+            Ok(record) => record,
+            Err(err) => {
+                row_errors.push(CsvRowError {
+                    row: err.position().map(|position| position.line()).unwrap_or(0),
+                    reason: "malformed CSV row".to_string(),
+                });
+                continue;
+            }
+        };
+        let row = record
+            .position()
+            .map(|position| position.line())
+            .unwrap_or(0);
+        if record.len() > header_row.len() {
+            row_errors.push(CsvRowError {
+                row,
+                reason: format!(
+                    "expected at most {} columns, found {}",
+                    header_row.len(),
+                    record.len()
+                ),
+            });
+            continue;
+        }
+        let field = |index: Option<usize>| {
+            index
+                .and_then(|index| record.get(index))
+                .filter(|value| !value.is_empty())
+                .map(str::to_string)
+        };
+        let happened_at = field(happened_at_column);
+        let Ok(parsed_happened_at) = parse_rfc3339_or_error("happened_at", happened_at.as_deref())
+        else {
+            row_errors.push(CsvRowError {
+                row,
+                reason: "happened_at must be an RFC 3339 timestamp".to_string(),
+            });
+            continue;
+        };
+        items.push((
+            row,
+            MemoryImportItem {
+                title: field(Some(title_column)).unwrap_or_default(),
+                content: field(Some(content_column)).unwrap_or_default(),
+                tags: field(tags_column).map(|value| {
+                    value
+                        .split([';', ','])
+                        .map(|tag| tag.trim().to_string())
+                        .collect()
+                }),
+                source: field(source_column),
+                happened_at,
+            },
+            parsed_happened_at,
+        ));
+    }
+    Ok(ParsedMemoryCsv { items, row_errors })
 }
 
-async fn studio_preferences_get(
+/// One `MemoryRecord` per line. The records are copied out of the lock up front and serialized
+/// as the body is written, so the full export is never held as one JSON document.
+async fn memory_export_jsonl(
     State(state): State<ApiState>,
     headers: HeaderMap,
-    Query(query): Query<UserLookupQuery>,
-) -> impl IntoResponse {
-    let user_id = match resolve_user_id(&state, &headers, query.user_id.clone()) {
-        Some(value) => value,
-        None => {
-            return (
-                StatusCode::UNAUTHORIZED,
-                Json(serde_json::json!({
-                    "error": "not_authenticated",
-                    "message": "sign in first"
-                })),
-            )
-                .into_response();
-        }
+    Query(query): Query<MemoryUserQuery>,
+) -> Response {
+    let Some(user_id) = resolve_user_id(&state, &headers, query.user_id) else {
+        return ApiError::not_authenticated().into_response();
     };
 
-    let prefs = state
-        .studio_preferences
-        .read()
-        .get(&user_id)
-        .cloned()
-        .unwrap_or_else(|| default_studio_preferences(&user_id));
+    let opt_in = user_memory_opt_in(&state, user_id.as_str());
+    let records = if opt_in {
+        state
+            .user_memories
+            .read()
+            .get(&user_id)
+            .cloned()
+            .unwrap_or_default()
+    } else {
+        Vec::new()
+    };
+    let mut response = Body::from_stream(memory_export_lines(records)).into_response();
+    let response_headers = response.headers_mut();
+    response_headers.insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static("application/x-ndjson"),
+    );
+    response_headers.insert(header::CACHE_CONTROL, HeaderValue::from_static("no-store"));
+    response_headers.insert(
+        "x-memory-opt-in",
+        HeaderValue::from_static(if opt_in { "true" } else { "false" }),
+    );
+    response
+}
 
-    (
-        StatusCode::OK,
-        Json(serde_json::json!({ "preferences": prefs })),
-    )
-        .into_response()
+// Sealed pre-scrub originals never leave the server, not even in an export.
+fn memory_export_lines(
+    records: Vec<MemoryRecord>,
+) -> impl futures::Stream<Item = std::result::Result<Vec<u8>, serde_json::Error>> {
+    futures::stream::iter(records.into_iter().map(|mut record| {
+        record.text_original_sealed = None;
+        let mut line = serde_json::to_vec(&record)?;
+        line.push(b'\n');
+        Ok(line)
+    }))
 }
 
-async fn studio_preferences_upsert(
+async fn memory_records_list(
     State(state): State<ApiState>,
     headers: HeaderMap,
-    Json(input): Json<StudioPreferencesUpsertRequest>,
+    Query(query): Query<MemoryRecordsQuery>,
 ) -> impl IntoResponse {
-    let user_id = match resolve_user_id(&state, &headers, input.user_id.clone()) {
+    let user_id = match resolve_user_id(&state, &headers, query.user_id.clone()) {
         Some(value) => value,
         None => {
-            return (
-                StatusCode::UNAUTHORIZED,
-                Json(serde_json::json!({
-                    "error": "not_authenticated",
-                    "message": "sign in first"
-                })),
-            )
-                .into_response();
+            return ApiError::not_authenticated().into_response();
         }
     };
 
-    let merged = {
-        let mut prefs_map = state.studio_preferences.write();
-        let current = prefs_map
-            .get(&user_id)
-            .cloned()
-            .unwrap_or_else(|| default_studio_preferences(&user_id));
-        let merged = merge_studio_preferences(current, input);
-        prefs_map.insert(user_id, merged.clone());
-        merged
+    let opt_in = user_memory_opt_in(&state, user_id.as_str());
+    if !opt_in {
+        let empty_items: Vec<MemoryRetrievedItem> = Vec::new();
+        return (
+            StatusCode::OK,
+            Json(serde_json::json!({
+                "memory_opt_in": false,
+                "count": 0,
+                "items": empty_items
+            })),
+        )
+            .into_response();
+    }
+
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_MEMORY_RETRIEVAL_LIMIT)
+        .clamp(1, MAX_MEMORY_RETRIEVAL_LIMIT);
+    let search = query.q.unwrap_or_default();
+    let sources = match query.sources.as_deref() {
+        None => None,
+        Some(value) => match parse_memory_sources(value) {
+            Some(sources) => Some(sources),
+            None => {
+                return ApiError::bad_request(
+                    "invalid_memory_sources",
+                    format!("sources must list any of: {}", MEMORY_SOURCES.join(", ")),
+                )
+                .into_response();
+            }
+        },
     };
-    let _ = persist_studio_preferences_if_configured(&state, merged.user_id.as_str()).await;
+    let items = retrieve_user_memory_context(
+        &state,
+        user_id.as_str(),
+        search.as_str(),
+        limit,
+        sources.as_deref(),
+    );
 
     (
         StatusCode::OK,
-        Json(serde_json::json!({ "ok": true, "preferences": merged })),
+        Json(serde_json::json!({
+            "memory_opt_in": true,
+            "count": items.len(),
+            "items": items
+        })),
     )
         .into_response()
 }
 
-async fn survey_next(
+async fn memory_search(
     State(state): State<ApiState>,
     headers: HeaderMap,
-    Query(query): Query<UserLookupQuery>,
+    Json(input): Json<MemorySearchRequest>,
 ) -> impl IntoResponse {
-    let user_id = resolve_user_id_or_guest(&state, &headers, query.user_id.clone());
-    let user_locale = resolve_request_locale(&state, &user_id, query.locale.as_deref());
+    let Some(user_id) = resolve_user_id(&state, &headers, input.user_id.clone()) else {
+        return ApiError::not_authenticated().into_response();
+    };
 
-    let survey_state = state
-        .survey_states
+    let query = sanitize_limited_text(input.query.as_str(), MAX_MEMORY_TEXT_LEN);
+    if query.is_empty() {
+        return ApiError::bad_request("invalid_query", "query is required").into_response();
+    }
+    let mut sources = Vec::new();
+    for value in input.sources.unwrap_or_default() {
+        let value = value.trim().to_lowercase();
+        if !MEMORY_SOURCES.contains(&value.as_str()) {
+            return ApiError::bad_request(
+                "invalid_memory_sources",
+                format!("sources must list any of: {}", MEMORY_SOURCES.join(", ")),
+            )
+            .into_response();
+        }
+        sources.push(value);
+    }
+    let mut types = Vec::new();
+    for value in input.types.unwrap_or_default() {
+        let value = value.trim().to_lowercase();
+        if !MEMORY_TYPES.contains(&value.as_str()) {
+            return ApiError::bad_request(
+                "invalid_memory_types",
+                format!("types must list any of: {}", MEMORY_TYPES.join(", ")),
+            )
+            .into_response();
+        }
+        types.push(value);
+    }
+    let min_score = input.min_score.unwrap_or(0.0);
+    if !(0.0..=1.0).contains(&min_score) {
+        return ApiError::bad_request("invalid_min_score", "min_score must be between 0 and 1")
+            .into_response();
+    }
+
+    if !user_memory_opt_in(&state, user_id.as_str()) {
+        let empty_items: Vec<MemoryRetrievedItem> = Vec::new();
+        return (
+            StatusCode::OK,
+            Json(serde_json::json!({
+                "memory_opt_in": false,
+                "query": query,
+                "count": 0,
+                "items": empty_items
+            })),
+        )
+            .into_response();
+    }
+
+    let snapshot = state
+        .user_memories
         .read()
         .get(&user_id)
         .cloned()
-        .unwrap_or_else(|| SurveyStateRecord {
-            user_id: user_id.clone(),
-            answers: HashMap::new(),
-            completed: false,
-            started_at: None,
-            completed_at: None,
-            updated_at: chrono::Utc::now().to_rfc3339(),
-        });
-
-    let question = next_survey_question(&user_locale, &survey_state.answers);
-    let total = survey_total_questions(&survey_state.answers);
-    let answered = survey_state.answers.len().min(total);
-    let progress = SurveyProgress {
-        answered,
-        total,
-        percent: if total == 0 {
-            0
-        } else {
-            ((answered as f32 / total as f32) * 100.0).round() as u8
+        .unwrap_or_default();
+    let items = search_memory_records(
+        snapshot.as_slice(),
+        query.as_str(),
+        &MemorySearchFilters {
+            sources: &sources,
+            types: &types,
+            min_score,
         },
-    };
+        input.limit.unwrap_or(DEFAULT_MEMORY_RETRIEVAL_LIMIT),
+        chrono::Utc::now(),
+    );
 
     (
         StatusCode::OK,
-        Json(SurveyNextResponse {
-            question,
-            progress,
-            profile_hints: build_survey_hints(&survey_state),
-        }),
+        Json(serde_json::json!({
+            "memory_opt_in": true,
+            "query": query,
+            "count": items.len(),
+            "items": items
+        })),
     )
         .into_response()
 }
 
-async fn survey_answer(
+async fn memory_upsert(
     State(state): State<ApiState>,
     headers: HeaderMap,
-    Json(input): Json<SurveyAnswerRequest>,
+    Json(input): Json<MemoryUpsertRequest>,
 ) -> impl IntoResponse {
-    if input.question_id.trim().is_empty() || input.answer.trim().is_empty() {
+    let user_id = match resolve_user_id(&state, &headers, input.user_id.clone()) {
+        Some(value) => value,
+        None => {
+            return ApiError::not_authenticated().into_response();
+        }
+    };
+
+    if !user_memory_opt_in(&state, user_id.as_str()) {
+        return ApiError::forbidden(
+            "memory_opt_out",
+            "memory ingestion is disabled for this profile",
+        )
+        .into_response();
+    }
+
+    let expires_at = match parse_rfc3339_or_error("expires_at", input.expires_at.as_deref()) {
+        Ok(value) => value,
+        Err(error) => return error.into_response(),
+    };
+    let event = MemoryIngestEvent {
+        memory_type: sanitize_memory_type(
+            input
+                .memory_type
+                .unwrap_or_else(|| "insight".to_string())
+                .as_str(),
+        ),
+        stability: sanitize_memory_stability(
+            input
+                .stability
+                .unwrap_or_else(|| "permanent".to_string())
+                .as_str(),
+        ),
+        source: sanitize_memory_source(
+            input
+                .source
+                .unwrap_or_else(|| "manual".to_string())
+                .as_str(),
+        ),
+        text: input.text,
+        weight: input.weight.unwrap_or(0.8),
+        tags: sanitize_note_tags(input.tags.unwrap_or_default()),
+        happened_at: Some(chrono::Utc::now()),
+        expires_at,
+    };
+
+    let ingested = ingest_memory_event_for_user(&state, user_id.as_str(), event).await;
+    if let Some(record) = ingested {
         return (
-            StatusCode::BAD_REQUEST,
+            StatusCode::OK,
             Json(serde_json::json!({
-                "error": "invalid_answer",
-                "message": "question_id and answer are required"
+                "ok": true,
+                "memory": record
             })),
         )
             .into_response();
     }
 
-    let user_id = resolve_user_id_or_guest(&state, &headers, input.user_id.clone());
-    let user_locale = resolve_request_locale(&state, &user_id, input.locale.as_deref());
+    ApiError::bad_request("invalid_memory", "text is required").into_response()
+}
 
-    let persisted_user = {
-        let mut states = state.survey_states.write();
-        let now = chrono::Utc::now();
-        let entry = states
-            .entry(user_id.clone())
-            .or_insert_with(|| SurveyStateRecord {
-                user_id: user_id.clone(),
-                answers: HashMap::new(),
-                completed: false,
-                started_at: None,
-                completed_at: None,
-                updated_at: now.to_rfc3339(),
-            });
-        if entry.started_at.is_none() {
-            entry.started_at = Some(now.to_rfc3339());
-        }
-        entry.answers.insert(
-            input.question_id.trim().to_string(),
-            input.answer.trim().to_string(),
-        );
-        entry.completed = next_survey_question(&user_locale, &entry.answers).is_none();
-        entry.completed_at = if entry.completed {
-            entry
-                .completed_at
-                .clone()
-                .or_else(|| Some(now.to_rfc3339()))
-        } else {
-            None
-        };
-        entry.updated_at = now.to_rfc3339();
-        entry.user_id.clone()
-    };
-    let _ = persist_survey_state_if_configured(&state, persisted_user.as_str()).await;
+async fn memory_delete(
+    State(state): State<ApiState>,
+    headers: HeaderMap,
+    Json(input): Json<MemoryDeleteRequest>,
+) -> Response {
+    delete_memory_for_caller(&state, &headers, input.user_id, input.memory_id.as_str()).await
+}
 
-    if input.question_id.trim() == "trip_style" {
-        let normalized = sanitize_enum_value(
-            input.answer.trim(),
-            &["mixed", "beach", "north", "desert"],
-            "mixed",
-        );
-        let updated_user = {
-            let mut users = state.users.write();
-            if let Some(user) = users.get_mut(&user_id) {
-                user.trip_style = Some(normalized);
-                user.updated_at = chrono::Utc::now().to_rfc3339();
-                Some(user.clone())
-            } else {
-                None
-            }
-        };
-        if let Some(user) = updated_user {
-            let _ = persist_user_if_configured(&state, &user).await;
-        }
-    }
+async fn memory_delete_by_id(
+    State(state): State<ApiState>,
+    headers: HeaderMap,
+    AxumPath(memory_id): AxumPath<String>,
+    Query(query): Query<MemoryUserQuery>,
+) -> Response {
+    delete_memory_for_caller(&state, &headers, query.user_id, memory_id.as_str()).await
+}
 
-    let survey_question_id =
-        sanitize_limited_text(input.question_id.as_str(), MAX_PROFILE_FIELD_LEN);
-    let survey_answer_value = sanitize_limited_text(input.answer.as_str(), MAX_MEMORY_TEXT_LEN);
-    if !survey_question_id.is_empty() && !survey_answer_value.is_empty() {
-        let (memory_type, stability, weight) =
-            classify_survey_memory(survey_question_id.as_str(), survey_answer_value.as_str());
-        let _ = ingest_memory_event_for_user(
-            &state,
-            user_id.as_str(),
-            MemoryIngestEvent {
-                memory_type,
-                stability,
-                source: "survey".to_string(),
-                text: format!(
-                    "Survey signal: {} => {}",
-                    survey_question_id, survey_answer_value
-                ),
-                weight,
-                tags: sanitize_note_tags(vec![format!("survey_{}", survey_question_id)]),
-                happened_at: Some(chrono::Utc::now()),
-                expires_at: None,
-            },
-        )
-        .await;
-    }
+async fn delete_memory_for_caller(
+    state: &ApiState,
+    headers: &HeaderMap,
+    explicit_user_id: Option<String>,
+    memory_id: &str,
+) -> Response {
+    let Some(user_id) = resolve_user_id(state, headers, explicit_user_id) else {
+        return ApiError::not_authenticated().into_response();
+    };
 
-    let state_snapshot =
-        state
-            .survey_states
-            .read()
-            .get(&user_id)
-            .cloned()
-            .unwrap_or(SurveyStateRecord {
-                user_id: user_id.clone(),
-                answers: HashMap::new(),
-                completed: false,
-                started_at: None,
-                completed_at: None,
-                updated_at: chrono::Utc::now().to_rfc3339(),
-            });
+    let memory_id = sanitize_limited_text(memory_id, 96);
+    if memory_id.is_empty() {
+        return ApiError::bad_request("invalid_memory_id", "memory_id is required").into_response();
+    }
 
-    let total = survey_total_questions(&state_snapshot.answers);
-    let answered = state_snapshot.answers.len().min(total);
-    let progress = SurveyProgress {
-        answered,
-        total,
-        percent: if total == 0 {
-            0
+    let deleted = {
+        let mut memories_map = state.user_memories.write();
+        if let Some(records) = memories_map.get_mut(&user_id) {
+            let before = records.len();
+            records.retain(|entry| entry.memory_id != memory_id);
+            before != records.len()
         } else {
-            ((answered as f32 / total as f32) * 100.0).round() as u8
-        },
+            false
+        }
     };
+    if deleted {
+        let _ = persist_memories_if_configured(state, user_id.as_str()).await;
+    }
 
     (
         StatusCode::OK,
-        Json(SurveyNextResponse {
-            question: next_survey_question(&user_locale, &state_snapshot.answers),
-            progress,
-            profile_hints: build_survey_hints(&state_snapshot),
-        }),
+        Json(serde_json::json!({
+            "ok": true,
+            "deleted": deleted
+        })),
     )
         .into_response()
 }
 
-async fn feed_proactive(
+async fn memory_clear(
     State(state): State<ApiState>,
     headers: HeaderMap,
-    Query(query): Query<UserLookupQuery>,
-) -> impl IntoResponse {
-    let user_id = resolve_user_id_or_guest(&state, &headers, query.user_id.clone());
-    let request_locale = resolve_request_locale(&state, &user_id, query.locale.as_deref());
-    let response = build_proactive_feed_response(&state, user_id.as_str(), request_locale.as_str());
-    (StatusCode::OK, Json(response)).into_response()
-}
-
-async fn execution_checkin_submit(
-    State(state): State<ApiState>,
-    headers: HeaderMap,
-    Json(input): Json<ExecutionCheckinRequest>,
+    Json(input): Json<MemoryClearRequest>,
 ) -> impl IntoResponse {
     let user_id = match resolve_user_id(&state, &headers, input.user_id.clone()) {
         Some(value) => value,
         None => {
-            return (
-                StatusCode::UNAUTHORIZED,
-                Json(serde_json::json!({
-                    "error": "not_authenticated",
-                    "message": "sign in first"
-                })),
-            )
-                .into_response();
+            return ApiError::not_authenticated().into_response();
         }
     };
 
-    let daily_focus = sanitize_limited_text(input.daily_focus.as_str(), MAX_MEMORY_TEXT_LEN);
-    if daily_focus.is_empty() {
-        return (
-            StatusCode::BAD_REQUEST,
-            Json(serde_json::json!({
-                "error": "invalid_daily_focus",
-                "message": "daily_focus is required"
-            })),
+    let scope = sanitize_enum_value(
+        input.scope.unwrap_or_else(|| "all".to_string()).as_str(),
+        &["all", "permanent", "transient"],
+        "all",
+    );
+    let tag_match = sanitize_enum_value(
+        input
+            .tag_match
+            .unwrap_or_else(|| "any".to_string())
+            .as_str(),
+        &["any", "all"],
+        "any",
+    );
+    let tags_requested = input.tags.is_some();
+    let tags = sanitize_note_tags(input.tags.unwrap_or_default());
+    if tags_requested && tags.is_empty() {
+        return ApiError::bad_request(
+            "invalid_memory_tags",
+            "tags must contain at least one non-empty tag",
         )
-            .into_response();
+        .into_response();
     }
-    let now = chrono::Utc::now();
-    let checkin = ExecutionCheckinRecord {
-        checkin_id: uuid::Uuid::new_v4().to_string(),
-        user_id: user_id.clone(),
-        daily_focus: daily_focus.clone(),
-        mid_term_focus: input
-            .mid_term_focus
-            .map(|value| sanitize_limited_text(value.as_str(), MAX_MEMORY_TEXT_LEN))
-            .filter(|value| !value.is_empty()),
-        long_term_focus: input
-            .long_term_focus
-            .map(|value| sanitize_limited_text(value.as_str(), MAX_MEMORY_TEXT_LEN))
-            .filter(|value| !value.is_empty()),
-        blocker: input
-            .blocker
-            .map(|value| sanitize_limited_text(value.as_str(), MAX_MEMORY_TEXT_LEN))
-            .filter(|value| !value.is_empty()),
-        next_action_now: input
-            .next_action_now
-            .map(|value| sanitize_limited_text(value.as_str(), MAX_MEMORY_TEXT_LEN))
-            .filter(|value| !value.is_empty()),
-        energy_level: input.energy_level.map(|value| value.clamp(1, 5)),
-        mood: input
-            .mood
-            .map(|value| sanitize_limited_text(value.as_str(), MAX_PROFILE_FIELD_LEN))
-            .filter(|value| !value.is_empty()),
-        gym_today: input.gym_today,
-        money_today: input.money_today,
-        created_at: now.to_rfc3339(),
+    let filter = MemoryClearFilter {
+        scope: scope.as_str(),
+        tags: tags.as_slice(),
+        match_all_tags: tag_match == "all",
     };
-
-    {
-        let mut checkins = state.execution_checkins.write();
-        let history = checkins.entry(user_id.clone()).or_default();
-        history.push(checkin.clone());
-        history.sort_by(|lhs, rhs| rhs.created_at.cmp(&lhs.created_at));
-        history.truncate(180);
-    }
-    let _ = persist_checkins_if_configured(&state, user_id.as_str()).await;
-
-    let mut memory_tags = vec!["checkin".to_string(), "daily_execution".to_string()];
-    if checkin.energy_level.unwrap_or(3) <= 2 {
-        memory_tags.push("low_energy".to_string());
-    }
-    match checkin.gym_today {
-        Some(true) => memory_tags.push("gym_done".to_string()),
-        Some(false) => memory_tags.push("gym_missed".to_string()),
-        None => {}
-    }
-    match checkin.money_today {
-        Some(true) => memory_tags.push("money_progress".to_string()),
-        Some(false) => memory_tags.push("money_gap".to_string()),
-        None => {}
+    let removed = clear_user_memories(&state, user_id.as_str(), &filter).await;
+    let cleared = removed.len();
+    let now = chrono::Utc::now();
+    sweep_expired_memory_trash(&state, now).await;
+    let restore_id = (!removed.is_empty()).then(|| uuid::Uuid::new_v4().to_string());
+    if let Some(restore_id) = restore_id.as_ref() {
+        let trashed = removed
+            .into_iter()
+            .map(|memory| TrashedMemory {
+                memory,
+                restore_id: restore_id.clone(),
+                deleted_at: now,
+            })
+            .collect::<Vec<_>>();
+        if let Err(err) =
+            persist_trashed_memories_if_configured(&state, user_id.as_str(), &trashed).await
+        {
+            tracing::warn!(error = %err, "failed to persist cleared memories to the trash");
+        }
+        state
+            .deleted_memories
+            .write()
+            .entry(user_id.clone())
+            .or_default()
+            .extend(trashed);
     }
-    let _ = ingest_memory_event_for_user(
-        &state,
-        user_id.as_str(),
-        MemoryIngestEvent {
-            memory_type: "task".to_string(),
-            stability: "transient".to_string(),
-            source: "system".to_string(),
-            text: format!(
-                "Check-in focus: {} | blocker: {} | next action: {} | gym_today: {} | money_today: {}",
-                checkin.daily_focus,
-                checkin
-                    .blocker
-                    .clone()
-                    .unwrap_or_else(|| "none".to_string()),
-                checkin
-                    .next_action_now
-                    .clone()
-                    .unwrap_or_else(|| "not_set".to_string()),
-                checkin
-                    .gym_today
-                    .map(|value| if value { "yes" } else { "no" })
-                    .unwrap_or("unknown"),
-                checkin
-                    .money_today
-                    .map(|value| if value { "yes" } else { "no" })
-                    .unwrap_or("unknown")
-            ),
-            weight: 0.84,
-            tags: memory_tags,
-            happened_at: Some(now),
-            expires_at: Some(now + chrono::Duration::days(3)),
-        },
-    )
-    .await;
 
-    let locale = resolve_request_locale(&state, &user_id, None);
-    let refreshed = build_proactive_feed_response(&state, user_id.as_str(), locale.as_str());
     (
         StatusCode::OK,
         Json(serde_json::json!({
             "ok": true,
-            "checkin": checkin,
-            "feed": refreshed
+            "scope": scope,
+            "tags": tags,
+            "tag_match": tag_match,
+            "cleared": cleared,
+            "restore_id": restore_id,
+            "restorable_until": restore_id
+                .as_ref()
+                .map(|_| (now + chrono::Duration::hours(MEMORY_TRASH_TTL_HOURS)).to_rfc3339())
         })),
     )
         .into_response()
 }
 
-#[derive(Debug, Clone, Deserialize, Default)]
-struct ExecutionRefreshRequest {
-    user_id: Option<String>,
-    locale: Option<String>,
-}
-
-async fn execution_refresh(
-    State(state): State<ApiState>,
-    headers: HeaderMap,
-    Json(input): Json<ExecutionRefreshRequest>,
-) -> impl IntoResponse {
-    let user_id = resolve_user_id_or_guest(&state, &headers, input.user_id.clone());
-    let request_locale = resolve_request_locale(&state, &user_id, input.locale.as_deref());
-    let response = build_proactive_feed_response(&state, user_id.as_str(), request_locale.as_str());
-    (StatusCode::OK, Json(response)).into_response()
-}
-
-async fn execution_controls_get(
+async fn memory_restore(
     State(state): State<ApiState>,
     headers: HeaderMap,
+    Json(input): Json<MemoryRestoreRequest>,
 ) -> impl IntoResponse {
-    let user_id = match session_user_from_headers(&state, &headers) {
-        Some(user) => user.user_id,
+    let user_id = match resolve_user_id(&state, &headers, input.user_id.clone()) {
+        Some(value) => value,
         None => {
-            return (
-                StatusCode::UNAUTHORIZED,
-                Json(serde_json::json!({
-                    "error": "not_authenticated",
-                    "message": "sign in first"
-                })),
-            )
-                .into_response();
+            return ApiError::not_authenticated().into_response();
         }
     };
-    let controls = get_execution_controls(&state, user_id.as_str());
-    (
-        StatusCode::OK,
-        Json(serde_json::json!({
-            "controls": controls
-        })),
-    )
-        .into_response()
-}
 
-async fn execution_controls_upsert(
-    State(state): State<ApiState>,
-    headers: HeaderMap,
-    Json(input): Json<ExecutionControlsUpsertRequest>,
-) -> impl IntoResponse {
-    let user_id = match session_user_from_headers(&state, &headers) {
-        Some(user) => user.user_id,
-        None => {
-            return (
-                StatusCode::UNAUTHORIZED,
-                Json(serde_json::json!({
-                    "error": "not_authenticated",
-                    "message": "sign in first"
-                })),
-            )
-                .into_response();
-        }
+    sweep_expired_memory_trash(&state, chrono::Utc::now()).await;
+    let restore_id = input
+        .restore_id
+        .as_deref()
+        .map(str::trim)
+        .filter(|value| !value.is_empty());
+    let trashed = {
+        let mut trash = state.deleted_memories.write();
+        let Some(entries) = trash.get_mut(&user_id) else {
+            return memory_restore_not_found();
+        };
+        let (matched, kept) = std::mem::take(entries)
+            .into_iter()
+            .partition::<Vec<_>, _>(|entry| {
+                restore_id.is_none_or(|restore_id| entry.restore_id == restore_id)
+            });
+        *entries = kept;
+        matched
     };
-    let updated = {
-        let mut map = state.execution_controls.write();
-        let mut record = map
-            .get(&user_id)
-            .cloned()
-            .unwrap_or_else(|| default_execution_controls(&user_id));
-        if let Some(cadence) = input.cadence {
-            record.cadence =
-                sanitize_enum_value(cadence.as_str(), &["steady", "aggressive"], "steady");
-        }
-        if let Some(detail_level) = input.detail_level {
-            record.detail_level = sanitize_enum_value(
-                detail_level.as_str(),
-                &["concise", "standard", "expanded"],
-                "standard",
-            );
-        }
-        if let Some(value) = input.include_company_awareness {
-            record.include_company_awareness = value;
-        }
-        if let Some(value) = input.include_reminder_suggestions {
-            record.include_reminder_suggestions = value;
-        }
-        record.updated_at = chrono::Utc::now().to_rfc3339();
-        map.insert(user_id.clone(), record.clone());
-        record
+    if trashed.is_empty() {
+        return memory_restore_not_found();
+    }
+
+    let taken = trashed
+        .iter()
+        .map(|entry| (entry.restore_id.clone(), entry.memory.memory_id.clone()))
+        .collect::<Vec<_>>();
+    let (restored, skipped, over_limit) = {
+        let mut memories_map = state.user_memories.write();
+        let records = memories_map.entry(user_id.clone()).or_default();
+        restore_trashed_memories(records, trashed)
     };
-    let _ = persist_execution_controls_if_configured(&state, user_id.as_str()).await;
+    if restored > 0 {
+        let _ = persist_memories_if_configured(&state, user_id.as_str()).await;
+    }
+    // Entries that did not fit under the per-user cap stay in the trash for a later restore.
+    let resolved = taken
+        .into_iter()
+        .filter(|(restore_id, memory_id)| {
+            !over_limit.iter().any(|entry| {
+                entry.restore_id == *restore_id && entry.memory.memory_id == *memory_id
+            })
+        })
+        .collect::<Vec<_>>();
+    let _ = delete_trashed_memories_if_configured(&state, &resolved).await;
+    let left_in_trash = over_limit.len();
+    if !over_limit.is_empty() {
+        state
+            .deleted_memories
+            .write()
+            .entry(user_id.clone())
+            .or_default()
+            .extend(over_limit);
+    }
+
     (
         StatusCode::OK,
         Json(serde_json::json!({
             "ok": true,
-            "controls": updated
+            "restored": restored,
+            "skipped_duplicates": skipped,
+            "over_limit": left_in_trash
         })),
     )
         .into_response()
 }
 
-async fn company_status(State(state): State<ApiState>) -> impl IntoResponse {
-    (StatusCode::OK, Json(state.company_status.clone())).into_response()
+fn memory_restore_not_found() -> Response {
+    ApiError::not_found(
+        "nothing_to_restore",
+        "no cleared memories are waiting to be restored; the trash keeps them for 24 hours",
+    )
+    .into_response()
 }
 
-async fn feedback_submit(
+async fn billing_create_checkout_session(
     State(state): State<ApiState>,
     headers: HeaderMap,
-    Json(input): Json<FeedbackSubmitRequest>,
+    Json(_input): Json<BillingCheckoutRequest>,
 ) -> impl IntoResponse {
-    let message = sanitize_limited_text(input.message.trim(), MAX_FEEDBACK_MESSAGE_LEN);
-    if message.is_empty() {
+    let Some(user) = session_user_from_headers(&state, &headers) else {
+        return ApiError::not_authenticated().into_response();
+    };
+
+    if is_subscription_bypass_email(user.email.as_str()) {
+        let now = chrono::Utc::now().to_rfc3339();
+        let billing = BillingStatusRecord {
+            user_id: user.user_id.clone(),
+            stripe_customer_id: None,
+            stripe_subscription_id: None,
+            status: "owner_bypass".to_string(),
+            current_period_end: None,
+            updated_at: now,
+        };
+        let _ = persist_billing_status_if_configured(&state, &billing).await;
+
         return (
-            StatusCode::BAD_REQUEST,
+            StatusCode::OK,
             Json(serde_json::json!({
-                "error": "invalid_message",
-                "message": "feedback message is required"
+                "checkout_url": "https://atlasmasa.com/concierge-local.html?billing=owner_bypass",
+                "checkout_session_id": "owner-bypass",
+                "bypass": true
             })),
         )
             .into_response();
     }
 
-    let user_id = resolve_user_id(&state, &headers, input.user_id.clone());
-    let tags = input
-        .tags
-        .unwrap_or_default()
-        .into_iter()
-        .take(MAX_FEEDBACK_TAGS)
-        .map(|value| sanitize_limited_text(value.trim(), MAX_FEEDBACK_TAG_LEN))
-        .filter(|value| !value.is_empty())
-        .collect::<Vec<_>>();
-    let target_employee = sanitize_limited_text(
-        input
-            .target_employee
-            .unwrap_or_else(|| "product_team".to_string())
-            .trim()
-            .to_lowercase()
-            .as_str(),
-        MAX_PROFILE_FIELD_LEN,
-    );
-    let source = sanitize_limited_text(
-        input
-            .source
-            .unwrap_or_else(|| "web".to_string())
-            .trim()
-            .to_lowercase()
-            .as_str(),
-        MAX_PROFILE_FIELD_LEN,
-    );
+    let Some(runtime) = state.billing_runtime.as_ref() else {
+        return ApiError::service_unavailable(
+            "billing_unavailable",
+            "Stripe billing is not configured",
+        )
+        .into_response();
+    };
 
-    let item = FeedbackRecord {
-        feedback_id: uuid::Uuid::new_v4().to_string(),
-        user_id,
-        category: sanitize_enum_value(
-            input.category.trim(),
-            &["product", "ux", "bug", "safety", "support", "other"],
-            "other",
-        ),
-        severity: sanitize_enum_value(
-            input
-                .severity
-                .unwrap_or_else(|| "normal".to_string())
-                .as_str(),
-            &["low", "normal", "high", "critical"],
-            "normal",
-        ),
-        message,
-        tags,
-        target_employee: if target_employee.is_empty() {
-            "product_team".to_string()
-        } else {
-            target_employee
-        },
-        source: if source.is_empty() {
-            "web".to_string()
-        } else {
-            source
-        },
-        status: "new".to_string(),
-        created_at: chrono::Utc::now().to_rfc3339(),
+    // Authoritative billing config is server-side only. Do not allow client overrides
+    // for Stripe price IDs or redirect URLs to prevent plan tampering/open redirects.
+    let price_id = runtime.monthly_price_id.clone();
+    let success_url = runtime.success_url.clone();
+    let cancel_url = runtime.cancel_url.clone();
+
+    let response = match state
+        .http_client
+        .post("https://api.stripe.com/v1/checkout/sessions")
+        .bearer_auth(runtime.stripe_secret_key.as_str())
+        .form(&[
+            ("mode", "subscription"),
+            ("line_items[0][price]", price_id.as_str()),
+            ("line_items[0][quantity]", "1"),
+            ("payment_method_types[0]", "card"),
+            ("success_url", success_url.as_str()),
+            ("cancel_url", cancel_url.as_str()),
+            ("allow_promotion_codes", "true"),
+            ("automatic_tax[enabled]", "true"),
+            ("customer_email", user.email.as_str()),
+            ("client_reference_id", user.user_id.as_str()),
+            ("metadata[user_id]", user.user_id.as_str()),
+            ("metadata[product]", "atlas_masa_pro"),
+            (
+                "subscription_data[metadata][user_id]",
+                user.user_id.as_str(),
+            ),
+        ])
+        .send()
+        .await
+    {
+        Ok(value) => value,
+        Err(error) => {
+            return ApiError::bad_gateway("stripe_network_failed", error.to_string())
+                .into_response()
+        }
     };
 
-    state.feedback_items.write().push(item.clone());
-    let _ = persist_feedback_if_configured(&state).await;
-    if let Some(feedback_user_id) = item.user_id.as_ref() {
-        let _ = ingest_memory_event_for_user(
-            &state,
-            feedback_user_id.as_str(),
-            MemoryIngestEvent {
-                memory_type: "friction".to_string(),
-                stability: "transient".to_string(),
-                source: "feedback".to_string(),
-                text: format!(
-                    "Feedback {} [{}]: {}",
-                    item.category, item.severity, item.message
-                ),
-                weight: if item.severity == "critical" {
-                    0.95
-                } else if item.severity == "high" {
-                    0.85
-                } else {
-                    0.72
-                },
-                tags: item.tags.clone(),
-                happened_at: Some(chrono::Utc::now()),
-                expires_at: Some(
-                    chrono::Utc::now() + chrono::Duration::days(TRANSIENT_MEMORY_TTL_DAYS),
-                ),
-            },
+    let status = response.status();
+    let body = response.text().await.unwrap_or_default();
+    if !status.is_success() {
+        return ApiError::bad_gateway(
+            "stripe_checkout_failed",
+            "Stripe rejected the checkout session request",
         )
-        .await;
+        .with_details(serde_json::json!({ "status": status.as_u16(), "response": body }))
+        .into_response();
+    }
+
+    let parsed: serde_json::Value = serde_json::from_str(body.as_str()).unwrap_or_default();
+    let checkout_url = parsed
+        .get("url")
+        .and_then(|value| value.as_str())
+        .unwrap_or_default()
+        .to_string();
+    let session_id = parsed
+        .get("id")
+        .and_then(|value| value.as_str())
+        .unwrap_or_default()
+        .to_string();
+
+    if checkout_url.is_empty() || session_id.is_empty() {
+        return ApiError::bad_gateway(
+            "stripe_checkout_parse_failed",
+            "Stripe returned an unexpected checkout session",
+        )
+        .into_response();
     }
 
     (
         StatusCode::OK,
-        Json(serde_json::json!({
-            "ok": true,
-            "feedback": item
-        })),
+        Json(BillingCheckoutResponse {
+            checkout_url,
+            checkout_session_id: session_id,
+        }),
     )
         .into_response()
 }
 
-async fn feedback_for_employee(
+async fn billing_stripe_webhook(
     State(state): State<ApiState>,
-    AxumPath(employee): AxumPath<String>,
-    Query(query): Query<FeedbackListQuery>,
+    headers: HeaderMap,
+    body: String,
 ) -> impl IntoResponse {
-    let employee_normalized = employee.trim().to_lowercase();
-    let limit = query.limit.unwrap_or(30).clamp(1, 200);
-
-    let mut items = state
-        .feedback_items
-        .read()
-        .iter()
-        .filter(|entry| entry.target_employee == employee_normalized)
-        .cloned()
-        .collect::<Vec<_>>();
-    items.sort_by(|lhs, rhs| rhs.created_at.cmp(&lhs.created_at));
-    items.truncate(limit);
-
-    (
-        StatusCode::OK,
-        Json(serde_json::json!({
-            "employee": employee_normalized,
-            "count": items.len(),
-            "items": items
-        })),
-    )
-        .into_response()
-}
+    let Some(runtime) = state.billing_runtime.as_ref() else {
+        return StatusCode::SERVICE_UNAVAILABLE.into_response();
+    };
 
-fn build_action_telemetry(
-    action: &str,
-    success: bool,
-    app: Option<&str>,
-    supports_direct_write: bool,
-    fallback_used: bool,
-    primary_target: Option<String>,
-    warnings: Vec<String>,
-) -> ActionTelemetry {
-    ActionTelemetry {
-        trace_id: uuid::Uuid::new_v4().to_string(),
-        action: action.to_string(),
-        success,
-        app: app.map(|value| value.to_string()),
-        supports_direct_write,
-        fallback_used,
-        primary_target,
-        warnings,
-        generated_at: chrono::Utc::now().to_rfc3339(),
+    if let Some(secret) = runtime.stripe_webhook_secret.as_ref() {
+        let signature = headers
+            .get("stripe-signature")
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or_default();
+        if !verify_stripe_webhook_signature(
+            signature,
+            body.as_str(),
+            secret.as_str(),
+            runtime.stripe_webhook_tolerance_seconds,
+        ) {
+            return StatusCode::UNAUTHORIZED.into_response();
+        }
     }
-}
 
-fn action_error_response(
-    status: StatusCode,
-    action: &str,
-    error: &str,
-    message: &str,
-    app: Option<&str>,
-) -> Response {
-    let telemetry = build_action_telemetry(
-        action,
-        false,
-        app,
-        false,
-        false,
-        None,
-        vec![error.to_string()],
-    );
-    (
-        status,
-        Json(serde_json::json!({
-            "error": error,
-            "message": message,
-            "telemetry": telemetry,
-        })),
-    )
-        .into_response()
-}
+    let event: serde_json::Value = match serde_json::from_str(body.as_str()) {
+        Ok(value) => value,
+        Err(_) => return StatusCode::BAD_REQUEST.into_response(),
+    };
 
-fn build_google_calendar_url(
-    title: &str,
-    details: &str,
-    start: chrono::DateTime<chrono::Utc>,
-    end: chrono::DateTime<chrono::Utc>,
-) -> (String, bool) {
-    let details_for_url = sanitize_limited_text(details, MAX_REMINDER_DETAILS_FOR_URL);
-    let details_truncated = details_for_url != details;
-    let url = format!(
-        "https://calendar.google.com/calendar/render?action=TEMPLATE&text={}&details={}&dates={}/{}&ctz=UTC&sf=true&output=xml",
-        pct_encode(title),
-        pct_encode(details_for_url.as_str()),
-        start.format("%Y%m%dT%H%M%SZ"),
-        end.format("%Y%m%dT%H%M%SZ")
-    );
-    (url, details_truncated)
-}
+    let event_type = event
+        .get("type")
+        .and_then(|value| value.as_str())
+        .unwrap_or_default();
+    let object = event
+        .get("data")
+        .and_then(|value| value.get("object"))
+        .cloned()
+        .unwrap_or_default();
 
-fn build_shortcuts_url(shortcut_name: &str, payload: &str) -> Option<String> {
-    let url = format!(
-        "shortcuts://run-shortcut?name={}&input=text&text={}",
-        pct_encode(shortcut_name),
-        pct_encode(payload)
-    );
-    if url.len() > MAX_SHORTCUTS_URL_LEN {
-        None
-    } else {
-        Some(url)
-    }
-}
+    match event_type {
+        "checkout.session.completed" => {
+            let user_id = object
+                .get("metadata")
+                .and_then(|value| value.get("user_id"))
+                .and_then(|value| value.as_str())
+                .map(|value| value.to_string())
+                .or_else(|| {
+                    object
+                        .get("customer_details")
+                        .and_then(|value| value.get("email"))
+                        .and_then(|value| value.as_str())
+                        .and_then(|email| {
+                            state
+                                .users
+                                .read()
+                                .values()
+                                .find(|user| user.email == email.to_lowercase())
+                                .map(|user| user.user_id.clone())
+                        })
+                });
 
-fn build_shortcuts_url_with_fallback(
-    shortcut_name: &str,
-    full_payload: &str,
-    compact_payload: &str,
-) -> (Option<String>, bool) {
-    if let Some(url) = build_shortcuts_url(shortcut_name, full_payload) {
-        return (Some(url), false);
-    }
-    (build_shortcuts_url(shortcut_name, compact_payload), true)
-}
+            if let Some(user_id) = user_id {
+                let billing = BillingStatusRecord {
+                    user_id: user_id.clone(),
+                    stripe_customer_id: object
+                        .get("customer")
+                        .and_then(|value| value.as_str())
+                        .map(|value| value.to_string()),
+                    stripe_subscription_id: object
+                        .get("subscription")
+                        .and_then(|value| value.as_str())
+                        .map(|value| value.to_string()),
+                    status: "active".to_string(),
+                    current_period_end: None,
+                    updated_at: chrono::Utc::now().to_rfc3339(),
+                };
+                let _ = persist_billing_status_if_configured(&state, &billing).await;
+            }
+        }
+        "customer.subscription.updated" | "customer.subscription.deleted" => {
+            let subscription_id = object
+                .get("id")
+                .and_then(|value| value.as_str())
+                .unwrap_or_default()
+                .to_string();
+            let customer_id = object
+                .get("customer")
+                .and_then(|value| value.as_str())
+                .map(|value| value.to_string());
+            let status = object
+                .get("status")
+                .and_then(|value| value.as_str())
+                .unwrap_or("unknown")
+                .to_string();
+            let period_end = object
+                .get("current_period_end")
+                .and_then(|value| value.as_i64())
+                .and_then(|epoch| chrono::DateTime::<chrono::Utc>::from_timestamp(epoch, 0))
+                .map(|value| value.to_rfc3339());
 
-fn sanitize_alarm_days(days: Option<Vec<String>>) -> Vec<String> {
-    let mut out = Vec::new();
-    let mut seen = HashSet::new();
-    let incoming = days.unwrap_or_else(|| {
-        vec![
-            "Sun".to_string(),
-            "Mon".to_string(),
-            "Tue".to_string(),
-            "Wed".to_string(),
-            "Thu".to_string(),
-        ]
-    });
-    for day in incoming {
-        let lower = day.trim().to_lowercase();
-        let normalized = match lower.as_str() {
-            "sun" | "sunday" => Some("Sun"),
-            "mon" | "monday" => Some("Mon"),
-            "tue" | "tues" | "tuesday" => Some("Tue"),
-            "wed" | "wednesday" => Some("Wed"),
-            "thu" | "thurs" | "thursday" => Some("Thu"),
-            "fri" | "friday" => Some("Fri"),
-            "sat" | "saturday" => Some("Sat"),
-            _ => None,
-        };
-        if let Some(value) = normalized {
-            if seen.insert(value) {
-                out.push(value.to_string());
+            let user_id_from_customer = if let Some(customer) = customer_id.as_ref() {
+                resolve_user_id_by_customer(&state, customer.as_str()).await
+            } else {
+                None
+            };
+            if let Some(user_id) = user_id_from_customer {
+                let billing = BillingStatusRecord {
+                    user_id,
+                    stripe_customer_id: customer_id,
+                    stripe_subscription_id: Some(subscription_id),
+                    status,
+                    current_period_end: period_end,
+                    updated_at: chrono::Utc::now().to_rfc3339(),
+                };
+                let _ = persist_billing_status_if_configured(&state, &billing).await;
             }
         }
+        _ => {}
     }
-    if out.is_empty() {
-        vec![
-            "Sun".to_string(),
-            "Mon".to_string(),
-            "Tue".to_string(),
-            "Wed".to_string(),
-            "Thu".to_string(),
-        ]
-    } else {
-        out
-    }
+
+    StatusCode::OK.into_response()
 }
 
-async fn action_reminder(
+async fn studio_preferences_get(
     State(state): State<ApiState>,
     headers: HeaderMap,
-    Json(input): Json<ReminderActionRequest>,
+    Query(query): Query<UserLookupQuery>,
 ) -> impl IntoResponse {
-    if input.title.trim().is_empty() {
-        return action_error_response(
-            StatusCode::BAD_REQUEST,
-            "reminder",
-            "invalid_title",
-            "title is required",
-            None,
-        );
-    }
+    let user_id = match resolve_user_id(&state, &headers, query.user_id.clone()) {
+        Some(value) => value,
+        None => {
+            return ApiError::not_authenticated().into_response();
+        }
+    };
 
-    let user_id = resolve_user_id_or_guest(&state, &headers, None);
-    let locale = state
-        .users
-        .read()
-        .get(&user_id)
-        .map(|user| {
-            sanitize_enum_value(user.locale.as_str(), &["he", "en", "ar", "ru", "fr"], "en")
-        })
-        .unwrap_or_else(|| "en".to_string());
-    let is_he = locale == "he";
     let prefs = state
         .studio_preferences
         .read()
@@ -4170,4313 +6712,13157 @@ async fn action_reminder(
         .cloned()
         .unwrap_or_else(|| default_studio_preferences(&user_id));
 
-    let app = sanitize_enum_value(
-        input
-            .reminders_app
-            .unwrap_or_else(|| prefs.reminders_app.clone())
-            .as_str(),
-        &[
-            "google_calendar",
-            "apple_reminders",
-            "shortcuts",
-            "todoist",
-            "notion",
-        ],
-        "google_calendar",
-    );
+    (
+        StatusCode::OK,
+        Json(serde_json::json!({ "preferences": prefs })),
+    )
+        .into_response()
+}
 
-    let mut warnings = Vec::new();
-    let title = sanitize_limited_text(input.title.trim(), MAX_REMINDER_TITLE_LEN);
-    if title.is_empty() {
-        return action_error_response(
-            StatusCode::BAD_REQUEST,
-            "reminder",
-            "invalid_title",
-            "title is required",
-            Some(app.as_str()),
-        );
-    }
-    let details = sanitize_limited_text(
-        input.details.unwrap_or_default().as_str(),
-        MAX_REMINDER_DETAILS_LEN,
-    );
-    let requested_duration = input.duration_minutes.unwrap_or(30);
-    let duration_minutes =
-        requested_duration.clamp(MIN_REMINDER_DURATION_MINUTES, MAX_REMINDER_DURATION_MINUTES);
-    if duration_minutes != requested_duration {
-        warnings.push("duration_minutes_clamped".to_string());
-    }
+async fn studio_preferences_upsert(
+    State(state): State<ApiState>,
+    headers: HeaderMap,
+    Json(input): Json<StudioPreferencesUpsertRequest>,
+) -> impl IntoResponse {
+    let user_id = match resolve_user_id(&state, &headers, input.user_id.clone()) {
+        Some(value) => value,
+        None => {
+            return ApiError::not_authenticated().into_response();
+        }
+    };
 
-    let start = parse_or_default_utc(
-        input.due_at_utc.as_deref(),
-        chrono::Utc::now() + chrono::Duration::hours(2),
-    );
-    let end = start + chrono::Duration::minutes(duration_minutes as i64);
-    let (google_calendar_url, details_truncated) =
-        build_google_calendar_url(title.as_str(), details.as_str(), start, end);
-    if details_truncated {
-        warnings.push("details_truncated_for_google_calendar_url".to_string());
-    }
+    let mut coerced_fields = Vec::new();
+    let merged = {
+        let mut prefs_map = state.studio_preferences.write();
+        let current = prefs_map
+            .get(&user_id)
+            .cloned()
+            .unwrap_or_else(|| default_studio_preferences(&user_id));
+        let merged = merge_studio_preferences(current, input, &mut coerced_fields);
+        prefs_map.insert(user_id, merged.clone());
+        merged
+    };
+    let _ = persist_studio_preferences_if_configured(&state, merged.user_id.as_str()).await;
 
-    let ics_content = format!(
-        "BEGIN:VCALENDAR\r\nVERSION:2.0\r\nPRODID:-//AtlasMasa//Reminder//EN\r\nMETHOD:PUBLISH\r\nBEGIN:VEVENT\r\nUID:{}\r\nDTSTAMP:{}\r\nDTSTART:{}\r\nDTEND:{}\r\nSUMMARY:{}\r\nDESCRIPTION:{}\r\nEND:VEVENT\r\nEND:VCALENDAR\r\n",
-        uuid::Uuid::new_v4(),
-        chrono::Utc::now().format("%Y%m%dT%H%M%SZ"),
-        start.format("%Y%m%dT%H%M%SZ"),
-        end.format("%Y%m%dT%H%M%SZ"),
-        escape_ics(title.as_str()),
-        escape_ics(details.as_str())
-    );
-    let shortcuts_payload = format!(
-        "Action: Create reminder\nTitle: {}\nWhen (UTC): {}\nDuration (minutes): {}\nDetails: {}",
-        title,
-        start.to_rfc3339(),
-        duration_minutes,
-        details
-    );
-    let shortcuts_compact_payload = format!(
-        "Create reminder: {} at {} UTC for {} minutes",
-        title,
-        start.format("%Y-%m-%d %H:%M"),
-        duration_minutes
-    );
-    let (shortcuts_url, shortcuts_compact_used) = build_shortcuts_url_with_fallback(
-        "AtlasMasaReminder",
-        &shortcuts_payload,
-        &shortcuts_compact_payload,
-    );
-    if shortcuts_compact_used {
-        warnings.push("shortcuts_compact_payload_used".to_string());
-    }
-    if shortcuts_url.is_none() {
-        warnings.push("shortcuts_url_unavailable".to_string());
-    }
-    let todoist_url = format!(
-        "https://todoist.com/app/add?content={}&description={}&date={}",
-        pct_encode(title.as_str()),
-        pct_encode(details.as_str()),
-        pct_encode(start.format("%Y-%m-%d %H:%M").to_string().as_str())
-    );
+    (
+        StatusCode::OK,
+        Json(serde_json::json!({
+            "ok": true,
+            "preferences": merged,
+            "coerced_fields": coerced_fields
+        })),
+    )
+        .into_response()
+}
 
-    warnings.push("web_auto_write_requires_user_confirmation".to_string());
+async fn studio_preferences_defaults() -> impl IntoResponse {
+    (
+        StatusCode::OK,
+        Json(serde_json::json!({ "fields": STUDIO_PREFERENCE_OPTIONS })),
+    )
+        .into_response()
+}
 
-    let (primary_url, user_message) = match app.as_str() {
-        "google_calendar" => (
-            Some(google_calendar_url.clone()),
-            if is_he {
-                "ווב לא כותב ישירות ליומן. נפתחה טיוטת אירוע ב-Google Calendar; אשרו שמירה. קובץ ICS זמין כגיבוי."
-                    .to_string()
-            } else {
-                "Web cannot write directly to calendar providers. A prefilled Google Calendar draft was opened; confirm save. ICS fallback is included."
-                    .to_string()
-            },
-        ),
-        "shortcuts" => (
-            shortcuts_url.clone(),
-            if is_he {
-                if shortcuts_url.is_some() {
-                    "ווב לא כותב ישירות לתזכורות. נשלח קישור ל-Shortcuts; אם לא זמין, השתמשו בקובץ ICS."
-                        .to_string()
-                } else {
-                    "לא ניתן לייצר קישור Shortcuts בטוח כרגע. השתמשו בקובץ ICS כגיבוי.".to_string()
-                }
-            } else if shortcuts_url.is_some() {
-                "Web cannot write directly to reminders. Shortcuts deep link is ready; if unavailable, use the ICS fallback."
-                    .to_string()
-            } else {
-                "A safe Shortcuts deep link could not be generated. Use the ICS fallback file."
-                    .to_string()
-            },
-        ),
-        "todoist" => (
-            Some(todoist_url),
-            if is_he {
-                "ווב לא יכול ליצור משימות Todoist ישירות ללא אישור ידני. נפתחה טיוטה + גיבוי ICS."
-                    .to_string()
-            } else {
-                "Web cannot directly write into Todoist without user confirmation. Opened a task draft plus ICS fallback."
-                    .to_string()
-            },
-        ),
-        "notion" => (
-            Some("https://www.notion.so".to_string()),
-            if is_he {
-                "ווב לא יכול לכתוב ישירות ל-Notion. נפתחה סביבת Notion וקובץ ICS זמין לגיבוי."
-                    .to_string()
-            } else {
-                "Web cannot directly write into Notion. Opened Notion and provided ICS fallback."
-                    .to_string()
-            },
-        ),
-        _ => (
-            shortcuts_url
-                .clone()
-                .or_else(|| Some(google_calendar_url.clone())),
-            if is_he {
-                "ווב לא מאפשר כתיבה ישירה ל-Apple Reminders. ננסה לפתוח קיצור דרך; לחלופין השתמשו בקובץ ICS."
-                    .to_string()
-            } else {
-                "Web cannot directly write to Apple Reminders. We attempt a Shortcuts handoff; otherwise use the ICS fallback."
-                    .to_string()
-            },
-        ),
-    };
-    let fallback_used = true;
-
-    let telemetry = build_action_telemetry(
-        "reminder",
-        true,
-        Some(app.as_str()),
-        false,
-        fallback_used,
-        primary_url.clone(),
-        warnings,
-    );
-
-    (
-        StatusCode::OK,
-        Json(ReminderActionResponse {
-            app,
-            google_calendar_url,
-            ics_filename: "atlas-masa-reminder.ics".to_string(),
-            ics_content,
-            shortcuts_url: shortcuts_url.clone().unwrap_or_default(),
-            primary_url,
-            supports_direct_write: false,
-            fallback_used,
-            user_message,
-            telemetry,
-        }),
-    )
-        .into_response()
-}
-
-async fn action_alarm(
+async fn survey_next(
     State(state): State<ApiState>,
     headers: HeaderMap,
-    Json(input): Json<AlarmActionRequest>,
+    Query(query): Query<UserLookupQuery>,
 ) -> impl IntoResponse {
-    if input.label.trim().is_empty() {
-        return action_error_response(
-            StatusCode::BAD_REQUEST,
-            "alarm",
-            "invalid_label",
-            "label is required",
-            None,
-        );
-    }
-
-    if !is_valid_hhmm(&input.time_local) {
-        return action_error_response(
-            StatusCode::BAD_REQUEST,
-            "alarm",
-            "invalid_time",
-            "time_local must be HH:MM",
-            None,
-        );
-    }
+    let user_id = resolve_user_id_or_guest(&state, &headers, query.user_id.clone());
+    let user_locale = resolve_request_locale(&state, &headers, &user_id, query.locale.as_deref());
 
-    let user_id = resolve_user_id_or_guest(&state, &headers, None);
-    let locale = state
-        .users
-        .read()
-        .get(&user_id)
-        .map(|user| {
-            sanitize_enum_value(user.locale.as_str(), &["he", "en", "ar", "ru", "fr"], "en")
-        })
-        .unwrap_or_else(|| "en".to_string());
-    let is_he = locale == "he";
-    let prefs = state
-        .studio_preferences
+    let survey_state = state
+        .survey_states
         .read()
         .get(&user_id)
         .cloned()
-        .unwrap_or_else(|| default_studio_preferences(&user_id));
-    let app = sanitize_enum_value(
-        input
-            .alarms_app
-            .unwrap_or_else(|| prefs.alarms_app.clone())
-            .as_str(),
-        &["apple_clock", "google_clock", "shortcuts"],
-        "apple_clock",
-    );
-
-    let mut warnings = Vec::new();
-    let label = sanitize_limited_text(input.label.trim(), MAX_ALARM_LABEL_LEN);
-    if label.is_empty() {
-        return action_error_response(
-            StatusCode::BAD_REQUEST,
-            "alarm",
-            "invalid_label",
-            "label is required",
-            Some(app.as_str()),
-        );
-    }
-    let days = sanitize_alarm_days(input.days);
-    let payload = format!(
-        "Label: {}\nTime: {}\nDays: {}",
-        label,
-        input.time_local.trim(),
-        days.join(",")
-    );
-    let compact_payload = format!(
-        "Set alarm {} at {} ({})",
-        label,
-        input.time_local.trim(),
-        days.join(",")
-    );
-    let (shortcuts_url, shortcuts_compact_used) =
-        build_shortcuts_url_with_fallback("AtlasMasaAlarm", &payload, &compact_payload);
-    if shortcuts_compact_used {
-        warnings.push("shortcuts_compact_payload_used".to_string());
-    }
-    if shortcuts_url.is_none() {
-        warnings.push("shortcuts_url_unavailable".to_string());
-    }
-    warnings.push("web_auto_write_requires_user_confirmation".to_string());
-
-    let clock_url = if app == "google_clock" {
-        "intent://alarms#Intent;package=com.google.android.deskclock;end".to_string()
-    } else {
-        "clock://".to_string()
-    };
-    let primary_url = match app.as_str() {
-        "shortcuts" => shortcuts_url.clone().or_else(|| Some(clock_url.clone())),
-        "google_clock" | "apple_clock" => Some(clock_url.clone()),
-        _ => Some(clock_url.clone()),
-    };
-
-    let days_label = days.join(", ");
-    let user_message = match app.as_str() {
-        "shortcuts" => {
-            if is_he {
-                "ווב לא יוצר אזעקות אוטומטית. נשלח קישור Shortcuts; אם הוא לא נפתח, צרו אזעקה ידנית באפליקציית השעון."
-                    .to_string()
-            } else {
-                "Web cannot create alarms directly. A Shortcuts deep link was prepared; if unavailable, create it manually in Clock."
-                    .to_string()
-            }
-        }
-        "google_clock" => {
-            if is_he {
-                "ווב לא מגדיר אזעקה ישירה. ננסה לפתוח Google Clock דרך intent; אם נחסם בדפדפן, הגדירו ידנית."
-                    .to_string()
-            } else {
-                "Web cannot set Google Clock alarms directly. We attempt an intent launch; if blocked by browser, set it manually."
-                    .to_string()
-            }
-        }
-        _ => {
-            if is_he {
-                "ווב לא יכול ליצור אזעקות ישירות. נפתח קישור לאפליקציית השעון עם הוראות השלמה ידנית."
-                    .to_string()
-            } else {
-                "Web cannot create alarms directly. Clock launch is attempted with manual fallback guidance."
-                    .to_string()
-            }
-        }
-    };
-    let telemetry = build_action_telemetry(
-        "alarm",
-        true,
-        Some(app.as_str()),
-        false,
-        true,
-        primary_url.clone(),
-        warnings,
-    );
+        .unwrap_or_else(|| SurveyStateRecord {
+            user_id: user_id.clone(),
+            answers: HashMap::new(),
+            completed: false,
+            started_at: None,
+            completed_at: None,
+            updated_at: chrono::Utc::now().to_rfc3339(),
+        });
 
-    let fallback_instructions = if is_he {
-        format!(
-            "אם האוטומציה לא הופעלה, פתחו ידנית את אפליקציית השעון והגדירו אזעקה: '{}' בשעה {} בימים {}.",
-            label,
-            input.time_local.trim(),
-            days_label
-        )
-    } else {
-        format!(
-            "If automation does not trigger, open your Clock app manually and create alarm '{}' at {} on {}.",
-            label,
-            input.time_local.trim(),
-            days_label
-        )
+    let question = next_survey_question(&user_locale, &survey_state.answers);
+    let total = survey_total_questions(&survey_state.answers);
+    let answered = survey_state.answers.len().min(total);
+    let progress = SurveyProgress {
+        answered,
+        total,
+        percent: if total == 0 {
+            0
+        } else {
+            ((answered as f32 / total as f32) * 100.0).round() as u8
+        },
     };
 
     (
         StatusCode::OK,
-        Json(AlarmActionResponse {
-            app,
-            clock_url,
-            shortcuts_url: shortcuts_url.unwrap_or_default(),
-            primary_url,
-            supports_direct_write: false,
-            fallback_used: true,
-            user_message,
-            fallback_instructions,
-            telemetry,
+        localized_response_headers(user_locale.clone()),
+        Json(SurveyNextResponse {
+            question,
+            progress,
+            profile_hints: build_survey_hints(&survey_state),
         }),
     )
         .into_response()
 }
 
-async fn plan_trip(
+async fn survey_answer(
     State(state): State<ApiState>,
-    Json(input): Json<TripPlanRequest>,
+    headers: HeaderMap,
+    Json(input): Json<SurveyAnswerRequest>,
 ) -> impl IntoResponse {
-    match state.agent.plan_trip(input).await {
-        Ok(response) => (StatusCode::OK, Json(response)).into_response(),
-        Err(error) => (
-            StatusCode::BAD_REQUEST,
-            Json(serde_json::json!({
-                "error": "plan_trip_failed",
-                "message": error.to_string()
-            })),
-        )
-            .into_response(),
+    if input.question_id.trim().is_empty() || input.answer.trim().is_empty() {
+        return ApiError::bad_request("invalid_answer", "question_id and answer are required")
+            .into_response();
     }
-}
 
-async fn api_key_middleware(
-    State(state): State<ApiState>,
-    request: Request<Body>,
-    next: Next,
-) -> Response {
-    let path = request.uri().path().to_string();
-    if request.method() == Method::OPTIONS || is_public_endpoint(path.as_str()) {
-        return next.run(request).await;
-    }
-
-    let header_key = request
-        .headers()
-        .get("x-api-key")
-        .and_then(|value| value.to_str().ok())
-        .unwrap_or_default();
-    let has_service_api_key = header_key == state.api_key;
-
-    if has_service_api_key {
-        return next.run(request).await;
-    }
-
-    // Browser requests can skip x-api-key only when:
-    // 1) origin is first-party allowlisted, and
-    // 2) a valid session cookie already resolves to a user.
-    // This blocks spoofed anonymous Origin headers from bypassing service-key checks.
-    if !request_origin_is_allowed(&state, request.headers()) {
-        return (
-            StatusCode::UNAUTHORIZED,
-            Json(serde_json::json!({
-                "error": "unauthorized",
-                "message": "missing or invalid x-api-key"
-            })),
-        )
-            .into_response();
-    }
-
-    let Some(session_user) = session_user_from_headers(&state, request.headers()) else {
-        return (
-            StatusCode::UNAUTHORIZED,
-            Json(serde_json::json!({
-                "error": "not_authenticated",
-                "message": "session is required when x-api-key is absent"
-            })),
-        )
-            .into_response();
-    };
+    let user_id = resolve_user_id_or_guest(&state, &headers, input.user_id.clone());
+    let user_locale = resolve_request_locale(&state, &headers, &user_id, input.locale.as_deref());
 
-    let (needs_cloud_storage, needs_cloud_compute) = cloud_requirements_for_endpoint(path.as_str());
-    if needs_cloud_storage || needs_cloud_compute {
-        let subscription = subscription_access_for_user(&state, &session_user).await;
-        let storage_ok = !needs_cloud_storage || subscription.cloud_storage_enabled;
-        let compute_ok = !needs_cloud_compute || subscription.cloud_compute_enabled;
-        if !storage_ok || !compute_ok {
-            let reason = if needs_cloud_storage && needs_cloud_compute {
-                "cloud_storage_and_compute_require_subscription"
-            } else if needs_cloud_storage {
-                "cloud_storage_requires_subscription"
-            } else {
-                "cloud_compute_requires_subscription"
-            };
-            return (
-                StatusCode::PAYMENT_REQUIRED,
-                Json(serde_json::json!({
-                    "error": reason,
-                    "message": "This cloud feature is available on the paid subscription plan.",
-                    "subscription": subscription
-                })),
-            )
-                .into_response();
+    let persisted_user = {
+        let mut states = state.survey_states.write();
+        let now = chrono::Utc::now();
+        let entry = states
+            .entry(user_id.clone())
+            .or_insert_with(|| SurveyStateRecord {
+                user_id: user_id.clone(),
+                answers: HashMap::new(),
+                completed: false,
+                started_at: None,
+                completed_at: None,
+                updated_at: now.to_rfc3339(),
+            });
+        if entry.started_at.is_none() {
+            entry.started_at = Some(now.to_rfc3339());
         }
+        entry.answers.insert(
+            input.question_id.trim().to_string(),
+            input.answer.trim().to_string(),
+        );
+        entry.completed = next_survey_question(&user_locale, &entry.answers).is_none();
+        entry.completed_at = if entry.completed {
+            entry
+                .completed_at
+                .clone()
+                .or_else(|| Some(now.to_rfc3339()))
+        } else {
+            None
+        };
+        entry.updated_at = now.to_rfc3339();
+        entry.user_id.clone()
+    };
+    if !is_guest_user_id(persisted_user.as_str()) {
+        let _ = persist_survey_state_if_configured(&state, persisted_user.as_str()).await;
     }
 
-    next.run(request).await
-}
-
-fn session_user_from_headers(state: &ApiState, headers: &HeaderMap) -> Option<UserRecord> {
-    let session_id = read_cookie_value(headers, &state.cookie_name)?;
-
-    let session = {
-        let mut sessions = state.sessions.write();
-        let now = chrono::Utc::now();
-
-        match sessions.get(&session_id).cloned() {
-            Some(session) if session.expires_at > now => Some(session),
-            Some(_) => {
-                sessions.remove(&session_id);
+    if input.question_id.trim() == "trip_style" {
+        let normalized = sanitize_enum_value(
+            input.answer.trim(),
+            &["mixed", "beach", "north", "desert"],
+            "mixed",
+        );
+        let updated_user = {
+            let mut users = state.users.write();
+            if let Some(user) = users.get_mut(&user_id) {
+                user.trip_style = Some(normalized);
+                user.updated_at = chrono::Utc::now().to_rfc3339();
+                Some(user.clone())
+            } else {
                 None
             }
-            None => None,
+        };
+        if let Some(user) = updated_user {
+            let _ = persist_user_if_configured(&state, &user).await;
         }
-    }?;
+    }
 
-    state.users.read().get(&session.user_id).cloned()
-}
+    let survey_question_id =
+        sanitize_limited_text(input.question_id.as_str(), MAX_PROFILE_FIELD_LEN);
+    let survey_answer_value = sanitize_limited_text(input.answer.as_str(), MAX_MEMORY_TEXT_LEN);
+    if !survey_question_id.is_empty() && !survey_answer_value.is_empty() {
+        let (memory_type, stability, weight) =
+            classify_survey_memory(survey_question_id.as_str(), survey_answer_value.as_str());
+        let _ = ingest_memory_event_for_user(
+            &state,
+            user_id.as_str(),
+            MemoryIngestEvent {
+                memory_type,
+                stability,
+                source: "survey".to_string(),
+                text: format!(
+                    "Survey signal: {} => {}",
+                    survey_question_id, survey_answer_value
+                ),
+                weight,
+                tags: sanitize_note_tags(vec![format!("survey_{}", survey_question_id)]),
+                happened_at: Some(chrono::Utc::now()),
+                expires_at: None,
+            },
+        )
+        .await;
+    }
 
-fn read_cookie_value(headers: &HeaderMap, cookie_name: &str) -> Option<String> {
-    let raw_cookie = headers.get(header::COOKIE)?.to_str().ok()?;
-    raw_cookie.split(';').find_map(|part| {
-        let mut split = part.trim().splitn(2, '=');
-        let key = split.next()?.trim();
-        let value = split.next()?.trim();
-        if key == cookie_name {
-            Some(value.to_string())
+    let state_snapshot =
+        state
+            .survey_states
+            .read()
+            .get(&user_id)
+            .cloned()
+            .unwrap_or(SurveyStateRecord {
+                user_id: user_id.clone(),
+                answers: HashMap::new(),
+                completed: false,
+                started_at: None,
+                completed_at: None,
+                updated_at: chrono::Utc::now().to_rfc3339(),
+            });
+
+    let total = survey_total_questions(&state_snapshot.answers);
+    let answered = state_snapshot.answers.len().min(total);
+    let progress = SurveyProgress {
+        answered,
+        total,
+        percent: if total == 0 {
+            0
         } else {
-            None
-        }
-    })
-}
+            ((answered as f32 / total as f32) * 100.0).round() as u8
+        },
+    };
 
-fn request_origin_is_allowed(state: &ApiState, headers: &HeaderMap) -> bool {
-    if let Some(origin) = request_origin_from_headers(headers) {
-        return state
-            .allowed_origins
-            .iter()
-            .any(|allowed| allowed == &origin);
-    }
-    false
+    (
+        StatusCode::OK,
+        localized_response_headers(user_locale.clone()),
+        Json(SurveyNextResponse {
+            question: next_survey_question(&user_locale, &state_snapshot.answers),
+            progress,
+            profile_hints: build_survey_hints(&state_snapshot),
+        }),
+    )
+        .into_response()
 }
 
-fn request_origin_from_headers(headers: &HeaderMap) -> Option<String> {
-    headers
-        .get(header::ORIGIN)
-        .and_then(|value| value.to_str().ok())
-        .map(|value| value.trim().trim_end_matches('/').to_string())
-        .filter(|value| !value.is_empty())
+async fn feed_proactive(
+    State(state): State<ApiState>,
+    headers: HeaderMap,
+    Query(query): Query<UserLookupQuery>,
+) -> impl IntoResponse {
+    let user_id = resolve_user_id_or_guest(&state, &headers, query.user_id.clone());
+    let request_locale =
+        resolve_request_locale(&state, &headers, &user_id, query.locale.as_deref());
+    let response = build_proactive_feed_response(&state, user_id.as_str(), request_locale.as_str());
+    (
+        StatusCode::OK,
+        localized_response_headers(request_locale),
+        Json(response),
+    )
+        .into_response()
 }
 
-fn cookie_same_site_attr(value: &str) -> &'static str {
-    match value.trim().to_ascii_lowercase().as_str() {
-        "none" => "None",
-        "lax" => "Lax",
-        _ => "Strict",
-    }
+// Energy is a discrete 1-5 rating picked by the user; out-of-range values point at a client
+// bug, so they are rejected instead of clamped.
+fn energy_level_is_valid(value: Option<u8>) -> bool {
+    value.is_none_or(|level| (1..=5).contains(&level))
 }
 
-fn build_session_cookie(
-    cookie_name: &str,
-    session_id: &str,
-    max_age_seconds: u64,
-    secure: bool,
-    same_site: &str,
-    domain: &str,
-) -> String {
-    let mut segments = vec![
-        format!("{cookie_name}={session_id}"),
-        "Path=/".to_string(),
-        "HttpOnly".to_string(),
-        format!("SameSite={}", cookie_same_site_attr(same_site)),
-        format!("Max-Age={max_age_seconds}"),
-    ];
-    if secure {
-        segments.push("Secure".to_string());
+async fn execution_checkin_submit(
+    State(state): State<ApiState>,
+    headers: HeaderMap,
+    Json(input): Json<ExecutionCheckinRequest>,
+) -> impl IntoResponse {
+    let user_id = match resolve_user_id(&state, &headers, input.user_id.clone()) {
+        Some(value) => value,
+        None => {
+            return ApiError::not_authenticated().into_response();
+        }
+    };
+
+    let daily_focus = sanitize_limited_text(input.daily_focus.as_str(), MAX_MEMORY_TEXT_LEN);
+    if daily_focus.is_empty() {
+        return ApiError::bad_request("invalid_daily_focus", "daily_focus is required")
+            .into_response();
     }
-    if !domain.trim().is_empty() {
-        segments.push(format!("Domain={domain}"));
+    if !energy_level_is_valid(input.energy_level) {
+        return ApiError::bad_request(
+            "invalid_energy_level",
+            "energy_level must be between 1 and 5",
+        )
+        .into_response();
     }
-    segments.join("; ")
-}
-
-fn build_clear_cookie(cookie_name: &str, secure: bool, same_site: &str, domain: &str) -> String {
-    let mut segments = vec![
-        format!("{cookie_name}="),
-        "Path=/".to_string(),
-        "HttpOnly".to_string(),
-        format!("SameSite={}", cookie_same_site_attr(same_site)),
-        "Max-Age=0".to_string(),
-        "Expires=Thu, 01 Jan 1970 00:00:00 GMT".to_string(),
-    ];
-    if secure {
-        segments.push("Secure".to_string());
+    let now = chrono::Utc::now();
+    let checkin = ExecutionCheckinRecord {
+        checkin_id: uuid::Uuid::new_v4().to_string(),
+        user_id: user_id.clone(),
+        daily_focus: daily_focus.clone(),
+        mid_term_focus: input
+            .mid_term_focus
+            .map(|value| sanitize_limited_text(value.as_str(), MAX_MEMORY_TEXT_LEN))
+            .filter(|value| !value.is_empty()),
+        long_term_focus: input
+            .long_term_focus
+            .map(|value| sanitize_limited_text(value.as_str(), MAX_MEMORY_TEXT_LEN))
+            .filter(|value| !value.is_empty()),
+        blocker: input
+            .blocker
+            .map(|value| sanitize_limited_text(value.as_str(), MAX_MEMORY_TEXT_LEN))
+            .filter(|value| !value.is_empty()),
+        next_action_now: input
+            .next_action_now
+            .map(|value| sanitize_limited_text(value.as_str(), MAX_MEMORY_TEXT_LEN))
+            .filter(|value| !value.is_empty()),
+        energy_level: input.energy_level,
+        mood: input
+            .mood
+            .map(|value| sanitize_limited_text(value.as_str(), MAX_PROFILE_FIELD_LEN))
+            .filter(|value| !value.is_empty()),
+        gym_today: input.gym_today,
+        money_today: input.money_today,
+        created_at: now.to_rfc3339(),
+    };
+
+    {
+        let mut checkins = state.execution_checkins.write();
+        let history = checkins.entry(user_id.clone()).or_default();
+        history.push(checkin.clone());
+        history.sort_by(|lhs, rhs| rhs.created_at.cmp(&lhs.created_at));
+        history.truncate(180);
     }
-    if !domain.trim().is_empty() {
-        segments.push(format!("Domain={domain}"));
+    let _ = persist_checkins_if_configured(&state, user_id.as_str()).await;
+
+    let mut memory_tags = vec!["checkin".to_string(), "daily_execution".to_string()];
+    if checkin.energy_level.unwrap_or(3) <= 2 {
+        memory_tags.push("low_energy".to_string());
     }
-    segments.join("; ")
+    match checkin.gym_today {
+        Some(true) => memory_tags.push("gym_done".to_string()),
+        Some(false) => memory_tags.push("gym_missed".to_string()),
+        None => {}
+    }
+    match checkin.money_today {
+        Some(true) => memory_tags.push("money_progress".to_string()),
+        Some(false) => memory_tags.push("money_gap".to_string()),
+        None => {}
+    }
+    let _ = ingest_memory_event_for_user(
+        &state,
+        user_id.as_str(),
+        MemoryIngestEvent {
+            memory_type: "task".to_string(),
+            stability: "transient".to_string(),
+            source: "system".to_string(),
+            text: format!(
+                "Check-in focus: {} | blocker: {} | next action: {} | gym_today: {} | money_today: {}",
+                checkin.daily_focus,
+                checkin
+                    .blocker
+                    .clone()
+                    .unwrap_or_else(|| "none".to_string()),
+                checkin
+                    .next_action_now
+                    .clone()
+                    .unwrap_or_else(|| "not_set".to_string()),
+                checkin
+                    .gym_today
+                    .map(|value| if value { "yes" } else { "no" })
+                    .unwrap_or("unknown"),
+                checkin
+                    .money_today
+                    .map(|value| if value { "yes" } else { "no" })
+                    .unwrap_or("unknown")
+            ),
+            weight: 0.84,
+            tags: memory_tags,
+            happened_at: Some(now),
+            expires_at: Some(now + chrono::Duration::days(3)),
+        },
+    )
+    .await;
+
+    let locale = resolve_request_locale(&state, &headers, &user_id, None);
+    let refreshed = build_proactive_feed_response(&state, user_id.as_str(), locale.as_str());
+    (
+        StatusCode::OK,
+        localized_response_headers(locale),
+        Json(serde_json::json!({
+            "ok": true,
+            "checkin": checkin,
+            "feed": refreshed
+        })),
+    )
+        .into_response()
 }
 
-fn default_company_status() -> CompanyStatusRecord {
-    CompanyStatusRecord {
-        phase: "Build now, launch in controlled stages".to_string(),
-        current_focus: vec![
-            "Mobile-first AI concierge and studio".to_string(),
-            "Deep personalization and proactive support".to_string(),
-            "Atlas/אטלס travel/work ecosystem MVP".to_string(),
-        ],
-        upcoming: vec![
-            "Expanded user account intelligence".to_string(),
-            "Vehicle integration APIs".to_string(),
-            "Pilot-ready operations and legal routing".to_string(),
-        ],
-        open_for_investment: true,
-        message: "Atlas/אטלס is open to strategic partnerships and investments while building a long-term mobility ecosystem.".to_string(),
-    }
+#[derive(Debug, Clone, Deserialize, Default)]
+struct ExecutionRefreshRequest {
+    user_id: Option<String>,
+    locale: Option<String>,
 }
 
-fn resolve_user_id(
-    state: &ApiState,
-    headers: &HeaderMap,
-    explicit_user_id: Option<String>,
-) -> Option<String> {
-    let session_user = session_user_from_headers(state, headers)?;
-    if let Some(from_body) = explicit_user_id.as_ref() {
-        if from_body != &session_user.user_id {
-            return None;
-        }
-    }
-    Some(session_user.user_id)
+async fn execution_refresh(
+    State(state): State<ApiState>,
+    headers: HeaderMap,
+    Json(input): Json<ExecutionRefreshRequest>,
+) -> impl IntoResponse {
+    let user_id = resolve_user_id_or_guest(&state, &headers, input.user_id.clone());
+    let request_locale =
+        resolve_request_locale(&state, &headers, &user_id, input.locale.as_deref());
+    let response = build_proactive_feed_response(&state, user_id.as_str(), request_locale.as_str());
+    (
+        StatusCode::OK,
+        localized_response_headers(request_locale),
+        Json(response),
+    )
+        .into_response()
 }
 
-fn resolve_user_id_or_guest(
-    state: &ApiState,
-    headers: &HeaderMap,
-    explicit_user_id: Option<String>,
-) -> String {
-    resolve_user_id(state, headers, explicit_user_id).unwrap_or_else(|| "guest".to_string())
+async fn execution_digest(
+    State(state): State<ApiState>,
+    headers: HeaderMap,
+    Query(query): Query<ExecutionDigestQuery>,
+) -> impl IntoResponse {
+    let user_id = resolve_user_id_or_guest(&state, &headers, query.user_id.clone());
+    let request_locale =
+        resolve_request_locale(&state, &headers, &user_id, query.locale.as_deref());
+    let now = chrono::Utc::now();
+    let period_start = now - chrono::Duration::days(DIGEST_PERIOD_DAYS);
+    let checkins = state
+        .execution_checkins
+        .read()
+        .get(&user_id)
+        .cloned()
+        .unwrap_or_default();
+    let summary = summarize_execution_week(&checkins, request_locale.as_str(), period_start);
+    // Prioritization stays with the proactive engine; the digest only surfaces its top items.
+    let feed = build_proactive_feed_response(&state, user_id.as_str(), request_locale.as_str());
+    let mut top_tasks = feed.items;
+    top_tasks.truncate(DIGEST_TOP_TASKS);
+    // The narrative is a paid model call, so it is only produced on request.
+    let narrative = if query.narrative {
+        digest_narrative(
+            &state,
+            user_id.as_str(),
+            request_locale.as_str(),
+            &summary,
+            &top_tasks,
+            now,
+        )
+        .await
+    } else {
+        None
+    };
+
+    let response = ExecutionDigestResponse {
+        generated_at: now.to_rfc3339(),
+        period_start: period_start.to_rfc3339(),
+        period_end: now.to_rfc3339(),
+        summary,
+        feed_ready: feed.feed_ready,
+        top_tasks,
+        narrative,
+    };
+    (
+        StatusCode::OK,
+        localized_response_headers(request_locale),
+        Json(response),
+    )
+        .into_response()
 }
 
-fn resolve_request_locale(state: &ApiState, user_id: &str, requested: Option<&str>) -> String {
-    let requested = requested.unwrap_or_default().trim().to_lowercase();
-    if matches!(requested.as_str(), "he" | "en" | "ar" | "ru" | "fr") {
-        return requested;
+// A focus counts as completed once a later check-in in the window moves on from it; whatever
+// the most recent check-in still names is pending.
+fn summarize_execution_week(
+    checkins: &[ExecutionCheckinRecord],
+    locale: &str,
+    period_start: chrono::DateTime<chrono::Utc>,
+) -> ExecutionWeekSummary {
+    let mut in_window = checkins
+        .iter()
+        .filter_map(|checkin| {
+            let at = chrono::DateTime::parse_from_rfc3339(checkin.created_at.as_str()).ok()?;
+            (at >= period_start).then_some((at, checkin))
+        })
+        .collect::<Vec<_>>();
+    in_window.sort_by_key(|(at, _)| *at);
+
+    let focus_tasks = |checkin: &ExecutionCheckinRecord| {
+        extract_checkin_tasks(Some(checkin), locale)
+            .into_iter()
+            .filter(|task| {
+                DIGEST_FOCUS_TASK_PREFIXES
+                    .iter()
+                    .any(|prefix| task.task_id.starts_with(prefix))
+            })
+            .map(|task| ExecutionDigestFocus {
+                title: task.title,
+                detail: task.detail,
+                horizon: task.horizon,
+                checked_in_at: checkin.created_at.clone(),
+            })
+            .collect::<Vec<_>>()
+    };
+    let focus_key =
+        |focus: &ExecutionDigestFocus| (focus.horizon.clone(), focus.detail.trim().to_lowercase());
+
+    let pending_focuses = in_window
+        .last()
+        .map(|(_, checkin)| focus_tasks(checkin))
+        .unwrap_or_default();
+    let mut seen = pending_focuses
+        .iter()
+        .map(focus_key)
+        .collect::<HashSet<_>>();
+    let mut completed_focuses = Vec::new();
+    for (_, checkin) in in_window.iter().rev().skip(1) {
+        for focus in focus_tasks(checkin) {
+            if seen.insert(focus_key(&focus)) {
+                completed_focuses.push(focus);
+            }
+        }
     }
-    state
-        .users
-        .read()
-        .get(user_id)
-        .map(|user| {
-            sanitize_enum_value(user.locale.as_str(), &["he", "en", "ar", "ru", "fr"], "en")
+
+    let readings = in_window
+        .iter()
+        .filter_map(|(_, checkin)| {
+            checkin.energy_level.map(|level| EnergyReading {
+                checked_in_at: checkin.created_at.clone(),
+                level,
+            })
         })
-        .unwrap_or_else(|| "en".to_string())
+        .collect::<Vec<_>>();
+
+    ExecutionWeekSummary {
+        checkin_count: in_window.len(),
+        completed_focuses,
+        pending_focuses,
+        energy: energy_trend(readings),
+    }
 }
 
-fn survey_elapsed_minutes(state: &SurveyStateRecord) -> Option<u32> {
-    let start = state
-        .started_at
-        .as_deref()
-        .and_then(|value| chrono::DateTime::parse_from_rfc3339(value).ok())?;
-    let end = state
-        .completed_at
-        .as_deref()
-        .and_then(|value| chrono::DateTime::parse_from_rfc3339(value).ok())
-        .unwrap_or_else(|| chrono::Utc::now().into());
-    let duration = end.signed_duration_since(start);
-    if duration.num_minutes() < 0 {
-        Some(0)
+fn energy_trend(readings: Vec<EnergyReading>) -> EnergyTrend {
+    let mean = |values: &[EnergyReading]| {
+        values
+            .iter()
+            .map(|reading| reading.level as f32)
+            .sum::<f32>()
+            / values.len() as f32
+    };
+    let average = (!readings.is_empty()).then(|| mean(&readings));
+    let trend = if readings.len() < 2 {
+        "insufficient_data"
     } else {
-        Some(duration.num_minutes() as u32)
+        let (earlier, later) = readings.split_at(readings.len() / 2);
+        let delta = mean(later) - mean(earlier);
+        if delta >= 0.5 {
+            "rising"
+        } else if delta <= -0.5 {
+            "falling"
+        } else {
+            "steady"
+        }
+    };
+    EnergyTrend {
+        readings,
+        average,
+        trend,
     }
 }
 
-fn default_studio_preferences(user_id: &str) -> StudioPreferencesRecord {
-    StudioPreferencesRecord {
-        user_id: user_id.to_string(),
-        preferred_format: "structured_plan".to_string(),
-        response_depth: "deep".to_string(),
-        response_tone: "executive".to_string(),
-        proactive_mode: "enabled".to_string(),
-        reminders_app: "google_calendar".to_string(),
-        alarms_app: "apple_clock".to_string(),
-        voice_mode: "enabled".to_string(),
-        updated_at: chrono::Utc::now().to_rfc3339(),
+const DIGEST_NARRATIVE_INSTRUCTION: &str = "Write a short week-in-review narrative from the structured digest: what moved, what is still open, how energy trended, and the single most important next move.";
+
+// One narrative per user per UTC day and locale: the digest covers a week, so regenerating it on
+// every refresh would only spend budget.
+async fn digest_narrative(
+    state: &ApiState,
+    user_id: &str,
+    locale: &str,
+    summary: &ExecutionWeekSummary,
+    top_tasks: &[ProactiveFeedItem],
+    now: chrono::DateTime<chrono::Utc>,
+) -> Option<String> {
+    let today = now.date_naive();
+    let cached = state
+        .digest_narratives
+        .read()
+        .get(user_id)
+        .filter(|record| record.day == today && record.locale == locale)
+        .map(|record| record.text.clone());
+    if cached.is_some() {
+        return cached;
     }
+
+    let text = generate_digest_narrative(state, user_id, locale, summary, top_tasks).await?;
+    let mut narratives = state.digest_narratives.write();
+    narratives.retain(|_, record| record.day == today);
+    narratives.insert(
+        user_id.to_string(),
+        DigestNarrativeRecord {
+            day: today,
+            locale: locale.to_string(),
+            text: text.clone(),
+        },
+    );
+    Some(text)
 }
 
-fn merge_studio_preferences(
-    mut base: StudioPreferencesRecord,
-    incoming: StudioPreferencesUpsertRequest,
-) -> StudioPreferencesRecord {
-    if let Some(value) = incoming.preferred_format {
-        base.preferred_format = sanitize_enum_value(
-            value.as_str(),
-            &[
-                "structured_plan",
-                "checklist",
-                "step_by_step",
-                "concise",
-                "timeline",
-                "json",
-                "notebook_style",
-            ],
-            "structured_plan",
-        );
-    }
-    if let Some(value) = incoming.response_depth {
-        base.response_depth =
-            sanitize_enum_value(value.as_str(), &["quick", "balanced", "deep"], "deep");
-    }
-    if let Some(value) = incoming.response_tone {
-        base.response_tone = sanitize_enum_value(
-            value.as_str(),
-            &["coach", "direct", "calm", "strategic", "executive"],
-            "executive",
-        );
-    }
-    if let Some(value) = incoming.proactive_mode {
-        base.proactive_mode = sanitize_enum_value(
-            value.as_str(),
-            &["enabled", "focus_only", "disabled"],
-            "enabled",
-        );
-    }
-    if let Some(value) = incoming.reminders_app {
-        base.reminders_app = sanitize_enum_value(
-            value.as_str(),
-            &[
-                "google_calendar",
-                "apple_reminders",
-                "shortcuts",
-                "todoist",
-                "notion",
-            ],
-            "google_calendar",
-        );
-    }
-    if let Some(value) = incoming.alarms_app {
-        base.alarms_app = sanitize_enum_value(
-            value.as_str(),
-            &["apple_clock", "google_clock", "shortcuts"],
-            "apple_clock",
-        );
-    }
-    if let Some(value) = incoming.voice_mode {
-        base.voice_mode = sanitize_enum_value(value.as_str(), &["enabled", "disabled"], "enabled");
-    }
-    base.updated_at = chrono::Utc::now().to_rfc3339();
-    base
-}
-
-fn request_overrides_to_studio(request: &ChatRequest) -> StudioPreferencesUpsertRequest {
-    StudioPreferencesUpsertRequest {
-        user_id: request.user_id.clone(),
-        preferred_format: request.preferred_format.clone(),
-        response_depth: request.response_depth.clone(),
-        response_tone: request.response_tone.clone(),
-        proactive_mode: None,
-        reminders_app: None,
-        alarms_app: None,
-        voice_mode: None,
+async fn generate_digest_narrative(
+    state: &ApiState,
+    user_id: &str,
+    locale: &str,
+    summary: &ExecutionWeekSummary,
+    top_tasks: &[ProactiveFeedItem],
+) -> Option<String> {
+    let runtime = state.ai_runtime.as_ref()?;
+    let user = state.users.read().get(user_id).cloned()?;
+    if !subscription_access_for_user(state, &user)
+        .await
+        .cloud_compute_enabled
+        || ai_budget_exhausted(state, &user)
+    {
+        return None;
     }
-}
 
-fn apply_studio_format(
-    base_reply: String,
-    prefs: &StudioPreferencesRecord,
-    locale: atlas_core::Locale,
-    user: &UserRecord,
-) -> String {
-    let profile_line = if locale == atlas_core::Locale::He {
-        format!(
-            "פרופיל פעיל: {} | סגנון: {} | סיכון: {}",
-            user.name,
-            user.trip_style
-                .clone()
-                .unwrap_or_else(|| "mixed".to_string()),
-            user.risk_preference
-                .clone()
-                .unwrap_or_else(|| "medium".to_string())
-        )
-    } else {
-        format!(
-            "Active profile: {} | style: {} | risk: {}",
-            user.name,
-            user.trip_style
-                .clone()
-                .unwrap_or_else(|| "mixed".to_string()),
-            user.risk_preference
-                .clone()
-                .unwrap_or_else(|| "medium".to_string())
-        )
+    let context = serde_json::json!({
+        "summary": summary,
+        "top_tasks": top_tasks
+    })
+    .to_string();
+    let prompt = ChatBackendPrompt {
+        system_prompt: format!(
+            "{}\n\n{DIGEST_NARRATIVE_INSTRUCTION}",
+            state
+                .premium_system_prompts
+                .for_locale(atlas_core::Locale::from_optional_str(Some(locale)))
+        ),
+        user_messages: vec![format!("Digest JSON: {context}")],
+        reasoning_effort: None,
     };
-
-    format_by_mode(base_reply, prefs, locale, profile_line)
+    let result = runtime.complete(&state.http_client, &prompt).await;
+    let token_estimate = result
+        .as_ref()
+        .map(|reply| {
+            reply
+                .usage
+                .as_ref()
+                .and_then(usage_total_tokens)
+                .unwrap_or_else(|| estimate_ai_tokens(context.as_str(), reply.text.as_str()))
+        })
+        .unwrap_or_default();
+    let _ = record_ai_usage_for_user(state, user_id, token_estimate).await;
+    match result {
+        Ok(reply) => Some(reply.text),
+        Err(err) => {
+            tracing::warn!(error = %err, "execution digest narrative failed");
+            None
+        }
+    }
 }
 
-fn apply_studio_format_guest(
-    base_reply: String,
-    prefs: &StudioPreferencesRecord,
-    locale: atlas_core::Locale,
-) -> String {
-    let profile_line = if locale == atlas_core::Locale::He {
-        "מצב אורח: אפשר להתחבר כדי לשמור זיכרון ארוך-טווח.".to_string()
-    } else {
-        "Guest mode: sign in to unlock long-term personalization.".to_string()
+async fn execution_controls_get(
+    State(state): State<ApiState>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    let user_id = match session_user_from_headers(&state, &headers) {
+        Some(user) => user.user_id,
+        None => {
+            return ApiError::not_authenticated().into_response();
+        }
     };
-    format_by_mode(base_reply, prefs, locale, profile_line)
+    let controls = get_execution_controls(&state, user_id.as_str());
+    (
+        StatusCode::OK,
+        Json(serde_json::json!({
+            "controls": controls
+        })),
+    )
+        .into_response()
 }
 
-fn format_by_mode(
-    base_reply: String,
-    prefs: &StudioPreferencesRecord,
-    locale: atlas_core::Locale,
-    profile_line: String,
-) -> String {
-    let rendered = match prefs.preferred_format.as_str() {
-        "concise" => {
-            if locale == atlas_core::Locale::He {
-                format!(
-                    "{}\n\nתכל'ס עכשיו: בצעו צעד אחד ב-15 הדקות הקרובות.",
-                    base_reply
-                )
-            } else {
-                format!(
-                    "{}\n\nDo this now: execute one action in the next 15 minutes.",
-                    base_reply
-                )
-            }
+async fn execution_controls_upsert(
+    State(state): State<ApiState>,
+    headers: HeaderMap,
+    Json(input): Json<ExecutionControlsUpsertRequest>,
+) -> impl IntoResponse {
+    let user_id = match session_user_from_headers(&state, &headers) {
+        Some(user) => user.user_id,
+        None => {
+            return ApiError::not_authenticated().into_response();
         }
-        "checklist" => {
-            if locale == atlas_core::Locale::He {
-                format!(
-                    "{}\n\nצ'ק-ליסט ביצוע:\n1) הגדירו יעד קצר.\n2) קבעו זמן ביצוע.\n3) הגדירו תזכורת.\n4) שלחו פידבק אחרי ביצוע.\n\n{}",
-                    base_reply, profile_line
-                )
-            } else {
-                format!(
-                    "{}\n\nExecution checklist:\n1) Set one short goal.\n2) Set execution time.\n3) Create a reminder.\n4) Send feedback after completion.\n\n{}",
-                    base_reply, profile_line
-                )
-            }
+    };
+    let updated = {
+        let mut map = state.execution_controls.write();
+        let mut record = map
+            .get(&user_id)
+            .cloned()
+            .unwrap_or_else(|| default_execution_controls(&user_id));
+        if let Some(cadence) = input.cadence {
+            record.cadence =
+                sanitize_enum_value(cadence.as_str(), &["steady", "aggressive"], "steady");
         }
-        "step_by_step" => {
-            if locale == atlas_core::Locale::He {
-                format!(
-                    "{}\n\nשלבים:\nשלב 1: בהירות - מה המטרה היום.\nשלב 2: תנועה - מה הפעולה הראשונה.\nשלב 3: רצף - מה הפעולה הבאה אחרי זה.\n\n{}",
-                    base_reply, profile_line
-                )
-            } else {
-                format!(
-                    "{}\n\nSteps:\nStep 1: Clarity - define today's target.\nStep 2: Motion - execute first action.\nStep 3: Continuity - define next action.\n\n{}",
-                    base_reply, profile_line
-                )
-            }
+        if let Some(detail_level) = input.detail_level {
+            record.detail_level = sanitize_enum_value(
+                detail_level.as_str(),
+                &["concise", "standard", "expanded"],
+                "standard",
+            );
         }
-        "timeline" => {
-            if locale == atlas_core::Locale::He {
-                format!(
-                    "{}\n\nציר זמן מומלץ:\n08:30-10:00 פוקוס עמוק\n10:00-10:15 הפסקת איפוס\n10:15-12:00 ביצוע והתקדמות\n\n{}",
-                    base_reply, profile_line
-                )
-            } else {
-                format!(
-                    "{}\n\nSuggested timeline:\n08:30-10:00 deep focus\n10:00-10:15 reset break\n10:15-12:00 execution and follow-through\n\n{}",
-                    base_reply, profile_line
-                )
-            }
+        if let Some(value) = input.include_company_awareness {
+            record.include_company_awareness = value;
         }
-        "json" => serde_json::json!({
-            "mode": "json",
-            "tone": prefs.response_tone,
-            "depth": prefs.response_depth,
-            "profile": profile_line,
-            "response": base_reply
-        })
-        .to_string(),
-        "notebook_style" => {
-            if locale == atlas_core::Locale::He {
-                format!(
-                    "סטודיו אטלס: תשובה בפורמט מחברת עבודה\n\nתמצית:\n{}\n\nפעולות מומלצות:\n- הפעלת תזכורת\n- קביעת אזעקת פוקוס\n- בדיקת פיד יזום\n\n{}",
-                    base_reply, profile_line
-                )
-            } else {
-                format!(
-                    "Atlas Studio response (notebook style)\n\nSummary:\n{}\n\nSuggested actions:\n- trigger reminder\n- set focus alarm\n- review proactive feed\n\n{}",
-                    base_reply, profile_line
-                )
-            }
+        if let Some(value) = input.include_reminder_suggestions {
+            record.include_reminder_suggestions = value;
         }
-        _ => format!("{}\n\n{}", base_reply, profile_line),
+        record.updated_at = chrono::Utc::now().to_rfc3339();
+        map.insert(user_id.clone(), record.clone());
+        record
     };
+    let _ = persist_execution_controls_if_configured(&state, user_id.as_str()).await;
+    (
+        StatusCode::OK,
+        Json(serde_json::json!({
+            "ok": true,
+            "controls": updated
+        })),
+    )
+        .into_response()
+}
 
-    if prefs.response_tone == "executive" {
-        if locale == atlas_core::Locale::He {
-            format!("סטנדרט הנהלה: מסר מדויק, מכובד ותכליתי.\n\n{}", rendered)
-        } else {
-            format!(
-                "Executive standard: precise, high-caliber, and mission-aligned guidance.\n\n{}",
-                rendered
-            )
-        }
+async fn company_status(State(state): State<ApiState>, headers: HeaderMap) -> impl IntoResponse {
+    let status = state.company_status.read().clone();
+    let etag = company_status_etag(&status);
+    let mut response = if if_none_match_matches(&headers, etag.as_str()) {
+        StatusCode::NOT_MODIFIED.into_response()
     } else {
-        rendered
+        (StatusCode::OK, Json(status)).into_response()
+    };
+    if let Ok(header_value) = HeaderValue::from_str(etag.as_str()) {
+        response.headers_mut().insert(header::ETAG, header_value);
     }
+    response
+        .headers_mut()
+        .insert(header::CACHE_CONTROL, HeaderValue::from_static("no-cache"));
+    response
 }
 
-fn build_proactive_feed_response(
-    state: &ApiState,
-    user_id: &str,
-    request_locale: &str,
-) -> ProactiveFeedResponse {
-    const MIN_SURVEY_MINUTES: u32 = 20;
+async fn admin_company_status_update(
+    State(state): State<ApiState>,
+    headers: HeaderMap,
+    Json(input): Json<CompanyStatusRecord>,
+) -> impl IntoResponse {
+    // Browser sessions pass api_key_middleware without a key; editing company status is
+    // reserved for service keys.
+    if let Err(error) = require_service_key(&state, &headers) {
+        return error.into_response();
+    }
 
-    let user = state
-        .users
-        .read()
-        .get(user_id)
-        .cloned()
-        .unwrap_or_else(|| UserRecord {
-            user_id: user_id.to_string(),
-            provider: "guest".to_string(),
-            email: "guest@atlasmasa.local".to_string(),
-            name: "Guest".to_string(),
-            locale: request_locale.to_string(),
-            trip_style: Some("mixed".to_string()),
-            risk_preference: Some("medium".to_string()),
-            memory_opt_in: true,
-            passkey_user_handle: None,
-            created_at: chrono::Utc::now().to_rfc3339(),
-            updated_at: chrono::Utc::now().to_rfc3339(),
-        });
-    let mut effective_user = user;
-    effective_user.locale = request_locale.to_string();
+    let status = match validate_company_status(input) {
+        Ok(status) => status,
+        Err(message) => {
+            return ApiError::bad_request("invalid_company_status", message).into_response()
+        }
+    };
 
-    let studio_pref = state
-        .studio_preferences
-        .read()
-        .get(user_id)
-        .cloned()
-        .unwrap_or_else(|| default_studio_preferences(user_id));
-    let survey_state = state.survey_states.read().get(user_id).cloned();
-    let notes = state
-        .user_notes
-        .read()
-        .get(user_id)
-        .cloned()
-        .unwrap_or_default();
-    let controls = get_execution_controls(state, user_id);
-    let latest_checkin = latest_execution_checkin(state, user_id);
-    let memories = retrieve_user_memory_context(state, user_id, "", 20);
-    let elapsed_minutes = survey_state
-        .as_ref()
-        .and_then(survey_elapsed_minutes)
-        .unwrap_or(0);
-    let survey_complete = survey_state
-        .as_ref()
-        .map(|value| value.completed)
-        .unwrap_or(false);
-    let feed_ready = survey_complete && elapsed_minutes >= MIN_SURVEY_MINUTES;
+    *state.company_status.write() = status.clone();
+    let _ = persist_company_status_if_configured(&state, &status).await;
 
-    let gate_reason = if feed_ready {
-        None
-    } else if request_locale.starts_with("he") {
-        Some(format!(
-            "זרם הביצוע ייפתח אחרי השלמת סקר העומק ולאחר לפחות {} דקות תהליך.",
-            MIN_SURVEY_MINUTES
-        ))
-    } else {
-        Some(format!(
-            "Execution Stream unlocks after completing the adaptive deep survey and at least {} minutes of survey process.",
-            MIN_SURVEY_MINUTES
-        ))
-    };
-    let items = if feed_ready {
-        build_orchestrated_proactive_feed(&ExecutionFeedContext {
-            company_status: &state.company_status,
-            user: &effective_user,
-            prefs: Some(&studio_pref),
-            survey: survey_state.as_ref(),
-            notes: Some(notes.as_slice()),
-            controls: &controls,
-            memories: memories.as_slice(),
-            latest_checkin: latest_checkin.as_ref(),
-        })
-    } else {
-        Vec::new()
-    };
+    (
+        StatusCode::OK,
+        Json(serde_json::json!({ "ok": true, "company_status": status })),
+    )
+        .into_response()
+}
 
-    ProactiveFeedResponse {
-        generated_at: chrono::Utc::now().to_rfc3339(),
-        items,
-        feed_ready,
-        gate_reason,
-        required_minutes: MIN_SURVEY_MINUTES,
-        company_status: state.company_status.clone(),
+async fn admin_memory_prune_expired(
+    State(state): State<ApiState>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    if let Err(error) = require_service_key(&state, &headers) {
+        return error.into_response();
     }
+
+    let pruned = prune_expired_memories_everywhere(&state).await;
+    (
+        StatusCode::OK,
+        Json(serde_json::json!({
+            "ok": true,
+            "users": pruned.len(),
+            "removed": pruned.iter().map(|(_, removed)| removed).sum::<usize>(),
+            "per_user": pruned
+                .iter()
+                .map(|(user_id, removed)| serde_json::json!({
+                    "user_id": user_id,
+                    "removed": removed
+                }))
+                .collect::<Vec<_>>()
+        })),
+    )
+        .into_response()
 }
 
-fn default_execution_controls(user_id: &str) -> ExecutionControlsRecord {
-    ExecutionControlsRecord {
-        user_id: user_id.to_string(),
-        cadence: "steady".to_string(),
-        detail_level: "standard".to_string(),
-        include_company_awareness: true,
-        include_reminder_suggestions: true,
-        updated_at: chrono::Utc::now().to_rfc3339(),
+// Read-only view of the feed's intermediate state: every candidate the extractors produced,
+// before dedup and selection, with its score and where (if anywhere) it ranked.
+async fn admin_execution_candidates(
+    State(state): State<ApiState>,
+    headers: HeaderMap,
+    Query(query): Query<ExecutionCandidatesQuery>,
+) -> impl IntoResponse {
+    if let Err(error) = require_service_key(&state, &headers) {
+        return error.into_response();
+    }
+    let user_id = query.user_id.trim();
+    if user_id.is_empty() {
+        return ApiError::bad_request("invalid_user_id", "user_id is required").into_response();
     }
-}
 
-fn get_execution_controls(state: &ApiState, user_id: &str) -> ExecutionControlsRecord {
-    state
-        .execution_controls
+    let locale = state
+        .users
         .read()
         .get(user_id)
-        .cloned()
-        .unwrap_or_else(|| default_execution_controls(user_id))
-}
+        .map(|user| user.locale.clone())
+        .unwrap_or_else(|| "en".to_string());
+    let inputs = load_execution_feed_inputs(&state, user_id, locale.as_str());
+    let candidates = collect_execution_task_candidates(&inputs.context(state.feed_max_items));
+    let ranked = prioritize_execution_tasks(candidates.clone());
 
-fn latest_execution_checkin(state: &ApiState, user_id: &str) -> Option<ExecutionCheckinRecord> {
-    state
-        .execution_checkins
-        .read()
-        .get(user_id)
-        .and_then(|entries| entries.first().cloned())
+    let source = query.source.as_deref().map(str::trim);
+    let horizon = query.horizon.as_deref().map(str::trim);
+    let filtered = candidates
+        .into_iter()
+        .filter(|task| source.is_none_or(|value| task.source == value))
+        .filter(|task| horizon.is_none_or(|value| task.horizon == value))
+        .collect::<Vec<_>>();
+    let total = filtered.len();
+    let offset = query.offset.unwrap_or(0);
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_EXECUTION_CANDIDATES_LIMIT)
+        .clamp(1, MAX_EXECUTION_CANDIDATES_LIMIT);
+    let page = filtered
+        .into_iter()
+        .skip(offset)
+        .take(limit)
+        .map(|task| {
+            let rank = ranked
+                .iter()
+                .position(|entry| entry.task_id == task.task_id && entry.title == task.title);
+            serde_json::json!({
+                "priority_score": execution_priority_score(&task),
+                "rank": rank,
+                "candidate": task
+            })
+        })
+        .collect::<Vec<_>>();
+
+    (
+        StatusCode::OK,
+        Json(serde_json::json!({
+            "user_id": user_id,
+            "total": total,
+            "offset": offset,
+            "limit": limit,
+            "candidates": page
+        })),
+    )
+        .into_response()
 }
 
-fn schedule_minutes_offset(cadence: &str, horizon: &str, index: usize) -> i64 {
-    let cadence_base = match cadence {
-        "aggressive" => 8_i64,
-        _ => 18_i64,
+async fn admin_ai_healthcheck(State(state): State<ApiState>, headers: HeaderMap) -> Response {
+    if let Err(error) = require_service_key(&state, &headers) {
+        return error.into_response();
+    }
+    let Some(runtime) = state.openai_runtime.as_ref() else {
+        return ApiError::service_unavailable(
+            "openai_not_configured",
+            "ATLAS_OPENAI_API_KEY is not set",
+        )
+        .into_response();
     };
-    let horizon_boost = match horizon {
-        "daily" => 0_i64,
-        "mid_term" => 50_i64,
-        "long_term" => 180_i64,
-        _ => 25_i64,
+
+    let result = run_ai_healthcheck(
+        &state.http_client,
+        runtime,
+        Duration::from_secs(AI_HEALTHCHECK_TIMEOUT_SECONDS),
+    )
+    .await;
+    let status = if result.ok {
+        StatusCode::OK
+    } else {
+        StatusCode::BAD_GATEWAY
     };
-    cadence_base + horizon_boost + (index as i64 * 12)
+    (status, Json(result)).into_response()
 }
 
-fn classify_horizon_from_text(text: &str) -> String {
-    let lower = text.trim().to_lowercase();
-    if [
-        "today",
-        "tonight",
-        "now",
-        "urgent",
-        "daily",
-        "היום",
-        "עכשיו",
-        "יומי",
-        "דחוף",
-    ]
-    .iter()
-    .any(|needle| lower.contains(needle))
-    {
-        return "daily".to_string();
-    }
-    if [
-        "month",
-        "quarter",
-        "roadmap",
-        "milestone",
-        "חודש",
-        "רבעון",
-        "יעד ביניים",
-    ]
-    .iter()
-    .any(|needle| lower.contains(needle))
-    {
-        return "mid_term".to_string();
-    }
-    if [
-        "year", "decade", "legacy", "mission", "חזון", "שנתי", "ארוך",
-    ]
-    .iter()
-    .any(|needle| lower.contains(needle))
-    {
-        return "long_term".to_string();
+#[derive(Debug, Serialize)]
+struct AiHealthcheckResult {
+    ok: bool,
+    provider: &'static str,
+    model: String,
+    latency_ms: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    upstream_status: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+// Sends the smallest Responses call that still exercises key, model, and network. Upstream
+// error bodies can echo part of the key, so only the status and OpenAI's error code are kept.
+async fn run_ai_healthcheck(
+    client: &Client,
+    runtime: &OpenAiRuntimeConfig,
+    timeout: Duration,
+) -> AiHealthcheckResult {
+    let payload = serde_json::json!({
+        "model": runtime.model,
+        "input": "ping",
+        "max_output_tokens": 16
+    });
+    let started = Instant::now();
+    let response = client
+        .post(openai_responses_url(runtime))
+        .bearer_auth(runtime.api_key.as_str())
+        .timeout(timeout)
+        .json(&payload)
+        .send()
+        .await;
+
+    let (upstream_status, error) = match response {
+        Ok(response) if response.status().is_success() => (Some(response.status().as_u16()), None),
+        Ok(response) => {
+            let status = response.status().as_u16();
+            let code = response
+                .json::<serde_json::Value>()
+                .await
+                .ok()
+                .and_then(|body| {
+                    body["error"]["code"]
+                        .as_str()
+                        .or_else(|| body["error"]["type"].as_str())
+                        .map(str::to_string)
+                })
+                .unwrap_or_else(|| "upstream_error".to_string());
+            (Some(status), Some(code))
+        }
+        Err(error) if error.is_timeout() => (None, Some("timeout".to_string())),
+        Err(error) if error.is_connect() => (None, Some("connect_failed".to_string())),
+        Err(_) => (None, Some("request_failed".to_string())),
+    };
+
+    AiHealthcheckResult {
+        ok: error.is_none(),
+        provider: runtime.backend_name(),
+        model: runtime.model.clone(),
+        latency_ms: started.elapsed().as_millis() as u64,
+        upstream_status,
+        error,
     }
-    "daily".to_string()
 }
 
-fn push_task_if_valid(tasks: &mut Vec<ExecutionTaskCandidate>, task: ExecutionTaskCandidate) {
-    let title = task.title.trim();
-    let detail = task.detail.trim();
-    if title.is_empty() || detail.is_empty() {
-        return;
+fn validate_company_status(
+    input: CompanyStatusRecord,
+) -> std::result::Result<CompanyStatusRecord, String> {
+    let phase = input.phase.trim().to_string();
+    let message = input.message.trim().to_string();
+    if phase.is_empty() || phase.chars().count() > MAX_COMPANY_STATUS_FIELD_LEN {
+        return Err(format!(
+            "phase is required and must be at most {MAX_COMPANY_STATUS_FIELD_LEN} characters"
+        ));
     }
-    tasks.push(task);
+    if message.is_empty() || message.chars().count() > MAX_COMPANY_STATUS_MESSAGE_LEN {
+        return Err(format!(
+            "message is required and must be at most {MAX_COMPANY_STATUS_MESSAGE_LEN} characters"
+        ));
+    }
+    let clean_list = |name: &str, items: Vec<String>| {
+        let items = items
+            .into_iter()
+            .map(|item| item.trim().to_string())
+            .filter(|item| !item.is_empty())
+            .collect::<Vec<_>>();
+        if items.len() > MAX_COMPANY_STATUS_ITEMS
+            || items
+                .iter()
+                .any(|item| item.chars().count() > MAX_COMPANY_STATUS_FIELD_LEN)
+        {
+            return Err(format!(
+                "{name} allows at most {MAX_COMPANY_STATUS_ITEMS} items of {MAX_COMPANY_STATUS_FIELD_LEN} characters"
+            ));
+        }
+        Ok(items)
+    };
+    Ok(CompanyStatusRecord {
+        phase,
+        current_focus: clean_list("current_focus", input.current_focus)?,
+        upcoming: clean_list("upcoming", input.upcoming)?,
+        open_for_investment: input.open_for_investment,
+        message,
+    })
 }
 
-fn extract_note_tasks(notes: Option<&[UserNoteRecord]>) -> Vec<ExecutionTaskCandidate> {
-    let mut tasks = Vec::new();
-    let Some(notes) = notes else {
-        return tasks;
+fn company_status_etag(status: &CompanyStatusRecord) -> String {
+    let serialized = serde_json::to_vec(status).unwrap_or_default();
+    let digest = Sha256::digest(serialized.as_slice());
+    format!("\"{}\"", hex_encode(&digest[..16]))
+}
+
+fn if_none_match_matches(headers: &HeaderMap, etag: &str) -> bool {
+    let Some(raw) = headers
+        .get(header::IF_NONE_MATCH)
+        .and_then(|value| value.to_str().ok())
+    else {
+        return false;
     };
-    for note in notes.iter().take(8) {
-        let summary = sanitize_limited_text(note.content.as_str(), 200);
-        let horizon =
-            classify_horizon_from_text(format!("{} {}", note.title, note.content).as_str());
-        push_task_if_valid(
-            &mut tasks,
-            ExecutionTaskCandidate {
-                task_id: format!("note-{}", note.note_id),
-                title: note.title.clone(),
-                detail: summary,
-                source: "notes".to_string(),
-                horizon,
-                urgency: 0.72,
-                impact: 0.82,
-                confidence: 0.78,
-            },
-        );
+    raw.split(',').map(str::trim).any(|candidate| {
+        candidate == "*" || candidate.strip_prefix("W/").unwrap_or(candidate) == etag
+    })
+}
+
+/// User tags keep their place; derived tags are appended unless already present, and the
+/// total stays within `MAX_FEEDBACK_TAGS`.
+fn merge_feedback_tags(tags: &mut Vec<String>, derived: Vec<&'static str>) {
+    for tag in derived {
+        if tags.len() >= MAX_FEEDBACK_TAGS {
+            break;
+        }
+        if !tags
+            .iter()
+            .any(|existing| existing.eq_ignore_ascii_case(tag))
+        {
+            tags.push(tag.to_string());
+        }
     }
-    tasks
 }
 
-fn extract_survey_tasks(
-    survey: Option<&SurveyStateRecord>,
-    locale: &str,
-) -> Vec<ExecutionTaskCandidate> {
-    let mut tasks = Vec::new();
-    let Some(survey_state) = survey else {
-        return tasks;
-    };
+async fn feedback_submit(
+    State(state): State<ApiState>,
+    headers: HeaderMap,
+    Json(input): Json<FeedbackSubmitRequest>,
+) -> impl IntoResponse {
+    let message = sanitize_limited_text(input.message.trim(), MAX_FEEDBACK_MESSAGE_LEN);
+    if message.is_empty() {
+        return ApiError::bad_request("invalid_message", "feedback message is required")
+            .into_response();
+    }
+    let (message, message_original_sealed) = scrub_text_for_storage(&state, message);
 
-    if let Some(goal) = survey_state.answers.get("primary_goal") {
-        let detail = if locale == "he" {
-            format!("יעד אסטרטגי ראשי מהסקר: {}", goal)
-        } else {
-            format!("Primary strategic goal from survey: {}", goal)
-        };
-        push_task_if_valid(
-            &mut tasks,
-            ExecutionTaskCandidate {
-                task_id: "survey-primary-goal".to_string(),
-                title: if locale == "he" {
-                    "עיגון יעד אסטרטגי".to_string()
-                } else {
-                    "Anchor strategic objective".to_string()
-                },
-                detail,
-                source: "survey".to_string(),
-                horizon: "long_term".to_string(),
-                urgency: 0.6,
-                impact: 0.95,
-                confidence: 0.86,
-            },
+    let user_id = resolve_user_id(&state, &headers, input.user_id.clone());
+    let mut tags = input
+        .tags
+        .unwrap_or_default()
+        .into_iter()
+        .take(MAX_FEEDBACK_TAGS)
+        .map(|value| sanitize_limited_text(value.trim(), MAX_FEEDBACK_TAG_LEN))
+        .filter(|value| !value.is_empty())
+        .collect::<Vec<_>>();
+    if state.feedback_auto_tags {
+        let locale = atlas_core::Locale::from_optional_str(input.locale.as_deref());
+        merge_feedback_tags(
+            &mut tags,
+            feedback_tags_for_message(message.as_str(), locale),
         );
     }
-    if let Some(pressure) = survey_state.answers.get("daily_pressure") {
-        push_task_if_valid(
-            &mut tasks,
-            ExecutionTaskCandidate {
-                task_id: "survey-pressure".to_string(),
-                title: if locale == "he" {
-                    "ייצוב עומס יומי".to_string()
-                } else {
-                    "Stabilize daily pressure".to_string()
-                },
-                detail: if locale == "he" {
-                    format!(
-                        "המערכת זיהתה לחץ יומי ברמה {}. בצע חסימה יזומה ביומן.",
-                        pressure
-                    )
+    let target_employee = sanitize_limited_text(
+        input
+            .target_employee
+            .unwrap_or_else(|| "product_team".to_string())
+            .trim()
+            .to_lowercase()
+            .as_str(),
+        MAX_PROFILE_FIELD_LEN,
+    );
+    let source = sanitize_limited_text(
+        input
+            .source
+            .unwrap_or_else(|| "web".to_string())
+            .trim()
+            .to_lowercase()
+            .as_str(),
+        MAX_PROFILE_FIELD_LEN,
+    );
+
+    let item = FeedbackRecord {
+        feedback_id: uuid::Uuid::new_v4().to_string(),
+        user_id,
+        category: sanitize_enum_value(
+            input.category.trim(),
+            &["product", "ux", "bug", "safety", "support", "other"],
+            "other",
+        ),
+        severity: sanitize_enum_value(
+            input
+                .severity
+                .unwrap_or_else(|| "normal".to_string())
+                .as_str(),
+            &["low", "normal", "high", "critical"],
+            "normal",
+        ),
+        message,
+        tags,
+        target_employee: if target_employee.is_empty() {
+            "product_team".to_string()
+        } else {
+            target_employee
+        },
+        source: if source.is_empty() {
+            "web".to_string()
+        } else {
+            source
+        },
+        status: "new".to_string(),
+        created_at: chrono::Utc::now().to_rfc3339(),
+        resolved_at: None,
+        resolved_by: None,
+        resolution_note: None,
+        message_original_sealed,
+    };
+
+    state.feedback_items.write().push(item.clone());
+    let _ = persist_feedback_items_if_configured(&state, std::slice::from_ref(&item)).await;
+    if let Some(feedback_user_id) = item.user_id.as_ref() {
+        let _ = ingest_memory_event_for_user(
+            &state,
+            feedback_user_id.as_str(),
+            MemoryIngestEvent {
+                memory_type: "friction".to_string(),
+                stability: "transient".to_string(),
+                source: "feedback".to_string(),
+                text: format!(
+                    "Feedback {} [{}]: {}",
+                    item.category, item.severity, item.message
+                ),
+                weight: if item.severity == "critical" {
+                    0.95
+                } else if item.severity == "high" {
+                    0.85
                 } else {
-                    format!(
-                        "Survey indicates daily pressure at {}. Block focus time in calendar.",
-                        pressure
-                    )
+                    0.72
                 },
-                source: "survey".to_string(),
-                horizon: "daily".to_string(),
-                urgency: if pressure == "high" { 0.95 } else { 0.72 },
-                impact: 0.78,
-                confidence: 0.9,
+                tags: item.tags.clone(),
+                happened_at: Some(chrono::Utc::now()),
+                expires_at: Some(
+                    chrono::Utc::now() + chrono::Duration::days(TRANSIENT_MEMORY_TTL_DAYS),
+                ),
             },
+        )
+        .await;
+    }
+
+    (
+        StatusCode::OK,
+        Json(serde_json::json!({
+            "ok": true,
+            "feedback": FeedbackRecord {
+                message_original_sealed: None,
+                ..item
+            }
+        })),
+    )
+        .into_response()
+}
+
+async fn feedback_for_employee(
+    State(state): State<ApiState>,
+    headers: HeaderMap,
+    AxumPath(employee): AxumPath<String>,
+    Query(query): Query<FeedbackListQuery>,
+) -> impl IntoResponse {
+    // Feedback holds free-form user messages, so a signed-in browser session is not enough
+    // to read it back; only service keys may.
+    if let Err(error) = require_service_key(&state, &headers) {
+        return error.into_response();
+    }
+
+    let employee_normalized = employee.trim().to_lowercase();
+    let limit = query.limit.unwrap_or(30).clamp(1, 200);
+
+    let mut items = state
+        .feedback_items
+        .read()
+        .iter()
+        .filter(|entry| entry.target_employee == employee_normalized)
+        .cloned()
+        .collect::<Vec<_>>();
+    items.sort_by(|lhs, rhs| rhs.created_at.cmp(&lhs.created_at));
+    items.truncate(limit);
+    for item in items.iter_mut() {
+        item.message = redact_email_addresses(item.message.as_str());
+        item.message_original_sealed = None;
+    }
+
+    (
+        StatusCode::OK,
+        Json(serde_json::json!({
+            "employee": employee_normalized,
+            "count": items.len(),
+            "items": items
+        })),
+    )
+        .into_response()
+}
+
+async fn feedback_status_update(
+    State(state): State<ApiState>,
+    headers: HeaderMap,
+    AxumPath(feedback_id): AxumPath<String>,
+    Json(input): Json<FeedbackStatusRequest>,
+) -> impl IntoResponse {
+    if let Err(error) = require_service_key(&state, &headers) {
+        return error.into_response();
+    }
+
+    let updated = {
+        let mut items = state.feedback_items.write();
+        let Some(item) = items
+            .iter_mut()
+            .find(|entry| entry.feedback_id == feedback_id)
+        else {
+            return ApiError::not_found("feedback_not_found", "feedback item not found")
+                .into_response();
+        };
+        if let Err(err) = apply_feedback_status(
+            item,
+            input.status.as_str(),
+            input.resolved_by.as_deref(),
+            input.note.as_deref(),
+            chrono::Utc::now(),
+        ) {
+            return err.into_response();
+        }
+        item.clone()
+    };
+    let _ = persist_feedback_items_if_configured(&state, std::slice::from_ref(&updated)).await;
+
+    (
+        StatusCode::OK,
+        Json(serde_json::json!({
+            "ok": true,
+            "feedback": FeedbackRecord {
+                message: redact_email_addresses(updated.message.as_str()),
+                message_original_sealed: None,
+                ..updated
+            }
+        })),
+    )
+        .into_response()
+}
+
+// Clears an employee's open queue once a fix ships. Only items that actually change are
+// written back.
+async fn feedback_resolve_all(
+    State(state): State<ApiState>,
+    headers: HeaderMap,
+    AxumPath(employee): AxumPath<String>,
+    Json(input): Json<FeedbackResolveAllRequest>,
+) -> impl IntoResponse {
+    if let Err(error) = require_service_key(&state, &headers) {
+        return error.into_response();
+    }
+    if let Err(err) = feedback_resolver(input.resolved_by.as_deref()) {
+        return err.into_response();
+    }
+
+    let employee_normalized = employee.trim().to_lowercase();
+    let category = input
+        .category
+        .map(|value| value.trim().to_lowercase())
+        .filter(|value| !value.is_empty());
+    let tag = input
+        .tag
+        .map(|value| value.trim().to_lowercase())
+        .filter(|value| !value.is_empty());
+    let now = chrono::Utc::now();
+    let resolved = {
+        let mut items = state.feedback_items.write();
+        let mut resolved = Vec::new();
+        for item in items.iter_mut().filter(|entry| {
+            entry.target_employee == employee_normalized
+                && entry.status != "resolved"
+                && category
+                    .as_ref()
+                    .is_none_or(|category| entry.category == *category)
+                && tag.as_ref().is_none_or(|tag| {
+                    entry
+                        .tags
+                        .iter()
+                        .any(|value| value.eq_ignore_ascii_case(tag))
+                })
+        }) {
+            if apply_feedback_status(
+                item,
+                "resolved",
+                input.resolved_by.as_deref(),
+                input.note.as_deref(),
+                now,
+            )
+            .is_ok()
+            {
+                resolved.push(item.clone());
+            }
+        }
+        resolved
+    };
+    if !resolved.is_empty() {
+        let _ = persist_feedback_items_if_configured(&state, &resolved).await;
+    }
+
+    (
+        StatusCode::OK,
+        Json(serde_json::json!({
+            "ok": true,
+            "employee": employee_normalized,
+            "resolved": resolved.len(),
+            "feedback_ids": resolved
+                .iter()
+                .map(|item| item.feedback_id.as_str())
+                .collect::<Vec<_>>()
+        })),
+    )
+        .into_response()
+}
+
+fn feedback_resolver(resolved_by: Option<&str>) -> std::result::Result<String, ApiError> {
+    resolved_by
+        .map(|value| sanitize_limited_text(value.trim(), MAX_PROFILE_FIELD_LEN))
+        .filter(|value| !value.is_empty())
+        .ok_or_else(|| {
+            ApiError::bad_request(
+                "invalid_resolved_by",
+                "resolved_by is required when resolving feedback",
+            )
+        })
+}
+
+/// Resolving stamps `resolved_at`/`resolved_by` (and the optional note) once; re-resolving
+/// keeps the original stamp so time-to-resolve is not reset. Any other status reopens the
+/// item and clears all three.
+fn apply_feedback_status(
+    item: &mut FeedbackRecord,
+    status: &str,
+    resolved_by: Option<&str>,
+    note: Option<&str>,
+    now: chrono::DateTime<chrono::Utc>,
+) -> std::result::Result<(), ApiError> {
+    let status = status.trim().to_lowercase();
+    if !FEEDBACK_STATUSES.contains(&status.as_str()) {
+        return Err(ApiError::bad_request(
+            "invalid_feedback_status",
+            format!("status must be one of: {}", FEEDBACK_STATUSES.join(", ")),
+        ));
+    }
+    if status == "resolved" {
+        let resolved_by = feedback_resolver(resolved_by)?;
+        if item.resolved_at.is_none() {
+            item.resolved_at = Some(now.to_rfc3339());
+            item.resolved_by = Some(resolved_by);
+            item.resolution_note = note
+                .map(|value| sanitize_limited_text(value.trim(), MAX_FEEDBACK_MESSAGE_LEN))
+                .filter(|value| !value.is_empty());
+        }
+    } else {
+        item.resolved_at = None;
+        item.resolved_by = None;
+        item.resolution_note = None;
+    }
+    item.status = status;
+    Ok(())
+}
+
+/// Write-time scrubbing for free-form text. The sealed original is only produced when an
+/// originals key is configured and the scrub actually changed something.
+fn scrub_text_for_storage(state: &ApiState, text: String) -> (String, Option<String>) {
+    if !state.scrub_pii {
+        return (text, None);
+    }
+    let scrubbed = scrub_pii(text.as_str());
+    if scrubbed == text {
+        return (text, None);
+    }
+    let sealed = state
+        .pii_original_key
+        .as_ref()
+        .and_then(|key| key.seal(text.as_str()));
+    (scrubbed, sealed)
+}
+
+fn dedupe_suggested_actions(actions: &mut Vec<atlas_core::SuggestedAction>) {
+    let mut seen = HashSet::new();
+    actions.retain(|action| {
+        let title = action
+            .payload
+            .get("title")
+            .or_else(|| action.payload.get("label"))
+            .and_then(|value| value.as_str())
+            .unwrap_or(action.label.as_str());
+        seen.insert((
+            action.action_type.trim().to_lowercase(),
+            title.trim().to_lowercase(),
+        ))
+    });
+}
+
+#[allow(clippy::too_many_arguments)]
+fn build_action_telemetry(
+    trace_id: &str,
+    action: &str,
+    success: bool,
+    app: Option<&str>,
+    supports_direct_write: bool,
+    fallback_used: bool,
+    primary_target: Option<String>,
+    warnings: Vec<String>,
+) -> ActionTelemetry {
+    ActionTelemetry {
+        trace_id: trace_id.to_string(),
+        action: action.to_string(),
+        success,
+        app: app.map(|value| value.to_string()),
+        supports_direct_write,
+        fallback_used,
+        primary_target,
+        warnings,
+        generated_at: chrono::Utc::now().to_rfc3339(),
+        scheduled_start_utc: None,
+    }
+}
+
+fn action_error(
+    status: StatusCode,
+    trace_id: &str,
+    action: &str,
+    error: &str,
+    message: &str,
+    app: Option<&str>,
+) -> ApiError {
+    let telemetry = build_action_telemetry(
+        trace_id,
+        action,
+        false,
+        app,
+        false,
+        false,
+        None,
+        vec![error.to_string()],
+    );
+    ApiError::new(status, error, message)
+        .with_details(serde_json::json!({ "telemetry": telemetry }))
+}
+
+fn build_google_calendar_url(
+    title: &str,
+    details: &str,
+    start: chrono::DateTime<chrono::Utc>,
+    end: chrono::DateTime<chrono::Utc>,
+) -> (String, bool) {
+    let details_for_url = sanitize_limited_text(details, MAX_REMINDER_DETAILS_FOR_URL);
+    let details_truncated = details_for_url != details;
+    let url = format!(
+        "https://calendar.google.com/calendar/render?action=TEMPLATE&text={}&details={}&dates={}/{}&ctz=UTC&sf=true&output=xml",
+        pct_encode(title),
+        pct_encode(details_for_url.as_str()),
+        start.format("%Y%m%dT%H%M%SZ"),
+        end.format("%Y%m%dT%H%M%SZ")
+    );
+    (url, details_truncated)
+}
+
+fn build_shortcuts_url(shortcut_name: &str, payload: &str) -> Option<String> {
+    let url = format!(
+        "shortcuts://run-shortcut?name={}&input=text&text={}",
+        pct_encode(shortcut_name),
+        pct_encode(payload)
+    );
+    if url.len() > MAX_SHORTCUTS_URL_LEN {
+        None
+    } else {
+        Some(url)
+    }
+}
+
+fn build_shortcuts_url_with_fallback(
+    shortcut_name: &str,
+    full_payload: &str,
+    compact_payload: &str,
+) -> (Option<String>, bool) {
+    if let Some(url) = build_shortcuts_url(shortcut_name, full_payload) {
+        return (Some(url), false);
+    }
+    (build_shortcuts_url(shortcut_name, compact_payload), true)
+}
+
+// Days come back in the app's Sun-first week order regardless of input order, so the payload
+// and fallback instructions are deterministic.
+fn sanitize_alarm_days(days: Option<Vec<String>>) -> Vec<String> {
+    const WEEK_DAYS: [&str; 7] = ["Sun", "Mon", "Tue", "Wed", "Thu", "Fri", "Sat"];
+    let default_days = || WEEK_DAYS[..5].iter().map(|day| day.to_string()).collect();
+    let Some(incoming) = days else {
+        return default_days();
+    };
+    let mut selected = [false; 7];
+    for day in incoming {
+        let lower = day.trim().to_lowercase();
+        let index = match lower.as_str() {
+            "sun" | "sunday" => Some(0),
+            "mon" | "monday" => Some(1),
+            "tue" | "tues" | "tuesday" => Some(2),
+            "wed" | "wednesday" => Some(3),
+            "thu" | "thurs" | "thursday" => Some(4),
+            "fri" | "friday" => Some(5),
+            "sat" | "saturday" => Some(6),
+            _ => None,
+        };
+        if let Some(index) = index {
+            selected[index] = true;
+        }
+    }
+    let out: Vec<String> = WEEK_DAYS
+        .iter()
+        .zip(selected)
+        .filter(|(_, is_selected)| *is_selected)
+        .map(|(day, _)| day.to_string())
+        .collect();
+    if out.is_empty() {
+        default_days()
+    } else {
+        out
+    }
+}
+
+async fn action_reminder(
+    State(state): State<ApiState>,
+    headers: HeaderMap,
+    Json(input): Json<ReminderActionRequest>,
+) -> impl IntoResponse {
+    let user_id = resolve_user_id_or_guest(&state, &headers, None);
+    let locale = resolve_request_locale(&state, &headers, &user_id, None);
+    let trace_id = trace_id_from_headers(&headers);
+    match build_reminder_action(
+        &state,
+        user_id.as_str(),
+        locale == "he",
+        trace_id.as_str(),
+        input,
+    ) {
+        Ok(response) => (
+            StatusCode::OK,
+            localized_response_headers(locale),
+            Json(response),
+        )
+            .into_response(),
+        Err(error) => error.into_response(),
+    }
+}
+
+async fn action_reminder_snooze(
+    State(state): State<ApiState>,
+    headers: HeaderMap,
+    Json(input): Json<ReminderSnoozeRequest>,
+) -> impl IntoResponse {
+    let user_id = resolve_user_id_or_guest(&state, &headers, None);
+    let locale = resolve_request_locale(&state, &headers, &user_id, None);
+    let trace_id = trace_id_from_headers(&headers);
+    let utc_offset_minutes = input
+        .utc_offset_minutes
+        .map(clamp_utc_offset_minutes)
+        .unwrap_or_else(|| {
+            state
+                .studio_preferences
+                .read()
+                .get(&user_id)
+                .map(|prefs| prefs.utc_offset_minutes)
+                .unwrap_or(0)
+        });
+    let now = chrono::Utc::now();
+    let Some(due_at) = snooze_due_at(input.snooze.trim(), now, utc_offset_minutes) else {
+        let available = reminder_snooze_options(now, utc_offset_minutes)
+            .into_iter()
+            .map(|option| option.snooze)
+            .collect::<Vec<_>>();
+        return action_error(
+            StatusCode::BAD_REQUEST,
+            trace_id.as_str(),
+            "reminder",
+            "invalid_snooze_option",
+            format!("snooze must be one of: {}", available.join(", ")).as_str(),
+            None,
+        )
+        .into_response();
+    };
+
+    let mut reminder = input.reminder;
+    reminder.due_at_utc = Some(due_at.to_rfc3339());
+    match build_reminder_action(
+        &state,
+        user_id.as_str(),
+        locale == "he",
+        trace_id.as_str(),
+        reminder,
+    ) {
+        Ok(response) => (
+            StatusCode::OK,
+            localized_response_headers(locale),
+            Json(response),
+        )
+            .into_response(),
+        Err(error) => error.into_response(),
+    }
+}
+
+// "tonight" is only offered while it is still at least an hour away in the user's local time;
+// later than that it would be no different from "plus_1h".
+fn snooze_due_at(
+    snooze: &str,
+    now: chrono::DateTime<chrono::Utc>,
+    utc_offset_minutes: i32,
+) -> Option<chrono::DateTime<chrono::Utc>> {
+    let offset = chrono::FixedOffset::east_opt(utc_offset_minutes * 60)?;
+    let local_now = now.with_timezone(&offset);
+    let local_at = |date: chrono::NaiveDate, hour: u32| {
+        date.and_hms_opt(hour, 0, 0)?
+            .and_local_timezone(offset)
+            .single()
+            .map(|value| value.with_timezone(&chrono::Utc))
+    };
+    match snooze {
+        "plus_1h" => Some(now + chrono::Duration::hours(1)),
+        "tonight" => local_at(local_now.date_naive(), SNOOZE_TONIGHT_HOUR)
+            .filter(|due_at| *due_at - now >= chrono::Duration::hours(1)),
+        "tomorrow_morning" => local_at(local_now.date_naive().succ_opt()?, SNOOZE_MORNING_HOUR),
+        _ => None,
+    }
+}
+
+fn reminder_snooze_options(
+    now: chrono::DateTime<chrono::Utc>,
+    utc_offset_minutes: i32,
+) -> Vec<ReminderSnoozeOption> {
+    REMINDER_SNOOZE_OPTIONS
+        .iter()
+        .filter_map(|snooze| {
+            snooze_due_at(snooze, now, utc_offset_minutes).map(|due_at| ReminderSnoozeOption {
+                snooze,
+                due_at_utc: due_at.to_rfc3339(),
+            })
+        })
+        .collect()
+}
+
+fn build_reminder_action(
+    state: &ApiState,
+    user_id: &str,
+    is_he: bool,
+    trace_id: &str,
+    input: ReminderActionRequest,
+) -> std::result::Result<ReminderActionResponse, ApiError> {
+    if input.title.trim().is_empty() {
+        return Err(action_error(
+            StatusCode::BAD_REQUEST,
+            trace_id,
+            "reminder",
+            "invalid_title",
+            "title is required",
+            None,
+        ));
+    }
+
+    let prefs = state
+        .studio_preferences
+        .read()
+        .get(user_id)
+        .cloned()
+        .unwrap_or_else(|| default_studio_preferences(user_id));
+
+    let app = sanitize_enum_value(
+        input
+            .reminders_app
+            .unwrap_or_else(|| prefs.reminders_app.clone())
+            .as_str(),
+        &[
+            "google_calendar",
+            "apple_reminders",
+            "shortcuts",
+            "todoist",
+            "notion",
+        ],
+        "google_calendar",
+    );
+
+    let mut warnings = Vec::new();
+    let title = sanitize_limited_text(input.title.trim(), MAX_REMINDER_TITLE_LEN);
+    if title.is_empty() {
+        return Err(action_error(
+            StatusCode::BAD_REQUEST,
+            trace_id,
+            "reminder",
+            "invalid_title",
+            "title is required",
+            Some(app.as_str()),
+        ));
+    }
+    let details = sanitize_limited_text(
+        input.details.unwrap_or_default().as_str(),
+        MAX_REMINDER_DETAILS_LEN,
+    );
+    let requested_duration = input.duration_minutes.unwrap_or(30);
+    let duration_minutes =
+        requested_duration.clamp(MIN_REMINDER_DURATION_MINUTES, MAX_REMINDER_DURATION_MINUTES);
+    if duration_minutes != requested_duration {
+        warnings.push("duration_minutes_clamped".to_string());
+    }
+
+    // Lenient by design: an unusable due date still yields a reminder two hours out, and the
+    // warning lets the client tell the user the time was not taken.
+    let due_at =
+        parse_rfc3339_or_error("due_at_utc", input.due_at_utc.as_deref()).unwrap_or_else(|_| {
+            warnings.push("due_at_utc_invalid_defaulted".to_string());
+            None
+        });
+    let start = due_at.unwrap_or_else(|| chrono::Utc::now() + chrono::Duration::hours(2));
+    let end = start + chrono::Duration::minutes(duration_minutes as i64);
+    let (google_calendar_url, details_truncated) =
+        build_google_calendar_url(title.as_str(), details.as_str(), start, end);
+    if details_truncated {
+        warnings.push("details_truncated_for_google_calendar_url".to_string());
+    }
+
+    let ics_content = format!(
+        "BEGIN:VCALENDAR\r\nVERSION:2.0\r\nPRODID:-//AtlasMasa//Reminder//EN\r\nMETHOD:PUBLISH\r\nBEGIN:VEVENT\r\nUID:{}\r\nDTSTAMP:{}\r\nDTSTART:{}\r\nDTEND:{}\r\n{}\r\n{}\r\nEND:VEVENT\r\nEND:VCALENDAR\r\n",
+        uuid::Uuid::new_v4(),
+        chrono::Utc::now().format("%Y%m%dT%H%M%SZ"),
+        start.format("%Y%m%dT%H%M%SZ"),
+        end.format("%Y%m%dT%H%M%SZ"),
+        fold_ics_line(format!("SUMMARY:{}", escape_ics(title.as_str())).as_str()),
+        fold_ics_line(format!("DESCRIPTION:{}", escape_ics(details.as_str())).as_str())
+    );
+    let shortcuts_payload = format!(
+        "Action: Create reminder\nTitle: {}\nWhen (UTC): {}\nDuration (minutes): {}\nDetails: {}",
+        title,
+        start.to_rfc3339(),
+        duration_minutes,
+        details
+    );
+    let shortcuts_compact_payload = format!(
+        "Create reminder: {} at {} UTC for {} minutes",
+        title,
+        start.format("%Y-%m-%d %H:%M"),
+        duration_minutes
+    );
+    let (shortcuts_url, shortcuts_compact_used) = build_shortcuts_url_with_fallback(
+        state.shortcut_reminder_name.as_str(),
+        &shortcuts_payload,
+        &shortcuts_compact_payload,
+    );
+    if shortcuts_compact_used {
+        warnings.push("shortcuts_compact_payload_used".to_string());
+    }
+    if shortcuts_url.is_none() {
+        warnings.push("shortcuts_url_unavailable".to_string());
+    }
+    let todoist_url = format!(
+        "https://todoist.com/app/add?content={}&description={}&date={}",
+        pct_encode(title.as_str()),
+        pct_encode(details.as_str()),
+        pct_encode(start.format("%Y-%m-%d %H:%M").to_string().as_str())
+    );
+
+    warnings.push("web_auto_write_requires_user_confirmation".to_string());
+
+    let (primary_url, user_message) = match app.as_str() {
+        "google_calendar" => (
+            Some(google_calendar_url.clone()),
+            if is_he {
+                "ווב לא כותב ישירות ליומן. נפתחה טיוטת אירוע ב-Google Calendar; אשרו שמירה. קובץ ICS זמין כגיבוי."
+                    .to_string()
+            } else {
+                "Web cannot write directly to calendar providers. A prefilled Google Calendar draft was opened; confirm save. ICS fallback is included."
+                    .to_string()
+            },
+        ),
+        "shortcuts" => (
+            shortcuts_url.clone(),
+            if is_he {
+                if shortcuts_url.is_some() {
+                    "ווב לא כותב ישירות לתזכורות. נשלח קישור ל-Shortcuts; אם לא זמין, השתמשו בקובץ ICS."
+                        .to_string()
+                } else {
+                    "לא ניתן לייצר קישור Shortcuts בטוח כרגע. השתמשו בקובץ ICS כגיבוי.".to_string()
+                }
+            } else if shortcuts_url.is_some() {
+                "Web cannot write directly to reminders. Shortcuts deep link is ready; if unavailable, use the ICS fallback."
+                    .to_string()
+            } else {
+                "A safe Shortcuts deep link could not be generated. Use the ICS fallback file."
+                    .to_string()
+            },
+        ),
+        "todoist" => (
+            Some(todoist_url),
+            if is_he {
+                "ווב לא יכול ליצור משימות Todoist ישירות ללא אישור ידני. נפתחה טיוטה + גיבוי ICS."
+                    .to_string()
+            } else {
+                "Web cannot directly write into Todoist without user confirmation. Opened a task draft plus ICS fallback."
+                    .to_string()
+            },
+        ),
+        "notion" => (
+            Some("https://www.notion.so".to_string()),
+            if is_he {
+                "ווב לא יכול לכתוב ישירות ל-Notion. נפתחה סביבת Notion וקובץ ICS זמין לגיבוי."
+                    .to_string()
+            } else {
+                "Web cannot directly write into Notion. Opened Notion and provided ICS fallback."
+                    .to_string()
+            },
+        ),
+        _ => (
+            shortcuts_url
+                .clone()
+                .or_else(|| Some(google_calendar_url.clone())),
+            if is_he {
+                "ווב לא מאפשר כתיבה ישירה ל-Apple Reminders. ננסה לפתוח קיצור דרך; לחלופין השתמשו בקובץ ICS."
+                    .to_string()
+            } else {
+                "Web cannot directly write to Apple Reminders. We attempt a Shortcuts handoff; otherwise use the ICS fallback."
+                    .to_string()
+            },
+        ),
+    };
+    let fallback_used = true;
+
+    let mut telemetry = build_action_telemetry(
+        trace_id,
+        "reminder",
+        true,
+        Some(app.as_str()),
+        false,
+        fallback_used,
+        primary_url.clone(),
+        warnings,
+    );
+    telemetry.scheduled_start_utc = Some(start.to_rfc3339());
+
+    Ok(ReminderActionResponse {
+        app,
+        google_calendar_url,
+        ics_filename: "atlas-masa-reminder.ics".to_string(),
+        ics_content,
+        shortcuts_url: shortcuts_url.clone().unwrap_or_default(),
+        primary_url,
+        supports_direct_write: false,
+        fallback_used,
+        user_message,
+        telemetry,
+    })
+}
+
+async fn action_alarm(
+    State(state): State<ApiState>,
+    headers: HeaderMap,
+    Json(input): Json<AlarmActionRequest>,
+) -> impl IntoResponse {
+    let user_id = resolve_user_id_or_guest(&state, &headers, None);
+    let locale = resolve_request_locale(&state, &headers, &user_id, None);
+    let trace_id = trace_id_from_headers(&headers);
+    match build_alarm_action(
+        &state,
+        user_id.as_str(),
+        locale == "he",
+        trace_id.as_str(),
+        input,
+    ) {
+        Ok(response) => (
+            StatusCode::OK,
+            localized_response_headers(locale),
+            Json(response),
+        )
+            .into_response(),
+        Err(error) => error.into_response(),
+    }
+}
+
+fn build_alarm_action(
+    state: &ApiState,
+    user_id: &str,
+    is_he: bool,
+    trace_id: &str,
+    input: AlarmActionRequest,
+) -> std::result::Result<AlarmActionResponse, ApiError> {
+    if input.label.trim().is_empty() {
+        return Err(action_error(
+            StatusCode::BAD_REQUEST,
+            trace_id,
+            "alarm",
+            "invalid_label",
+            "label is required",
+            None,
+        ));
+    }
+
+    if !is_valid_hhmm(&input.time_local) {
+        return Err(action_error(
+            StatusCode::BAD_REQUEST,
+            trace_id,
+            "alarm",
+            "invalid_time",
+            "time_local must be HH:MM",
+            None,
+        ));
+    }
+
+    let prefs = state
+        .studio_preferences
+        .read()
+        .get(user_id)
+        .cloned()
+        .unwrap_or_else(|| default_studio_preferences(user_id));
+    let app = sanitize_enum_value(
+        input
+            .alarms_app
+            .unwrap_or_else(|| prefs.alarms_app.clone())
+            .as_str(),
+        &["apple_clock", "google_clock", "shortcuts"],
+        "apple_clock",
+    );
+
+    let mut warnings = Vec::new();
+    let label = sanitize_limited_text(input.label.trim(), MAX_ALARM_LABEL_LEN);
+    if label.is_empty() {
+        return Err(action_error(
+            StatusCode::BAD_REQUEST,
+            trace_id,
+            "alarm",
+            "invalid_label",
+            "label is required",
+            Some(app.as_str()),
+        ));
+    }
+    let days = sanitize_alarm_days(input.days);
+    let payload = format!(
+        "Label: {}\nTime: {}\nDays: {}",
+        label,
+        input.time_local.trim(),
+        days.join(",")
+    );
+    let compact_payload = format!(
+        "Set alarm {} at {} ({})",
+        label,
+        input.time_local.trim(),
+        days.join(",")
+    );
+    let (shortcuts_url, shortcuts_compact_used) = build_shortcuts_url_with_fallback(
+        state.shortcut_alarm_name.as_str(),
+        &payload,
+        &compact_payload,
+    );
+    if shortcuts_compact_used {
+        warnings.push("shortcuts_compact_payload_used".to_string());
+    }
+    if shortcuts_url.is_none() {
+        warnings.push("shortcuts_url_unavailable".to_string());
+    }
+    warnings.push("web_auto_write_requires_user_confirmation".to_string());
+
+    let clock_url = if app == "google_clock" {
+        "intent://alarms#Intent;package=com.google.android.deskclock;end".to_string()
+    } else {
+        "clock://".to_string()
+    };
+    let primary_url = match app.as_str() {
+        "shortcuts" => shortcuts_url.clone().or_else(|| Some(clock_url.clone())),
+        "google_clock" | "apple_clock" => Some(clock_url.clone()),
+        _ => Some(clock_url.clone()),
+    };
+
+    let days_label = days.join(", ");
+    let user_message = match app.as_str() {
+        "shortcuts" => {
+            if is_he {
+                "ווב לא יוצר אזעקות אוטומטית. נשלח קישור Shortcuts; אם הוא לא נפתח, צרו אזעקה ידנית באפליקציית השעון."
+                    .to_string()
+            } else {
+                "Web cannot create alarms directly. A Shortcuts deep link was prepared; if unavailable, create it manually in Clock."
+                    .to_string()
+            }
+        }
+        "google_clock" => {
+            if is_he {
+                "ווב לא מגדיר אזעקה ישירה. ננסה לפתוח Google Clock דרך intent; אם נחסם בדפדפן, הגדירו ידנית."
+                    .to_string()
+            } else {
+                "Web cannot set Google Clock alarms directly. We attempt an intent launch; if blocked by browser, set it manually."
+                    .to_string()
+            }
+        }
+        _ => {
+            if is_he {
+                "ווב לא יכול ליצור אזעקות ישירות. נפתח קישור לאפליקציית השעון עם הוראות השלמה ידנית."
+                    .to_string()
+            } else {
+                "Web cannot create alarms directly. Clock launch is attempted with manual fallback guidance."
+                    .to_string()
+            }
+        }
+    };
+    let telemetry = build_action_telemetry(
+        trace_id,
+        "alarm",
+        true,
+        Some(app.as_str()),
+        false,
+        true,
+        primary_url.clone(),
+        warnings,
+    );
+
+    let fallback_instructions = if is_he {
+        format!(
+            "אם האוטומציה לא הופעלה, פתחו ידנית את אפליקציית השעון והגדירו אזעקה: '{}' בשעה {} בימים {}.",
+            label,
+            input.time_local.trim(),
+            days_label
+        )
+    } else {
+        format!(
+            "If automation does not trigger, open your Clock app manually and create alarm '{}' at {} on {}.",
+            label,
+            input.time_local.trim(),
+            days_label
+        )
+    };
+
+    Ok(AlarmActionResponse {
+        app,
+        clock_url,
+        shortcuts_url: shortcuts_url.unwrap_or_default(),
+        primary_url,
+        supports_direct_write: false,
+        fallback_used: true,
+        user_message,
+        fallback_instructions,
+        telemetry,
+    })
+}
+
+// One round trip for the "schedule this" button. Both specs are validated before anything is
+// returned, and both telemetry blocks carry the request's trace id.
+async fn action_plan(
+    State(state): State<ApiState>,
+    headers: HeaderMap,
+    Json(input): Json<ActionPlanRequest>,
+) -> impl IntoResponse {
+    let user_id = resolve_user_id_or_guest(&state, &headers, None);
+    let locale = resolve_request_locale(&state, &headers, &user_id, None);
+    let is_he = locale == "he";
+    let trace_id = trace_id_from_headers(&headers);
+    let reminder = match build_reminder_action(
+        &state,
+        user_id.as_str(),
+        is_he,
+        trace_id.as_str(),
+        input.reminder,
+    ) {
+        Ok(response) => response,
+        Err(error) => return error.into_response(),
+    };
+    let alarm = match build_alarm_action(
+        &state,
+        user_id.as_str(),
+        is_he,
+        trace_id.as_str(),
+        input.alarm,
+    ) {
+        Ok(response) => response,
+        Err(error) => return error.into_response(),
+    };
+
+    (
+        StatusCode::OK,
+        localized_response_headers(locale),
+        Json(ActionPlanResponse {
+            trace_id,
+            reminder,
+            alarm,
+        }),
+    )
+        .into_response()
+}
+
+async fn plan_trip(
+    State(state): State<ApiState>,
+    Json(input): Json<TripPlanRequest>,
+) -> impl IntoResponse {
+    match state.agent.plan_trip(input).await {
+        Ok(response) => (StatusCode::OK, Json(response)).into_response(),
+        Err(error) => ApiError::bad_request("plan_trip_failed", error.to_string()).into_response(),
+    }
+}
+
+// Admin and operator endpoints: a signed-in browser session passes api_key_middleware without a
+// key, so these handlers additionally insist on a service x-api-key (any scope).
+fn require_service_key(state: &ApiState, headers: &HeaderMap) -> std::result::Result<(), ApiError> {
+    let provided_key = headers
+        .get("x-api-key")
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default();
+    if service_api_key_scope(state, provided_key).is_none() {
+        return Err(ApiError::forbidden(
+            "service_key_required",
+            "this endpoint requires a service x-api-key",
+        ));
+    }
+    Ok(())
+}
+
+// Set by `api_key_middleware` when a browser request was let through on its session cookie
+// (allowlisted origin, live session). Only such requests slide the session forward.
+#[derive(Debug, Clone)]
+struct CookieAuthenticatedSession(String);
+
+async fn api_key_middleware(
+    State(state): State<ApiState>,
+    mut request: Request<Body>,
+    next: Next,
+) -> Response {
+    let path = request.uri().path().to_string();
+    if request.method() == Method::OPTIONS || is_public_endpoint(path.as_str()) {
+        return next.run(request).await;
+    }
+
+    let header_key = request
+        .headers()
+        .get("x-api-key")
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default();
+    match service_api_key_scope(&state, header_key) {
+        Some(ApiKeyScope::Full) => return next.run(request).await,
+        Some(ApiKeyScope::Routes(route_prefixes)) => {
+            if route_in_scope(path.as_str(), route_prefixes) {
+                return next.run(request).await;
+            }
+            return ApiError::forbidden(
+                "insufficient_scope",
+                "x-api-key is not scoped for this endpoint",
+            )
+            .into_response();
+        }
+        None => {}
+    }
+
+    // Browser requests can skip x-api-key only when:
+    // 1) origin is first-party allowlisted, and
+    // 2) a valid session cookie already resolves to a user.
+    // This blocks spoofed anonymous Origin headers from bypassing service-key checks.
+    if !request_origin_is_allowed(&state, request.headers()) {
+        return ApiError::unauthorized("unauthorized", "missing or invalid x-api-key")
+            .into_response();
+    }
+
+    let Some(session_user) = session_user_from_headers(&state, request.headers()) else {
+        return ApiError::unauthorized(
+            "not_authenticated",
+            "session is required when x-api-key is absent",
+        )
+        .into_response();
+    };
+
+    let (needs_cloud_storage, needs_cloud_compute) = cloud_requirements_for_endpoint(path.as_str());
+    if needs_cloud_storage || needs_cloud_compute {
+        let subscription = subscription_access_for_user(&state, &session_user).await;
+        let storage_ok = !needs_cloud_storage || subscription.cloud_storage_enabled;
+        let compute_ok = !needs_cloud_compute || subscription.cloud_compute_enabled;
+        if !storage_ok || !compute_ok {
+            let reason = if needs_cloud_storage && needs_cloud_compute {
+                "cloud_storage_and_compute_require_subscription"
+            } else if needs_cloud_storage {
+                "cloud_storage_requires_subscription"
+            } else {
+                "cloud_compute_requires_subscription"
+            };
+            return ApiError::payment_required(
+                reason,
+                "This cloud feature is available on the paid subscription plan.",
+            )
+            .with_details(serde_json::json!({ "subscription": subscription }))
+            .into_response();
+        }
+    }
+
+    if let Some(session_id) = read_cookie_value(request.headers(), &state.cookie_name) {
+        request
+            .extensions_mut()
+            .insert(CookieAuthenticatedSession(session_id));
+    }
+    next.run(request).await
+}
+
+fn session_user_from_headers(state: &ApiState, headers: &HeaderMap) -> Option<UserRecord> {
+    let session_id = read_cookie_value(headers, &state.cookie_name)?;
+
+    let (session, expired) = {
+        let mut sessions = state.sessions.write();
+        let now = chrono::Utc::now();
+
+        match sessions.get(&session_id).cloned() {
+            Some(session) if session.expires_at > now => (Some(session), None),
+            Some(expired) => {
+                sessions.remove(&session_id);
+                (None, Some(expired))
+            }
+            None => (None, None),
+        }
+    };
+    if let Some(expired) = expired {
+        let user = state.users.read().get(&expired.user_id).cloned();
+        log_auth_event(
+            "auth.session_revoked",
+            "success",
+            "session",
+            request_id_from_headers(headers).as_str(),
+            user.as_ref(),
+            Some("expired"),
+        );
+    }
+    let session = session?;
+
+    state.users.read().get(&session.user_id).cloned()
+}
+
+fn read_cookie_value(headers: &HeaderMap, cookie_name: &str) -> Option<String> {
+    let raw_cookie = headers.get(header::COOKIE)?.to_str().ok()?;
+    raw_cookie.split(';').find_map(|part| {
+        let mut split = part.trim().splitn(2, '=');
+        let key = split.next()?.trim();
+        let value = split.next()?.trim();
+        if key == cookie_name {
+            Some(value.to_string())
+        } else {
+            None
+        }
+    })
+}
+
+fn request_origin_is_allowed(state: &ApiState, headers: &HeaderMap) -> bool {
+    if let Some(origin) = request_origin_from_headers(headers) {
+        return state
+            .allowed_origins
+            .iter()
+            .any(|allowed| allowed == &origin);
+    }
+    false
+}
+
+fn request_origin_from_headers(headers: &HeaderMap) -> Option<String> {
+    headers
+        .get(header::ORIGIN)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.trim().trim_end_matches('/').to_string())
+        .filter(|value| !value.is_empty())
+}
+
+fn cookie_same_site_attr(value: &str) -> &'static str {
+    match value.trim().to_ascii_lowercase().as_str() {
+        "none" => "None",
+        "lax" => "Lax",
+        _ => "Strict",
+    }
+}
+
+// CHIPS only accepts Partitioned alongside Secure and SameSite=None; browsers
+// reject the whole cookie otherwise, so the attribute is dropped instead.
+fn cookie_is_partitioned(partitioned: bool, secure: bool, same_site: &str) -> bool {
+    partitioned && secure && cookie_same_site_attr(same_site) == "None"
+}
+
+fn build_session_cookie(
+    cookie_name: &str,
+    session_id: &str,
+    max_age_seconds: u64,
+    secure: bool,
+    same_site: &str,
+    partitioned: bool,
+    domain: &str,
+) -> String {
+    let mut segments = vec![
+        format!("{cookie_name}={session_id}"),
+        "Path=/".to_string(),
+        "HttpOnly".to_string(),
+        format!("SameSite={}", cookie_same_site_attr(same_site)),
+        format!("Max-Age={max_age_seconds}"),
+    ];
+    if secure {
+        segments.push("Secure".to_string());
+    }
+    if cookie_is_partitioned(partitioned, secure, same_site) {
+        segments.push("Partitioned".to_string());
+    }
+    if !domain.trim().is_empty() {
+        segments.push(format!("Domain={domain}"));
+    }
+    segments.join("; ")
+}
+
+fn build_clear_cookie(
+    cookie_name: &str,
+    secure: bool,
+    same_site: &str,
+    partitioned: bool,
+    domain: &str,
+) -> String {
+    let mut segments = vec![
+        format!("{cookie_name}="),
+        "Path=/".to_string(),
+        "HttpOnly".to_string(),
+        format!("SameSite={}", cookie_same_site_attr(same_site)),
+        "Max-Age=0".to_string(),
+        "Expires=Thu, 01 Jan 1970 00:00:00 GMT".to_string(),
+    ];
+    if secure {
+        segments.push("Secure".to_string());
+    }
+    if cookie_is_partitioned(partitioned, secure, same_site) {
+        segments.push("Partitioned".to_string());
+    }
+    if !domain.trim().is_empty() {
+        segments.push(format!("Domain={domain}"));
+    }
+    segments.join("; ")
+}
+
+/// A status saved through the admin endpoint wins; otherwise `ATLAS_COMPANY_STATUS_FILE`,
+/// then the compiled default. A missing or invalid file is logged and skipped rather than
+/// failing startup.
+fn initial_company_status(
+    persisted: Option<CompanyStatusRecord>,
+    file_path: Option<&str>,
+) -> CompanyStatusRecord {
+    if let Some(status) = persisted {
+        tracing::info!(source = "database", "company status loaded");
+        return status;
+    }
+    let Some(path) = file_path.map(str::trim).filter(|path| !path.is_empty()) else {
+        tracing::info!(source = "compiled_default", "company status loaded");
+        return default_company_status();
+    };
+    let loaded = std::fs::read_to_string(path)
+        .map_err(|err| err.to_string())
+        .and_then(|contents| parse_company_status_file(contents.as_str()));
+    match loaded {
+        Ok(status) => {
+            tracing::info!(source = "file", path, "company status loaded");
+            status
+        }
+        Err(reason) => {
+            tracing::warn!(
+                source = "compiled_default",
+                path,
+                %reason,
+                "ignoring ATLAS_COMPANY_STATUS_FILE; using the compiled default company status"
+            );
+            default_company_status()
+        }
+    }
+}
+
+fn parse_company_status_file(contents: &str) -> std::result::Result<CompanyStatusRecord, String> {
+    let status = serde_json::from_str::<CompanyStatusRecord>(contents)
+        .map_err(|err| format!("not a company status JSON object: {err}"))?;
+    validate_company_status(status)
+}
+
+fn default_company_status() -> CompanyStatusRecord {
+    CompanyStatusRecord {
+        phase: "Build now, launch in controlled stages".to_string(),
+        current_focus: vec![
+            "Mobile-first AI concierge and studio".to_string(),
+            "Deep personalization and proactive support".to_string(),
+            "Atlas/אטלס travel/work ecosystem MVP".to_string(),
+        ],
+        upcoming: vec![
+            "Expanded user account intelligence".to_string(),
+            "Vehicle integration APIs".to_string(),
+            "Pilot-ready operations and legal routing".to_string(),
+        ],
+        open_for_investment: true,
+        message: "Atlas/אטלס is open to strategic partnerships and investments while building a long-term mobility ecosystem.".to_string(),
+    }
+}
+
+fn resolve_user_id(
+    state: &ApiState,
+    headers: &HeaderMap,
+    explicit_user_id: Option<String>,
+) -> Option<String> {
+    let session_user = session_user_from_headers(state, headers)?;
+    if let Some(from_body) = explicit_user_id.as_ref() {
+        if from_body != &session_user.user_id {
+            return None;
+        }
+    }
+    Some(session_user.user_id)
+}
+
+fn resolve_user_id_or_guest(
+    state: &ApiState,
+    headers: &HeaderMap,
+    explicit_user_id: Option<String>,
+) -> String {
+    resolve_user_id(state, headers, explicit_user_id).unwrap_or_else(|| {
+        read_cookie_value(headers, GUEST_COOKIE_NAME)
+            .filter(|value| is_valid_guest_id(value))
+            .unwrap_or_else(new_guest_id)
+    })
+}
+
+fn new_guest_id() -> String {
+    format!("{GUEST_ID_PREFIX}{}", uuid::Uuid::new_v4())
+}
+
+fn is_valid_guest_id(value: &str) -> bool {
+    value
+        .strip_prefix(GUEST_ID_PREFIX)
+        .and_then(|suffix| uuid::Uuid::parse_str(suffix).ok())
+        .is_some()
+}
+
+fn is_guest_user_id(user_id: &str) -> bool {
+    user_id == "guest" || user_id.starts_with(GUEST_ID_PREFIX)
+}
+
+fn is_guest_endpoint(path: &str) -> bool {
+    matches!(
+        path,
+        "/v1/survey/next"
+            | "/v1/survey/answer"
+            | "/v1/feed/proactive"
+            | "/v1/execution/refresh"
+            | "/v1/execution/digest"
+            | "/v1/actions/reminder"
+            | "/v1/actions/reminder/snooze"
+            | "/v1/actions/alarm"
+            | "/v1/actions/plan"
+    )
+}
+
+fn replace_cookie_value(headers: &mut HeaderMap, cookie_name: &str, value: &str) {
+    let mut parts = headers
+        .get(header::COOKIE)
+        .and_then(|raw| raw.to_str().ok())
+        .unwrap_or_default()
+        .split(';')
+        .map(str::trim)
+        .filter(|part| {
+            !part.is_empty() && part.split('=').next().map(str::trim) != Some(cookie_name)
+        })
+        .map(str::to_string)
+        .collect::<Vec<_>>();
+    parts.push(format!("{cookie_name}={value}"));
+    if let Ok(header_value) = HeaderValue::from_str(parts.join("; ").as_str()) {
+        headers.insert(header::COOKIE, header_value);
+    }
+}
+
+fn touch_guest_session(state: &ApiState, guest_id: &str) {
+    state
+        .guest_sessions
+        .write()
+        .insert(guest_id.to_string(), chrono::Utc::now());
+}
+
+fn sweep_expired_guest_sessions(state: &ApiState) -> usize {
+    let ttl = chrono::Duration::from_std(state.guest_ttl)
+        .unwrap_or_else(|_| chrono::Duration::seconds(DEFAULT_GUEST_TTL_SECONDS as i64));
+    let cutoff = chrono::Utc::now() - ttl;
+    let expired = {
+        let mut sessions = state.guest_sessions.write();
+        let expired = sessions
+            .iter()
+            .filter(|(_, last_seen)| **last_seen <= cutoff)
+            .map(|(guest_id, _)| guest_id.clone())
+            .collect::<Vec<_>>();
+        for guest_id in &expired {
+            sessions.remove(guest_id);
+        }
+        expired
+    };
+    if expired.is_empty() {
+        return 0;
+    }
+
+    state
+        .survey_states
+        .write()
+        .retain(|user_id, _| !expired.contains(user_id));
+    state
+        .studio_preferences
+        .write()
+        .retain(|user_id, _| !expired.contains(user_id));
+    state
+        .execution_controls
+        .write()
+        .retain(|user_id, _| !expired.contains(user_id));
+    state
+        .execution_checkins
+        .write()
+        .retain(|user_id, _| !expired.contains(user_id));
+    state
+        .user_memories
+        .write()
+        .retain(|user_id, _| !expired.contains(user_id));
+    state
+        .deleted_memories
+        .write()
+        .retain(|user_id, _| !expired.contains(user_id));
+    state
+        .chat_turns
+        .write()
+        .retain(|user_id, _| !expired.contains(user_id));
+    expired.len()
+}
+
+fn resolve_request_locale(
+    state: &ApiState,
+    headers: &HeaderMap,
+    user_id: &str,
+    requested: Option<&str>,
+) -> String {
+    let requested = requested.unwrap_or_default().trim().to_lowercase();
+    if matches!(requested.as_str(), "he" | "en" | "ar" | "ru" | "fr") {
+        return requested;
+    }
+    state
+        .users
+        .read()
+        .get(user_id)
+        .map(|user| {
+            sanitize_enum_value(user.locale.as_str(), &["he", "en", "ar", "ru", "fr"], "en")
+        })
+        .or_else(|| locale_from_accept_language(headers))
+        .unwrap_or_else(|| "en".to_string())
+}
+
+// A locale from `resolve_request_locale` can come from `Accept-Language`, so shared caches must
+// key on that header too.
+fn localized_response_headers(locale: String) -> [(header::HeaderName, String); 2] {
+    [
+        (header::CONTENT_LANGUAGE, locale),
+        (header::VARY, header::ACCEPT_LANGUAGE.as_str().to_string()),
+    ]
+}
+
+// Picks the highest-q supported language; ties keep header order. `q=0` means "not acceptable",
+// and the legacy `iw` tag still shows up from older Android builds for Hebrew.
+fn locale_from_accept_language(headers: &HeaderMap) -> Option<String> {
+    let header_value = headers
+        .get(header::ACCEPT_LANGUAGE)
+        .and_then(|value| value.to_str().ok())?;
+    let mut candidates = header_value
+        .split(',')
+        .filter_map(|entry| {
+            let mut parts = entry.split(';');
+            let tag = parts.next()?.trim().to_lowercase();
+            let quality = parts
+                .filter_map(|param| param.trim().strip_prefix("q="))
+                .find_map(|value| value.trim().parse::<f32>().ok())
+                .unwrap_or(1.0);
+            let primary = match tag.split('-').next()? {
+                "iw" => "he",
+                other => other,
+            };
+            let locale = ["he", "en", "ar", "ru", "fr"]
+                .into_iter()
+                .find(|supported| *supported == primary)?;
+            (quality > 0.0).then_some((locale, quality))
+        })
+        .collect::<Vec<_>>();
+    candidates.sort_by(|lhs, rhs| rhs.1.total_cmp(&lhs.1));
+    candidates.first().map(|(locale, _)| locale.to_string())
+}
+
+fn survey_elapsed_minutes(state: &SurveyStateRecord) -> Option<u32> {
+    let start = state
+        .started_at
+        .as_deref()
+        .and_then(|value| chrono::DateTime::parse_from_rfc3339(value).ok())?;
+    let end = state
+        .completed_at
+        .as_deref()
+        .and_then(|value| chrono::DateTime::parse_from_rfc3339(value).ok())
+        .unwrap_or_else(|| chrono::Utc::now().into());
+    let duration = end.signed_duration_since(start);
+    if duration.num_minutes() < 0 {
+        Some(0)
+    } else {
+        Some(duration.num_minutes() as u32)
+    }
+}
+
+// Single source for the allowed values of each studio preference: upserts sanitize against it
+// and `/v1/studio/preferences/defaults` publishes it so clients never hardcode the enums.
+static STUDIO_PREFERENCE_OPTIONS: &[StudioPreferenceOption] = &[
+    StudioPreferenceOption {
+        field: "preferred_format",
+        allowed: &[
+            "structured_plan",
+            "checklist",
+            "step_by_step",
+            "concise",
+            "timeline",
+            "json",
+            "notebook_style",
+            "raw",
+        ],
+        default_value: "structured_plan",
+    },
+    StudioPreferenceOption {
+        field: "response_depth",
+        allowed: &["quick", "balanced", "deep"],
+        default_value: "deep",
+    },
+    StudioPreferenceOption {
+        field: "response_tone",
+        allowed: &["coach", "direct", "calm", "strategic", "executive"],
+        default_value: "executive",
+    },
+    StudioPreferenceOption {
+        field: "proactive_mode",
+        allowed: &["enabled", "focus_only", "disabled"],
+        default_value: "enabled",
+    },
+    StudioPreferenceOption {
+        field: "reminders_app",
+        allowed: &[
+            "google_calendar",
+            "apple_reminders",
+            "shortcuts",
+            "todoist",
+            "notion",
+        ],
+        default_value: "google_calendar",
+    },
+    StudioPreferenceOption {
+        field: "alarms_app",
+        allowed: &["apple_clock", "google_clock", "shortcuts"],
+        default_value: "apple_clock",
+    },
+    StudioPreferenceOption {
+        field: "voice_mode",
+        allowed: &["enabled", "disabled"],
+        default_value: "enabled",
+    },
+];
+
+fn default_studio_preferences(user_id: &str) -> StudioPreferencesRecord {
+    StudioPreferencesRecord {
+        user_id: user_id.to_string(),
+        preferred_format: "structured_plan".to_string(),
+        response_depth: "deep".to_string(),
+        response_tone: "executive".to_string(),
+        proactive_mode: "enabled".to_string(),
+        reminders_app: "google_calendar".to_string(),
+        alarms_app: "apple_clock".to_string(),
+        voice_mode: "enabled".to_string(),
+        utc_offset_minutes: 0,
+        working_hours_start: default_working_hours_start(),
+        working_hours_end: default_working_hours_end(),
+        working_days: default_working_days(),
+        updated_at: chrono::Utc::now().to_rfc3339(),
+    }
+}
+
+fn default_working_hours_start() -> String {
+    DEFAULT_WORKING_HOURS_START.to_string()
+}
+
+fn default_working_hours_end() -> String {
+    DEFAULT_WORKING_HOURS_END.to_string()
+}
+
+fn default_working_days() -> Vec<String> {
+    sanitize_alarm_days(None)
+}
+
+fn merge_studio_preferences(
+    mut base: StudioPreferencesRecord,
+    incoming: StudioPreferencesUpsertRequest,
+    coerced: &mut Vec<CoercedField>,
+) -> StudioPreferencesRecord {
+    let fields = [
+        (
+            &mut base.preferred_format,
+            incoming.preferred_format,
+            "preferred_format",
+        ),
+        (
+            &mut base.response_depth,
+            incoming.response_depth,
+            "response_depth",
+        ),
+        (
+            &mut base.response_tone,
+            incoming.response_tone,
+            "response_tone",
+        ),
+        (
+            &mut base.proactive_mode,
+            incoming.proactive_mode,
+            "proactive_mode",
+        ),
+        (
+            &mut base.reminders_app,
+            incoming.reminders_app,
+            "reminders_app",
+        ),
+        (&mut base.alarms_app, incoming.alarms_app, "alarms_app"),
+        (&mut base.voice_mode, incoming.voice_mode, "voice_mode"),
+    ];
+    for (target, value, field) in fields {
+        if let (Some(value), Some(option)) = (value, studio_preference_option(field)) {
+            *target = sanitize_enum_field(
+                option.field,
+                value.as_str(),
+                option.allowed,
+                option.default_value,
+                coerced,
+            );
+        }
+    }
+    if let Some(offset) = incoming.utc_offset_minutes {
+        base.utc_offset_minutes = clamp_utc_offset_minutes(offset);
+        if base.utc_offset_minutes != offset {
+            coerced.push(CoercedField {
+                field: "utc_offset_minutes",
+                provided: offset.to_string(),
+                applied: base.utc_offset_minutes.to_string(),
+            });
+        }
+    }
+    for (target, value, field) in [
+        (
+            &mut base.working_hours_start,
+            incoming.working_hours_start,
+            "working_hours_start",
+        ),
+        (
+            &mut base.working_hours_end,
+            incoming.working_hours_end,
+            "working_hours_end",
+        ),
+    ] {
+        let Some(value) = value else {
+            continue;
+        };
+        match parse_hhmm(value.as_str()) {
+            Some(time) => *target = time.format("%H:%M").to_string(),
+            None => coerced.push(CoercedField {
+                field,
+                provided: value,
+                applied: target.clone(),
+            }),
+        }
+    }
+    if parse_hhmm(base.working_hours_start.as_str()) >= parse_hhmm(base.working_hours_end.as_str())
+    {
+        coerced.push(CoercedField {
+            field: "working_hours_end",
+            provided: base.working_hours_end.clone(),
+            applied: DEFAULT_WORKING_HOURS_END.to_string(),
+        });
+        base.working_hours_start = default_working_hours_start();
+        base.working_hours_end = default_working_hours_end();
+    }
+    if let Some(days) = incoming.working_days {
+        base.working_days = sanitize_alarm_days(Some(days));
+    }
+    base.updated_at = chrono::Utc::now().to_rfc3339();
+    base
+}
+
+fn parse_hhmm(value: &str) -> Option<chrono::NaiveTime> {
+    let value = value.trim();
+    if !is_valid_hhmm(value) {
+        return None;
+    }
+    chrono::NaiveTime::parse_from_str(value, "%H:%M").ok()
+}
+
+/// Moves a proposed reminder time forward to the next moment inside the user's working hours
+/// (local to `utc_offset_minutes`). Times already inside a working window are kept as-is.
+fn snap_to_working_hours(
+    proposed: chrono::DateTime<chrono::Utc>,
+    prefs: &StudioPreferencesRecord,
+) -> chrono::DateTime<chrono::Utc> {
+    let Some(offset) = chrono::FixedOffset::east_opt(prefs.utc_offset_minutes * 60) else {
+        return proposed;
+    };
+    let (Some(start), Some(end)) = (
+        parse_hhmm(prefs.working_hours_start.as_str()),
+        parse_hhmm(prefs.working_hours_end.as_str()),
+    ) else {
+        return proposed;
+    };
+    let local = proposed.with_timezone(&offset);
+    for day in 0..8 {
+        let date = local.date_naive() + chrono::Duration::days(day);
+        let weekday = date.format("%a").to_string();
+        if !prefs.working_days.contains(&weekday) {
+            continue;
+        }
+        if day == 0 && local.time() >= end {
+            continue;
+        }
+        if day == 0 && local.time() >= start {
+            return proposed;
+        }
+        if let Some(window_start) = date.and_time(start).and_local_timezone(offset).single() {
+            return window_start.with_timezone(&chrono::Utc);
+        }
+    }
+    proposed
+}
+
+fn clamp_utc_offset_minutes(offset: i32) -> i32 {
+    offset.clamp(MIN_UTC_OFFSET_MINUTES, MAX_UTC_OFFSET_MINUTES)
+}
+
+fn studio_preference_option(field: &str) -> Option<&'static StudioPreferenceOption> {
+    STUDIO_PREFERENCE_OPTIONS
+        .iter()
+        .find(|option| option.field == field)
+}
+
+// Awaited in place, so a missed deadline drops the call at its next await point and nothing
+// keeps running after the 504 has been sent.
+async fn run_chat_agent(state: &ApiState, input: ChatInput) -> Result<ConciergeReply> {
+    state.chat_agent.handle_chat(input).await
+}
+
+// The local agent has no internal deadline, so a pathological path must not hold the
+// connection open; callers get a JSON 504 instead of a hung request.
+async fn chat_with_deadline<T>(
+    deadline: Duration,
+    chat: impl std::future::Future<Output = T>,
+) -> std::result::Result<T, Response> {
+    tokio::time::timeout(deadline, chat).await.map_err(|_| {
+        tracing::warn!(
+            timeout_ms = deadline.as_millis() as u64,
+            "chat agent exceeded deadline"
+        );
+        ApiError::new(
+            StatusCode::GATEWAY_TIMEOUT,
+            "chat_timeout",
+            "the assistant took too long to respond; please retry",
+        )
+        .into_response()
+    })
+}
+
+fn request_overrides_to_studio(request: &ChatRequest) -> StudioPreferencesUpsertRequest {
+    StudioPreferencesUpsertRequest {
+        user_id: request.user_id.clone(),
+        preferred_format: request.preferred_format.clone(),
+        response_depth: request.response_depth.clone(),
+        response_tone: request.response_tone.clone(),
+        proactive_mode: None,
+        reminders_app: None,
+        alarms_app: None,
+        voice_mode: None,
+        utc_offset_minutes: None,
+        working_hours_start: None,
+        working_hours_end: None,
+        working_days: None,
+    }
+}
+
+fn apply_studio_format(
+    base_reply: String,
+    prefs: &StudioPreferencesRecord,
+    locale: atlas_core::Locale,
+    user: &UserRecord,
+) -> StudioReply {
+    let profile_line = if locale == atlas_core::Locale::He {
+        format!(
+            "פרופיל פעיל: {} | סגנון: {} | סיכון: {}",
+            user.name,
+            user.trip_style
+                .clone()
+                .unwrap_or_else(|| "mixed".to_string()),
+            user.risk_preference
+                .clone()
+                .unwrap_or_else(|| "medium".to_string())
+        )
+    } else {
+        format!(
+            "Active profile: {} | style: {} | risk: {}",
+            user.name,
+            user.trip_style
+                .clone()
+                .unwrap_or_else(|| "mixed".to_string()),
+            user.risk_preference
+                .clone()
+                .unwrap_or_else(|| "medium".to_string())
+        )
+    };
+
+    format_by_mode(base_reply, prefs, locale, profile_line)
+}
+
+fn apply_studio_format_guest(
+    base_reply: String,
+    prefs: &StudioPreferencesRecord,
+    locale: atlas_core::Locale,
+) -> StudioReply {
+    let profile_line = if locale == atlas_core::Locale::He {
+        "מצב אורח: אפשר להתחבר כדי לשמור זיכרון ארוך-טווח.".to_string()
+    } else {
+        "Guest mode: sign in to unlock long-term personalization.".to_string()
+    };
+    format_by_mode(base_reply, prefs, locale, profile_line)
+}
+
+fn format_by_mode(
+    base_reply: String,
+    prefs: &StudioPreferencesRecord,
+    locale: atlas_core::Locale,
+    profile_line: String,
+) -> StudioReply {
+    // `raw` is the minimal mode for integrations: the agent reply exactly as produced, with no
+    // tone prefix, profile line or format scaffolding.
+    if prefs.preferred_format == "raw" {
+        return StudioReply {
+            text: base_reply,
+            formatted_response: None,
+        };
+    }
+
+    // The json format is returned as a real object in `json_payload.formatted_response`;
+    // embedding it in `reply_text` forced clients to decode JSON from inside a JSON string.
+    if prefs.preferred_format == "json" {
+        return StudioReply {
+            text: JSON_FORMAT_REPLY_MARKER.to_string(),
+            formatted_response: Some(serde_json::json!({
+                "mode": "json",
+                "tone": prefs.response_tone,
+                "depth": prefs.response_depth,
+                "profile": profile_line,
+                "response": base_reply
+            })),
+        };
+    }
+
+    let rendered = match prefs.preferred_format.as_str() {
+        "concise" => {
+            if locale == atlas_core::Locale::He {
+                format!(
+                    "{}\n\nתכל'ס עכשיו: בצעו צעד אחד ב-15 הדקות הקרובות.",
+                    base_reply
+                )
+            } else {
+                format!(
+                    "{}\n\nDo this now: execute one action in the next 15 minutes.",
+                    base_reply
+                )
+            }
+        }
+        "checklist" => {
+            if locale == atlas_core::Locale::He {
+                format!(
+                    "{}\n\nצ'ק-ליסט ביצוע:\n1) הגדירו יעד קצר.\n2) קבעו זמן ביצוע.\n3) הגדירו תזכורת.\n4) שלחו פידבק אחרי ביצוע.\n\n{}",
+                    base_reply, profile_line
+                )
+            } else {
+                format!(
+                    "{}\n\nExecution checklist:\n1) Set one short goal.\n2) Set execution time.\n3) Create a reminder.\n4) Send feedback after completion.\n\n{}",
+                    base_reply, profile_line
+                )
+            }
+        }
+        "step_by_step" => {
+            if locale == atlas_core::Locale::He {
+                format!(
+                    "{}\n\nשלבים:\nשלב 1: בהירות - מה המטרה היום.\nשלב 2: תנועה - מה הפעולה הראשונה.\nשלב 3: רצף - מה הפעולה הבאה אחרי זה.\n\n{}",
+                    base_reply, profile_line
+                )
+            } else {
+                format!(
+                    "{}\n\nSteps:\nStep 1: Clarity - define today's target.\nStep 2: Motion - execute first action.\nStep 3: Continuity - define next action.\n\n{}",
+                    base_reply, profile_line
+                )
+            }
+        }
+        "timeline" => {
+            if locale == atlas_core::Locale::He {
+                format!(
+                    "{}\n\nציר זמן מומלץ:\n08:30-10:00 פוקוס עמוק\n10:00-10:15 הפסקת איפוס\n10:15-12:00 ביצוע והתקדמות\n\n{}",
+                    base_reply, profile_line
+                )
+            } else {
+                format!(
+                    "{}\n\nSuggested timeline:\n08:30-10:00 deep focus\n10:00-10:15 reset break\n10:15-12:00 execution and follow-through\n\n{}",
+                    base_reply, profile_line
+                )
+            }
+        }
+        "notebook_style" => {
+            if locale == atlas_core::Locale::He {
+                format!(
+                    "סטודיו אטלס: תשובה בפורמט מחברת עבודה\n\nתמצית:\n{}\n\nפעולות מומלצות:\n- הפעלת תזכורת\n- קביעת אזעקת פוקוס\n- בדיקת פיד יזום\n\n{}",
+                    base_reply, profile_line
+                )
+            } else {
+                format!(
+                    "Atlas Studio response (notebook style)\n\nSummary:\n{}\n\nSuggested actions:\n- trigger reminder\n- set focus alarm\n- review proactive feed\n\n{}",
+                    base_reply, profile_line
+                )
+            }
+        }
+        _ => format!("{}\n\n{}", base_reply, profile_line),
+    };
+
+    // Tone is a text decoration only; `json` carries it as the `tone` field and `raw` has none,
+    // both returned above so the prefix can never end up inside structured output.
+    let text = if prefs.response_tone == "executive" {
+        if locale == atlas_core::Locale::He {
+            format!("סטנדרט הנהלה: מסר מדויק, מכובד ותכליתי.\n\n{}", rendered)
+        } else {
+            format!(
+                "Executive standard: precise, high-caliber, and mission-aligned guidance.\n\n{}",
+                rendered
+            )
+        }
+    } else {
+        rendered
+    };
+    StudioReply {
+        text,
+        formatted_response: None,
+    }
+}
+
+// TTS engines read markdown literally, so list markers, headings and emphasis are dropped and
+// the text is cut at a sentence boundary that fits a short spoken turn.
+fn build_spoken_summary(reply: &str) -> String {
+    let plain = reply
+        .lines()
+        .map(|line| {
+            let line = line
+                .trim()
+                .trim_start_matches(['#', '-', '*', '>', '•'])
+                .trim_start();
+            let digits = line.len()
+                - line
+                    .trim_start_matches(|ch: char| ch.is_ascii_digit())
+                    .len();
+            let line = match line[digits..].strip_prefix([')', '.']) {
+                Some(rest) if digits > 0 => rest,
+                _ => line,
+            };
+            line.replace("**", "")
+                .replace("__", "")
+                .replace('`', "")
+                .trim()
+                .to_string()
+        })
+        .filter(|line| !line.is_empty())
+        .collect::<Vec<_>>()
+        .join(" ");
+    let plain = plain.split_whitespace().collect::<Vec<_>>().join(" ");
+
+    let mut summary = String::new();
+    for sentence in plain.split_inclusive(['.', '!', '?']) {
+        if summary.chars().count() + sentence.chars().count() > MAX_SPOKEN_SUMMARY_CHARS {
+            break;
+        }
+        summary.push_str(sentence);
+    }
+    if summary.trim().is_empty() {
+        return truncate_on_word_boundary(plain.as_str(), MAX_SPOKEN_SUMMARY_CHARS)
+            .unwrap_or(plain);
+    }
+    summary.trim().to_string()
+}
+
+// Returns `None` when the text already fits. Otherwise cuts at the last whitespace within
+// `max_chars` (or hard-cuts a single long word) and appends an ellipsis.
+fn truncate_on_word_boundary(text: &str, max_chars: usize) -> Option<String> {
+    if text.chars().count() <= max_chars {
+        return None;
+    }
+    let mut truncated = text.chars().take(max_chars).collect::<String>();
+    if let Some(cut) = truncated.rfind(char::is_whitespace).filter(|cut| *cut > 0) {
+        truncated.truncate(cut);
+    }
+    truncated.truncate(truncated.trim_end().len());
+    truncated.push('…');
+    Some(truncated)
+}
+
+fn build_proactive_feed_response(
+    state: &ApiState,
+    user_id: &str,
+    request_locale: &str,
+) -> ProactiveFeedResponse {
+    const MIN_SURVEY_MINUTES: u32 = 20;
+
+    let inputs = load_execution_feed_inputs(state, user_id, request_locale);
+    let elapsed_minutes = inputs
+        .survey
+        .as_ref()
+        .and_then(survey_elapsed_minutes)
+        .unwrap_or(0);
+    let survey_complete = inputs
+        .survey
+        .as_ref()
+        .map(|value| value.completed)
+        .unwrap_or(false);
+    let feed_ready = survey_complete && elapsed_minutes >= MIN_SURVEY_MINUTES;
+
+    let gate_reason = if feed_ready {
+        None
+    } else if request_locale.starts_with("he") {
+        Some(format!(
+            "זרם הביצוע ייפתח אחרי השלמת סקר העומק ולאחר לפחות {} דקות תהליך.",
+            MIN_SURVEY_MINUTES
+        ))
+    } else {
+        Some(format!(
+            "Execution Stream unlocks after completing the adaptive deep survey and at least {} minutes of survey process.",
+            MIN_SURVEY_MINUTES
+        ))
+    };
+    let items = if feed_ready {
+        build_orchestrated_proactive_feed(&inputs.context(state.feed_max_items))
+    } else {
+        Vec::new()
+    };
+
+    ProactiveFeedResponse {
+        generated_at: chrono::Utc::now().to_rfc3339(),
+        items,
+        feed_ready,
+        gate_reason,
+        required_minutes: MIN_SURVEY_MINUTES,
+        company_status: inputs.company_status,
+    }
+}
+
+fn load_execution_feed_inputs(
+    state: &ApiState,
+    user_id: &str,
+    request_locale: &str,
+) -> ExecutionFeedInputs {
+    let mut user = state
+        .users
+        .read()
+        .get(user_id)
+        .cloned()
+        .unwrap_or_else(|| UserRecord {
+            user_id: user_id.to_string(),
+            provider: "guest".to_string(),
+            email: "guest@atlasmasa.local".to_string(),
+            name: "Guest".to_string(),
+            locale: request_locale.to_string(),
+            trip_style: Some("mixed".to_string()),
+            risk_preference: Some("medium".to_string()),
+            memory_opt_in: true,
+            passkey_user_handle: None,
+            created_at: chrono::Utc::now().to_rfc3339(),
+            updated_at: chrono::Utc::now().to_rfc3339(),
+            last_login_at: None,
+            login_count: 0,
+        });
+    user.locale = request_locale.to_string();
+
+    let prefs = state
+        .studio_preferences
+        .read()
+        .get(user_id)
+        .cloned()
+        .unwrap_or_else(|| default_studio_preferences(user_id));
+    let survey = state.survey_states.read().get(user_id).cloned();
+    let notes = state
+        .user_notes
+        .read()
+        .get(user_id)
+        .cloned()
+        .unwrap_or_default();
+    let controls = get_execution_controls(state, user_id);
+    let latest_checkin = latest_execution_checkin(state, user_id);
+    let latest_chat_text = state.chat_turns.read().get(user_id).and_then(|turns| {
+        turns
+            .iter()
+            .max_by(|lhs, rhs| lhs.created_at.cmp(&rhs.created_at))
+            .map(|turn| turn.user_text.clone())
+    });
+    let memory_query = proactive_feed_memory_query(
+        &state.feed_memory_query_signals,
+        latest_checkin.as_ref(),
+        latest_chat_text.as_deref(),
+    );
+    let memories = retrieve_user_memory_context(state, user_id, memory_query.as_str(), 20, None);
+
+    ExecutionFeedInputs {
+        company_status: state.company_status.read().clone(),
+        user,
+        prefs,
+        survey,
+        notes,
+        controls,
+        memories,
+        latest_checkin,
+    }
+}
+
+fn default_execution_controls(user_id: &str) -> ExecutionControlsRecord {
+    ExecutionControlsRecord {
+        user_id: user_id.to_string(),
+        cadence: "steady".to_string(),
+        detail_level: "standard".to_string(),
+        include_company_awareness: true,
+        include_reminder_suggestions: true,
+        updated_at: chrono::Utc::now().to_rfc3339(),
+    }
+}
+
+fn get_execution_controls(state: &ApiState, user_id: &str) -> ExecutionControlsRecord {
+    state
+        .execution_controls
+        .read()
+        .get(user_id)
+        .cloned()
+        .unwrap_or_else(|| default_execution_controls(user_id))
+}
+
+// Unset means every signal; `none` (or nothing valid) keeps the old weight/recency-only ranking.
+fn parse_feed_memory_query_signals(value: Option<&str>) -> Vec<String> {
+    let Some(value) = value else {
+        return FEED_MEMORY_QUERY_SIGNALS
+            .iter()
+            .map(|signal| signal.to_string())
+            .collect();
+    };
+    let mut signals = Vec::new();
+    for signal in value
+        .split(',')
+        .map(|item| item.trim().to_ascii_lowercase())
+    {
+        if FEED_MEMORY_QUERY_SIGNALS.contains(&signal.as_str()) && !signals.contains(&signal) {
+            signals.push(signal);
+        }
+    }
+    signals
+}
+
+/// Builds the relevance query for feed memories from the user's current focus (today's focus and
+/// next action from the latest check-in) and their latest chat message. An empty result falls
+/// back to ranking by weight and recency alone.
+fn proactive_feed_memory_query(
+    signals: &[String],
+    latest_checkin: Option<&ExecutionCheckinRecord>,
+    latest_chat_text: Option<&str>,
+) -> String {
+    let mut parts = Vec::new();
+    if signals.iter().any(|signal| signal == "focus") {
+        if let Some(checkin) = latest_checkin {
+            parts.push(checkin.daily_focus.as_str());
+            if let Some(next_action) = checkin.next_action_now.as_deref() {
+                parts.push(next_action);
+            }
+        }
+    }
+    if signals.iter().any(|signal| signal == "chat") {
+        if let Some(text) = latest_chat_text {
+            parts.push(text);
+        }
+    }
+    let query = parts
+        .into_iter()
+        .map(str::trim)
+        .filter(|part| !part.is_empty())
+        .collect::<Vec<_>>()
+        .join(" ");
+    truncate_on_word_boundary(query.as_str(), MAX_FEED_MEMORY_QUERY_CHARS).unwrap_or(query)
+}
+
+fn latest_execution_checkin(state: &ApiState, user_id: &str) -> Option<ExecutionCheckinRecord> {
+    state
+        .execution_checkins
+        .read()
+        .get(user_id)
+        .and_then(|entries| entries.first().cloned())
+}
+
+fn schedule_minutes_offset(cadence: &str, horizon: &str, index: usize) -> i64 {
+    let cadence_base = match cadence {
+        "aggressive" => 8_i64,
+        _ => 18_i64,
+    };
+    let horizon_boost = match horizon {
+        "daily" => 0_i64,
+        "mid_term" => 50_i64,
+        "long_term" => 180_i64,
+        _ => 25_i64,
+    };
+    cadence_base + horizon_boost + (index as i64 * 12)
+}
+
+fn push_task_if_valid(tasks: &mut Vec<ExecutionTaskCandidate>, task: ExecutionTaskCandidate) {
+    let title = task.title.trim();
+    let detail = task.detail.trim();
+    if title.is_empty() || detail.is_empty() {
+        return;
+    }
+    tasks.push(task);
+}
+
+fn extract_note_tasks(notes: Option<&[UserNoteRecord]>) -> Vec<ExecutionTaskCandidate> {
+    let mut tasks = Vec::new();
+    let Some(notes) = notes else {
+        return tasks;
+    };
+    for note in notes.iter().take(8) {
+        let summary = sanitize_limited_text(note.content.as_str(), 200);
+        let horizon =
+            classify_horizon_from_text(format!("{} {}", note.title, note.content).as_str());
+        push_task_if_valid(
+            &mut tasks,
+            ExecutionTaskCandidate {
+                task_id: format!("note-{}", note.note_id),
+                title: note.title.clone(),
+                detail: summary,
+                source: "notes".to_string(),
+                horizon,
+                urgency: 0.72,
+                impact: 0.82,
+                confidence: 0.78,
+            },
+        );
+    }
+    tasks
+}
+
+fn extract_survey_tasks(
+    survey: Option<&SurveyStateRecord>,
+    locale: &str,
+) -> Vec<ExecutionTaskCandidate> {
+    let mut tasks = Vec::new();
+    let Some(survey_state) = survey else {
+        return tasks;
+    };
+
+    if let Some(goal) = survey_state.answers.get("primary_goal") {
+        let detail = if locale == "he" {
+            format!("יעד אסטרטגי ראשי מהסקר: {}", goal)
+        } else {
+            format!("Primary strategic goal from survey: {}", goal)
+        };
+        push_task_if_valid(
+            &mut tasks,
+            ExecutionTaskCandidate {
+                task_id: "survey-primary-goal".to_string(),
+                title: if locale == "he" {
+                    "עיגון יעד אסטרטגי".to_string()
+                } else {
+                    "Anchor strategic objective".to_string()
+                },
+                detail,
+                source: "survey".to_string(),
+                horizon: "long_term".to_string(),
+                urgency: 0.6,
+                impact: 0.95,
+                confidence: 0.86,
+            },
+        );
+    }
+    if let Some(pressure) = survey_state.answers.get("daily_pressure") {
+        push_task_if_valid(
+            &mut tasks,
+            ExecutionTaskCandidate {
+                task_id: "survey-pressure".to_string(),
+                title: if locale == "he" {
+                    "ייצוב עומס יומי".to_string()
+                } else {
+                    "Stabilize daily pressure".to_string()
+                },
+                detail: if locale == "he" {
+                    format!(
+                        "המערכת זיהתה לחץ יומי ברמה {}. בצע חסימה יזומה ביומן.",
+                        pressure
+                    )
+                } else {
+                    format!(
+                        "Survey indicates daily pressure at {}. Block focus time in calendar.",
+                        pressure
+                    )
+                },
+                source: "survey".to_string(),
+                horizon: "daily".to_string(),
+                urgency: if pressure == "high" { 0.95 } else { 0.72 },
+                impact: 0.78,
+                confidence: 0.9,
+            },
+        );
+    }
+    if let Some(charity) = survey_state.answers.get("charity_commitment") {
+        push_task_if_valid(
+            &mut tasks,
+            ExecutionTaskCandidate {
+                task_id: "survey-charity".to_string(),
+                title: if locale == "he" {
+                    "תכנון תרומה ושפע".to_string()
+                } else {
+                    "Plan giving and abundance".to_string()
+                },
+                detail: if locale == "he" {
+                    format!("מחויבות תרומה שנבחרה: {}. קבע כלל ביצוע קבוע.", charity)
+                } else {
+                    format!(
+                        "Selected giving commitment: {}. Define a fixed execution rule.",
+                        charity
+                    )
+                },
+                source: "survey".to_string(),
+                horizon: "long_term".to_string(),
+                urgency: 0.48,
+                impact: 0.8,
+                confidence: 0.82,
+            },
+        );
+    }
+    tasks
+}
+
+fn extract_memory_tasks(
+    memories: &[MemoryRetrievedItem],
+    locale: &str,
+) -> Vec<ExecutionTaskCandidate> {
+    let mut tasks = Vec::new();
+    for memory in memories.iter().take(12) {
+        if !matches!(
+            memory.source.as_str(),
+            "chat" | "survey" | "feedback" | "note" | "note_rewrite" | "manual"
+        ) {
+            continue;
+        }
+        if memory.text.trim().is_empty() {
+            continue;
+        }
+        let horizon = if memory.memory_type == "goal" {
+            "long_term".to_string()
+        } else if memory.memory_type == "friction" || memory.memory_type == "mood" {
+            "daily".to_string()
+        } else {
+            classify_horizon_from_text(memory.text.as_str())
+        };
+        push_task_if_valid(
+            &mut tasks,
+            ExecutionTaskCandidate {
+                task_id: format!("memory-{}", memory.memory_id),
+                title: if locale == "he" {
+                    "משימה מנגזרת מזיכרון".to_string()
+                } else {
+                    "Action from long-term memory".to_string()
+                },
+                detail: sanitize_limited_text(memory.text.as_str(), 180),
+                source: memory.source.clone(),
+                horizon,
+                urgency: (memory.final_score * 0.9).clamp(0.4, 0.98),
+                impact: (memory.weight * 0.9).clamp(0.35, 0.95),
+                confidence: (memory.relevance_score * 0.6 + 0.35).clamp(0.35, 0.95),
+            },
+        );
+    }
+    tasks
+}
+
+fn extract_checkin_tasks(
+    checkin: Option<&ExecutionCheckinRecord>,
+    locale: &str,
+) -> Vec<ExecutionTaskCandidate> {
+    let mut tasks = Vec::new();
+    let Some(checkin) = checkin else {
+        return tasks;
+    };
+    push_task_if_valid(
+        &mut tasks,
+        ExecutionTaskCandidate {
+            task_id: format!("checkin-daily-{}", checkin.checkin_id),
+            title: if locale == "he" {
+                "פוקוס יומי מהצ׳ק-אין".to_string()
+            } else {
+                "Daily focus from check-in".to_string()
+            },
+            detail: checkin.daily_focus.clone(),
+            source: "checkin".to_string(),
+            horizon: "daily".to_string(),
+            urgency: 0.96,
+            impact: 0.82,
+            confidence: 0.95,
+        },
+    );
+    if let Some(mid) = checkin.mid_term_focus.as_ref() {
+        push_task_if_valid(
+            &mut tasks,
+            ExecutionTaskCandidate {
+                task_id: format!("checkin-mid-{}", checkin.checkin_id),
+                title: if locale == "he" {
+                    "יעד ביניים מהצ׳ק-אין".to_string()
+                } else {
+                    "Mid-term focus from check-in".to_string()
+                },
+                detail: mid.clone(),
+                source: "checkin".to_string(),
+                horizon: "mid_term".to_string(),
+                urgency: 0.68,
+                impact: 0.86,
+                confidence: 0.9,
+            },
+        );
+    }
+    if let Some(long) = checkin.long_term_focus.as_ref() {
+        push_task_if_valid(
+            &mut tasks,
+            ExecutionTaskCandidate {
+                task_id: format!("checkin-long-{}", checkin.checkin_id),
+                title: if locale == "he" {
+                    "כיוון ארוך-טווח מהצ׳ק-אין".to_string()
+                } else {
+                    "Long-horizon direction from check-in".to_string()
+                },
+                detail: long.clone(),
+                source: "checkin".to_string(),
+                horizon: "long_term".to_string(),
+                urgency: 0.55,
+                impact: 0.92,
+                confidence: 0.88,
+            },
+        );
+    }
+    if let Some(gym_today) = checkin.gym_today {
+        push_task_if_valid(
+            &mut tasks,
+            ExecutionTaskCandidate {
+                task_id: format!("checkin-gym-{}", checkin.checkin_id),
+                title: if locale == "he" {
+                    if gym_today {
+                        "עיגון משמעת בריאותית".to_string()
+                    } else {
+                        "להחזיר מומנטום בריאותי היום".to_string()
+                    }
+                } else if gym_today {
+                    "Lock health discipline momentum".to_string()
+                } else {
+                    "Recover health momentum today".to_string()
+                },
+                detail: if locale == "he" {
+                    if gym_today {
+                        "בוצע אימון היום. עגנו שעת אימון קבועה גם למחר כדי לשמור רצף.".to_string()
+                    } else {
+                        "לא בוצע אימון היום. קבעו בלוק אימון קצר ומדויק לפני סוף היום.".to_string()
+                    }
+                } else if gym_today {
+                    "Gym completed today. Pre-commit tomorrow’s session to preserve streak."
+                        .to_string()
+                } else {
+                    "Gym was missed today. Schedule one precise training block before day-end."
+                        .to_string()
+                },
+                source: "checkin".to_string(),
+                horizon: "daily".to_string(),
+                urgency: if gym_today { 0.58 } else { 0.86 },
+                impact: 0.74,
+                confidence: 0.87,
+            },
+        );
+    }
+    if let Some(money_today) = checkin.money_today {
+        push_task_if_valid(
+            &mut tasks,
+            ExecutionTaskCandidate {
+                task_id: format!("checkin-money-{}", checkin.checkin_id),
+                title: if locale == "he" {
+                    if money_today {
+                        "לנעול התקדמות הכנסה".to_string()
+                    } else {
+                        "יצירת מהלך הכנסה מיידי".to_string()
+                    }
+                } else if money_today {
+                    "Lock income progress".to_string()
+                } else {
+                    "Create an immediate income move".to_string()
+                },
+                detail: if locale == "he" {
+                    if money_today {
+                        "נרשמה התקדמות כספית היום. תעדו מה עבד ושכפלו אותו ל-48 השעות הקרובות."
+                            .to_string()
+                    } else {
+                        "עדיין ללא הכנסה היום. בצעו מהלך אחד: יצירת קשר, הצעה, או סגירה."
+                            .to_string()
+                    }
+                } else if money_today {
+                    "Revenue moved today. Capture what worked and replicate it over the next 48 hours."
+                        .to_string()
+                } else {
+                    "No money signal today yet. Execute one move now: outreach, offer, or close."
+                        .to_string()
+                },
+                source: "checkin".to_string(),
+                horizon: "daily".to_string(),
+                urgency: if money_today { 0.64 } else { 0.92 },
+                impact: 0.84,
+                confidence: 0.89,
+            },
+        );
+    }
+    tasks
+}
+
+fn build_company_awareness_task(
+    company_status: &CompanyStatusRecord,
+    locale: &str,
+) -> ExecutionTaskCandidate {
+    let detail = if locale == "he" {
+        format!(
+            "פאזה: {} | פוקוס: {} | בהמשך: {}",
+            company_status.phase,
+            company_status.current_focus.join(", "),
+            company_status.upcoming.join(", ")
+        )
+    } else {
+        format!(
+            "Phase: {} | Current focus: {} | Upcoming: {}",
+            company_status.phase,
+            company_status.current_focus.join(", "),
+            company_status.upcoming.join(", ")
+        )
+    };
+    ExecutionTaskCandidate {
+        task_id: "company-awareness".to_string(),
+        title: if locale == "he" {
+            "יישור לתכנית החברה".to_string()
+        } else {
+            "Align with company plan".to_string()
+        },
+        detail,
+        source: "company".to_string(),
+        horizon: "mid_term".to_string(),
+        urgency: 0.62,
+        impact: 0.84,
+        confidence: 0.93,
+    }
+}
+
+fn execution_priority_score(task: &ExecutionTaskCandidate) -> f32 {
+    let horizon_boost = match task.horizon.as_str() {
+        "daily" => 0.12,
+        "mid_term" => 0.08,
+        "long_term" => 0.05,
+        _ => 0.03,
+    };
+    (task.impact * 0.45 + task.urgency * 0.35 + task.confidence * 0.2 + horizon_boost)
+        .clamp(0.0, 1.25)
+}
+
+fn prioritize_execution_tasks(tasks: Vec<ExecutionTaskCandidate>) -> Vec<ExecutionTaskCandidate> {
+    let mut dedup = HashMap::<String, ExecutionTaskCandidate>::new();
+    for task in tasks {
+        let key = task.title.trim().to_lowercase();
+        match dedup.get(&key) {
+            Some(existing)
+                if execution_priority_score(existing) >= execution_priority_score(&task) => {}
+            _ => {
+                dedup.insert(key, task);
+            }
+        }
+    }
+    let mut ranked = dedup.into_values().collect::<Vec<_>>();
+    ranked.sort_by(|lhs, rhs| {
+        execution_priority_score(rhs).total_cmp(&execution_priority_score(lhs))
+    });
+    ranked
+}
+
+fn collect_execution_task_candidates(
+    context: &ExecutionFeedContext<'_>,
+) -> Vec<ExecutionTaskCandidate> {
+    let mut tasks = Vec::new();
+    tasks.extend(extract_checkin_tasks(
+        context.latest_checkin,
+        context.user.locale.as_str(),
+    ));
+    tasks.extend(extract_note_tasks(context.notes));
+    tasks.extend(extract_survey_tasks(
+        context.survey,
+        context.user.locale.as_str(),
+    ));
+    tasks.extend(extract_memory_tasks(
+        context.memories,
+        context.user.locale.as_str(),
+    ));
+    if context.controls.include_company_awareness {
+        tasks.push(build_company_awareness_task(
+            context.company_status,
+            context.user.locale.as_str(),
+        ));
+    }
+    tasks
+}
+
+fn build_orchestrated_proactive_feed(context: &ExecutionFeedContext<'_>) -> Vec<ProactiveFeedItem> {
+    let reminder_app = context
+        .prefs
+        .map(|value| value.reminders_app.clone())
+        .unwrap_or_else(|| "google_calendar".to_string());
+    let alarm_app = context
+        .prefs
+        .map(|value| value.alarms_app.clone())
+        .unwrap_or_else(|| "apple_clock".to_string());
+    let fallback_prefs;
+    let prefs = match context.prefs {
+        Some(prefs) => prefs,
+        None => {
+            fallback_prefs = default_studio_preferences(context.user.user_id.as_str());
+            &fallback_prefs
+        }
+    };
+    // `disabled` silences the feed; `focus_only` keeps just the "next action now" card, picked
+    // from the user's own tasks rather than company awareness.
+    let focus_only = match prefs.proactive_mode.as_str() {
+        "disabled" => return Vec::new(),
+        mode => mode == "focus_only",
+    };
+    let mut candidates = collect_execution_task_candidates(context);
+    if focus_only {
+        candidates.retain(|task| task.source != "company");
+    }
+    let ranked = prioritize_execution_tasks(candidates);
+    let mut items = Vec::new();
+    let now = chrono::Utc::now();
+    let snooze_options = reminder_snooze_options(now, prefs.utc_offset_minutes);
+
+    if let Some(top) = ranked.first() {
+        let due_at = snap_to_working_hours(
+            now + chrono::Duration::minutes(schedule_minutes_offset(
+                context.controls.cadence.as_str(),
+                "daily",
+                0,
+            )),
+            prefs,
+        );
+        let mut actions = Vec::new();
+        if context.controls.include_reminder_suggestions {
+            actions.push(atlas_core::SuggestedAction {
+                action_type: "create_reminder".to_string(),
+                label: if context.user.locale == "he" {
+                    "תזכורת לביצוע מיידי".to_string()
+                } else {
+                    "Set immediate execution reminder".to_string()
+                },
+                payload: serde_json::json!({
+                    "title": top.title,
+                    "details": top.detail,
+                    "due_at_utc": due_at.to_rfc3339(),
+                    "reminders_app": reminder_app,
+                    "snooze_options": snooze_options
+                }),
+            });
+            actions.push(atlas_core::SuggestedAction {
+                action_type: "create_alarm".to_string(),
+                label: if context.user.locale == "he" {
+                    "אזעקת התחלה".to_string()
+                } else {
+                    "Start alarm".to_string()
+                },
+                payload: serde_json::json!({
+                    "label": "Atlas next action now",
+                    "time_local": "09:00",
+                    "days": ["Sun","Mon","Tue","Wed","Thu"],
+                    "alarms_app": alarm_app
+                }),
+            });
+        }
+        items.push(ProactiveFeedItem {
+            id: "next_action_now".to_string(),
+            title: if context.user.locale == "he" {
+                "הפעולה הבאה עכשיו".to_string()
+            } else {
+                "Next action now".to_string()
+            },
+            summary: format!("{} — {}", top.title, top.detail),
+            why_now: if context.user.locale == "he" {
+                format!("מקור: {} | אופק: {}", top.source, top.horizon)
+            } else {
+                format!("Source: {} | Horizon: {}", top.source, top.horizon)
+            },
+            priority: "critical".to_string(),
+            actions,
+        });
+    }
+
+    let mut used_task_ids = HashSet::new();
+    if let Some(top) = ranked.first() {
+        used_task_ids.insert(top.task_id.clone());
+    }
+    let mut selected = Vec::new();
+    if !focus_only {
+        for horizon in ["daily", "mid_term", "long_term"] {
+            if let Some(task) = ranked.iter().find(|candidate| {
+                candidate.horizon == horizon && !used_task_ids.contains(&candidate.task_id)
+            }) {
+                used_task_ids.insert(task.task_id.clone());
+                selected.push(task.clone());
+            }
+        }
+        for task in ranked.iter() {
+            if selected.len() >= 4 {
+                break;
+            }
+            if used_task_ids.contains(&task.task_id) {
+                continue;
+            }
+            used_task_ids.insert(task.task_id.clone());
+            selected.push(task.clone());
+        }
+    }
+
+    for (index, task) in selected.iter().enumerate() {
+        let due_at = snap_to_working_hours(
+            now + chrono::Duration::minutes(schedule_minutes_offset(
+                context.controls.cadence.as_str(),
+                task.horizon.as_str(),
+                index + 1,
+            )),
+            prefs,
+        );
+        let mut actions = Vec::new();
+        if context.controls.include_reminder_suggestions {
+            actions.push(atlas_core::SuggestedAction {
+                action_type: "create_reminder".to_string(),
+                label: if context.user.locale == "he" {
+                    "קבע תזכורת".to_string()
+                } else {
+                    "Set reminder".to_string()
+                },
+                payload: serde_json::json!({
+                    "title": task.title,
+                    "details": task.detail,
+                    "due_at_utc": due_at.to_rfc3339(),
+                    "reminders_app": reminder_app,
+                    "snooze_options": snooze_options
+                }),
+            });
+        }
+        if task.source == "company" {
+            actions.push(atlas_core::SuggestedAction {
+                action_type: "open_company_status".to_string(),
+                label: if context.user.locale == "he" {
+                    "פתח סטטוס חברה".to_string()
+                } else {
+                    "Open company status".to_string()
+                },
+                payload: serde_json::json!({}),
+            });
+        }
+        items.push(ProactiveFeedItem {
+            id: task.task_id.clone(),
+            title: task.title.clone(),
+            summary: task.detail.clone(),
+            why_now: if context.user.locale == "he" {
+                format!("אופק {} | סדר עדיפויות מחושב", task.horizon)
+            } else {
+                format!("{} horizon | prioritized by execution engine", task.horizon)
+            },
+            priority: if execution_priority_score(task) > 0.85 {
+                "high".to_string()
+            } else {
+                "normal".to_string()
+            },
+            actions,
+        });
+    }
+
+    if context.controls.include_company_awareness && !focus_only {
+        items.push(ProactiveFeedItem {
+            id: "company_planning_awareness".to_string(),
+            title: if context.user.locale == "he" {
+                "מודעות תכנית חברה".to_string()
+            } else {
+                "Company planning awareness".to_string()
+            },
+            summary: context.company_status.message.clone(),
+            why_now: if context.user.locale == "he" {
+                format!(
+                    "פאזה {}. פוקוס: {}.",
+                    context.company_status.phase,
+                    context.company_status.current_focus.join(", ")
+                )
+            } else {
+                format!(
+                    "Phase {}. Focus: {}.",
+                    context.company_status.phase,
+                    context.company_status.current_focus.join(", ")
+                )
+            },
+            priority: "normal".to_string(),
+            actions: vec![atlas_core::SuggestedAction {
+                action_type: "open_company_status".to_string(),
+                label: if context.user.locale == "he" {
+                    "סקירת סטטוס מלאה".to_string()
+                } else {
+                    "Review full company status".to_string()
+                },
+                payload: serde_json::json!({}),
+            }],
+        });
+    }
+
+    let items = cap_proactive_feed_items(items, context.max_items);
+
+    if context.controls.detail_level == "concise" {
+        items
+            .into_iter()
+            .map(|mut item| {
+                item.summary = sanitize_limited_text(item.summary.as_str(), 120);
+                item.why_now = sanitize_limited_text(item.why_now.as_str(), 90);
+                item
+            })
+            .collect()
+    } else if context.controls.detail_level == "expanded" {
+        items
+            .into_iter()
+            .map(|mut item| {
+                item.why_now = format!(
+                    "{} | {}",
+                    item.why_now,
+                    if context.user.locale == "he" {
+                        "המלצה זו נגזרת מדפוסי שימוש, זיכרון ארוך-טווח ויעדי אופק."
+                    } else {
+                        "Recommendation derived from usage patterns, long-term memory, and horizon goals."
+                    }
+                );
+                item
+            })
+            .collect()
+    } else {
+        items
+    }
+}
+
+// Trimming keeps "next action now" first, then the selected tasks in ranked order; the company
+// planning card is informational and is the first item dropped.
+fn cap_proactive_feed_items(
+    mut items: Vec<ProactiveFeedItem>,
+    max_items: usize,
+) -> Vec<ProactiveFeedItem> {
+    if items.len() > max_items {
+        items.retain(|item| item.id != "company_planning_awareness");
+    }
+    items.truncate(max_items);
+    items
+}
+
+fn build_survey_hints(state: &SurveyStateRecord) -> Vec<String> {
+    let mut hints = Vec::new();
+    if let Some(goal) = state.answers.get("primary_goal") {
+        hints.push(format!("goal: {}", goal));
+    }
+    if let Some(pressure) = state.answers.get("daily_pressure") {
+        hints.push(format!("pressure: {}", pressure));
+    }
+    if let Some(pattern) = state.answers.get("travel_pattern") {
+        hints.push(format!("travel_pattern: {}", pattern));
+    }
+    if let Some(style) = state.answers.get("trip_style") {
+        hints.push(format!("trip_style: {}", style));
+    }
+    if let Some(gym) = state.answers.get("gym_frequency") {
+        hints.push(format!("gym_frequency: {}", gym));
+    }
+    if let Some(income) = state.answers.get("income_cadence") {
+        hints.push(format!("income_cadence: {}", income));
+    }
+    if let Some(wealth) = state.answers.get("wealth_focus") {
+        hints.push(format!("wealth_focus: {}", wealth));
+    }
+    if let Some(charity) = state.answers.get("charity_commitment") {
+        hints.push(format!("charity_commitment: {}", charity));
+    }
+    hints
+}
+
+fn survey_total_questions(answers: &HashMap<String, String>) -> usize {
+    let mut total = 13;
+    if answers
+        .get("daily_pressure")
+        .map(|value| value == "high")
+        .unwrap_or(false)
+    {
+        total += 1;
+    }
+    if answers
+        .get("work_hours")
+        .map(|value| value == "10_plus")
+        .unwrap_or(false)
+    {
+        total += 1;
+    }
+    if answers
+        .get("stress_trigger")
+        .map(|value| value == "uncertainty")
+        .unwrap_or(false)
+    {
+        total += 1;
+    }
+    total
+}
+
+fn next_survey_question(locale: &str, answers: &HashMap<String, String>) -> Option<SurveyQuestion> {
+    let he = locale.starts_with("he");
+    let en = !he;
+
+    let mk = |id: &str,
+              title_he: &str,
+              title_en: &str,
+              desc_he: Option<&str>,
+              desc_en: Option<&str>,
+              kind: &str,
+              choices: Vec<SurveyChoice>,
+              placeholder_he: Option<&str>,
+              placeholder_en: Option<&str>| SurveyQuestion {
+        id: id.to_string(),
+        title: if he { title_he } else { title_en }.to_string(),
+        description: if he { desc_he } else { desc_en }.map(|value| value.to_string()),
+        kind: kind.to_string(),
+        required: true,
+        choices,
+        placeholder: if he { placeholder_he } else { placeholder_en }
+            .map(|value| value.to_string()),
+    };
+
+    if !answers.contains_key("primary_goal") {
+        return Some(mk(
+            "primary_goal",
+            "מה המטרה המרכזית שלך ל-90 הימים הקרובים?",
+            "What is your primary goal for the next 90 days?",
+            Some("זה מכוון את כל ההמלצות והפיד היזום."),
+            Some("This tunes your recommendations and proactive feed."),
+            "choice",
+            vec![
+                survey_choice(he, "wealth", "בניית הכנסה/עושר", "Build income/wealth"),
+                survey_choice(he, "stability", "יציבות וסדר אישי", "Personal stability"),
+                survey_choice(he, "health", "בריאות ואנרגיה", "Health and energy"),
+                survey_choice(he, "mixed", "שילוב הכל", "Mix of all"),
+            ],
+            None,
+            None,
+        ));
+    }
+
+    if !answers.contains_key("daily_pressure") {
+        return Some(mk(
+            "daily_pressure",
+            "כמה עומס אתה מרגיש ביום-יום?",
+            "How much daily pressure are you under?",
+            None,
+            None,
+            "choice",
+            vec![
+                survey_choice(he, "low", "נמוך", "Low"),
+                survey_choice(he, "medium", "בינוני", "Medium"),
+                survey_choice(he, "high", "גבוה", "High"),
+            ],
+            None,
+            None,
+        ));
+    }
+
+    if answers
+        .get("daily_pressure")
+        .map(|value| value == "high")
+        .unwrap_or(false)
+        && !answers.contains_key("pressure_source")
+    {
+        return Some(mk(
+            "pressure_source",
+            "מה המקור המרכזי לעומס כרגע?",
+            "What is the main source of pressure right now?",
+            None,
+            None,
+            "choice",
+            vec![
+                survey_choice(he, "money", "כסף", "Money"),
+                survey_choice(he, "time", "זמן", "Time"),
+                survey_choice(he, "uncertainty", "חוסר ודאות", "Uncertainty"),
+                survey_choice(he, "relationships", "יחסים/צוות", "Relationships/team"),
+            ],
+            None,
+            None,
+        ));
+    }
+
+    if !answers.contains_key("work_hours") {
+        return Some(mk(
+            "work_hours",
+            "כמה שעות עבודה ממוצעות ביום?",
+            "Average work hours per day?",
+            None,
+            None,
+            "choice",
+            vec![
+                survey_choice(he, "under_6", "עד 6", "Up to 6"),
+                survey_choice(he, "6_10", "6-10", "6-10"),
+                survey_choice(he, "10_plus", "10+", "10+"),
+            ],
+            None,
+            None,
+        ));
+    }
+
+    if answers
+        .get("work_hours")
+        .map(|value| value == "10_plus")
+        .unwrap_or(false)
+        && !answers.contains_key("break_structure")
+    {
+        return Some(mk(
+            "break_structure",
+            "איך אתה רוצה שהמערכת תנהל הפסקות?",
+            "How should the system handle your breaks?",
+            None,
+            None,
+            "choice",
+            vec![
+                survey_choice(he, "strict", "משמעת קבועה", "Strict schedule"),
+                survey_choice(he, "flex", "גמיש לפי עומס", "Adaptive to workload"),
+                survey_choice(he, "manual", "ידני בלבד", "Manual only"),
+            ],
+            None,
+            None,
+        ));
+    }
+
+    if !answers.contains_key("stress_trigger") {
+        return Some(mk(
+            "stress_trigger",
+            "מה הטריגר הנפוץ ללחץ/דחיינות?",
+            "What usually triggers stress/procrastination?",
+            None,
+            None,
+            "choice",
+            vec![
+                survey_choice(he, "uncertainty", "חוסר ודאות", "Uncertainty"),
+                survey_choice(he, "fatigue", "עייפות", "Fatigue"),
+                survey_choice(he, "overload", "עומס משימות", "Task overload"),
+                survey_choice(
+                    he,
+                    "social",
+                    "רעש חברתי/התראות",
+                    "Social noise/notifications",
+                ),
+            ],
+            None,
+            None,
+        ));
+    }
+
+    if answers
+        .get("stress_trigger")
+        .map(|value| value == "uncertainty")
+        .unwrap_or(false)
+        && !answers.contains_key("proactive_alerts")
+    {
+        return Some(mk(
+            "proactive_alerts",
+            "איזה סוג עדכונים יזומים יעזור לך?",
+            "Which proactive alerts help you most?",
+            None,
+            None,
+            "choice",
+            vec![
+                survey_choice(he, "daily_brief", "בריף יומי", "Daily brief"),
+                survey_choice(he, "risk_alerts", "התראות סיכון", "Risk alerts"),
+                survey_choice(he, "execution", "דחיפת ביצוע", "Execution nudges"),
+            ],
+            None,
+            None,
+        ));
+    }
+
+    if !answers.contains_key("travel_pattern") {
+        return Some(mk(
+            "travel_pattern",
+            "מה דפוס התנועה שלך?",
+            "What is your movement pattern?",
+            None,
+            None,
+            "choice",
+            vec![
+                survey_choice(
+                    he,
+                    "daily_commute",
+                    "נסיעות יומיות כבדות",
+                    "Heavy daily commuting",
+                ),
+                survey_choice(
+                    he,
+                    "multi_day",
+                    "שהייה מתגלגלת רב-יומית",
+                    "Multi-day rolling travel",
+                ),
+                survey_choice(he, "hybrid", "היברידי", "Hybrid"),
+            ],
+            None,
+            None,
+        ));
+    }
+
+    if !answers.contains_key("trip_style") {
+        return Some(mk(
+            "trip_style",
+            "מה סגנון המסע המועדף עליך?",
+            "What is your preferred trip style?",
+            Some("נשתמש בזה כדי לכוון מסלולים ופיד יזום."),
+            Some("Used to tune routes and proactive feed recommendations."),
+            "choice",
+            vec![
+                survey_choice(he, "mixed", "משולב", "Mixed"),
+                survey_choice(he, "beach", "חוף", "Beach"),
+                survey_choice(he, "north", "צפון", "North"),
+                survey_choice(he, "desert", "מדבר", "Desert"),
+            ],
+            None,
+            None,
+        ));
+    }
+
+    if !answers.contains_key("health_priority") {
+        return Some(mk(
+            "health_priority",
+            "מה העדיפות הבריאותית החשובה כרגע?",
+            "Top health priority right now?",
+            None,
+            None,
+            "choice",
+            vec![
+                survey_choice(he, "sleep", "שינה", "Sleep"),
+                survey_choice(he, "focus", "פוקוס וקוגניציה", "Focus/cognition"),
+                survey_choice(he, "stress", "הורדת סטרס", "Stress reduction"),
+                survey_choice(he, "nutrition", "תזונה טובה", "Better nutrition"),
+            ],
+            None,
+            None,
+        ));
+    }
+
+    if !answers.contains_key("gym_frequency") {
+        return Some(mk(
+            "gym_frequency",
+            "באיזו תדירות אתה מתאמן כרגע?",
+            "How often do you currently train/work out?",
+            Some("המערכת תשתמש בזה לצ׳ק-אין יומי ובניית עקביות."),
+            Some("This powers daily follow-up check-ins and consistency coaching."),
+            "choice",
+            vec![
+                survey_choice(he, "rarely", "כמעט לא", "Rarely"),
+                survey_choice(he, "sometimes", "לפעמים", "Sometimes"),
+                survey_choice(he, "regularly", "באופן קבוע", "Regularly"),
+            ],
+            None,
+            None,
+        ));
+    }
+
+    if !answers.contains_key("income_cadence") {
+        return Some(mk(
+            "income_cadence",
+            "כמה רציפה ההכנסה שלך כרגע?",
+            "How regular is your income right now?",
+            Some("זה מאפשר למערכת להציע פעולות הכנסה יומיות כשצריך."),
+            Some("This lets Atlas trigger daily income actions when needed."),
+            "choice",
+            vec![
+                survey_choice(he, "none", "ללא הכנסה רציפה", "No regular income"),
+                survey_choice(he, "sometimes", "מדי פעם", "Sometimes"),
+                survey_choice(he, "regularly", "רציפה", "Regularly"),
+            ],
+            None,
+            None,
+        ));
+    }
+
+    if !answers.contains_key("wealth_focus") {
+        return Some(mk(
+            "wealth_focus",
+            "מה חשוב לך יותר בשנתיים הקרובות?",
+            "In the next two years, what matters more?",
+            None,
+            None,
+            "choice",
+            vec![
+                survey_choice(he, "income_growth", "צמיחת הכנסה", "Income growth"),
+                survey_choice(he, "capital", "בניית הון", "Capital building"),
+                survey_choice(he, "both", "שניהם יחד", "Both"),
+            ],
+            None,
+            None,
+        ));
+    }
+
+    if !answers.contains_key("charity_commitment") {
+        return Some(mk(
+            "charity_commitment",
+            "איך תרצה לשלב תרומה/נתינה בתכנון?",
+            "How do you want to include charity in planning?",
+            None,
+            None,
+            "choice",
+            vec![
+                survey_choice(
+                    he,
+                    "fixed_percent",
+                    "אחוז קבוע מהכנסות",
+                    "Fixed percent of income",
+                ),
+                survey_choice(he, "milestones", "לפי אבני דרך", "By milestones"),
+                survey_choice(he, "later", "בהמשך", "Later"),
+            ],
+            None,
+            None,
+        ));
+    }
+
+    if !answers.contains_key("support_style") {
+        return Some(mk(
+            "support_style",
+            "איזה סגנון ליווי אתה מעדיף?",
+            "What coaching style do you prefer?",
+            None,
+            None,
+            "choice",
+            vec![
+                survey_choice(he, "direct", "ישיר וחד", "Direct and sharp"),
+                survey_choice(he, "coach", "מאמן תומך", "Supportive coach"),
+                survey_choice(he, "strategic", "אסטרטגי ארוך טווח", "Long-term strategic"),
+            ],
+            None,
+            None,
+        ));
+    }
+
+    if !answers.contains_key("voice_preference") {
+        return Some(mk(
+            "voice_preference",
+            "האם אתה רוצה שיחה קולית רציפה עם המערכת?",
+            "Do you want continuous voice conversation with the system?",
+            if en {
+                Some("This can be changed later in Studio settings.")
+            } else {
+                Some("אפשר לשנות בכל רגע בהגדרות הסטודיו.")
+            },
+            if en {
+                Some("This can be changed later in Studio settings.")
+            } else {
+                Some("אפשר לשנות בכל רגע בהגדרות הסטודיו.")
+            },
+            "choice",
+            vec![
+                survey_choice(he, "yes", "כן", "Yes"),
+                survey_choice(he, "sometimes", "לפעמים", "Sometimes"),
+                survey_choice(he, "no", "לא", "No"),
+            ],
+            None,
+            None,
+        ));
+    }
+
+    None
+}
+
+fn survey_choice(is_he: bool, value: &str, he: &str, en: &str) -> SurveyChoice {
+    SurveyChoice {
+        value: value.to_string(),
+        label: if is_he { he } else { en }.to_string(),
+    }
+}
+
+fn sanitize_enum_value(value: &str, allowed: &[&str], default_value: &str) -> String {
+    let normalized = value.trim().to_lowercase();
+    if allowed.iter().any(|candidate| *candidate == normalized) {
+        normalized
+    } else {
+        default_value.to_string()
+    }
+}
+
+// Lenient like `sanitize_enum_value`, but records the rejected input so the caller can tell
+// clients which values were replaced by defaults.
+fn sanitize_enum_field(
+    field: &'static str,
+    value: &str,
+    allowed: &[&str],
+    default_value: &str,
+    coerced: &mut Vec<CoercedField>,
+) -> String {
+    let applied = sanitize_enum_value(value, allowed, default_value);
+    if applied != value.trim().to_lowercase() {
+        coerced.push(CoercedField {
+            field,
+            provided: value.to_string(),
+            applied: applied.clone(),
+        });
+    }
+    applied
+}
+
+fn sanitize_cookie_domain(value: &str) -> Option<String> {
+    let normalized = value
+        .trim()
+        .trim_start_matches('.')
+        .trim_end_matches('.')
+        .to_ascii_lowercase();
+    if normalized.is_empty() {
+        return None;
+    }
+    if normalized
+        .chars()
+        .all(|ch| ch.is_ascii_alphanumeric() || ch == '.' || ch == '-')
+    {
+        Some(normalized)
+    } else {
+        None
+    }
+}
+
+fn sanitize_limited_text(value: &str, max_chars: usize) -> String {
+    value.trim().chars().take(max_chars).collect::<String>()
+}
+
+fn normalize_tag(tag: &str) -> String {
+    tag.trim()
+        .chars()
+        .take(MAX_NOTE_TAG_LEN)
+        .filter(|ch| ch.is_ascii_alphanumeric() || *ch == '-' || *ch == '_')
+        .collect::<String>()
+        .to_lowercase()
+}
+
+fn is_subscription_bypass_email(email: &str) -> bool {
+    let target = email.trim().to_lowercase();
+    if target.is_empty() {
+        return false;
+    }
+
+    let configured = env::var("ATLAS_SUBSCRIPTION_BYPASS_EMAILS")
+        .ok()
+        .filter(|value| !value.trim().is_empty())
+        .unwrap_or_else(|| DEFAULT_SUBSCRIPTION_BYPASS_EMAILS.to_string());
+
+    configured
+        .split(',')
+        .map(|value| value.trim().to_lowercase())
+        .any(|value| !value.is_empty() && value == target)
+}
+
+fn sanitize_note_tags(tags: Vec<String>) -> Vec<String> {
+    tags.into_iter()
+        .map(|tag| normalize_tag(tag.as_str()))
+        .filter(|tag| !tag.is_empty())
+        .take(MAX_NOTE_TAGS)
+        .collect()
+}
+
+fn sanitize_memory_type(value: &str) -> String {
+    sanitize_enum_value(value, MEMORY_TYPES, "insight")
+}
+
+// Unknown types are dropped rather than sanitized to the default, which would make `insight`
+// ephemeral by accident.
+fn parse_ephemeral_memory_types(value: Option<&str>) -> Vec<String> {
+    let mut types = Vec::new();
+    for memory_type in value
+        .unwrap_or_default()
+        .split(',')
+        .map(|item| item.trim().to_ascii_lowercase())
+    {
+        if MEMORY_TYPES.contains(&memory_type.as_str()) && !types.contains(&memory_type) {
+            types.push(memory_type);
+        }
+    }
+    types
+}
+
+fn sanitize_memory_stability(value: &str) -> String {
+    sanitize_enum_value(value, &["permanent", "transient"], "transient")
+}
+
+fn sanitize_memory_source(value: &str) -> String {
+    sanitize_enum_value(value, MEMORY_SOURCES, "system")
+}
+
+// `?sources=survey,note` → known sources only; `None` when no known source was named.
+fn parse_memory_sources(value: &str) -> Option<Vec<String>> {
+    let mut sources = value
+        .split(',')
+        .map(|source| source.trim().to_lowercase())
+        .filter(|source| MEMORY_SOURCES.contains(&source.as_str()))
+        .collect::<Vec<_>>();
+    sources.sort();
+    sources.dedup();
+    (!sources.is_empty()).then_some(sources)
+}
+
+fn clamp_memory_weight(weight: f32) -> f32 {
+    if !weight.is_finite() {
+        return 0.5;
+    }
+    weight.clamp(0.05, 1.0)
+}
+
+// Repetition should only ever build confidence: start from the stronger of the two weights
+// and close a fixed share of the remaining gap to 1.0.
+fn reinforce_memory_weight(existing: f32, incoming: f32) -> f32 {
+    let base = existing.max(incoming);
+    clamp_memory_weight(base + (1.0 - base) * MEMORY_REINFORCEMENT_RATE)
+}
+
+fn memory_fingerprint(memory_type: &str, stability: &str, text: &str) -> String {
+    let normalized = text
+        .trim()
+        .to_lowercase()
+        .chars()
+        .filter(|ch| ch.is_ascii_alphanumeric() || ch.is_ascii_whitespace())
+        .take(300)
+        .collect::<String>();
+    let key = format!("{}|{}|{}", memory_type, stability, normalized);
+    hex_encode(Sha256::digest(key.as_bytes()).as_slice())
+}
+
+fn memory_recency_score(updated_at: &str, now: chrono::DateTime<chrono::Utc>) -> f32 {
+    let updated = chrono::DateTime::parse_from_rfc3339(updated_at)
+        .ok()
+        .map(|value| value.with_timezone(&chrono::Utc))
+        .unwrap_or(now);
+    let age_hours = now.signed_duration_since(updated).num_hours().max(0) as f32;
+    (1.0 / (1.0 + (age_hours / 72.0))).clamp(0.0, 1.0)
+}
+
+fn is_memory_expired(record: &MemoryRecord, now: chrono::DateTime<chrono::Utc>) -> bool {
+    record
+        .expires_at
+        .as_deref()
+        .and_then(|value| chrono::DateTime::parse_from_rfc3339(value).ok())
+        .map(|value| value.with_timezone(&chrono::Utc) <= now)
+        .unwrap_or(false)
+}
+
+fn prune_expired_memories(records: &mut Vec<MemoryRecord>, now: chrono::DateTime<chrono::Utc>) {
+    records.retain(|entry| !is_memory_expired(entry, now));
+}
+
+/// Removal counts per user, sorted by user id; users with nothing expired are left out.
+fn prune_expired_memories_for_all_users(
+    memories: &mut HashMap<String, Vec<MemoryRecord>>,
+    now: chrono::DateTime<chrono::Utc>,
+) -> Vec<(String, usize)> {
+    let mut pruned = memories
+        .iter_mut()
+        .filter_map(|(user_id, records)| {
+            let before = records.len();
+            prune_expired_memories(records, now);
+            let removed = before - records.len();
+            (removed > 0).then(|| (user_id.clone(), removed))
+        })
+        .collect::<Vec<_>>();
+    pruned.sort();
+    pruned
+}
+
+async fn prune_expired_memories_everywhere(state: &ApiState) -> Vec<(String, usize)> {
+    let pruned =
+        prune_expired_memories_for_all_users(&mut state.user_memories.write(), chrono::Utc::now());
+    for (user_id, _) in &pruned {
+        let _ = persist_memories_if_configured(state, user_id.as_str()).await;
+    }
+    pruned
+}
+
+fn memory_relevance_score(query: &str, record: &MemoryRecord) -> f32 {
+    let query_tokens = tokenize_memory_text(query);
+    if query_tokens.is_empty() {
+        return 0.0;
+    }
+    let mut corpus = record.text.clone();
+    if !record.tags.is_empty() {
+        corpus.push(' ');
+        corpus.push_str(record.tags.join(" ").as_str());
+    }
+    let record_tokens = tokenize_memory_text(corpus.as_str());
+    if record_tokens.is_empty() {
+        return 0.0;
+    }
+    let overlap = query_tokens
+        .iter()
+        .filter(|token| record_tokens.contains(*token))
+        .count();
+    (overlap as f32 / query_tokens.len() as f32).clamp(0.0, 1.0)
+}
+
+fn tokenize_memory_text(text: &str) -> std::collections::HashSet<String> {
+    text.to_lowercase()
+        .split(|ch: char| !ch.is_ascii_alphanumeric() && !ch.is_alphabetic())
+        .filter(|token| token.len() >= 2)
+        .take(256)
+        .map(|token| token.to_string())
+        .collect()
+}
+
+fn ingest_memory_records_if_opted_in(
+    records: &mut Vec<MemoryRecord>,
+    user_id: &str,
+    opt_in: bool,
+    ephemeral_types: &[String],
+    event: MemoryIngestEvent,
+    now: chrono::DateTime<chrono::Utc>,
+) -> Option<MemoryRecord> {
+    if !opt_in {
+        return None;
+    }
+
+    let text = sanitize_limited_text(event.text.as_str(), MAX_MEMORY_TEXT_LEN);
+    if text.is_empty() {
+        return None;
+    }
+
+    let memory_type = sanitize_memory_type(event.memory_type.as_str());
+    let stability = sanitize_memory_stability(event.stability.as_str());
+    let source = sanitize_memory_source(event.source.as_str());
+    let tags = sanitize_note_tags(event.tags);
+    let happened_at = event.happened_at.unwrap_or(now);
+    let updated_at = happened_at.to_rfc3339();
+    let weight = clamp_memory_weight(event.weight);
+    let recency_score = memory_recency_score(updated_at.as_str(), now);
+    let expires_at = if ephemeral_types.contains(&memory_type) {
+        let cap = now + chrono::Duration::hours(EPHEMERAL_MEMORY_TTL_HOURS);
+        Some(
+            event
+                .expires_at
+                .map_or(cap, |value| value.min(cap))
+                .to_rfc3339(),
+        )
+    } else if stability == "transient" {
+        event
+            .expires_at
+            .or_else(|| Some(happened_at + chrono::Duration::days(TRANSIENT_MEMORY_TTL_DAYS)))
+            .map(|value| value.to_rfc3339())
+    } else {
+        None
+    };
+    let fingerprint = memory_fingerprint(memory_type.as_str(), stability.as_str(), text.as_str());
+
+    if let Some(index) = records
+        .iter()
+        .position(|entry| entry.fingerprint == fingerprint)
+    {
+        {
+            let existing = &mut records[index];
+            existing.source = source;
+            existing.text = text;
+            existing.weight = reinforce_memory_weight(existing.weight, weight);
+            existing.recency_score = recency_score;
+            existing.updated_at = updated_at;
+            existing.expires_at = expires_at;
+            existing.tags = sanitize_note_tags(
+                existing
+                    .tags
+                    .iter()
+                    .cloned()
+                    .chain(tags)
+                    .collect::<Vec<_>>(),
+            );
+        }
+        let updated = records[index].clone();
+        prune_expired_memories(records, now);
+        return Some(updated);
+    }
+
+    let created = MemoryRecord {
+        memory_id: uuid::Uuid::new_v4().to_string(),
+        user_id: user_id.to_string(),
+        memory_type,
+        stability,
+        source,
+        text,
+        weight,
+        recency_score,
+        tags,
+        created_at: now.to_rfc3339(),
+        updated_at,
+        expires_at,
+        fingerprint,
+        text_original_sealed: None,
+    };
+    records.push(created.clone());
+    prune_expired_memories(records, now);
+    records.sort_by(|lhs, rhs| {
+        let lhs_score = lhs.weight * 0.7 + lhs.recency_score * 0.3;
+        let rhs_score = rhs.weight * 0.7 + rhs.recency_score * 0.3;
+        rhs_score.total_cmp(&lhs_score)
+    });
+    records.truncate(MAX_MEMORY_RECORDS_PER_USER);
+    Some(created)
+}
+
+fn retrieve_memory_context_from_records(
+    records: &[MemoryRecord],
+    query: &str,
+    limit: usize,
+    sources: Option<&[String]>,
+    now: chrono::DateTime<chrono::Utc>,
+) -> Vec<MemoryRetrievedItem> {
+    let top_limit = limit.clamp(1, MAX_MEMORY_RETRIEVAL_LIMIT);
+    let mut scored = records
+        .iter()
+        .filter(|record| !is_memory_expired(record, now))
+        .filter(|record| sources.is_none_or(|allowed| allowed.contains(&record.source)))
+        .map(|record| score_memory_record(record, query, now))
+        .collect::<Vec<_>>();
+    scored.sort_by(|lhs, rhs| rhs.final_score.total_cmp(&lhs.final_score));
+    scored.truncate(top_limit);
+    scored
+}
+
+// Unlike retrieval for chat context, search only returns memories that actually match the
+// query (relevance above zero and at least `min_score`), best matches first.
+fn search_memory_records(
+    records: &[MemoryRecord],
+    query: &str,
+    filters: &MemorySearchFilters<'_>,
+    limit: usize,
+    now: chrono::DateTime<chrono::Utc>,
+) -> Vec<MemoryRetrievedItem> {
+    let mut matches = records
+        .iter()
+        .filter(|record| !is_memory_expired(record, now))
+        .filter(|record| filters.sources.is_empty() || filters.sources.contains(&record.source))
+        .filter(|record| filters.types.is_empty() || filters.types.contains(&record.memory_type))
+        .map(|record| score_memory_record(record, query, now))
+        .filter(|item| item.relevance_score > 0.0 && item.relevance_score >= filters.min_score)
+        .collect::<Vec<_>>();
+    matches.sort_by(|lhs, rhs| {
+        rhs.relevance_score
+            .total_cmp(&lhs.relevance_score)
+            .then(rhs.final_score.total_cmp(&lhs.final_score))
+    });
+    matches.truncate(limit.clamp(1, MAX_MEMORY_RETRIEVAL_LIMIT));
+    matches
+}
+
+fn score_memory_record(
+    record: &MemoryRecord,
+    query: &str,
+    now: chrono::DateTime<chrono::Utc>,
+) -> MemoryRetrievedItem {
+    let recency_score = memory_recency_score(record.updated_at.as_str(), now);
+    let relevance_score = memory_relevance_score(query, record);
+    let stability_boost = if record.stability == "permanent" {
+        0.05
+    } else {
+        0.0
+    };
+    let final_score =
+        (record.weight * 0.45 + recency_score * 0.3 + relevance_score * 0.25 + stability_boost)
+            .clamp(0.0, 1.2);
+    MemoryRetrievedItem {
+        memory_id: record.memory_id.clone(),
+        memory_type: record.memory_type.clone(),
+        stability: record.stability.clone(),
+        source: record.source.clone(),
+        text: record.text.clone(),
+        weight: record.weight,
+        recency_score,
+        relevance_score,
+        final_score,
+        tags: record.tags.clone(),
+        updated_at: record.updated_at.clone(),
+    }
+}
+
+fn user_memory_opt_in(state: &ApiState, user_id: &str) -> bool {
+    state
+        .users
+        .read()
+        .get(user_id)
+        .map(|user| user.memory_opt_in)
+        .unwrap_or(false)
+}
+
+// Distinct from `!user_memory_opt_in`: an id with no account record has not opted out, so the
+// chat ingest limiter still applies to it.
+fn user_opted_out_of_memory(state: &ApiState, user_id: &str) -> bool {
+    state
+        .users
+        .read()
+        .get(user_id)
+        .is_some_and(|user| !user.memory_opt_in)
+}
+
+fn retrieve_user_memory_context(
+    state: &ApiState,
+    user_id: &str,
+    query: &str,
+    limit: usize,
+    sources: Option<&[String]>,
+) -> Vec<MemoryRetrievedItem> {
+    if !user_memory_opt_in(state, user_id) {
+        return Vec::new();
+    }
+    let snapshot = state
+        .user_memories
+        .read()
+        .get(user_id)
+        .cloned()
+        .unwrap_or_default();
+    retrieve_memory_context_from_records(
+        snapshot.as_slice(),
+        query,
+        limit,
+        sources,
+        chrono::Utc::now(),
+    )
+}
+
+async fn ingest_memory_event_for_user(
+    state: &ApiState,
+    user_id: &str,
+    event: MemoryIngestEvent,
+) -> Option<MemoryRecord> {
+    let ingested = ingest_memory_event_in_memory(state, user_id, event);
+    if ingested.is_some() {
+        let _ = persist_memories_if_configured(state, user_id).await;
+    }
+    ingested
+}
+
+// Leaves persisting to the caller, so batch writers can save once and see the error.
+fn ingest_memory_event_in_memory(
+    state: &ApiState,
+    user_id: &str,
+    mut event: MemoryIngestEvent,
+) -> Option<MemoryRecord> {
+    let now = chrono::Utc::now();
+    let opt_in = user_memory_opt_in(state, user_id);
+    let (text, text_original_sealed) = scrub_text_for_storage(state, event.text);
+    event.text = text;
+    let ingested = {
+        let mut memories_map = state.user_memories.write();
+        let records = memories_map.entry(user_id.to_string()).or_default();
+        let ingested = ingest_memory_records_if_opted_in(
+            records,
+            user_id,
+            opt_in,
+            &state.ephemeral_memory_types,
+            event,
+            now,
+        );
+        // The returned copy never carries the sealed original back to the caller.
+        if let Some(memory_id) = ingested.as_ref().map(|record| record.memory_id.as_str()) {
+            if let Some(stored) = records
+                .iter_mut()
+                .find(|record| record.memory_id == memory_id)
+            {
+                stored.text_original_sealed = text_original_sealed;
+            }
+        }
+        ingested
+    };
+    ingested
+}
+
+// Hard delete used when a user opts out of memory: nothing goes to the trash, and anything
+// already there is dropped too.
+async fn clear_user_memories_by_scope(state: &ApiState, user_id: &str, scope: &str) -> usize {
+    let filter = MemoryClearFilter {
+        scope,
+        tags: &[],
+        match_all_tags: false,
+    };
+    state.deleted_memories.write().remove(user_id);
+    let _ = purge_memory_trash_if_configured(state, user_id).await;
+    clear_user_memories(state, user_id, &filter).await.len()
+}
+
+async fn clear_user_memories(
+    state: &ApiState,
+    user_id: &str,
+    filter: &MemoryClearFilter<'_>,
+) -> Vec<MemoryRecord> {
+    let removed = {
+        let mut memories_map = state.user_memories.write();
+        let Some(records) = memories_map.get_mut(user_id) else {
+            return Vec::new();
+        };
+        let (removed, kept) = std::mem::take(records)
+            .into_iter()
+            .partition::<Vec<_>, _>(|entry| filter.matches(entry));
+        *records = kept;
+        removed
+    };
+    if !removed.is_empty() {
+        let memory_ids = removed
+            .iter()
+            .map(|memory| memory.memory_id.as_str())
+            .collect::<Vec<_>>();
+        let _ = delete_memories_if_configured(state, user_id, &memory_ids).await;
+    }
+    removed
+}
+
+async fn sweep_expired_memory_trash(state: &ApiState, now: chrono::DateTime<chrono::Utc>) {
+    let cutoff = now - chrono::Duration::hours(MEMORY_TRASH_TTL_HOURS);
+    {
+        let mut trash = state.deleted_memories.write();
+        for entries in trash.values_mut() {
+            entries.retain(|entry| entry.deleted_at > cutoff);
+        }
+        trash.retain(|_, entries| !entries.is_empty());
+    }
+    if let Some(pool) = state.db_pool.as_ref() {
+        let _ = sqlx::query("DELETE FROM deleted_memories WHERE deleted_at <= ?1")
+            .bind(cutoff.to_rfc3339())
+            .execute(pool)
+            .await;
+    }
+}
+
+/// Moves trashed memories back into the user's store. Entries whose id or fingerprint has
+/// reappeared since the clear (e.g. the same preference was mentioned again) are dropped rather
+/// than duplicated, and entries that would push the user past `MAX_MEMORY_RECORDS_PER_USER` are
+/// handed back. Returns `(restored, skipped, over_limit)`.
+fn restore_trashed_memories(
+    records: &mut Vec<MemoryRecord>,
+    trashed: Vec<TrashedMemory>,
+) -> (usize, usize, Vec<TrashedMemory>) {
+    let mut restored = 0;
+    let mut skipped = 0;
+    let mut over_limit = Vec::new();
+    for entry in trashed {
+        let duplicate = records.iter().any(|record| {
+            record.memory_id == entry.memory.memory_id
+                || record.fingerprint == entry.memory.fingerprint
+        });
+        if duplicate {
+            skipped += 1;
+        } else if records.len() >= MAX_MEMORY_RECORDS_PER_USER {
+            over_limit.push(entry);
+        } else {
+            records.push(entry.memory);
+            restored += 1;
+        }
+    }
+    (restored, skipped, over_limit)
+}
+
+/// Appends `turn`, trimming its session to `MAX_CHAT_TURNS_PER_SESSION`. Past
+/// `MAX_CHAT_SESSIONS_PER_USER` the least recently used session is dropped and its id returned.
+fn append_chat_turn(turns: &mut Vec<ChatTurnRecord>, turn: ChatTurnRecord) -> Option<String> {
+    let session_id = turn.session_id.clone();
+    turns.push(turn);
+    let session_count = turns
+        .iter()
+        .filter(|entry| entry.session_id == session_id)
+        .count();
+    let mut overflow = session_count.saturating_sub(MAX_CHAT_TURNS_PER_SESSION);
+    turns.retain(|entry| {
+        if overflow > 0 && entry.session_id == session_id {
+            overflow -= 1;
+            return false;
+        }
+        true
+    });
+
+    // Turns are kept in arrival order, so the session whose newest turn comes first is the
+    // least recently used one.
+    let mut sessions_by_recency: Vec<&str> = Vec::new();
+    for entry in turns.iter().rev() {
+        if !sessions_by_recency.contains(&entry.session_id.as_str()) {
+            sessions_by_recency.push(entry.session_id.as_str());
+        }
+    }
+    if sessions_by_recency.len() <= MAX_CHAT_SESSIONS_PER_USER {
+        return None;
+    }
+    let evicted = sessions_by_recency.last()?.to_string();
+    turns.retain(|entry| entry.session_id != evicted);
+    Some(evicted)
+}
+
+fn recent_chat_context_turns(
+    state: &ApiState,
+    user_id: &str,
+    session_id: &str,
+    limit: usize,
+) -> Vec<ChatContextTurn> {
+    let turns_map = state.chat_turns.read();
+    let Some(turns) = turns_map.get(user_id) else {
+        return Vec::new();
+    };
+    let mut context = turns
+        .iter()
+        .rev()
+        .filter(|turn| turn.session_id == session_id)
+        .take(limit)
+        .map(|turn| ChatContextTurn {
+            user_text: turn.user_text.clone(),
+            assistant_text: turn.assistant_text.clone(),
+        })
+        .collect::<Vec<_>>();
+    context.reverse();
+    context
+}
+
+async fn record_chat_turn_for_user(
+    state: &ApiState,
+    user_id: &str,
+    session_id: &str,
+    user_text: &str,
+    assistant_text: &str,
+) -> Result<()> {
+    if !user_memory_opt_in(state, user_id) {
+        return Ok(());
+    }
+    let (evicted_session, evicted_user) = {
+        let mut turns_map = state.chat_turns.write();
+        let turns = turns_map.entry(user_id.to_string()).or_default();
+        let evicted_session = append_chat_turn(
+            turns,
+            ChatTurnRecord {
+                turn_id: uuid::Uuid::new_v4().to_string(),
+                session_id: session_id.to_string(),
+                user_id: user_id.to_string(),
+                user_text: sanitize_limited_text(user_text, MAX_CHAT_TURN_TEXT_LEN),
+                assistant_text: sanitize_limited_text(assistant_text, MAX_CHAT_TURN_TEXT_LEN),
+                created_at: chrono::Utc::now().to_rfc3339(),
+            },
+        );
+        let evicted_user = if turns_map.len() > MAX_CHAT_HISTORY_USERS {
+            turns_map
+                .iter()
+                .filter(|(owner, _)| owner.as_str() != user_id)
+                .min_by(|(_, left), (_, right)| {
+                    let newest = |turns: &Vec<ChatTurnRecord>| {
+                        turns.last().map(|turn| turn.created_at.clone())
+                    };
+                    newest(left).cmp(&newest(right))
+                })
+                .map(|(owner, _)| owner.clone())
+        } else {
+            None
+        };
+        if let Some(owner) = evicted_user.as_ref() {
+            turns_map.remove(owner);
+        }
+        (evicted_session, evicted_user)
+    };
+    if let Some(evicted_session) = evicted_session {
+        delete_chat_turns_if_configured(state, user_id, Some(evicted_session.as_str())).await?;
+    }
+    if let Some(evicted_user) = evicted_user {
+        delete_chat_turns_if_configured(state, evicted_user.as_str(), None).await?;
+    }
+    persist_chat_session_if_configured(state, user_id, session_id).await
+}
+
+/// Drops a user's stored conversations, e.g. once they opt out of memory.
+async fn purge_chat_turns_for_user(state: &ApiState, user_id: &str) -> Result<()> {
+    state.chat_turns.write().remove(user_id);
+    delete_chat_turns_if_configured(state, user_id, None).await
+}
+
+/// Absent or blank values are `None`, anything else must be RFC 3339 or the request is rejected
+/// with `400 invalid_timestamp` naming `field`. Callers use the returned value rather than
+/// parsing the raw input again, so surrounding whitespace and offsets are handled once.
+fn parse_rfc3339_or_error(
+    field: &str,
+    value: Option<&str>,
+) -> std::result::Result<Option<chrono::DateTime<chrono::Utc>>, ApiError> {
+    let Some(value) = value.map(str::trim).filter(|value| !value.is_empty()) else {
+        return Ok(None);
+    };
+    chrono::DateTime::parse_from_rfc3339(value)
+        .map(|parsed| Some(parsed.with_timezone(&chrono::Utc)))
+        .map_err(|_| {
+            ApiError::bad_request(
+                "invalid_timestamp",
+                format!("{field} must be an RFC 3339 timestamp such as 2026-03-01T09:00:00Z"),
+            )
+            .with_details(serde_json::json!({ "field": field, "value": value }))
+        })
+}
+
+fn pct_encode(input: &str) -> String {
+    let mut output = String::with_capacity(input.len() * 2);
+    for byte in input.bytes() {
+        if byte.is_ascii_alphanumeric() || matches!(byte, b'-' | b'_' | b'.' | b'~') {
+            output.push(byte as char);
+        } else {
+            output.push('%');
+            output.push_str(&format!("{:02X}", byte));
+        }
+    }
+    output
+}
+
+fn escape_ics(input: &str) -> String {
+    input
+        .replace('\\', "\\\\")
+        .replace(';', "\\;")
+        .replace(',', "\\,")
+        .replace('\n', "\\n")
+}
+
+// RFC 5545 §3.1: content lines are folded at 75 octets with CRLF + a single space, and a fold
+// must not split a UTF-8 sequence. Continuation lines spend one octet on that leading space.
+fn fold_ics_line(line: &str) -> String {
+    const MAX_LINE_OCTETS: usize = 75;
+    let mut folded = String::with_capacity(line.len() + line.len() / MAX_LINE_OCTETS * 3);
+    let mut line_octets = 0;
+    for ch in line.chars() {
+        let char_octets = ch.len_utf8();
+        if line_octets + char_octets > MAX_LINE_OCTETS {
+            folded.push_str("\r\n ");
+            line_octets = 1;
+        }
+        folded.push(ch);
+        line_octets += char_octets;
+    }
+    folded
+}
+
+fn is_valid_hhmm(value: &str) -> bool {
+    let parts = value.split(':').collect::<Vec<_>>();
+    if parts.len() != 2 {
+        return false;
+    }
+    let hour = parts[0].parse::<u8>().ok();
+    let minute = parts[1].parse::<u8>().ok();
+    matches!((hour, minute), (Some(h), Some(m)) if h < 24 && m < 60)
+}
+
+fn parse_allowed_origins() -> Vec<String> {
+    let default_origins = [
+        "http://localhost:5500",
+        "http://127.0.0.1:5500",
+        "http://localhost:3000",
+        "http://127.0.0.1:3000",
+        "https://atlasmasa.com",
+        "https://www.atlasmasa.com",
+    ];
+
+    env::var("ATLAS_ALLOWED_ORIGINS")
+        .ok()
+        .map(|value| {
+            value
+                .split(',')
+                .map(|origin| origin.trim().trim_end_matches('/').to_string())
+                .filter(|origin| !origin.is_empty())
+                .collect::<Vec<_>>()
+        })
+        .unwrap_or_else(|| {
+            default_origins
+                .iter()
+                .map(|value| value.trim_end_matches('/').to_string())
+                .collect()
+        })
+}
+
+fn build_google_oauth_config() -> Option<GoogleOAuthConfig> {
+    let client_id = env::var("ATLAS_GOOGLE_CLIENT_ID").ok()?;
+    let client_secret = env::var("ATLAS_GOOGLE_CLIENT_SECRET").ok()?;
+    let redirect_uri = env::var("ATLAS_GOOGLE_REDIRECT_URI").ok()?;
+    let frontend_origin = env::var("ATLAS_FRONTEND_ORIGIN")
+        .ok()
+        .unwrap_or_else(|| "https://atlasmasa.com".to_string());
+
+    Some(GoogleOAuthConfig {
+        client_id,
+        client_secret,
+        redirect_uri,
+        frontend_origin,
+    })
+}
+
+fn build_apple_oauth_config() -> Option<AppleOAuthConfig> {
+    let client_id = env::var("ATLAS_APPLE_CLIENT_ID").ok()?;
+    let client_secret = env::var("ATLAS_APPLE_CLIENT_SECRET").ok()?;
+    let redirect_uri = env::var("ATLAS_APPLE_REDIRECT_URI").ok()?;
+    let frontend_origin = env::var("ATLAS_FRONTEND_ORIGIN")
+        .ok()
+        .unwrap_or_else(|| "https://atlasmasa.com".to_string());
+
+    Some(AppleOAuthConfig {
+        client_id,
+        client_secret,
+        redirect_uri,
+        frontend_origin,
+    })
+}
+
+fn build_openai_runtime_config() -> Option<OpenAiRuntimeConfig> {
+    let api_key = env::var("ATLAS_OPENAI_API_KEY").ok()?;
+    let model = env::var("ATLAS_OPENAI_MODEL").unwrap_or_else(|_| "gpt-5.2".to_string());
+    let default_reasoning_effort =
+        env::var("ATLAS_OPENAI_REASONING_EFFORT").unwrap_or_else(|_| "high".to_string());
+    let max_reasoning_effort = env::var("ATLAS_OPENAI_MAX_REASONING_EFFORT")
+        .ok()
+        .and_then(|value| {
+            normalize_reasoning_effort(Some(value.as_str()))
+                .ok()
+                .flatten()
+        })
+        .unwrap_or_else(|| "high".to_string());
+    let base_url = env::var("ATLAS_OPENAI_BASE_URL")
+        .ok()
+        .map(|value| value.trim().to_string())
+        .filter(|value| !value.is_empty())
+        .unwrap_or_else(|| DEFAULT_OPENAI_BASE_URL.to_string());
+    let Some(base_url) = sanitize_ai_base_url(base_url.as_str()) else {
+        tracing::warn!(
+            openai_base_url = %base_url,
+            "ATLAS_OPENAI_BASE_URL must be an https URL; premium runtime disabled"
+        );
+        return None;
+    };
+    tracing::info!(openai_base_url = %base_url, model = %model, "openai runtime configured");
+
+    Some(OpenAiRuntimeConfig {
+        api_key,
+        base_url,
+        model,
+        default_reasoning_effort,
+        max_reasoning_effort,
+    })
+}
+
+// The file is either plain text (the base prompt) or a JSON object with an optional "default"
+// and per-locale keys ("he", "en", "ar", "ru", "fr"). An inline ATLAS_OPENAI_SYSTEM_PROMPT
+// wins over the file's base prompt.
+fn parse_premium_system_prompts(
+    inline: Option<&str>,
+    file_contents: Option<&str>,
+) -> PremiumSystemPrompts {
+    let mut base = None;
+    let mut by_locale = HashMap::new();
+    if let Some(contents) = file_contents {
+        match serde_json::from_str::<HashMap<String, String>>(contents) {
+            Ok(entries) => {
+                for (key, value) in entries {
+                    let key = key.trim().to_ascii_lowercase();
+                    let value = value.trim().to_string();
+                    if value.is_empty() {
+                        continue;
+                    }
+                    if key == "default" {
+                        base = Some(value);
+                    } else if ["he", "en", "ar", "ru", "fr"].contains(&key.as_str()) {
+                        by_locale.insert(key, value);
+                    }
+                }
+            }
+            Err(_) => base = Some(contents.trim().to_string()).filter(|value| !value.is_empty()),
+        }
+    }
+    if let Some(value) = inline.map(str::trim).filter(|value| !value.is_empty()) {
+        base = Some(value.to_string());
+    }
+    PremiumSystemPrompts {
+        base: base.unwrap_or_else(|| DEFAULT_PREMIUM_SYSTEM_PROMPT.to_string()),
+        by_locale,
+    }
+}
+
+impl PremiumSystemPrompts {
+    fn for_locale(&self, locale: atlas_core::Locale) -> String {
+        let prompt = self.by_locale.get(locale.as_code()).unwrap_or(&self.base);
+        let language = match locale {
+            atlas_core::Locale::He => "Hebrew",
+            atlas_core::Locale::En => "English",
+            atlas_core::Locale::Ar => "Arabic",
+            atlas_core::Locale::Ru => "Russian",
+            atlas_core::Locale::Fr => "French",
+            atlas_core::Locale::Unknown => {
+                return format!("{prompt}\n\nRespond in the language the user writes in.");
+            }
+        };
+        format!("{prompt}\n\nRespond in {language} unless the user explicitly asks for another language.")
+    }
+}
+
+// The system prompt lets the user ask for another language, so a reply in that language is
+// what they wanted and the script check does not apply.
+fn premium_reply_usable(locale: atlas_core::Locale, user_text: &str, reply: &str) -> bool {
+    requests_explicit_language(user_text) || premium_reply_matches_locale(locale, reply)
+}
+
+const EXPLICIT_LANGUAGE_MARKERS: &[&str] = &[
+    "translate",
+    "in english",
+    "in hebrew",
+    "in french",
+    "in russian",
+    "in arabic",
+    "to english",
+    "to hebrew",
+    "to french",
+    "to russian",
+    "to arabic",
+    "תרגם",
+    "תרגמי",
+    "באנגלית",
+    "בעברית",
+    "בצרפתית",
+    "ברוסית",
+    "בערבית",
+    "переведи",
+    "по-английски",
+    "по-французски",
+    "на английском",
+    "на иврите",
+    "на французском",
+    "на арабском",
+    "ترجم",
+    "بالإنجليزية",
+    "بالعبرية",
+    "بالفرنسية",
+    "بالروسية",
+    "tradui",
+    "en anglais",
+    "en hébreu",
+    "en russe",
+    "en arabe",
+];
+
+fn requests_explicit_language(text: &str) -> bool {
+    let text = text.to_lowercase();
+    EXPLICIT_LANGUAGE_MARKERS
+        .iter()
+        .any(|marker| text.contains(marker))
+}
+
+// Only catches the obvious case: a reply written almost entirely in another script. English
+// and French share a script, so a French reply to an English user is not flagged.
+fn premium_reply_matches_locale(locale: atlas_core::Locale, reply: &str) -> bool {
+    let (mut hebrew, mut arabic, mut cyrillic, mut latin) = (0usize, 0usize, 0usize, 0usize);
+    for ch in reply.chars().filter(|ch| ch.is_alphabetic()) {
+        match ch as u32 {
+            0x0590..=0x05FF => hebrew += 1,
+            0x0600..=0x06FF => arabic += 1,
+            0x0400..=0x04FF => cyrillic += 1,
+            0x0041..=0x024F => latin += 1,
+            _ => {}
+        }
+    }
+    let total = hebrew + arabic + cyrillic + latin;
+    if total < MIN_LOCALE_CHECK_LETTERS {
+        return true;
+    }
+    let expected = match locale {
+        atlas_core::Locale::He => hebrew,
+        atlas_core::Locale::Ar => arabic,
+        atlas_core::Locale::Ru => cyrillic,
+        atlas_core::Locale::En | atlas_core::Locale::Fr => latin,
+        atlas_core::Locale::Unknown => return true,
+    };
+    expected * 5 >= total
+}
+
+fn normalize_reasoning_effort(
+    value: Option<&str>,
+) -> std::result::Result<Option<String>, ApiError> {
+    let Some(value) = value.map(|value| value.trim().to_ascii_lowercase()) else {
+        return Ok(None);
+    };
+    if value.is_empty() {
+        return Ok(None);
+    }
+    if !REASONING_EFFORT_LEVELS.contains(&value.as_str()) {
+        return Err(ApiError::bad_request(
+            "invalid_reasoning_effort",
+            "reasoning_effort must be low, medium, or high",
+        )
+        .with_details(serde_json::json!({ "allowed": REASONING_EFFORT_LEVELS })));
+    }
+    Ok(Some(value))
+}
+
+// A turn may ask for less effort than the default but never more than the server max. A
+// configured default outside the known levels is passed through untouched.
+fn resolve_reasoning_effort(requested: Option<&str>, default: &str, max: &str) -> String {
+    let rank = |effort: &str| {
+        REASONING_EFFORT_LEVELS
+            .iter()
+            .position(|level| *level == effort)
+    };
+    let effort = requested.unwrap_or(default);
+    match (rank(effort), rank(max)) {
+        (Some(effort_rank), Some(max_rank)) if effort_rank > max_rank => max.to_string(),
+        _ => effort.to_string(),
+    }
+}
+
+fn sanitize_ai_base_url(value: &str) -> Option<String> {
+    let parsed = Url::parse(value).ok()?;
+    if parsed.scheme() != "https" || parsed.host_str().is_none() {
+        return None;
+    }
+    Some(value.trim_end_matches('/').to_string())
+}
+
+fn build_anthropic_runtime_config() -> Option<AnthropicRuntimeConfig> {
+    let api_key = env::var("ATLAS_ANTHROPIC_API_KEY").ok()?;
+    let model =
+        env::var("ATLAS_ANTHROPIC_MODEL").unwrap_or_else(|_| "claude-sonnet-4-5".to_string());
+    let max_tokens = env::var("ATLAS_ANTHROPIC_MAX_TOKENS")
+        .ok()
+        .and_then(|value| value.parse::<u32>().ok())
+        .unwrap_or(2_048);
+    let base_url = env::var("ATLAS_ANTHROPIC_BASE_URL")
+        .ok()
+        .map(|value| value.trim().to_string())
+        .filter(|value| !value.is_empty())
+        .unwrap_or_else(|| DEFAULT_ANTHROPIC_BASE_URL.to_string());
+    let Some(base_url) = sanitize_ai_base_url(base_url.as_str()) else {
+        tracing::warn!(
+            anthropic_base_url = %base_url,
+            "ATLAS_ANTHROPIC_BASE_URL must be an https URL; premium runtime disabled"
+        );
+        return None;
+    };
+    tracing::info!(anthropic_base_url = %base_url, model = %model, "anthropic runtime configured");
+
+    Some(AnthropicRuntimeConfig {
+        api_key,
+        base_url,
+        model,
+        max_tokens,
+    })
+}
+
+fn build_ai_provider_runtime(
+    openai_runtime: Option<&OpenAiRuntimeConfig>,
+) -> Option<AiProviderRuntime> {
+    let provider = sanitize_enum_value(
+        env::var("ATLAS_AI_PROVIDER")
+            .ok()
+            .unwrap_or_else(|| "openai".to_string())
+            .as_str(),
+        &["openai", "anthropic"],
+        "openai",
+    );
+    match provider.as_str() {
+        "anthropic" => build_anthropic_runtime_config().map(AiProviderRuntime::Anthropic),
+        _ => openai_runtime.cloned().map(AiProviderRuntime::OpenAi),
+    }
+}
+
+fn openai_responses_url(runtime: &OpenAiRuntimeConfig) -> String {
+    format!("{}/responses", runtime.base_url)
+}
+
+fn build_billing_runtime_config() -> Option<BillingRuntimeConfig> {
+    let stripe_secret_key = env::var("ATLAS_STRIPE_SECRET_KEY").ok()?;
+    let monthly_price_id = env::var("ATLAS_STRIPE_MONTHLY_PRICE_ID").ok()?;
+    let success_url = env::var("ATLAS_STRIPE_SUCCESS_URL").unwrap_or_else(|_| {
+        "https://atlasmasa.com/concierge-local.html?billing=success".to_string()
+    });
+    let cancel_url = env::var("ATLAS_STRIPE_CANCEL_URL").unwrap_or_else(|_| {
+        "https://atlasmasa.com/concierge-local.html?billing=cancel".to_string()
+    });
+    let stripe_webhook_secret = env::var("ATLAS_STRIPE_WEBHOOK_SECRET")
+        .ok()
+        .filter(|value| !value.trim().is_empty());
+    let stripe_webhook_tolerance_seconds = env::var("ATLAS_STRIPE_WEBHOOK_TOLERANCE_SECONDS")
+        .ok()
+        .and_then(|value| value.trim().parse::<u64>().ok())
+        .map(|value| value.clamp(30, 86_400))
+        .unwrap_or(DEFAULT_STRIPE_WEBHOOK_TOLERANCE_SECONDS);
+
+    Some(BillingRuntimeConfig {
+        stripe_secret_key,
+        stripe_webhook_secret,
+        stripe_webhook_tolerance_seconds,
+        monthly_price_id,
+        success_url,
+        cancel_url,
+    })
+}
+
+#[cfg(feature = "redis")]
+type RateLimitRedis = Option<redis::aio::ConnectionManager>;
+#[cfg(not(feature = "redis"))]
+type RateLimitRedis = Option<()>;
+
+// `ATLAS_REDIS_URL` moves rate-limit counters into Redis so every instance shares them. A
+// configured but unreachable Redis fails startup rather than silently splitting the limits.
+async fn connect_rate_limit_redis() -> Result<RateLimitRedis> {
+    let Some(redis_url) = env::var("ATLAS_REDIS_URL")
+        .ok()
+        .map(|value| value.trim().to_string())
+        .filter(|value| !value.is_empty())
+    else {
+        return Ok(None);
+    };
+    #[cfg(feature = "redis")]
+    {
+        let client = redis::Client::open(redis_url.as_str()).context("invalid ATLAS_REDIS_URL")?;
+        let connection = redis::aio::ConnectionManager::new(client)
+            .await
+            .context("failed to connect to ATLAS_REDIS_URL")?;
+        Ok(Some(connection))
+    }
+    #[cfg(not(feature = "redis"))]
+    {
+        let _ = redis_url;
+        tracing::warn!(
+            "ATLAS_REDIS_URL is set but atlas-api was built without the `redis` feature; using in-memory rate limits"
+        );
+        Ok(None)
+    }
+}
+
+fn build_rate_limiter(
+    redis: &RateLimitRedis,
+    namespace: &str,
+    window: Duration,
+    max_requests: usize,
+) -> RateLimiter {
+    #[cfg(feature = "redis")]
+    if let Some(connection) = redis {
+        return RateLimiter::Redis(Box::new(crate::rate_limit::RedisRateLimiter::new(
+            connection.clone(),
+            namespace,
+            window,
+            max_requests,
+        )));
+    }
+    #[cfg(not(feature = "redis"))]
+    let _ = (redis, namespace);
+    RateLimiter::Local(IpRateLimiter::new(window, max_requests))
+}
+
+fn build_webauthn_runtime() -> anyhow::Result<Option<WebauthnRuntimeConfig>> {
+    let rp_id_env = env::var("ATLAS_WEBAUTHN_RP_ID").ok();
+    let origin_env = env::var("ATLAS_WEBAUTHN_ORIGIN").ok();
+    let configured = rp_id_env.is_some() || origin_env.is_some();
+    let rp_id = rp_id_env.unwrap_or_else(|| "atlasmasa.com".to_string());
+    let origin = origin_env.unwrap_or_else(|| "https://atlasmasa.com".to_string());
+    let rp_name = env::var("ATLAS_WEBAUTHN_RP_NAME")
+        .ok()
+        .unwrap_or_else(|| "Atlas/אטלס".to_string());
+    let strict = env::var("ATLAS_WEBAUTHN_STRICT")
+        .map(|value| matches!(value.trim(), "1" | "true"))
+        .unwrap_or(false);
+
+    let webauthn = match build_webauthn(rp_id.as_str(), origin.as_str(), rp_name.as_str()) {
+        Ok(webauthn) => webauthn,
+        Err(reason) => {
+            if strict && configured {
+                anyhow::bail!("passkey configuration is invalid: {reason}");
+            }
+            tracing::warn!(reason = %reason, "passkeys disabled: invalid relying party configuration");
+            return Ok(None);
+        }
+    };
+    let require_user_verification = env::var("ATLAS_WEBAUTHN_REQUIRE_UV")
+        .map(|value| matches!(value.trim(), "1" | "true"))
+        .unwrap_or(false);
+    let attestation = sanitize_enum_value(
+        env::var("ATLAS_WEBAUTHN_ATTESTATION")
+            .unwrap_or_else(|_| "none".to_string())
+            .trim()
+            .to_lowercase()
+            .as_str(),
+        &["none", "indirect", "direct"],
+        "none",
+    );
+
+    Ok(Some(WebauthnRuntimeConfig {
+        webauthn: Arc::new(webauthn),
+        require_user_verification,
+        attestation,
+    }))
+}
+
+fn build_webauthn(rp_id: &str, origin: &str, rp_name: &str) -> Result<Webauthn, String> {
+    let origin_url = Url::parse(origin)
+        .map_err(|err| format!("ATLAS_WEBAUTHN_ORIGIN `{origin}` is not a valid URL ({err})"))?;
+    let builder = WebauthnBuilder::new(rp_id, &origin_url)
+        .map_err(|err| {
+            format!("ATLAS_WEBAUTHN_RP_ID `{rp_id}` does not match origin `{origin}` ({err})")
+        })?
+        .rp_name(rp_name);
+    builder
+        .build()
+        .map_err(|err| format!("webauthn relying party could not be built ({err})"))
+}
+
+fn generate_urlsafe_token(bytes: usize) -> String {
+    let mut buffer = vec![0_u8; bytes];
+    rng().fill_bytes(buffer.as_mut_slice());
+    URL_SAFE_NO_PAD.encode(buffer)
+}
+
+fn sanitize_return_to(value: &str) -> String {
+    let cleaned = value.trim();
+    if is_safe_return_to(cleaned) {
+        return cleaned.to_string();
+    }
+    DEFAULT_RETURN_TO.to_string()
+}
+
+fn is_safe_return_to(value: &str) -> bool {
+    if value.contains('\\')
+        || value
+            .chars()
+            .any(|ch| ch.is_control() || ch.is_whitespace())
+    {
+        return false;
+    }
+    let path = value.split(['?', '#']).next().unwrap_or_default();
+    if !is_safe_return_path(path) {
+        return false;
+    }
+
+    // Browsers normalise encoded separators, so the path must stay safe after decoding
+    // (twice, to cover double-encoded payloads such as `%252F`).
+    let Some(decoded) = percent_decode_path(path) else {
+        return false;
+    };
+    let Some(double_decoded) = percent_decode_path(decoded.as_str()) else {
+        return false;
+    };
+    is_safe_return_path(decoded.as_str())
+        && is_safe_return_path(double_decoded.as_str())
+        && RETURN_TO_ALLOWED_PREFIXES
+            .iter()
+            .any(|prefix| double_decoded.starts_with(prefix))
+}
+
+fn is_safe_return_path(path: &str) -> bool {
+    path.starts_with('/')
+        && !path.starts_with("//")
+        && !path.contains('\\')
+        && !path.contains(':')
+        && !path.split('/').any(|segment| segment == "..")
+}
+
+fn percent_decode_path(value: &str) -> Option<String> {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut index = 0;
+    while index < bytes.len() {
+        if bytes[index] == b'%' {
+            let hex = value.get(index + 1..index + 3)?;
+            decoded.push(u8::from_str_radix(hex, 16).ok()?);
+            index += 3;
+        } else {
+            decoded.push(bytes[index]);
+            index += 1;
+        }
+    }
+    String::from_utf8(decoded).ok()
+}
+
+async fn verify_apple_id_token(
+    http_client: &Client,
+    id_token: &str,
+    expected_client_id: &str,
+) -> Result<AppleIdTokenClaims> {
+    let mut segments = id_token.split('.');
+    let header_segment = segments
+        .next()
+        .context("apple id_token missing header segment")?;
+    let payload_segment = segments
+        .next()
+        .context("apple id_token missing payload segment")?;
+    let signature_segment = segments
+        .next()
+        .context("apple id_token missing signature segment")?;
+    if segments.next().is_some() {
+        anyhow::bail!("apple id_token has invalid segment count");
+    }
+
+    let header_bytes = URL_SAFE_NO_PAD
+        .decode(header_segment)
+        .context("failed to decode apple id_token header segment")?;
+    let header: AppleJwtHeader =
+        serde_json::from_slice(&header_bytes).context("failed to parse apple id_token header")?;
+    if header.alg.as_deref() != Some("RS256") {
+        anyhow::bail!("unexpected apple id_token signing algorithm");
+    }
+    let Some(kid) = header.kid.as_deref() else {
+        anyhow::bail!("apple id_token missing kid");
+    };
+
+    let payload_bytes = URL_SAFE_NO_PAD
+        .decode(payload_segment)
+        .context("failed to decode apple id_token payload segment")?;
+    let claims: AppleIdTokenClaims =
+        serde_json::from_slice(&payload_bytes).context("failed to parse apple id_token claims")?;
+
+    let signature = URL_SAFE_NO_PAD
+        .decode(signature_segment)
+        .context("failed to decode apple id_token signature segment")?;
+
+    let jwks = http_client
+        .get("https://appleid.apple.com/auth/keys")
+        .send()
+        .await
+        .context("failed to fetch apple jwks")?
+        .error_for_status()
+        .context("apple jwks non-success status")?
+        .json::<AppleJwksResponse>()
+        .await
+        .context("failed to parse apple jwks")?;
+
+    let Some(jwk) = jwks.keys.into_iter().find(|record| {
+        let key_id_match = record.kid.as_deref() == Some(kid);
+        let key_type_ok = record.kty.as_deref().unwrap_or_default() == "RSA";
+        let alg_ok = record.alg.as_deref().unwrap_or_default() == "RS256";
+        key_id_match && key_type_ok && alg_ok
+    }) else {
+        anyhow::bail!("apple jwk for token kid not found");
+    };
+
+    let n = jwk.n.context("apple jwk missing modulus")?;
+    let e = jwk.e.context("apple jwk missing exponent")?;
+    let modulus = URL_SAFE_NO_PAD
+        .decode(n.as_bytes())
+        .context("failed to decode apple jwk modulus")?;
+    let exponent = URL_SAFE_NO_PAD
+        .decode(e.as_bytes())
+        .context("failed to decode apple jwk exponent")?;
+
+    let signed_payload = format!("{header_segment}.{payload_segment}");
+    let public_key = RsaPublicKeyComponents {
+        n: modulus.as_slice(),
+        e: exponent.as_slice(),
+    };
+    public_key
+        .verify(
+            &RSA_PKCS1_2048_8192_SHA256,
+            signed_payload.as_bytes(),
+            signature.as_slice(),
+        )
+        .map_err(|_| anyhow::anyhow!("apple id_token signature verification failed"))?;
+
+    let valid_iss = claims.iss.as_deref() == Some("https://appleid.apple.com");
+    if !valid_iss {
+        anyhow::bail!("apple id_token issuer mismatch");
+    }
+    let valid_aud = claims
+        .aud
+        .as_ref()
+        .map(|aud| aud.includes(expected_client_id))
+        .unwrap_or(false);
+    if !valid_aud {
+        anyhow::bail!("apple id_token audience mismatch");
+    }
+
+    Ok(claims)
+}
+
+fn bool_from_jsonish(value: &serde_json::Value) -> Option<bool> {
+    if let Some(parsed) = value.as_bool() {
+        return Some(parsed);
+    }
+    value.as_str().and_then(|parsed| match parsed {
+        "true" | "1" => Some(true),
+        "false" | "0" => Some(false),
+        _ => None,
+    })
+}
+
+// Replaces TraceLayer's default span: the path without its query string (which can carry user
+// ids) and the request id. No other headers are logged; user agents, IPs and referers identify
+// people as well as credentials do.
+fn request_span(request: &Request<Body>) -> tracing::Span {
+    tracing::debug_span!(
+        "request",
+        method = %request.method(),
+        path = %request.uri().path(),
+        version = ?request.version(),
+        request_id = %request_id_from_headers(request.headers()),
+    )
+}
+
+fn cloud_requirements_for_endpoint(path: &str) -> (bool, bool) {
+    let needs_cloud_storage = matches!(
+        path,
+        "/v1/profile/upsert"
+            | "/v1/notes"
+            | "/v1/notes/upsert"
+            | "/v1/notes/rewrite"
+            | "/v1/notes/rewrite_preview"
+            | "/v1/notes/delete"
+            | "/v1/memory/import"
+            | "/v1/memory/import_csv"
+            | "/v1/memory/records"
+            | "/v1/memory/export.jsonl"
+            | "/v1/memory/search"
+            | "/v1/memory/upsert"
+            | "/v1/memory/delete"
+            | "/v1/memory/clear"
+            | "/v1/memory/restore"
+            | "/v1/chat/history"
+            | "/v1/studio/preferences"
+            | "/v1/survey/next"
+            | "/v1/survey/answer"
+            | "/v1/feed/proactive"
+            | "/v1/execution/checkin"
+            | "/v1/execution/refresh"
+            | "/v1/execution/digest"
+            | "/v1/execution/controls"
+            | "/v1/feedback/submit"
+            | "/v1/actions/reminder"
+            | "/v1/actions/reminder/snooze"
+            | "/v1/actions/alarm"
+            | "/v1/actions/plan"
+    ) || path.starts_with("/v1/feedback/")
+        || path.starts_with("/v1/notes/")
+        || path.starts_with("/v1/memory/");
+
+    let needs_cloud_compute = matches!(
+        path,
+        "/v1/chat"
+            | "/v1/plan_trip"
+            | "/v1/notes/rewrite"
+            | "/v1/notes/rewrite_preview"
+            | "/v1/feed/proactive"
+            | "/v1/execution/refresh"
+            | "/v1/execution/digest"
+            | "/v1/actions/reminder"
+            | "/v1/actions/reminder/snooze"
+            | "/v1/actions/alarm"
+            | "/v1/actions/plan"
+    );
+
+    (needs_cloud_storage, needs_cloud_compute)
+}
+
+fn is_public_endpoint(path: &str) -> bool {
+    matches!(
+        path,
+        "/health"
+            | "/openapi.json"
+            | "/v1/auth/me"
+            | "/v1/auth/sessions"
+            | "/v1/auth/logout"
+            | "/v1/auth/google/start"
+            | "/v1/auth/google/callback"
+            | "/v1/auth/apple/start"
+            | "/v1/auth/apple/callback"
+            | "/v1/auth/passkey/register/start"
+            | "/v1/auth/passkey/register/finish"
+            | "/v1/auth/passkey/login/start"
+            | "/v1/auth/passkey/login/finish"
+            | "/v1/auth/recovery_codes/redeem"
+            | "/v1/billing/stripe_webhook"
+    )
+}
+
+// The CREATE TABLE statements below are the frozen baseline schema. Column and index changes
+// go into `schema_migrations::MIGRATIONS`, which runs after the baseline.
+async fn ensure_app_schema(pool: &SqlitePool) -> Result<()> {
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS auth_users (
+          user_id TEXT PRIMARY KEY,
+          provider TEXT NOT NULL,
+          email TEXT NOT NULL,
+          name TEXT NOT NULL,
+          locale TEXT NOT NULL,
+          trip_style TEXT,
+          risk_preference TEXT,
+          memory_opt_in INTEGER NOT NULL,
+          passkey_user_handle TEXT,
+          created_at TEXT NOT NULL,
+          updated_at TEXT NOT NULL
+        );
+        "#,
+    )
+    .execute(pool)
+    .await?;
+
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS auth_sessions (
+          session_id TEXT PRIMARY KEY,
+          user_id TEXT NOT NULL,
+          expires_at TEXT NOT NULL,
+          created_at TEXT NOT NULL
+        );
+        "#,
+    )
+    .execute(pool)
+    .await?;
+
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS studio_preferences (
+          user_id TEXT PRIMARY KEY,
+          data_json TEXT NOT NULL
+        );
+        "#,
+    )
+    .execute(pool)
+    .await?;
+
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS survey_states (
+          user_id TEXT PRIMARY KEY,
+          data_json TEXT NOT NULL
+        );
+        "#,
+    )
+    .execute(pool)
+    .await?;
+
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS feedback_items (
+          feedback_id TEXT PRIMARY KEY,
+          data_json TEXT NOT NULL
+        );
+        "#,
+    )
+    .execute(pool)
+    .await?;
+
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS user_notes (
+          note_id TEXT PRIMARY KEY,
+          user_id TEXT NOT NULL,
+          data_json TEXT NOT NULL
+        );
+        "#,
+    )
+    .execute(pool)
+    .await?;
+
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS user_memories (
+          memory_id TEXT PRIMARY KEY,
+          user_id TEXT NOT NULL,
+          data_json TEXT NOT NULL
+        );
+        "#,
+    )
+    .execute(pool)
+    .await?;
+
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS chat_turns (
+          turn_id TEXT PRIMARY KEY,
+          user_id TEXT NOT NULL,
+          session_id TEXT NOT NULL,
+          data_json TEXT NOT NULL
+        );
+        "#,
+    )
+    .execute(pool)
+    .await?;
+
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS execution_checkins (
+          checkin_id TEXT PRIMARY KEY,
+          user_id TEXT NOT NULL,
+          data_json TEXT NOT NULL
+        );
+        "#,
+    )
+    .execute(pool)
+    .await?;
+
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS execution_controls (
+          user_id TEXT PRIMARY KEY,
+          data_json TEXT NOT NULL
+        );
+        "#,
+    )
+    .execute(pool)
+    .await?;
+
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS passkeys (
+          passkey_id TEXT PRIMARY KEY,
+          user_id TEXT NOT NULL,
+          data_json TEXT NOT NULL
+        );
+        "#,
+    )
+    .execute(pool)
+    .await?;
+
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS recovery_codes (
+          code_id TEXT PRIMARY KEY,
+          user_id TEXT NOT NULL,
+          data_json TEXT NOT NULL
+        );
+        "#,
+    )
+    .execute(pool)
+    .await?;
+
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS linked_identities (
+          identity_key TEXT PRIMARY KEY,
+          user_id TEXT NOT NULL,
+          data_json TEXT NOT NULL
+        );
+        "#,
+    )
+    .execute(pool)
+    .await?;
+
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS company_status (
+          id INTEGER PRIMARY KEY CHECK (id = 1),
+          data_json TEXT NOT NULL,
+          updated_at TEXT NOT NULL
+        );
+        "#,
+    )
+    .execute(pool)
+    .await?;
+
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS usage_counters (
+          user_id TEXT PRIMARY KEY,
+          period TEXT NOT NULL,
+          call_count INTEGER NOT NULL,
+          token_estimate INTEGER NOT NULL,
+          updated_at TEXT NOT NULL
+        );
+        "#,
+    )
+    .execute(pool)
+    .await?;
+
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS billing_subscriptions (
+          user_id TEXT PRIMARY KEY,
+          stripe_customer_id TEXT,
+          stripe_subscription_id TEXT,
+          status TEXT NOT NULL,
+          current_period_end TEXT,
+          updated_at TEXT NOT NULL
+        );
+        "#,
+    )
+    .execute(pool)
+    .await?;
+
+    apply_migrations(pool, MIGRATIONS).await?;
+    Ok(())
+}
+
+async fn load_persistent_state(pool: Option<&SqlitePool>) -> Result<PersistedState> {
+    let Some(pool) = pool else {
+        return Ok(PersistedState::default());
+    };
+
+    let mut state = PersistedState::default();
+
+    let users = sqlx::query(
+        r#"
+        SELECT user_id, provider, email, name, locale, trip_style, risk_preference, memory_opt_in, passkey_user_handle, created_at, updated_at, last_login_at, login_count
+        FROM auth_users
+        "#,
+    )
+    .fetch_all(pool)
+    .await?;
+    for row in users {
+        let user = UserRecord {
+            user_id: row.get("user_id"),
+            provider: row.get("provider"),
+            email: row.get("email"),
+            name: row.get("name"),
+            locale: row.get("locale"),
+            trip_style: row.get("trip_style"),
+            risk_preference: row.get("risk_preference"),
+            memory_opt_in: row.get::<i64, _>("memory_opt_in") > 0,
+            passkey_user_handle: row.get("passkey_user_handle"),
+            created_at: row.get("created_at"),
+            updated_at: row.get("updated_at"),
+            last_login_at: row.get("last_login_at"),
+            login_count: row.get::<i64, _>("login_count").max(0) as u64,
+        };
+        state.users.insert(user.user_id.clone(), user);
+    }
+
+    let sessions =
+        sqlx::query("SELECT session_id, user_id, expires_at, created_at, client_network, region FROM auth_sessions")
+            .fetch_all(pool)
+            .await?;
+    // Session rows are only written one at a time, so expired ones are dropped here instead of
+    // by a later full-table rewrite.
+    let now = chrono::Utc::now();
+    for row in sessions {
+        let expires_at = row
+            .get::<String, _>("expires_at")
+            .parse()
+            .unwrap_or_else(|_| chrono::Utc::now());
+        if expires_at <= now {
+            delete_session_row(pool, row.get::<String, _>("session_id").as_str()).await?;
+            continue;
+        }
+        let created_at = row
+            .get::<String, _>("created_at")
+            .parse()
+            .unwrap_or_else(|_| chrono::Utc::now());
+        state.sessions.insert(
+            row.get("session_id"),
+            SessionRecord {
+                user_id: row.get("user_id"),
+                expires_at,
+                created_at,
+                client_network: row.get("client_network"),
+                region: row.get("region"),
+            },
+        );
+    }
+
+    let studio = sqlx::query("SELECT user_id, data_json FROM studio_preferences")
+        .fetch_all(pool)
+        .await?;
+    for row in studio {
+        let json: String = row.get("data_json");
+        if let Ok(value) = serde_json::from_str::<StudioPreferencesRecord>(&json) {
+            state.studio_preferences.insert(row.get("user_id"), value);
+        }
+    }
+
+    let surveys = sqlx::query("SELECT user_id, data_json FROM survey_states")
+        .fetch_all(pool)
+        .await?;
+    for row in surveys {
+        let json: String = row.get("data_json");
+        if let Ok(value) = serde_json::from_str::<SurveyStateRecord>(&json) {
+            state.survey_states.insert(row.get("user_id"), value);
+        }
+    }
+
+    let feedback = sqlx::query("SELECT data_json FROM feedback_items")
+        .fetch_all(pool)
+        .await?;
+    for row in feedback {
+        let json: String = row.get("data_json");
+        if let Ok(value) = serde_json::from_str::<FeedbackRecord>(&json) {
+            state.feedback_items.push(value);
+        }
+    }
+
+    let notes = sqlx::query("SELECT user_id, data_json FROM user_notes")
+        .fetch_all(pool)
+        .await?;
+    for row in notes {
+        let json: String = row.get("data_json");
+        if let Ok(value) = serde_json::from_str::<UserNoteRecord>(&json) {
+            state
+                .user_notes
+                .entry(row.get("user_id"))
+                .or_default()
+                .push(value);
+        }
+    }
+
+    let note_versions = sqlx::query("SELECT user_id, data_json FROM note_versions ORDER BY rowid")
+        .fetch_all(pool)
+        .await?;
+    for row in note_versions {
+        let json: String = row.get("data_json");
+        if let Ok(value) = serde_json::from_str::<NoteVersionRecord>(&json) {
+            state
+                .note_versions
+                .entry(row.get("user_id"))
+                .or_default()
+                .push(value);
+        }
+    }
+
+    let notes_revisions = sqlx::query("SELECT user_id, revision, modified_at FROM notes_revisions")
+        .fetch_all(pool)
+        .await?;
+    for row in notes_revisions {
+        let modified_at: String = row.get("modified_at");
+        let Ok(modified_at) = chrono::DateTime::parse_from_rfc3339(modified_at.as_str()) else {
+            continue;
+        };
+        state.notes_revisions.insert(
+            row.get("user_id"),
+            NotesRevision {
+                revision: row.get::<i64, _>("revision").max(0) as u64,
+                modified_at: modified_at.with_timezone(&chrono::Utc),
+            },
+        );
+    }
+
+    let memories = sqlx::query("SELECT user_id, data_json FROM user_memories")
+        .fetch_all(pool)
+        .await?;
+    for row in memories {
+        let json: String = row.get("data_json");
+        if let Ok(value) = serde_json::from_str::<MemoryRecord>(&json) {
+            state
+                .user_memories
+                .entry(row.get("user_id"))
+                .or_default()
+                .push(value);
+        }
+    }
+
+    let trashed = sqlx::query(
+        "SELECT user_id, restore_id, deleted_at, data_json FROM deleted_memories ORDER BY rowid",
+    )
+    .fetch_all(pool)
+    .await?;
+    for row in trashed {
+        let json: String = row.get("data_json");
+        let deleted_at: String = row.get("deleted_at");
+        let (Ok(memory), Ok(deleted_at)) = (
+            serde_json::from_str::<MemoryRecord>(&json),
+            chrono::DateTime::parse_from_rfc3339(deleted_at.as_str()),
+        ) else {
+            continue;
+        };
+        state
+            .deleted_memories
+            .entry(row.get("user_id"))
+            .or_default()
+            .push(TrashedMemory {
+                memory,
+                restore_id: row.get("restore_id"),
+                deleted_at: deleted_at.with_timezone(&chrono::Utc),
+            });
+    }
+
+    let chat_turns = sqlx::query("SELECT user_id, data_json FROM chat_turns ORDER BY rowid")
+        .fetch_all(pool)
+        .await?;
+    for row in chat_turns {
+        let json: String = row.get("data_json");
+        if let Ok(value) = serde_json::from_str::<ChatTurnRecord>(&json) {
+            state
+                .chat_turns
+                .entry(row.get("user_id"))
+                .or_default()
+                .push(value);
+        }
+    }
+
+    let checkins = sqlx::query("SELECT user_id, data_json FROM execution_checkins")
+        .fetch_all(pool)
+        .await?;
+    for row in checkins {
+        let json: String = row.get("data_json");
+        if let Ok(value) = serde_json::from_str::<ExecutionCheckinRecord>(&json) {
+            state
+                .execution_checkins
+                .entry(row.get("user_id"))
+                .or_default()
+                .push(value);
+        }
+    }
+
+    let controls = sqlx::query("SELECT user_id, data_json FROM execution_controls")
+        .fetch_all(pool)
+        .await?;
+    for row in controls {
+        let json: String = row.get("data_json");
+        if let Ok(value) = serde_json::from_str::<ExecutionControlsRecord>(&json) {
+            state.execution_controls.insert(row.get("user_id"), value);
+        }
+    }
+
+    let passkeys = sqlx::query("SELECT user_id, data_json FROM passkeys")
+        .fetch_all(pool)
+        .await?;
+    for row in passkeys {
+        let json: String = row.get("data_json");
+        if let Ok(value) = serde_json::from_str::<PasskeyRecord>(&json) {
+            state
+                .passkeys_by_user
+                .entry(row.get("user_id"))
+                .or_default()
+                .push(value);
+        }
+    }
+
+    let recovery_codes = sqlx::query("SELECT user_id, data_json FROM recovery_codes")
+        .fetch_all(pool)
+        .await?;
+    for row in recovery_codes {
+        let json: String = row.get("data_json");
+        if let Ok(value) = serde_json::from_str::<RecoveryCodeRecord>(&json) {
+            state
+                .recovery_codes
+                .entry(row.get("user_id"))
+                .or_default()
+                .push(value);
+        }
+    }
+
+    let linked = sqlx::query("SELECT identity_key, data_json FROM linked_identities")
+        .fetch_all(pool)
+        .await?;
+    for row in linked {
+        let json: String = row.get("data_json");
+        if let Ok(value) = serde_json::from_str::<LinkedIdentityRecord>(&json) {
+            state
+                .linked_identities
+                .insert(row.get("identity_key"), value);
+        }
+    }
+
+    let usage = sqlx::query(
+        "SELECT user_id, period, call_count, token_estimate, updated_at FROM usage_counters",
+    )
+    .fetch_all(pool)
+    .await?;
+    for row in usage {
+        let counter = AiUsageCounterRecord {
+            user_id: row.get("user_id"),
+            period: row.get("period"),
+            call_count: row.get::<i64, _>("call_count").max(0) as u32,
+            token_estimate: row.get::<i64, _>("token_estimate").max(0) as u64,
+            updated_at: row.get("updated_at"),
+        };
+        state
+            .ai_usage_counters
+            .insert(counter.user_id.clone(), counter);
+    }
+
+    let company_status = sqlx::query("SELECT data_json FROM company_status WHERE id = 1")
+        .fetch_optional(pool)
+        .await?;
+    if let Some(row) = company_status {
+        let data_json: String = row.get("data_json");
+        state.company_status = serde_json::from_str::<CompanyStatusRecord>(&data_json).ok();
+    }
+
+    Ok(state)
+}
+
+async fn persist_user_if_configured(state: &ApiState, user: &UserRecord) -> Result<()> {
+    #[cfg(feature = "postgres")]
+    if let Some(pool) = state.pg_pool.as_ref() {
+        return postgres_state::persist_user(pool, user).await;
+    }
+    let Some(pool) = state.db_pool.as_ref() else {
+        return Ok(());
+    };
+
+    sqlx::query(
+        r#"
+        INSERT INTO auth_users (user_id, provider, email, name, locale, trip_style, risk_preference, memory_opt_in, passkey_user_handle, created_at, updated_at, last_login_at, login_count)
+        VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13)
+        ON CONFLICT(user_id) DO UPDATE SET
+          provider=excluded.provider,
+          email=excluded.email,
+          name=excluded.name,
+          locale=excluded.locale,
+          trip_style=excluded.trip_style,
+          risk_preference=excluded.risk_preference,
+          memory_opt_in=excluded.memory_opt_in,
+          passkey_user_handle=excluded.passkey_user_handle,
+          updated_at=excluded.updated_at,
+          last_login_at=excluded.last_login_at,
+          login_count=excluded.login_count
+        "#,
+    )
+    .bind(user.user_id.as_str())
+    .bind(user.provider.as_str())
+    .bind(user.email.as_str())
+    .bind(user.name.as_str())
+    .bind(user.locale.as_str())
+    .bind(user.trip_style.as_deref())
+    .bind(user.risk_preference.as_deref())
+    .bind(if user.memory_opt_in { 1_i64 } else { 0_i64 })
+    .bind(user.passkey_user_handle.as_deref())
+    .bind(user.created_at.as_str())
+    .bind(user.updated_at.as_str())
+    .bind(user.last_login_at.as_deref())
+    .bind(user.login_count as i64)
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+async fn persist_studio_preferences_if_configured(state: &ApiState, user_id: &str) -> Result<()> {
+    let Some(pool) = state.db_pool.as_ref() else {
+        return Ok(());
+    };
+    let value = state
+        .studio_preferences
+        .read()
+        .get(user_id)
+        .cloned()
+        .unwrap_or_else(|| default_studio_preferences(user_id));
+    let json = serde_json::to_string(&value)?;
+    sqlx::query(
+        r#"
+        INSERT INTO studio_preferences (user_id, data_json)
+        VALUES (?1, ?2)
+        ON CONFLICT(user_id) DO UPDATE SET data_json=excluded.data_json
+        "#,
+    )
+    .bind(user_id)
+    .bind(json)
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+async fn persist_survey_state_if_configured(state: &ApiState, user_id: &str) -> Result<()> {
+    let Some(pool) = state.db_pool.as_ref() else {
+        return Ok(());
+    };
+    let Some(value) = state.survey_states.read().get(user_id).cloned() else {
+        return Ok(());
+    };
+    let json = serde_json::to_string(&value)?;
+    sqlx::query(
+        r#"
+        INSERT INTO survey_states (user_id, data_json)
+        VALUES (?1, ?2)
+        ON CONFLICT(user_id) DO UPDATE SET data_json=excluded.data_json
+        "#,
+    )
+    .bind(user_id)
+    .bind(json)
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+// Writes only the given rows, in one transaction, so a bulk resolve does not rewrite the
+// whole table.
+async fn persist_feedback_items_if_configured(
+    state: &ApiState,
+    items: &[FeedbackRecord],
+) -> Result<()> {
+    let Some(pool) = state.db_pool.as_ref() else {
+        return Ok(());
+    };
+    let mut tx = pool.begin().await?;
+    for item in items {
+        let json = serde_json::to_string(item)?;
+        sqlx::query(
+            r#"
+            INSERT INTO feedback_items (feedback_id, data_json)
+            VALUES (?1, ?2)
+            ON CONFLICT(feedback_id) DO UPDATE SET data_json=excluded.data_json
+            "#,
+        )
+        .bind(item.feedback_id.as_str())
+        .bind(json)
+        .execute(&mut *tx)
+        .await?;
+    }
+    tx.commit().await?;
+    Ok(())
+}
+
+// Writes only the session that changed: an upsert when it still exists, a delete when it was
+// revoked. Rewriting the whole table would cost O(sessions) per login and, with shared auth
+// state, drop sessions issued by other instances.
+async fn persist_session_if_configured(state: &ApiState, session_id: &str) -> Result<()> {
+    let session = state.sessions.read().get(session_id).cloned();
+    if let Some(shared) = state.shared_auth.as_ref() {
+        return match session {
+            Some(session) => shared.upsert_session(session_id, &session).await,
+            None => shared.delete_session(session_id).await,
+        };
+    }
+    #[cfg(feature = "postgres")]
+    if let Some(pool) = state.pg_pool.as_ref() {
+        return match session {
+            Some(session) => postgres_state::upsert_session(pool, session_id, &session).await,
+            None => postgres_state::delete_session(pool, session_id).await,
+        };
+    }
+    let Some(pool) = state.db_pool.as_ref() else {
+        return Ok(());
+    };
+    match session {
+        Some(session) => upsert_session_row(pool, session_id, &session).await?,
+        None => delete_session_row(pool, session_id).await?,
+    };
+    Ok(())
+}
+
+/// Returns the number of rows written, which is always one.
+async fn upsert_session_row(
+    pool: &SqlitePool,
+    session_id: &str,
+    session: &SessionRecord,
+) -> Result<u64> {
+    let result = sqlx::query(
+        r#"
+        INSERT INTO auth_sessions (session_id, user_id, expires_at, created_at, client_network, region)
+        VALUES (?1, ?2, ?3, ?4, ?5, ?6)
+        ON CONFLICT(session_id) DO UPDATE SET expires_at=excluded.expires_at
+        "#,
+    )
+    .bind(session_id)
+    .bind(session.user_id.as_str())
+    .bind(session.expires_at.to_rfc3339())
+    .bind(session.created_at.to_rfc3339())
+    .bind(session.client_network.as_deref())
+    .bind(session.region.as_deref())
+    .execute(pool)
+    .await?;
+    Ok(result.rows_affected())
+}
+
+async fn delete_session_row(pool: &SqlitePool, session_id: &str) -> Result<u64> {
+    let result = sqlx::query("DELETE FROM auth_sessions WHERE session_id = ?1")
+        .bind(session_id)
+        .execute(pool)
+        .await?;
+    Ok(result.rows_affected())
+}
+
+/// Bumps the caller's notes revision and persists the notes with it. Every note write, import and
+/// delete goes through here, so the notes list ETag changes whenever the list does.
+async fn commit_notes_change(state: &ApiState, user_id: &str) -> Result<()> {
+    let revision = {
+        let now = chrono::Utc::now();
+        let mut revisions = state.notes_revisions.write();
+        let entry = revisions
+            .entry(user_id.to_string())
+            .or_insert(NotesRevision {
+                revision: 0,
+                modified_at: now,
+            });
+        entry.revision += 1;
+        entry.modified_at = now;
+        *entry
+    };
+    persist_notes_if_configured(state, user_id).await?;
+    let Some(pool) = state.db_pool.as_ref() else {
+        return Ok(());
+    };
+    sqlx::query(
+        "INSERT OR REPLACE INTO notes_revisions (user_id, revision, modified_at) VALUES (?1, ?2, ?3)",
+    )
+    .bind(user_id)
+    .bind(revision.revision as i64)
+    .bind(
+        revision
+            .modified_at
+            .to_rfc3339_opts(chrono::SecondsFormat::Micros, true),
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+async fn persist_notes_if_configured(state: &ApiState, user_id: &str) -> Result<()> {
+    let Some(pool) = state.db_pool.as_ref() else {
+        return Ok(());
+    };
+    sqlx::query("DELETE FROM user_notes WHERE user_id = ?1")
+        .bind(user_id)
+        .execute(pool)
+        .await?;
+    let notes = state
+        .user_notes
+        .read()
+        .get(user_id)
+        .cloned()
+        .unwrap_or_default();
+    for note in notes {
+        let json = serde_json::to_string(&note)?;
+        sqlx::query("INSERT INTO user_notes (note_id, user_id, data_json) VALUES (?1, ?2, ?3)")
+            .bind(note.note_id)
+            .bind(user_id)
+            .bind(json)
+            .execute(pool)
+            .await?;
+    }
+    Ok(())
+}
+
+async fn persist_note_versions_if_configured(state: &ApiState, user_id: &str) -> Result<()> {
+    let Some(pool) = state.db_pool.as_ref() else {
+        return Ok(());
+    };
+    sqlx::query("DELETE FROM note_versions WHERE user_id = ?1")
+        .bind(user_id)
+        .execute(pool)
+        .await?;
+    let versions = state
+        .note_versions
+        .read()
+        .get(user_id)
+        .cloned()
+        .unwrap_or_default();
+    for version in versions {
+        let json = serde_json::to_string(&version)?;
+        sqlx::query(
+            "INSERT INTO note_versions (version_id, note_id, user_id, data_json) VALUES (?1, ?2, ?3, ?4)",
+        )
+        .bind(version.version_id)
+        .bind(version.note_id)
+        .bind(user_id)
+        .bind(json)
+        .execute(pool)
+        .await?;
+    }
+    Ok(())
+}
+
+async fn persist_checkins_if_configured(state: &ApiState, user_id: &str) -> Result<()> {
+    let Some(pool) = state.db_pool.as_ref() else {
+        return Ok(());
+    };
+    sqlx::query("DELETE FROM execution_checkins WHERE user_id = ?1")
+        .bind(user_id)
+        .execute(pool)
+        .await?;
+    let checkins = state
+        .execution_checkins
+        .read()
+        .get(user_id)
+        .cloned()
+        .unwrap_or_default();
+    for checkin in checkins {
+        let json = serde_json::to_string(&checkin)?;
+        sqlx::query(
+            "INSERT INTO execution_checkins (checkin_id, user_id, data_json) VALUES (?1, ?2, ?3)",
+        )
+        .bind(checkin.checkin_id)
+        .bind(user_id)
+        .bind(json)
+        .execute(pool)
+        .await?;
+    }
+    Ok(())
+}
+
+async fn persist_execution_controls_if_configured(state: &ApiState, user_id: &str) -> Result<()> {
+    let Some(pool) = state.db_pool.as_ref() else {
+        return Ok(());
+    };
+    let Some(controls) = state.execution_controls.read().get(user_id).cloned() else {
+        return Ok(());
+    };
+    let json = serde_json::to_string(&controls)?;
+    sqlx::query(
+        r#"
+        INSERT INTO execution_controls (user_id, data_json)
+        VALUES (?1, ?2)
+        ON CONFLICT(user_id) DO UPDATE SET data_json=excluded.data_json
+        "#,
+    )
+    .bind(user_id)
+    .bind(json)
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+async fn persist_memories_if_configured(state: &ApiState, user_id: &str) -> Result<()> {
+    let Some(pool) = state.db_pool.as_ref() else {
+        return Ok(());
+    };
+    sqlx::query("DELETE FROM user_memories WHERE user_id = ?1")
+        .bind(user_id)
+        .execute(pool)
+        .await?;
+    let memories = state
+        .user_memories
+        .read()
+        .get(user_id)
+        .cloned()
+        .unwrap_or_default();
+    for memory in memories
+        .into_iter()
+        .filter(|memory| !state.ephemeral_memory_types.contains(&memory.memory_type))
+    {
+        let json = serde_json::to_string(&memory)?;
+        sqlx::query(
+            "INSERT INTO user_memories (memory_id, user_id, data_json) VALUES (?1, ?2, ?3)",
+        )
+        .bind(memory.memory_id)
+        .bind(user_id)
+        .bind(json)
+        .execute(pool)
+        .await?;
+    }
+    Ok(())
+}
+
+/// Deletes only the given memory rows, leaving the rest of the user's set untouched.
+async fn delete_memories_if_configured(
+    state: &ApiState,
+    user_id: &str,
+    memory_ids: &[&str],
+) -> Result<()> {
+    let Some(pool) = state.db_pool.as_ref() else {
+        return Ok(());
+    };
+    // Chunked to stay well under SQLite's bound-parameter limit.
+    for chunk in memory_ids.chunks(500) {
+        let mut query =
+            sqlx::QueryBuilder::<sqlx::Sqlite>::new("DELETE FROM user_memories WHERE user_id = ");
+        query.push_bind(user_id).push(" AND memory_id IN (");
+        let mut ids = query.separated(", ");
+        for memory_id in chunk {
+            ids.push_bind(*memory_id);
+        }
+        ids.push_unseparated(")");
+        query.build().execute(pool).await?;
+    }
+    Ok(())
+}
+
+async fn persist_trashed_memories_if_configured(
+    state: &ApiState,
+    user_id: &str,
+    trashed: &[TrashedMemory],
+) -> Result<()> {
+    let Some(pool) = state.db_pool.as_ref() else {
+        return Ok(());
+    };
+    // Ephemeral types never reach `user_memories`, so they stay out of the trash table too.
+    for entry in trashed.iter().filter(|entry| {
+        !state
+            .ephemeral_memory_types
+            .contains(&entry.memory.memory_type)
+    }) {
+        let json = serde_json::to_string(&entry.memory)?;
+        sqlx::query(
+            "INSERT OR REPLACE INTO deleted_memories (restore_id, memory_id, user_id, deleted_at, data_json) VALUES (?1, ?2, ?3, ?4, ?5)",
+        )
+        .bind(entry.restore_id.as_str())
+        .bind(entry.memory.memory_id.as_str())
+        .bind(user_id)
+        .bind(entry.deleted_at.to_rfc3339())
+        .bind(json)
+        .execute(pool)
+        .await?;
+    }
+    Ok(())
+}
+
+/// Drops trash rows by `(restore_id, memory_id)` once they have been restored or skipped.
+async fn delete_trashed_memories_if_configured(
+    state: &ApiState,
+    entries: &[(String, String)],
+) -> Result<()> {
+    let Some(pool) = state.db_pool.as_ref() else {
+        return Ok(());
+    };
+    for (restore_id, memory_id) in entries {
+        sqlx::query("DELETE FROM deleted_memories WHERE restore_id = ?1 AND memory_id = ?2")
+            .bind(restore_id)
+            .bind(memory_id)
+            .execute(pool)
+            .await?;
+    }
+    Ok(())
+}
+
+async fn purge_memory_trash_if_configured(state: &ApiState, user_id: &str) -> Result<()> {
+    let Some(pool) = state.db_pool.as_ref() else {
+        return Ok(());
+    };
+    sqlx::query("DELETE FROM deleted_memories WHERE user_id = ?1")
+        .bind(user_id)
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+/// Rewrites only the rows of the session that just changed.
+async fn persist_chat_session_if_configured(
+    state: &ApiState,
+    user_id: &str,
+    session_id: &str,
+) -> Result<()> {
+    let Some(pool) = state.db_pool.as_ref() else {
+        return Ok(());
+    };
+    let turns = state
+        .chat_turns
+        .read()
+        .get(user_id)
+        .map(|turns| {
+            turns
+                .iter()
+                .filter(|turn| turn.session_id == session_id)
+                .cloned()
+                .collect::<Vec<_>>()
+        })
+        .unwrap_or_default();
+    let mut tx = pool.begin().await?;
+    sqlx::query("DELETE FROM chat_turns WHERE user_id = ?1 AND session_id = ?2")
+        .bind(user_id)
+        .bind(session_id)
+        .execute(&mut *tx)
+        .await?;
+    for turn in turns {
+        let json = serde_json::to_string(&turn)?;
+        sqlx::query(
+            "INSERT INTO chat_turns (turn_id, user_id, session_id, data_json) VALUES (?1, ?2, ?3, ?4)",
+        )
+        .bind(turn.turn_id)
+        .bind(user_id)
+        .bind(turn.session_id)
+        .bind(json)
+        .execute(&mut *tx)
+        .await?;
+    }
+    tx.commit().await?;
+    Ok(())
+}
+
+/// Deletes one session's rows, or all of the user's when `session_id` is `None`.
+async fn delete_chat_turns_if_configured(
+    state: &ApiState,
+    user_id: &str,
+    session_id: Option<&str>,
+) -> Result<()> {
+    let Some(pool) = state.db_pool.as_ref() else {
+        return Ok(());
+    };
+    match session_id {
+        Some(session_id) => {
+            sqlx::query("DELETE FROM chat_turns WHERE user_id = ?1 AND session_id = ?2")
+                .bind(user_id)
+                .bind(session_id)
+                .execute(pool)
+                .await?;
+        }
+        None => {
+            sqlx::query("DELETE FROM chat_turns WHERE user_id = ?1")
+                .bind(user_id)
+                .execute(pool)
+                .await?;
+        }
+    }
+    Ok(())
+}
+
+async fn persist_passkeys_if_configured(state: &ApiState, user_id: &str) -> Result<()> {
+    #[cfg(feature = "postgres")]
+    if let Some(pool) = state.pg_pool.as_ref() {
+        let records = state
+            .passkeys_by_user
+            .read()
+            .get(user_id)
+            .cloned()
+            .unwrap_or_default();
+        return postgres_state::replace_passkeys(pool, user_id, &records).await;
+    }
+    let Some(pool) = state.db_pool.as_ref() else {
+        return Ok(());
+    };
+    sqlx::query("DELETE FROM passkeys WHERE user_id = ?1")
+        .bind(user_id)
+        .execute(pool)
+        .await?;
+    let records = state
+        .passkeys_by_user
+        .read()
+        .get(user_id)
+        .cloned()
+        .unwrap_or_default();
+    for record in records {
+        let json = serde_json::to_string(&record)?;
+        sqlx::query("INSERT INTO passkeys (passkey_id, user_id, data_json) VALUES (?1, ?2, ?3)")
+            .bind(record.passkey_id)
+            .bind(user_id)
+            .bind(json)
+            .execute(pool)
+            .await?;
+    }
+    Ok(())
+}
+
+// Returns whether the code was found; marking requires it unused, clearing requires it used.
+fn set_recovery_code_used(
+    state: &ApiState,
+    user_id: &str,
+    code_id: &str,
+    used_at: Option<String>,
+) -> bool {
+    state
+        .recovery_codes
+        .write()
+        .get_mut(user_id)
+        .and_then(|records| {
+            records.iter_mut().find(|record| {
+                record.code_id == code_id && record.used_at.is_none() == used_at.is_some()
+            })
+        })
+        .map(|record| record.used_at = used_at)
+        .is_some()
+}
+
+// Without a database the secret lives only as long as the process, like the state it keys.
+async fn load_or_create_secret(pool: Option<&SqlitePool>, name: &str) -> Result<String> {
+    let generated = generate_urlsafe_token(32);
+    let Some(pool) = pool else {
+        return Ok(generated);
+    };
+    sqlx::query(
+        "INSERT INTO app_secrets (name, value) VALUES (?1, ?2) ON CONFLICT(name) DO NOTHING",
+    )
+    .bind(name)
+    .bind(generated)
+    .execute(pool)
+    .await?;
+    let value = sqlx::query("SELECT value FROM app_secrets WHERE name = ?1")
+        .bind(name)
+        .fetch_one(pool)
+        .await?
+        .get("value");
+    Ok(value)
+}
+
+async fn persist_recovery_codes_if_configured(state: &ApiState, user_id: &str) -> Result<()> {
+    #[cfg(feature = "postgres")]
+    if let Some(pool) = state.pg_pool.as_ref() {
+        let records = state
+            .recovery_codes
+            .read()
+            .get(user_id)
+            .cloned()
+            .unwrap_or_default();
+        return postgres_state::replace_recovery_codes(pool, user_id, &records).await;
+    }
+    let Some(pool) = state.db_pool.as_ref() else {
+        return Ok(());
+    };
+    sqlx::query("DELETE FROM recovery_codes WHERE user_id = ?1")
+        .bind(user_id)
+        .execute(pool)
+        .await?;
+    let records = state
+        .recovery_codes
+        .read()
+        .get(user_id)
+        .cloned()
+        .unwrap_or_default();
+    for record in records {
+        let json = serde_json::to_string(&record)?;
+        sqlx::query("INSERT INTO recovery_codes (code_id, user_id, data_json) VALUES (?1, ?2, ?3)")
+            .bind(record.code_id)
+            .bind(user_id)
+            .bind(json)
+            .execute(pool)
+            .await?;
+    }
+    Ok(())
+}
+
+// Returns false when the identity is already stored for a different user; another instance won
+// the race, so the caller must not treat the link as made.
+async fn persist_linked_identity_if_configured(
+    state: &ApiState,
+    record: &LinkedIdentityRecord,
+) -> Result<bool> {
+    #[cfg(feature = "postgres")]
+    if let Some(pool) = state.pg_pool.as_ref() {
+        let key = linked_identity_key(record.provider.as_str(), record.subject.as_str());
+        return postgres_state::persist_linked_identity(pool, key.as_str(), record).await;
+    }
+    let Some(pool) = state.db_pool.as_ref() else {
+        return Ok(true);
+    };
+    let json = serde_json::to_string(record)?;
+    let result = sqlx::query(
+        r#"
+        INSERT INTO linked_identities (identity_key, user_id, data_json)
+        VALUES (?1, ?2, ?3)
+        ON CONFLICT(identity_key) DO UPDATE SET
+          data_json=excluded.data_json
+        WHERE linked_identities.user_id = excluded.user_id
+        "#,
+    )
+    .bind(linked_identity_key(
+        record.provider.as_str(),
+        record.subject.as_str(),
+    ))
+    .bind(record.user_id.as_str())
+    .bind(json)
+    .execute(pool)
+    .await?;
+    Ok(result.rows_affected() > 0)
+}
+
+async fn persist_billing_status_if_configured(
+    state: &ApiState,
+    billing: &BillingStatusRecord,
+) -> Result<()> {
+    #[cfg(feature = "postgres")]
+    if let Some(pool) = state.pg_pool.as_ref() {
+        return postgres_state::persist_billing_status(pool, billing).await;
+    }
+    let Some(pool) = state.db_pool.as_ref() else {
+        return Ok(());
+    };
+
+    sqlx::query(
+        r#"
+        INSERT INTO billing_subscriptions (user_id, stripe_customer_id, stripe_subscription_id, status, current_period_end, updated_at)
+        VALUES (?1, ?2, ?3, ?4, ?5, ?6)
+        ON CONFLICT(user_id) DO UPDATE SET
+          stripe_customer_id=excluded.stripe_customer_id,
+          stripe_subscription_id=excluded.stripe_subscription_id,
+          status=excluded.status,
+          current_period_end=excluded.current_period_end,
+          updated_at=excluded.updated_at
+        "#,
+    )
+    .bind(billing.user_id.as_str())
+    .bind(billing.stripe_customer_id.as_deref())
+    .bind(billing.stripe_subscription_id.as_deref())
+    .bind(billing.status.as_str())
+    .bind(billing.current_period_end.as_deref())
+    .bind(billing.updated_at.as_str())
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+async fn persist_ai_usage_if_configured(
+    state: &ApiState,
+    counter: &AiUsageCounterRecord,
+) -> Result<()> {
+    #[cfg(feature = "postgres")]
+    if let Some(pool) = state.pg_pool.as_ref() {
+        return postgres_state::persist_ai_usage(pool, counter).await;
+    }
+    let Some(pool) = state.db_pool.as_ref() else {
+        return Ok(());
+    };
+
+    sqlx::query(
+        r#"
+        INSERT INTO usage_counters (user_id, period, call_count, token_estimate, updated_at)
+        VALUES (?1, ?2, ?3, ?4, ?5)
+        ON CONFLICT(user_id) DO UPDATE SET
+          period=excluded.period,
+          call_count=excluded.call_count,
+          token_estimate=excluded.token_estimate,
+          updated_at=excluded.updated_at
+        "#,
+    )
+    .bind(counter.user_id.as_str())
+    .bind(counter.period.as_str())
+    .bind(i64::from(counter.call_count))
+    .bind(counter.token_estimate as i64)
+    .bind(counter.updated_at.as_str())
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+async fn persist_company_status_if_configured(
+    state: &ApiState,
+    status: &CompanyStatusRecord,
+) -> Result<()> {
+    let Some(pool) = state.db_pool.as_ref() else {
+        return Ok(());
+    };
+    let json = serde_json::to_string(status)?;
+    sqlx::query(
+        r#"
+        INSERT INTO company_status (id, data_json, updated_at)
+        VALUES (1, ?1, ?2)
+        ON CONFLICT(id) DO UPDATE SET data_json=excluded.data_json, updated_at=excluded.updated_at
+        "#,
+    )
+    .bind(json)
+    .bind(chrono::Utc::now().to_rfc3339())
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+async fn resolve_user_id_by_customer(state: &ApiState, customer_id: &str) -> Option<String> {
+    #[cfg(feature = "postgres")]
+    if let Some(pool) = state.pg_pool.as_ref() {
+        return postgres_state::user_id_by_customer(pool, customer_id)
+            .await
+            .ok()
+            .flatten();
+    }
+    let pool = state.db_pool.as_ref()?;
+    sqlx::query("SELECT user_id FROM billing_subscriptions WHERE stripe_customer_id = ?1 LIMIT 1")
+        .bind(customer_id)
+        .fetch_optional(pool)
+        .await
+        .ok()
+        .flatten()
+        .map(|row| row.get::<String, _>("user_id"))
+}
+
+fn verify_stripe_webhook_signature(
+    signature: &str,
+    payload: &str,
+    secret: &str,
+    tolerance_seconds: u64,
+) -> bool {
+    let mut timestamp = "";
+    let mut expected_signatures: Vec<&str> = Vec::new();
+    for part in signature.split(',') {
+        let mut split = part.splitn(2, '=');
+        let key = split.next().unwrap_or_default();
+        let value = split.next().unwrap_or_default();
+        if key == "t" {
+            timestamp = value;
+        } else if key == "v1" {
+            expected_signatures.push(value);
+        }
+    }
+    if timestamp.is_empty() || expected_signatures.is_empty() {
+        return false;
+    }
+    let timestamp_value = match timestamp.parse::<i64>() {
+        Ok(value) => value,
+        Err(_) => return false,
+    };
+    if tolerance_seconds > 0 {
+        let now = chrono::Utc::now().timestamp();
+        if (now - timestamp_value).abs() > tolerance_seconds as i64 {
+            return false;
+        }
+    }
+
+    if payload.len() > 256 * 1024 {
+        return false;
+    }
+
+    let signed_payload = format!("{}.{}", timestamp, payload);
+    let mut mac = match Hmac::<Sha256>::new_from_slice(secret.as_bytes()) {
+        Ok(value) => value,
+        Err(_) => return false,
+    };
+    mac.update(signed_payload.as_bytes());
+    let result = mac.finalize().into_bytes();
+    let computed = hex_encode(result.as_slice());
+    expected_signatures
+        .iter()
+        .any(|expected| constant_time_eq(computed.as_bytes(), expected.as_bytes()))
+}
+
+#[cfg(test)]
+fn build_test_stripe_signature(
+    payload: &str,
+    secret: &str,
+    timestamp: i64,
+) -> Result<String, hmac::digest::InvalidLength> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes())?;
+    let signed_payload = format!("{}.{}", timestamp, payload);
+    mac.update(signed_payload.as_bytes());
+    let signature = hex_encode(mac.finalize().into_bytes().as_slice());
+    Ok(format!("t={},v1={}", timestamp, signature))
+}
+
+fn constant_time_eq(lhs: &[u8], rhs: &[u8]) -> bool {
+    if lhs.len() != rhs.len() {
+        return false;
+    }
+    let mut diff = 0_u8;
+    for (a, b) in lhs.iter().zip(rhs.iter()) {
+        diff |= a ^ b;
+    }
+    diff == 0
+}
+
+// Every configured key is checked, so the time taken does not reveal which one matched.
+fn service_api_key_scope<'a>(state: &'a ApiState, provided: &str) -> Option<ApiKeyScope<'a>> {
+    let mut scope = None;
+    if service_api_key_matches(provided, state.api_key.as_str()) {
+        scope = Some(ApiKeyScope::Full);
+    }
+    for scoped in state.scoped_api_keys.iter() {
+        if service_api_key_matches(provided, scoped.key.as_str()) && scope.is_none() {
+            scope = Some(ApiKeyScope::Routes(scoped.route_prefixes.as_slice()));
+        }
+    }
+    scope
+}
+
+fn route_in_scope(path: &str, route_prefixes: &[String]) -> bool {
+    route_prefixes.iter().any(|prefix| {
+        path == prefix
+            || (path.starts_with(prefix.as_str())
+                && (prefix.ends_with('/') || path[prefix.len()..].starts_with('/')))
+    })
+}
+
+fn parse_scoped_api_keys(raw: &str) -> Vec<ScopedApiKey> {
+    let parsed = match serde_json::from_str::<HashMap<String, Vec<String>>>(raw) {
+        Ok(parsed) => parsed,
+        Err(err) => {
+            tracing::warn!(error = %err, "ignoring ATLAS_SCOPED_API_KEYS: expected a JSON object of key -> route prefixes");
+            return Vec::new();
+        }
+    };
+    parsed
+        .into_iter()
+        .filter_map(|(key, prefixes)| {
+            let key = key.trim().to_string();
+            let route_prefixes = prefixes
+                .into_iter()
+                .map(|prefix| prefix.trim().to_string())
+                .filter(|prefix| prefix.starts_with("/v1/"))
+                .collect::<Vec<_>>();
+            if key.is_empty() || route_prefixes.is_empty() {
+                return None;
+            }
+            Some(ScopedApiKey {
+                key,
+                route_prefixes,
+            })
+        })
+        .collect()
+}
+
+// Both sides are hashed first so the comparison always runs over 32 bytes; missing or
+// garbage keys take the same path as a near-miss instead of failing on a length check.
+fn service_api_key_matches(provided: &str, expected: &str) -> bool {
+    if expected.is_empty() {
+        return false;
+    }
+    let provided_digest = Sha256::digest(provided.as_bytes());
+    let expected_digest = Sha256::digest(expected.as_bytes());
+    constant_time_eq(provided_digest.as_slice(), expected_digest.as_slice())
+}
+
+fn hex_encode(bytes: &[u8]) -> String {
+    let mut out = String::with_capacity(bytes.len() * 2);
+    for byte in bytes {
+        out.push_str(format!("{:02x}", byte).as_str());
+    }
+    out
+}
+
+async fn find_or_create_user_by_email(
+    state: &ApiState,
+    identity: &ProviderIdentity,
+    name: String,
+    locale: String,
+    now: String,
+) -> UserRecord {
+    load_shared_accounts_by_email(state, identity.email.as_str()).await;
+    let linked_user_id = linked_identity_owner(&state.linked_identities.read(), identity)
+        .map(|record| record.user_id.clone());
+    if let Some(existing) =
+        linked_user_id.and_then(|user_id| state.users.read().get(&user_id).cloned())
+    {
+        return existing;
+    }
+    let matched = {
+        let user_ids_by_email = state.user_ids_by_email.read();
+        matching_sign_in_account(
+            &state.users.read(),
+            &user_ids_by_email,
+            &state.linked_identities.read(),
+            identity,
+        )
+    };
+    if let Some(existing) = matched {
+        return existing;
+    }
+
+    let user_id = uuid::Uuid::new_v4().to_string();
+    let user = UserRecord {
+        user_id: user_id.clone(),
+        provider: identity.provider.to_string(),
+        email: identity.email.clone(),
+        name,
+        locale,
+        trip_style: Some("mixed".to_string()),
+        risk_preference: Some("medium".to_string()),
+        memory_opt_in: true,
+        passkey_user_handle: Some(uuid::Uuid::new_v4().to_string()),
+        created_at: now.clone(),
+        updated_at: now,
+        last_login_at: None,
+        login_count: 0,
+    };
+    remember_user(state, user.clone());
+    let _ = persist_user_if_configured(state, &user).await;
+    user
+}
+
+fn normalize_email(email: &str) -> String {
+    email.trim().to_lowercase()
+}
+
+fn index_user_emails<'a>(
+    users: impl IntoIterator<Item = &'a UserRecord>,
+) -> HashMap<String, Vec<String>> {
+    let mut index: HashMap<String, Vec<String>> = HashMap::new();
+    for user in users {
+        index
+            .entry(normalize_email(user.email.as_str()))
+            .or_default()
+            .push(user.user_id.clone());
+    }
+    index
+}
+
+/// Caches a user and indexes their email, so email lookups never scan every account.
+fn remember_user(state: &ApiState, user: UserRecord) {
+    let mut index = state.user_ids_by_email.write();
+    let user_ids = index
+        .entry(normalize_email(user.email.as_str()))
+        .or_default();
+    if !user_ids.contains(&user.user_id) {
+        user_ids.push(user.user_id.clone());
+    }
+    state.users.write().insert(user.user_id.clone(), user);
+}
+
+fn is_placeholder_email(email: &str) -> bool {
+    email.ends_with(PLACEHOLDER_EMAIL_DOMAIN)
+}
+
+// Emails typed into passkey registration are self-asserted, so they never claim an existing
+// account, and an account is only adopted when both the sign-in and the account's own email are
+// provider-verified. Synthetic placeholder emails never match.
+fn sign_in_matches_account(
+    existing: &UserRecord,
+    existing_email_verified: bool,
+    identity: &ProviderIdentity,
+) -> bool {
+    if existing.email != identity.email
+        || is_placeholder_email(identity.email.as_str())
+        || identity.provider == "passkey"
+    {
+        return false;
+    }
+    identity.email_verified
+        && existing_email_verified
+        && (existing.provider == identity.provider || existing.provider == "passkey")
+}
+
+// OAuth accounts are only created from provider-verified emails. A passkey account's email is
+// self-asserted until a provider identity carrying the same email is linked to it.
+fn account_email_verified(
+    user: &UserRecord,
+    linked: &HashMap<String, LinkedIdentityRecord>,
+) -> bool {
+    user.provider != "passkey"
+        || linked
+            .values()
+            .any(|record| record.user_id == user.user_id && record.email == user.email)
+}
+
+// Several accounts can share an email (a provider account and a passkey account), so the oldest
+// one wins rather than whichever the map yields first.
+fn matching_sign_in_account(
+    users: &HashMap<String, UserRecord>,
+    user_ids_by_email: &HashMap<String, Vec<String>>,
+    linked: &HashMap<String, LinkedIdentityRecord>,
+    identity: &ProviderIdentity,
+) -> Option<UserRecord> {
+    user_ids_by_email
+        .get(normalize_email(identity.email.as_str()).as_str())?
+        .iter()
+        .filter_map(|user_id| users.get(user_id))
+        .filter(|user| {
+            sign_in_matches_account(user, account_email_verified(user, linked), identity)
+        })
+        .min_by(|lhs, rhs| {
+            let created = |user: &UserRecord| {
+                chrono::DateTime::parse_from_rfc3339(user.created_at.as_str())
+                    .map(|value| value.with_timezone(&chrono::Utc))
+                    .unwrap_or(chrono::DateTime::<chrono::Utc>::MAX_UTC)
+            };
+            created(lhs)
+                .cmp(&created(rhs))
+                .then_with(|| lhs.user_id.cmp(&rhs.user_id))
+        })
+        .cloned()
+}
+
+fn linked_identity_key(provider: &str, subject: &str) -> String {
+    format!("{provider}:{subject}")
+}
+
+// Links are keyed by the provider's subject only; an identity without one (a passkey sign-up)
+// is never linked.
+fn linked_identity_owner<'a>(
+    linked: &'a HashMap<String, LinkedIdentityRecord>,
+    identity: &ProviderIdentity,
+) -> Option<&'a LinkedIdentityRecord> {
+    if identity.subject.is_empty() {
+        return None;
+    }
+    linked.get(&linked_identity_key(
+        identity.provider,
+        identity.subject.as_str(),
+    ))
+}
+
+// An identity is owned by the account it was explicitly linked to, or else by the account that
+// signed up with it.
+fn provider_identity_owner(
+    users: &HashMap<String, UserRecord>,
+    user_ids_by_email: &HashMap<String, Vec<String>>,
+    linked: &HashMap<String, LinkedIdentityRecord>,
+    identity: &ProviderIdentity,
+) -> Option<String> {
+    if let Some(record) = linked_identity_owner(linked, identity) {
+        return Some(record.user_id.clone());
+    }
+    user_ids_by_email
+        .get(normalize_email(identity.email.as_str()).as_str())?
+        .iter()
+        .filter_map(|user_id| users.get(user_id))
+        .find(|user| user.provider == identity.provider && user.email == identity.email)
+        .map(|user| user.user_id.clone())
+}
+
+async fn link_provider_identity(
+    state: &ApiState,
+    user_id: &str,
+    identity: ProviderIdentity,
+) -> std::result::Result<UserRecord, &'static str> {
+    // The ownership check and the insert share one write lock, so two concurrent links of the
+    // same identity cannot both pass the check.
+    // Links are keyed by subject, so an identity without one is never stored.
+    if identity.subject.is_empty() {
+        return Err("missing_subject");
+    }
+    // The email index is locked before the users map, in the same order as `remember_user`.
+    let (user, record) = {
+        let user_ids_by_email = state.user_ids_by_email.read();
+        let users = state.users.read();
+        let Some(user) = users.get(user_id).cloned() else {
+            return Err("account_not_found");
+        };
+        let mut linked = state.linked_identities.write();
+        match provider_identity_owner(&users, &user_ids_by_email, &linked, &identity).as_deref() {
+            Some(owner_id) if owner_id != user_id => {
+                return Err("identity_linked_to_another_account")
+            }
+            Some(_) => return Ok(user),
+            None => {}
+        }
+        let record = LinkedIdentityRecord {
+            provider: identity.provider.to_string(),
+            subject: identity.subject,
+            email: identity.email,
+            user_id: user_id.to_string(),
+            linked_at: chrono::Utc::now().to_rfc3339(),
+        };
+        linked.insert(
+            linked_identity_key(record.provider.as_str(), record.subject.as_str()),
+            record.clone(),
         );
+        (user, record)
+    };
+    let persisted = persist_linked_identity_if_configured(state, &record).await;
+    if !matches!(persisted, Ok(true)) {
+        state.linked_identities.write().remove(&linked_identity_key(
+            record.provider.as_str(),
+            record.subject.as_str(),
+        ));
+        return Err(match persisted {
+            Ok(_) => "identity_linked_to_another_account",
+            Err(_) => "link_persist_failed",
+        });
     }
-    if let Some(charity) = survey_state.answers.get("charity_commitment") {
-        push_task_if_valid(
-            &mut tasks,
-            ExecutionTaskCandidate {
-                task_id: "survey-charity".to_string(),
-                title: if locale == "he" {
-                    "תכנון תרומה ושפע".to_string()
-                } else {
-                    "Plan giving and abundance".to_string()
-                },
-                detail: if locale == "he" {
-                    format!("מחויבות תרומה שנבחרה: {}. קבע כלל ביצוע קבוע.", charity)
-                } else {
-                    format!(
-                        "Selected giving commitment: {}. Define a fixed execution rule.",
-                        charity
-                    )
-                },
-                source: "survey".to_string(),
-                horizon: "long_term".to_string(),
-                urgency: 0.48,
-                impact: 0.8,
-                confidence: 0.82,
-            },
+    Ok(user)
+}
+
+// Links only when the browser completing the callback still holds the session that started the
+// link; otherwise an attacker could start a link and hand the callback URL to a victim.
+fn link_initiated_by_session(
+    state: &ApiState,
+    headers: &HeaderMap,
+    pending: &OAuthStateRecord,
+) -> bool {
+    let (Some(link_user_id), Some(link_session_id)) = (
+        pending.link_user_id.as_deref(),
+        pending.link_session_id.as_deref(),
+    ) else {
+        return false;
+    };
+    read_cookie_value(headers, &state.cookie_name).as_deref() == Some(link_session_id)
+        && session_user_from_headers(state, headers)
+            .is_some_and(|user| user.user_id == link_user_id)
+}
+
+async fn complete_provider_link(
+    state: &ApiState,
+    headers: &HeaderMap,
+    pending: &OAuthStateRecord,
+    identity: ProviderIdentity,
+    request_id: &str,
+    frontend_origin: &str,
+) -> Response {
+    let provider = identity.provider;
+    let return_to = pending.return_to.as_str();
+    let linked = match pending.link_user_id.as_deref() {
+        Some(user_id) if link_initiated_by_session(state, headers, pending) => {
+            link_provider_identity(state, user_id, identity).await
+        }
+        _ => Err("link_session_mismatch"),
+    };
+    let target = match linked {
+        Ok(user) => {
+            log_auth_event(
+                "auth.link",
+                "success",
+                provider,
+                request_id,
+                Some(&user),
+                None,
+            );
+            format!("{frontend_origin}{return_to}?link=success&provider={provider}")
+        }
+        Err(reason) => {
+            log_auth_event(
+                "auth.link",
+                "failure",
+                provider,
+                request_id,
+                None,
+                Some(reason),
+            );
+            format!("{frontend_origin}{return_to}?link=error&reason={reason}")
+        }
+    };
+    Redirect::to(target.as_str()).into_response()
+}
+
+fn request_id_from_headers(headers: &HeaderMap) -> String {
+    propagated_request_id(headers)
+        .unwrap_or("unknown")
+        .to_string()
+}
+
+fn propagated_request_id(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get("x-request-id")
+        .and_then(|value| value.to_str().ok())
+        .map(str::trim)
+        .filter(|value| !value.is_empty())
+}
+
+// Action telemetry reuses the propagated x-request-id so a client log line and the server
+// trace share one id; a fresh uuid only stands in when the layer did not run (direct calls).
+fn trace_id_from_headers(headers: &HeaderMap) -> String {
+    propagated_request_id(headers)
+        .map(str::to_string)
+        .unwrap_or_else(|| uuid::Uuid::new_v4().to_string())
+}
+
+fn mask_email(email: &str) -> String {
+    let Some((local, domain)) = email.trim().split_once('@') else {
+        return "***".to_string();
+    };
+    let first = local.chars().next().map(String::from).unwrap_or_default();
+    format!("{first}***@{domain}")
+}
+
+// Only failed verifications (a passkey finish or a recovery code) are counted; starting a sign-in
+// never is. A locked email still signs in with a valid passkey, because passkey finishes are
+// verified before the lockout is consulted; recovery codes can be guessed, so their redeem path
+// checks the lockout first. Unknown emails are counted and locked exactly like real ones, so the
+// lockout response does not reveal whether an account exists.
+fn count_login_attempt_for_email(
+    state: &ApiState,
+    email: &str,
+    provider: &str,
+    request_id: &str,
+) -> Option<Response> {
+    let now = Instant::now();
+    if let Some(remaining) = state.login_email_lockouts.locked_for(email, now) {
+        return Some(login_locked_response(remaining));
+    }
+    let lockout = state.login_email_lockouts.record_attempt(email, now)?;
+    let user = state
+        .user_ids_by_email
+        .read()
+        .get(normalize_email(email).as_str())
+        .and_then(|user_ids| user_ids.first().cloned())
+        .and_then(|user_id| state.users.read().get(&user_id).cloned());
+    log_auth_event(
+        "auth.login_lockout",
+        "failure",
+        provider,
+        request_id,
+        user.as_ref(),
+        Some("email_attempts_exceeded"),
+    );
+    Some(login_locked_response(lockout))
+}
+
+fn passkey_login_failure(
+    state: &ApiState,
+    email: Option<&str>,
+    request_id: &str,
+    reason: &'static str,
+    message: String,
+) -> Response {
+    log_auth_event(
+        "auth.login",
+        "failure",
+        "passkey",
+        request_id,
+        None,
+        Some(reason),
+    );
+    if let Some(response) =
+        email.and_then(|email| count_login_attempt_for_email(state, email, "passkey", request_id))
+    {
+        return response;
+    }
+    ApiError::unauthorized(reason, message).into_response()
+}
+
+fn login_locked_response(remaining: Duration) -> Response {
+    let retry_after_seconds = remaining.as_secs().max(1);
+    let mut response = ApiError::too_many_requests(
+        "login_temporarily_locked",
+        "too many sign-in attempts for this account. wait and retry.",
+    )
+    .with_details(serde_json::json!({ "retry_after_seconds": retry_after_seconds }))
+    .into_response();
+    response
+        .headers_mut()
+        .insert(header::RETRY_AFTER, HeaderValue::from(retry_after_seconds));
+    response
+}
+
+// Audit trail for security review: every auth transition logs under an `auth.*` event name.
+// Tokens and credentials are never passed in, and emails are masked to the first character.
+fn log_auth_event(
+    event: &str,
+    outcome: &str,
+    provider: &str,
+    request_id: &str,
+    user: Option<&UserRecord>,
+    reason: Option<&str>,
+) {
+    let user_id = user.map(|value| value.user_id.as_str());
+    let email = user.map(|value| mask_email(value.email.as_str()));
+    if outcome == "success" {
+        tracing::info!(
+            event,
+            outcome,
+            provider,
+            request_id,
+            user_id,
+            email,
+            reason,
+            "auth event"
+        );
+    } else {
+        tracing::warn!(
+            event,
+            outcome,
+            provider,
+            request_id,
+            user_id,
+            email,
+            reason,
+            "auth event"
         );
     }
-    tasks
 }
 
-fn extract_memory_tasks(
-    memories: &[MemoryRetrievedItem],
-    locale: &str,
-) -> Vec<ExecutionTaskCandidate> {
-    let mut tasks = Vec::new();
-    for memory in memories.iter().take(12) {
-        if !matches!(
-            memory.source.as_str(),
-            "chat" | "survey" | "feedback" | "note" | "note_rewrite" | "manual"
-        ) {
-            continue;
+fn auth_failure_redirect(
+    provider: &str,
+    request_id: &str,
+    frontend_origin: &str,
+    return_to: &str,
+    reason: &str,
+) -> Response {
+    log_auth_event(
+        "auth.login",
+        "failure",
+        provider,
+        request_id,
+        None,
+        Some(reason),
+    );
+    let target = format!("{frontend_origin}{return_to}?auth=error&reason={reason}");
+    Redirect::to(target.as_str()).into_response()
+}
+
+async fn issue_session_for_user(
+    state: &ApiState,
+    user: &mut UserRecord,
+    peer: Option<std::net::IpAddr>,
+    headers: &HeaderMap,
+) -> Result<String> {
+    let session_id = uuid::Uuid::new_v4().to_string();
+    let expires_at =
+        chrono::Utc::now() + chrono::Duration::seconds(state.session_ttl.as_secs() as i64);
+    let (client_network, region) = session_location_from_headers(state, peer, headers);
+    state.sessions.write().insert(
+        session_id.clone(),
+        SessionRecord {
+            user_id: user.user_id.clone(),
+            expires_at,
+            created_at: chrono::Utc::now(),
+            client_network,
+            region,
+        },
+    );
+    persist_session_if_configured(state, &session_id).await?;
+
+    // Bump the stored record in place so concurrent sign-ins each count, and persist that record
+    // rather than the caller's copy, which may predate other writes.
+    let now = chrono::Utc::now().to_rfc3339();
+    let updated = match state.users.write().get_mut(&user.user_id) {
+        Some(stored) => {
+            stored.last_login_at = Some(now);
+            stored.login_count = stored.login_count.saturating_add(1);
+            stored.clone()
         }
-        if memory.text.trim().is_empty() {
-            continue;
+        None => {
+            user.last_login_at = Some(now);
+            user.login_count = user.login_count.saturating_add(1);
+            user.clone()
         }
-        let horizon = if memory.memory_type == "goal" {
-            "long_term".to_string()
-        } else if memory.memory_type == "friction" || memory.memory_type == "mood" {
-            "daily".to_string()
+    };
+    persist_user_if_configured(state, &updated).await?;
+    *user = updated;
+    Ok(session_id)
+}
+
+// Location hints are opt-in; without configuration nothing is recorded. Headers are only read
+// when the socket peer is one of `ATLAS_TRUSTED_PROXIES`, so a client connecting directly is
+// placed by its own address and cannot pick its network or region. Only the network prefix is
+// kept, never the full client address.
+fn session_location_from_headers(
+    state: &ApiState,
+    peer: Option<std::net::IpAddr>,
+    headers: &HeaderMap,
+) -> (Option<String>, Option<String>) {
+    let from_trusted_proxy = peer.is_some_and(|ip| state.trusted_proxies.contains(ip));
+    let client_network = state.trusted_proxy_header.as_deref().and_then(|name| {
+        let ip = if from_trusted_proxy && !matches!(name, "x-forwarded-for" | "x-real-ip") {
+            parse_trusted_client_ip(name, headers.get(name)?.to_str().ok()?)?
         } else {
-            classify_horizon_from_text(memory.text.as_str())
+            resolve_client_ip(peer, headers, &state.trusted_proxies)?
         };
-        push_task_if_valid(
-            &mut tasks,
-            ExecutionTaskCandidate {
-                task_id: format!("memory-{}", memory.memory_id),
-                title: if locale == "he" {
-                    "משימה מנגזרת מזיכרון".to_string()
-                } else {
-                    "Action from long-term memory".to_string()
-                },
-                detail: sanitize_limited_text(memory.text.as_str(), 180),
-                source: memory.source.clone(),
-                horizon,
-                urgency: (memory.final_score * 0.9).clamp(0.4, 0.98),
-                impact: (memory.weight * 0.9).clamp(0.35, 0.95),
-                confidence: (memory.relevance_score * 0.6 + 0.35).clamp(0.35, 0.95),
-            },
-        );
+        Some(coarse_client_network(ip))
+    });
+    let region = state.trusted_region_header.as_deref().and_then(|name| {
+        if !from_trusted_proxy {
+            return None;
+        }
+        let raw = headers.get(name)?.to_str().ok()?.trim();
+        (raw.len() == 2 && raw.chars().all(|ch| ch.is_ascii_alphabetic()))
+            .then(|| raw.to_ascii_uppercase())
+    });
+    (client_network, region)
+}
+
+fn parse_trusted_client_ip(header_name: &str, raw: &str) -> Option<std::net::IpAddr> {
+    // X-Forwarded-For is appended to hop by hop; only the last entry was written by the
+    // trusted proxy. Every other header must carry exactly one address.
+    let candidate = if header_name == "x-forwarded-for" {
+        raw.rsplit(',').next()?
+    } else if raw.contains(',') {
+        return None;
+    } else {
+        raw
+    };
+    candidate.trim().parse::<std::net::IpAddr>().ok()
+}
+
+fn coarse_client_network(ip: std::net::IpAddr) -> String {
+    match ip {
+        std::net::IpAddr::V4(v4) => {
+            let [a, b, c, _] = v4.octets();
+            format!("{a}.{b}.{c}.0/24")
+        }
+        std::net::IpAddr::V6(v6) => {
+            let segments = v6.segments();
+            format!("{:x}:{:x}:{:x}::/48", segments[0], segments[1], segments[2])
+        }
+    }
+}
+
+fn session_location_key(session: &SessionRecord) -> Option<String> {
+    session
+        .region
+        .clone()
+        .or_else(|| session.client_network.clone())
+}
+
+fn sanitize_header_name(value: &str) -> Option<String> {
+    let name = value.trim().to_ascii_lowercase();
+    header::HeaderName::from_bytes(name.as_bytes())
+        .ok()
+        .map(|_| name)
+}
+
+fn resolve_user_id_for_passkey_credential(state: &ApiState, cred_id: &[u8]) -> Option<String> {
+    state
+        .passkeys_by_user
+        .read()
+        .iter()
+        .find_map(|(user_id, entries)| {
+            if entries
+                .iter()
+                .any(|entry| entry.credential.cred_id().as_slice() == cred_id)
+            {
+                Some(user_id.clone())
+            } else {
+                None
+            }
+        })
+}
+
+fn update_passkey_credential_usage(
+    state: &ApiState,
+    user_id: &str,
+    auth_result: &AuthenticationResult,
+) {
+    if let Some(entries) = state.passkeys_by_user.write().get_mut(user_id) {
+        let now = chrono::Utc::now().to_rfc3339();
+        for entry in entries.iter_mut() {
+            if entry.credential.update_credential(auth_result).is_some() {
+                entry.last_used_at = Some(now.clone());
+            }
+        }
     }
-    tasks
 }
 
-fn extract_checkin_tasks(
-    checkin: Option<&ExecutionCheckinRecord>,
-    locale: &str,
-) -> Vec<ExecutionTaskCandidate> {
-    let mut tasks = Vec::new();
-    let Some(checkin) = checkin else {
-        return tasks;
-    };
-    push_task_if_valid(
-        &mut tasks,
-        ExecutionTaskCandidate {
-            task_id: format!("checkin-daily-{}", checkin.checkin_id),
-            title: if locale == "he" {
-                "פוקוס יומי מהצ׳ק-אין".to_string()
-            } else {
-                "Daily focus from check-in".to_string()
-            },
-            detail: checkin.daily_focus.clone(),
-            source: "checkin".to_string(),
-            horizon: "daily".to_string(),
-            urgency: 0.96,
-            impact: 0.82,
-            confidence: 0.95,
+#[allow(clippy::too_many_arguments)]
+async fn generate_premium_reply(
+    state: &ApiState,
+    request: &ChatRequest,
+    locale: atlas_core::Locale,
+    user: Option<&UserRecord>,
+    survey: Option<&SurveyStateRecord>,
+    notes: &[UserNoteRecord],
+    memory_context: &[MemoryRetrievedItem],
+    fallback_reply: &str,
+) -> Result<ChatBackendReply> {
+    let runtime = state
+        .ai_runtime
+        .as_ref()
+        .context("premium AI runtime is not configured")?;
+
+    let user_context = user.map(|value| {
+        serde_json::json!({
+            "name": value.name,
+            "locale": value.locale,
+            "trip_style": value.trip_style,
+            "risk_preference": value.risk_preference,
+            "memory_opt_in": value.memory_opt_in
+        })
+    });
+    let survey_context = survey.map(|value| serde_json::to_value(value).unwrap_or_default());
+    let notes_context = notes
+        .iter()
+        .take(12)
+        .map(|note| {
+            serde_json::json!({
+                "title": note.title,
+                "content": note.content,
+                "tags": note.tags
+            })
+        })
+        .collect::<Vec<_>>();
+    let memory_context = memory_context
+        .iter()
+        .take(12)
+        .map(|entry| {
+            serde_json::json!({
+                "memory_type": entry.memory_type,
+                "stability": entry.stability,
+                "source": entry.source,
+                "text": entry.text,
+                "weight": entry.weight,
+                "recency_score": entry.recency_score,
+                "relevance_score": entry.relevance_score,
+                "tags": entry.tags
+            })
+        })
+        .collect::<Vec<_>>();
+
+    let (context_json, dropped_notes, dropped_memories) = fit_context_to_budget(
+        notes_context,
+        memory_context,
+        state.ai_context_max_bytes,
+        |notes, memories| {
+            serde_json::json!({
+                "user": user_context,
+                "survey": survey_context,
+                "notes": notes,
+                "memory_context": memories,
+                "fallback_reply": fallback_reply
+            })
+            .to_string()
         },
     );
-    if let Some(mid) = checkin.mid_term_focus.as_ref() {
-        push_task_if_valid(
-            &mut tasks,
-            ExecutionTaskCandidate {
-                task_id: format!("checkin-mid-{}", checkin.checkin_id),
-                title: if locale == "he" {
-                    "יעד ביניים מהצ׳ק-אין".to_string()
-                } else {
-                    "Mid-term focus from check-in".to_string()
-                },
-                detail: mid.clone(),
-                source: "checkin".to_string(),
-                horizon: "mid_term".to_string(),
-                urgency: 0.68,
-                impact: 0.86,
-                confidence: 0.9,
-            },
-        );
-    }
-    if let Some(long) = checkin.long_term_focus.as_ref() {
-        push_task_if_valid(
-            &mut tasks,
-            ExecutionTaskCandidate {
-                task_id: format!("checkin-long-{}", checkin.checkin_id),
-                title: if locale == "he" {
-                    "כיוון ארוך-טווח מהצ׳ק-אין".to_string()
-                } else {
-                    "Long-horizon direction from check-in".to_string()
-                },
-                detail: long.clone(),
-                source: "checkin".to_string(),
-                horizon: "long_term".to_string(),
-                urgency: 0.55,
-                impact: 0.92,
-                confidence: 0.88,
-            },
-        );
-    }
-    if let Some(gym_today) = checkin.gym_today {
-        push_task_if_valid(
-            &mut tasks,
-            ExecutionTaskCandidate {
-                task_id: format!("checkin-gym-{}", checkin.checkin_id),
-                title: if locale == "he" {
-                    if gym_today {
-                        "עיגון משמעת בריאותית".to_string()
-                    } else {
-                        "להחזיר מומנטום בריאותי היום".to_string()
-                    }
-                } else if gym_today {
-                    "Lock health discipline momentum".to_string()
-                } else {
-                    "Recover health momentum today".to_string()
-                },
-                detail: if locale == "he" {
-                    if gym_today {
-                        "בוצע אימון היום. עגנו שעת אימון קבועה גם למחר כדי לשמור רצף.".to_string()
-                    } else {
-                        "לא בוצע אימון היום. קבעו בלוק אימון קצר ומדויק לפני סוף היום.".to_string()
-                    }
-                } else if gym_today {
-                    "Gym completed today. Pre-commit tomorrow’s session to preserve streak."
-                        .to_string()
-                } else {
-                    "Gym was missed today. Schedule one precise training block before day-end."
-                        .to_string()
-                },
-                source: "checkin".to_string(),
-                horizon: "daily".to_string(),
-                urgency: if gym_today { 0.58 } else { 0.86 },
-                impact: 0.74,
-                confidence: 0.87,
-            },
-        );
-    }
-    if let Some(money_today) = checkin.money_today {
-        push_task_if_valid(
-            &mut tasks,
-            ExecutionTaskCandidate {
-                task_id: format!("checkin-money-{}", checkin.checkin_id),
-                title: if locale == "he" {
-                    if money_today {
-                        "לנעול התקדמות הכנסה".to_string()
-                    } else {
-                        "יצירת מהלך הכנסה מיידי".to_string()
-                    }
-                } else if money_today {
-                    "Lock income progress".to_string()
-                } else {
-                    "Create an immediate income move".to_string()
-                },
-                detail: if locale == "he" {
-                    if money_today {
-                        "נרשמה התקדמות כספית היום. תעדו מה עבד ושכפלו אותו ל-48 השעות הקרובות."
-                            .to_string()
-                    } else {
-                        "עדיין ללא הכנסה היום. בצעו מהלך אחד: יצירת קשר, הצעה, או סגירה."
-                            .to_string()
-                    }
-                } else if money_today {
-                    "Revenue moved today. Capture what worked and replicate it over the next 48 hours."
-                        .to_string()
-                } else {
-                    "No money signal today yet. Execute one move now: outreach, offer, or close."
-                        .to_string()
-                },
-                source: "checkin".to_string(),
-                horizon: "daily".to_string(),
-                urgency: if money_today { 0.64 } else { 0.92 },
-                impact: 0.84,
-                confidence: 0.89,
-            },
+    if dropped_notes + dropped_memories > 0 {
+        tracing::info!(
+            dropped_notes,
+            dropped_memories,
+            context_bytes = context_json.len(),
+            budget_bytes = state.ai_context_max_bytes,
+            "trimmed premium chat context to fit the byte budget"
         );
     }
-    tasks
-}
 
-fn build_company_awareness_task(
-    company_status: &CompanyStatusRecord,
-    locale: &str,
-) -> ExecutionTaskCandidate {
-    let detail = if locale == "he" {
-        format!(
-            "פאזה: {} | פוקוס: {} | בהמשך: {}",
-            company_status.phase,
-            company_status.current_focus.join(", "),
-            company_status.upcoming.join(", ")
-        )
-    } else {
-        format!(
-            "Phase: {} | Current focus: {} | Upcoming: {}",
-            company_status.phase,
-            company_status.current_focus.join(", "),
-            company_status.upcoming.join(", ")
-        )
+    let prompt = ChatBackendPrompt {
+        system_prompt: state.premium_system_prompts.for_locale(locale),
+        user_messages: vec![
+            request.text.clone(),
+            format!("Context JSON: {context_json}"),
+        ],
+        reasoning_effort: request.reasoning_effort.clone(),
     };
-    ExecutionTaskCandidate {
-        task_id: "company-awareness".to_string(),
-        title: if locale == "he" {
-            "יישור לתכנית החברה".to_string()
+
+    runtime.complete(&state.http_client, &prompt).await
+}
+
+/// Drops items until `render` fits in `budget` bytes: notes arrive newest first and memories
+/// most relevant first, so each step pops from the end of whichever list is longer. If the
+/// fixed parts alone are over budget the smallest possible context is returned anyway. Returns
+/// the rendered context and how many notes and memories were dropped.
+fn fit_context_to_budget(
+    mut notes: Vec<serde_json::Value>,
+    mut memories: Vec<serde_json::Value>,
+    budget: usize,
+    render: impl Fn(&[serde_json::Value], &[serde_json::Value]) -> String,
+) -> (String, usize, usize) {
+    let (mut dropped_notes, mut dropped_memories) = (0, 0);
+    loop {
+        let rendered = render(&notes, &memories);
+        if rendered.len() <= budget || (notes.is_empty() && memories.is_empty()) {
+            return (rendered, dropped_notes, dropped_memories);
+        }
+        if notes.len() >= memories.len() {
+            notes.pop();
+            dropped_notes += 1;
         } else {
-            "Align with company plan".to_string()
-        },
-        detail,
-        source: "company".to_string(),
-        horizon: "mid_term".to_string(),
-        urgency: 0.62,
-        impact: 0.84,
-        confidence: 0.93,
+            memories.pop();
+            dropped_memories += 1;
+        }
     }
 }
 
-fn execution_priority_score(task: &ExecutionTaskCandidate) -> f32 {
-    let horizon_boost = match task.horizon.as_str() {
-        "daily" => 0.12,
-        "mid_term" => 0.08,
-        "long_term" => 0.05,
-        _ => 0.03,
-    };
-    (task.impact * 0.45 + task.urgency * 0.35 + task.confidence * 0.2 + horizon_boost)
-        .clamp(0.0, 1.25)
-}
+impl ChatBackend for OpenAiRuntimeConfig {
+    fn backend_name(&self) -> &'static str {
+        "openai_responses"
+    }
 
-fn prioritize_execution_tasks(tasks: Vec<ExecutionTaskCandidate>) -> Vec<ExecutionTaskCandidate> {
-    let mut dedup = HashMap::<String, ExecutionTaskCandidate>::new();
-    for task in tasks {
-        let key = task.title.trim().to_lowercase();
-        match dedup.get(&key) {
-            Some(existing)
-                if execution_priority_score(existing) >= execution_priority_score(&task) => {}
-            _ => {
-                dedup.insert(key, task);
+    fn model(&self) -> &str {
+        self.model.as_str()
+    }
+
+    async fn complete(
+        &self,
+        client: &Client,
+        prompt: &ChatBackendPrompt,
+    ) -> Result<ChatBackendReply> {
+        let mut input = vec![serde_json::json!({
+            "role": "system",
+            "content": [
+                { "type": "input_text", "text": prompt.system_prompt }
+            ]
+        })];
+        input.extend(prompt.user_messages.iter().map(|message| {
+            serde_json::json!({
+                "role": "user",
+                "content": [
+                    { "type": "input_text", "text": message }
+                ]
+            })
+        }));
+        let payload = serde_json::json!({
+            "model": self.model,
+            "reasoning": {
+                "effort": resolve_reasoning_effort(
+                    prompt.reasoning_effort.as_deref(),
+                    self.default_reasoning_effort.as_str(),
+                    self.max_reasoning_effort.as_str(),
+                )
+            },
+            "input": input,
+            "text": {
+                "verbosity": "high"
             }
+        });
+
+        let response = client
+            .post(openai_responses_url(self))
+            .bearer_auth(self.api_key.as_str())
+            .json(&payload)
+            .send()
+            .await
+            .context("OpenAI request failed")?;
+
+        let status = response.status();
+        if !status.is_success() {
+            let body = response.text().await.unwrap_or_default();
+            anyhow::bail!("OpenAI non-success status {}: {}", status.as_u16(), body);
         }
+
+        let body: serde_json::Value = response.json().await.context("OpenAI parse failed")?;
+        let text = extract_openai_output_text(&body)
+            .filter(|value| !value.trim().is_empty())
+            .context("OpenAI output text missing")?;
+        Ok(build_chat_backend_reply(text, &body))
     }
-    let mut ranked = dedup.into_values().collect::<Vec<_>>();
-    ranked.sort_by(|lhs, rhs| {
-        execution_priority_score(rhs).total_cmp(&execution_priority_score(lhs))
-    });
-    ranked
 }
 
-fn build_orchestrated_proactive_feed(context: &ExecutionFeedContext<'_>) -> Vec<ProactiveFeedItem> {
-    let reminder_app = context
-        .prefs
-        .map(|value| value.reminders_app.clone())
-        .unwrap_or_else(|| "google_calendar".to_string());
-    let alarm_app = context
-        .prefs
-        .map(|value| value.alarms_app.clone())
-        .unwrap_or_else(|| "apple_clock".to_string());
-    let mut tasks = Vec::new();
-    tasks.extend(extract_checkin_tasks(
-        context.latest_checkin,
-        context.user.locale.as_str(),
-    ));
-    tasks.extend(extract_note_tasks(context.notes));
-    tasks.extend(extract_survey_tasks(
-        context.survey,
-        context.user.locale.as_str(),
-    ));
-    tasks.extend(extract_memory_tasks(
-        context.memories,
-        context.user.locale.as_str(),
-    ));
-    if context.controls.include_company_awareness {
-        tasks.push(build_company_awareness_task(
-            context.company_status,
-            context.user.locale.as_str(),
-        ));
+impl ChatBackend for AnthropicRuntimeConfig {
+    fn backend_name(&self) -> &'static str {
+        "anthropic_messages"
     }
-    let ranked = prioritize_execution_tasks(tasks);
-    let mut items = Vec::new();
-    let now = chrono::Utc::now();
 
-    if let Some(top) = ranked.first() {
-        let due_at = now
-            + chrono::Duration::minutes(schedule_minutes_offset(
-                context.controls.cadence.as_str(),
-                "daily",
-                0,
-            ));
-        let mut actions = Vec::new();
-        if context.controls.include_reminder_suggestions {
-            actions.push(atlas_core::SuggestedAction {
-                action_type: "create_reminder".to_string(),
-                label: if context.user.locale == "he" {
-                    "תזכורת לביצוע מיידי".to_string()
-                } else {
-                    "Set immediate execution reminder".to_string()
-                },
-                payload: serde_json::json!({
-                    "title": top.title,
-                    "details": top.detail,
-                    "due_at_utc": due_at.to_rfc3339(),
-                    "reminders_app": reminder_app
-                }),
-            });
-            actions.push(atlas_core::SuggestedAction {
-                action_type: "create_alarm".to_string(),
-                label: if context.user.locale == "he" {
-                    "אזעקת התחלה".to_string()
-                } else {
-                    "Start alarm".to_string()
-                },
-                payload: serde_json::json!({
-                    "label": "Atlas next action now",
-                    "time_local": "09:00",
-                    "days": ["Sun","Mon","Tue","Wed","Thu"],
-                    "alarms_app": alarm_app
-                }),
-            });
-        }
-        items.push(ProactiveFeedItem {
-            id: "next_action_now".to_string(),
-            title: if context.user.locale == "he" {
-                "הפעולה הבאה עכשיו".to_string()
-            } else {
-                "Next action now".to_string()
-            },
-            summary: format!("{} — {}", top.title, top.detail),
-            why_now: if context.user.locale == "he" {
-                format!("מקור: {} | אופק: {}", top.source, top.horizon)
-            } else {
-                format!("Source: {} | Horizon: {}", top.source, top.horizon)
-            },
-            priority: "critical".to_string(),
-            actions,
-        });
+    fn model(&self) -> &str {
+        self.model.as_str()
     }
 
-    let mut used_task_ids = HashSet::new();
-    if let Some(top) = ranked.first() {
-        used_task_ids.insert(top.task_id.clone());
-    }
-    let mut selected = Vec::new();
-    for horizon in ["daily", "mid_term", "long_term"] {
-        if let Some(task) = ranked.iter().find(|candidate| {
-            candidate.horizon == horizon && !used_task_ids.contains(&candidate.task_id)
-        }) {
-            used_task_ids.insert(task.task_id.clone());
-            selected.push(task.clone());
+    async fn complete(
+        &self,
+        client: &Client,
+        prompt: &ChatBackendPrompt,
+    ) -> Result<ChatBackendReply> {
+        let content = prompt
+            .user_messages
+            .iter()
+            .map(|message| serde_json::json!({ "type": "text", "text": message }))
+            .collect::<Vec<_>>();
+        let payload = serde_json::json!({
+            "model": self.model,
+            "max_tokens": self.max_tokens,
+            "system": prompt.system_prompt,
+            "messages": [
+                { "role": "user", "content": content }
+            ]
+        });
+
+        let response = client
+            .post(format!("{}/messages", self.base_url))
+            .header("x-api-key", self.api_key.as_str())
+            .header("anthropic-version", ANTHROPIC_API_VERSION)
+            .json(&payload)
+            .send()
+            .await
+            .context("Anthropic request failed")?;
+
+        let status = response.status();
+        if !status.is_success() {
+            let body = response.text().await.unwrap_or_default();
+            anyhow::bail!("Anthropic non-success status {}: {}", status.as_u16(), body);
         }
+
+        let body: serde_json::Value = response.json().await.context("Anthropic parse failed")?;
+        let text = extract_anthropic_output_text(&body)
+            .filter(|value| !value.trim().is_empty())
+            .context("Anthropic output text missing")?;
+        Ok(build_chat_backend_reply(text, &body))
     }
-    for task in ranked.iter() {
-        if selected.len() >= 4 {
-            break;
-        }
-        if used_task_ids.contains(&task.task_id) {
-            continue;
+}
+
+impl ChatBackend for AiProviderRuntime {
+    fn backend_name(&self) -> &'static str {
+        match self {
+            AiProviderRuntime::OpenAi(runtime) => runtime.backend_name(),
+            AiProviderRuntime::Anthropic(runtime) => runtime.backend_name(),
         }
-        used_task_ids.insert(task.task_id.clone());
-        selected.push(task.clone());
     }
 
-    for (index, task) in selected.iter().enumerate() {
-        let due_at = now
-            + chrono::Duration::minutes(schedule_minutes_offset(
-                context.controls.cadence.as_str(),
-                task.horizon.as_str(),
-                index + 1,
-            ));
-        let mut actions = Vec::new();
-        if context.controls.include_reminder_suggestions {
-            actions.push(atlas_core::SuggestedAction {
-                action_type: "create_reminder".to_string(),
-                label: if context.user.locale == "he" {
-                    "קבע תזכורת".to_string()
-                } else {
-                    "Set reminder".to_string()
-                },
-                payload: serde_json::json!({
-                    "title": task.title,
-                    "details": task.detail,
-                    "due_at_utc": due_at.to_rfc3339(),
-                    "reminders_app": reminder_app
-                }),
-            });
+    fn model(&self) -> &str {
+        match self {
+            AiProviderRuntime::OpenAi(runtime) => runtime.model(),
+            AiProviderRuntime::Anthropic(runtime) => runtime.model(),
         }
-        if task.source == "company" {
-            actions.push(atlas_core::SuggestedAction {
-                action_type: "open_company_status".to_string(),
-                label: if context.user.locale == "he" {
-                    "פתח סטטוס חברה".to_string()
-                } else {
-                    "Open company status".to_string()
-                },
-                payload: serde_json::json!({}),
-            });
+    }
+
+    async fn complete(
+        &self,
+        client: &Client,
+        prompt: &ChatBackendPrompt,
+    ) -> Result<ChatBackendReply> {
+        match self {
+            AiProviderRuntime::OpenAi(runtime) => runtime.complete(client, prompt).await,
+            AiProviderRuntime::Anthropic(runtime) => runtime.complete(client, prompt).await,
         }
-        items.push(ProactiveFeedItem {
-            id: task.task_id.clone(),
-            title: task.title.clone(),
-            summary: task.detail.clone(),
-            why_now: if context.user.locale == "he" {
-                format!("אופק {} | סדר עדיפויות מחושב", task.horizon)
-            } else {
-                format!("{} horizon | prioritized by execution engine", task.horizon)
-            },
-            priority: if execution_priority_score(task) > 0.85 {
-                "high".to_string()
-            } else {
-                "normal".to_string()
-            },
-            actions,
-        });
     }
+}
 
-    if context.controls.include_company_awareness {
-        items.push(ProactiveFeedItem {
-            id: "company_planning_awareness".to_string(),
-            title: if context.user.locale == "he" {
-                "מודעות תכנית חברה".to_string()
-            } else {
-                "Company planning awareness".to_string()
-            },
-            summary: context.company_status.message.clone(),
-            why_now: if context.user.locale == "he" {
-                format!(
-                    "פאזה {}. פוקוס: {}.",
-                    context.company_status.phase,
-                    context.company_status.current_focus.join(", ")
-                )
-            } else {
-                format!(
-                    "Phase {}. Focus: {}.",
-                    context.company_status.phase,
-                    context.company_status.current_focus.join(", ")
-                )
+async fn rewrite_note_with_openai(
+    state: &ApiState,
+    note: &UserNoteRecord,
+    instruction: &str,
+) -> Result<StructuredNoteRewrite> {
+    let runtime = state
+        .openai_runtime
+        .as_ref()
+        .context("OpenAI runtime is not configured")?;
+
+    let payload = serde_json::json!({
+        "model": runtime.model,
+        "reasoning": {
+            "effort": runtime.default_reasoning_effort
+        },
+        "input": [
+            {
+                "role": "system",
+                "content": [
+                    { "type": "input_text", "text": "Rewrite notes into premium executive language while preserving facts and actionability. Split the result into immediate tasks, mid-term strategy, and long-term mission items." }
+                ]
             },
-            priority: "normal".to_string(),
-            actions: vec![atlas_core::SuggestedAction {
-                action_type: "open_company_status".to_string(),
-                label: if context.user.locale == "he" {
-                    "סקירת סטטוס מלאה".to_string()
-                } else {
-                    "Review full company status".to_string()
-                },
-                payload: serde_json::json!({}),
-            }],
-        });
+            {
+                "role": "user",
+                "content": [
+                    { "type": "input_text", "text": instruction },
+                    { "type": "input_text", "text": format!("Title: {}\n\nNote:\n{}", note.title, note.content) }
+                ]
+            }
+        ],
+        "text": {
+            "verbosity": "high",
+            "format": {
+                "type": "json_schema",
+                "name": "note_rewrite",
+                "strict": true,
+                "schema": structured_note_rewrite_schema()
+            }
+        }
+    });
+
+    let response = state
+        .http_client
+        .post(openai_responses_url(runtime))
+        .bearer_auth(runtime.api_key.as_str())
+        .json(&payload)
+        .send()
+        .await
+        .context("OpenAI note rewrite request failed")?;
+    let status = response.status();
+    if !status.is_success() {
+        let body = response.text().await.unwrap_or_default();
+        anyhow::bail!("OpenAI note rewrite failed {}: {}", status.as_u16(), body);
     }
 
-    if context.controls.detail_level == "concise" {
-        items
-            .into_iter()
-            .map(|mut item| {
-                item.summary = sanitize_limited_text(item.summary.as_str(), 120);
-                item.why_now = sanitize_limited_text(item.why_now.as_str(), 90);
-                item
-            })
-            .collect()
-    } else if context.controls.detail_level == "expanded" {
+    let body: serde_json::Value = response
+        .json()
+        .await
+        .context("OpenAI rewrite parse failed")?;
+    let output = extract_openai_output_text(&body)
+        .filter(|value| !value.trim().is_empty())
+        .context("OpenAI rewrite output missing")?;
+    parse_structured_note_rewrite(output.as_str())
+}
+
+fn structured_note_rewrite_schema() -> serde_json::Value {
+    let section = serde_json::json!({
+        "type": "array",
+        "items": { "type": "string" }
+    });
+    serde_json::json!({
+        "type": "object",
+        "properties": {
+            "immediate_tasks": section,
+            "mid_term": section,
+            "long_term": section
+        },
+        "required": ["immediate_tasks", "mid_term", "long_term"],
+        "additionalProperties": false
+    })
+}
+
+fn parse_structured_note_rewrite(output: &str) -> Result<StructuredNoteRewrite> {
+    let parsed: StructuredNoteRewrite = serde_json::from_str(output.trim())
+        .context("OpenAI rewrite returned invalid structured output")?;
+    Ok(sanitize_structured_note_rewrite(parsed))
+}
+
+fn sanitize_structured_note_rewrite(structured: StructuredNoteRewrite) -> StructuredNoteRewrite {
+    let clean = |items: Vec<String>| {
         items
             .into_iter()
-            .map(|mut item| {
-                item.why_now = format!(
-                    "{} | {}",
-                    item.why_now,
-                    if context.user.locale == "he" {
-                        "המלצה זו נגזרת מדפוסי שימוש, זיכרון ארוך-טווח ויעדי אופק."
-                    } else {
-                        "Recommendation derived from usage patterns, long-term memory, and horizon goals."
-                    }
-                );
-                item
-            })
-            .collect()
-    } else {
-        items
+            .map(|item| item.trim().to_string())
+            .filter(|item| !item.is_empty())
+            .take(MAX_REWRITE_SECTION_ITEMS)
+            .collect::<Vec<_>>()
+    };
+    StructuredNoteRewrite {
+        immediate_tasks: clean(structured.immediate_tasks),
+        mid_term: clean(structured.mid_term),
+        long_term: clean(structured.long_term),
     }
 }
 
-fn build_survey_hints(state: &SurveyStateRecord) -> Vec<String> {
-    let mut hints = Vec::new();
-    if let Some(goal) = state.answers.get("primary_goal") {
-        hints.push(format!("goal: {}", goal));
-    }
-    if let Some(pressure) = state.answers.get("daily_pressure") {
-        hints.push(format!("pressure: {}", pressure));
-    }
-    if let Some(pattern) = state.answers.get("travel_pattern") {
-        hints.push(format!("travel_pattern: {}", pattern));
-    }
-    if let Some(style) = state.answers.get("trip_style") {
-        hints.push(format!("trip_style: {}", style));
-    }
-    if let Some(gym) = state.answers.get("gym_frequency") {
-        hints.push(format!("gym_frequency: {}", gym));
-    }
-    if let Some(income) = state.answers.get("income_cadence") {
-        hints.push(format!("income_cadence: {}", income));
-    }
-    if let Some(wealth) = state.answers.get("wealth_focus") {
-        hints.push(format!("wealth_focus: {}", wealth));
-    }
-    if let Some(charity) = state.answers.get("charity_commitment") {
-        hints.push(format!("charity_commitment: {}", charity));
-    }
-    hints
+fn render_structured_note(structured: &StructuredNoteRewrite) -> String {
+    let sections = [
+        ("Immediate tasks", &structured.immediate_tasks),
+        ("Mid-term", &structured.mid_term),
+        ("Long-term", &structured.long_term),
+    ];
+    sections
+        .iter()
+        .filter(|(_, items)| !items.is_empty())
+        .map(|(heading, items)| {
+            let lines = items
+                .iter()
+                .map(|item| format!("- {item}"))
+                .collect::<Vec<_>>()
+                .join("\n");
+            format!("{heading}:\n{lines}")
+        })
+        .collect::<Vec<_>>()
+        .join("\n\n")
 }
 
-fn survey_total_questions(answers: &HashMap<String, String>) -> usize {
-    let mut total = 13;
-    if answers
-        .get("daily_pressure")
-        .map(|value| value == "high")
-        .unwrap_or(false)
-    {
-        total += 1;
+fn extract_openai_output_text(payload: &serde_json::Value) -> Option<String> {
+    if let Some(value) = payload.get("output_text").and_then(|value| value.as_str()) {
+        return Some(value.to_string());
     }
-    if answers
-        .get("work_hours")
-        .map(|value| value == "10_plus")
-        .unwrap_or(false)
-    {
-        total += 1;
+    let output = payload.get("output")?.as_array()?;
+    let mut chunks = Vec::new();
+    for item in output {
+        if let Some(content) = item.get("content").and_then(|value| value.as_array()) {
+            for content_item in content {
+                if content_item
+                    .get("type")
+                    .and_then(|value| value.as_str())
+                    .map(|value| value == "output_text")
+                    .unwrap_or(false)
+                {
+                    if let Some(text) = content_item.get("text").and_then(|value| value.as_str()) {
+                        chunks.push(text.to_string());
+                    }
+                }
+            }
+        }
     }
-    if answers
-        .get("stress_trigger")
-        .map(|value| value == "uncertainty")
-        .unwrap_or(false)
-    {
-        total += 1;
+    if chunks.is_empty() {
+        None
+    } else {
+        Some(chunks.join("\n\n"))
     }
-    total
 }
 
-fn next_survey_question(locale: &str, answers: &HashMap<String, String>) -> Option<SurveyQuestion> {
-    let he = locale.starts_with("he");
-    let en = !he;
-
-    let mk = |id: &str,
-              title_he: &str,
-              title_en: &str,
-              desc_he: Option<&str>,
-              desc_en: Option<&str>,
-              kind: &str,
-              choices: Vec<SurveyChoice>,
-              placeholder_he: Option<&str>,
-              placeholder_en: Option<&str>| SurveyQuestion {
-        id: id.to_string(),
-        title: if he { title_he } else { title_en }.to_string(),
-        description: if he { desc_he } else { desc_en }.map(|value| value.to_string()),
-        kind: kind.to_string(),
-        required: true,
-        choices,
-        placeholder: if he { placeholder_he } else { placeholder_en }
-            .map(|value| value.to_string()),
-    };
-
-    if !answers.contains_key("primary_goal") {
-        return Some(mk(
-            "primary_goal",
-            "מה המטרה המרכזית שלך ל-90 הימים הקרובים?",
-            "What is your primary goal for the next 90 days?",
-            Some("זה מכוון את כל ההמלצות והפיד היזום."),
-            Some("This tunes your recommendations and proactive feed."),
-            "choice",
-            vec![
-                survey_choice(he, "wealth", "בניית הכנסה/עושר", "Build income/wealth"),
-                survey_choice(he, "stability", "יציבות וסדר אישי", "Personal stability"),
-                survey_choice(he, "health", "בריאות ואנרגיה", "Health and energy"),
-                survey_choice(he, "mixed", "שילוב הכל", "Mix of all"),
-            ],
-            None,
-            None,
-        ));
+fn build_chat_backend_reply(text: String, body: &serde_json::Value) -> ChatBackendReply {
+    ChatBackendReply {
+        text,
+        response_id: body
+            .get("id")
+            .and_then(|value| value.as_str())
+            .map(ToString::to_string),
+        usage: body.get("usage").filter(|value| value.is_object()).cloned(),
     }
+}
 
-    if !answers.contains_key("daily_pressure") {
-        return Some(mk(
-            "daily_pressure",
-            "כמה עומס אתה מרגיש ביום-יום?",
-            "How much daily pressure are you under?",
-            None,
-            None,
-            "choice",
-            vec![
-                survey_choice(he, "low", "נמוך", "Low"),
-                survey_choice(he, "medium", "בינוני", "Medium"),
-                survey_choice(he, "high", "גבוה", "High"),
-            ],
-            None,
-            None,
-        ));
+fn usage_total_tokens(usage: &serde_json::Value) -> Option<u64> {
+    if let Some(total) = usage.get("total_tokens").and_then(|value| value.as_u64()) {
+        return Some(total);
     }
-
-    if answers
-        .get("daily_pressure")
-        .map(|value| value == "high")
-        .unwrap_or(false)
-        && !answers.contains_key("pressure_source")
-    {
-        return Some(mk(
-            "pressure_source",
-            "מה המקור המרכזי לעומס כרגע?",
-            "What is the main source of pressure right now?",
-            None,
-            None,
-            "choice",
-            vec![
-                survey_choice(he, "money", "כסף", "Money"),
-                survey_choice(he, "time", "זמן", "Time"),
-                survey_choice(he, "uncertainty", "חוסר ודאות", "Uncertainty"),
-                survey_choice(he, "relationships", "יחסים/צוות", "Relationships/team"),
-            ],
-            None,
-            None,
-        ));
+    let input = usage.get("input_tokens").and_then(|value| value.as_u64());
+    let output = usage.get("output_tokens").and_then(|value| value.as_u64());
+    match (input, output) {
+        (None, None) => None,
+        (input, output) => Some(input.unwrap_or(0) + output.unwrap_or(0)),
     }
+}
 
-    if !answers.contains_key("work_hours") {
-        return Some(mk(
-            "work_hours",
-            "כמה שעות עבודה ממוצעות ביום?",
-            "Average work hours per day?",
-            None,
-            None,
-            "choice",
-            vec![
-                survey_choice(he, "under_6", "עד 6", "Up to 6"),
-                survey_choice(he, "6_10", "6-10", "6-10"),
-                survey_choice(he, "10_plus", "10+", "10+"),
-            ],
-            None,
-            None,
-        ));
+fn extract_anthropic_output_text(payload: &serde_json::Value) -> Option<String> {
+    let content = payload.get("content")?.as_array()?;
+    let chunks = content
+        .iter()
+        .filter(|item| item.get("type").and_then(|value| value.as_str()) == Some("text"))
+        .filter_map(|item| item.get("text").and_then(|value| value.as_str()))
+        .map(ToString::to_string)
+        .collect::<Vec<_>>();
+    if chunks.is_empty() {
+        None
+    } else {
+        Some(chunks.join("\n\n"))
     }
+}
 
-    if answers
-        .get("work_hours")
-        .map(|value| value == "10_plus")
-        .unwrap_or(false)
-        && !answers.contains_key("break_structure")
-    {
-        return Some(mk(
-            "break_structure",
-            "איך אתה רוצה שהמערכת תנהל הפסקות?",
-            "How should the system handle your breaks?",
-            None,
-            None,
-            "choice",
-            vec![
-                survey_choice(he, "strict", "משמעת קבועה", "Strict schedule"),
-                survey_choice(he, "flex", "גמיש לפי עומס", "Adaptive to workload"),
-                survey_choice(he, "manual", "ידני בלבד", "Manual only"),
-            ],
-            None,
-            None,
-        ));
-    }
+#[derive(Debug, Clone)]
+pub struct CorsSettings {
+    allowed_methods: Vec<Method>,
+    allowed_headers: Vec<header::HeaderName>,
+    max_age: Duration,
+}
 
-    if !answers.contains_key("stress_trigger") {
-        return Some(mk(
-            "stress_trigger",
-            "מה הטריגר הנפוץ ללחץ/דחיינות?",
-            "What usually triggers stress/procrastination?",
-            None,
-            None,
-            "choice",
-            vec![
-                survey_choice(he, "uncertainty", "חוסר ודאות", "Uncertainty"),
-                survey_choice(he, "fatigue", "עייפות", "Fatigue"),
-                survey_choice(he, "overload", "עומס משימות", "Task overload"),
-                survey_choice(
-                    he,
-                    "social",
-                    "רעש חברתי/התראות",
-                    "Social noise/notifications",
-                ),
-            ],
-            None,
-            None,
-        ));
+// Unparseable entries are dropped; an empty or missing method list falls back to the defaults.
+fn parse_cors_settings(
+    methods: Option<&str>,
+    headers: Option<&str>,
+    max_age_seconds: Option<&str>,
+) -> CorsSettings {
+    let mut allowed_methods = methods
+        .unwrap_or_default()
+        .split(',')
+        .map(|value| value.trim().to_ascii_uppercase())
+        .filter(|value| !value.is_empty())
+        .filter_map(|value| Method::from_bytes(value.as_bytes()).ok())
+        .collect::<Vec<_>>();
+    if allowed_methods.is_empty() {
+        allowed_methods = DEFAULT_CORS_ALLOWED_METHODS
+            .iter()
+            .filter_map(|value| Method::from_bytes(value.as_bytes()).ok())
+            .collect();
     }
+    allowed_methods.dedup();
 
-    if answers
-        .get("stress_trigger")
-        .map(|value| value == "uncertainty")
-        .unwrap_or(false)
-        && !answers.contains_key("proactive_alerts")
+    let mut allowed_headers = REQUIRED_CORS_ALLOWED_HEADERS
+        .iter()
+        .map(|value| header::HeaderName::from_static(value))
+        .collect::<Vec<_>>();
+    for name in headers
+        .unwrap_or_default()
+        .split(',')
+        .filter_map(|value| header::HeaderName::from_bytes(value.trim().as_bytes()).ok())
     {
-        return Some(mk(
-            "proactive_alerts",
-            "איזה סוג עדכונים יזומים יעזור לך?",
-            "Which proactive alerts help you most?",
-            None,
-            None,
-            "choice",
-            vec![
-                survey_choice(he, "daily_brief", "בריף יומי", "Daily brief"),
-                survey_choice(he, "risk_alerts", "התראות סיכון", "Risk alerts"),
-                survey_choice(he, "execution", "דחיפת ביצוע", "Execution nudges"),
-            ],
-            None,
-            None,
-        ));
+        if !allowed_headers.contains(&name) {
+            allowed_headers.push(name);
+        }
     }
 
-    if !answers.contains_key("travel_pattern") {
-        return Some(mk(
-            "travel_pattern",
-            "מה דפוס התנועה שלך?",
-            "What is your movement pattern?",
-            None,
-            None,
-            "choice",
-            vec![
-                survey_choice(
-                    he,
-                    "daily_commute",
-                    "נסיעות יומיות כבדות",
-                    "Heavy daily commuting",
-                ),
-                survey_choice(
-                    he,
-                    "multi_day",
-                    "שהייה מתגלגלת רב-יומית",
-                    "Multi-day rolling travel",
-                ),
-                survey_choice(he, "hybrid", "היברידי", "Hybrid"),
-            ],
-            None,
-            None,
-        ));
-    }
+    let max_age = max_age_seconds
+        .and_then(|value| value.trim().parse::<u64>().ok())
+        .unwrap_or(DEFAULT_CORS_MAX_AGE_SECONDS)
+        .min(MAX_CORS_MAX_AGE_SECONDS);
 
-    if !answers.contains_key("trip_style") {
-        return Some(mk(
-            "trip_style",
-            "מה סגנון המסע המועדף עליך?",
-            "What is your preferred trip style?",
-            Some("נשתמש בזה כדי לכוון מסלולים ופיד יזום."),
-            Some("Used to tune routes and proactive feed recommendations."),
-            "choice",
-            vec![
-                survey_choice(he, "mixed", "משולב", "Mixed"),
-                survey_choice(he, "beach", "חוף", "Beach"),
-                survey_choice(he, "north", "צפון", "North"),
-                survey_choice(he, "desert", "מדבר", "Desert"),
-            ],
-            None,
-            None,
-        ));
+    CorsSettings {
+        allowed_methods,
+        allowed_headers,
+        max_age: Duration::from_secs(max_age),
     }
+}
 
-    if !answers.contains_key("health_priority") {
-        return Some(mk(
-            "health_priority",
-            "מה העדיפות הבריאותית החשובה כרגע?",
-            "Top health priority right now?",
-            None,
-            None,
-            "choice",
-            vec![
-                survey_choice(he, "sleep", "שינה", "Sleep"),
-                survey_choice(he, "focus", "פוקוס וקוגניציה", "Focus/cognition"),
-                survey_choice(he, "stress", "הורדת סטרס", "Stress reduction"),
-                survey_choice(he, "nutrition", "תזונה טובה", "Better nutrition"),
-            ],
-            None,
-            None,
-        ));
+fn build_cors_layer(allowed_origins: &Arc<Vec<String>>, cors: &CorsSettings) -> CorsLayer {
+    let origins = allowed_origins
+        .iter()
+        .filter_map(|origin| HeaderValue::from_str(origin).ok())
+        .collect::<Vec<_>>();
+    let origins = if origins.is_empty() {
+        vec![HeaderValue::from_static("http://localhost:5500")]
+    } else {
+        origins
+    };
+
+    CorsLayer::new()
+        .allow_origin(AllowOrigin::list(origins))
+        .allow_methods(cors.allowed_methods.clone())
+        .allow_headers(cors.allowed_headers.clone())
+        .max_age(cors.max_age)
+        .expose_headers([header::HeaderName::from_static(SERVER_TIME_HEADER)])
+        .allow_credentials(true)
+}
+
+async fn rate_limit_middleware(
+    State(state): State<ApiState>,
+    request: Request<Body>,
+    next: Next,
+) -> Response {
+    if request.method() == Method::OPTIONS {
+        return next.run(request).await;
     }
 
-    if !answers.contains_key("gym_frequency") {
-        return Some(mk(
-            "gym_frequency",
-            "באיזו תדירות אתה מתאמן כרגע?",
-            "How often do you currently train/work out?",
-            Some("המערכת תשתמש בזה לצ׳ק-אין יומי ובניית עקביות."),
-            Some("This powers daily follow-up check-ins and consistency coaching."),
-            "choice",
-            vec![
-                survey_choice(he, "rarely", "כמעט לא", "Rarely"),
-                survey_choice(he, "sometimes", "לפעמים", "Sometimes"),
-                survey_choice(he, "regularly", "באופן קבוע", "Regularly"),
-            ],
-            None,
-            None,
-        ));
+    let path = request.uri().path().to_string();
+    let ip = request_ip(&state, &request);
+
+    if is_auth_rate_limited_endpoint(path.as_str()) {
+        let auth_key = format!("auth:{}:{}", path, ip);
+        if !state.auth_limiter.allow(&auth_key).await {
+            return ApiError::too_many_requests(
+                "auth_rate_limited",
+                "too many authentication attempts from this IP. wait and retry.",
+            )
+            .into_response();
+        }
     }
 
-    if !answers.contains_key("income_cadence") {
-        return Some(mk(
-            "income_cadence",
-            "כמה רציפה ההכנסה שלך כרגע?",
-            "How regular is your income right now?",
-            Some("זה מאפשר למערכת להציע פעולות הכנסה יומיות כשצריך."),
-            Some("This lets Atlas trigger daily income actions when needed."),
-            "choice",
-            vec![
-                survey_choice(he, "none", "ללא הכנסה רציפה", "No regular income"),
-                survey_choice(he, "sometimes", "מדי פעם", "Sometimes"),
-                survey_choice(he, "regularly", "רציפה", "Regularly"),
-            ],
-            None,
-            None,
-        ));
+    if is_public_endpoint(path.as_str()) {
+        return next.run(request).await;
     }
 
-    if !answers.contains_key("wealth_focus") {
-        return Some(mk(
-            "wealth_focus",
-            "מה חשוב לך יותר בשנתיים הקרובות?",
-            "In the next two years, what matters more?",
-            None,
-            None,
-            "choice",
-            vec![
-                survey_choice(he, "income_growth", "צמיחת הכנסה", "Income growth"),
-                survey_choice(he, "capital", "בניית הון", "Capital building"),
-                survey_choice(he, "both", "שניהם יחד", "Both"),
-            ],
-            None,
-            None,
-        ));
+    // Service keys belong to trusted first-party backends, which often share one egress IP;
+    // api_key_middleware still enforces their route scope. Auth endpoints above stay limited.
+    let provided_key = request
+        .headers()
+        .get("x-api-key")
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default();
+    if service_api_key_scope(&state, provided_key).is_some() {
+        return next.run(request).await;
     }
 
-    if !answers.contains_key("charity_commitment") {
-        return Some(mk(
-            "charity_commitment",
-            "איך תרצה לשלב תרומה/נתינה בתכנון?",
-            "How do you want to include charity in planning?",
-            None,
-            None,
-            "choice",
-            vec![
-                survey_choice(
-                    he,
-                    "fixed_percent",
-                    "אחוז קבוע מהכנסות",
-                    "Fixed percent of income",
-                ),
-                survey_choice(he, "milestones", "לפי אבני דרך", "By milestones"),
-                survey_choice(he, "later", "בהמשך", "Later"),
-            ],
-            None,
-            None,
-        ));
+    if !state.limiter.allow(&ip).await {
+        return ApiError::too_many_requests("rate_limited", "rate limit exceeded for this IP")
+            .into_response();
     }
 
-    if !answers.contains_key("support_style") {
-        return Some(mk(
-            "support_style",
-            "איזה סגנון ליווי אתה מעדיף?",
-            "What coaching style do you prefer?",
-            None,
-            None,
-            "choice",
-            vec![
-                survey_choice(he, "direct", "ישיר וחד", "Direct and sharp"),
-                survey_choice(he, "coach", "מאמן תומך", "Supportive coach"),
-                survey_choice(he, "strategic", "אסטרטגי ארוך טווח", "Long-term strategic"),
-            ],
-            None,
-            None,
-        ));
+    next.run(request).await
+}
+
+async fn csrf_origin_middleware(
+    State(state): State<ApiState>,
+    request: Request<Body>,
+    next: Next,
+) -> Response {
+    if request.method() == Method::GET
+        || request.method() == Method::HEAD
+        || request.method() == Method::OPTIONS
+    {
+        return next.run(request).await;
     }
 
-    if !answers.contains_key("voice_preference") {
-        return Some(mk(
-            "voice_preference",
-            "האם אתה רוצה שיחה קולית רציפה עם המערכת?",
-            "Do you want continuous voice conversation with the system?",
-            if en {
-                Some("This can be changed later in Studio settings.")
-            } else {
-                Some("אפשר לשנות בכל רגע בהגדרות הסטודיו.")
-            },
-            if en {
-                Some("This can be changed later in Studio settings.")
-            } else {
-                Some("אפשר לשנות בכל רגע בהגדרות הסטודיו.")
-            },
-            "choice",
-            vec![
-                survey_choice(he, "yes", "כן", "Yes"),
-                survey_choice(he, "sometimes", "לפעמים", "Sometimes"),
-                survey_choice(he, "no", "לא", "No"),
-            ],
-            None,
-            None,
-        ));
+    let has_cookie_session = read_cookie_value(request.headers(), &state.cookie_name).is_some()
+        || read_cookie_value(request.headers(), GUEST_COOKIE_NAME).is_some();
+    if !has_cookie_session {
+        return next.run(request).await;
     }
 
-    None
-}
+    let origin = request
+        .headers()
+        .get(header::HeaderName::from_static("origin"))
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default()
+        .trim()
+        .trim_end_matches('/')
+        .to_string();
+
+    if origin.is_empty() {
+        return ApiError::forbidden(
+            "origin_required",
+            "origin header is required for cookie-authenticated state changes",
+        )
+        .into_response();
+    }
 
-fn survey_choice(is_he: bool, value: &str, he: &str, en: &str) -> SurveyChoice {
-    SurveyChoice {
-        value: value.to_string(),
-        label: if is_he { he } else { en }.to_string(),
+    if !state.allowed_origins.iter().any(|value| value == &origin) {
+        return ApiError::forbidden(
+            "origin_not_allowed",
+            "request origin is not in ATLAS_ALLOWED_ORIGINS",
+        )
+        .into_response();
     }
+
+    next.run(request).await
 }
 
-fn sanitize_enum_value(value: &str, allowed: &[&str], default_value: &str) -> String {
-    let normalized = value.trim().to_lowercase();
-    if allowed.iter().any(|candidate| *candidate == normalized) {
-        normalized
-    } else {
-        default_value.to_string()
-    }
+fn env_body_limit(name: &str, default: usize) -> usize {
+    env::var(name)
+        .ok()
+        .and_then(|value| value.trim().parse::<usize>().ok())
+        .filter(|value| *value > 0)
+        .unwrap_or(default)
 }
 
-fn sanitize_cookie_domain(value: &str) -> Option<String> {
-    let normalized = value
-        .trim()
-        .trim_start_matches('.')
-        .trim_end_matches('.')
-        .to_ascii_lowercase();
-    if normalized.is_empty() {
-        return None;
-    }
-    if normalized
-        .chars()
-        .all(|ch| ch.is_ascii_alphanumeric() || ch == '.' || ch == '-')
-    {
-        Some(normalized)
+fn body_limit_for_path(limits: &BodyLimits, note_max_content_chars: usize, path: &str) -> usize {
+    if is_bulk_endpoint(path) {
+        limits.bulk
+    } else if path == "/v1/notes/upsert" {
+        note_upsert_body_limit(note_max_content_chars).max(limits.default)
+    } else if path.starts_with("/v1/auth/") {
+        limits.auth
     } else {
-        None
+        limits.default
     }
 }
 
-fn sanitize_limited_text(value: &str, max_chars: usize) -> String {
-    value.trim().chars().take(max_chars).collect::<String>()
+// Sized so a note at the character limit always reaches the handler, which then answers with
+// `note_too_long` instead of a bare 413. UTF-8 takes at most 4 bytes per character.
+fn note_upsert_body_limit(note_max_content_chars: usize) -> usize {
+    note_max_content_chars
+        .saturating_mul(4)
+        .saturating_add(NOTE_UPSERT_ENVELOPE_BYTES)
 }
 
-fn normalize_tag(tag: &str) -> String {
-    tag.trim()
-        .chars()
-        .take(MAX_NOTE_TAG_LEN)
-        .filter(|ch| ch.is_ascii_alphanumeric() || *ch == '-' || *ch == '_')
-        .collect::<String>()
-        .to_lowercase()
+fn is_bulk_endpoint(path: &str) -> bool {
+    matches!(path, "/v1/memory/import" | "/v1/memory/import_csv")
 }
 
-fn is_subscription_bypass_email(email: &str) -> bool {
-    let target = email.trim().to_lowercase();
-    if target.is_empty() {
-        return false;
+// Oversized declared lengths are refused without reading the body; chunked bodies are read
+// only up to the route limit, so handlers never see (and we never buffer) more than that.
+async fn body_limit_middleware(
+    State(state): State<ApiState>,
+    request: Request<Body>,
+    next: Next,
+) -> Response {
+    let limit = body_limit_for_path(
+        &state.body_limits,
+        state.note_max_content_chars,
+        request.uri().path(),
+    );
+    let declared_length = request
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().parse::<usize>().ok());
+    if declared_length.is_some_and(|length| length > limit) {
+        return payload_too_large_response(limit);
     }
 
-    let configured = env::var("ATLAS_SUBSCRIPTION_BYPASS_EMAILS")
-        .ok()
-        .filter(|value| !value.trim().is_empty())
-        .unwrap_or_else(|| DEFAULT_SUBSCRIPTION_BYPASS_EMAILS.to_string());
-
-    configured
-        .split(',')
-        .map(|value| value.trim().to_lowercase())
-        .any(|value| !value.is_empty() && value == target)
+    let (parts, body) = request.into_parts();
+    let bytes = match axum::body::to_bytes(body, limit).await {
+        Ok(bytes) => bytes,
+        Err(_) => return payload_too_large_response(limit),
+    };
+    next.run(Request::from_parts(parts, Body::from(bytes)))
+        .await
 }
 
-fn sanitize_note_tags(tags: Vec<String>) -> Vec<String> {
-    tags.into_iter()
-        .map(|tag| normalize_tag(tag.as_str()))
-        .filter(|tag| !tag.is_empty())
-        .take(MAX_NOTE_TAGS)
-        .collect()
+fn payload_too_large_response(limit: usize) -> Response {
+    ApiError::new(
+        StatusCode::PAYLOAD_TOO_LARGE,
+        "payload_too_large",
+        format!("request body exceeds {limit} bytes for this endpoint"),
+    )
+    .into_response()
 }
 
-fn sanitize_memory_type(value: &str) -> String {
-    sanitize_enum_value(
-        value,
-        &[
-            "preference",
-            "mood",
-            "goal",
-            "constraint",
-            "insight",
-            "friction",
-            "identity",
-            "task",
-        ],
-        "insight",
+fn is_auth_rate_limited_endpoint(path: &str) -> bool {
+    matches!(
+        path,
+        "/v1/auth/google/start"
+            | "/v1/auth/google/callback"
+            | "/v1/auth/apple/start"
+            | "/v1/auth/apple/callback"
+            | "/v1/auth/passkey/register/start"
+            | "/v1/auth/passkey/register/finish"
+            | "/v1/auth/passkey/login/start"
+            | "/v1/auth/passkey/login/finish"
+            | "/v1/auth/recovery_codes/redeem"
     )
 }
 
-fn sanitize_memory_stability(value: &str) -> String {
-    sanitize_enum_value(value, &["permanent", "transient"], "transient")
+fn request_ip(state: &ApiState, request: &Request<Body>) -> String {
+    let peer = request
+        .extensions()
+        .get::<ConnectInfo<std::net::SocketAddr>>()
+        .map(|ConnectInfo(addr)| addr.ip());
+    resolve_client_ip(peer, request.headers(), &state.trusted_proxies)
+        .map(|ip| ip.to_string())
+        .unwrap_or_else(|| "local".to_string())
 }
 
-fn sanitize_memory_source(value: &str) -> String {
-    sanitize_enum_value(
-        value,
-        &[
-            "note",
-            "note_rewrite",
-            "survey",
-            "feedback",
-            "chat",
-            "import",
-            "manual",
-            "system",
-        ],
-        "system",
-    )
+// With shared auth state the database decides whether a session cookie is valid: the cached
+// entry is refreshed from `auth_sessions` before any handler resolves the session, so logins
+// and logouts on other instances take effect immediately.
+async fn shared_session_middleware(
+    State(state): State<ApiState>,
+    request: Request<Body>,
+    next: Next,
+) -> Response {
+    if let (Some(shared), Some(session_id)) = (
+        state.shared_auth.as_ref(),
+        read_cookie_value(request.headers(), &state.cookie_name),
+    ) {
+        match shared.load_session(&session_id).await {
+            Ok(Some(session)) => {
+                load_shared_account(&state, &session.user_id).await;
+                state.sessions.write().insert(session_id, session);
+            }
+            Ok(None) => {
+                state.sessions.write().remove(&session_id);
+            }
+            Err(err) => {
+                tracing::warn!(error = %err, "failed to load shared session");
+                state.sessions.write().remove(&session_id);
+            }
+        }
+    }
+    next.run(request).await
 }
 
-fn clamp_memory_weight(weight: f32) -> f32 {
-    if !weight.is_finite() {
-        return 0.5;
+// With shared auth state, accounts created on another instance after this one started exist only
+// in the database. A cache miss loads the user and their passkeys, so a flow started elsewhere
+// can finish here and a later passkey write does not drop credentials this instance never saw.
+async fn load_shared_account(state: &ApiState, user_id: &str) -> Option<UserRecord> {
+    if let Some(user) = state.users.read().get(user_id).cloned() {
+        return Some(user);
     }
-    weight.clamp(0.05, 1.0)
+    let shared = state.shared_auth.as_ref()?;
+    let user = match shared.load_user(user_id).await {
+        Ok(user) => user?,
+        Err(err) => {
+            tracing::warn!(error = %err, "failed to load shared user");
+            return None;
+        }
+    };
+    match shared.load_passkeys(user_id).await {
+        Ok(rows) => {
+            let records = rows
+                .iter()
+                .filter_map(|json| serde_json::from_str::<PasskeyRecord>(json).ok())
+                .collect::<Vec<_>>();
+            if !records.is_empty() {
+                state
+                    .passkeys_by_user
+                    .write()
+                    .entry(user_id.to_string())
+                    .or_insert(records);
+            }
+        }
+        Err(err) => tracing::warn!(error = %err, "failed to load shared passkeys"),
+    }
+    match shared.load_recovery_codes(user_id).await {
+        Ok(rows) => {
+            let records = rows
+                .iter()
+                .filter_map(|json| serde_json::from_str::<RecoveryCodeRecord>(json).ok())
+                .collect::<Vec<_>>();
+            if !records.is_empty() {
+                state
+                    .recovery_codes
+                    .write()
+                    .entry(user_id.to_string())
+                    .or_insert(records);
+            }
+        }
+        Err(err) => tracing::warn!(error = %err, "failed to load shared recovery codes"),
+    }
+    remember_user(state, user.clone());
+    Some(user)
 }
 
-fn memory_fingerprint(memory_type: &str, stability: &str, text: &str) -> String {
-    let normalized = text
-        .trim()
-        .to_lowercase()
-        .chars()
-        .filter(|ch| ch.is_ascii_alphanumeric() || ch.is_ascii_whitespace())
-        .take(300)
-        .collect::<String>();
-    let key = format!("{}|{}|{}", memory_type, stability, normalized);
-    hex_encode(Sha256::digest(key.as_bytes()).as_slice())
+/// Loads the accounts for `email` from the shared store when none of them is cached yet.
+async fn load_shared_accounts_by_email(state: &ApiState, email: &str) {
+    let Some(shared) = state.shared_auth.as_ref() else {
+        return;
+    };
+    if state
+        .user_ids_by_email
+        .read()
+        .contains_key(normalize_email(email).as_str())
+    {
+        return;
+    }
+    match shared.user_ids_for_email(email).await {
+        Ok(user_ids) => {
+            for user_id in user_ids {
+                load_shared_account(state, &user_id).await;
+            }
+        }
+        Err(err) => tracing::warn!(error = %err, "failed to look up shared accounts by email"),
+    }
 }
 
-fn memory_recency_score(updated_at: &str, now: chrono::DateTime<chrono::Utc>) -> f32 {
-    let updated = chrono::DateTime::parse_from_rfc3339(updated_at)
-        .ok()
-        .map(|value| value.with_timezone(&chrono::Utc))
-        .unwrap_or(now);
-    let age_hours = now.signed_duration_since(updated).num_hours().max(0) as f32;
-    (1.0 / (1.0 + (age_hours / 72.0))).clamp(0.0, 1.0)
+async fn stash_shared_challenge<T: Serialize>(
+    state: &ApiState,
+    kind: &str,
+    challenge_id: &str,
+    record: &T,
+    expires_at: chrono::DateTime<chrono::Utc>,
+) -> Result<()> {
+    let Some(shared) = state.shared_auth.as_ref() else {
+        return Ok(());
+    };
+    let stored = match serde_json::to_string(record) {
+        Ok(json) => {
+            shared
+                .put_challenge(kind, challenge_id, json.as_str(), expires_at)
+                .await
+        }
+        Err(err) => Err(err.into()),
+    };
+    if let Err(err) = stored.as_ref() {
+        tracing::warn!(error = %err, kind, "failed to store shared auth challenge");
+    }
+    stored
 }
 
-fn is_memory_expired(record: &MemoryRecord, now: chrono::DateTime<chrono::Utc>) -> bool {
-    record
-        .expires_at
-        .as_deref()
-        .and_then(|value| chrono::DateTime::parse_from_rfc3339(value).ok())
-        .map(|value| value.with_timezone(&chrono::Utc) <= now)
-        .unwrap_or(false)
+// A flow whose challenge is missing from the shared store could never finish, so the start
+// fails up front instead of handing out a challenge that every instance will reject.
+fn shared_challenge_unavailable_response() -> Response {
+    ApiError::service_unavailable(
+        "auth_state_unavailable",
+        "could not save the sign-in state; please retry",
+    )
+    .into_response()
 }
 
-fn prune_expired_memories(records: &mut Vec<MemoryRecord>, now: chrono::DateTime<chrono::Utc>) {
-    records.retain(|entry| !is_memory_expired(entry, now));
+// With shared auth state the database is authoritative: `local` is ignored, and a challenge
+// another instance already consumed (or one that cannot be read back) is rejected, so a
+// challenge can only be finished once across all instances.
+async fn take_shared_challenge<T: serde::de::DeserializeOwned>(
+    state: &ApiState,
+    kind: &str,
+    challenge_id: &str,
+    local: Option<T>,
+) -> Option<T> {
+    let Some(shared) = state.shared_auth.as_ref() else {
+        return local;
+    };
+    match shared.take_challenge(kind, challenge_id).await {
+        Ok(Some(json)) => serde_json::from_str(&json).ok(),
+        Ok(None) => None,
+        Err(err) => {
+            tracing::warn!(error = %err, kind, "failed to load shared auth challenge");
+            None
+        }
+    }
+}
+
+// Opt-in sliding expiration: a session used within the refresh window of its expiry gets a
+// fresh TTL, persisted and re-issued as a cookie, so active users are not logged out mid-use.
+async fn session_sliding_middleware(
+    State(state): State<ApiState>,
+    request: Request<Body>,
+    next: Next,
+) -> Response {
+    let refreshed_session = request
+        .extensions()
+        .get::<CookieAuthenticatedSession>()
+        .and_then(|session| refresh_session_if_due(&state, session.0.as_str()));
+    let mut response = next.run(request).await;
+    let Some(session_id) = refreshed_session else {
+        return response;
+    };
+
+    let _ = persist_session_if_configured(&state, &session_id).await;
+    let cookie_value = build_session_cookie(
+        &state.cookie_name,
+        session_id.as_str(),
+        state.session_ttl.as_secs(),
+        state.cookie_secure,
+        state.cookie_same_site.as_str(),
+        state.cookie_partitioned,
+        state.cookie_domain.as_str(),
+    );
+    if let Ok(header_value) = HeaderValue::from_str(&cookie_value) {
+        response
+            .headers_mut()
+            .append(header::SET_COOKIE, header_value);
+    }
+    response
 }
 
-fn classify_chat_memory(text: &str) -> (String, String, f32) {
-    let lower = text.trim().to_lowercase();
-    if lower.is_empty() {
-        return ("insight".to_string(), "transient".to_string(), 0.5);
+fn refresh_session_if_due(state: &ApiState, session_id: &str) -> Option<String> {
+    let threshold = chrono::Duration::from_std(state.session_refresh_threshold?).ok()?;
+    let now = chrono::Utc::now();
+    let mut sessions = state.sessions.write();
+    let session = sessions.get_mut(session_id)?;
+    if !session_refresh_due(session.expires_at, now, threshold) {
+        return None;
     }
-    if [
-        "stressed",
-        "anxious",
-        "overwhelmed",
-        "tired",
-        "רגוע",
-        "לחוץ",
-        "עייף",
-    ]
-    .iter()
-    .any(|needle| lower.contains(needle))
+    session.expires_at = now + chrono::Duration::seconds(state.session_ttl.as_secs() as i64);
+    Some(session_id.to_string())
+}
+
+fn session_refresh_due(
+    expires_at: chrono::DateTime<chrono::Utc>,
+    now: chrono::DateTime<chrono::Utc>,
+    threshold: chrono::Duration,
+) -> bool {
+    expires_at > now && expires_at - now <= threshold
+}
+
+// Anonymous visitors on guest-capable routes get their own `guest-<uuid>` bucket. A fresh id
+// is written back into the request cookies so handlers resolve it the same way as a returning
+// guest, and it is issued to the client on the way out.
+async fn guest_session_middleware(
+    State(state): State<ApiState>,
+    mut request: Request<Body>,
+    next: Next,
+) -> Response {
+    if !is_guest_endpoint(request.uri().path())
+        || session_user_from_headers(&state, request.headers()).is_some()
     {
-        return ("mood".to_string(), "transient".to_string(), 0.75);
+        return next.run(request).await;
     }
-    if ["plan", "goal", "mission", "target", "יעד", "מטרה", "תוכנית"]
-        .iter()
-        .any(|needle| lower.contains(needle))
-    {
-        return ("goal".to_string(), "permanent".to_string(), 0.82);
-    }
-    if [
-        "prefer",
-        "favorite",
-        "like",
-        "dislike",
-        "preferably",
-        "מעדיף",
-        "אוהב",
-        "לא אוהב",
-    ]
-    .iter()
-    .any(|needle| lower.contains(needle))
-    {
-        return ("preference".to_string(), "permanent".to_string(), 0.8);
+
+    let existing = read_cookie_value(request.headers(), GUEST_COOKIE_NAME)
+        .filter(|value| is_valid_guest_id(value));
+    let issued = existing.is_none();
+    let guest_id = existing.unwrap_or_else(new_guest_id);
+    if issued {
+        sweep_expired_guest_sessions(&state);
+        replace_cookie_value(request.headers_mut(), GUEST_COOKIE_NAME, guest_id.as_str());
+    }
+    touch_guest_session(&state, guest_id.as_str());
+
+    let mut response = next.run(request).await;
+    if issued {
+        let cookie_value = build_session_cookie(
+            GUEST_COOKIE_NAME,
+            guest_id.as_str(),
+            state.guest_ttl.as_secs(),
+            state.cookie_secure,
+            state.cookie_same_site.as_str(),
+            state.cookie_partitioned,
+            state.cookie_domain.as_str(),
+        );
+        if let Ok(header_value) = HeaderValue::from_str(&cookie_value) {
+            response
+                .headers_mut()
+                .append(header::SET_COOKIE, header_value);
+        }
+    }
+    response
+}
+
+async fn security_headers_middleware(
+    State(state): State<ApiState>,
+    request: Request<Body>,
+    next: Next,
+) -> Response {
+    let mut response = next.run(request).await;
+
+    response.headers_mut().insert(
+        header::X_CONTENT_TYPE_OPTIONS,
+        HeaderValue::from_static("nosniff"),
+    );
+    response.headers_mut().insert(
+        header::HeaderName::from_static("x-frame-options"),
+        HeaderValue::from_static("DENY"),
+    );
+    response.headers_mut().insert(
+        header::HeaderName::from_static("referrer-policy"),
+        HeaderValue::from_static("strict-origin-when-cross-origin"),
+    );
+    response.headers_mut().insert(
+        header::HeaderName::from_static("permissions-policy"),
+        HeaderValue::from_static("camera=(), microphone=(), geolocation=(self)"),
+    );
+    response.headers_mut().insert(
+        header::HeaderName::from_static("content-security-policy"),
+        HeaderValue::from_static("default-src 'none'; frame-ancestors 'none'; base-uri 'none'"),
+    );
+    if state.cookie_secure {
+        response.headers_mut().insert(
+            header::HeaderName::from_static("strict-transport-security"),
+            HeaderValue::from_static("max-age=31536000; includeSubDomains; preload"),
+        );
     }
-    ("insight".to_string(), "transient".to_string(), 0.65)
+
+    response
 }
 
-fn classify_survey_memory(question_id: &str, answer: &str) -> (String, String, f32) {
-    let question = question_id.trim().to_lowercase();
-    let answer = answer.trim().to_lowercase();
-    if [
-        "trip_style",
-        "risk_preference",
-        "voice_preference",
-        "language",
-        "gym_frequency",
-        "income_cadence",
-    ]
-    .iter()
-    .any(|needle| question.contains(needle))
-    {
-        return ("preference".to_string(), "permanent".to_string(), 0.88);
-    }
-    if ["goal", "mission", "wealth", "donation", "career"]
-        .iter()
-        .any(|needle| question.contains(needle) || answer.contains(needle))
-    {
-        return ("goal".to_string(), "permanent".to_string(), 0.9);
-    }
-    if ["stress", "fatigue", "mood", "energy", "burnout"]
-        .iter()
-        .any(|needle| question.contains(needle) || answer.contains(needle))
-    {
-        return ("mood".to_string(), "transient".to_string(), 0.8);
+// Lets clients reconcile server-computed times (reminder due dates, snooze options, feed
+// timestamps) against their own clock without a field on every response body.
+async fn server_time_middleware(request: Request<Body>, next: Next) -> Response {
+    let mut response = next.run(request).await;
+    let now = chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true);
+    if let Ok(value) = HeaderValue::from_str(now.as_str()) {
+        response
+            .headers_mut()
+            .insert(header::HeaderName::from_static(SERVER_TIME_HEADER), value);
     }
-    ("insight".to_string(), "transient".to_string(), 0.72)
+    response
 }
 
-fn memory_relevance_score(query: &str, record: &MemoryRecord) -> f32 {
-    let query_tokens = tokenize_memory_text(query);
-    if query_tokens.is_empty() {
-        return 0.0;
-    }
-    let mut corpus = record.text.clone();
-    if !record.tags.is_empty() {
-        corpus.push(' ');
-        corpus.push_str(record.tags.join(" ").as_str());
-    }
-    let record_tokens = tokenize_memory_text(corpus.as_str());
-    if record_tokens.is_empty() {
-        return 0.0;
+#[cfg(test)]
+mod tests {
+    use super::{
+        append_chat_turn, apply_feedback_status, apply_studio_format_guest,
+        apply_webauthn_login_policy, apply_webauthn_registration_policy, build_chat_backend_reply,
+        build_clear_cookie, build_orchestrated_proactive_feed, build_reminder_action, build_router,
+        build_session_cookie, build_spoken_summary, build_state, build_test_stripe_signature,
+        build_webauthn, cap_proactive_feed_items, clamp_utc_offset_minutes, clear_user_memories,
+        cloud_requirements_for_endpoint, coarse_client_network, company_status_etag,
+        complete_provider_link, current_usage_period, decoy_credential_ids,
+        dedupe_suggested_actions, default_company_status, default_execution_controls,
+        default_studio_preferences, energy_level_is_valid, ensure_app_schema, estimate_ai_tokens,
+        extract_anthropic_output_text, find_or_create_user_by_email, fit_context_to_budget,
+        fold_ics_line, if_none_match_matches, index_user_emails, ingest_memory_records_if_opted_in,
+        initial_company_status, is_public_endpoint, is_valid_guest_id, issue_session_for_user,
+        linked_identity_key, load_persistent_state, load_shared_account,
+        locale_from_accept_language, mask_email, matching_sign_in_account, memory_export_lines,
+        memory_fingerprint, merge_feedback_tags, merge_studio_preferences, next_survey_question,
+        normalize_reasoning_effort, note_length_error, notes_etag, parse_company_status_file,
+        parse_cors_settings, parse_ephemeral_memory_types, parse_feed_memory_query_signals,
+        parse_memory_import_csv, parse_memory_sources, parse_premium_system_prompts,
+        parse_rfc3339_or_error, parse_scoped_api_keys, parse_structured_note_rewrite,
+        parse_trusted_client_ip, passkey_login_failure, persist_memories_if_configured,
+        persist_passkeys_if_configured, premium_reply_matches_locale, premium_reply_usable,
+        preview_memory_import, prioritize_execution_tasks, proactive_feed_memory_query,
+        provider_identity_owner, prune_expired_memories_for_all_users, record_chat_turn_for_user,
+        redact_email_addresses, remember_user, reminder_snooze_options, render_structured_note,
+        replace_cookie_value, replace_note_keeping_history, request_id_from_headers,
+        request_origin_from_headers, request_span, resolve_reasoning_effort,
+        restore_trashed_memories, retrieve_memory_context_from_records, route_in_scope,
+        run_ai_healthcheck, sanitize_ai_base_url, sanitize_alarm_days, sanitize_enum_field,
+        sanitize_return_to, sanitize_structured_note_rewrite, schedule_minutes_offset,
+        search_memory_records, service_api_key_matches, session_location_from_headers,
+        session_refresh_due, sign_in_matches_account, snap_to_working_hours, snooze_due_at,
+        spawn_background_tasks, stash_shared_challenge, store_note_rewrite_preview,
+        summarize_execution_week, survey_total_questions, take_shared_challenge,
+        trace_id_from_headers, truncate_on_word_boundary, upsert_session_row, usage_total_tokens,
+        verify_stripe_webhook_signature, ApiState, Arc, ChatAgent, ChatInput, ChatTurnRecord,
+        ConciergeReply, DigestNarrativeRecord, ExecutionCheckinRecord, ExecutionFeedContext,
+        ExecutionTaskCandidate, FeedbackRecord, HashMap, HashSet, IpRateLimiter,
+        LinkedIdentityRecord, MemoryClearFilter, MemoryImportItem, MemoryIngestEvent, MemoryRecord,
+        MemorySearchFilters, Method, OAuthStateRecord, OpenAiRuntimeConfig, ParsedMemoryCsv,
+        Passkey, PasskeyRecord, ProactiveFeedItem, ProviderIdentity, RateLimiter,
+        ReminderActionRequest, SessionRecord, SharedAuthStore, StructuredNoteRewrite,
+        StudioPreferencesRecord, StudioPreferencesUpsertRequest, TrashedMemory, TrustedProxies,
+        Url, UserNoteRecord, UserRecord, WebauthnBuilder, WebauthnRuntimeConfig, CHALLENGE_OAUTH,
+        DECOY_CREDENTIAL_ID_LENGTHS, DEFAULT_FEED_MAX_ITEMS, DEFAULT_PREMIUM_SYSTEM_PROMPT,
+        DEFAULT_STRIPE_WEBHOOK_TOLERANCE_SECONDS, EPHEMERAL_MEMORY_TTL_HOURS,
+        JSON_FORMAT_REPLY_MARKER, MAX_CHAT_CONTEXT_TURNS, MAX_CHAT_SESSIONS_PER_USER,
+        MAX_CHAT_TURNS_PER_SESSION, MAX_FEEDBACK_TAGS, MAX_MEMORY_RECORDS_PER_USER,
+        MAX_NOTES_PER_USER, MAX_NOTE_CONTENT_LEN, MAX_NOTE_TITLE_LEN, MAX_REWRITE_SECTION_ITEMS,
+        MAX_SPOKEN_SUMMARY_CHARS, NOTE_VERSION_HISTORY_LIMIT, STUDIO_PREFERENCE_OPTIONS,
+        URL_SAFE_NO_PAD,
+    };
+    use axum::http::{header, HeaderMap, HeaderValue, StatusCode};
+    use axum::response::Response;
+    use base64::Engine as _;
+    use chrono::Duration;
+    use sqlx::Row as _;
+    use std::time::Instant;
+    use tower::ServiceExt;
+
+    #[test]
+    fn session_cookie_is_secure_and_domain_scoped() {
+        let cookie = build_session_cookie(
+            "atlas_session",
+            "session123",
+            3600,
+            true,
+            "strict",
+            false,
+            "atlasmasa.com",
+        );
+        assert!(cookie.contains("HttpOnly"));
+        assert!(cookie.contains("Secure"));
+        assert!(cookie.contains("SameSite=Strict"));
+        assert!(cookie.contains("Domain=atlasmasa.com"));
     }
-    let overlap = query_tokens
-        .iter()
-        .filter(|token| record_tokens.contains(*token))
-        .count();
-    (overlap as f32 / query_tokens.len() as f32).clamp(0.0, 1.0)
-}
 
-fn tokenize_memory_text(text: &str) -> std::collections::HashSet<String> {
-    text.to_lowercase()
-        .split(|ch: char| !ch.is_ascii_alphanumeric() && !ch.is_alphabetic())
-        .filter(|token| token.len() >= 2)
-        .take(256)
-        .map(|token| token.to_string())
-        .collect()
-}
+    #[test]
+    fn clear_cookie_preserves_security_attributes() {
+        let cookie = build_clear_cookie("atlas_session", true, "lax", false, "atlasmasa.com");
+        assert!(cookie.contains("HttpOnly"));
+        assert!(cookie.contains("Secure"));
+        assert!(cookie.contains("SameSite=Lax"));
+        assert!(cookie.contains("Domain=atlasmasa.com"));
+        assert!(cookie.contains("Max-Age=0"));
+    }
 
-fn ingest_memory_records_if_opted_in(
-    records: &mut Vec<MemoryRecord>,
-    user_id: &str,
-    opt_in: bool,
-    event: MemoryIngestEvent,
-    now: chrono::DateTime<chrono::Utc>,
-) -> Option<MemoryRecord> {
-    if !opt_in {
-        return None;
+    #[test]
+    fn session_cookie_can_be_host_only_without_domain_attribute() {
+        let cookie = build_session_cookie(
+            "atlas_session",
+            "session123",
+            3600,
+            true,
+            "strict",
+            false,
+            "",
+        );
+        assert!(!cookie.contains("Domain="));
     }
 
-    let text = sanitize_limited_text(event.text.as_str(), MAX_MEMORY_TEXT_LEN);
-    if text.is_empty() {
-        return None;
+    #[test]
+    fn partitioned_cookies_require_cross_site_secure_attributes() {
+        let cookie = build_session_cookie(
+            "atlas_session",
+            "session123",
+            3600,
+            true,
+            "none",
+            true,
+            "atlasmasa.com",
+        );
+        assert!(cookie.contains("SameSite=None"));
+        assert!(cookie.contains("Partitioned"));
+        let cleared = build_clear_cookie("atlas_session", true, "none", true, "atlasmasa.com");
+        assert!(cleared.contains("Partitioned"));
+        assert!(cleared.contains("Max-Age=0"));
+
+        let lax = build_session_cookie("atlas_session", "session123", 3600, true, "lax", true, "");
+        assert!(!lax.contains("Partitioned"));
+        let insecure = build_clear_cookie("atlas_session", false, "none", true, "");
+        assert!(!insecure.contains("Partitioned"));
     }
 
-    let memory_type = sanitize_memory_type(event.memory_type.as_str());
-    let stability = sanitize_memory_stability(event.stability.as_str());
-    let source = sanitize_memory_source(event.source.as_str());
-    let tags = sanitize_note_tags(event.tags);
-    let happened_at = event.happened_at.unwrap_or(now);
-    let updated_at = happened_at.to_rfc3339();
-    let weight = clamp_memory_weight(event.weight);
-    let recency_score = memory_recency_score(updated_at.as_str(), now);
-    let expires_at = if stability == "transient" {
-        event
-            .expires_at
-            .or_else(|| Some(happened_at + chrono::Duration::days(TRANSIENT_MEMORY_TTL_DAYS)))
-            .map(|value| value.to_rfc3339())
-    } else {
-        None
-    };
-    let fingerprint = memory_fingerprint(memory_type.as_str(), stability.as_str(), text.as_str());
+    #[test]
+    fn memory_ingestion_deduplicates_and_refreshes_existing_record() {
+        let now = chrono::Utc::now();
+        let mut records = Vec::new();
+        let first = ingest_memory_records_if_opted_in(
+            &mut records,
+            "user-1",
+            true,
+            &[],
+            MemoryIngestEvent {
+                memory_type: "preference".to_string(),
+                stability: "permanent".to_string(),
+                source: "note".to_string(),
+                text: "Prefers desert routes with low crowds".to_string(),
+                weight: 0.80,
+                tags: vec!["travel".to_string()],
+                happened_at: Some(now - Duration::days(2)),
+                expires_at: None,
+            },
+            now,
+        )
+        .expect("first ingestion should create a memory");
+        assert_eq!(records.len(), 1);
 
-    if let Some(index) = records
-        .iter()
-        .position(|entry| entry.fingerprint == fingerprint)
-    {
-        {
-            let existing = &mut records[index];
-            existing.source = source;
-            existing.text = text;
-            existing.weight = clamp_memory_weight((existing.weight + weight) / 2.0);
-            existing.recency_score = recency_score;
-            existing.updated_at = updated_at;
-            existing.expires_at = expires_at;
-            existing.tags = sanitize_note_tags(
-                existing
-                    .tags
-                    .iter()
-                    .cloned()
-                    .chain(tags)
-                    .collect::<Vec<_>>(),
+        let second = ingest_memory_records_if_opted_in(
+            &mut records,
+            "user-1",
+            true,
+            &[],
+            MemoryIngestEvent {
+                memory_type: "preference".to_string(),
+                stability: "permanent".to_string(),
+                source: "survey".to_string(),
+                text: "Prefers desert routes with low crowds".to_string(),
+                weight: 0.96,
+                tags: vec!["survey_trip_style".to_string()],
+                happened_at: Some(now),
+                expires_at: None,
+            },
+            now,
+        )
+        .expect("duplicate ingestion should update existing memory");
+
+        assert_eq!(records.len(), 1);
+        assert_eq!(first.memory_id, second.memory_id);
+        assert_eq!(records[0].source, "survey");
+        assert!(records[0].weight > 0.85);
+        assert!(records[0].tags.iter().any(|tag| tag == "survey_trip_style"));
+    }
+
+    #[test]
+    fn memory_reingestion_never_erodes_weight() {
+        let now = chrono::Utc::now();
+        let mut records = Vec::new();
+        let mut previous_weight = 0.0_f32;
+        for (index, weight) in [0.92_f32, 0.65, 0.65, 0.5, 0.65].into_iter().enumerate() {
+            ingest_memory_records_if_opted_in(
+                &mut records,
+                "user-1",
+                true,
+                &[],
+                MemoryIngestEvent {
+                    memory_type: "goal".to_string(),
+                    stability: "permanent".to_string(),
+                    source: "chat".to_string(),
+                    text: "Launch the Negev guided route this season".to_string(),
+                    weight,
+                    tags: Vec::new(),
+                    happened_at: Some(now),
+                    expires_at: None,
+                },
+                now,
+            )
+            .expect("ingestion should succeed");
+            assert_eq!(records.len(), 1);
+            assert!(
+                records[0].weight >= previous_weight,
+                "weight dropped on ingest {index}: {} -> {}",
+                previous_weight,
+                records[0].weight
             );
+            previous_weight = records[0].weight;
         }
-        let updated = records[index].clone();
-        prune_expired_memories(records, now);
-        return Some(updated);
+        assert!(previous_weight > 0.92);
+        assert!(previous_weight <= 1.0);
     }
 
-    let created = MemoryRecord {
-        memory_id: uuid::Uuid::new_v4().to_string(),
-        user_id: user_id.to_string(),
-        memory_type,
-        stability,
-        source,
-        text,
-        weight,
-        recency_score,
-        tags,
-        created_at: now.to_rfc3339(),
-        updated_at,
-        expires_at,
-        fingerprint,
-    };
-    records.push(created.clone());
-    prune_expired_memories(records, now);
-    records.sort_by(|lhs, rhs| {
-        let lhs_score = lhs.weight * 0.7 + lhs.recency_score * 0.3;
-        let rhs_score = rhs.weight * 0.7 + rhs.recency_score * 0.3;
-        rhs_score.total_cmp(&lhs_score)
-    });
-    records.truncate(MAX_MEMORY_RECORDS_PER_USER);
-    Some(created)
-}
-
-fn retrieve_memory_context_from_records(
-    records: &[MemoryRecord],
-    query: &str,
-    limit: usize,
-    now: chrono::DateTime<chrono::Utc>,
-) -> Vec<MemoryRetrievedItem> {
-    let top_limit = limit.clamp(1, MAX_MEMORY_RETRIEVAL_LIMIT);
-    let mut scored = records
-        .iter()
-        .filter(|record| !is_memory_expired(record, now))
-        .map(|record| {
-            let recency_score = memory_recency_score(record.updated_at.as_str(), now);
-            let relevance_score = memory_relevance_score(query, record);
-            let stability_boost = if record.stability == "permanent" {
-                0.05
-            } else {
-                0.0
-            };
-            let final_score = (record.weight * 0.45
-                + recency_score * 0.3
-                + relevance_score * 0.25
-                + stability_boost)
-                .clamp(0.0, 1.2);
-            MemoryRetrievedItem {
-                memory_id: record.memory_id.clone(),
-                memory_type: record.memory_type.clone(),
-                stability: record.stability.clone(),
-                source: record.source.clone(),
-                text: record.text.clone(),
-                weight: record.weight,
-                recency_score,
-                relevance_score,
-                final_score,
-                tags: record.tags.clone(),
-                updated_at: record.updated_at.clone(),
-            }
-        })
-        .collect::<Vec<_>>();
-    scored.sort_by(|lhs, rhs| rhs.final_score.total_cmp(&lhs.final_score));
-    scored.truncate(top_limit);
-    scored
-}
+    #[test]
+    fn memory_retrieval_orders_by_relevance_and_recency() {
+        let now = chrono::Utc::now();
+        let records = vec![
+            MemoryRecord {
+                memory_id: "memory-1".to_string(),
+                user_id: "user-1".to_string(),
+                memory_type: "preference".to_string(),
+                stability: "permanent".to_string(),
+                source: "survey".to_string(),
+                text: "User prefers desert routes and silence".to_string(),
+                weight: 0.95,
+                recency_score: 0.1,
+                tags: vec!["desert".to_string()],
+                created_at: (now - Duration::days(7)).to_rfc3339(),
+                updated_at: (now - Duration::days(3)).to_rfc3339(),
+                expires_at: None,
+                fingerprint: "f1".to_string(),
+                text_original_sealed: None,
+            },
+            MemoryRecord {
+                memory_id: "memory-2".to_string(),
+                user_id: "user-1".to_string(),
+                memory_type: "mood".to_string(),
+                stability: "transient".to_string(),
+                source: "chat".to_string(),
+                text: "User feels slightly tired this morning".to_string(),
+                weight: 0.60,
+                recency_score: 1.0,
+                tags: vec!["energy".to_string()],
+                created_at: (now - Duration::hours(5)).to_rfc3339(),
+                updated_at: (now - Duration::hours(3)).to_rfc3339(),
+                expires_at: Some((now + Duration::days(2)).to_rfc3339()),
+                fingerprint: "f2".to_string(),
+                text_original_sealed: None,
+            },
+        ];
 
-fn user_memory_opt_in(state: &ApiState, user_id: &str) -> bool {
-    state
-        .users
-        .read()
-        .get(user_id)
-        .map(|user| user.memory_opt_in)
-        .unwrap_or(false)
-}
+        let ranked = retrieve_memory_context_from_records(&records, "desert route", 5, None, now);
+        assert_eq!(ranked.len(), 2);
+        assert_eq!(ranked[0].memory_id, "memory-1");
+        assert!(ranked[0].final_score > ranked[1].final_score);
 
-fn retrieve_user_memory_context(
-    state: &ApiState,
-    user_id: &str,
-    query: &str,
-    limit: usize,
-) -> Vec<MemoryRetrievedItem> {
-    if !user_memory_opt_in(state, user_id) {
-        return Vec::new();
+        let chat_only = parse_memory_sources(" Chat ,bogus").expect("chat is a known source");
+        let scoped = retrieve_memory_context_from_records(
+            &records,
+            "desert route",
+            5,
+            Some(chat_only.as_slice()),
+            now,
+        );
+        assert_eq!(scoped.len(), 1);
+        assert_eq!(scoped[0].source, "chat");
+        assert_eq!(parse_memory_sources("bogus"), None);
     }
-    let snapshot = state
-        .user_memories
-        .read()
-        .get(user_id)
-        .cloned()
-        .unwrap_or_default();
-    retrieve_memory_context_from_records(snapshot.as_slice(), query, limit, chrono::Utc::now())
-}
 
-async fn ingest_memory_event_for_user(
-    state: &ApiState,
-    user_id: &str,
-    event: MemoryIngestEvent,
-) -> Option<MemoryRecord> {
-    let now = chrono::Utc::now();
-    let opt_in = user_memory_opt_in(state, user_id);
-    let ingested = {
-        let mut memories_map = state.user_memories.write();
-        let records = memories_map.entry(user_id.to_string()).or_default();
-        ingest_memory_records_if_opted_in(records, user_id, opt_in, event, now)
-    };
-    if ingested.is_some() {
-        let _ = persist_memories_if_configured(state, user_id).await;
+    #[test]
+    fn memory_ingestion_respects_privacy_opt_out() {
+        let now = chrono::Utc::now();
+        let mut records = Vec::new();
+        let ingested = ingest_memory_records_if_opted_in(
+            &mut records,
+            "user-1",
+            false,
+            &[],
+            MemoryIngestEvent {
+                memory_type: "goal".to_string(),
+                stability: "permanent".to_string(),
+                source: "chat".to_string(),
+                text: "Build a strong weekly execution cadence".to_string(),
+                weight: 0.88,
+                tags: vec!["execution".to_string()],
+                happened_at: Some(now),
+                expires_at: None,
+            },
+            now,
+        );
+        assert!(ingested.is_none());
+        assert!(records.is_empty());
     }
-    ingested
-}
 
-async fn clear_user_memories_by_scope(state: &ApiState, user_id: &str, scope: &str) -> usize {
-    let removed_count = {
-        let mut memories_map = state.user_memories.write();
-        let Some(records) = memories_map.get_mut(user_id) else {
-            return 0;
+    #[test]
+    fn chat_history_is_capped_per_session() {
+        let mut turns = Vec::new();
+        let turn = |session_id: &str, index: usize| ChatTurnRecord {
+            turn_id: format!("{session_id}-{index}"),
+            session_id: session_id.to_string(),
+            user_id: "user-1".to_string(),
+            user_text: format!("question {index}"),
+            assistant_text: format!("answer {index}"),
+            created_at: chrono::Utc::now().to_rfc3339(),
         };
-        let before = records.len();
-        match scope {
-            "permanent" => records.retain(|entry| entry.stability != "permanent"),
-            "transient" => records.retain(|entry| entry.stability != "transient"),
-            _ => records.clear(),
+        append_chat_turn(&mut turns, turn("session-b", 0));
+        for index in 0..MAX_CHAT_TURNS_PER_SESSION + 5 {
+            append_chat_turn(&mut turns, turn("session-a", index));
         }
-        before.saturating_sub(records.len())
-    };
-    if removed_count > 0 {
-        let _ = persist_memories_if_configured(state, user_id).await;
-    }
-    removed_count
-}
-
-fn parse_or_default_utc(
-    input: Option<&str>,
-    fallback: chrono::DateTime<chrono::Utc>,
-) -> chrono::DateTime<chrono::Utc> {
-    input
-        .and_then(|value| chrono::DateTime::parse_from_rfc3339(value).ok())
-        .map(|value| value.with_timezone(&chrono::Utc))
-        .unwrap_or(fallback)
-}
 
-fn pct_encode(input: &str) -> String {
-    let mut output = String::with_capacity(input.len() * 2);
-    for byte in input.bytes() {
-        if byte.is_ascii_alphanumeric() || matches!(byte, b'-' | b'_' | b'.' | b'~') {
-            output.push(byte as char);
-        } else {
-            output.push('%');
-            output.push_str(&format!("{:02X}", byte));
+        let session_a = turns
+            .iter()
+            .filter(|entry| entry.session_id == "session-a")
+            .collect::<Vec<_>>();
+        assert_eq!(session_a.len(), MAX_CHAT_TURNS_PER_SESSION);
+        assert_eq!(session_a[0].turn_id, "session-a-5");
+        assert!(turns.iter().any(|entry| entry.session_id == "session-b"));
+
+        // session-b is the least recently used, so it goes first once the user is over the cap.
+        for index in 0..MAX_CHAT_SESSIONS_PER_USER - 2 {
+            let session_id = format!("session-{index}");
+            assert_eq!(append_chat_turn(&mut turns, turn(&session_id, 0)), None);
         }
+        assert_eq!(
+            append_chat_turn(&mut turns, turn("session-new", 0)),
+            Some("session-b".to_string())
+        );
+        assert!(turns.iter().all(|entry| entry.session_id != "session-b"));
+        assert!(turns.iter().any(|entry| entry.session_id == "session-a"));
     }
-    output
-}
-
-fn escape_ics(input: &str) -> String {
-    input
-        .replace('\\', "\\\\")
-        .replace(';', "\\;")
-        .replace(',', "\\,")
-        .replace('\n', "\\n")
-}
 
-fn is_valid_hhmm(value: &str) -> bool {
-    let parts = value.split(':').collect::<Vec<_>>();
-    if parts.len() != 2 {
-        return false;
+    #[test]
+    fn suggested_actions_are_deduplicated_by_type_and_title() {
+        let reminder = |label: &str, title: &str| atlas_core::SuggestedAction {
+            action_type: "create_reminder".to_string(),
+            label: label.to_string(),
+            payload: serde_json::json!({ "title": title }),
+        };
+        let mut actions = vec![
+            reminder("Create reminder", "Atlas/אטלס follow-up"),
+            reminder("Set reminder", "atlas/אטלס follow-up "),
+            reminder("Create reminder", "Review budget"),
+            atlas_core::SuggestedAction {
+                action_type: "create_alarm".to_string(),
+                label: "Create alarm".to_string(),
+                payload: serde_json::json!({ "label": "Atlas/אטלס follow-up" }),
+            },
+        ];
+        dedupe_suggested_actions(&mut actions);
+        assert_eq!(actions.len(), 3);
+        assert_eq!(actions[0].label, "Create reminder");
+        assert_eq!(actions[2].action_type, "create_alarm");
     }
-    let hour = parts[0].parse::<u8>().ok();
-    let minute = parts[1].parse::<u8>().ok();
-    matches!((hour, minute), (Some(h), Some(m)) if h < 24 && m < 60)
-}
-
-fn parse_allowed_origins() -> Vec<String> {
-    let default_origins = [
-        "http://localhost:5500",
-        "http://127.0.0.1:5500",
-        "http://localhost:3000",
-        "http://127.0.0.1:3000",
-        "https://atlasmasa.com",
-        "https://www.atlasmasa.com",
-    ];
-
-    env::var("ATLAS_ALLOWED_ORIGINS")
-        .ok()
-        .map(|value| {
-            value
-                .split(',')
-                .map(|origin| origin.trim().trim_end_matches('/').to_string())
-                .filter(|origin| !origin.is_empty())
-                .collect::<Vec<_>>()
-        })
-        .unwrap_or_else(|| {
-            default_origins
-                .iter()
-                .map(|value| value.trim_end_matches('/').to_string())
-                .collect()
-        })
-}
-
-fn build_google_oauth_config() -> Option<GoogleOAuthConfig> {
-    let client_id = env::var("ATLAS_GOOGLE_CLIENT_ID").ok()?;
-    let client_secret = env::var("ATLAS_GOOGLE_CLIENT_SECRET").ok()?;
-    let redirect_uri = env::var("ATLAS_GOOGLE_REDIRECT_URI").ok()?;
-    let frontend_origin = env::var("ATLAS_FRONTEND_ORIGIN")
-        .ok()
-        .unwrap_or_else(|| "https://atlasmasa.com".to_string());
-
-    Some(GoogleOAuthConfig {
-        client_id,
-        client_secret,
-        redirect_uri,
-        frontend_origin,
-    })
-}
-
-fn build_apple_oauth_config() -> Option<AppleOAuthConfig> {
-    let client_id = env::var("ATLAS_APPLE_CLIENT_ID").ok()?;
-    let client_secret = env::var("ATLAS_APPLE_CLIENT_SECRET").ok()?;
-    let redirect_uri = env::var("ATLAS_APPLE_REDIRECT_URI").ok()?;
-    let frontend_origin = env::var("ATLAS_FRONTEND_ORIGIN")
-        .ok()
-        .unwrap_or_else(|| "https://atlasmasa.com".to_string());
-
-    Some(AppleOAuthConfig {
-        client_id,
-        client_secret,
-        redirect_uri,
-        frontend_origin,
-    })
-}
-
-fn build_openai_runtime_config() -> Option<OpenAiRuntimeConfig> {
-    let api_key = env::var("ATLAS_OPENAI_API_KEY").ok()?;
-    let model = env::var("ATLAS_OPENAI_MODEL").unwrap_or_else(|_| "gpt-5.2".to_string());
-    let default_reasoning_effort =
-        env::var("ATLAS_OPENAI_REASONING_EFFORT").unwrap_or_else(|_| "high".to_string());
-
-    Some(OpenAiRuntimeConfig {
-        api_key,
-        model,
-        default_reasoning_effort,
-    })
-}
 
-fn build_billing_runtime_config() -> Option<BillingRuntimeConfig> {
-    let stripe_secret_key = env::var("ATLAS_STRIPE_SECRET_KEY").ok()?;
-    let monthly_price_id = env::var("ATLAS_STRIPE_MONTHLY_PRICE_ID").ok()?;
-    let success_url = env::var("ATLAS_STRIPE_SUCCESS_URL").unwrap_or_else(|_| {
-        "https://atlasmasa.com/concierge-local.html?billing=success".to_string()
-    });
-    let cancel_url = env::var("ATLAS_STRIPE_CANCEL_URL").unwrap_or_else(|_| {
-        "https://atlasmasa.com/concierge-local.html?billing=cancel".to_string()
-    });
-    let stripe_webhook_secret = env::var("ATLAS_STRIPE_WEBHOOK_SECRET")
-        .ok()
-        .filter(|value| !value.trim().is_empty());
-    let stripe_webhook_tolerance_seconds = env::var("ATLAS_STRIPE_WEBHOOK_TOLERANCE_SECONDS")
-        .ok()
-        .and_then(|value| value.trim().parse::<u64>().ok())
-        .map(|value| value.clamp(30, 86_400))
-        .unwrap_or(DEFAULT_STRIPE_WEBHOOK_TOLERANCE_SECONDS);
+    #[test]
+    fn scheduling_offsets_follow_cadence_and_horizon() {
+        let aggressive_daily = schedule_minutes_offset("aggressive", "daily", 0);
+        let steady_daily = schedule_minutes_offset("steady", "daily", 0);
+        let steady_mid = schedule_minutes_offset("steady", "mid_term", 0);
+        let steady_long = schedule_minutes_offset("steady", "long_term", 0);
+        assert!(aggressive_daily < steady_daily);
+        assert!(steady_mid > steady_daily);
+        assert!(steady_long > steady_mid);
+    }
 
-    Some(BillingRuntimeConfig {
-        stripe_secret_key,
-        stripe_webhook_secret,
-        stripe_webhook_tolerance_seconds,
-        monthly_price_id,
-        success_url,
-        cancel_url,
-    })
-}
+    #[test]
+    fn prioritization_prefers_urgent_daily_execution() {
+        let ranked = prioritize_execution_tasks(vec![
+            ExecutionTaskCandidate {
+                task_id: "long-a".to_string(),
+                title: "Long mission planning".to_string(),
+                detail: "Prepare annual strategic narrative".to_string(),
+                source: "survey".to_string(),
+                horizon: "long_term".to_string(),
+                urgency: 0.45,
+                impact: 0.95,
+                confidence: 0.85,
+            },
+            ExecutionTaskCandidate {
+                task_id: "daily-a".to_string(),
+                title: "Next action now".to_string(),
+                detail: "Ship the current customer deliverable in this block".to_string(),
+                source: "checkin".to_string(),
+                horizon: "daily".to_string(),
+                urgency: 0.97,
+                impact: 0.82,
+                confidence: 0.94,
+            },
+        ]);
 
-fn build_webauthn_runtime() -> Option<WebauthnRuntimeConfig> {
-    let rp_id = env::var("ATLAS_WEBAUTHN_RP_ID")
-        .ok()
-        .unwrap_or_else(|| "atlasmasa.com".to_string());
-    let origin = env::var("ATLAS_WEBAUTHN_ORIGIN")
-        .ok()
-        .unwrap_or_else(|| "https://atlasmasa.com".to_string());
-    let rp_name = env::var("ATLAS_WEBAUTHN_RP_NAME")
-        .ok()
-        .unwrap_or_else(|| "Atlas/אטלס".to_string());
+        assert!(!ranked.is_empty());
+        assert_eq!(ranked[0].task_id, "daily-a");
+    }
 
-    let origin_url = Url::parse(origin.as_str()).ok()?;
-    let builder = WebauthnBuilder::new(rp_id.as_str(), &origin_url)
-        .ok()?
-        .rp_name(rp_name.as_str());
-    let webauthn = builder.build().ok()?;
+    #[test]
+    fn survey_includes_gym_and_income_cadence_questions() {
+        let mut answers = std::collections::HashMap::new();
+        answers.insert("primary_goal".to_string(), "wealth".to_string());
+        answers.insert("daily_pressure".to_string(), "medium".to_string());
+        answers.insert("work_hours".to_string(), "6_10".to_string());
+        answers.insert("stress_trigger".to_string(), "overload".to_string());
+        answers.insert("travel_pattern".to_string(), "hybrid".to_string());
+        answers.insert("trip_style".to_string(), "mixed".to_string());
+        answers.insert("health_priority".to_string(), "focus".to_string());
 
-    Some(WebauthnRuntimeConfig {
-        webauthn: Arc::new(webauthn),
-    })
-}
+        let gym_q = next_survey_question("en", &answers).expect("gym question should exist");
+        assert_eq!(gym_q.id, "gym_frequency");
+        answers.insert("gym_frequency".to_string(), "regularly".to_string());
 
-fn generate_urlsafe_token(bytes: usize) -> String {
-    let mut buffer = vec![0_u8; bytes];
-    rng().fill_bytes(buffer.as_mut_slice());
-    URL_SAFE_NO_PAD.encode(buffer)
-}
+        let income_q =
+            next_survey_question("en", &answers).expect("income cadence question should exist");
+        assert_eq!(income_q.id, "income_cadence");
+    }
 
-fn sanitize_return_to(value: &str) -> String {
-    let cleaned = value.trim();
-    if cleaned.is_empty() {
-        return "/concierge-local.html".to_string();
+    #[test]
+    fn survey_total_questions_accounts_for_new_baseline_questions() {
+        let answers = std::collections::HashMap::<String, String>::new();
+        assert_eq!(survey_total_questions(&answers), 13);
     }
-    if cleaned.starts_with('/') && !cleaned.starts_with("//") {
-        return cleaned.to_string();
+
+    #[test]
+    fn stripe_webhook_signature_accepts_valid_recent_payload() {
+        let payload = r#"{"type":"checkout.session.completed"}"#;
+        let secret = "whsec_test_secret";
+        let now = chrono::Utc::now().timestamp();
+        let signature = build_test_stripe_signature(payload, secret, now)
+            .expect("signature generation should succeed");
+        assert!(verify_stripe_webhook_signature(
+            signature.as_str(),
+            payload,
+            secret,
+            DEFAULT_STRIPE_WEBHOOK_TOLERANCE_SECONDS,
+        ));
     }
-    "/concierge-local.html".to_string()
-}
 
-async fn verify_apple_id_token(
-    http_client: &Client,
-    id_token: &str,
-    expected_client_id: &str,
-) -> Result<AppleIdTokenClaims> {
-    let mut segments = id_token.split('.');
-    let header_segment = segments
-        .next()
-        .context("apple id_token missing header segment")?;
-    let payload_segment = segments
-        .next()
-        .context("apple id_token missing payload segment")?;
-    let signature_segment = segments
-        .next()
-        .context("apple id_token missing signature segment")?;
-    if segments.next().is_some() {
-        anyhow::bail!("apple id_token has invalid segment count");
+    #[test]
+    fn stripe_webhook_signature_rejects_replay_outside_tolerance() {
+        let payload = r#"{"type":"checkout.session.completed"}"#;
+        let secret = "whsec_test_secret";
+        let old = chrono::Utc::now().timestamp() - 900;
+        let signature = build_test_stripe_signature(payload, secret, old)
+            .expect("signature generation should succeed");
+        assert!(!verify_stripe_webhook_signature(
+            signature.as_str(),
+            payload,
+            secret,
+            DEFAULT_STRIPE_WEBHOOK_TOLERANCE_SECONDS,
+        ));
     }
 
-    let header_bytes = URL_SAFE_NO_PAD
-        .decode(header_segment)
-        .context("failed to decode apple id_token header segment")?;
-    let header: AppleJwtHeader =
-        serde_json::from_slice(&header_bytes).context("failed to parse apple id_token header")?;
-    if header.alg.as_deref() != Some("RS256") {
-        anyhow::bail!("unexpected apple id_token signing algorithm");
+    #[test]
+    fn request_origin_parses_origin_header_first() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::ORIGIN,
+            HeaderValue::from_static("https://atlasmasa.com"),
+        );
+        assert_eq!(
+            request_origin_from_headers(&headers).as_deref(),
+            Some("https://atlasmasa.com")
+        );
     }
-    let Some(kid) = header.kid.as_deref() else {
-        anyhow::bail!("apple id_token missing kid");
-    };
 
-    let payload_bytes = URL_SAFE_NO_PAD
-        .decode(payload_segment)
-        .context("failed to decode apple id_token payload segment")?;
-    let claims: AppleIdTokenClaims =
-        serde_json::from_slice(&payload_bytes).context("failed to parse apple id_token claims")?;
+    #[test]
+    fn request_origin_does_not_trust_referer_without_origin_header() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::REFERER,
+            HeaderValue::from_static("https://www.atlasmasa.com/concierge-local.html?launch=chat"),
+        );
+        assert_eq!(request_origin_from_headers(&headers), None);
+    }
 
-    let signature = URL_SAFE_NO_PAD
-        .decode(signature_segment)
-        .context("failed to decode apple id_token signature segment")?;
+    #[test]
+    fn guest_ids_are_namespaced_and_replace_forged_cookies() {
+        assert!(is_valid_guest_id(
+            "guest-6f1c2a0e-4b7d-4c55-9a43-2f4b8d1e7c90"
+        ));
+        assert!(!is_valid_guest_id("guest"));
+        assert!(!is_valid_guest_id("guest-admin"));
+        assert!(!is_valid_guest_id(
+            "user-6f1c2a0e-4b7d-4c55-9a43-2f4b8d1e7c90"
+        ));
 
-    let jwks = http_client
-        .get("https://appleid.apple.com/auth/keys")
-        .send()
-        .await
-        .context("failed to fetch apple jwks")?
-        .error_for_status()
-        .context("apple jwks non-success status")?
-        .json::<AppleJwksResponse>()
-        .await
-        .context("failed to parse apple jwks")?;
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::COOKIE,
+            HeaderValue::from_static("atlas_guest=guest; theme=dark"),
+        );
+        replace_cookie_value(&mut headers, "atlas_guest", "guest-new");
+        assert_eq!(
+            headers.get(header::COOKIE).and_then(|v| v.to_str().ok()),
+            Some("theme=dark; atlas_guest=guest-new")
+        );
+    }
 
-    let Some(jwk) = jwks.keys.into_iter().find(|record| {
-        let key_id_match = record.kid.as_deref() == Some(kid);
-        let key_type_ok = record.kty.as_deref().unwrap_or_default() == "RSA";
-        let alg_ok = record.alg.as_deref().unwrap_or_default() == "RS256";
-        key_id_match && key_type_ok && alg_ok
-    }) else {
-        anyhow::bail!("apple jwk for token kid not found");
-    };
+    #[test]
+    fn return_to_accepts_known_first_party_pages() {
+        assert_eq!(
+            sanitize_return_to("/concierge-local.html"),
+            "/concierge-local.html"
+        );
+        assert_eq!(
+            sanitize_return_to("/signin.html?api_base=https%3A%2F%2Fapi.atlasmasa.com"),
+            "/signin.html?api_base=https%3A%2F%2Fapi.atlasmasa.com"
+        );
+        assert_eq!(sanitize_return_to("/tool-notes.html"), "/tool-notes.html");
+    }
 
-    let n = jwk.n.context("apple jwk missing modulus")?;
-    let e = jwk.e.context("apple jwk missing exponent")?;
-    let modulus = URL_SAFE_NO_PAD
-        .decode(n.as_bytes())
-        .context("failed to decode apple jwk modulus")?;
-    let exponent = URL_SAFE_NO_PAD
-        .decode(e.as_bytes())
-        .context("failed to decode apple jwk exponent")?;
+    #[test]
+    fn return_to_rejects_redirect_bypass_payloads() {
+        for payload in [
+            "",
+            "//evil.com",
+            "/\\evil.com",
+            "\\\\evil.com",
+            "/%2F%2Fevil.com",
+            "/%2f/evil.com",
+            "/%5Cevil.com",
+            "/%252F%252Fevil.com",
+            "/%E0%A4%A",
+            "https://evil.com",
+            "javascript:alert(1)",
+            "/javascript:alert(1)",
+            "/tool-notes.html/../../admin",
+            "/concierge-local.html\t//evil.com",
+            "/admin",
+        ] {
+            assert_eq!(
+                sanitize_return_to(payload),
+                "/concierge-local.html",
+                "payload {payload:?} should fall back"
+            );
+        }
+    }
 
-    let signed_payload = format!("{header_segment}.{payload_segment}");
-    let public_key = RsaPublicKeyComponents {
-        n: modulus.as_slice(),
-        e: exponent.as_slice(),
-    };
-    public_key
-        .verify(
-            &RSA_PKCS1_2048_8192_SHA256,
-            signed_payload.as_bytes(),
-            signature.as_slice(),
-        )
-        .map_err(|_| anyhow::anyhow!("apple id_token signature verification failed"))?;
+    #[test]
+    fn scoped_api_keys_limit_routes_by_prefix() {
+        let keys = parse_scoped_api_keys(
+            r#"{"feedback-key": ["/v1/feedback/submit", "/v1/company/"], "bad-key": ["*"]}"#,
+        );
+        assert_eq!(keys.len(), 1);
+        let scopes = keys[0].route_prefixes.as_slice();
+        assert!(route_in_scope("/v1/feedback/submit", scopes));
+        assert!(route_in_scope("/v1/company/status", scopes));
+        assert!(!route_in_scope("/v1/feedback/submitted", scopes));
+        assert!(!route_in_scope("/v1/notes", scopes));
+        assert!(parse_scoped_api_keys("not json").is_empty());
+    }
 
-    let valid_iss = claims.iss.as_deref() == Some("https://appleid.apple.com");
-    if !valid_iss {
-        anyhow::bail!("apple id_token issuer mismatch");
+    #[test]
+    fn trusted_proxy_ip_parsing_is_strict() {
+        let forwarded = parse_trusted_client_ip("x-forwarded-for", "6.6.6.6, 203.0.113.45");
+        assert_eq!(
+            forwarded.map(coarse_client_network).as_deref(),
+            Some("203.0.113.0/24")
+        );
+        assert_eq!(
+            parse_trusted_client_ip("cf-connecting-ip", "2001:db8:85a3::8a2e:370:7334")
+                .map(coarse_client_network)
+                .as_deref(),
+            Some("2001:db8:85a3::/48")
+        );
+        assert!(parse_trusted_client_ip("cf-connecting-ip", "6.6.6.6, 203.0.113.45").is_none());
+        assert!(parse_trusted_client_ip("x-forwarded-for", "203.0.113.45, evil").is_none());
+        assert!(parse_trusted_client_ip("x-real-ip", "localhost").is_none());
     }
-    let valid_aud = claims
-        .aud
-        .as_ref()
-        .map(|aud| aud.includes(expected_client_id))
-        .unwrap_or(false);
-    if !valid_aud {
-        anyhow::bail!("apple id_token audience mismatch");
+
+    #[tokio::test]
+    async fn session_location_headers_are_only_read_from_trusted_proxies() {
+        let mut state = test_state().await;
+        state.trusted_proxy_header = Some("cf-connecting-ip".to_string());
+        state.trusted_region_header = Some("cf-ipcountry".to_string());
+        state.trusted_proxies = TrustedProxies::parse("10.0.0.0/8");
+        let mut headers = HeaderMap::new();
+        headers.insert("cf-connecting-ip", HeaderValue::from_static("203.0.113.45"));
+        headers.insert("cf-ipcountry", HeaderValue::from_static("il"));
+
+        let proxy = "10.1.2.3".parse().ok();
+        assert_eq!(
+            session_location_from_headers(&state, proxy, &headers),
+            (Some("203.0.113.0/24".to_string()), Some("IL".to_string()))
+        );
+        // A client connecting directly is placed by its own address, whatever it sends.
+        let direct = "198.51.100.7".parse().ok();
+        assert_eq!(
+            session_location_from_headers(&state, direct, &headers),
+            (Some("198.51.100.0/24".to_string()), None)
+        );
     }
 
-    Ok(claims)
-}
+    #[test]
+    fn company_status_etag_tracks_content() {
+        let mut status = default_company_status();
+        let etag = company_status_etag(&status);
+        assert_eq!(etag, company_status_etag(&status));
+        assert!(etag.starts_with('"') && etag.ends_with('"'));
 
-fn bool_from_jsonish(value: &serde_json::Value) -> Option<bool> {
-    if let Some(parsed) = value.as_bool() {
-        return Some(parsed);
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::IF_NONE_MATCH,
+            HeaderValue::from_str(format!("\"stale\", W/{etag}").as_str()).unwrap(),
+        );
+        assert!(if_none_match_matches(&headers, etag.as_str()));
+
+        status.message = "Updated".to_string();
+        assert_ne!(company_status_etag(&status), etag);
+        assert!(!if_none_match_matches(
+            &headers,
+            company_status_etag(&status).as_str()
+        ));
     }
-    value.as_str().and_then(|parsed| match parsed {
-        "true" | "1" => Some(true),
-        "false" | "0" => Some(false),
-        _ => None,
-    })
-}
 
-fn cloud_requirements_for_endpoint(path: &str) -> (bool, bool) {
-    let needs_cloud_storage = matches!(
-        path,
-        "/v1/profile/upsert"
-            | "/v1/notes"
-            | "/v1/notes/upsert"
-            | "/v1/notes/rewrite"
-            | "/v1/memory/import"
-            | "/v1/memory/records"
-            | "/v1/memory/upsert"
-            | "/v1/memory/delete"
-            | "/v1/memory/clear"
-            | "/v1/studio/preferences"
-            | "/v1/survey/next"
-            | "/v1/survey/answer"
-            | "/v1/feed/proactive"
-            | "/v1/execution/checkin"
-            | "/v1/execution/refresh"
-            | "/v1/execution/controls"
-            | "/v1/feedback/submit"
-            | "/v1/actions/reminder"
-            | "/v1/actions/alarm"
-    ) || path.starts_with("/v1/feedback/employee/");
+    #[test]
+    fn auth_event_emails_are_masked() {
+        assert_eq!(mask_email("jane.doe@atlasmasa.com"), "j***@atlasmasa.com");
+        assert_eq!(mask_email("@atlasmasa.com"), "***@atlasmasa.com");
+        assert_eq!(mask_email("not-an-email"), "***");
+    }
+
+    #[test]
+    fn service_api_key_match_requires_exact_key() {
+        assert!(service_api_key_matches("dev-atlas-key", "dev-atlas-key"));
+        assert!(!service_api_key_matches("dev-atlas-kez", "dev-atlas-key"));
+        assert!(!service_api_key_matches(
+            "dev-atlas-key-extra",
+            "dev-atlas-key"
+        ));
+        assert!(!service_api_key_matches("", "dev-atlas-key"));
+        assert!(!service_api_key_matches("", ""));
+    }
 
-    let needs_cloud_compute = matches!(
-        path,
-        "/v1/chat"
-            | "/v1/plan_trip"
-            | "/v1/notes/rewrite"
-            | "/v1/feed/proactive"
-            | "/v1/execution/refresh"
-            | "/v1/actions/reminder"
-            | "/v1/actions/alarm"
-    );
+    #[test]
+    fn openai_base_url_requires_https() {
+        assert_eq!(
+            sanitize_ai_base_url("https://gateway.internal.example/openai/v1/").as_deref(),
+            Some("https://gateway.internal.example/openai/v1")
+        );
+        assert_eq!(
+            sanitize_ai_base_url("http://gateway.internal.example"),
+            None
+        );
+        assert_eq!(sanitize_ai_base_url("not a url"), None);
+    }
 
-    (needs_cloud_storage, needs_cloud_compute)
-}
+    #[test]
+    fn ai_usage_is_bucketed_by_month_with_token_estimates() {
+        let now = chrono::DateTime::parse_from_rfc3339("2026-03-31T23:59:00Z")
+            .unwrap()
+            .with_timezone(&chrono::Utc);
+        assert_eq!(current_usage_period(now), "2026-03");
+        assert_eq!(current_usage_period(now + Duration::minutes(2)), "2026-04");
+        assert_eq!(estimate_ai_tokens("abcd", "efghi"), 3);
+        assert_eq!(estimate_ai_tokens("", ""), 0);
+    }
 
-fn is_public_endpoint(path: &str) -> bool {
-    matches!(
-        path,
-        "/health"
-            | "/v1/auth/me"
-            | "/v1/auth/logout"
-            | "/v1/auth/google/start"
-            | "/v1/auth/google/callback"
-            | "/v1/auth/apple/start"
-            | "/v1/auth/apple/callback"
-            | "/v1/auth/passkey/register/start"
-            | "/v1/auth/passkey/register/finish"
-            | "/v1/auth/passkey/login/start"
-            | "/v1/auth/passkey/login/finish"
-            | "/v1/billing/stripe_webhook"
-    )
-}
+    #[test]
+    fn backend_reply_surfaces_response_id_and_usage() {
+        let body = serde_json::json!({
+            "id": "resp_abc123",
+            "output_text": "Ready.",
+            "usage": { "input_tokens": 120, "output_tokens": 30, "total_tokens": 150 }
+        });
+        let reply = build_chat_backend_reply("Ready.".to_string(), &body);
+        assert_eq!(reply.response_id.as_deref(), Some("resp_abc123"));
+        assert_eq!(reply.usage.as_ref().and_then(usage_total_tokens), Some(150));
+        assert_eq!(
+            usage_total_tokens(&serde_json::json!({ "input_tokens": 10, "output_tokens": 5 })),
+            Some(15)
+        );
+        assert_eq!(usage_total_tokens(&serde_json::json!({})), None);
+    }
 
-async fn ensure_app_schema(pool: &SqlitePool) -> Result<()> {
-    sqlx::query(
-        r#"
-        CREATE TABLE IF NOT EXISTS auth_users (
-          user_id TEXT PRIMARY KEY,
-          provider TEXT NOT NULL,
-          email TEXT NOT NULL,
-          name TEXT NOT NULL,
-          locale TEXT NOT NULL,
-          trip_style TEXT,
-          risk_preference TEXT,
-          memory_opt_in INTEGER NOT NULL,
-          passkey_user_handle TEXT,
-          created_at TEXT NOT NULL,
-          updated_at TEXT NOT NULL
+    #[test]
+    fn structured_note_rewrite_parses_and_renders_sections() {
+        let structured = parse_structured_note_rewrite(
+            r#"{"immediate_tasks":["Call the garage"," "],"mid_term":["Book the Negev route"],"long_term":[]}"#,
+        )
+        .expect("structured output should parse");
+        assert_eq!(structured.immediate_tasks, vec!["Call the garage"]);
+        assert_eq!(
+            render_structured_note(&structured),
+            "Immediate tasks:\n- Call the garage\n\nMid-term:\n- Book the Negev route"
         );
-        "#,
-    )
-    .execute(pool)
-    .await?;
+        assert!(parse_structured_note_rewrite("plain prose reply").is_err());
+    }
 
-    sqlx::query(
-        r#"
-        CREATE TABLE IF NOT EXISTS auth_sessions (
-          session_id TEXT PRIMARY KEY,
-          user_id TEXT NOT NULL,
-          expires_at TEXT NOT NULL,
-          created_at TEXT NOT NULL
+    #[test]
+    fn anthropic_output_text_joins_text_blocks() {
+        let payload = serde_json::json!({
+            "id": "msg_123",
+            "content": [
+                { "type": "text", "text": "First block" },
+                { "type": "tool_use", "id": "tool_1" },
+                { "type": "text", "text": "Second block" }
+            ]
+        });
+        assert_eq!(
+            extract_anthropic_output_text(&payload).as_deref(),
+            Some("First block\n\nSecond block")
         );
-        "#,
-    )
-    .execute(pool)
-    .await?;
+        assert_eq!(
+            extract_anthropic_output_text(&serde_json::json!({ "content": [] })),
+            None
+        );
+    }
 
-    sqlx::query(
-        r#"
-        CREATE TABLE IF NOT EXISTS studio_preferences (
-          user_id TEXT PRIMARY KEY,
-          data_json TEXT NOT NULL
+    #[test]
+    fn cloud_requirements_classify_paths_correctly() {
+        assert_eq!(cloud_requirements_for_endpoint("/v1/chat"), (false, true));
+        assert_eq!(
+            cloud_requirements_for_endpoint("/v1/plan_trip"),
+            (false, true)
         );
-        "#,
-    )
-    .execute(pool)
-    .await?;
+        assert_eq!(
+            cloud_requirements_for_endpoint("/v1/notes/upsert"),
+            (true, false)
+        );
+        assert_eq!(
+            cloud_requirements_for_endpoint("/v1/notes/rewrite_preview"),
+            (true, true)
+        );
+        assert_eq!(
+            cloud_requirements_for_endpoint("/v1/feed/proactive"),
+            (true, true)
+        );
+        assert_eq!(
+            cloud_requirements_for_endpoint("/v1/auth/me"),
+            (false, false)
+        );
+    }
 
-    sqlx::query(
-        r#"
-        CREATE TABLE IF NOT EXISTS survey_states (
-          user_id TEXT PRIMARY KEY,
-          data_json TEXT NOT NULL
+    #[test]
+    fn public_endpoints_include_session_probe_and_logout() {
+        assert!(is_public_endpoint("/health"));
+        assert!(is_public_endpoint("/v1/auth/me"));
+        assert!(is_public_endpoint("/v1/auth/logout"));
+        assert!(is_public_endpoint("/v1/auth/sessions"));
+        assert!(!is_public_endpoint("/v1/profile/upsert"));
+    }
+
+    #[test]
+    fn sliding_sessions_refresh_only_inside_the_window() {
+        let now = chrono::Utc::now();
+        let threshold = chrono::Duration::days(7);
+        assert!(session_refresh_due(
+            now + chrono::Duration::days(2),
+            now,
+            threshold
+        ));
+        assert!(!session_refresh_due(
+            now + chrono::Duration::days(20),
+            now,
+            threshold
+        ));
+        assert!(!session_refresh_due(
+            now - chrono::Duration::seconds(1),
+            now,
+            threshold
+        ));
+    }
+
+    #[test]
+    fn enum_fields_report_coerced_values() {
+        let mut coerced = Vec::new();
+        let allowed = ["mixed", "beach", "north"];
+        assert_eq!(
+            sanitize_enum_field("trip_style", " Beach ", &allowed, "mixed", &mut coerced),
+            "beach"
         );
-        "#,
-    )
-    .execute(pool)
-    .await?;
+        assert!(coerced.is_empty());
+        assert_eq!(
+            sanitize_enum_field("trip_style", "mountains", &allowed, "mixed", &mut coerced),
+            "mixed"
+        );
+        assert_eq!(coerced.len(), 1);
+        assert_eq!(coerced[0].field, "trip_style");
+        assert_eq!(coerced[0].provided, "mountains");
+        assert_eq!(coerced[0].applied, "mixed");
+    }
 
-    sqlx::query(
-        r#"
-        CREATE TABLE IF NOT EXISTS feedback_items (
-          feedback_id TEXT PRIMARY KEY,
-          data_json TEXT NOT NULL
+    #[test]
+    fn memory_csv_rows_parse_with_per_row_errors() {
+        let csv = "title,content,tags,source,happened_at\n\
+                   Trip,Beach weekend,\"travel;family\",sheets,2025-05-01T10:00:00Z\n\
+                   Only title,,,,\n\
+                   Too,many,columns,here,now,extra\n\
+                   Gym,Three times a week,,,\n";
+        let ParsedMemoryCsv { items, row_errors } =
+            parse_memory_import_csv(csv).expect("valid header");
+        assert_eq!(items.len(), 3);
+        assert_eq!(items[0].0, 2);
+        assert_eq!(
+            items[0].2.map(|value| value.to_rfc3339()).as_deref(),
+            Some("2025-05-01T10:00:00+00:00")
         );
-        "#,
-    )
-    .execute(pool)
-    .await?;
+        assert_eq!(
+            items[0].1.tags.as_deref(),
+            Some(&["travel".to_string(), "family".to_string()][..])
+        );
+        assert_eq!(items[0].1.source.as_deref(), Some("sheets"));
+        assert_eq!(items[1].1.content, "");
+        assert_eq!(row_errors.len(), 1);
+        assert_eq!(row_errors[0].row, 4);
+        assert!(parse_memory_import_csv("name,body\nx,y\n").is_err());
+    }
 
-    sqlx::query(
-        r#"
-        CREATE TABLE IF NOT EXISTS user_notes (
-          note_id TEXT PRIMARY KEY,
-          user_id TEXT NOT NULL,
-          data_json TEXT NOT NULL
+    #[test]
+    fn memory_import_preview_explains_each_item() {
+        let now = chrono::Utc::now();
+        let item = |title: &str, content: &str| {
+            let item = MemoryImportItem {
+                title: title.to_string(),
+                content: content.to_string(),
+                tags: None,
+                source: None,
+                happened_at: None,
+            };
+            (item, None)
+        };
+        let existing = HashSet::from([memory_fingerprint(
+            "insight",
+            "permanent",
+            "Known: already stored",
+        )]);
+        let preview = preview_memory_import(
+            "user-1",
+            vec![
+                item("Trip", "Beach weekend"),
+                item("Trip", "Beach weekend"),
+                item("Known", "already stored"),
+                item("  ", "no title"),
+            ],
+            &existing,
+            true,
+            false,
+            MAX_NOTE_CONTENT_LEN,
+            now,
         );
-        "#,
-    )
-    .execute(pool)
-    .await?;
+        let reasons: Vec<_> = preview.iter().map(|entry| entry.reason).collect();
+        assert_eq!(
+            reasons,
+            [
+                "new_memory",
+                "duplicate_in_batch",
+                "reinforces_existing_memory",
+                "empty_after_sanitization"
+            ]
+        );
+        assert!(!preview[3].would_import);
 
-    sqlx::query(
-        r#"
-        CREATE TABLE IF NOT EXISTS user_memories (
-          memory_id TEXT PRIMARY KEY,
-          user_id TEXT NOT NULL,
-          data_json TEXT NOT NULL
+        let opted_out = preview_memory_import(
+            "user-1",
+            vec![item("Trip", "Beach weekend")],
+            &existing,
+            false,
+            false,
+            MAX_NOTE_CONTENT_LEN,
+            now,
         );
-        "#,
-    )
-    .execute(pool)
-    .await?;
+        assert_eq!(opted_out[0].reason, "note_only_memory_opt_out");
 
-    sqlx::query(
-        r#"
-        CREATE TABLE IF NOT EXISTS execution_checkins (
-          checkin_id TEXT PRIMARY KEY,
-          user_id TEXT NOT NULL,
-          data_json TEXT NOT NULL
+        let scrubbed_existing = HashSet::from([memory_fingerprint(
+            "insight",
+            "permanent",
+            "Hotel: call [redacted phone]",
+        )]);
+        let phone_item = || vec![item("Hotel", "call 054-123-4567")];
+        let scrubbed = preview_memory_import(
+            "user-1",
+            phone_item(),
+            &scrubbed_existing,
+            true,
+            true,
+            MAX_NOTE_CONTENT_LEN,
+            now,
         );
-        "#,
-    )
-    .execute(pool)
-    .await?;
+        assert_eq!(scrubbed[0].reason, "reinforces_existing_memory");
+        let unscrubbed = preview_memory_import(
+            "user-1",
+            phone_item(),
+            &scrubbed_existing,
+            true,
+            false,
+            MAX_NOTE_CONTENT_LEN,
+            now,
+        );
+        assert_eq!(unscrubbed[0].reason, "new_memory");
+    }
 
-    sqlx::query(
-        r#"
-        CREATE TABLE IF NOT EXISTS execution_controls (
-          user_id TEXT PRIMARY KEY,
-          data_json TEXT NOT NULL
+    #[test]
+    fn accept_language_prefers_highest_quality_supported_locale() {
+        let with_language = |value: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(
+                header::ACCEPT_LANGUAGE,
+                HeaderValue::from_str(value).unwrap(),
+            );
+            headers
+        };
+        assert_eq!(
+            locale_from_accept_language(&with_language("en-US;q=0.7, he-IL, fr;q=0.9")).as_deref(),
+            Some("he")
         );
-        "#,
-    )
-    .execute(pool)
-    .await?;
+        assert_eq!(
+            locale_from_accept_language(&with_language("de-DE, ru;q=0.4, ar;q=0.5")).as_deref(),
+            Some("ar")
+        );
+        assert_eq!(
+            locale_from_accept_language(&with_language("iw")).as_deref(),
+            Some("he")
+        );
+        assert_eq!(
+            locale_from_accept_language(&with_language("de, ja;q=0.8, en;q=0")),
+            None
+        );
+        assert_eq!(locale_from_accept_language(&HeaderMap::new()), None);
+    }
+
+    #[test]
+    fn ics_lines_fold_at_75_octets_without_splitting_characters() {
+        let short = "SUMMARY:Pick up the van";
+        assert_eq!(fold_ics_line(short), short);
+
+        let line = format!("DESCRIPTION:{}", "שלום עולם, ".repeat(12));
+        let folded = fold_ics_line(line.as_str());
+        let physical_lines: Vec<&str> = folded.split("\r\n").collect();
+        assert!(physical_lines.len() > 1);
+        for (index, physical) in physical_lines.iter().enumerate() {
+            assert!(
+                physical.len() <= 75,
+                "line {index} has {} octets",
+                physical.len()
+            );
+            if index > 0 {
+                assert!(physical.starts_with(' '));
+            }
+        }
+        assert_eq!(folded.replace("\r\n ", ""), line);
+    }
+
+    #[test]
+    fn alarm_days_are_deduplicated_into_week_order() {
+        let days = |values: &[&str]| {
+            sanitize_alarm_days(Some(values.iter().map(|value| value.to_string()).collect()))
+        };
+        assert_eq!(
+            days(&["Wed", "monday", "SAT", "mon", "Sunday"]),
+            ["Sun", "Mon", "Wed", "Sat"]
+        );
+        assert_eq!(days(&["Fri", "fri", "Thurs"]), ["Thu", "Fri"]);
+        assert_eq!(days(&["someday"]), ["Sun", "Mon", "Tue", "Wed", "Thu"]);
+        assert_eq!(
+            sanitize_alarm_days(None),
+            ["Sun", "Mon", "Tue", "Wed", "Thu"]
+        );
+    }
 
-    sqlx::query(
-        r#"
-        CREATE TABLE IF NOT EXISTS passkeys (
-          passkey_id TEXT PRIMARY KEY,
-          user_id TEXT NOT NULL,
-          data_json TEXT NOT NULL
+    #[test]
+    fn studio_preference_options_match_record_defaults() {
+        let defaults = serde_json::to_value(default_studio_preferences("user-1")).unwrap();
+        for option in STUDIO_PREFERENCE_OPTIONS {
+            assert_eq!(
+                defaults[option.field], option.default_value,
+                "{}",
+                option.field
+            );
+            assert!(option.allowed.contains(&option.default_value));
+        }
+    }
+
+    #[test]
+    fn studio_preference_merge_reports_rejected_values() {
+        let mut coerced = Vec::new();
+        let merged = merge_studio_preferences(
+            default_studio_preferences("user-1"),
+            StudioPreferencesUpsertRequest {
+                user_id: None,
+                preferred_format: Some("haiku".to_string()),
+                response_depth: Some("Quick".to_string()),
+                response_tone: None,
+                proactive_mode: None,
+                reminders_app: None,
+                alarms_app: Some("sundial".to_string()),
+                voice_mode: None,
+                utc_offset_minutes: None,
+                working_hours_start: None,
+                working_hours_end: None,
+                working_days: None,
+            },
+            &mut coerced,
         );
-        "#,
-    )
-    .execute(pool)
-    .await?;
+        assert_eq!(merged.preferred_format, "structured_plan");
+        assert_eq!(merged.response_depth, "quick");
+        let fields: Vec<_> = coerced.iter().map(|entry| entry.field).collect();
+        assert_eq!(fields, ["preferred_format", "alarms_app"]);
+        assert_eq!(coerced[1].provided, "sundial");
+    }
 
-    sqlx::query(
-        r#"
-        CREATE TABLE IF NOT EXISTS billing_subscriptions (
-          user_id TEXT PRIMARY KEY,
-          stripe_customer_id TEXT,
-          stripe_subscription_id TEXT,
-          status TEXT NOT NULL,
-          current_period_end TEXT,
-          updated_at TEXT NOT NULL
+    #[test]
+    fn spoken_summary_strips_markdown_and_stays_short() {
+        let reply =
+            "## Weekend plan\n- **Day 1:** drive to the Dead Sea.\n- Day 2: hike `Ein Gedi`.\n\n";
+        assert_eq!(
+            build_spoken_summary(reply),
+            "Weekend plan Day 1: drive to the Dead Sea. Day 2: hike Ein Gedi."
         );
-        "#,
-    )
-    .execute(pool)
-    .await?;
 
-    Ok(())
-}
+        let long_reply = "Stay hydrated. ".repeat(40);
+        let summary = build_spoken_summary(long_reply.as_str());
+        assert!(summary.chars().count() <= MAX_SPOKEN_SUMMARY_CHARS);
+        assert!(summary.ends_with('.'));
 
-async fn load_persistent_state(pool: Option<&SqlitePool>) -> Result<PersistedState> {
-    let Some(pool) = pool else {
-        return Ok(PersistedState::default());
-    };
+        let run_on = "word ".repeat(100);
+        let summary = build_spoken_summary(run_on.as_str());
+        assert!(summary.ends_with('…'));
+        assert!(summary.chars().count() <= MAX_SPOKEN_SUMMARY_CHARS + 1);
+    }
 
-    let mut state = PersistedState::default();
+    #[test]
+    fn json_studio_format_returns_an_object() {
+        let mut prefs = default_studio_preferences("user-1");
+        prefs.preferred_format = "json".to_string();
+        let reply =
+            apply_studio_format_guest("Pack water.".to_string(), &prefs, atlas_core::Locale::En);
+        assert_eq!(reply.text, JSON_FORMAT_REPLY_MARKER);
+        let formatted = reply.formatted_response.expect("json object");
+        assert!(formatted.is_object());
+        assert_eq!(formatted["response"], "Pack water.");
+        assert_eq!(formatted["tone"], "executive");
+    }
 
-    let users = sqlx::query(
-        r#"
-        SELECT user_id, provider, email, name, locale, trip_style, risk_preference, memory_opt_in, passkey_user_handle, created_at, updated_at
-        FROM auth_users
-        "#,
-    )
-    .fetch_all(pool)
-    .await?;
-    for row in users {
-        let user = UserRecord {
-            user_id: row.get("user_id"),
-            provider: row.get("provider"),
-            email: row.get("email"),
-            name: row.get("name"),
-            locale: row.get("locale"),
-            trip_style: row.get("trip_style"),
-            risk_preference: row.get("risk_preference"),
-            memory_opt_in: row.get::<i64, _>("memory_opt_in") > 0,
-            passkey_user_handle: row.get("passkey_user_handle"),
-            created_at: row.get("created_at"),
-            updated_at: row.get("updated_at"),
+    #[test]
+    fn raw_studio_format_returns_the_base_reply_unchanged() {
+        let mut prefs = default_studio_preferences("user-1");
+        prefs.preferred_format = "raw".to_string();
+        let reply =
+            apply_studio_format_guest("Pack water.".to_string(), &prefs, atlas_core::Locale::He);
+        assert_eq!(reply.text, "Pack water.");
+        assert!(reply.formatted_response.is_none());
+    }
+
+    #[test]
+    fn executive_tone_prefix_only_decorates_text_formats() {
+        let mut prefs = default_studio_preferences("user-1");
+        prefs.response_tone = "executive".to_string();
+        let render = |format: &str, prefs: &mut StudioPreferencesRecord| {
+            prefs.preferred_format = format.to_string();
+            apply_studio_format_guest("Pack water.".to_string(), prefs, atlas_core::Locale::En)
         };
-        state.users.insert(user.user_id.clone(), user);
+
+        let checklist = render("checklist", &mut prefs);
+        assert!(checklist.text.starts_with("Executive standard:"));
+
+        let json = render("json", &mut prefs);
+        assert!(!json.text.contains("Executive standard"));
+        let formatted = json.formatted_response.expect("json object");
+        assert!(!formatted.to_string().contains("Executive standard"));
+        assert_eq!(formatted["tone"], "executive");
+
+        let raw = render("raw", &mut prefs);
+        assert_eq!(raw.text, "Pack water.");
     }
 
-    let sessions =
-        sqlx::query("SELECT session_id, user_id, expires_at, created_at FROM auth_sessions")
-            .fetch_all(pool)
-            .await?;
-    for row in sessions {
-        let expires_at = row
-            .get::<String, _>("expires_at")
-            .parse()
-            .unwrap_or_else(|_| chrono::Utc::now());
-        let created_at = row
-            .get::<String, _>("created_at")
-            .parse()
-            .unwrap_or_else(|_| chrono::Utc::now());
-        state.sessions.insert(
-            row.get("session_id"),
-            SessionRecord {
-                user_id: row.get("user_id"),
-                expires_at,
-                created_at,
-            },
+    #[test]
+    fn replies_truncate_on_word_boundaries() {
+        assert_eq!(truncate_on_word_boundary("short reply", 80), None);
+        assert_eq!(
+            truncate_on_word_boundary("drive north then hike", 14).as_deref(),
+            Some("drive north…")
+        );
+        assert_eq!(
+            truncate_on_word_boundary("שלום עולם ומה שלומך", 12).as_deref(),
+            Some("שלום עולם…")
+        );
+        assert_eq!(
+            truncate_on_word_boundary("abcdefghij", 4).as_deref(),
+            Some("abcd…")
         );
     }
 
-    let studio = sqlx::query("SELECT user_id, data_json FROM studio_preferences")
-        .fetch_all(pool)
-        .await?;
-    for row in studio {
-        let json: String = row.get("data_json");
-        if let Ok(value) = serde_json::from_str::<StudioPreferencesRecord>(&json) {
-            state.studio_preferences.insert(row.get("user_id"), value);
+    struct SlowChatAgent(std::time::Duration);
+
+    impl ChatAgent for SlowChatAgent {
+        fn handle_chat(
+            &self,
+            _input: ChatInput,
+        ) -> futures::future::BoxFuture<'_, anyhow::Result<ConciergeReply>> {
+            Box::pin(async move {
+                tokio::time::sleep(self.0).await;
+                anyhow::bail!("the slow agent should have been cancelled")
+            })
         }
     }
 
-    let surveys = sqlx::query("SELECT user_id, data_json FROM survey_states")
-        .fetch_all(pool)
-        .await?;
-    for row in surveys {
-        let json: String = row.get("data_json");
-        if let Ok(value) = serde_json::from_str::<SurveyStateRecord>(&json) {
-            state.survey_states.insert(row.get("user_id"), value);
-        }
+    #[tokio::test]
+    async fn chat_returns_json_504_when_the_agent_misses_the_deadline() {
+        let mut state = test_state().await;
+        let mut service = HeaderMap::new();
+        service.insert("x-api-key", HeaderValue::from_str(&state.api_key).unwrap());
+        let chat = || {
+            json_post(
+                "/v1/chat",
+                serde_json::json!({ "text": "Plan a weekend in Haifa" }),
+                &service,
+            )
+        };
+
+        state.chat_timeout = std::time::Duration::from_millis(50);
+        let real_agent = std::mem::replace(
+            &mut state.chat_agent,
+            Arc::new(SlowChatAgent(std::time::Duration::from_secs(5))),
+        );
+        let started = std::time::Instant::now();
+        let response = build_router(state.clone()).oneshot(chat()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::GATEWAY_TIMEOUT);
+        assert_eq!(response_json(response).await["error"], "chat_timeout");
+        assert!(started.elapsed() < std::time::Duration::from_secs(5));
+
+        state.chat_agent = real_agent;
+        state.chat_timeout = std::time::Duration::from_secs(30);
+        let response = build_router(state).oneshot(chat()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
     }
 
-    let feedback = sqlx::query("SELECT data_json FROM feedback_items")
-        .fetch_all(pool)
-        .await?;
-    for row in feedback {
-        let json: String = row.get("data_json");
-        if let Ok(value) = serde_json::from_str::<FeedbackRecord>(&json) {
-            state.feedback_items.push(value);
-        }
+    #[test]
+    fn memory_clear_filter_combines_scope_and_tags() {
+        let record = |stability: &str, tags: &[&str]| MemoryRecord {
+            memory_id: "memory-1".to_string(),
+            user_id: "user-1".to_string(),
+            memory_type: "insight".to_string(),
+            stability: stability.to_string(),
+            source: "import".to_string(),
+            text: "Imported card".to_string(),
+            weight: 0.7,
+            recency_score: 1.0,
+            tags: tags.iter().map(|tag| tag.to_string()).collect(),
+            created_at: chrono::Utc::now().to_rfc3339(),
+            updated_at: chrono::Utc::now().to_rfc3339(),
+            expires_at: None,
+            fingerprint: "f1".to_string(),
+            text_original_sealed: None,
+        };
+        let tags = vec!["source_trello".to_string(), "travel".to_string()];
+        let any = MemoryClearFilter {
+            scope: "all",
+            tags: &tags,
+            match_all_tags: false,
+        };
+        let all = MemoryClearFilter {
+            match_all_tags: true,
+            ..any
+        };
+        let trello_only = record("permanent", &["source_trello"]);
+        let both = record("permanent", &["source_trello", "travel"]);
+        let untagged = record("permanent", &[]);
+
+        assert!(any.matches(&trello_only));
+        assert!(!any.matches(&untagged));
+        assert!(!all.matches(&trello_only));
+        assert!(all.matches(&both));
+
+        let transient_only = MemoryClearFilter {
+            scope: "transient",
+            tags: &tags,
+            match_all_tags: false,
+        };
+        assert!(!transient_only.matches(&both));
+        assert!(transient_only.matches(&record("transient", &["travel"])));
+
+        let everything = MemoryClearFilter {
+            scope: "all",
+            tags: &[],
+            match_all_tags: false,
+        };
+        assert!(everything.matches(&untagged));
     }
 
-    let notes = sqlx::query("SELECT user_id, data_json FROM user_notes")
-        .fetch_all(pool)
-        .await?;
-    for row in notes {
-        let json: String = row.get("data_json");
-        if let Ok(value) = serde_json::from_str::<UserNoteRecord>(&json) {
-            state
-                .user_notes
-                .entry(row.get("user_id"))
-                .or_default()
-                .push(value);
-        }
+    #[test]
+    fn feedback_messages_have_email_addresses_redacted() {
+        assert_eq!(
+            redact_email_addresses("Driver was rude, contact me at dana.levi@example.co.il."),
+            "Driver was rude, contact me at [redacted email]."
+        );
+        assert_eq!(
+            redact_email_addresses("Email <ops@atlas.io>, or call"),
+            "Email <[redacted email]>, or call"
+        );
+        assert_eq!(
+            redact_email_addresses("Meet @ the lot at 9.30, rating 5@5"),
+            "Meet @ the lot at 9.30, rating 5@5"
+        );
     }
 
-    let memories = sqlx::query("SELECT user_id, data_json FROM user_memories")
-        .fetch_all(pool)
-        .await?;
-    for row in memories {
-        let json: String = row.get("data_json");
-        if let Ok(value) = serde_json::from_str::<MemoryRecord>(&json) {
-            state
-                .user_memories
-                .entry(row.get("user_id"))
-                .or_default()
-                .push(value);
-        }
+    #[test]
+    fn energy_level_must_be_between_one_and_five_when_present() {
+        assert!(energy_level_is_valid(None));
+        assert!(energy_level_is_valid(Some(1)));
+        assert!(energy_level_is_valid(Some(5)));
+        assert!(!energy_level_is_valid(Some(0)));
+        assert!(!energy_level_is_valid(Some(200)));
     }
 
-    let checkins = sqlx::query("SELECT user_id, data_json FROM execution_checkins")
-        .fetch_all(pool)
-        .await?;
-    for row in checkins {
-        let json: String = row.get("data_json");
-        if let Ok(value) = serde_json::from_str::<ExecutionCheckinRecord>(&json) {
-            state
-                .execution_checkins
-                .entry(row.get("user_id"))
-                .or_default()
-                .push(value);
-        }
+    #[test]
+    fn execution_week_summary_splits_completed_and_pending_focuses() {
+        let now = chrono::Utc::now();
+        let checkin =
+            |id: &str, days_ago: i64, focus: &str, energy: Option<u8>| ExecutionCheckinRecord {
+                checkin_id: id.to_string(),
+                user_id: "user-1".to_string(),
+                daily_focus: focus.to_string(),
+                mid_term_focus: None,
+                long_term_focus: None,
+                blocker: None,
+                next_action_now: None,
+                energy_level: energy,
+                mood: None,
+                gym_today: Some(true),
+                money_today: None,
+                created_at: (now - chrono::Duration::days(days_ago)).to_rfc3339(),
+            };
+        let checkins = vec![
+            checkin("c4", 0, "Close the Eilat partner deal", Some(5)),
+            checkin("c3", 2, "Close the Eilat partner deal", Some(4)),
+            checkin("c2", 4, "Ship the booking page", Some(2)),
+            checkin("c1", 12, "Old focus outside the window", Some(1)),
+        ];
+
+        let summary = summarize_execution_week(&checkins, "en", now - chrono::Duration::days(7));
+        assert_eq!(summary.checkin_count, 3);
+        assert_eq!(summary.pending_focuses.len(), 1);
+        assert_eq!(
+            summary.pending_focuses[0].detail,
+            "Close the Eilat partner deal"
+        );
+        assert_eq!(summary.completed_focuses.len(), 1);
+        assert_eq!(summary.completed_focuses[0].detail, "Ship the booking page");
+        assert_eq!(summary.energy.readings.len(), 3);
+        assert_eq!(summary.energy.trend, "rising");
+
+        let empty = summarize_execution_week(&[], "en", now - chrono::Duration::days(7));
+        assert_eq!(empty.checkin_count, 0);
+        assert_eq!(empty.energy.trend, "insufficient_data");
+        assert!(empty.energy.average.is_none());
     }
 
-    let controls = sqlx::query("SELECT user_id, data_json FROM execution_controls")
-        .fetch_all(pool)
-        .await?;
-    for row in controls {
-        let json: String = row.get("data_json");
-        if let Ok(value) = serde_json::from_str::<ExecutionControlsRecord>(&json) {
-            state.execution_controls.insert(row.get("user_id"), value);
-        }
+    #[test]
+    fn proactive_feed_cap_drops_company_card_before_ranked_tasks() {
+        let item = |id: &str| ProactiveFeedItem {
+            id: id.to_string(),
+            title: id.to_string(),
+            summary: String::new(),
+            why_now: String::new(),
+            priority: "normal".to_string(),
+            actions: Vec::new(),
+        };
+        let feed = vec![
+            item("next_action_now"),
+            item("task-a"),
+            item("task-b"),
+            item("company_planning_awareness"),
+        ];
+        let ids = |items: Vec<ProactiveFeedItem>| {
+            items.into_iter().map(|item| item.id).collect::<Vec<_>>()
+        };
+
+        assert_eq!(ids(cap_proactive_feed_items(feed.clone(), 4)).len(), 4);
+        assert_eq!(
+            ids(cap_proactive_feed_items(feed.clone(), 3)),
+            vec!["next_action_now", "task-a", "task-b"]
+        );
+        assert_eq!(
+            ids(cap_proactive_feed_items(feed, 2)),
+            vec!["next_action_now", "task-a"]
+        );
+    }
+
+    #[test]
+    fn provider_identity_owner_prefers_explicit_links() {
+        let user = |user_id: &str, provider: &str, email: &str| UserRecord {
+            user_id: user_id.to_string(),
+            provider: provider.to_string(),
+            email: email.to_string(),
+            name: "Dana".to_string(),
+            locale: "en".to_string(),
+            trip_style: None,
+            risk_preference: None,
+            memory_opt_in: true,
+            passkey_user_handle: None,
+            created_at: chrono::Utc::now().to_rfc3339(),
+            updated_at: chrono::Utc::now().to_rfc3339(),
+            last_login_at: None,
+            login_count: 0,
+        };
+        let mut users = HashMap::new();
+        users.insert(
+            "google-user".to_string(),
+            user("google-user", "google", "dana@example.com"),
+        );
+        users.insert(
+            "apple-user".to_string(),
+            user("apple-user", "apple", "dana@icloud.com"),
+        );
+        let index = index_user_emails(users.values());
+        let mut linked = HashMap::new();
+        let apple = |subject: &str, email: &str| ProviderIdentity {
+            provider: "apple",
+            subject: subject.to_string(),
+            email: email.to_string(),
+            email_verified: true,
+        };
+
+        assert_eq!(
+            provider_identity_owner(&users, &index, &linked, &apple("sub-1", "dana@icloud.com"))
+                .as_deref(),
+            Some("apple-user")
+        );
+        assert_eq!(
+            provider_identity_owner(&users, &index, &linked, &apple("sub-2", "dana@example.com")),
+            None
+        );
+
+        linked.insert(
+            linked_identity_key("apple", "sub-2"),
+            LinkedIdentityRecord {
+                provider: "apple".to_string(),
+                subject: "sub-2".to_string(),
+                email: "dana@example.com".to_string(),
+                user_id: "google-user".to_string(),
+                linked_at: chrono::Utc::now().to_rfc3339(),
+            },
+        );
+        assert_eq!(
+            provider_identity_owner(&users, &index, &linked, &apple("sub-2", "dana@example.com"))
+                .as_deref(),
+            Some("google-user")
+        );
+        // The link follows the provider account even after its email changes.
+        assert_eq!(
+            provider_identity_owner(&users, &index, &linked, &apple("sub-2", "dana@new.example"))
+                .as_deref(),
+            Some("google-user")
+        );
+
+        // A record keyed by email instead of subject never resolves.
+        linked.insert(
+            linked_identity_key("apple", "dana@legacy.example"),
+            LinkedIdentityRecord {
+                provider: "apple".to_string(),
+                subject: String::new(),
+                email: "dana@legacy.example".to_string(),
+                user_id: "google-user".to_string(),
+                linked_at: chrono::Utc::now().to_rfc3339(),
+            },
+        );
+        assert_eq!(
+            provider_identity_owner(
+                &users,
+                &index,
+                &linked,
+                &apple("sub-3", "dana@legacy.example")
+            ),
+            None
+        );
+    }
+
+    #[test]
+    fn sign_in_email_collisions_do_not_merge_accounts() {
+        let passkey = test_user("passkey-user", "passkey", "dana@example.com");
+        let google = test_user("google-user", "google", "dana@example.com");
+        let placeholder = test_user("placeholder", "passkey", "passkey-0f3a@atlasmasa.local");
+        let identity =
+            |provider: &'static str, email: &str, email_verified: bool| ProviderIdentity {
+                provider,
+                subject: "sub-1".to_string(),
+                email: email.to_string(),
+                email_verified,
+            };
+
+        assert!(sign_in_matches_account(
+            &google,
+            true,
+            &identity("google", "dana@example.com", true)
+        ));
+        assert!(!sign_in_matches_account(
+            &google,
+            true,
+            &identity("google", "dana@example.com", false)
+        ));
+        assert!(sign_in_matches_account(
+            &passkey,
+            true,
+            &identity("google", "dana@example.com", true)
+        ));
+        // A passkey account whose email was only typed in cannot be adopted (pre-hijack).
+        assert!(!sign_in_matches_account(
+            &passkey,
+            false,
+            &identity("google", "dana@example.com", true)
+        ));
+        assert!(!sign_in_matches_account(
+            &google,
+            true,
+            &identity("apple", "dana@example.com", true)
+        ));
+        assert!(!sign_in_matches_account(
+            &passkey,
+            true,
+            &identity("passkey", "dana@example.com", false)
+        ));
+        assert!(!sign_in_matches_account(
+            &placeholder,
+            true,
+            &identity("google", "passkey-0f3a@atlasmasa.local", true)
+        ));
     }
 
-    let passkeys = sqlx::query("SELECT user_id, data_json FROM passkeys")
-        .fetch_all(pool)
-        .await?;
-    for row in passkeys {
-        let json: String = row.get("data_json");
-        if let Ok(value) = serde_json::from_str::<PasskeyRecord>(&json) {
-            state
-                .passkeys_by_user
-                .entry(row.get("user_id"))
-                .or_default()
-                .push(value);
+    #[test]
+    fn sign_in_adopts_only_verified_accounts_and_prefers_the_oldest() {
+        let mut users = HashMap::new();
+        let mut linked = HashMap::new();
+        let google_sign_in = ProviderIdentity {
+            provider: "google",
+            subject: "google-sub".to_string(),
+            email: "dana@example.com".to_string(),
+            email_verified: true,
+        };
+        let mut passkey = test_user("passkey-user", "passkey", "dana@example.com");
+        passkey.created_at = "2026-01-01T00:00:00+00:00".to_string();
+        users.insert(passkey.user_id.clone(), passkey.clone());
+        assert!(matching_sign_in_account(
+            &users,
+            &index_user_emails(users.values()),
+            &linked,
+            &google_sign_in
+        )
+        .is_none());
+
+        linked.insert(
+            linked_identity_key("apple", "apple-sub"),
+            LinkedIdentityRecord {
+                provider: "apple".to_string(),
+                subject: "apple-sub".to_string(),
+                email: "dana@example.com".to_string(),
+                user_id: passkey.user_id.clone(),
+                linked_at: chrono::Utc::now().to_rfc3339(),
+            },
+        );
+        let mut google = test_user("google-user", "google", "dana@example.com");
+        google.created_at = "2026-03-01T00:00:00+00:00".to_string();
+        users.insert(google.user_id.clone(), google);
+        for _ in 0..8 {
+            assert_eq!(
+                matching_sign_in_account(
+                    &users,
+                    &index_user_emails(users.values()),
+                    &linked,
+                    &google_sign_in
+                )
+                .map(|user| user.user_id)
+                .as_deref(),
+                Some("passkey-user")
+            );
         }
     }
 
-    Ok(state)
-}
-
-async fn persist_user_if_configured(state: &ApiState, user: &UserRecord) -> Result<()> {
-    let Some(pool) = state.db_pool.as_ref() else {
-        return Ok(());
-    };
-
-    sqlx::query(
-        r#"
-        INSERT INTO auth_users (user_id, provider, email, name, locale, trip_style, risk_preference, memory_opt_in, passkey_user_handle, created_at, updated_at)
-        VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)
-        ON CONFLICT(user_id) DO UPDATE SET
-          provider=excluded.provider,
-          email=excluded.email,
-          name=excluded.name,
-          locale=excluded.locale,
-          trip_style=excluded.trip_style,
-          risk_preference=excluded.risk_preference,
-          memory_opt_in=excluded.memory_opt_in,
-          passkey_user_handle=excluded.passkey_user_handle,
-          updated_at=excluded.updated_at
-        "#,
-    )
-    .bind(user.user_id.as_str())
-    .bind(user.provider.as_str())
-    .bind(user.email.as_str())
-    .bind(user.name.as_str())
-    .bind(user.locale.as_str())
-    .bind(user.trip_style.as_deref())
-    .bind(user.risk_preference.as_deref())
-    .bind(if user.memory_opt_in { 1_i64 } else { 0_i64 })
-    .bind(user.passkey_user_handle.as_deref())
-    .bind(user.created_at.as_str())
-    .bind(user.updated_at.as_str())
-    .execute(pool)
-    .await?;
-    Ok(())
-}
-
-async fn persist_studio_preferences_if_configured(state: &ApiState, user_id: &str) -> Result<()> {
-    let Some(pool) = state.db_pool.as_ref() else {
-        return Ok(());
-    };
-    let value = state
-        .studio_preferences
-        .read()
-        .get(user_id)
-        .cloned()
-        .unwrap_or_else(|| default_studio_preferences(user_id));
-    let json = serde_json::to_string(&value)?;
-    sqlx::query(
-        r#"
-        INSERT INTO studio_preferences (user_id, data_json)
-        VALUES (?1, ?2)
-        ON CONFLICT(user_id) DO UPDATE SET data_json=excluded.data_json
-        "#,
-    )
-    .bind(user_id)
-    .bind(json)
-    .execute(pool)
-    .await?;
-    Ok(())
-}
+    #[test]
+    fn decoy_credential_ids_are_stable_per_email() {
+        let ids = |secret: &str, email: &str| decoy_credential_ids(secret, email).unwrap();
+        let first = ids("secret", "nobody@example.com");
+        assert_eq!(first, ids("secret", "nobody@example.com"));
+        assert_ne!(first, ids("secret", "other@example.com"));
+        assert_ne!(first, ids("rotated", "nobody@example.com"));
+
+        let shapes = (0..32)
+            .map(|index| {
+                let ids = ids("secret", format!("user{index}@example.com").as_str());
+                assert!((1..=3).contains(&ids.len()));
+                assert!(ids
+                    .iter()
+                    .all(|id| DECOY_CREDENTIAL_ID_LENGTHS.contains(&id.len())));
+                ids.iter().map(Vec::len).collect::<Vec<_>>()
+            })
+            .collect::<HashSet<_>>();
+        assert!(shapes.len() > 1);
+        assert!(shapes.iter().any(|lengths| lengths.len() > 1));
+    }
 
-async fn persist_survey_state_if_configured(state: &ApiState, user_id: &str) -> Result<()> {
-    let Some(pool) = state.db_pool.as_ref() else {
-        return Ok(());
-    };
-    let Some(value) = state.survey_states.read().get(user_id).cloned() else {
-        return Ok(());
-    };
-    let json = serde_json::to_string(&value)?;
-    sqlx::query(
-        r#"
-        INSERT INTO survey_states (user_id, data_json)
-        VALUES (?1, ?2)
-        ON CONFLICT(user_id) DO UPDATE SET data_json=excluded.data_json
-        "#,
-    )
-    .bind(user_id)
-    .bind(json)
-    .execute(pool)
-    .await?;
-    Ok(())
-}
+    #[test]
+    fn webauthn_policy_rewrites_attestation_and_login_verification() {
+        let webauthn = WebauthnBuilder::new(
+            "atlasmasa.com",
+            &Url::parse("https://atlasmasa.com").unwrap(),
+        )
+        .unwrap()
+        .build()
+        .unwrap();
+        let runtime = WebauthnRuntimeConfig {
+            webauthn: Arc::new(webauthn),
+            require_user_verification: true,
+            attestation: "direct".to_string(),
+        };
+        let registration = apply_webauthn_registration_policy(
+            serde_json::json!({ "publicKey": { "attestation": "none" } }),
+            &runtime,
+        );
+        assert_eq!(registration["publicKey"]["attestation"], "direct");
+        let login = apply_webauthn_login_policy(
+            serde_json::json!({ "publicKey": { "userVerification": "preferred" } }),
+            &runtime,
+        );
+        assert_eq!(login["publicKey"]["userVerification"], "required");
 
-async fn persist_feedback_if_configured(state: &ApiState) -> Result<()> {
-    let Some(pool) = state.db_pool.as_ref() else {
-        return Ok(());
-    };
-    sqlx::query("DELETE FROM feedback_items")
-        .execute(pool)
-        .await?;
-    let items = state.feedback_items.read().clone();
-    for item in &items {
-        let json = serde_json::to_string(item)?;
-        sqlx::query("INSERT INTO feedback_items (feedback_id, data_json) VALUES (?1, ?2)")
-            .bind(item.feedback_id.as_str())
-            .bind(json)
-            .execute(pool)
-            .await?;
+        let relaxed = WebauthnRuntimeConfig {
+            require_user_verification: false,
+            ..runtime
+        };
+        let login = apply_webauthn_login_policy(
+            serde_json::json!({ "publicKey": { "userVerification": "preferred" } }),
+            &relaxed,
+        );
+        assert_eq!(login["publicKey"]["userVerification"], "preferred");
     }
-    Ok(())
-}
-
-async fn persist_sessions_if_configured(state: &ApiState) -> Result<()> {
-    let Some(pool) = state.db_pool.as_ref() else {
-        return Ok(());
-    };
 
-    sqlx::query("DELETE FROM auth_sessions")
-        .execute(pool)
-        .await?;
-    let snapshot = state
-        .sessions
-        .read()
-        .iter()
-        .map(|(session_id, session)| {
-            (
-                session_id.clone(),
-                session.user_id.clone(),
-                session.expires_at.to_rfc3339(),
-                session.created_at.to_rfc3339(),
-            )
-        })
-        .collect::<Vec<_>>();
-    for (session_id, user_id, expires_at, created_at) in snapshot {
+    #[tokio::test]
+    async fn schema_upgrade_adds_login_columns_and_user_id_indexes() {
+        let pool = sqlx::sqlite::SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect("sqlite::memory:")
+            .await
+            .unwrap();
         sqlx::query(
-            "INSERT INTO auth_sessions (session_id, user_id, expires_at, created_at) VALUES (?1, ?2, ?3, ?4)",
+            r#"
+            CREATE TABLE auth_users (
+              user_id TEXT PRIMARY KEY,
+              provider TEXT NOT NULL,
+              email TEXT NOT NULL,
+              name TEXT NOT NULL,
+              locale TEXT NOT NULL,
+              trip_style TEXT,
+              risk_preference TEXT,
+              memory_opt_in INTEGER NOT NULL,
+              passkey_user_handle TEXT,
+              created_at TEXT NOT NULL,
+              updated_at TEXT NOT NULL
+            );
+            "#,
         )
-        .bind(session_id.as_str())
-        .bind(user_id.as_str())
-        .bind(expires_at)
-        .bind(created_at)
-        .execute(pool)
-        .await?;
+        .execute(&pool)
+        .await
+        .unwrap();
+        sqlx::query(
+            "INSERT INTO auth_users VALUES ('u1', 'google', 'dana@example.com', 'Dana', 'en', NULL, NULL, 1, NULL, '2026-01-01T00:00:00Z', '2026-01-01T00:00:00Z')",
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+
+        ensure_app_schema(&pool).await.unwrap();
+        ensure_app_schema(&pool).await.unwrap();
+
+        let persisted = load_persistent_state(Some(&pool)).await.unwrap();
+        let user = persisted.users.get("u1").unwrap();
+        assert_eq!(user.last_login_at, None);
+        assert_eq!(user.login_count, 0);
+
+        for table in [
+            "user_notes",
+            "user_memories",
+            "execution_checkins",
+            "passkeys",
+        ] {
+            let plan = sqlx::query(
+                format!("EXPLAIN QUERY PLAN DELETE FROM {table} WHERE user_id = 'u1'").as_str(),
+            )
+            .fetch_all(&pool)
+            .await
+            .unwrap()
+            .iter()
+            .map(|row| sqlx::Row::get::<String, _>(row, "detail"))
+            .collect::<Vec<_>>()
+            .join(" ");
+            assert!(
+                plan.contains(&format!("idx_{table}_user_id")),
+                "{table}: {plan}"
+            );
+        }
     }
-    Ok(())
-}
 
-async fn persist_notes_if_configured(state: &ApiState, user_id: &str) -> Result<()> {
-    let Some(pool) = state.db_pool.as_ref() else {
-        return Ok(());
-    };
-    sqlx::query("DELETE FROM user_notes WHERE user_id = ?1")
-        .bind(user_id)
-        .execute(pool)
-        .await?;
-    let notes = state
-        .user_notes
-        .read()
-        .get(user_id)
-        .cloned()
-        .unwrap_or_default();
-    for note in notes {
-        let json = serde_json::to_string(&note)?;
-        sqlx::query("INSERT INTO user_notes (note_id, user_id, data_json) VALUES (?1, ?2, ?3)")
-            .bind(note.note_id)
-            .bind(user_id)
-            .bind(json)
-            .execute(pool)
-            .await?;
-    }
-    Ok(())
-}
+    #[test]
+    fn cors_settings_keep_required_headers_and_clamp_max_age() {
+        let defaults = parse_cors_settings(None, None, None);
+        assert_eq!(
+            defaults.allowed_methods,
+            vec![Method::GET, Method::POST, Method::DELETE, Method::OPTIONS]
+        );
+        assert_eq!(defaults.max_age, std::time::Duration::from_secs(600));
+        let headers = defaults
+            .allowed_headers
+            .iter()
+            .map(|name| name.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(headers, vec!["content-type", "x-api-key", "x-csrf-token"]);
 
-async fn persist_checkins_if_configured(state: &ApiState, user_id: &str) -> Result<()> {
-    let Some(pool) = state.db_pool.as_ref() else {
-        return Ok(());
-    };
-    sqlx::query("DELETE FROM execution_checkins WHERE user_id = ?1")
-        .bind(user_id)
-        .execute(pool)
-        .await?;
-    let checkins = state
-        .execution_checkins
-        .read()
-        .get(user_id)
-        .cloned()
-        .unwrap_or_default();
-    for checkin in checkins {
-        let json = serde_json::to_string(&checkin)?;
-        sqlx::query(
-            "INSERT INTO execution_checkins (checkin_id, user_id, data_json) VALUES (?1, ?2, ?3)",
-        )
-        .bind(checkin.checkin_id)
-        .bind(user_id)
-        .bind(json)
-        .execute(pool)
-        .await?;
+        let custom = parse_cors_settings(
+            Some("get, delete, not a method"),
+            Some("X-Request-Id, content-type, bad header"),
+            Some("999999"),
+        );
+        assert_eq!(custom.allowed_methods, vec![Method::GET, Method::DELETE]);
+        assert_eq!(custom.allowed_headers.len(), 4);
+        assert!(custom
+            .allowed_headers
+            .iter()
+            .any(|name| name.as_str() == "x-request-id"));
+        assert_eq!(custom.max_age, std::time::Duration::from_secs(86_400));
     }
-    Ok(())
-}
 
-async fn persist_execution_controls_if_configured(state: &ApiState, user_id: &str) -> Result<()> {
-    let Some(pool) = state.db_pool.as_ref() else {
-        return Ok(());
-    };
-    let Some(controls) = state.execution_controls.read().get(user_id).cloned() else {
-        return Ok(());
-    };
-    let json = serde_json::to_string(&controls)?;
-    sqlx::query(
-        r#"
-        INSERT INTO execution_controls (user_id, data_json)
-        VALUES (?1, ?2)
-        ON CONFLICT(user_id) DO UPDATE SET data_json=excluded.data_json
-        "#,
-    )
-    .bind(user_id)
-    .bind(json)
-    .execute(pool)
-    .await?;
-    Ok(())
-}
+    #[tokio::test]
+    async fn strict_timestamps_name_the_rejected_field() {
+        assert_eq!(parse_rfc3339_or_error("expires_at", None).unwrap(), None);
+        assert_eq!(
+            parse_rfc3339_or_error("expires_at", Some("  ")).unwrap(),
+            None
+        );
+        assert_eq!(
+            parse_rfc3339_or_error("expires_at", Some("2026-03-01T09:00:00+02:00"))
+                .unwrap()
+                .map(|value| value.to_rfc3339()),
+            Some("2026-03-01T07:00:00+00:00".to_string())
+        );
 
-async fn persist_memories_if_configured(state: &ApiState, user_id: &str) -> Result<()> {
-    let Some(pool) = state.db_pool.as_ref() else {
-        return Ok(());
-    };
-    sqlx::query("DELETE FROM user_memories WHERE user_id = ?1")
-        .bind(user_id)
-        .execute(pool)
-        .await?;
-    let memories = state
-        .user_memories
-        .read()
-        .get(user_id)
-        .cloned()
-        .unwrap_or_default();
-    for memory in memories {
-        let json = serde_json::to_string(&memory)?;
-        sqlx::query(
-            "INSERT INTO user_memories (memory_id, user_id, data_json) VALUES (?1, ?2, ?3)",
+        let error =
+            parse_rfc3339_or_error("items[2].happened_at", Some("2026-03-01 09:00")).unwrap_err();
+        let response = axum::response::IntoResponse::into_response(error);
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(json["error"], "invalid_timestamp");
+        assert_eq!(json["details"]["field"], "items[2].happened_at");
+
+        let csv = "title,content,happened_at\n\
+                   Trip,Beach weekend,yesterday\n\
+                   Gym,Three times a week,2025-05-01T10:00:00Z\n";
+        let ParsedMemoryCsv { items, row_errors } =
+            parse_memory_import_csv(csv).expect("valid header");
+        assert_eq!(items.len(), 1);
+        assert_eq!(row_errors.len(), 1);
+        assert_eq!(row_errors[0].row, 2);
+
+        // Callers keep the validated value, so padding accepted by validation is not lost later.
+        let padded = " 2026-03-01T09:00:00+02:00 ";
+        let state = test_state().await;
+        let user = test_user("padded-time-user", "google", "ceo@atlasmasa.com");
+        let session = signed_in_headers(&state, &user);
+        let response = build_router(state.clone())
+            .oneshot(json_post(
+                "/v1/memory/import",
+                serde_json::json!({
+                    "items": [{ "title": "Trip", "content": "Beach weekend", "happened_at": padded }]
+                }),
+                &session,
+            ))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            state.user_notes.read()[&user.user_id][0].updated_at,
+            "2026-03-01T07:00:00+00:00"
+        );
+
+        let reminder = build_reminder_action(
+            &state,
+            user.user_id.as_str(),
+            false,
+            "trace-1",
+            ReminderActionRequest {
+                title: "Call the hotel".to_string(),
+                details: None,
+                due_at_utc: Some(padded.to_string()),
+                duration_minutes: None,
+                reminders_app: None,
+            },
         )
-        .bind(memory.memory_id)
-        .bind(user_id)
-        .bind(json)
-        .execute(pool)
-        .await?;
+        .unwrap();
+        assert!(reminder.ics_content.contains("DTSTART:20260301T070000Z"));
     }
-    Ok(())
-}
 
-async fn persist_passkeys_if_configured(state: &ApiState, user_id: &str) -> Result<()> {
-    let Some(pool) = state.db_pool.as_ref() else {
-        return Ok(());
-    };
-    sqlx::query("DELETE FROM passkeys WHERE user_id = ?1")
-        .bind(user_id)
-        .execute(pool)
-        .await?;
-    let records = state
-        .passkeys_by_user
-        .read()
-        .get(user_id)
-        .cloned()
-        .unwrap_or_default();
-    for record in records {
-        let json = serde_json::to_string(&record)?;
-        sqlx::query("INSERT INTO passkeys (passkey_id, user_id, data_json) VALUES (?1, ?2, ?3)")
-            .bind(record.passkey_id)
-            .bind(user_id)
-            .bind(json)
-            .execute(pool)
-            .await?;
+    #[test]
+    fn feed_memory_query_prefers_current_focus_over_heavy_memories() {
+        let checkin = ExecutionCheckinRecord {
+            checkin_id: "c1".to_string(),
+            user_id: "u1".to_string(),
+            daily_focus: "Finish the investor deck".to_string(),
+            mid_term_focus: None,
+            long_term_focus: None,
+            blocker: None,
+            next_action_now: Some("draft pricing slide".to_string()),
+            energy_level: None,
+            mood: None,
+            gym_today: None,
+            money_today: None,
+            created_at: chrono::Utc::now().to_rfc3339(),
+        };
+        let all = parse_feed_memory_query_signals(None);
+        let query = proactive_feed_memory_query(&all, Some(&checkin), Some("what about churn?"));
+        assert_eq!(
+            query,
+            "Finish the investor deck draft pricing slide what about churn?"
+        );
+        let focus_only = parse_feed_memory_query_signals(Some(" Focus, bogus"));
+        assert_eq!(focus_only, vec!["focus".to_string()]);
+        assert_eq!(
+            proactive_feed_memory_query(&focus_only, None, Some("what about churn?")),
+            ""
+        );
+        assert!(parse_feed_memory_query_signals(Some("none")).is_empty());
+
+        let now = chrono::Utc::now();
+        let memory = |id: &str, text: &str, weight: f32| MemoryRecord {
+            memory_id: id.to_string(),
+            user_id: "u1".to_string(),
+            memory_type: "insight".to_string(),
+            stability: "permanent".to_string(),
+            source: "manual".to_string(),
+            text: text.to_string(),
+            weight,
+            recency_score: 1.0,
+            tags: Vec::new(),
+            created_at: now.to_rfc3339(),
+            updated_at: now.to_rfc3339(),
+            expires_at: None,
+            fingerprint: id.to_string(),
+            text_original_sealed: None,
+        };
+        let records = vec![
+            memory("heavy", "Prefers window seats on long flights", 0.9),
+            memory("focus", "Investor deck needs a pricing slide", 0.7),
+        ];
+        let ranked = retrieve_memory_context_from_records(&records, query.as_str(), 2, None, now);
+        assert_eq!(ranked[0].memory_id, "focus");
+        let unranked = retrieve_memory_context_from_records(&records, "", 2, None, now);
+        assert_eq!(unranked[0].memory_id, "heavy");
     }
-    Ok(())
-}
 
-async fn persist_billing_status_if_configured(
-    state: &ApiState,
-    billing: &BillingStatusRecord,
-) -> Result<()> {
-    let Some(pool) = state.db_pool.as_ref() else {
-        return Ok(());
-    };
+    #[test]
+    fn snooze_options_follow_the_users_local_day() {
+        // 15:30 UTC is 18:30 in UTC+3, so "tonight" (20:00 local) is still 90 minutes away.
+        let now = chrono::DateTime::parse_from_rfc3339("2026-03-10T15:30:00Z")
+            .unwrap()
+            .with_timezone(&chrono::Utc);
+        let options = reminder_snooze_options(now, 180);
+        let due = |snooze: &str| {
+            options
+                .iter()
+                .find(|option| option.snooze == snooze)
+                .map(|option| option.due_at_utc.clone())
+        };
+        assert_eq!(due("plus_1h").as_deref(), Some("2026-03-10T16:30:00+00:00"));
+        assert_eq!(due("tonight").as_deref(), Some("2026-03-10T17:00:00+00:00"));
+        assert_eq!(
+            due("tomorrow_morning").as_deref(),
+            Some("2026-03-11T06:00:00+00:00")
+        );
 
-    sqlx::query(
-        r#"
-        INSERT INTO billing_subscriptions (user_id, stripe_customer_id, stripe_subscription_id, status, current_period_end, updated_at)
-        VALUES (?1, ?2, ?3, ?4, ?5, ?6)
-        ON CONFLICT(user_id) DO UPDATE SET
-          stripe_customer_id=excluded.stripe_customer_id,
-          stripe_subscription_id=excluded.stripe_subscription_id,
-          status=excluded.status,
-          current_period_end=excluded.current_period_end,
-          updated_at=excluded.updated_at
-        "#,
-    )
-    .bind(billing.user_id.as_str())
-    .bind(billing.stripe_customer_id.as_deref())
-    .bind(billing.stripe_subscription_id.as_deref())
-    .bind(billing.status.as_str())
-    .bind(billing.current_period_end.as_deref())
-    .bind(billing.updated_at.as_str())
-    .execute(pool)
-    .await?;
-    Ok(())
-}
+        // Ten minutes later in UTC+4 it is 19:40 locally, too close for "tonight".
+        let later = now + chrono::Duration::minutes(10);
+        assert!(snooze_due_at("tonight", later, 240).is_none());
+        assert_eq!(reminder_snooze_options(later, 240).len(), 2);
+        assert!(snooze_due_at("next_week", now, 0).is_none());
+        assert_eq!(clamp_utc_offset_minutes(2000), 14 * 60);
+    }
 
-async fn resolve_user_id_by_customer(state: &ApiState, customer_id: &str) -> Option<String> {
-    let pool = state.db_pool.as_ref()?;
-    sqlx::query("SELECT user_id FROM billing_subscriptions WHERE stripe_customer_id = ?1 LIMIT 1")
-        .bind(customer_id)
-        .fetch_optional(pool)
-        .await
-        .ok()
-        .flatten()
-        .map(|row| row.get::<String, _>("user_id"))
-}
+    #[test]
+    fn reminders_snap_into_the_next_working_window() {
+        let mut prefs = default_studio_preferences("user-1");
+        prefs.utc_offset_minutes = 120;
+        let at = |value: &str| {
+            chrono::DateTime::parse_from_rfc3339(value)
+                .unwrap()
+                .with_timezone(&chrono::Utc)
+        };
+        // Tuesday 12:00 local is inside 09:00-18:00 and stays put.
+        let midday = at("2026-03-10T10:00:00Z");
+        assert_eq!(snap_to_working_hours(midday, &prefs), midday);
+        // Tuesday 03:00 local moves to 09:00 the same day.
+        assert_eq!(
+            snap_to_working_hours(at("2026-03-10T01:00:00Z"), &prefs),
+            at("2026-03-10T07:00:00Z")
+        );
+        // Thursday 21:00 local skips Fri/Sat (not working days by default) to Sunday 09:00.
+        assert_eq!(
+            snap_to_working_hours(at("2026-03-12T19:00:00Z"), &prefs),
+            at("2026-03-15T07:00:00Z")
+        );
 
-fn verify_stripe_webhook_signature(
-    signature: &str,
-    payload: &str,
-    secret: &str,
-    tolerance_seconds: u64,
-) -> bool {
-    let mut timestamp = "";
-    let mut expected_signatures: Vec<&str> = Vec::new();
-    for part in signature.split(',') {
-        let mut split = part.splitn(2, '=');
-        let key = split.next().unwrap_or_default();
-        let value = split.next().unwrap_or_default();
-        if key == "t" {
-            timestamp = value;
-        } else if key == "v1" {
-            expected_signatures.push(value);
-        }
+        let mut coerced = Vec::new();
+        let merged = merge_studio_preferences(
+            prefs,
+            StudioPreferencesUpsertRequest {
+                user_id: None,
+                preferred_format: None,
+                response_depth: None,
+                response_tone: None,
+                proactive_mode: None,
+                reminders_app: None,
+                alarms_app: None,
+                voice_mode: None,
+                utc_offset_minutes: None,
+                working_hours_start: Some("7:30".to_string()),
+                working_hours_end: Some("25:00".to_string()),
+                working_days: Some(vec!["monday".to_string(), "Fri".to_string()]),
+            },
+            &mut coerced,
+        );
+        assert_eq!(merged.working_hours_start, "07:30");
+        assert_eq!(merged.working_hours_end, "18:00");
+        assert_eq!(merged.working_days, vec!["Mon", "Fri"]);
+        assert_eq!(coerced.len(), 1);
+        assert_eq!(coerced[0].field, "working_hours_end");
     }
-    if timestamp.is_empty() || expected_signatures.is_empty() {
-        return false;
+
+    #[tokio::test]
+    async fn ai_healthcheck_reports_failure_without_leaking_the_key() {
+        let runtime = OpenAiRuntimeConfig {
+            api_key: "sk-healthcheck-secret".to_string(),
+            base_url: "http://127.0.0.1:9/v1".to_string(),
+            model: "gpt-healthcheck".to_string(),
+            default_reasoning_effort: "low".to_string(),
+            max_reasoning_effort: "high".to_string(),
+        };
+        let result = run_ai_healthcheck(
+            &reqwest::Client::new(),
+            &runtime,
+            std::time::Duration::from_secs(2),
+        )
+        .await;
+        assert!(!result.ok);
+        assert_eq!(result.model, "gpt-healthcheck");
+        assert_eq!(result.provider, "openai_responses");
+        assert!(result.error.is_some());
+
+        let body = serde_json::to_string(&result).unwrap();
+        assert!(!body.contains("sk-healthcheck-secret"));
+        assert!(body.contains("latency_ms"));
     }
-    let timestamp_value = match timestamp.parse::<i64>() {
-        Ok(value) => value,
-        Err(_) => return false,
-    };
-    if tolerance_seconds > 0 {
-        let now = chrono::Utc::now().timestamp();
-        if (now - timestamp_value).abs() > tolerance_seconds as i64 {
-            return false;
-        }
+
+    #[test]
+    fn reasoning_effort_is_validated_and_clamped_to_the_server_max() {
+        assert_eq!(normalize_reasoning_effort(None).unwrap(), None);
+        assert_eq!(normalize_reasoning_effort(Some("  ")).unwrap(), None);
+        assert_eq!(
+            normalize_reasoning_effort(Some(" Medium "))
+                .unwrap()
+                .as_deref(),
+            Some("medium")
+        );
+        assert!(normalize_reasoning_effort(Some("extreme")).is_err());
+
+        assert_eq!(resolve_reasoning_effort(None, "high", "high"), "high");
+        assert_eq!(resolve_reasoning_effort(Some("low"), "high", "high"), "low");
+        assert_eq!(
+            resolve_reasoning_effort(Some("high"), "low", "medium"),
+            "medium"
+        );
+        assert_eq!(resolve_reasoning_effort(None, "high", "medium"), "medium");
+        assert_eq!(
+            resolve_reasoning_effort(None, "minimal", "medium"),
+            "minimal"
+        );
     }
 
-    if payload.len() > 256 * 1024 {
-        return false;
+    #[test]
+    fn ephemeral_memory_types_get_a_short_ttl() {
+        assert_eq!(
+            parse_ephemeral_memory_types(Some(" Mood, friction, bogus, mood")),
+            ["mood", "friction"]
+        );
+        assert!(parse_ephemeral_memory_types(None).is_empty());
+
+        let now = chrono::Utc::now();
+        let ephemeral = ["mood".to_string()];
+        let event = |memory_type: &str| MemoryIngestEvent {
+            memory_type: memory_type.to_string(),
+            stability: "permanent".to_string(),
+            source: "chat".to_string(),
+            text: format!("{memory_type} signal after a long drive"),
+            weight: 0.6,
+            tags: Vec::new(),
+            happened_at: Some(now),
+            expires_at: Some(now + Duration::days(30)),
+        };
+        let mut records = Vec::new();
+        let mood = ingest_memory_records_if_opted_in(
+            &mut records,
+            "user-1",
+            true,
+            &ephemeral,
+            event("mood"),
+            now,
+        )
+        .expect("mood should still enter the working set");
+        let expires_at = chrono::DateTime::parse_from_rfc3339(mood.expires_at.as_deref().unwrap())
+            .unwrap()
+            .with_timezone(&chrono::Utc);
+        assert_eq!(
+            expires_at,
+            now + Duration::hours(EPHEMERAL_MEMORY_TTL_HOURS)
+        );
+
+        let goal = ingest_memory_records_if_opted_in(
+            &mut records,
+            "user-1",
+            true,
+            &ephemeral,
+            event("goal"),
+            now,
+        )
+        .unwrap();
+        assert_eq!(goal.expires_at, None);
     }
 
-    let signed_payload = format!("{}.{}", timestamp, payload);
-    let mut mac = match Hmac::<Sha256>::new_from_slice(secret.as_bytes()) {
-        Ok(value) => value,
-        Err(_) => return false,
-    };
-    mac.update(signed_payload.as_bytes());
-    let result = mac.finalize().into_bytes();
-    let computed = hex_encode(result.as_slice());
-    expected_signatures
-        .iter()
-        .any(|expected| constant_time_eq(computed.as_bytes(), expected.as_bytes()))
-}
+    #[test]
+    fn proactive_mode_filters_the_feed() {
+        let user = UserRecord {
+            user_id: "user-1".to_string(),
+            provider: "google".to_string(),
+            email: "dana@example.com".to_string(),
+            name: "Dana".to_string(),
+            locale: "en".to_string(),
+            trip_style: None,
+            risk_preference: None,
+            memory_opt_in: true,
+            passkey_user_handle: None,
+            created_at: chrono::Utc::now().to_rfc3339(),
+            updated_at: chrono::Utc::now().to_rfc3339(),
+            last_login_at: None,
+            login_count: 0,
+        };
+        let notes = ["Book the ferry", "Renew the passport"].map(|title| UserNoteRecord {
+            note_id: title.to_lowercase().replace(' ', "-"),
+            user_id: "user-1".to_string(),
+            title: title.to_string(),
+            content: format!("{title} today before noon"),
+            tags: Vec::new(),
+            updated_at: chrono::Utc::now().to_rfc3339(),
+            structured: None,
+        });
+        let company_status = default_company_status();
+        let controls = default_execution_controls("user-1");
+        let feed = |mode: &str| {
+            let mut prefs = default_studio_preferences("user-1");
+            prefs.proactive_mode = mode.to_string();
+            build_orchestrated_proactive_feed(&ExecutionFeedContext {
+                company_status: &company_status,
+                user: &user,
+                prefs: Some(&prefs),
+                survey: None,
+                notes: Some(notes.as_slice()),
+                controls: &controls,
+                memories: &[],
+                latest_checkin: None,
+                max_items: DEFAULT_FEED_MAX_ITEMS,
+            })
+        };
 
-#[cfg(test)]
-fn build_test_stripe_signature(
-    payload: &str,
-    secret: &str,
-    timestamp: i64,
-) -> Result<String, hmac::digest::InvalidLength> {
-    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes())?;
-    let signed_payload = format!("{}.{}", timestamp, payload);
-    mac.update(signed_payload.as_bytes());
-    let signature = hex_encode(mac.finalize().into_bytes().as_slice());
-    Ok(format!("t={},v1={}", timestamp, signature))
-}
+        let enabled = feed("enabled");
+        assert!(enabled.len() > 1);
+        assert!(enabled
+            .iter()
+            .any(|item| item.id == "company_planning_awareness"));
 
-fn constant_time_eq(lhs: &[u8], rhs: &[u8]) -> bool {
-    if lhs.len() != rhs.len() {
-        return false;
+        let focus_only = feed("focus_only");
+        assert_eq!(focus_only.len(), 1);
+        assert_eq!(focus_only[0].id, "next_action_now");
+        assert!(!focus_only[0].why_now.contains("company"));
+
+        assert!(feed("disabled").is_empty());
     }
-    let mut diff = 0_u8;
-    for (a, b) in lhs.iter().zip(rhs.iter()) {
-        diff |= a ^ b;
+
+    #[test]
+    fn restoring_trashed_memories_skips_ones_that_reappeared() {
+        let record = |memory_id: &str, fingerprint: &str| MemoryRecord {
+            memory_id: memory_id.to_string(),
+            user_id: "user-1".to_string(),
+            memory_type: "preference".to_string(),
+            stability: "permanent".to_string(),
+            source: "chat".to_string(),
+            text: format!("Memory {memory_id}"),
+            weight: 0.7,
+            recency_score: 1.0,
+            tags: Vec::new(),
+            created_at: chrono::Utc::now().to_rfc3339(),
+            updated_at: chrono::Utc::now().to_rfc3339(),
+            expires_at: None,
+            fingerprint: fingerprint.to_string(),
+            text_original_sealed: None,
+        };
+        let trashed = ["memory-1", "memory-2"]
+            .iter()
+            .enumerate()
+            .map(|(index, memory_id)| TrashedMemory {
+                memory: record(memory_id, format!("f{}", index + 1).as_str()),
+                restore_id: "restore-1".to_string(),
+                deleted_at: chrono::Utc::now(),
+            })
+            .collect::<Vec<_>>();
+        // The user mentioned the second memory again after clearing.
+        let mut records = vec![record("memory-9", "f2")];
+
+        let (restored, skipped, over_limit) = restore_trashed_memories(&mut records, trashed);
+        assert_eq!((restored, skipped), (1, 1));
+        assert!(over_limit.is_empty());
+        let ids: Vec<_> = records
+            .iter()
+            .map(|entry| entry.memory_id.as_str())
+            .collect();
+        assert_eq!(ids, ["memory-9", "memory-1"]);
     }
-    diff == 0
-}
 
-fn hex_encode(bytes: &[u8]) -> String {
-    let mut out = String::with_capacity(bytes.len() * 2);
-    for byte in bytes {
-        out.push_str(format!("{:02x}", byte).as_str());
+    #[test]
+    fn note_length_error_reports_the_limit_instead_of_truncating() {
+        assert!(note_length_error("content", "  short note  ", 10).is_none());
+        let title = "t".repeat(MAX_NOTE_TITLE_LEN + 1);
+        let title_error = note_length_error("title", title.as_str(), MAX_NOTE_TITLE_LEN)
+            .expect("long titles are rejected too");
+        let title_value = serde_json::to_value(&title_error).expect("serializable");
+        assert_eq!(title_value["details"]["field"], "title");
+
+        let error = note_length_error("content", "0123456789ab", 10).expect("too long");
+        let value = serde_json::to_value(&error).expect("serializable");
+        assert_eq!(value["error"], "note_too_long");
+        assert_eq!(value["details"]["limit"], 10);
+        assert_eq!(value["details"]["submitted_chars"], 12);
+        assert_eq!(
+            axum::response::IntoResponse::into_response(error).status(),
+            StatusCode::PAYLOAD_TOO_LARGE
+        );
+    }
+
+    #[test]
+    fn note_history_archives_changed_notes_and_keeps_the_newest_versions() {
+        let note = |content: &str| UserNoteRecord {
+            note_id: "n1".to_string(),
+            user_id: "u1".to_string(),
+            title: "Haifa route".to_string(),
+            content: content.to_string(),
+            tags: vec!["ops".to_string()],
+            updated_at: "2026-01-01T00:00:00Z".to_string(),
+            structured: None,
+        };
+        let now = chrono::Utc::now();
+        let mut notes = Vec::new();
+        let mut versions = Vec::new();
+
+        assert!(!replace_note_keeping_history(
+            &mut notes,
+            &mut versions,
+            note("v0"),
+            "upsert",
+            now
+        ));
+        assert!(!replace_note_keeping_history(
+            &mut notes,
+            &mut versions,
+            note("v0"),
+            "upsert",
+            now
+        ));
+        assert!(versions.is_empty());
+
+        for index in 1..=NOTE_VERSION_HISTORY_LIMIT + 2 {
+            let replaced_by = if index % 2 == 0 { "rewrite" } else { "upsert" };
+            assert!(replace_note_keeping_history(
+                &mut notes,
+                &mut versions,
+                note(format!("v{index}").as_str()),
+                replaced_by,
+                now
+            ));
+        }
+        assert_eq!(notes.len(), 1);
+        assert_eq!(
+            notes[0].content,
+            format!("v{}", NOTE_VERSION_HISTORY_LIMIT + 2)
+        );
+        assert_eq!(versions.len(), NOTE_VERSION_HISTORY_LIMIT);
+        assert_eq!(versions[0].content, "v2");
+        assert_eq!(
+            versions.last().map(|version| version.content.as_str()),
+            Some(format!("v{}", NOTE_VERSION_HISTORY_LIMIT + 1).as_str())
+        );
+        assert_eq!(versions[0].replaced_by, "upsert");
+    }
+
+    #[test]
+    fn premium_system_prompt_is_configurable_per_locale() {
+        let defaults = parse_premium_system_prompts(None, None);
+        let english = defaults.for_locale(atlas_core::Locale::En);
+        assert!(english.starts_with(DEFAULT_PREMIUM_SYSTEM_PROMPT));
+        assert!(english
+            .ends_with("Respond in English unless the user explicitly asks for another language."));
+
+        let from_text_file =
+            parse_premium_system_prompts(None, Some("  You are Acme Concierge.\n"));
+        assert!(from_text_file
+            .for_locale(atlas_core::Locale::Unknown)
+            .starts_with("You are Acme Concierge.\n\nRespond in the language"));
+
+        let from_json = parse_premium_system_prompts(
+            Some("Inline base wins."),
+            Some(r#"{"default": "File base.", "he": "אתה הקונסיירז' של Acme.", "xx": "ignored"}"#),
+        );
+        assert!(from_json
+            .for_locale(atlas_core::Locale::Fr)
+            .starts_with("Inline base wins.\n\nRespond in French"));
+        assert!(from_json
+            .for_locale(atlas_core::Locale::He)
+            .starts_with("אתה הקונסיירז' של Acme.\n\nRespond in Hebrew"));
+        assert!(!from_json.by_locale.contains_key("xx"));
     }
-    out
-}
 
-async fn find_or_create_user_by_email(
-    state: &ApiState,
-    provider: &str,
-    email: String,
-    name: String,
-    locale: String,
-    now: String,
-) -> UserRecord {
-    if let Some(existing) = state
-        .users
-        .read()
-        .values()
-        .find(|value| {
-            value.email == email && (value.provider == provider || value.provider == "passkey")
-        })
-        .cloned()
-    {
-        return existing;
+    #[test]
+    fn premium_prompt_for_a_hebrew_user_asks_for_hebrew() {
+        let prompts = parse_premium_system_prompts(None, None);
+        assert_eq!(
+            prompts.for_locale(atlas_core::Locale::He),
+            "You are Atlas/אטלס Executive Intelligence. Speak with refined, high-class language and clear structure. Act like a strategic chief-of-staff for a high-performing traveler-builder. Prioritize execution, safety, resilience, and momentum.\n\nRespond in Hebrew unless the user explicitly asks for another language."
+        );
     }
 
-    let user_id = uuid::Uuid::new_v4().to_string();
-    let user = UserRecord {
-        user_id: user_id.clone(),
-        provider: provider.to_string(),
-        email,
-        name,
-        locale,
-        trip_style: Some("mixed".to_string()),
-        risk_preference: Some("medium".to_string()),
-        memory_opt_in: true,
-        passkey_user_handle: Some(uuid::Uuid::new_v4().to_string()),
-        created_at: now.clone(),
-        updated_at: now,
-    };
-    state.users.write().insert(user_id, user.clone());
-    let _ = persist_user_if_configured(state, &user).await;
-    user
-}
+    #[test]
+    fn premium_replies_in_the_wrong_script_are_detected() {
+        use atlas_core::Locale;
+
+        let hebrew = "בוקר טוב, הנה שלושת הצעדים החשובים להיום: לאשר את הנהג ולבדוק את המסלול.";
+        let english = "Good morning, here are the three most important steps for today.";
+        assert!(premium_reply_matches_locale(Locale::He, hebrew));
+        assert!(!premium_reply_matches_locale(Locale::He, english));
+        assert!(premium_reply_matches_locale(
+            Locale::He,
+            "הנה התוכנית: Tel Aviv → Haifa, יציאה ב-09:30 עם Gett ולאחר מכן פגישה."
+        ));
+        assert!(!premium_reply_matches_locale(Locale::En, hebrew));
+        assert!(premium_reply_matches_locale(
+            Locale::Fr,
+            "Bonjour, voici les trois étapes les plus importantes pour aujourd'hui."
+        ));
+        assert!(premium_reply_matches_locale(Locale::Ru, "OK, 09:30"));
+        assert!(premium_reply_matches_locale(Locale::Unknown, english));
+
+        // Asking for another language is honoured rather than treated as a mismatch.
+        assert!(premium_reply_usable(
+            Locale::He,
+            "תרגם לי את התוכנית באנגלית",
+            english
+        ));
+        assert!(premium_reply_usable(
+            Locale::He,
+            "Please answer in English",
+            english
+        ));
+        assert!(premium_reply_usable(
+            Locale::En,
+            "Translate this to Hebrew: good morning",
+            hebrew
+        ));
+        assert!(!premium_reply_usable(
+            Locale::He,
+            "מה התוכנית להיום?",
+            english
+        ));
+    }
 
-async fn issue_session_for_user(state: &ApiState, user: &UserRecord) -> Result<String> {
-    let session_id = uuid::Uuid::new_v4().to_string();
-    let expires_at =
-        chrono::Utc::now() + chrono::Duration::seconds(state.session_ttl.as_secs() as i64);
-    state.sessions.write().insert(
-        session_id.clone(),
-        SessionRecord {
-            user_id: user.user_id.clone(),
-            expires_at,
-            created_at: chrono::Utc::now(),
-        },
-    );
-    persist_sessions_if_configured(state).await?;
-    Ok(session_id)
-}
+    #[test]
+    fn premium_context_is_trimmed_to_the_byte_budget() {
+        let item = |label: &str, size: usize| serde_json::json!({ "label": label, "text": "x".repeat(size) });
+        let notes = vec![item("note-new", 400), item("note-old", 400)];
+        let memories = vec![item("memory-best", 100), item("memory-worst", 100)];
+        let render = |notes: &[serde_json::Value], memories: &[serde_json::Value]| {
+            serde_json::json!({ "notes": notes, "memory_context": memories }).to_string()
+        };
 
-fn resolve_user_id_for_passkey_credential(state: &ApiState, cred_id: &[u8]) -> Option<String> {
-    state
-        .passkeys_by_user
-        .read()
-        .iter()
-        .find_map(|(user_id, entries)| {
-            if entries
-                .iter()
-                .any(|entry| entry.credential.cred_id().as_slice() == cred_id)
-            {
-                Some(user_id.clone())
-            } else {
-                None
-            }
-        })
-}
+        let (untouched, dropped_notes, dropped_memories) =
+            fit_context_to_budget(notes.clone(), memories.clone(), 10_000, render);
+        assert_eq!((dropped_notes, dropped_memories), (0, 0));
+        assert!(untouched.contains("note-old"));
+
+        let (trimmed, dropped_notes, dropped_memories) =
+            fit_context_to_budget(notes.clone(), memories.clone(), 800, render);
+        assert!(trimmed.len() <= 800);
+        assert_eq!((dropped_notes, dropped_memories), (1, 0));
+        assert!(trimmed.contains("note-new") && !trimmed.contains("note-old"));
+
+        let (minimal, dropped_notes, dropped_memories) =
+            fit_context_to_budget(notes, memories, 10, render);
+        assert_eq!((dropped_notes, dropped_memories), (2, 2));
+        assert_eq!(minimal, r#"{"memory_context":[],"notes":[]}"#);
+    }
 
-fn update_passkey_credential_usage(
-    state: &ApiState,
-    user_id: &str,
-    auth_result: &AuthenticationResult,
-) {
-    if let Some(entries) = state.passkeys_by_user.write().get_mut(user_id) {
-        let now = chrono::Utc::now().to_rfc3339();
-        for entry in entries.iter_mut() {
-            if entry.credential.update_credential(auth_result).is_some() {
-                entry.last_used_at = Some(now.clone());
+    #[tokio::test]
+    async fn chat_memory_context_is_omitted_when_opted_out_or_empty() {
+        let mut state = test_state().await;
+        // Chat ingestion would store the message itself; keep the store to what the test seeds.
+        state.chat_memory_limiter =
+            RateLimiter::Local(IpRateLimiter::new(std::time::Duration::from_secs(3600), 0));
+        let app = build_router(state.clone());
+        let chat = |user: &UserRecord| {
+            let app = app.clone();
+            let session = signed_in_headers(&state, user);
+            async move {
+                let response = app
+                    .oneshot(json_post(
+                        "/v1/chat",
+                        serde_json::json!({
+                            "text": "plan a beach weekend",
+                            "session_id": "memory-context"
+                        }),
+                        &session,
+                    ))
+                    .await
+                    .unwrap();
+                assert_eq!(response.status(), StatusCode::OK);
+                response_json(response).await["json_payload"].clone()
             }
+        };
+
+        let mut opted_out = test_user("context-opted-out", "google", "ceo@atlasmasa.com");
+        opted_out.memory_opt_in = false;
+        let empty = test_user("context-empty", "google", "ceo@atlasmasa.com");
+        let seeded = test_user("context-seeded", "google", "ceo@atlasmasa.com");
+        for user in [&opted_out, &seeded] {
+            state.user_memories.write().insert(
+                user.user_id.clone(),
+                vec![MemoryRecord {
+                    memory_id: format!("{}-beach", user.user_id),
+                    user_id: user.user_id.clone(),
+                    memory_type: "preference".to_string(),
+                    stability: "permanent".to_string(),
+                    source: "chat".to_string(),
+                    text: "Prefers a quiet beach weekend".to_string(),
+                    weight: 0.8,
+                    recency_score: 1.0,
+                    tags: Vec::new(),
+                    created_at: chrono::Utc::now().to_rfc3339(),
+                    updated_at: chrono::Utc::now().to_rfc3339(),
+                    expires_at: None,
+                    fingerprint: "beach".to_string(),
+                    text_original_sealed: None,
+                }],
+            );
         }
-    }
-}
 
-async fn generate_premium_openai_reply(
-    state: &ApiState,
-    request: &ChatRequest,
-    user: Option<&UserRecord>,
-    survey: Option<&SurveyStateRecord>,
-    notes: &[UserNoteRecord],
-    memory_context: &[MemoryRetrievedItem],
-    fallback_reply: &str,
-) -> Result<String> {
-    let runtime = state
-        .openai_runtime
-        .as_ref()
-        .context("OpenAI runtime is not configured")?;
+        assert!(chat(&opted_out).await.get("memory_context").is_none());
+        assert!(chat(&empty).await.get("memory_context").is_none());
+        let payload = chat(&seeded).await;
+        assert_eq!(
+            payload["memory_context"][0]["memory_id"],
+            "context-seeded-beach"
+        );
+    }
 
-    let user_context = user.map(|value| {
-        serde_json::json!({
-            "name": value.name,
-            "locale": value.locale,
-            "trip_style": value.trip_style,
-            "risk_preference": value.risk_preference,
-            "memory_opt_in": value.memory_opt_in
-        })
-    });
-    let survey_context = survey.map(|value| serde_json::to_value(value).unwrap_or_default());
-    let notes_context = notes
-        .iter()
-        .take(12)
-        .map(|note| {
-            serde_json::json!({
-                "title": note.title,
-                "content": note.content,
-                "tags": note.tags
-            })
-        })
-        .collect::<Vec<_>>();
-    let memory_context = memory_context
-        .iter()
-        .take(12)
-        .map(|entry| {
-            serde_json::json!({
-                "memory_type": entry.memory_type,
-                "stability": entry.stability,
-                "source": entry.source,
-                "text": entry.text,
-                "weight": entry.weight,
-                "recency_score": entry.recency_score,
-                "relevance_score": entry.relevance_score,
-                "tags": entry.tags
-            })
-        })
-        .collect::<Vec<_>>();
+    #[test]
+    fn memory_search_returns_only_matches_above_the_floor() {
+        let now = chrono::Utc::now();
+        let memory = |id: &str, memory_type: &str, source: &str, text: &str| MemoryRecord {
+            memory_id: id.to_string(),
+            user_id: "u1".to_string(),
+            memory_type: memory_type.to_string(),
+            stability: "permanent".to_string(),
+            source: source.to_string(),
+            text: text.to_string(),
+            weight: 0.8,
+            recency_score: 1.0,
+            tags: Vec::new(),
+            created_at: now.to_rfc3339(),
+            updated_at: now.to_rfc3339(),
+            expires_at: None,
+            fingerprint: id.to_string(),
+            text_original_sealed: None,
+        };
+        let records = vec![
+            memory("m1", "preference", "chat", "Prefers the early Haifa train"),
+            memory("m2", "constraint", "note", "Haifa client meetings run late"),
+            memory("m3", "preference", "chat", "Likes window seats"),
+        ];
+        let any = MemorySearchFilters {
+            sources: &[],
+            types: &[],
+            min_score: 0.0,
+        };
 
-    let system_prompt = "You are Atlas/אטלס Executive Intelligence. Speak with refined, high-class language and clear structure. Act like a strategic chief-of-staff for a high-performing traveler-builder. Prioritize execution, safety, resilience, and momentum.";
-    let payload = serde_json::json!({
-        "model": runtime.model,
-        "reasoning": {
-            "effort": runtime.default_reasoning_effort
-        },
-        "input": [
-            {
-                "role": "system",
-                "content": [
-                    { "type": "input_text", "text": system_prompt }
-                ]
-            },
-            {
-                "role": "user",
-                "content": [
-                    { "type": "input_text", "text": request.text }
-                ]
-            },
-            {
-                "role": "user",
-                "content": [
-                    { "type": "input_text", "text": format!("Context JSON: {}", serde_json::json!({
-                        "user": user_context,
-                        "survey": survey_context,
-                        "notes": notes_context,
-                        "memory_context": memory_context,
-                        "fallback_reply": fallback_reply
-                    })) }
-                ]
-            }
-        ],
-        "text": {
-            "verbosity": "high"
-        }
-    });
+        let found = search_memory_records(&records, "haifa train", &any, 10, now);
+        let ids = found
+            .iter()
+            .map(|item| item.memory_id.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(ids, vec!["m1", "m2"]);
 
-    let response = state
-        .http_client
-        .post("https://api.openai.com/v1/responses")
-        .bearer_auth(runtime.api_key.as_str())
-        .json(&payload)
-        .send()
-        .await
-        .context("OpenAI request failed")?;
+        let strict = MemorySearchFilters {
+            min_score: 0.9,
+            ..any
+        };
+        assert_eq!(
+            search_memory_records(&records, "haifa train", &strict, 10, now).len(),
+            1
+        );
 
-    let status = response.status();
-    if !status.is_success() {
-        let body = response.text().await.unwrap_or_default();
-        anyhow::bail!("OpenAI non-success status {}: {}", status.as_u16(), body);
+        let notes_only = ["note".to_string()];
+        let constraints = ["constraint".to_string()];
+        let filtered = MemorySearchFilters {
+            sources: &notes_only,
+            types: &constraints,
+            min_score: 0.0,
+        };
+        let found = search_memory_records(&records, "haifa", &filtered, 10, now);
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].memory_id, "m2");
     }
 
-    let body: serde_json::Value = response.json().await.context("OpenAI parse failed")?;
-    extract_openai_output_text(&body)
-        .filter(|value| !value.trim().is_empty())
-        .context("OpenAI output text missing")
-}
+    #[tokio::test]
+    async fn notes_etag_changes_on_every_write_import_and_delete() {
+        let pool = sqlx::sqlite::SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect("sqlite::memory:")
+            .await
+            .unwrap();
+        ensure_app_schema(&pool).await.unwrap();
+        let mut state = test_state().await;
+        state.db_pool = Some(pool.clone());
+        let user = test_user("notes-etag-user", "google", "ceo@atlasmasa.com");
+        let session = signed_in_headers(&state, &user);
+        let app = build_router(state.clone());
+        let list = |if_none_match: Option<&str>| {
+            let mut request = axum::http::Request::builder()
+                .method("GET")
+                .uri("/v1/notes")
+                .header(header::ORIGIN, "http://localhost:5500")
+                .body(axum::body::Body::empty())
+                .unwrap();
+            request.headers_mut().extend(session.clone());
+            if let Some(etag) = if_none_match {
+                request
+                    .headers_mut()
+                    .insert(header::IF_NONE_MATCH, HeaderValue::from_str(etag).unwrap());
+            }
+            request
+        };
+        let etag_of = |response: &Response| {
+            response.headers()[header::ETAG]
+                .to_str()
+                .unwrap()
+                .to_string()
+        };
 
-async fn rewrite_note_with_openai(
-    state: &ApiState,
-    note: &UserNoteRecord,
-    instruction: &str,
-) -> Result<String> {
-    let runtime = state
-        .openai_runtime
-        .as_ref()
-        .context("OpenAI runtime is not configured")?;
+        let response = app.clone().oneshot(list(None)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(response.headers().get(header::ETAG).is_none());
 
-    let payload = serde_json::json!({
-        "model": runtime.model,
-        "reasoning": {
-            "effort": runtime.default_reasoning_effort
-        },
-        "input": [
-            {
-                "role": "system",
-                "content": [
-                    { "type": "input_text", "text": "Rewrite notes into premium executive language while preserving facts and actionability." }
-                ]
-            },
-            {
-                "role": "user",
-                "content": [
-                    { "type": "input_text", "text": instruction },
-                    { "type": "input_text", "text": format!("Title: {}\n\nNote:\n{}", note.title, note.content) }
-                ]
-            }
-        ],
-        "text": {
-            "verbosity": "high"
-        }
-    });
+        let upsert = |content: &str| {
+            json_post(
+                "/v1/notes/upsert",
+                serde_json::json!({ "note_id": "n1", "title": "Haifa route", "content": content }),
+                &session,
+            )
+        };
+        let response = app.clone().oneshot(upsert("Coastal line")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let first = etag_of(&app.clone().oneshot(list(None)).await.unwrap());
+        let response = app.clone().oneshot(list(Some(&first))).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+
+        // A second edit in the same second must still produce a new tag.
+        app.clone().oneshot(upsert("Inland line")).await.unwrap();
+        let response = app.clone().oneshot(list(Some(&first))).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let edited = etag_of(&response);
+        assert_ne!(edited, first);
+
+        let response = app
+            .clone()
+            .oneshot(json_post(
+                "/v1/memory/import",
+                serde_json::json!({
+                    "items": [{
+                        "title": "Old trip",
+                        "content": "Eilat in 2019",
+                        "happened_at": "2019-04-01T09:00:00Z"
+                    }]
+                }),
+                &session,
+            ))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = app.clone().oneshot(list(Some(&edited))).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let imported = etag_of(&response);
+        assert_ne!(imported, edited);
+
+        let mut request = axum::http::Request::builder()
+            .method("DELETE")
+            .uri("/v1/notes/n1")
+            .header(header::ORIGIN, "http://localhost:5500")
+            .body(axum::body::Body::empty())
+            .unwrap();
+        request.headers_mut().extend(session.clone());
+        assert_eq!(
+            app.clone().oneshot(request).await.unwrap().status(),
+            StatusCode::OK
+        );
+        let response = app.clone().oneshot(list(Some(&imported))).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let deleted = etag_of(&response);
+        assert_ne!(deleted, imported);
+
+        let persisted = load_persistent_state(Some(&pool)).await.unwrap();
+        let revision = persisted.notes_revisions[&user.user_id];
+        assert_eq!(revision.revision, 4);
+        assert_eq!(notes_etag(revision), deleted);
+    }
 
-    let response = state
-        .http_client
-        .post("https://api.openai.com/v1/responses")
-        .bearer_auth(runtime.api_key.as_str())
-        .json(&payload)
-        .send()
-        .await
-        .context("OpenAI note rewrite request failed")?;
-    let status = response.status();
-    if !status.is_success() {
-        let body = response.text().await.unwrap_or_default();
-        anyhow::bail!("OpenAI note rewrite failed {}: {}", status.as_u16(), body);
+    #[tokio::test]
+    async fn memory_export_writes_one_record_per_line_without_sealed_originals() {
+        use futures::StreamExt as _;
+
+        let record = |memory_id: &str, text: &str| MemoryRecord {
+            memory_id: memory_id.to_string(),
+            user_id: "user-1".to_string(),
+            memory_type: "preference".to_string(),
+            stability: "permanent".to_string(),
+            source: "chat".to_string(),
+            text: text.to_string(),
+            weight: 0.7,
+            recency_score: 1.0,
+            tags: Vec::new(),
+            created_at: "2026-01-01T00:00:00Z".to_string(),
+            updated_at: "2026-01-01T00:00:00Z".to_string(),
+            expires_at: None,
+            fingerprint: memory_id.to_string(),
+            text_original_sealed: Some("sealed-original".to_string()),
+        };
+        let chunks = memory_export_lines(vec![
+            record("m1", "Prefers window seats"),
+            record("m2", "Line one\nline two"),
+        ])
+        .collect::<Vec<_>>()
+        .await;
+        let body = chunks
+            .into_iter()
+            .map(|chunk| String::from_utf8(chunk.unwrap()).unwrap())
+            .collect::<String>();
+
+        let lines = body.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 2);
+        assert!(body.ends_with('\n'));
+        assert!(!body.contains("sealed-original"));
+        let second: serde_json::Value = serde_json::from_str(lines[1]).unwrap();
+        assert_eq!(second["memory_id"], "m2");
+        assert_eq!(second["text"], "Line one\nline two");
+        assert!(memory_export_lines(Vec::new())
+            .collect::<Vec<_>>()
+            .await
+            .is_empty());
     }
 
-    let body: serde_json::Value = response
-        .json()
-        .await
-        .context("OpenAI rewrite parse failed")?;
-    extract_openai_output_text(&body)
-        .filter(|value| !value.trim().is_empty())
-        .context("OpenAI rewrite output missing")
-}
+    #[test]
+    fn derived_feedback_tags_merge_without_duplicates_under_the_cap() {
+        let mut tags = vec!["Crash".to_string(), "ios".to_string()];
+        merge_feedback_tags(&mut tags, vec!["crash", "billing"]);
+        assert_eq!(tags, vec!["Crash", "ios", "billing"]);
 
-fn extract_openai_output_text(payload: &serde_json::Value) -> Option<String> {
-    if let Some(value) = payload.get("output_text").and_then(|value| value.as_str()) {
-        return Some(value.to_string());
+        let mut full = (0..MAX_FEEDBACK_TAGS)
+            .map(|index| format!("tag-{index}"))
+            .collect::<Vec<_>>();
+        merge_feedback_tags(&mut full, vec!["auth"]);
+        assert_eq!(full.len(), MAX_FEEDBACK_TAGS);
+        assert!(!full.contains(&"auth".to_string()));
     }
-    let output = payload.get("output")?.as_array()?;
-    let mut chunks = Vec::new();
-    for item in output {
-        if let Some(content) = item.get("content").and_then(|value| value.as_array()) {
-            for content_item in content {
-                if content_item
-                    .get("type")
-                    .and_then(|value| value.as_str())
-                    .map(|value| value == "output_text")
-                    .unwrap_or(false)
-                {
-                    if let Some(text) = content_item.get("text").and_then(|value| value.as_str()) {
-                        chunks.push(text.to_string());
-                    }
-                }
-            }
-        }
+
+    #[test]
+    fn feedback_resolution_is_stamped_once_and_cleared_on_reopen() {
+        let mut item = FeedbackRecord {
+            feedback_id: "fb-1".to_string(),
+            user_id: None,
+            category: "bug".to_string(),
+            severity: "high".to_string(),
+            message: "Reminder fired twice".to_string(),
+            tags: Vec::new(),
+            target_employee: "product_team".to_string(),
+            source: "web".to_string(),
+            status: "new".to_string(),
+            created_at: "2026-01-01T00:00:00Z".to_string(),
+            resolved_at: None,
+            resolved_by: None,
+            resolution_note: None,
+            message_original_sealed: None,
+        };
+        let first = chrono::DateTime::parse_from_rfc3339("2026-01-02T09:00:00Z")
+            .unwrap()
+            .with_timezone(&chrono::Utc);
+        let later = first + chrono::Duration::hours(5);
+
+        let missing_actor = apply_feedback_status(&mut item, "resolved", Some("  "), None, first)
+            .expect_err("resolving needs an actor");
+        assert_eq!(
+            serde_json::to_value(&missing_actor).unwrap()["error"],
+            "invalid_resolved_by"
+        );
+        let unknown = apply_feedback_status(&mut item, "done", None, None, first)
+            .expect_err("unknown status is rejected");
+        assert_eq!(
+            serde_json::to_value(&unknown).unwrap()["error"],
+            "invalid_feedback_status"
+        );
+        assert_eq!(item.status, "new");
+
+        apply_feedback_status(
+            &mut item,
+            " Resolved ",
+            Some("dana"),
+            Some("Fixed in 2.4"),
+            first,
+        )
+        .unwrap();
+        assert_eq!(item.status, "resolved");
+        assert_eq!(
+            item.resolved_at.as_deref(),
+            Some("2026-01-02T09:00:00+00:00")
+        );
+        apply_feedback_status(&mut item, "resolved", Some("omer"), None, later).unwrap();
+        assert_eq!(item.resolved_by.as_deref(), Some("dana"));
+        assert_eq!(item.resolution_note.as_deref(), Some("Fixed in 2.4"));
+        assert_eq!(
+            item.resolved_at.as_deref(),
+            Some("2026-01-02T09:00:00+00:00")
+        );
+
+        apply_feedback_status(&mut item, "triaged", None, None, later).unwrap();
+        assert_eq!(item.status, "triaged");
+        assert!(item.resolved_at.is_none() && item.resolved_by.is_none());
+        assert!(item.resolution_note.is_none());
     }
-    if chunks.is_empty() {
-        None
-    } else {
-        Some(chunks.join("\n\n"))
+
+    #[test]
+    fn company_status_file_is_validated_and_falls_back_to_the_default() {
+        let parsed = parse_company_status_file(
+            r#"{"phase": " Pilot ", "current_focus": ["Haifa ops", " "], "upcoming": [],
+                "open_for_investment": false, "message": "Pilot running in Haifa"}"#,
+        )
+        .expect("valid file should parse");
+        assert_eq!(parsed.phase, "Pilot");
+        assert_eq!(parsed.current_focus, vec!["Haifa ops"]);
+        assert!(!parsed.open_for_investment);
+
+        assert!(parse_company_status_file(r#"{"phase": "Pilot"}"#).is_err());
+        assert!(parse_company_status_file(
+            r#"{"phase": "", "current_focus": [], "upcoming": [],
+                "open_for_investment": true, "message": "x"}"#
+        )
+        .is_err());
+
+        let default_phase = default_company_status().phase;
+        assert_eq!(
+            initial_company_status(None, Some("/nonexistent/company_status.json")).phase,
+            default_phase
+        );
+        assert_eq!(initial_company_status(None, None).phase, default_phase);
+        assert_eq!(
+            initial_company_status(Some(parsed), Some("/nonexistent/company_status.json")).phase,
+            "Pilot"
+        );
     }
-}
 
-fn build_cors_layer(allowed_origins: &Arc<Vec<String>>) -> CorsLayer {
-    let origins = allowed_origins
-        .iter()
-        .filter_map(|origin| HeaderValue::from_str(origin).ok())
-        .collect::<Vec<_>>();
-    let origins = if origins.is_empty() {
-        vec![HeaderValue::from_static("http://localhost:5500")]
-    } else {
-        origins
-    };
+    #[test]
+    fn request_spans_never_carry_credential_headers() {
+        #[derive(Clone, Default)]
+        struct Captured(std::sync::Arc<parking_lot::Mutex<Vec<u8>>>);
+        impl std::io::Write for Captured {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                self.0.lock().extend_from_slice(buf);
+                Ok(buf.len())
+            }
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
 
-    CorsLayer::new()
-        .allow_origin(AllowOrigin::list(origins))
-        .allow_methods([Method::GET, Method::POST, Method::OPTIONS])
-        .allow_headers([
-            header::CONTENT_TYPE,
-            header::HeaderName::from_static("x-api-key"),
-        ])
-        .allow_credentials(true)
-}
+        let request = axum::http::Request::builder()
+            .method("POST")
+            .uri("/v1/chat?user_id=user-secret-id")
+            .header("cookie", "atlas_session=cookie-secret")
+            .header("authorization", "Bearer bearer-secret")
+            .header("x-api-key", "key-secret")
+            .header("stripe-signature", "t=1,v1=sig-secret")
+            .header("user-agent", "atlas-ios/2.4")
+            .header("x-forwarded-for", "203.0.113.7")
+            .header("x-request-id", "req-123")
+            .body(axum::body::Body::from("my private chat text"))
+            .unwrap();
+
+        let captured = Captured::default();
+        let writer = captured.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_max_level(tracing::Level::DEBUG)
+            .with_ansi(false)
+            .with_writer(move || writer.clone())
+            .finish();
+        tracing::subscriber::with_default(subscriber, || {
+            let span = request_span(&request);
+            let _entered = span.enter();
+            tracing::info!("request finished");
+        });
 
-async fn rate_limit_middleware(
-    State(state): State<ApiState>,
-    request: Request<Body>,
-    next: Next,
-) -> Response {
-    if request.method() == Method::OPTIONS {
-        return next.run(request).await;
+        let output = String::from_utf8(captured.0.lock().clone()).unwrap();
+        assert!(output.contains("path=/v1/chat"));
+        assert!(output.contains("request_id=req-123"));
+        for secret in [
+            "cookie-secret",
+            "bearer-secret",
+            "key-secret",
+            "sig-secret",
+            "user-secret-id",
+            "my private chat text",
+            "atlas-ios/2.4",
+            "203.0.113.7",
+        ] {
+            assert!(!output.contains(secret), "{secret} leaked into {output}");
+        }
     }
 
-    let path = request.uri().path().to_string();
-    let ip = request_ip(&request);
+    #[test]
+    fn expired_memories_are_pruned_for_every_user() {
+        let now = chrono::Utc::now();
+        let record = |user_id: &str, memory_id: &str, expires_at: Option<String>| MemoryRecord {
+            memory_id: memory_id.to_string(),
+            user_id: user_id.to_string(),
+            memory_type: "mood".to_string(),
+            stability: "transient".to_string(),
+            source: "chat".to_string(),
+            text: "Tired after the night shift".to_string(),
+            weight: 0.75,
+            recency_score: 1.0,
+            tags: Vec::new(),
+            created_at: now.to_rfc3339(),
+            updated_at: now.to_rfc3339(),
+            expires_at,
+            fingerprint: memory_id.to_string(),
+            text_original_sealed: None,
+        };
+        let past = Some((now - chrono::Duration::days(1)).to_rfc3339());
+        let future = Some((now + chrono::Duration::days(1)).to_rfc3339());
+        let mut memories = HashMap::from([
+            (
+                "user-b".to_string(),
+                vec![
+                    record("user-b", "b1", past.clone()),
+                    record("user-b", "b2", past.clone()),
+                    record("user-b", "b3", None),
+                ],
+            ),
+            (
+                "user-a".to_string(),
+                vec![record("user-a", "a1", past), record("user-a", "a2", future)],
+            ),
+            ("user-c".to_string(), vec![record("user-c", "c1", None)]),
+        ]);
 
-    if is_auth_rate_limited_endpoint(path.as_str()) {
-        let auth_key = format!("auth:{}:{}", path, ip);
-        if !state.auth_limiter.allow(&auth_key) {
-            return (
-                StatusCode::TOO_MANY_REQUESTS,
-                Json(serde_json::json!({
-                    "error": "auth_rate_limited",
-                    "message": "too many authentication attempts from this IP. wait and retry."
-                })),
+        let pruned = prune_expired_memories_for_all_users(&mut memories, now);
+        assert_eq!(
+            pruned,
+            vec![("user-a".to_string(), 1), ("user-b".to_string(), 2)]
+        );
+        assert_eq!(memories["user-a"].len(), 1);
+        assert_eq!(memories["user-b"][0].memory_id, "b3");
+        assert_eq!(memories["user-c"].len(), 1);
+        assert!(prune_expired_memories_for_all_users(&mut memories, now).is_empty());
+    }
+
+    #[tokio::test]
+    async fn issuing_a_session_writes_exactly_one_row() {
+        let pool = sqlx::sqlite::SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect("sqlite::memory:")
+            .await
+            .unwrap();
+        ensure_app_schema(&pool).await.unwrap();
+        let mut state = test_state().await;
+        state.db_pool = Some(pool.clone());
+        let now = chrono::Utc::now();
+        let session = |user_id: &str, expires_at: chrono::DateTime<chrono::Utc>| SessionRecord {
+            user_id: user_id.to_string(),
+            expires_at,
+            created_at: now,
+            client_network: None,
+            region: Some("IL".to_string()),
+        };
+        let count = || async {
+            sqlx::query("SELECT COUNT(*) AS total FROM auth_sessions")
+                .fetch_one(&pool)
+                .await
+                .unwrap()
+                .get::<i64, _>("total")
+        };
+        for (session_id, user_id) in [("s1", "u1"), ("s2", "u2")] {
+            upsert_session_row(
+                &pool,
+                session_id,
+                &session(user_id, now + chrono::Duration::days(7)),
             )
-                .into_response();
+            .await
+            .unwrap();
         }
-    }
 
-    if is_public_endpoint(path.as_str()) {
-        return next.run(request).await;
+        let mut user = test_user("u3", "google", "dana@example.com");
+        state
+            .users
+            .write()
+            .insert(user.user_id.clone(), user.clone());
+        let issued = issue_session_for_user(&state, &mut user, None, &HeaderMap::new())
+            .await
+            .unwrap();
+        assert_eq!(count().await, 3);
+        let stored_user = sqlx::query("SELECT user_id FROM auth_sessions WHERE session_id = ?1")
+            .bind(issued.as_str())
+            .fetch_one(&pool)
+            .await
+            .unwrap()
+            .get::<String, _>("user_id");
+        assert_eq!(stored_user, "u3");
+
+        let refreshed = upsert_session_row(
+            &pool,
+            issued.as_str(),
+            &session("u3", now + chrono::Duration::days(30)),
+        )
+        .await
+        .unwrap();
+        assert_eq!(refreshed, 1);
+        assert_eq!(count().await, 3);
+
+        let mut logout = axum::http::Request::builder()
+            .method("POST")
+            .uri("/v1/auth/logout")
+            .header("origin", "http://localhost:5500")
+            .body(axum::body::Body::empty())
+            .unwrap();
+        logout.headers_mut().insert(
+            header::COOKIE,
+            HeaderValue::from_str(format!("{}={issued}", state.cookie_name).as_str()).unwrap(),
+        );
+        let response = build_router(state.clone()).oneshot(logout).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(count().await, 2);
+        let persisted = load_persistent_state(Some(&pool)).await.unwrap();
+        assert!(!persisted.sessions.contains_key(&issued));
+
+        upsert_session_row(
+            &pool,
+            "old",
+            &session("u1", now - chrono::Duration::hours(1)),
+        )
+        .await
+        .unwrap();
+        let persisted = load_persistent_state(Some(&pool)).await.unwrap();
+        assert!(persisted.sessions.contains_key("s1"));
+        assert!(persisted.sessions.contains_key("s2"));
+        assert!(!persisted.sessions.contains_key("old"));
+        assert_eq!(count().await, 2);
     }
 
-    if !state.limiter.allow(&ip) {
-        return (
-            StatusCode::TOO_MANY_REQUESTS,
-            Json(serde_json::json!({
-                "error": "rate_limited",
-                "message": "rate limit exceeded for this IP"
-            })),
-        )
-            .into_response();
+    #[test]
+    fn webauthn_config_errors_name_the_bad_variable() {
+        assert!(build_webauthn("atlasmasa.com", "https://atlasmasa.com", "Atlas").is_ok());
+        let bad_origin = build_webauthn("atlasmasa.com", "atlasmasa dot com", "Atlas")
+            .expect_err("malformed origin");
+        assert!(bad_origin.contains("ATLAS_WEBAUTHN_ORIGIN"));
+        let mismatch = build_webauthn("example.org", "https://atlasmasa.com", "Atlas")
+            .expect_err("rp id outside origin");
+        assert!(mismatch.contains("ATLAS_WEBAUTHN_RP_ID"));
     }
 
-    next.run(request).await
-}
+    async fn test_state() -> ApiState {
+        build_state(std::path::PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("../../kb"))
+            .await
+            .expect("state should build")
+    }
 
-async fn csrf_origin_middleware(
-    State(state): State<ApiState>,
-    request: Request<Body>,
-    next: Next,
-) -> Response {
-    if request.method() == Method::GET
-        || request.method() == Method::HEAD
-        || request.method() == Method::OPTIONS
-    {
-        return next.run(request).await;
+    fn test_user(user_id: &str, provider: &str, email: &str) -> UserRecord {
+        let now = chrono::Utc::now().to_rfc3339();
+        UserRecord {
+            user_id: user_id.to_string(),
+            provider: provider.to_string(),
+            email: email.to_string(),
+            name: "Dana".to_string(),
+            locale: "en".to_string(),
+            trip_style: None,
+            risk_preference: None,
+            memory_opt_in: true,
+            passkey_user_handle: None,
+            created_at: now.clone(),
+            updated_at: now,
+            last_login_at: None,
+            login_count: 0,
+        }
     }
 
-    let has_cookie_session = read_cookie_value(request.headers(), &state.cookie_name).is_some();
-    if !has_cookie_session {
-        return next.run(request).await;
+    // Signs `user` in on `state` and returns request headers carrying the session cookie.
+    fn signed_in_headers(state: &ApiState, user: &UserRecord) -> HeaderMap {
+        let session_id = format!("session-{}", user.user_id);
+        remember_user(state, user.clone());
+        state.sessions.write().insert(
+            session_id.clone(),
+            SessionRecord {
+                user_id: user.user_id.clone(),
+                expires_at: chrono::Utc::now() + chrono::Duration::days(1),
+                created_at: chrono::Utc::now(),
+                client_network: None,
+                region: None,
+            },
+        );
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::COOKIE,
+            HeaderValue::from_str(format!("{}={session_id}", state.cookie_name).as_str()).unwrap(),
+        );
+        headers
     }
 
-    let origin = request
-        .headers()
-        .get(header::HeaderName::from_static("origin"))
-        .and_then(|value| value.to_str().ok())
-        .unwrap_or_default()
-        .trim()
-        .trim_end_matches('/')
-        .to_string();
+    #[tokio::test]
+    async fn provider_links_require_the_initiating_session_and_a_free_identity() {
+        let state = test_state().await;
+        let owner = test_user("owner", "passkey", "dana@example.com");
+        let other = test_user("other", "passkey", "sam@example.com");
+        let owner_headers = signed_in_headers(&state, &owner);
+        let other_headers = signed_in_headers(&state, &other);
+        let pending = |user: &UserRecord| OAuthStateRecord {
+            provider: "google".to_string(),
+            code_verifier: None,
+            nonce: None,
+            return_to: "/app".to_string(),
+            link_user_id: Some(user.user_id.clone()),
+            link_session_id: Some(format!("session-{}", user.user_id)),
+            expires_at: chrono::Utc::now() + chrono::Duration::minutes(5),
+        };
+        let identity = || ProviderIdentity {
+            provider: "google",
+            subject: "google-sub-1".to_string(),
+            email: "dana@gmail.com".to_string(),
+            email_verified: true,
+        };
+        let location = |response: Response| {
+            response.headers()[header::LOCATION]
+                .to_str()
+                .unwrap()
+                .to_string()
+        };
+        let owner_of = |state: &ApiState| {
+            state
+                .linked_identities
+                .read()
+                .get(&linked_identity_key("google", "google-sub-1"))
+                .map(|record| record.user_id.clone())
+        };
 
-    if origin.is_empty() {
-        return (
-            StatusCode::FORBIDDEN,
-            Json(serde_json::json!({
-                "error": "origin_required",
-                "message": "origin header is required for cookie-authenticated state changes"
-            })),
+        // A victim finishing a link someone else started, and a callback without any session.
+        for headers in [other_headers.clone(), HeaderMap::new()] {
+            let response =
+                complete_provider_link(&state, &headers, &pending(&owner), identity(), "t", "")
+                    .await;
+            assert!(location(response).contains("reason=link_session_mismatch"));
+            assert_eq!(owner_of(&state), None);
+        }
+
+        let response = complete_provider_link(
+            &state,
+            &owner_headers,
+            &pending(&owner),
+            identity(),
+            "t",
+            "",
         )
-            .into_response();
+        .await;
+        assert!(location(response).contains("link=success"));
+        assert_eq!(owner_of(&state).as_deref(), Some("owner"));
+
+        let response = complete_provider_link(
+            &state,
+            &other_headers,
+            &pending(&other),
+            identity(),
+            "t",
+            "",
+        )
+        .await;
+        assert!(location(response).contains("reason=identity_linked_to_another_account"));
+        assert_eq!(owner_of(&state).as_deref(), Some("owner"));
     }
 
-    if !state.allowed_origins.iter().any(|value| value == &origin) {
-        return (
-            StatusCode::FORBIDDEN,
-            Json(serde_json::json!({
-                "error": "origin_not_allowed",
-                "message": "request origin is not in ATLAS_ALLOWED_ORIGINS"
-            })),
+    #[tokio::test]
+    async fn passkey_login_starts_never_lock_an_email() {
+        let state = test_state().await;
+        let email = "dana@example.com";
+        let app = build_router(state.clone());
+        for _ in 0..15 {
+            let request = axum::http::Request::builder()
+                .method("POST")
+                .uri("/v1/auth/passkey/login/start")
+                .header("content-type", "application/json")
+                .body(axum::body::Body::from(
+                    serde_json::json!({ "email": email }).to_string(),
+                ))
+                .unwrap();
+            let response = app.clone().oneshot(request).await.unwrap();
+            assert_ne!(response.status(), StatusCode::UNAUTHORIZED);
+        }
+        assert!(state
+            .login_email_lockouts
+            .locked_for(email, Instant::now())
+            .is_none());
+
+        let mut last = StatusCode::OK;
+        for _ in 0..11 {
+            last = passkey_login_failure(
+                &state,
+                Some(email),
+                "t",
+                "passkey_authentication_failed",
+                "passkey authentication failed".to_string(),
+            )
+            .status();
+        }
+        assert_eq!(last, StatusCode::TOO_MANY_REQUESTS);
+        assert!(state
+            .login_email_lockouts
+            .locked_for(email, Instant::now())
+            .is_some());
+    }
+
+    #[tokio::test]
+    async fn shared_challenges_cannot_be_replayed_from_the_local_cache() {
+        let pool = sqlx::sqlite::SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect("sqlite::memory:")
+            .await
+            .unwrap();
+        ensure_app_schema(&pool).await.unwrap();
+        let mut state = test_state().await;
+        let shared = SharedAuthStore::Sqlite(pool);
+        state.shared_auth = Some(shared.clone());
+        let expires_at = chrono::Utc::now() + chrono::Duration::minutes(5);
+
+        stash_shared_challenge(&state, CHALLENGE_OAUTH, "state-1", &"first", expires_at)
+            .await
+            .unwrap();
+        // Another instance finishes the flow first.
+        assert!(shared
+            .take_challenge(CHALLENGE_OAUTH, "state-1")
+            .await
+            .unwrap()
+            .is_some());
+        let replayed = take_shared_challenge(
+            &state,
+            CHALLENGE_OAUTH,
+            "state-1",
+            Some("cached".to_string()),
         )
-            .into_response();
+        .await;
+        assert_eq!(replayed, None);
+
+        stash_shared_challenge(&state, CHALLENGE_OAUTH, "state-2", &"stored", expires_at)
+            .await
+            .unwrap();
+        let taken = take_shared_challenge(
+            &state,
+            CHALLENGE_OAUTH,
+            "state-2",
+            Some("cached".to_string()),
+        )
+        .await;
+        assert_eq!(taken.as_deref(), Some("stored"));
     }
 
-    next.run(request).await
-}
+    #[tokio::test]
+    async fn auth_starts_fail_when_the_shared_challenge_cannot_be_stored() {
+        let pool = sqlx::sqlite::SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect("sqlite::memory:")
+            .await
+            .unwrap();
+        ensure_app_schema(&pool).await.unwrap();
+        let mut state = test_state().await;
+        state.shared_auth = Some(SharedAuthStore::Sqlite(pool.clone()));
+        state
+            .passkeys_by_user
+            .write()
+            .insert("known".to_string(), vec![test_passkey("known")]);
+        sqlx::query("DROP TABLE auth_challenges")
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        let response = build_router(state.clone())
+            .oneshot(json_post(
+                "/v1/auth/passkey/login/start",
+                serde_json::json!({ "email": "nobody@example.com" }),
+                &HeaderMap::new(),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(
+            response_json(response).await["error"],
+            "auth_state_unavailable"
+        );
+        assert!(state.passkey_authentications.read().is_empty());
+    }
 
-fn is_auth_rate_limited_endpoint(path: &str) -> bool {
-    matches!(
-        path,
-        "/v1/auth/google/start"
-            | "/v1/auth/google/callback"
-            | "/v1/auth/apple/start"
-            | "/v1/auth/apple/callback"
-            | "/v1/auth/passkey/register/start"
-            | "/v1/auth/passkey/register/finish"
-            | "/v1/auth/passkey/login/start"
-            | "/v1/auth/passkey/login/finish"
-    )
-}
+    fn json_post(
+        uri: &str,
+        body: serde_json::Value,
+        headers: &HeaderMap,
+    ) -> axum::http::Request<axum::body::Body> {
+        let mut request = axum::http::Request::builder()
+            .method("POST")
+            .uri(uri)
+            .header("content-type", "application/json")
+            .header("origin", "http://localhost:5500")
+            .body(axum::body::Body::from(body.to_string()))
+            .unwrap();
+        request.headers_mut().extend(headers.clone());
+        request
+    }
 
-fn request_ip(request: &Request<Body>) -> String {
-    request
-        .headers()
-        .get("x-forwarded-for")
-        .and_then(|value| value.to_str().ok())
-        .map(|value| {
-            value
-                .split(',')
-                .next()
-                .unwrap_or("unknown")
-                .trim()
-                .to_string()
-        })
-        .unwrap_or_else(|| "local".to_string())
-}
+    async fn response_json(response: Response) -> serde_json::Value {
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        serde_json::from_slice(&body).unwrap()
+    }
 
-async fn security_headers_middleware(
-    State(state): State<ApiState>,
-    request: Request<Body>,
-    next: Next,
-) -> Response {
-    let mut response = next.run(request).await;
+    #[tokio::test]
+    async fn recovery_codes_redeem_once_and_fail_closed_when_unsaved() {
+        let pool = sqlx::sqlite::SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect("sqlite::memory:")
+            .await
+            .unwrap();
+        ensure_app_schema(&pool).await.unwrap();
+        let mut state = test_state().await;
+        state.db_pool = Some(pool.clone());
+        let user = test_user("recovery-user", "google", "dana@example.com");
+        let session = signed_in_headers(&state, &user);
+        let app = build_router(state.clone());
+
+        let generated = app
+            .clone()
+            .oneshot(json_post(
+                "/v1/auth/recovery_codes/generate",
+                serde_json::json!({ "count": 2 }),
+                &session,
+            ))
+            .await
+            .unwrap();
+        assert_eq!(generated.status(), StatusCode::OK);
+        let codes = response_json(generated).await["codes"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|code| code.as_str().unwrap().to_string())
+            .collect::<Vec<_>>();
+        let redeem = |code: &str| {
+            json_post(
+                "/v1/auth/recovery_codes/redeem",
+                serde_json::json!({ "email": "dana@example.com", "code": code }),
+                &HeaderMap::new(),
+            )
+        };
 
-    response.headers_mut().insert(
-        header::X_CONTENT_TYPE_OPTIONS,
-        HeaderValue::from_static("nosniff"),
-    );
-    response.headers_mut().insert(
-        header::HeaderName::from_static("x-frame-options"),
-        HeaderValue::from_static("DENY"),
-    );
-    response.headers_mut().insert(
-        header::HeaderName::from_static("referrer-policy"),
-        HeaderValue::from_static("strict-origin-when-cross-origin"),
-    );
-    response.headers_mut().insert(
-        header::HeaderName::from_static("permissions-policy"),
-        HeaderValue::from_static("camera=(), microphone=(), geolocation=(self)"),
-    );
-    response.headers_mut().insert(
-        header::HeaderName::from_static("content-security-policy"),
-        HeaderValue::from_static("default-src 'none'; frame-ancestors 'none'; base-uri 'none'"),
-    );
-    if state.cookie_secure {
-        response.headers_mut().insert(
-            header::HeaderName::from_static("strict-transport-security"),
-            HeaderValue::from_static("max-age=31536000; includeSubDomains; preload"),
+        let first = app.clone().oneshot(redeem(&codes[0])).await.unwrap();
+        assert_eq!(first.status(), StatusCode::OK);
+        let reused = app.clone().oneshot(redeem(&codes[0])).await.unwrap();
+        assert_eq!(reused.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(
+            response_json(reused).await["error"],
+            "invalid_recovery_code"
         );
+        let persisted = load_persistent_state(Some(&pool)).await.unwrap();
+        assert_eq!(
+            persisted.recovery_codes["recovery-user"]
+                .iter()
+                .filter(|record| record.used_at.is_some())
+                .count(),
+            1
+        );
+
+        sqlx::query("DROP TABLE recovery_codes")
+            .execute(&pool)
+            .await
+            .unwrap();
+        let unsaved = app.clone().oneshot(redeem(&codes[1])).await.unwrap();
+        assert_eq!(unsaved.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert!(state.recovery_codes.read()["recovery-user"]
+            .iter()
+            .any(|record| record.used_at.is_none()));
     }
 
-    response
-}
+    #[tokio::test]
+    async fn recovery_codes_generate_keeps_the_previous_set_when_unsaved() {
+        let pool = sqlx::sqlite::SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect("sqlite::memory:")
+            .await
+            .unwrap();
+        ensure_app_schema(&pool).await.unwrap();
+        let mut state = test_state().await;
+        state.db_pool = Some(pool.clone());
+        let user = test_user("recovery-user", "google", "dana@example.com");
+        let session = signed_in_headers(&state, &user);
+        let app = build_router(state.clone());
+        let generate = || {
+            json_post(
+                "/v1/auth/recovery_codes/generate",
+                serde_json::json!({ "count": 2 }),
+                &session,
+            )
+        };
 
-#[cfg(test)]
-mod tests {
-    use super::{
-        build_clear_cookie, build_session_cookie, build_test_stripe_signature,
-        cloud_requirements_for_endpoint, ingest_memory_records_if_opted_in, is_public_endpoint,
-        next_survey_question, prioritize_execution_tasks, request_origin_from_headers,
-        retrieve_memory_context_from_records, schedule_minutes_offset, survey_total_questions,
-        verify_stripe_webhook_signature, ExecutionTaskCandidate, MemoryIngestEvent, MemoryRecord,
-        DEFAULT_STRIPE_WEBHOOK_TOLERANCE_SECONDS,
-    };
-    use axum::http::{header, HeaderMap, HeaderValue};
-    use chrono::Duration;
+        let first = app.clone().oneshot(generate()).await.unwrap();
+        assert_eq!(first.status(), StatusCode::OK);
+        let previous = state.recovery_codes.read()["recovery-user"].clone();
 
-    #[test]
-    fn session_cookie_is_secure_and_domain_scoped() {
-        let cookie = build_session_cookie(
-            "atlas_session",
-            "session123",
-            3600,
-            true,
-            "strict",
-            "atlasmasa.com",
+        sqlx::query("DROP TABLE recovery_codes")
+            .execute(&pool)
+            .await
+            .unwrap();
+        let unsaved = app.clone().oneshot(generate()).await.unwrap();
+        assert_eq!(unsaved.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        let body = response_json(unsaved).await;
+        assert_eq!(body["error"], "recovery_code_persist_failed");
+        assert!(body.get("codes").is_none());
+        let kept = state.recovery_codes.read()["recovery-user"]
+            .iter()
+            .map(|record| record.code_id.clone())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            kept,
+            previous
+                .iter()
+                .map(|record| record.code_id.clone())
+                .collect::<Vec<_>>()
         );
-        assert!(cookie.contains("HttpOnly"));
-        assert!(cookie.contains("Secure"));
-        assert!(cookie.contains("SameSite=Strict"));
-        assert!(cookie.contains("Domain=atlasmasa.com"));
     }
 
-    #[test]
-    fn clear_cookie_preserves_security_attributes() {
-        let cookie = build_clear_cookie("atlas_session", true, "lax", "atlasmasa.com");
-        assert!(cookie.contains("HttpOnly"));
-        assert!(cookie.contains("Secure"));
-        assert!(cookie.contains("SameSite=Lax"));
-        assert!(cookie.contains("Domain=atlasmasa.com"));
-        assert!(cookie.contains("Max-Age=0"));
+    fn test_passkey(user_id: &str) -> PasskeyRecord {
+        let credential: Passkey = serde_json::from_value(serde_json::json!({
+            "cred": {
+                "cred_id": URL_SAFE_NO_PAD.encode([7_u8; 32]),
+                "cred": {
+                    "type_": "ES256",
+                    "key": { "EC_EC2": {
+                        "curve": "SECP256R1",
+                        "x": URL_SAFE_NO_PAD.encode([1_u8; 32]),
+                        "y": URL_SAFE_NO_PAD.encode([2_u8; 32])
+                    } }
+                },
+                "counter": 0,
+                "transports": null,
+                "user_verified": true,
+                "backup_eligible": false,
+                "backup_state": false,
+                "registration_policy": "required",
+                "extensions": {
+                    "cred_protect": "Ignored",
+                    "hmac_create_secret": "NotRequested",
+                    "appid": "NotRequested",
+                    "cred_props": "Ignored"
+                },
+                "attestation": { "data": "None", "metadata": "None" },
+                "attestation_format": "none"
+            }
+        }))
+        .expect("passkey fixture should deserialize");
+        PasskeyRecord {
+            passkey_id: format!("passkey-{user_id}"),
+            user_id: user_id.to_string(),
+            credential,
+            created_at: chrono::Utc::now().to_rfc3339(),
+            last_used_at: None,
+        }
     }
 
-    #[test]
-    fn session_cookie_can_be_host_only_without_domain_attribute() {
-        let cookie = build_session_cookie("atlas_session", "session123", 3600, true, "strict", "");
-        assert!(!cookie.contains("Domain="));
+    #[tokio::test]
+    async fn passkey_login_start_answers_unknown_emails_like_known_ones() {
+        let state = test_state().await;
+        let known = test_user("known", "passkey", "known@example.com");
+        state.users.write().insert(known.user_id.clone(), known);
+        state
+            .passkeys_by_user
+            .write()
+            .insert("known".to_string(), vec![test_passkey("known")]);
+        let app = build_router(state.clone());
+        let start = |email: &str| {
+            let app = app.clone();
+            let request = json_post(
+                "/v1/auth/passkey/login/start",
+                serde_json::json!({ "email": email }),
+                &HeaderMap::new(),
+            );
+            async move {
+                let response = app.oneshot(request).await.unwrap();
+                assert_eq!(response.status(), StatusCode::OK);
+                response_json(response).await
+            }
+        };
+        let shape = |value: &serde_json::Value| {
+            let mut keys = value["options"]["publicKey"]
+                .as_object()
+                .unwrap()
+                .keys()
+                .cloned()
+                .collect::<Vec<_>>();
+            keys.sort();
+            let credentials = value["options"]["publicKey"]["allowCredentials"]
+                .as_array()
+                .unwrap()
+                .clone();
+            let entry_keys = credentials
+                .iter()
+                .map(|entry| {
+                    let mut keys = entry
+                        .as_object()
+                        .unwrap()
+                        .keys()
+                        .cloned()
+                        .collect::<Vec<_>>();
+                    keys.sort();
+                    keys
+                })
+                .collect::<HashSet<_>>();
+            (keys, entry_keys)
+        };
+        let credential_id = |value: &serde_json::Value| {
+            value["options"]["publicKey"]["allowCredentials"][0]["id"].clone()
+        };
+
+        let real = start("known@example.com").await;
+        let decoy = start("nobody@example.com").await;
+        assert_eq!(shape(&real), shape(&decoy));
+        let decoy_ids = decoy_credential_ids(&state.passkey_decoy_secret, "nobody@example.com")
+            .unwrap()
+            .iter()
+            .map(|id| serde_json::json!(URL_SAFE_NO_PAD.encode(id)))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            decoy["options"]["publicKey"]["allowCredentials"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|entry| entry["id"].clone())
+                .collect::<Vec<_>>(),
+            decoy_ids
+        );
+        assert_ne!(credential_id(&real), credential_id(&decoy));
+        assert_eq!(
+            credential_id(&decoy),
+            credential_id(&start("nobody@example.com").await)
+        );
+        assert_ne!(
+            credential_id(&decoy),
+            credential_id(&start("someone@example.com").await)
+        );
+        // Knowing the API key is not enough to compute a decoy.
+        assert_ne!(
+            credential_id(&decoy),
+            serde_json::json!(URL_SAFE_NO_PAD.encode(
+                &decoy_credential_ids(state.api_key.as_str(), "nobody@example.com").unwrap()[0]
+            ))
+        );
     }
 
-    #[test]
-    fn memory_ingestion_deduplicates_and_refreshes_existing_record() {
-        let now = chrono::Utc::now();
-        let mut records = Vec::new();
-        let first = ingest_memory_records_if_opted_in(
-            &mut records,
-            "user-1",
-            true,
-            MemoryIngestEvent {
-                memory_type: "preference".to_string(),
-                stability: "permanent".to_string(),
-                source: "note".to_string(),
-                text: "Prefers desert routes with low crowds".to_string(),
-                weight: 0.80,
-                tags: vec!["travel".to_string()],
-                happened_at: Some(now - Duration::days(2)),
-                expires_at: None,
-            },
-            now,
-        )
-        .expect("first ingestion should create a memory");
-        assert_eq!(records.len(), 1);
+    #[tokio::test]
+    async fn note_rewrites_save_only_server_issued_previews() {
+        let state = test_state().await;
+        let user = test_user("rewrite-user", "google", "ceo@atlasmasa.com");
+        let session = signed_in_headers(&state, &user);
+        state.user_notes.write().insert(
+            user.user_id.clone(),
+            vec![UserNoteRecord {
+                note_id: "note-1".to_string(),
+                user_id: user.user_id.clone(),
+                title: "Plan".to_string(),
+                content: "draft".to_string(),
+                tags: Vec::new(),
+                updated_at: chrono::Utc::now().to_rfc3339(),
+                structured: None,
+            }],
+        );
+        let structured = sanitize_structured_note_rewrite(StructuredNoteRewrite {
+            immediate_tasks: (0..40).map(|index| format!("task {index}")).collect(),
+            mid_term: vec!["hire".to_string()],
+            long_term: Vec::new(),
+        });
+        assert_eq!(structured.immediate_tasks.len(), MAX_REWRITE_SECTION_ITEMS);
+        let foreign = store_note_rewrite_preview(
+            &state,
+            "someone-else".to_string(),
+            "note-1".to_string(),
+            &structured,
+        );
+        let own = store_note_rewrite_preview(
+            &state,
+            user.user_id.clone(),
+            "note-1".to_string(),
+            &structured,
+        );
+        let app = build_router(state.clone());
+        let save = |preview_id: serde_json::Value| {
+            json_post(
+                "/v1/notes/rewrite",
+                serde_json::json!({ "note_id": "note-1", "preview_id": preview_id }),
+                &session,
+            )
+        };
 
-        let second = ingest_memory_records_if_opted_in(
-            &mut records,
-            "user-1",
-            true,
-            MemoryIngestEvent {
-                memory_type: "preference".to_string(),
-                stability: "permanent".to_string(),
-                source: "survey".to_string(),
-                text: "Prefers desert routes with low crowds".to_string(),
-                weight: 0.96,
-                tags: vec!["survey_trip_style".to_string()],
-                happened_at: Some(now),
-                expires_at: None,
-            },
-            now,
-        )
-        .expect("duplicate ingestion should update existing memory");
+        for preview_id in [serde_json::json!(foreign), serde_json::json!("made-up")] {
+            let response = app.clone().oneshot(save(preview_id)).await.unwrap();
+            assert_eq!(response.status(), StatusCode::NOT_FOUND);
+            assert_eq!(
+                response_json(response).await["error"],
+                "rewrite_preview_not_found"
+            );
+        }
 
-        assert_eq!(records.len(), 1);
-        assert_eq!(first.memory_id, second.memory_id);
-        assert_eq!(records[0].source, "survey");
-        assert!(records[0].weight > 0.85);
-        assert!(records[0].tags.iter().any(|tag| tag == "survey_trip_style"));
+        let response = app
+            .clone()
+            .oneshot(save(serde_json::json!(own)))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let note = &response_json(response).await["note"];
+        assert_eq!(note["content"], render_structured_note(&structured));
+        assert!(state.note_rewrite_previews.read().contains_key(&foreign));
+
+        let replay = app.oneshot(save(serde_json::json!(own))).await.unwrap();
+        assert_eq!(replay.status(), StatusCode::NOT_FOUND);
     }
 
-    #[test]
-    fn memory_retrieval_orders_by_relevance_and_recency() {
-        let now = chrono::Utc::now();
-        let records = vec![
-            MemoryRecord {
-                memory_id: "memory-1".to_string(),
-                user_id: "user-1".to_string(),
-                memory_type: "preference".to_string(),
-                stability: "permanent".to_string(),
-                source: "survey".to_string(),
-                text: "User prefers desert routes and silence".to_string(),
-                weight: 0.95,
-                recency_score: 0.1,
-                tags: vec!["desert".to_string()],
-                created_at: (now - Duration::days(7)).to_rfc3339(),
-                updated_at: (now - Duration::days(3)).to_rfc3339(),
-                expires_at: None,
-                fingerprint: "f1".to_string(),
-            },
-            MemoryRecord {
-                memory_id: "memory-2".to_string(),
-                user_id: "user-1".to_string(),
-                memory_type: "mood".to_string(),
-                stability: "transient".to_string(),
-                source: "chat".to_string(),
-                text: "User feels slightly tired this morning".to_string(),
-                weight: 0.60,
-                recency_score: 1.0,
-                tags: vec!["energy".to_string()],
-                created_at: (now - Duration::hours(5)).to_rfc3339(),
-                updated_at: (now - Duration::hours(3)).to_rfc3339(),
-                expires_at: Some((now + Duration::days(2)).to_rfc3339()),
-                fingerprint: "f2".to_string(),
-            },
-        ];
+    #[tokio::test]
+    async fn cleared_ephemeral_memories_are_not_persisted_to_the_trash() {
+        let pool = sqlx::sqlite::SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect("sqlite::memory:")
+            .await
+            .unwrap();
+        ensure_app_schema(&pool).await.unwrap();
+        let mut state = test_state().await;
+        state.db_pool = Some(pool.clone());
+        state.ephemeral_memory_types = vec!["mood".to_string()];
+        let user = test_user("ephemeral-trash-user", "google", "ceo@atlasmasa.com");
+        let session = signed_in_headers(&state, &user);
+        let memory = |memory_id: &str, memory_type: &str| MemoryRecord {
+            memory_id: memory_id.to_string(),
+            user_id: user.user_id.clone(),
+            memory_type: memory_type.to_string(),
+            stability: "permanent".to_string(),
+            source: "chat".to_string(),
+            text: format!("{memory_type} memory"),
+            weight: 0.7,
+            recency_score: 1.0,
+            tags: Vec::new(),
+            created_at: chrono::Utc::now().to_rfc3339(),
+            updated_at: chrono::Utc::now().to_rfc3339(),
+            expires_at: None,
+            fingerprint: memory_id.to_string(),
+            text_original_sealed: None,
+        };
+        state.user_memories.write().insert(
+            user.user_id.clone(),
+            vec![memory("calm", "mood"), memory("aisle", "preference")],
+        );
 
-        let ranked = retrieve_memory_context_from_records(&records, "desert route", 5, now);
-        assert_eq!(ranked.len(), 2);
-        assert_eq!(ranked[0].memory_id, "memory-1");
-        assert!(ranked[0].final_score > ranked[1].final_score);
+        let cleared = build_router(state.clone())
+            .oneshot(json_post(
+                "/v1/memory/clear",
+                serde_json::json!({}),
+                &session,
+            ))
+            .await
+            .unwrap();
+        assert_eq!(cleared.status(), StatusCode::OK);
+        assert_eq!(response_json(cleared).await["cleared"], 2);
+        assert_eq!(state.deleted_memories.read()[&user.user_id].len(), 2);
+
+        let persisted = load_persistent_state(Some(&pool)).await.unwrap();
+        let trashed = &persisted.deleted_memories[&user.user_id];
+        assert_eq!(trashed.len(), 1);
+        assert_eq!(trashed[0].memory.memory_id, "aisle");
     }
 
-    #[test]
-    fn memory_ingestion_respects_privacy_opt_out() {
-        let now = chrono::Utc::now();
-        let mut records = Vec::new();
-        let ingested = ingest_memory_records_if_opted_in(
-            &mut records,
-            "user-1",
-            false,
-            MemoryIngestEvent {
-                memory_type: "goal".to_string(),
-                stability: "permanent".to_string(),
-                source: "chat".to_string(),
-                text: "Build a strong weekly execution cadence".to_string(),
-                weight: 0.88,
-                tags: vec!["execution".to_string()],
-                happened_at: Some(now),
-                expires_at: None,
-            },
-            now,
+    #[tokio::test]
+    async fn cleared_memories_survive_a_restart_and_restore_under_the_cap() {
+        let pool = sqlx::sqlite::SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect("sqlite::memory:")
+            .await
+            .unwrap();
+        ensure_app_schema(&pool).await.unwrap();
+        let mut state = test_state().await;
+        state.db_pool = Some(pool.clone());
+        let user = test_user("trash-user", "google", "ceo@atlasmasa.com");
+        let session = signed_in_headers(&state, &user);
+        let memory = |index: usize| MemoryRecord {
+            memory_id: format!("memory-{index}"),
+            user_id: user.user_id.clone(),
+            memory_type: "preference".to_string(),
+            stability: "permanent".to_string(),
+            source: "chat".to_string(),
+            text: format!("Memory {index}"),
+            weight: 0.7,
+            recency_score: 1.0,
+            tags: Vec::new(),
+            created_at: chrono::Utc::now().to_rfc3339(),
+            updated_at: chrono::Utc::now().to_rfc3339(),
+            expires_at: None,
+            fingerprint: format!("f{index}"),
+            text_original_sealed: None,
+        };
+        state
+            .user_memories
+            .write()
+            .insert(user.user_id.clone(), (0..2).map(memory).collect());
+        let app = build_router(state.clone());
+        let trash_rows = || async {
+            sqlx::query("SELECT COUNT(*) AS count FROM deleted_memories")
+                .fetch_one(&pool)
+                .await
+                .unwrap()
+                .get::<i64, _>("count")
+        };
+
+        let cleared = app
+            .clone()
+            .oneshot(json_post(
+                "/v1/memory/clear",
+                serde_json::json!({}),
+                &session,
+            ))
+            .await
+            .unwrap();
+        assert_eq!(cleared.status(), StatusCode::OK);
+        assert_eq!(response_json(cleared).await["cleared"], 2);
+        assert_eq!(trash_rows().await, 2);
+        let persisted = load_persistent_state(Some(&pool)).await.unwrap();
+        assert_eq!(persisted.deleted_memories[&user.user_id].len(), 2);
+
+        // A full store takes nothing back and keeps the trash for later.
+        state.user_memories.write().insert(
+            user.user_id.clone(),
+            (10..10 + MAX_MEMORY_RECORDS_PER_USER).map(memory).collect(),
         );
-        assert!(ingested.is_none());
-        assert!(records.is_empty());
+        let full = app
+            .clone()
+            .oneshot(json_post(
+                "/v1/memory/restore",
+                serde_json::json!({}),
+                &session,
+            ))
+            .await
+            .unwrap();
+        let full = response_json(full).await;
+        assert_eq!(
+            (full["restored"].clone(), full["over_limit"].clone()),
+            (0.into(), 2.into())
+        );
+        assert_eq!(trash_rows().await, 2);
+
+        state
+            .user_memories
+            .write()
+            .get_mut(&user.user_id)
+            .unwrap()
+            .truncate(MAX_MEMORY_RECORDS_PER_USER - 1);
+        let partial = app
+            .clone()
+            .oneshot(json_post(
+                "/v1/memory/restore",
+                serde_json::json!({}),
+                &session,
+            ))
+            .await
+            .unwrap();
+        let partial = response_json(partial).await;
+        assert_eq!(
+            (partial["restored"].clone(), partial["over_limit"].clone()),
+            (1.into(), 1.into())
+        );
+        assert_eq!(
+            state.user_memories.read()[&user.user_id].len(),
+            MAX_MEMORY_RECORDS_PER_USER
+        );
+        assert_eq!(trash_rows().await, 1);
+
+        state
+            .user_memories
+            .write()
+            .get_mut(&user.user_id)
+            .unwrap()
+            .truncate(10);
+        let rest = app
+            .clone()
+            .oneshot(json_post(
+                "/v1/memory/restore",
+                serde_json::json!({}),
+                &session,
+            ))
+            .await
+            .unwrap();
+        assert_eq!(response_json(rest).await["restored"], 1);
+        assert_eq!(trash_rows().await, 0);
+        let empty = app
+            .oneshot(json_post(
+                "/v1/memory/restore",
+                serde_json::json!({}),
+                &session,
+            ))
+            .await
+            .unwrap();
+        assert_eq!(empty.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn truncated_replies_promise_history_only_to_users_who_keep_it() {
+        let state = test_state().await;
+        let app = build_router(state.clone());
+        for (opted_in, expected) in [(true, true), (false, false)] {
+            let mut user = test_user(
+                format!("reply-cap-{opted_in}").as_str(),
+                "google",
+                "ceo@atlasmasa.com",
+            );
+            user.memory_opt_in = opted_in;
+            let session = signed_in_headers(&state, &user);
+            let response = app
+                .clone()
+                .oneshot(json_post(
+                    "/v1/chat",
+                    serde_json::json!({
+                        "text": "plan a beach weekend",
+                        "session_id": "reply-cap",
+                        "max_reply_chars": 1
+                    }),
+                    &session,
+                ))
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            let payload = &response_json(response).await["json_payload"];
+            assert_eq!(payload["reply_truncated"], true);
+            assert_eq!(payload["full_reply_available"], expected);
+        }
     }
 
-    #[test]
-    fn scheduling_offsets_follow_cadence_and_horizon() {
-        let aggressive_daily = schedule_minutes_offset("aggressive", "daily", 0);
-        let steady_daily = schedule_minutes_offset("steady", "daily", 0);
-        let steady_mid = schedule_minutes_offset("steady", "mid_term", 0);
-        let steady_long = schedule_minutes_offset("steady", "long_term", 0);
-        assert!(aggressive_daily < steady_daily);
-        assert!(steady_mid > steady_daily);
-        assert!(steady_long > steady_mid);
-    }
+    #[tokio::test]
+    async fn clearing_memories_deletes_only_the_removed_rows() {
+        let pool = sqlx::sqlite::SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect("sqlite::memory:")
+            .await
+            .unwrap();
+        ensure_app_schema(&pool).await.unwrap();
+        let mut state = test_state().await;
+        state.db_pool = Some(pool.clone());
+        let memory = |memory_id: &str, stability: &str| MemoryRecord {
+            memory_id: memory_id.to_string(),
+            user_id: "user-1".to_string(),
+            memory_type: "preference".to_string(),
+            stability: stability.to_string(),
+            source: "chat".to_string(),
+            text: format!("Memory {memory_id}"),
+            weight: 0.7,
+            recency_score: 1.0,
+            tags: Vec::new(),
+            created_at: chrono::Utc::now().to_rfc3339(),
+            updated_at: chrono::Utc::now().to_rfc3339(),
+            expires_at: None,
+            fingerprint: memory_id.to_string(),
+            text_original_sealed: None,
+        };
+        state.user_memories.write().insert(
+            "user-1".to_string(),
+            vec![memory("keep", "transient"), memory("drop", "permanent")],
+        );
+        persist_memories_if_configured(&state, "user-1")
+            .await
+            .unwrap();
+        // Written by another instance; a whole-set rewrite from this process would lose it.
+        sqlx::query(
+            "INSERT INTO user_memories (memory_id, user_id, data_json) VALUES ('elsewhere', 'user-1', '{}')",
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
 
-    #[test]
-    fn prioritization_prefers_urgent_daily_execution() {
-        let ranked = prioritize_execution_tasks(vec![
-            ExecutionTaskCandidate {
-                task_id: "long-a".to_string(),
-                title: "Long mission planning".to_string(),
-                detail: "Prepare annual strategic narrative".to_string(),
-                source: "survey".to_string(),
-                horizon: "long_term".to_string(),
-                urgency: 0.45,
-                impact: 0.95,
-                confidence: 0.85,
-            },
-            ExecutionTaskCandidate {
-                task_id: "daily-a".to_string(),
-                title: "Next action now".to_string(),
-                detail: "Ship the current customer deliverable in this block".to_string(),
-                source: "checkin".to_string(),
-                horizon: "daily".to_string(),
-                urgency: 0.97,
-                impact: 0.82,
-                confidence: 0.94,
-            },
-        ]);
+        let filter = MemoryClearFilter {
+            scope: "permanent",
+            tags: &[],
+            match_all_tags: false,
+        };
+        let removed = clear_user_memories(&state, "user-1", &filter).await;
+        assert_eq!(removed.len(), 1);
 
-        assert!(!ranked.is_empty());
-        assert_eq!(ranked[0].task_id, "daily-a");
+        let mut remaining = sqlx::query("SELECT memory_id FROM user_memories")
+            .fetch_all(&pool)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|row| row.get::<String, _>("memory_id"))
+            .collect::<Vec<_>>();
+        remaining.sort();
+        assert_eq!(remaining, ["elsewhere", "keep"]);
     }
 
-    #[test]
-    fn survey_includes_gym_and_income_cadence_questions() {
-        let mut answers = std::collections::HashMap::new();
-        answers.insert("primary_goal".to_string(), "wealth".to_string());
-        answers.insert("daily_pressure".to_string(), "medium".to_string());
-        answers.insert("work_hours".to_string(), "6_10".to_string());
-        answers.insert("stress_trigger".to_string(), "overload".to_string());
-        answers.insert("travel_pattern".to_string(), "hybrid".to_string());
-        answers.insert("trip_style".to_string(), "mixed".to_string());
-        answers.insert("health_priority".to_string(), "focus".to_string());
+    #[tokio::test]
+    async fn note_upsert_body_limit_follows_the_note_character_limit() {
+        let mut state = test_state().await;
+        state.note_max_content_chars = 40_000;
+        let user = test_user("long-note-user", "google", "ceo@atlasmasa.com");
+        let session = signed_in_headers(&state, &user);
+        let app = build_router(state.clone());
+        let upsert = |chars: usize| {
+            json_post(
+                "/v1/notes/upsert",
+                serde_json::json!({ "title": "Long", "content": "ש".repeat(chars) }),
+                &session,
+            )
+        };
 
-        let gym_q = next_survey_question("en", &answers).expect("gym question should exist");
-        assert_eq!(gym_q.id, "gym_frequency");
-        answers.insert("gym_frequency".to_string(), "regularly".to_string());
+        assert!("ש".repeat(40_000).len() > 64 * 1024);
+        let response = app.clone().oneshot(upsert(40_000)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
 
-        let income_q =
-            next_survey_question("en", &answers).expect("income cadence question should exist");
-        assert_eq!(income_q.id, "income_cadence");
+        let response = app.oneshot(upsert(40_001)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(response_json(response).await["error"], "note_too_long");
     }
 
-    #[test]
-    fn survey_total_questions_accounts_for_new_baseline_questions() {
-        let answers = std::collections::HashMap::<String, String>::new();
-        assert_eq!(survey_total_questions(&answers), 13);
+    #[tokio::test]
+    async fn sessions_slide_only_on_cookie_authenticated_routes() {
+        let mut state = test_state().await;
+        state.session_refresh_threshold = Some(std::time::Duration::from_secs(7 * 24 * 60 * 60));
+        let user = test_user("sliding-user", "google", "ceo@atlasmasa.com");
+        let session = signed_in_headers(&state, &user);
+        let session_id = format!("session-{}", user.user_id);
+        let app = build_router(state.clone());
+        let near_expiry = chrono::Utc::now() + chrono::Duration::days(1);
+        let get = |uri: &str, headers: &[(&str, &str)]| {
+            let mut request = axum::http::Request::builder()
+                .method("GET")
+                .uri(uri)
+                .body(axum::body::Body::empty())
+                .unwrap();
+            request.headers_mut().extend(session.clone());
+            for (name, value) in headers {
+                request.headers_mut().insert(
+                    header::HeaderName::from_bytes(name.as_bytes()).unwrap(),
+                    HeaderValue::from_str(value).unwrap(),
+                );
+            }
+            request
+        };
+        let api_key = state.api_key.clone();
+        let cases = [
+            (
+                get("/v1/notes", &[("origin", "http://localhost:5500")]),
+                true,
+            ),
+            (
+                get("/v1/notes", &[("origin", "https://evil.example")]),
+                false,
+            ),
+            (get("/v1/notes", &[("x-api-key", api_key.as_str())]), false),
+            (
+                get("/v1/auth/me", &[("origin", "http://localhost:5500")]),
+                false,
+            ),
+            (
+                get("/health", &[("origin", "http://localhost:5500")]),
+                false,
+            ),
+        ];
+
+        for (request, slides) in cases {
+            let uri = request.uri().to_string();
+            state
+                .sessions
+                .write()
+                .get_mut(&session_id)
+                .unwrap()
+                .expires_at = near_expiry;
+            let response = app.clone().oneshot(request).await.unwrap();
+            let reissued = response
+                .headers()
+                .get_all(header::SET_COOKIE)
+                .iter()
+                .any(|value| value.to_str().unwrap().contains(session_id.as_str()));
+            let extended = state.sessions.read()[&session_id].expires_at > near_expiry;
+            assert_eq!((reissued, extended), (slides, slides), "{uri}");
+        }
     }
 
-    #[test]
-    fn stripe_webhook_signature_accepts_valid_recent_payload() {
-        let payload = r#"{"type":"checkout.session.completed"}"#;
-        let secret = "whsec_test_secret";
-        let now = chrono::Utc::now().timestamp();
-        let signature = build_test_stripe_signature(payload, secret, now)
-            .expect("signature generation should succeed");
-        assert!(verify_stripe_webhook_signature(
-            signature.as_str(),
-            payload,
-            secret,
-            DEFAULT_STRIPE_WEBHOOK_TOLERANCE_SECONDS,
-        ));
+    #[tokio::test]
+    async fn admin_endpoints_refuse_browser_sessions_without_a_service_key() {
+        let state = test_state().await;
+        let user = test_user("admin-probe", "google", "ceo@atlasmasa.com");
+        let session = signed_in_headers(&state, &user);
+        let app = build_router(state.clone());
+        let company_status = serde_json::to_value(state.company_status.read().clone()).unwrap();
+        let get = |uri: &str| {
+            let mut request = axum::http::Request::builder()
+                .method("GET")
+                .uri(uri)
+                .header("origin", "http://localhost:5500")
+                .body(axum::body::Body::empty())
+                .unwrap();
+            request.headers_mut().extend(session.clone());
+            request
+        };
+        let requests = [
+            json_post("/v1/admin/company_status", company_status, &session),
+            json_post(
+                "/v1/admin/memory/prune_expired",
+                serde_json::json!({}),
+                &session,
+            ),
+            get("/v1/admin/execution/candidates?user_id=admin-probe"),
+            get("/v1/admin/ai_healthcheck"),
+            get("/v1/feedback/employee/dana"),
+            json_post(
+                "/v1/feedback/employee/dana/resolve_all",
+                serde_json::json!({}),
+                &session,
+            ),
+            json_post(
+                "/v1/feedback/feedback-1/status",
+                serde_json::json!({ "status": "resolved" }),
+                &session,
+            ),
+        ];
+
+        for request in requests {
+            let uri = request.uri().to_string();
+            let response = app.clone().oneshot(request).await.unwrap();
+            assert_eq!(response.status(), StatusCode::FORBIDDEN, "{uri}");
+            assert_eq!(
+                response_json(response).await["error"],
+                "service_key_required",
+                "{uri}"
+            );
+        }
     }
 
-    #[test]
-    fn stripe_webhook_signature_rejects_replay_outside_tolerance() {
-        let payload = r#"{"type":"checkout.session.completed"}"#;
-        let secret = "whsec_test_secret";
-        let old = chrono::Utc::now().timestamp() - 900;
-        let signature = build_test_stripe_signature(payload, secret, old)
-            .expect("signature generation should succeed");
-        assert!(!verify_stripe_webhook_signature(
-            signature.as_str(),
-            payload,
-            secret,
-            DEFAULT_STRIPE_WEBHOOK_TOLERANCE_SECONDS,
-        ));
+    #[tokio::test]
+    async fn issuing_a_session_counts_the_login_on_the_stored_user() {
+        let state = test_state().await;
+        let mut stored = test_user("counted", "google", "dana@example.com");
+        stored.name = "Dana Renamed".to_string();
+        stored.login_count = 5;
+        state
+            .users
+            .write()
+            .insert(stored.user_id.clone(), stored.clone());
+        // Read before the rename and before earlier sign-ins were counted.
+        let mut stale = test_user("counted", "google", "dana@example.com");
+
+        issue_session_for_user(&state, &mut stale, None, &HeaderMap::new())
+            .await
+            .unwrap();
+        issue_session_for_user(&state, &mut stale, None, &HeaderMap::new())
+            .await
+            .unwrap();
+
+        let current = state.users.read()["counted"].clone();
+        assert_eq!(current.login_count, 7);
+        assert_eq!(current.name, "Dana Renamed");
+        assert!(current.last_login_at.is_some());
+        assert_eq!(stale.login_count, 7);
+        assert_eq!(stale.name, "Dana Renamed");
     }
 
     #[test]
-    fn request_origin_parses_origin_header_first() {
+    fn request_and_trace_ids_share_the_propagated_header() {
         let mut headers = HeaderMap::new();
-        headers.insert(
-            header::ORIGIN,
-            HeaderValue::from_static("https://atlasmasa.com"),
-        );
+        headers.insert("x-request-id", HeaderValue::from_static(" req-42 "));
+        assert_eq!(request_id_from_headers(&headers), "req-42");
+        assert_eq!(trace_id_from_headers(&headers), "req-42");
+
+        headers.insert("x-request-id", HeaderValue::from_static("  "));
+        assert_eq!(request_id_from_headers(&headers), "unknown");
+        let generated = trace_id_from_headers(&headers);
+        assert!(uuid::Uuid::parse_str(generated.as_str()).is_ok());
+    }
+
+    #[tokio::test]
+    async fn digest_narrative_is_opt_in_and_reused_for_the_day() {
+        let state = test_state().await;
+        let user = test_user("digest-user", "google", "ceo@atlasmasa.com");
+        let session = signed_in_headers(&state, &user);
+        let app = build_router(state.clone());
+        let digest = |query: &str| {
+            let mut request = axum::http::Request::builder()
+                .method("GET")
+                .uri(format!("/v1/execution/digest?locale=en{query}"))
+                .header(header::ORIGIN, "http://localhost:5500")
+                .body(axum::body::Body::empty())
+                .unwrap();
+            request.headers_mut().extend(session.clone());
+            let app = app.clone();
+            async move { response_json(app.oneshot(request).await.unwrap()).await }
+        };
+        let cache = |day: chrono::NaiveDate| {
+            state.digest_narratives.write().insert(
+                user.user_id.clone(),
+                DigestNarrativeRecord {
+                    day,
+                    locale: "en".to_string(),
+                    text: "A steady week.".to_string(),
+                },
+            );
+        };
+
+        let today = chrono::Utc::now().date_naive();
+        cache(today);
+        assert!(digest("").await["narrative"].is_null());
         assert_eq!(
-            request_origin_from_headers(&headers).as_deref(),
-            Some("https://atlasmasa.com")
+            digest("&narrative=true").await["narrative"],
+            "A steady week."
         );
+
+        cache(today - chrono::Duration::days(1));
+        assert!(digest("&narrative=true").await["narrative"].is_null());
     }
 
-    #[test]
-    fn request_origin_does_not_trust_referer_without_origin_header() {
-        let mut headers = HeaderMap::new();
-        headers.insert(
-            header::REFERER,
-            HeaderValue::from_static("https://www.atlasmasa.com/concierge-local.html?launch=chat"),
+    #[tokio::test]
+    async fn memory_import_follows_the_note_character_limit() {
+        let mut state = test_state().await;
+        state.note_max_content_chars = 10_000;
+        let user = test_user("long-import-user", "google", "ceo@atlasmasa.com");
+        let session = signed_in_headers(&state, &user);
+        let app = build_router(state.clone());
+        let import = |chars: usize| {
+            json_post(
+                "/v1/memory/import",
+                serde_json::json!({ "items": [{ "title": "Long", "content": "x".repeat(chars) }] }),
+                &session,
+            )
+        };
+
+        let response = app.clone().oneshot(import(9_000)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            state.user_notes.read()[&user.user_id][0]
+                .content
+                .chars()
+                .count(),
+            9_000
         );
-        assert_eq!(request_origin_from_headers(&headers), None);
+
+        let response = app.oneshot(import(10_001)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        let json = response_json(response).await;
+        assert_eq!(json["error"], "note_too_long");
+        assert_eq!(json["details"]["field"], "items[0].content");
     }
 
-    #[test]
-    fn cloud_requirements_classify_paths_correctly() {
-        assert_eq!(cloud_requirements_for_endpoint("/v1/chat"), (false, true));
-        assert_eq!(
-            cloud_requirements_for_endpoint("/v1/plan_trip"),
-            (false, true)
+    #[tokio::test]
+    async fn memory_import_only_remembers_notes_that_fit_under_the_cap() {
+        let state = test_state().await;
+        let user = test_user("full-notes-user", "google", "ceo@atlasmasa.com");
+        let session = signed_in_headers(&state, &user);
+        let now = chrono::Utc::now();
+        let existing = (0..MAX_NOTES_PER_USER)
+            .map(|index| UserNoteRecord {
+                note_id: format!("note-{index}"),
+                user_id: user.user_id.clone(),
+                title: format!("Note {index}"),
+                content: "kept".to_string(),
+                tags: Vec::new(),
+                updated_at: now.to_rfc3339(),
+                structured: None,
+            })
+            .collect::<Vec<_>>();
+        state
+            .user_notes
+            .write()
+            .insert(user.user_id.clone(), existing);
+        let app = build_router(state.clone());
+
+        let response = app
+            .oneshot(json_post(
+                "/v1/memory/import",
+                serde_json::json!({ "items": [
+                    { "title": "Fresh", "content": "new plan", "happened_at": (now + chrono::Duration::minutes(1)).to_rfc3339() },
+                    { "title": "Stale", "content": "old plan", "happened_at": "2020-01-01T00:00:00Z" }
+                ] }),
+                &session,
+            ))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let json = response_json(response).await;
+        assert_eq!(json["imported"], 1);
+        assert_eq!(json["over_limit"], 1);
+        assert_eq!(json["total_notes"], MAX_NOTES_PER_USER);
+        let memories = state.user_memories.read()[&user.user_id].clone();
+        assert_eq!(memories.len(), 1);
+        assert!(memories[0].text.starts_with("Fresh"));
+    }
+
+    #[tokio::test]
+    async fn memory_import_accepts_bodies_up_to_the_bulk_limit() {
+        let state = test_state().await;
+        let user = test_user("bulk-import-user", "google", "ceo@atlasmasa.com");
+        let session = signed_in_headers(&state, &user);
+        let app = build_router(state.clone());
+        let items = (0..200)
+            .map(|index| {
+                serde_json::json!({ "title": format!("Entry {index}"), "content": "x".repeat(1_400) })
+            })
+            .collect::<Vec<_>>();
+        let body = serde_json::json!({ "items": items });
+        assert!(body.to_string().len() > 256 * 1024);
+
+        let response = app
+            .oneshot(json_post("/v1/memory/import", body, &session))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response_json(response).await["imported"], 200);
+    }
+
+    #[tokio::test]
+    async fn background_tasks_start_only_with_a_database() {
+        let mut state = test_state().await;
+        assert!(spawn_background_tasks(&state).is_none());
+
+        state.db_pool = Some(
+            sqlx::sqlite::SqlitePoolOptions::new()
+                .max_connections(1)
+                .connect("sqlite::memory:")
+                .await
+                .unwrap(),
         );
+        let handle = spawn_background_tasks(&state).expect("prune task should start");
+        handle.abort();
+        assert!(handle.await.unwrap_err().is_cancelled());
+    }
+
+    #[tokio::test]
+    async fn chat_saves_turns_only_for_the_signed_in_user() {
+        let state = test_state().await;
+        let victim = test_user("history-victim", "google", "ceo@atlasmasa.com");
+        remember_user(&state, victim.clone());
+        let app = build_router(state.clone());
+        let mut service = HeaderMap::new();
+        service.insert("x-api-key", HeaderValue::from_str(&state.api_key).unwrap());
+
+        let response = app
+            .clone()
+            .oneshot(json_post(
+                "/v1/chat",
+                serde_json::json!({
+                    "text": "plan a beach weekend",
+                    "session_id": "trip",
+                    "user_id": victim.user_id
+                }),
+                &service,
+            ))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(!state.chat_turns.read().contains_key(&victim.user_id));
+
+        let response = app
+            .oneshot(json_post(
+                "/v1/chat",
+                serde_json::json!({ "text": "plan a beach weekend", "session_id": "trip" }),
+                &signed_in_headers(&state, &victim),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(state.chat_turns.read().contains_key(&victim.user_id));
+    }
+
+    #[tokio::test]
+    async fn chat_replays_prior_turns_only_for_the_signed_in_owner() {
+        let pool = sqlx::sqlite::SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect("sqlite::memory:")
+            .await
+            .unwrap();
+        ensure_app_schema(&pool).await.unwrap();
+        let mut state = test_state().await;
+        state.db_pool = Some(pool.clone());
+        let user = test_user("history-owner", "google", "ceo@atlasmasa.com");
+        let session = signed_in_headers(&state, &user);
+        for session_id in ["trip", "other"] {
+            record_chat_turn_for_user(
+                &state,
+                &user.user_id,
+                session_id,
+                "plan a beach weekend",
+                "Here is a beach plan.",
+            )
+            .await
+            .unwrap();
+        }
+        let app = build_router(state.clone());
+        let context_turns = |headers: HeaderMap| {
+            let app = app.clone();
+            async move {
+                let response = app
+                    .oneshot(json_post(
+                        "/v1/chat",
+                        serde_json::json!({
+                            "text": "and what about Eilat?",
+                            "session_id": "trip",
+                            "user_id": "history-owner"
+                        }),
+                        &headers,
+                    ))
+                    .await
+                    .unwrap();
+                assert_eq!(response.status(), StatusCode::OK);
+                response_json(response).await["json_payload"]["context_turns"].clone()
+            }
+        };
+        let mut service = HeaderMap::new();
+        service.insert("x-api-key", HeaderValue::from_str(&state.api_key).unwrap());
+        assert_eq!(context_turns(service).await, 0);
+        let stored_trip_turns = || {
+            state.chat_turns.read()[&user.user_id]
+                .iter()
+                .filter(|turn| turn.session_id == "trip")
+                .count() as i64
+        };
+        let replayable = stored_trip_turns().min(MAX_CHAT_CONTEXT_TURNS as i64);
+        assert!(replayable > 0);
+        assert_eq!(context_turns(session.clone()).await, replayable);
+
+        // Rows are written per session, so the untouched session keeps its single row.
+        let rows = |session_id: &'static str| {
+            let pool = pool.clone();
+            async move {
+                sqlx::query("SELECT COUNT(*) AS count FROM chat_turns WHERE session_id = ?1")
+                    .bind(session_id)
+                    .fetch_one(&pool)
+                    .await
+                    .unwrap()
+                    .get::<i64, _>("count")
+            }
+        };
         assert_eq!(
-            cloud_requirements_for_endpoint("/v1/notes/upsert"),
-            (true, false)
+            (rows("trip").await, rows("other").await),
+            (stored_trip_turns(), 1)
         );
-        assert_eq!(
-            cloud_requirements_for_endpoint("/v1/feed/proactive"),
-            (true, true)
+
+        let opted_out = app
+            .oneshot(json_post(
+                "/v1/profile/upsert",
+                serde_json::json!({ "memory_opt_in": false }),
+                &session,
+            ))
+            .await
+            .unwrap();
+        assert_eq!(opted_out.status(), StatusCode::OK);
+        assert!(!state.chat_turns.read().contains_key(&user.user_id));
+        assert_eq!((rows("trip").await, rows("other").await), (0, 0));
+    }
+
+    #[tokio::test]
+    async fn chat_echoes_the_message_as_sent_not_the_contextualized_text() {
+        let state = test_state().await;
+        let user = test_user("echo-user", "google", "ceo@atlasmasa.com");
+        let session = signed_in_headers(&state, &user);
+        record_chat_turn_for_user(
+            &state,
+            &user.user_id,
+            "echo",
+            "plan a beach trip for the weekend",
+            "Option 1: Haifa coast. Option 2: Dor beach.",
+        )
+        .await
+        .unwrap();
+
+        let response = build_router(state.clone())
+            .oneshot(json_post(
+                "/v1/chat",
+                serde_json::json!({ "text": "and the second option?", "session_id": "echo" }),
+                &session,
+            ))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let payload = &response_json(response).await["json_payload"];
+        assert_eq!(payload["context_turns"], 1);
+        assert_eq!(payload["classifier"]["rule_intent"], "trip_planning");
+        assert_eq!(payload["input_echo"], "and the second option?");
+    }
+
+    #[tokio::test]
+    async fn chat_validate_uses_only_the_session_user() {
+        let state = test_state().await;
+        let victim = test_user("validate-victim", "google", "victim@example.com");
+        state
+            .users
+            .write()
+            .insert(victim.user_id.clone(), victim.clone());
+        let mut victim_prefs = default_studio_preferences(&victim.user_id);
+        victim_prefs.reminders_app = "apple_reminders".to_string();
+        state
+            .studio_preferences
+            .write()
+            .insert(victim.user_id.clone(), victim_prefs);
+        let owner = test_user("validate-owner", "google", "owner@example.com");
+        let session = signed_in_headers(&state, &owner);
+        let mut service = HeaderMap::new();
+        service.insert("x-api-key", HeaderValue::from_str(&state.api_key).unwrap());
+        let app = build_router(state.clone());
+        let validate = |headers: HeaderMap, user_id: &'static str| {
+            let app = app.clone();
+            async move {
+                let response = app
+                    .oneshot(json_post(
+                        "/v1/chat/validate",
+                        serde_json::json!({ "text": "plan a beach weekend", "user_id": user_id }),
+                        &headers,
+                    ))
+                    .await
+                    .unwrap();
+                assert_eq!(response.status(), StatusCode::OK);
+                response_json(response).await
+            }
+        };
+
+        for user_id in ["validate-victim", "no-such-user"] {
+            let parsed = validate(service.clone(), user_id).await;
+            assert_eq!(parsed["interpreted"]["user_id"], serde_json::Value::Null);
+            assert_eq!(
+                parsed["interpreted"]["studio_preferences"]["reminders_app"],
+                "google_calendar"
+            );
+            assert_eq!(parsed["warnings"][0], "user_not_found_guest_formatting");
+        }
+        let own = validate(session.clone(), "validate-owner").await;
+        assert_eq!(own["interpreted"]["user_id"], "validate-owner");
+        let other = validate(session, "validate-victim").await;
+        assert_eq!(other["interpreted"]["user_id"], serde_json::Value::Null);
+    }
+
+    #[tokio::test]
+    async fn accounts_created_on_another_instance_are_loaded_from_the_shared_store() {
+        let pool = sqlx::sqlite::SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect("sqlite::memory:")
+            .await
+            .unwrap();
+        ensure_app_schema(&pool).await.unwrap();
+        let instance = || async {
+            let mut state = test_state().await;
+            state.db_pool = Some(pool.clone());
+            state.shared_auth = Some(SharedAuthStore::Sqlite(pool.clone()));
+            state
+        };
+        let (first, second) = (instance().await, instance().await);
+        let identity = ProviderIdentity {
+            provider: "google",
+            subject: "google-subject".to_string(),
+            email: "shared@example.com".to_string(),
+            email_verified: true,
+        };
+        let now = chrono::Utc::now().to_rfc3339();
+
+        let created = find_or_create_user_by_email(
+            &first,
+            &identity,
+            "Shared".to_string(),
+            "en".to_string(),
+            now.clone(),
+        )
+        .await;
+        first.passkeys_by_user.write().insert(
+            created.user_id.clone(),
+            vec![test_passkey(&created.user_id)],
         );
+        persist_passkeys_if_configured(&first, &created.user_id)
+            .await
+            .unwrap();
+
+        // A passkey login started on the first instance finishes on the second.
+        let loaded = load_shared_account(&second, &created.user_id).await;
         assert_eq!(
-            cloud_requirements_for_endpoint("/v1/auth/me"),
-            (false, false)
+            loaded.map(|user| user.user_id),
+            Some(created.user_id.clone())
         );
+        assert_eq!(second.passkeys_by_user.read()[&created.user_id].len(), 1);
+
+        // An OAuth sign-in on a third instance adopts the account instead of duplicating it.
+        let third = instance().await;
+        let signed_in = find_or_create_user_by_email(
+            &third,
+            &identity,
+            "Shared".to_string(),
+            "en".to_string(),
+            now,
+        )
+        .await;
+        assert_eq!(signed_in.user_id, created.user_id);
+        assert_eq!(third.passkeys_by_user.read()[&created.user_id].len(), 1);
+        let rows = sqlx::query("SELECT COUNT(*) AS count FROM auth_users")
+            .fetch_one(&pool)
+            .await
+            .unwrap()
+            .get::<i64, _>("count");
+        assert_eq!(rows, 1);
     }
 
-    #[test]
-    fn public_endpoints_include_session_probe_and_logout() {
-        assert!(is_public_endpoint("/health"));
-        assert!(is_public_endpoint("/v1/auth/me"));
-        assert!(is_public_endpoint("/v1/auth/logout"));
-        assert!(!is_public_endpoint("/v1/profile/upsert"));
+    #[tokio::test]
+    async fn recovery_codes_redeem_on_an_instance_that_never_saw_the_account() {
+        let pool = sqlx::sqlite::SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect("sqlite::memory:")
+            .await
+            .unwrap();
+        ensure_app_schema(&pool).await.unwrap();
+        let mut first = test_state().await;
+        first.db_pool = Some(pool.clone());
+        let mut second = test_state().await;
+        second.db_pool = Some(pool.clone());
+        second.shared_auth = Some(SharedAuthStore::Sqlite(pool.clone()));
+        // Both instances read the key from the same `app_secrets` row in production.
+        second.recovery_code_key = first.recovery_code_key.clone();
+        let identity = ProviderIdentity {
+            provider: "google",
+            subject: "google-recovery".to_string(),
+            email: "recover@example.com".to_string(),
+            email_verified: true,
+        };
+        let user = find_or_create_user_by_email(
+            &first,
+            &identity,
+            "Recover".to_string(),
+            "en".to_string(),
+            chrono::Utc::now().to_rfc3339(),
+        )
+        .await;
+        let generated = build_router(first.clone())
+            .oneshot(json_post(
+                "/v1/auth/recovery_codes/generate",
+                serde_json::json!({ "count": 1 }),
+                &signed_in_headers(&first, &user),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(generated.status(), StatusCode::OK);
+        let code = response_json(generated).await["codes"][0]
+            .as_str()
+            .unwrap()
+            .to_string();
+        assert!(second.users.read().is_empty());
+
+        let redeemed = build_router(second.clone())
+            .oneshot(json_post(
+                "/v1/auth/recovery_codes/redeem",
+                serde_json::json!({ "email": "Recover@Example.com", "code": code }),
+                &HeaderMap::new(),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(redeemed.status(), StatusCode::OK);
+        assert!(second.users.read().contains_key(&user.user_id));
     }
 }
@@ -1,7 +1,8 @@
 use std::env;
+use std::net::SocketAddr;
 
 use anyhow::Result;
-use atlas_api::build_app;
+use atlas_api::{build_router, build_state, spawn_background_tasks};
 use atlas_observability::init_tracing;
 
 #[tokio::main]
@@ -22,11 +23,17 @@ async fn main() -> Result<()> {
         })
         .unwrap_or_else(|| "0.0.0.0:8080".to_string());
 
-    let app = build_app(&kb_root).await?;
+    let state = build_state(&kb_root).await?;
+    spawn_background_tasks(&state);
+    let app = build_router(state);
 
     let listener = tokio::net::TcpListener::bind(&bind).await?;
     tracing::info!(bind = %bind, kb_root = %kb_root, "atlas concierge api started");
 
-    axum::serve(listener, app).await?;
+    axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .await?;
     Ok(())
 }
@@ -0,0 +1,218 @@
+use crate::text_match::contains_word_prefix;
+
+pub struct KeywordRule {
+    pub label: &'static str,
+    pub stability: &'static str,
+    pub weight: f32,
+    pub en: &'static [&'static str],
+    pub he: &'static [&'static str],
+}
+
+pub struct HorizonRule {
+    pub horizon: &'static str,
+    pub en: &'static [&'static str],
+    pub he: &'static [&'static str],
+}
+
+pub struct SurveyRule {
+    pub label: &'static str,
+    pub stability: &'static str,
+    pub weight: f32,
+    pub needles: &'static [&'static str],
+    pub match_answer: bool,
+}
+
+pub static CHAT_MEMORY_RULES: &[KeywordRule] = &[
+    KeywordRule {
+        label: "mood",
+        stability: "transient",
+        weight: 0.75,
+        en: &["stressed", "anxious", "overwhelmed", "tired"],
+        he: &["רגוע", "לחוץ", "עייף"],
+    },
+    KeywordRule {
+        label: "goal",
+        stability: "permanent",
+        weight: 0.82,
+        en: &["plan", "goal", "mission", "target"],
+        he: &["יעד", "מטרה", "תוכנית"],
+    },
+    KeywordRule {
+        label: "preference",
+        stability: "permanent",
+        weight: 0.8,
+        en: &["prefer", "favorite", "like", "dislike"],
+        he: &["מעדיף", "אוהב", "לא אוהב"],
+    },
+];
+
+pub static HORIZON_RULES: &[HorizonRule] = &[
+    HorizonRule {
+        horizon: "daily",
+        en: &["today", "tonight", "now", "urgent", "daily"],
+        he: &["היום", "עכשיו", "יומי", "דחוף"],
+    },
+    HorizonRule {
+        horizon: "mid_term",
+        en: &["month", "quarter", "roadmap", "milestone"],
+        he: &["חודש", "רבעון", "יעד ביניים"],
+    },
+    HorizonRule {
+        horizon: "long_term",
+        en: &["year", "decade", "legacy", "mission"],
+        he: &["חזון", "שנתי", "ארוך"],
+    },
+];
+
+pub static SURVEY_MEMORY_RULES: &[SurveyRule] = &[
+    SurveyRule {
+        label: "preference",
+        stability: "permanent",
+        weight: 0.88,
+        needles: &[
+            "trip_style",
+            "risk_preference",
+            "voice_preference",
+            "language",
+            "gym_frequency",
+            "income_cadence",
+        ],
+        match_answer: false,
+    },
+    SurveyRule {
+        label: "goal",
+        stability: "permanent",
+        weight: 0.9,
+        needles: &["goal", "mission", "wealth", "donation", "career"],
+        match_answer: true,
+    },
+    SurveyRule {
+        label: "mood",
+        stability: "transient",
+        weight: 0.8,
+        needles: &["stress", "fatigue", "mood", "energy", "burnout"],
+        match_answer: true,
+    },
+];
+
+pub fn classify_chat_memory(text: &str) -> (String, String, f32) {
+    let lower = text.trim().to_lowercase();
+    if lower.is_empty() {
+        return ("insight".to_string(), "transient".to_string(), 0.5);
+    }
+    CHAT_MEMORY_RULES
+        .iter()
+        .find(|rule| matches_locale_keywords(lower.as_str(), rule.en, rule.he))
+        .map(|rule| {
+            (
+                rule.label.to_string(),
+                rule.stability.to_string(),
+                rule.weight,
+            )
+        })
+        .unwrap_or_else(|| ("insight".to_string(), "transient".to_string(), 0.65))
+}
+
+pub fn classify_survey_memory(question_id: &str, answer: &str) -> (String, String, f32) {
+    let question = question_id.trim().to_lowercase();
+    let answer = answer.trim().to_lowercase();
+    SURVEY_MEMORY_RULES
+        .iter()
+        .find(|rule| {
+            rule.needles.iter().any(|needle| {
+                question.contains(needle) || (rule.match_answer && answer.contains(needle))
+            })
+        })
+        .map(|rule| {
+            (
+                rule.label.to_string(),
+                rule.stability.to_string(),
+                rule.weight,
+            )
+        })
+        .unwrap_or_else(|| ("insight".to_string(), "transient".to_string(), 0.72))
+}
+
+pub fn classify_horizon_from_text(text: &str) -> String {
+    let lower = text.trim().to_lowercase();
+    HORIZON_RULES
+        .iter()
+        .find(|rule| matches_locale_keywords(lower.as_str(), rule.en, rule.he))
+        .map(|rule| rule.horizon.to_string())
+        .unwrap_or_else(|| "daily".to_string())
+}
+
+fn matches_locale_keywords(lower: &str, en: &[&str], he: &[&str]) -> bool {
+    en.iter().any(|needle| contains_word_prefix(lower, needle))
+        || he.iter().any(|needle| lower.contains(needle))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{classify_chat_memory, classify_horizon_from_text, classify_survey_memory};
+
+    fn memory_type(text: &str) -> String {
+        classify_chat_memory(text).0
+    }
+
+    #[test]
+    fn chat_mood_triggers_in_english_and_hebrew() {
+        assert_eq!(memory_type("I feel overwhelmed this week"), "mood");
+        assert_eq!(memory_type("אני עייף מאוד היום"), "mood");
+        assert_eq!(classify_chat_memory("so tired").1, "transient");
+    }
+
+    #[test]
+    fn chat_goal_triggers_in_english_and_hebrew() {
+        assert_eq!(memory_type("My goal is to ship the beta"), "goal");
+        assert_eq!(memory_type("המטרה שלי היא לחסוך"), "goal");
+        assert_eq!(classify_chat_memory("planning the quarter").1, "permanent");
+    }
+
+    #[test]
+    fn chat_preference_triggers_in_english_and_hebrew() {
+        assert_eq!(memory_type("I prefer quiet campsites"), "preference");
+        assert_eq!(memory_type("אני מעדיף חוף שקט"), "preference");
+    }
+
+    #[test]
+    fn chat_english_keywords_do_not_fire_inside_other_words() {
+        assert_eq!(memory_type("The airplane landed early"), "insight");
+        assert_eq!(
+            memory_type("Unlike last time, the road was open"),
+            "insight"
+        );
+        assert_eq!(memory_type(""), "insight");
+    }
+
+    #[test]
+    fn horizon_follows_english_and_hebrew_triggers() {
+        assert_eq!(classify_horizon_from_text("finish it today"), "daily");
+        assert_eq!(classify_horizon_from_text("לסיים עכשיו"), "daily");
+        assert_eq!(classify_horizon_from_text("quarter roadmap"), "mid_term");
+        assert_eq!(classify_horizon_from_text("יעד לרבעון הבא"), "mid_term");
+        assert_eq!(classify_horizon_from_text("a ten year legacy"), "long_term");
+        assert_eq!(classify_horizon_from_text("החזון שלי"), "long_term");
+        assert_eq!(
+            classify_horizon_from_text("I know the quarter plan"),
+            "mid_term"
+        );
+    }
+
+    #[test]
+    fn survey_classification_uses_question_ids_and_answers() {
+        assert_eq!(
+            classify_survey_memory("trip_style", "beach").0,
+            "preference"
+        );
+        assert_eq!(classify_survey_memory("primary_goal", "health").0, "goal");
+        assert_eq!(
+            classify_survey_memory("daily_pressure", "burnout").0,
+            "mood"
+        );
+        assert_eq!(
+            classify_survey_memory("travel_pattern", "hybrid").0,
+            "insight"
+        );
+    }
+}
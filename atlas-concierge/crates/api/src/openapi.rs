@@ -0,0 +1,729 @@
+//! Hand-maintained OpenAPI 3.0 description of the core v1 routes, served at `/openapi.json`.
+//! Schemas mirror the serde request/response structs in `lib.rs`; when a field is added to one
+//! of those structs, add it here too.
+
+use serde_json::{json, Value};
+
+fn schema_ref(name: &str) -> Value {
+    json!({ "$ref": format!("#/components/schemas/{name}") })
+}
+
+fn json_content(schema: Value) -> Value {
+    json!({ "application/json": { "schema": schema } })
+}
+
+fn error_response(description: &str) -> Value {
+    json!({ "description": description, "content": json_content(schema_ref("Error")) })
+}
+
+fn operation(summary: &str, tag: &str, request: Option<&str>, ok: Value) -> Value {
+    let mut op = json!({
+        "summary": summary,
+        "tags": [tag],
+        "responses": {
+            "200": { "description": "OK", "content": json_content(ok) },
+            "400": error_response("Invalid request"),
+            "401": error_response("Missing API key or session"),
+            "429": error_response("Rate limited")
+        }
+    });
+    if let Some(name) = request {
+        op["requestBody"] = json!({ "required": true, "content": json_content(schema_ref(name)) });
+    }
+    op
+}
+
+fn query_param(name: &str, required: bool, schema: Value, description: &str) -> Value {
+    json!({
+        "name": name,
+        "in": "query",
+        "required": required,
+        "schema": schema,
+        "description": description
+    })
+}
+
+fn path_param(name: &str, description: &str) -> Value {
+    json!({
+        "name": name,
+        "in": "path",
+        "required": true,
+        "schema": string(),
+        "description": description
+    })
+}
+
+fn object(required: &[&str], properties: Value) -> Value {
+    json!({ "type": "object", "required": required, "properties": properties })
+}
+
+fn string() -> Value {
+    json!({ "type": "string" })
+}
+
+fn nullable_string() -> Value {
+    json!({ "type": "string", "nullable": true })
+}
+
+fn strings() -> Value {
+    json!({ "type": "array", "items": { "type": "string" } })
+}
+
+fn number() -> Value {
+    json!({ "type": "number", "format": "float" })
+}
+
+fn boolean() -> Value {
+    json!({ "type": "boolean" })
+}
+
+fn paths() -> Value {
+    let user_id_param = query_param(
+        "user_id",
+        false,
+        string(),
+        "Only honoured with a service API key; signed-in sessions always use their own user",
+    );
+
+    let mut chat_history = operation(
+        "Stored turns of one chat session",
+        "chat",
+        None,
+        object(
+            &["session_id", "turns"],
+            json!({
+                "session_id": string(),
+                "turns": { "type": "array", "items": schema_ref("ChatTurn") }
+            }),
+        ),
+    );
+    chat_history["parameters"] = json!([
+        query_param("session_id", true, string(), "Chat session to read"),
+        user_id_param
+    ]);
+
+    let mut notes_list = operation(
+        "List the caller's notes, newest first",
+        "notes",
+        None,
+        object(
+            &["notes"],
+            json!({ "notes": { "type": "array", "items": schema_ref("UserNote") } }),
+        ),
+    );
+    notes_list["parameters"] = json!([user_id_param]);
+
+    let mut memory_records = operation(
+        "Ranked memories for the caller",
+        "memory",
+        None,
+        object(
+            &["memory_opt_in", "count", "items"],
+            json!({
+                "memory_opt_in": boolean(),
+                "count": { "type": "integer" },
+                "items": { "type": "array", "items": schema_ref("MemoryRetrievedItem") }
+            }),
+        ),
+    );
+    memory_records["parameters"] = json!([
+        user_id_param,
+        query_param("q", false, string(), "Text to rank memories against"),
+        query_param(
+            "limit",
+            false,
+            json!({ "type": "integer", "minimum": 1 }),
+            "Maximum number of items"
+        ),
+        query_param(
+            "sources",
+            false,
+            string(),
+            "Comma-separated memory sources to include"
+        )
+    ]);
+
+    let mut note_delete_by_id = operation(
+        "Delete one note and its version history",
+        "notes",
+        None,
+        object(
+            &["ok", "deleted"],
+            json!({ "ok": boolean(), "deleted": boolean() }),
+        ),
+    );
+    note_delete_by_id["parameters"] =
+        json!([path_param("note_id", "Note to delete"), user_id_param]);
+
+    let mut note_versions = operation(
+        "List a note's earlier versions, newest first",
+        "notes",
+        None,
+        object(
+            &["note_id", "count", "versions"],
+            json!({
+                "note_id": string(),
+                "count": { "type": "integer" },
+                "versions": { "type": "array", "items": schema_ref("NoteVersion") }
+            }),
+        ),
+    );
+    note_versions["parameters"] = json!([path_param("note_id", "Note to read"), user_id_param]);
+
+    let mut note_version_restore = operation(
+        "Restore an earlier version of a note, keeping the current text as a new version",
+        "notes",
+        None,
+        object(
+            &["ok", "restored_version_id", "note"],
+            json!({
+                "ok": boolean(),
+                "restored_version_id": string(),
+                "note": schema_ref("UserNote")
+            }),
+        ),
+    );
+    note_version_restore["parameters"] = json!([
+        path_param("note_id", "Note to restore"),
+        path_param("version_id", "Version to bring back")
+    ]);
+    note_version_restore["requestBody"] = json!({
+        "required": false,
+        "content": json_content(schema_ref("NoteVersionRestoreRequest"))
+    });
+
+    let mut memory_delete_by_id = operation(
+        "Delete one memory",
+        "memory",
+        None,
+        object(
+            &["ok", "deleted"],
+            json!({ "ok": boolean(), "deleted": boolean() }),
+        ),
+    );
+    memory_delete_by_id["parameters"] =
+        json!([path_param("memory_id", "Memory to delete"), user_id_param]);
+
+    let import_counts = json!({
+        "ok": boolean(),
+        "imported": { "type": "integer" },
+        "over_limit": { "type": "integer", "description": "Imported notes dropped by the per-user note cap" },
+        "total_notes": { "type": "integer" }
+    });
+    let mut memory_import = operation(
+        "Import notes and remember them as long-term memories",
+        "memory",
+        Some("MemoryImportRequest"),
+        object(&["ok"], {
+            let mut properties = import_counts.clone();
+            properties["dry_run"] = boolean();
+            properties["would_import"] = json!({ "type": "integer" });
+            properties["would_skip"] = json!({ "type": "integer" });
+            properties["items"] = json!({ "type": "array", "items": { "type": "object" } });
+            properties
+        }),
+    );
+    memory_import["description"] = json!(
+        "All or nothing: one invalid item (bad `happened_at`, or content over the note length limit) rejects the whole batch and nothing is imported. Use `/v1/memory/import_csv` for per-row errors."
+    );
+    memory_import["responses"]["413"] =
+        error_response("An item's content is over the note length limit");
+
+    let mut memory_import_csv = operation(
+        "Import notes from a CSV file and remember them as long-term memories",
+        "memory",
+        None,
+        object(
+            &["ok", "imported", "over_limit", "total_notes", "row_errors"],
+            {
+                let mut properties = import_counts;
+                properties["row_errors"] = json!({
+                    "type": "array",
+                    "items": object(&["row", "reason"], json!({
+                        "row": { "type": "integer", "description": "Line in the file; the header is 1" },
+                        "reason": string()
+                    }))
+                });
+                properties
+            },
+        ),
+    );
+    memory_import_csv["description"] = json!(
+        "Row by row: malformed rows, including a bad `happened_at` or content over the note length limit, are skipped and listed in `row_errors` while the other rows are imported. Fails with 400 only when no row can be imported."
+    );
+    memory_import_csv["requestBody"] = json!({
+        "required": true,
+        "content": { "text/csv": { "schema": string() } }
+    });
+    memory_import_csv["parameters"] = json!([user_id_param]);
+
+    let note_envelope = object(
+        &["ok", "note"],
+        json!({ "ok": boolean(), "note": schema_ref("UserNote") }),
+    );
+
+    json!({
+        "/health": {
+            "get": {
+                "summary": "Liveness, metrics and enabled capabilities",
+                "tags": ["system"],
+                "security": [],
+                "responses": { "200": { "description": "OK", "content": json_content(json!({ "type": "object" })) } }
+            }
+        },
+        "/v1/chat": {
+            "post": operation("Send a message to the concierge", "chat", Some("ChatRequest"), schema_ref("ChatResponse"))
+        },
+        "/v1/chat/history": { "get": chat_history },
+        "/v1/chat/validate": {
+            "post": operation(
+                "Report how a chat request would be interpreted, without running it",
+                "chat",
+                Some("ChatRequest"),
+                object(&["valid", "errors", "coerced_fields", "warnings", "interpreted"], json!({
+                    "valid": boolean(),
+                    "errors": { "type": "array", "items": schema_ref("Error") },
+                    "coerced_fields": { "type": "array", "items": object(&["field", "provided", "applied"], json!({
+                        "field": string(),
+                        "provided": string(),
+                        "applied": string()
+                    })) },
+                    "warnings": strings(),
+                    "interpreted": { "type": "object" }
+                }))
+            )
+        },
+        "/v1/notes": { "get": notes_list },
+        "/v1/notes/upsert": {
+            "post": operation("Create or replace a note", "notes", Some("NoteUpsertRequest"), note_envelope.clone())
+        },
+        "/v1/notes/rewrite": {
+            "post": operation("Rewrite a note into a structured plan and save it", "notes", Some("NoteRewriteRequest"), note_envelope)
+        },
+        "/v1/notes/rewrite_preview": {
+            "post": operation(
+                "Preview a structured rewrite without saving it",
+                "notes",
+                Some("NoteRewriteRequest"),
+                object(&["ok", "note_id", "preview_id", "preview"], json!({
+                    "ok": boolean(),
+                    "note_id": string(),
+                    "preview_id": string(),
+                    "preview": object(&["title", "content", "structured"], json!({
+                        "title": string(),
+                        "content": string(),
+                        "structured": schema_ref("StructuredNoteRewrite")
+                    }))
+                }))
+            )
+        },
+        "/v1/notes/delete": {
+            "post": operation(
+                "Delete one note and its version history",
+                "notes",
+                Some("NoteDeleteRequest"),
+                object(&["ok", "deleted"], json!({ "ok": boolean(), "deleted": boolean() }))
+            )
+        },
+        "/v1/notes/{note_id}": { "delete": note_delete_by_id },
+        "/v1/notes/{note_id}/versions": { "get": note_versions },
+        "/v1/notes/{note_id}/versions/{version_id}/restore": { "post": note_version_restore },
+        "/v1/memory/records": { "get": memory_records },
+        "/v1/memory/import": { "post": memory_import },
+        "/v1/memory/import_csv": { "post": memory_import_csv },
+        "/v1/memory/search": {
+            "post": operation(
+                "Search the caller's memories by text, best matches first",
+                "memory",
+                Some("MemorySearchRequest"),
+                object(&["memory_opt_in", "query", "count", "items"], json!({
+                    "memory_opt_in": boolean(),
+                    "query": string(),
+                    "count": { "type": "integer" },
+                    "items": { "type": "array", "items": schema_ref("MemoryRetrievedItem") }
+                }))
+            )
+        },
+        "/v1/memory/upsert": {
+            "post": operation(
+                "Store a memory for the caller",
+                "memory",
+                Some("MemoryUpsertRequest"),
+                object(&["ok", "memory"], json!({ "ok": boolean(), "memory": schema_ref("MemoryRecord") }))
+            )
+        },
+        "/v1/memory/delete": {
+            "post": operation(
+                "Delete one memory",
+                "memory",
+                Some("MemoryDeleteRequest"),
+                object(&["ok", "deleted"], json!({ "ok": boolean(), "deleted": boolean() }))
+            )
+        },
+        "/v1/memory/{memory_id}": { "delete": memory_delete_by_id },
+        "/v1/memory/clear": {
+            "post": operation(
+                "Delete memories by stability scope and tags",
+                "memory",
+                Some("MemoryClearRequest"),
+                object(&["ok", "scope", "tags", "tag_match", "cleared"], json!({
+                    "ok": boolean(),
+                    "scope": string(),
+                    "tags": strings(),
+                    "tag_match": string(),
+                    "cleared": { "type": "integer" },
+                    "restore_id": nullable_string(),
+                    "restorable_until": nullable_string()
+                }))
+            )
+        },
+        "/v1/memory/restore": {
+            "post": operation(
+                "Restore memories removed by a clear within the last 24 hours",
+                "memory",
+                Some("MemoryRestoreRequest"),
+                object(&["ok", "restored", "skipped_duplicates", "over_limit"], json!({
+                    "ok": boolean(),
+                    "restored": { "type": "integer" },
+                    "skipped_duplicates": { "type": "integer" },
+                    "over_limit": { "type": "integer" }
+                }))
+            )
+        },
+        "/v1/actions/reminder": {
+            "post": operation("Build calendar/reminder links for a task", "actions", Some("ReminderActionRequest"), schema_ref("ReminderActionResponse"))
+        },
+        "/v1/actions/reminder/snooze": {
+            "post": operation("Reschedule a reminder to a snooze slot", "actions", Some("ReminderSnoozeRequest"), schema_ref("ReminderActionResponse"))
+        },
+        "/v1/actions/alarm": {
+            "post": operation("Build clock/alarm links", "actions", Some("AlarmActionRequest"), schema_ref("AlarmActionResponse"))
+        },
+        "/v1/actions/plan": {
+            "post": operation("Build a reminder and an alarm under one trace id", "actions", Some("ActionPlanRequest"), schema_ref("ActionPlanResponse"))
+        }
+    })
+}
+
+fn schemas() -> Value {
+    json!({
+        "Error": object(&["error", "message"], json!({
+            "error": string(),
+            "message": string(),
+            "details": { "type": "object", "description": "Route-specific context, e.g. subscription state or action telemetry" }
+        })),
+        "ChatRequest": object(&["text"], json!({
+            "text": string(),
+            "session_id": string(),
+            "locale": string(),
+            "user_id": string(),
+            "preferred_format": string(),
+            "response_depth": string(),
+            "response_tone": string(),
+            "include_proactive": boolean(),
+            "max_reply_chars": { "type": "integer", "minimum": 0 },
+            "reasoning_effort": { "type": "string", "enum": ["low", "medium", "high"] }
+        })),
+        "SuggestedAction": object(&["action_type", "label", "payload"], json!({
+            "action_type": string(),
+            "label": string(),
+            "payload": { "type": "object" }
+        })),
+        "ChatResponse": object(
+            &["reply_text", "suggested_actions", "json_payload", "locale", "intent", "clarifying_questions", "policy_notes", "retrieved_sources"],
+            json!({
+                "reply_text": string(),
+                "suggested_actions": { "type": "array", "items": schema_ref("SuggestedAction") },
+                "json_payload": { "type": "object" },
+                "locale": { "type": "string", "enum": ["he", "en", "ar", "ru", "fr", "unknown"] },
+                "intent": {
+                    "type": "string",
+                    "enum": ["trip_planning", "ops_checklist", "policy", "pricing", "troubleshooting", "content", "small_talk", "unknown"]
+                },
+                "clarifying_questions": strings(),
+                "policy_notes": strings(),
+                "retrieved_sources": strings()
+            })
+        ),
+        "ChatTurn": object(
+            &["turn_id", "session_id", "user_id", "user_text", "assistant_text", "created_at"],
+            json!({
+                "turn_id": string(),
+                "session_id": string(),
+                "user_id": string(),
+                "user_text": string(),
+                "assistant_text": string(),
+                "created_at": { "type": "string", "format": "date-time" }
+            })
+        ),
+        "StructuredNoteRewrite": object(&["immediate_tasks", "mid_term", "long_term"], json!({
+            "immediate_tasks": strings(),
+            "mid_term": strings(),
+            "long_term": strings()
+        })),
+        "UserNote": object(&["note_id", "user_id", "title", "content", "tags", "updated_at"], json!({
+            "note_id": string(),
+            "user_id": string(),
+            "title": string(),
+            "content": string(),
+            "tags": strings(),
+            "updated_at": { "type": "string", "format": "date-time" },
+            "structured": { "allOf": [schema_ref("StructuredNoteRewrite")], "nullable": true }
+        })),
+        "NoteVersion": object(
+            &["version_id", "note_id", "user_id", "title", "content", "tags", "updated_at", "replaced_at", "replaced_by"],
+            json!({
+                "version_id": string(),
+                "note_id": string(),
+                "user_id": string(),
+                "title": string(),
+                "content": string(),
+                "tags": strings(),
+                "structured": { "allOf": [schema_ref("StructuredNoteRewrite")], "nullable": true },
+                "updated_at": { "type": "string", "format": "date-time" },
+                "replaced_at": { "type": "string", "format": "date-time" },
+                "replaced_by": string()
+            })
+        ),
+        "NoteVersionRestoreRequest": object(&[], json!({ "user_id": string() })),
+        "NoteUpsertRequest": object(&["title", "content"], json!({
+            "note_id": string(),
+            "user_id": string(),
+            "title": string(),
+            "content": string(),
+            "tags": strings()
+        })),
+        "NoteRewriteRequest": object(&["note_id"], json!({
+            "note_id": string(),
+            "user_id": string(),
+            "instruction": string(),
+            "preview_id": string()
+        })),
+        "MemoryRecord": object(
+            &["memory_id", "user_id", "memory_type", "stability", "source", "text", "weight", "recency_score", "tags", "created_at", "updated_at", "fingerprint"],
+            json!({
+                "memory_id": string(),
+                "user_id": string(),
+                "memory_type": string(),
+                "stability": { "type": "string", "enum": ["permanent", "transient"] },
+                "source": string(),
+                "text": string(),
+                "weight": number(),
+                "recency_score": number(),
+                "tags": strings(),
+                "created_at": { "type": "string", "format": "date-time" },
+                "updated_at": { "type": "string", "format": "date-time" },
+                "expires_at": { "type": "string", "format": "date-time", "nullable": true },
+                "fingerprint": string()
+            })
+        ),
+        "MemoryRetrievedItem": object(
+            &["memory_id", "memory_type", "stability", "source", "text", "weight", "recency_score", "relevance_score", "final_score", "tags", "updated_at"],
+            json!({
+                "memory_id": string(),
+                "memory_type": string(),
+                "stability": string(),
+                "source": string(),
+                "text": string(),
+                "weight": number(),
+                "recency_score": number(),
+                "relevance_score": number(),
+                "final_score": number(),
+                "tags": strings(),
+                "updated_at": { "type": "string", "format": "date-time" }
+            })
+        ),
+        "MemoryUpsertRequest": object(&["text"], json!({
+            "text": string(),
+            "user_id": string(),
+            "memory_type": string(),
+            "stability": { "type": "string", "enum": ["permanent", "transient"] },
+            "source": string(),
+            "weight": number(),
+            "tags": strings(),
+            "expires_at": { "type": "string", "format": "date-time" }
+        })),
+        "MemorySearchRequest": object(&["query"], json!({
+            "user_id": string(),
+            "query": string(),
+            "limit": { "type": "integer", "minimum": 1 },
+            "sources": strings(),
+            "types": strings(),
+            "min_score": { "type": "number", "minimum": 0, "maximum": 1, "description": "Minimum relevance_score an item must reach" }
+        })),
+        "NoteDeleteRequest": object(&["note_id"], json!({
+            "note_id": string(),
+            "user_id": string()
+        })),
+        "MemoryImportRequest": object(&["items"], json!({
+            "user_id": string(),
+            "items": {
+                "type": "array",
+                "items": object(&["title", "content"], json!({
+                    "title": string(),
+                    "content": string(),
+                    "tags": strings(),
+                    "source": string(),
+                    "happened_at": { "type": "string", "format": "date-time" }
+                }))
+            },
+            "dry_run": { "type": "boolean", "default": false }
+        })),
+        "MemoryDeleteRequest": object(&["memory_id"], json!({
+            "memory_id": string(),
+            "user_id": string()
+        })),
+        "MemoryRestoreRequest": object(&[], json!({
+            "user_id": string(),
+            "restore_id": { "type": "string", "description": "From a clear response; omit to restore everything in the trash" }
+        })),
+        "MemoryClearRequest": object(&[], json!({
+            "user_id": string(),
+            "scope": { "type": "string", "enum": ["all", "permanent", "transient"], "default": "all" },
+            "tags": strings(),
+            "tag_match": { "type": "string", "enum": ["any", "all"], "default": "any" }
+        })),
+        "ActionTelemetry": object(
+            &["trace_id", "action", "success", "supports_direct_write", "fallback_used", "warnings", "generated_at"],
+            json!({
+                "trace_id": string(),
+                "action": string(),
+                "success": boolean(),
+                "app": nullable_string(),
+                "supports_direct_write": boolean(),
+                "fallback_used": boolean(),
+                "primary_target": nullable_string(),
+                "warnings": strings(),
+                "generated_at": { "type": "string", "format": "date-time" },
+                "scheduled_start_utc": nullable_string()
+            })
+        ),
+        "ReminderActionRequest": object(&["title"], json!({
+            "title": string(),
+            "details": string(),
+            "due_at_utc": { "type": "string", "format": "date-time" },
+            "duration_minutes": { "type": "integer", "minimum": 0 },
+            "reminders_app": string()
+        })),
+        "ReminderActionResponse": object(
+            &["app", "google_calendar_url", "ics_filename", "ics_content", "shortcuts_url", "supports_direct_write", "fallback_used", "user_message", "telemetry"],
+            json!({
+                "app": string(),
+                "google_calendar_url": string(),
+                "ics_filename": string(),
+                "ics_content": string(),
+                "shortcuts_url": string(),
+                "primary_url": nullable_string(),
+                "supports_direct_write": boolean(),
+                "fallback_used": boolean(),
+                "user_message": string(),
+                "telemetry": schema_ref("ActionTelemetry")
+            })
+        ),
+        "ReminderSnoozeRequest": object(&["reminder", "snooze"], json!({
+            "reminder": schema_ref("ReminderActionRequest"),
+            "snooze": { "type": "string", "enum": ["plus_1h", "tonight", "tomorrow_morning"] },
+            "utc_offset_minutes": { "type": "integer", "minimum": -720, "maximum": 840 }
+        })),
+        "AlarmActionRequest": object(&["label", "time_local"], json!({
+            "label": string(),
+            "time_local": { "type": "string", "example": "07:30" },
+            "days": strings(),
+            "alarms_app": string()
+        })),
+        "AlarmActionResponse": object(
+            &["app", "clock_url", "shortcuts_url", "supports_direct_write", "fallback_used", "user_message", "fallback_instructions", "telemetry"],
+            json!({
+                "app": string(),
+                "clock_url": string(),
+                "shortcuts_url": string(),
+                "primary_url": nullable_string(),
+                "supports_direct_write": boolean(),
+                "fallback_used": boolean(),
+                "user_message": string(),
+                "fallback_instructions": string(),
+                "telemetry": schema_ref("ActionTelemetry")
+            })
+        ),
+        "ActionPlanRequest": object(&["reminder", "alarm"], json!({
+            "reminder": schema_ref("ReminderActionRequest"),
+            "alarm": schema_ref("AlarmActionRequest")
+        })),
+        "ActionPlanResponse": object(&["trace_id", "reminder", "alarm"], json!({
+            "trace_id": string(),
+            "reminder": schema_ref("ReminderActionResponse"),
+            "alarm": schema_ref("AlarmActionResponse")
+        }))
+    })
+}
+
+pub(crate) fn spec(session_cookie_name: &str) -> Value {
+    json!({
+        "openapi": "3.0.3",
+        "info": {
+            "title": "Atlas Concierge API",
+            "version": env!("CARGO_PKG_VERSION"),
+            "description": "Core chat, notes, memory and action routes. Service clients send `x-api-key`; first-party browser clients use the session cookie and must send an allowed `Origin` on state-changing requests."
+        },
+        "security": [{ "apiKey": [] }, { "session": [] }],
+        "components": {
+            "securitySchemes": {
+                "apiKey": { "type": "apiKey", "in": "header", "name": "x-api-key" },
+                "session": { "type": "apiKey", "in": "cookie", "name": session_cookie_name }
+            },
+            "schemas": schemas()
+        },
+        "paths": paths()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::Value;
+
+    use super::spec;
+
+    fn collect_refs(value: &Value, refs: &mut Vec<String>) {
+        match value {
+            Value::Object(map) => {
+                if let Some(Value::String(target)) = map.get("$ref") {
+                    refs.push(target.clone());
+                }
+                map.values().for_each(|child| collect_refs(child, refs));
+            }
+            Value::Array(items) => items.iter().for_each(|child| collect_refs(child, refs)),
+            _ => {}
+        }
+    }
+
+    /// `$ref` targets that do not name a schema in `components.schemas`.
+    fn dangling_refs(spec: &Value) -> Vec<String> {
+        let empty = serde_json::Map::new();
+        let schemas = spec["components"]["schemas"].as_object().unwrap_or(&empty);
+        let mut refs = Vec::new();
+        collect_refs(spec, &mut refs);
+        refs.into_iter()
+            .filter(|target| {
+                target
+                    .strip_prefix("#/components/schemas/")
+                    .is_none_or(|name| !schemas.contains_key(name))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn every_schema_reference_resolves() {
+        let spec = spec("atlas_session");
+        assert_eq!(spec["openapi"], "3.0.3");
+        assert!(
+            dangling_refs(&spec).is_empty(),
+            "{:?}",
+            dangling_refs(&spec)
+        );
+        assert_eq!(
+            spec["paths"]["/v1/chat"]["post"]["requestBody"]["content"]["application/json"]
+                ["schema"]["$ref"],
+            "#/components/schemas/ChatRequest"
+        );
+    }
+}
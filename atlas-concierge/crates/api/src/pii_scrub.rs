@@ -0,0 +1,273 @@
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine as _;
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN};
+use ring::rand::{SecureRandom, SystemRandom};
+
+const REDACTED_EMAIL: &str = "[redacted email]";
+const REDACTED_PHONE: &str = "[redacted phone]";
+const REDACTED_CARD: &str = "[redacted card]";
+
+const MIN_PHONE_DIGITS: usize = 9;
+const MAX_PHONE_DIGITS: usize = 15;
+const MIN_CARD_DIGITS: usize = 13;
+const MAX_CARD_DIGITS: usize = 19;
+
+pub fn redact_email_addresses(text: &str) -> String {
+    text.split_inclusive(char::is_whitespace)
+        .map(|word| {
+            let core = word.trim_matches(|ch: char| {
+                ch.is_whitespace()
+                    || matches!(ch, ',' | ';' | ':' | '(' | ')' | '<' | '>' | '"' | '\'')
+            });
+            let core = core.trim_end_matches(['.', '!', '?']);
+            if looks_like_email(core) {
+                word.replacen(core, REDACTED_EMAIL, 1)
+            } else {
+                word.to_string()
+            }
+        })
+        .collect()
+}
+
+fn looks_like_email(value: &str) -> bool {
+    let Some((local, domain)) = value.split_once('@') else {
+        return false;
+    };
+    let Some((host, tld)) = domain.rsplit_once('.') else {
+        return false;
+    };
+    !local.is_empty()
+        && !host.is_empty()
+        && !domain.contains('@')
+        && tld.len() >= 2
+        && tld.chars().all(|ch| ch.is_ascii_alphabetic())
+}
+
+/// Masks emails, phone numbers and card-like digit runs. Dates, times, prices and other short
+/// numbers are left alone, as are digits glued to letters (booking refs, ids).
+pub fn scrub_pii(text: &str) -> String {
+    mask_digit_runs(redact_email_addresses(text).as_str())
+}
+
+fn mask_digit_runs(text: &str) -> String {
+    let chars = text.chars().collect::<Vec<_>>();
+    let mut output = String::with_capacity(text.len());
+    let mut index = 0;
+    while index < chars.len() {
+        let starts_run = chars[index].is_ascii_digit()
+            || (matches!(chars[index], '+' | '(')
+                && chars.get(index + 1).is_some_and(char::is_ascii_digit));
+        let glued_to_word = index > 0 && chars[index - 1].is_alphanumeric();
+        if !starts_run || glued_to_word {
+            output.push(chars[index]);
+            index += 1;
+            continue;
+        }
+
+        let (end, digits) = digit_run_end(&chars, index);
+        let glued_after = chars.get(end).is_some_and(|ch| ch.is_alphanumeric());
+        let mask = if glued_after {
+            None
+        } else {
+            classify_digit_run(&chars[index..end], digits)
+        };
+        match mask {
+            Some(mask) => output.push_str(mask),
+            None => output.extend(&chars[index..end]),
+        }
+        index = end;
+    }
+    output
+}
+
+// A run is digits joined by at most two separator characters at a time, so "(03) 555-1234"
+// and "4111 1111 1111 1111" are single runs while "10:30, 12" is not. A date never continues
+// across a space and an `HH:MM` time never joins a run, so "2024-05-01 10:30" stays two
+// short runs instead of one phone-length one.
+fn digit_run_end(chars: &[char], start: usize) -> (usize, usize) {
+    let mut index = start;
+    let mut end = start + 1;
+    let mut digits = 0;
+    let mut separators = 0;
+    let mut crossed_space = false;
+    while index < chars.len() {
+        let ch = chars[index];
+        if ch.is_ascii_digit() {
+            if separators > 0
+                && ((crossed_space && is_date_shaped(&chars[start..end]))
+                    || starts_clock_time(chars, index))
+            {
+                break;
+            }
+            digits += 1;
+            separators = 0;
+            crossed_space = false;
+            index += 1;
+            end = index;
+        } else if matches!(ch, ' ' | '-' | '.' | '(' | ')' | '+') && separators < 2 {
+            separators += 1;
+            crossed_space |= ch == ' ';
+            index += 1;
+        } else {
+            break;
+        }
+    }
+    // Trailing separators belong to the surrounding sentence, not the number.
+    (end, digits)
+}
+
+// `YYYY-MM-DD`, `DD.MM.YYYY` and the like: three groups joined by `-` or `.`, with the
+// four-digit year first or last.
+fn is_date_shaped(run: &[char]) -> bool {
+    let text = run.iter().collect::<String>();
+    let groups = text.split(['-', '.']).collect::<Vec<_>>();
+    if groups.len() != 3
+        || groups
+            .iter()
+            .any(|group| group.is_empty() || !group.chars().all(|ch| ch.is_ascii_digit()))
+    {
+        return false;
+    }
+    let short = |group: &str| group.len() <= 2;
+    (groups[0].len() == 4 && short(groups[1]) && short(groups[2]))
+        || (short(groups[0]) && short(groups[1]) && groups[2].len() == 4)
+}
+
+fn starts_clock_time(chars: &[char], start: usize) -> bool {
+    let hour_digits = chars[start..]
+        .iter()
+        .take_while(|ch| ch.is_ascii_digit())
+        .count();
+    let colon = start + hour_digits;
+    (1..=2).contains(&hour_digits)
+        && chars.get(colon) == Some(&':')
+        && chars
+            .get(colon + 1..colon + 3)
+            .is_some_and(|minutes| minutes.iter().all(char::is_ascii_digit))
+}
+
+fn classify_digit_run(run: &[char], digits: usize) -> Option<&'static str> {
+    if (MIN_CARD_DIGITS..=MAX_CARD_DIGITS).contains(&digits)
+        && (digits > MAX_PHONE_DIGITS || passes_luhn(run))
+    {
+        return Some(REDACTED_CARD);
+    }
+    if (MIN_PHONE_DIGITS..=MAX_PHONE_DIGITS).contains(&digits) {
+        return Some(REDACTED_PHONE);
+    }
+    None
+}
+
+fn passes_luhn(run: &[char]) -> bool {
+    let mut sum = 0;
+    for (position, digit) in run
+        .iter()
+        .rev()
+        .filter_map(|ch| ch.to_digit(10))
+        .enumerate()
+    {
+        let value = if position % 2 == 1 { digit * 2 } else { digit };
+        sum += if value > 9 { value - 9 } else { value };
+    }
+    sum % 10 == 0
+}
+
+/// AES-256-GCM key used to keep a sealed copy of text before scrubbing. Sealed values are
+/// base64 of `nonce || ciphertext || tag`; without the key they are opaque.
+pub struct PiiOriginalKey {
+    key: LessSafeKey,
+    random: SystemRandom,
+}
+
+impl PiiOriginalKey {
+    /// Expects 32 bytes, base64-encoded.
+    pub fn from_base64(value: &str) -> Option<Self> {
+        let bytes = STANDARD.decode(value.trim()).ok()?;
+        let key = UnboundKey::new(&AES_256_GCM, &bytes).ok()?;
+        Some(Self {
+            key: LessSafeKey::new(key),
+            random: SystemRandom::new(),
+        })
+    }
+
+    pub fn seal(&self, plaintext: &str) -> Option<String> {
+        let mut nonce = [0_u8; NONCE_LEN];
+        self.random.fill(&mut nonce).ok()?;
+        let mut in_out = plaintext.as_bytes().to_vec();
+        self.key
+            .seal_in_place_append_tag(
+                Nonce::assume_unique_for_key(nonce),
+                Aad::empty(),
+                &mut in_out,
+            )
+            .ok()?;
+        let mut sealed = nonce.to_vec();
+        sealed.extend_from_slice(&in_out);
+        Some(STANDARD.encode(sealed))
+    }
+
+    #[cfg(test)]
+    fn open(&self, sealed: &str) -> Option<String> {
+        let bytes = STANDARD.decode(sealed).ok()?;
+        if bytes.len() < NONCE_LEN {
+            return None;
+        }
+        let (nonce, ciphertext) = bytes.split_at(NONCE_LEN);
+        let nonce = Nonce::try_assume_unique_for_key(nonce).ok()?;
+        let mut in_out = ciphertext.to_vec();
+        let plaintext = self
+            .key
+            .open_in_place(nonce, Aad::empty(), &mut in_out)
+            .ok()?;
+        String::from_utf8(plaintext.to_vec()).ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{scrub_pii, PiiOriginalKey};
+    use base64::engine::general_purpose::STANDARD;
+    use base64::Engine as _;
+
+    #[test]
+    fn scrub_masks_phones_cards_and_emails_but_not_dates_or_prices() {
+        assert_eq!(
+            scrub_pii("Call me on +972 54-123-4567 or (03) 555-1234, mail dana@example.com."),
+            "Call me on [redacted phone] or [redacted phone], mail [redacted email]."
+        );
+        assert_eq!(
+            scrub_pii("Charged twice on 4111 1111 1111 1111 and 4111-1111-1111-1112-000."),
+            "Charged twice on [redacted card] and [redacted card]."
+        );
+        assert_eq!(
+            scrub_pii("Pickup 2024-05-01 at 10.30, paid 120 ILS, booking AB123456789X."),
+            "Pickup 2024-05-01 at 10.30, paid 120 ILS, booking AB123456789X."
+        );
+        assert_eq!(
+            scrub_pii("Flight on 2024-05-01 10:30"),
+            "Flight on 2024-05-01 10:30"
+        );
+        assert_eq!(
+            scrub_pii("Dentist 15.05.2024 14:00"),
+            "Dentist 15.05.2024 14:00"
+        );
+        assert_eq!(
+            scrub_pii("Front desk 054 123 4567 from 08:00"),
+            "Front desk [redacted phone] from 08:00"
+        );
+    }
+
+    #[test]
+    fn sealed_originals_only_open_with_the_same_key() {
+        let key = PiiOriginalKey::from_base64(STANDARD.encode([7_u8; 32]).as_str())
+            .expect("32-byte key should load");
+        let sealed = key.seal("call 054-123-4567").expect("seal should succeed");
+        assert!(!sealed.contains("4567"));
+        assert_eq!(key.open(&sealed).as_deref(), Some("call 054-123-4567"));
+
+        let other = PiiOriginalKey::from_base64(STANDARD.encode([8_u8; 32]).as_str())
+            .expect("32-byte key should load");
+        assert!(other.open(&sealed).is_none());
+        assert!(PiiOriginalKey::from_base64("too-short").is_none());
+    }
+}
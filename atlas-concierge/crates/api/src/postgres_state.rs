@@ -0,0 +1,504 @@
+//! Postgres persistence for the load-bearing auth, session, credential, linked-identity, AI usage
+//! and billing tables.
+//! Everything else the API persists still goes through the SQLite pool and stays in memory on
+//! Postgres deployments.
+
+use anyhow::Result;
+use sqlx::{PgPool, Row};
+
+use crate::schema_migrations::{apply_postgres_migrations, Migration, MigrationStep};
+use crate::{
+    AiUsageCounterRecord, BillingStatusRecord, LinkedIdentityRecord, PasskeyRecord, PersistedState,
+    RecoveryCodeRecord, SessionRecord, UserRecord,
+};
+
+/// Ordered Postgres schema changes, applied through the same versioned runner as the SQLite
+/// `MIGRATIONS`. Append new entries with the next version number; never edit or reorder an
+/// entry that has shipped.
+const POSTGRES_MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        description: "auth, session and billing tables",
+        steps: &[
+            MigrationStep::Sql(
+                r#"
+                CREATE TABLE IF NOT EXISTS auth_users (
+                  user_id TEXT PRIMARY KEY,
+                  provider TEXT NOT NULL,
+                  email TEXT NOT NULL,
+                  name TEXT NOT NULL,
+                  locale TEXT NOT NULL,
+                  trip_style TEXT,
+                  risk_preference TEXT,
+                  memory_opt_in BOOLEAN NOT NULL,
+                  passkey_user_handle TEXT,
+                  created_at TEXT NOT NULL,
+                  updated_at TEXT NOT NULL,
+                  last_login_at TEXT,
+                  login_count BIGINT NOT NULL DEFAULT 0
+                );
+                "#,
+            ),
+            MigrationStep::Sql(
+                r#"
+                CREATE TABLE IF NOT EXISTS auth_sessions (
+                  session_id TEXT PRIMARY KEY,
+                  user_id TEXT NOT NULL,
+                  expires_at TEXT NOT NULL,
+                  created_at TEXT NOT NULL,
+                  client_network TEXT,
+                  region TEXT
+                );
+                "#,
+            ),
+            MigrationStep::Sql(
+                "CREATE INDEX IF NOT EXISTS idx_auth_sessions_user_id ON auth_sessions (user_id)",
+            ),
+            MigrationStep::Sql(
+                r#"
+                CREATE TABLE IF NOT EXISTS auth_challenges (
+                  kind TEXT NOT NULL,
+                  challenge_id TEXT NOT NULL,
+                  data_json TEXT NOT NULL,
+                  expires_at TEXT NOT NULL,
+                  PRIMARY KEY (kind, challenge_id)
+                );
+                "#,
+            ),
+            MigrationStep::Sql(
+                r#"
+                CREATE TABLE IF NOT EXISTS billing_subscriptions (
+                  user_id TEXT PRIMARY KEY,
+                  stripe_customer_id TEXT,
+                  stripe_subscription_id TEXT,
+                  status TEXT NOT NULL,
+                  current_period_end TEXT,
+                  updated_at TEXT NOT NULL
+                );
+                "#,
+            ),
+        ],
+    },
+    // Same shape as the SQLite tables, so `SharedAuthStore::load_passkeys` reads either backend.
+    Migration {
+        version: 2,
+        description: "passkeys, recovery codes and server-generated secrets",
+        steps: &[
+            MigrationStep::Sql(
+                r#"
+                CREATE TABLE IF NOT EXISTS passkeys (
+                  passkey_id TEXT PRIMARY KEY,
+                  user_id TEXT NOT NULL,
+                  data_json TEXT NOT NULL
+                );
+                "#,
+            ),
+            MigrationStep::Sql(
+                "CREATE INDEX IF NOT EXISTS idx_passkeys_user_id ON passkeys (user_id)",
+            ),
+            MigrationStep::Sql(
+                r#"
+                CREATE TABLE IF NOT EXISTS recovery_codes (
+                  code_id TEXT PRIMARY KEY,
+                  user_id TEXT NOT NULL,
+                  data_json TEXT NOT NULL
+                );
+                "#,
+            ),
+            MigrationStep::Sql(
+                "CREATE INDEX IF NOT EXISTS idx_recovery_codes_user_id ON recovery_codes (user_id)",
+            ),
+            MigrationStep::Sql(
+                r#"
+                CREATE TABLE IF NOT EXISTS app_secrets (
+                  name TEXT PRIMARY KEY,
+                  value TEXT NOT NULL
+                );
+                "#,
+            ),
+        ],
+    },
+    Migration {
+        version: 3,
+        description: "linked identities and ai usage counters",
+        steps: &[
+            MigrationStep::Sql(
+                r#"
+                CREATE TABLE IF NOT EXISTS linked_identities (
+                  identity_key TEXT PRIMARY KEY,
+                  user_id TEXT NOT NULL,
+                  data_json TEXT NOT NULL
+                );
+                "#,
+            ),
+            MigrationStep::Sql(
+                "CREATE INDEX IF NOT EXISTS idx_linked_identities_user_id ON linked_identities (user_id)",
+            ),
+            MigrationStep::Sql(
+                r#"
+                CREATE TABLE IF NOT EXISTS usage_counters (
+                  user_id TEXT PRIMARY KEY,
+                  period TEXT NOT NULL,
+                  call_count BIGINT NOT NULL,
+                  token_estimate BIGINT NOT NULL,
+                  updated_at TEXT NOT NULL
+                );
+                "#,
+            ),
+        ],
+    },
+];
+
+pub(crate) async fn ensure_schema(pool: &PgPool) -> Result<()> {
+    apply_postgres_migrations(pool, POSTGRES_MIGRATIONS).await?;
+    Ok(())
+}
+
+pub(crate) async fn load_auth_state(pool: &PgPool, state: &mut PersistedState) -> Result<()> {
+    let users = sqlx::query(
+        r#"
+        SELECT user_id, provider, email, name, locale, trip_style, risk_preference, memory_opt_in, passkey_user_handle, created_at, updated_at, last_login_at, login_count
+        FROM auth_users
+        "#,
+    )
+    .fetch_all(pool)
+    .await?;
+    for row in users {
+        let user = UserRecord {
+            user_id: row.get("user_id"),
+            provider: row.get("provider"),
+            email: row.get("email"),
+            name: row.get("name"),
+            locale: row.get("locale"),
+            trip_style: row.get("trip_style"),
+            risk_preference: row.get("risk_preference"),
+            memory_opt_in: row.get("memory_opt_in"),
+            passkey_user_handle: row.get("passkey_user_handle"),
+            created_at: row.get("created_at"),
+            updated_at: row.get("updated_at"),
+            last_login_at: row.get("last_login_at"),
+            login_count: row.get::<i64, _>("login_count").max(0) as u64,
+        };
+        state.users.insert(user.user_id.clone(), user);
+    }
+
+    let sessions = sqlx::query(
+        "SELECT session_id, user_id, expires_at, created_at, client_network, region FROM auth_sessions",
+    )
+    .fetch_all(pool)
+    .await?;
+    let now = chrono::Utc::now();
+    for row in sessions {
+        let expires_at = row
+            .get::<String, _>("expires_at")
+            .parse()
+            .unwrap_or_else(|_| chrono::Utc::now());
+        if expires_at <= now {
+            delete_session(pool, row.get::<String, _>("session_id").as_str()).await?;
+            continue;
+        }
+        let created_at = row
+            .get::<String, _>("created_at")
+            .parse()
+            .unwrap_or_else(|_| chrono::Utc::now());
+        state.sessions.insert(
+            row.get("session_id"),
+            SessionRecord {
+                user_id: row.get("user_id"),
+                expires_at,
+                created_at,
+                client_network: row.get("client_network"),
+                region: row.get("region"),
+            },
+        );
+    }
+
+    let passkeys = sqlx::query("SELECT user_id, data_json FROM passkeys")
+        .fetch_all(pool)
+        .await?;
+    for row in passkeys {
+        let json: String = row.get("data_json");
+        if let Ok(value) = serde_json::from_str::<PasskeyRecord>(&json) {
+            state
+                .passkeys_by_user
+                .entry(row.get("user_id"))
+                .or_default()
+                .push(value);
+        }
+    }
+
+    let recovery_codes = sqlx::query("SELECT user_id, data_json FROM recovery_codes")
+        .fetch_all(pool)
+        .await?;
+    for row in recovery_codes {
+        let json: String = row.get("data_json");
+        if let Ok(value) = serde_json::from_str::<RecoveryCodeRecord>(&json) {
+            state
+                .recovery_codes
+                .entry(row.get("user_id"))
+                .or_default()
+                .push(value);
+        }
+    }
+
+    let linked = sqlx::query("SELECT identity_key, data_json FROM linked_identities")
+        .fetch_all(pool)
+        .await?;
+    for row in linked {
+        let json: String = row.get("data_json");
+        if let Ok(value) = serde_json::from_str::<LinkedIdentityRecord>(&json) {
+            state
+                .linked_identities
+                .insert(row.get("identity_key"), value);
+        }
+    }
+
+    let usage = sqlx::query(
+        "SELECT user_id, period, call_count, token_estimate, updated_at FROM usage_counters",
+    )
+    .fetch_all(pool)
+    .await?;
+    for row in usage {
+        let counter = AiUsageCounterRecord {
+            user_id: row.get("user_id"),
+            period: row.get("period"),
+            call_count: row.get::<i64, _>("call_count").max(0) as u32,
+            token_estimate: row.get::<i64, _>("token_estimate").max(0) as u64,
+            updated_at: row.get("updated_at"),
+        };
+        state
+            .ai_usage_counters
+            .insert(counter.user_id.clone(), counter);
+    }
+
+    Ok(())
+}
+
+pub(crate) async fn persist_user(pool: &PgPool, user: &UserRecord) -> Result<()> {
+    sqlx::query(
+        r#"
+        INSERT INTO auth_users (user_id, provider, email, name, locale, trip_style, risk_preference, memory_opt_in, passkey_user_handle, created_at, updated_at, last_login_at, login_count)
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13)
+        ON CONFLICT(user_id) DO UPDATE SET
+          provider=excluded.provider,
+          email=excluded.email,
+          name=excluded.name,
+          locale=excluded.locale,
+          trip_style=excluded.trip_style,
+          risk_preference=excluded.risk_preference,
+          memory_opt_in=excluded.memory_opt_in,
+          passkey_user_handle=excluded.passkey_user_handle,
+          updated_at=excluded.updated_at,
+          last_login_at=excluded.last_login_at,
+          login_count=excluded.login_count
+        "#,
+    )
+    .bind(user.user_id.as_str())
+    .bind(user.provider.as_str())
+    .bind(user.email.as_str())
+    .bind(user.name.as_str())
+    .bind(user.locale.as_str())
+    .bind(user.trip_style.as_deref())
+    .bind(user.risk_preference.as_deref())
+    .bind(user.memory_opt_in)
+    .bind(user.passkey_user_handle.as_deref())
+    .bind(user.created_at.as_str())
+    .bind(user.updated_at.as_str())
+    .bind(user.last_login_at.as_deref())
+    .bind(user.login_count as i64)
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+/// Replaces every passkey row of one user in a single transaction, so a concurrent reader never
+/// sees the user with no passkeys between the delete and the inserts.
+pub(crate) async fn replace_passkeys(
+    pool: &PgPool,
+    user_id: &str,
+    records: &[PasskeyRecord],
+) -> Result<()> {
+    let mut tx = pool.begin().await?;
+    sqlx::query("DELETE FROM passkeys WHERE user_id = $1")
+        .bind(user_id)
+        .execute(&mut *tx)
+        .await?;
+    for record in records {
+        sqlx::query("INSERT INTO passkeys (passkey_id, user_id, data_json) VALUES ($1, $2, $3)")
+            .bind(record.passkey_id.as_str())
+            .bind(user_id)
+            .bind(serde_json::to_string(record)?)
+            .execute(&mut *tx)
+            .await?;
+    }
+    tx.commit().await?;
+    Ok(())
+}
+
+/// Replaces every recovery-code row of one user in a single transaction.
+pub(crate) async fn replace_recovery_codes(
+    pool: &PgPool,
+    user_id: &str,
+    records: &[RecoveryCodeRecord],
+) -> Result<()> {
+    let mut tx = pool.begin().await?;
+    sqlx::query("DELETE FROM recovery_codes WHERE user_id = $1")
+        .bind(user_id)
+        .execute(&mut *tx)
+        .await?;
+    for record in records {
+        sqlx::query("INSERT INTO recovery_codes (code_id, user_id, data_json) VALUES ($1, $2, $3)")
+            .bind(record.code_id.as_str())
+            .bind(user_id)
+            .bind(serde_json::to_string(record)?)
+            .execute(&mut *tx)
+            .await?;
+    }
+    tx.commit().await?;
+    Ok(())
+}
+
+/// Stores a provider link. Returns false when the identity is already linked to a different
+/// user, in which case the existing row is left alone.
+pub(crate) async fn persist_linked_identity(
+    pool: &PgPool,
+    identity_key: &str,
+    record: &LinkedIdentityRecord,
+) -> Result<bool> {
+    let result = sqlx::query(
+        r#"
+        INSERT INTO linked_identities (identity_key, user_id, data_json)
+        VALUES ($1, $2, $3)
+        ON CONFLICT(identity_key) DO UPDATE SET
+          data_json=excluded.data_json
+        WHERE linked_identities.user_id = excluded.user_id
+        "#,
+    )
+    .bind(identity_key)
+    .bind(record.user_id.as_str())
+    .bind(serde_json::to_string(record)?)
+    .execute(pool)
+    .await?;
+    Ok(result.rows_affected() > 0)
+}
+
+pub(crate) async fn persist_ai_usage(pool: &PgPool, counter: &AiUsageCounterRecord) -> Result<()> {
+    sqlx::query(
+        r#"
+        INSERT INTO usage_counters (user_id, period, call_count, token_estimate, updated_at)
+        VALUES ($1, $2, $3, $4, $5)
+        ON CONFLICT(user_id) DO UPDATE SET
+          period=excluded.period,
+          call_count=excluded.call_count,
+          token_estimate=excluded.token_estimate,
+          updated_at=excluded.updated_at
+        "#,
+    )
+    .bind(counter.user_id.as_str())
+    .bind(counter.period.as_str())
+    .bind(i64::from(counter.call_count))
+    .bind(counter.token_estimate as i64)
+    .bind(counter.updated_at.as_str())
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+/// Returns the stored secret `name`, storing a fresh one first if none exists. Concurrent
+/// instances race on the insert and all read back the winner.
+pub(crate) async fn load_or_create_secret(pool: &PgPool, name: &str) -> Result<String> {
+    sqlx::query(
+        "INSERT INTO app_secrets (name, value) VALUES ($1, $2) ON CONFLICT(name) DO NOTHING",
+    )
+    .bind(name)
+    .bind(crate::generate_urlsafe_token(32))
+    .execute(pool)
+    .await?;
+    let value = sqlx::query("SELECT value FROM app_secrets WHERE name = $1")
+        .bind(name)
+        .fetch_one(pool)
+        .await?
+        .get("value");
+    Ok(value)
+}
+
+/// Writes one session row. A row that already exists only has its expiry moved, so refreshing
+/// a session never touches the other sessions in the table.
+pub(crate) async fn upsert_session(
+    pool: &PgPool,
+    session_id: &str,
+    session: &SessionRecord,
+) -> Result<()> {
+    sqlx::query(
+        r#"
+        INSERT INTO auth_sessions (session_id, user_id, expires_at, created_at, client_network, region)
+        VALUES ($1, $2, $3, $4, $5, $6)
+        ON CONFLICT(session_id) DO UPDATE SET expires_at=excluded.expires_at
+        "#,
+    )
+    .bind(session_id)
+    .bind(session.user_id.as_str())
+    .bind(session.expires_at.to_rfc3339())
+    .bind(session.created_at.to_rfc3339())
+    .bind(session.client_network.as_deref())
+    .bind(session.region.as_deref())
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+pub(crate) async fn delete_session(pool: &PgPool, session_id: &str) -> Result<()> {
+    sqlx::query("DELETE FROM auth_sessions WHERE session_id = $1")
+        .bind(session_id)
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+pub(crate) async fn persist_billing_status(
+    pool: &PgPool,
+    billing: &BillingStatusRecord,
+) -> Result<()> {
+    sqlx::query(
+        r#"
+        INSERT INTO billing_subscriptions (user_id, stripe_customer_id, stripe_subscription_id, status, current_period_end, updated_at)
+        VALUES ($1, $2, $3, $4, $5, $6)
+        ON CONFLICT(user_id) DO UPDATE SET
+          stripe_customer_id=excluded.stripe_customer_id,
+          stripe_subscription_id=excluded.stripe_subscription_id,
+          status=excluded.status,
+          current_period_end=excluded.current_period_end,
+          updated_at=excluded.updated_at
+        "#,
+    )
+    .bind(billing.user_id.as_str())
+    .bind(billing.stripe_customer_id.as_deref())
+    .bind(billing.stripe_subscription_id.as_deref())
+    .bind(billing.status.as_str())
+    .bind(billing.current_period_end.as_deref())
+    .bind(billing.updated_at.as_str())
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+pub(crate) async fn subscription_status(pool: &PgPool, user_id: &str) -> Result<Option<String>> {
+    let row = sqlx::query("SELECT status FROM billing_subscriptions WHERE user_id = $1 LIMIT 1")
+        .bind(user_id)
+        .fetch_optional(pool)
+        .await?;
+    Ok(row.and_then(|value| value.try_get::<String, _>("status").ok()))
+}
+
+pub(crate) async fn user_id_by_customer(
+    pool: &PgPool,
+    customer_id: &str,
+) -> Result<Option<String>> {
+    let row = sqlx::query(
+        "SELECT user_id FROM billing_subscriptions WHERE stripe_customer_id = $1 LIMIT 1",
+    )
+    .bind(customer_id)
+    .fetch_optional(pool)
+    .await?;
+    Ok(row.map(|value| value.get::<String, _>("user_id")))
+}
@@ -1,7 +1,9 @@
 use std::collections::{HashMap, VecDeque};
+use std::net::IpAddr;
 use std::sync::Arc;
 use std::time::{Duration, Instant};
 
+use axum::http::HeaderMap;
 use parking_lot::Mutex;
 
 #[derive(Debug, Clone)]
@@ -41,3 +43,464 @@ impl IpRateLimiter {
         true
     }
 }
+
+// Hard cap on tracked emails. Stale entries are pruned first; while a spray of fresh emails keeps
+// every entry current, the least recently active ones are evicted instead, active lockouts last.
+const MAX_TRACKED_LOGIN_EMAILS: usize = 10_000;
+
+/// Per-email login attempt counter. Unlike the IP limiters it ignores where a request comes
+/// from, so credential stuffing spread over many IPs still trips it. Going over `max_attempts`
+/// inside `window` locks the email out; each further lockout doubles, up to `max_lockout`,
+/// until a successful login resets it. Counts are per instance.
+#[derive(Debug, Clone)]
+pub struct EmailLoginLockouts {
+    inner: Arc<Mutex<HashMap<String, EmailLockoutState>>>,
+    max_attempts: usize,
+    window: Duration,
+    base_lockout: Duration,
+    max_lockout: Duration,
+}
+
+#[derive(Debug, Default)]
+struct EmailLockoutState {
+    attempts: VecDeque<Instant>,
+    locked_until: Option<Instant>,
+    lockouts: u32,
+}
+
+impl EmailLoginLockouts {
+    pub fn new(
+        max_attempts: usize,
+        window: Duration,
+        base_lockout: Duration,
+        max_lockout: Duration,
+    ) -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(HashMap::new())),
+            max_attempts,
+            window,
+            base_lockout,
+            max_lockout: max_lockout.max(base_lockout),
+        }
+    }
+
+    /// Time left on an active lockout.
+    pub fn locked_for(&self, email: &str, now: Instant) -> Option<Duration> {
+        let guard = self.inner.lock();
+        let locked_until = guard.get(email)?.locked_until?;
+        (locked_until > now).then(|| locked_until - now)
+    }
+
+    /// Counts one attempt and returns the lockout it started, if any.
+    pub fn record_attempt(&self, email: &str, now: Instant) -> Option<Duration> {
+        let mut guard = self.inner.lock();
+        if !guard.contains_key(email) && guard.len() >= MAX_TRACKED_LOGIN_EMAILS {
+            let window = self.window;
+            guard.retain(|_, entry| {
+                entry.locked_until.is_some_and(|until| until > now)
+                    || entry
+                        .attempts
+                        .back()
+                        .is_some_and(|last| now.duration_since(*last) <= window)
+            });
+            while guard.len() >= MAX_TRACKED_LOGIN_EMAILS {
+                let Some(oldest) = guard
+                    .iter()
+                    .min_by_key(|(_, entry)| {
+                        let locked = entry.locked_until.is_some_and(|until| until > now);
+                        (
+                            locked,
+                            entry.attempts.back().copied().or(entry.locked_until),
+                        )
+                    })
+                    .map(|(key, _)| key.clone())
+                else {
+                    break;
+                };
+                guard.remove(&oldest);
+            }
+        }
+        let entry = guard.entry(email.to_string()).or_default();
+        if let Some(locked_until) = entry.locked_until {
+            if locked_until > now {
+                return None;
+            }
+            // A quiet stretch as long as the longest lockout forgives earlier lockouts.
+            if now.duration_since(locked_until) > self.max_lockout {
+                entry.lockouts = 0;
+            }
+        }
+
+        while let Some(front) = entry.attempts.front() {
+            if now.duration_since(*front) > self.window {
+                entry.attempts.pop_front();
+            } else {
+                break;
+            }
+        }
+        entry.attempts.push_back(now);
+        if entry.attempts.len() <= self.max_attempts {
+            return None;
+        }
+
+        let lockout = self
+            .base_lockout
+            .saturating_mul(2_u32.saturating_pow(entry.lockouts))
+            .min(self.max_lockout);
+        entry.lockouts = entry.lockouts.saturating_add(1);
+        entry.locked_until = Some(now + lockout);
+        entry.attempts.clear();
+        Some(lockout)
+    }
+
+    pub fn reset(&self, email: &str) {
+        self.inner.lock().remove(email);
+    }
+}
+
+/// Limiter used by the request path. Without Redis every instance counts on its own, so the
+/// effective limit behind a load balancer is `max_requests` times the instance count.
+#[derive(Clone)]
+pub enum RateLimiter {
+    Local(IpRateLimiter),
+    #[cfg(feature = "redis")]
+    Redis(Box<RedisRateLimiter>),
+}
+
+impl RateLimiter {
+    pub async fn allow(&self, key: &str) -> bool {
+        match self {
+            RateLimiter::Local(limiter) => limiter.allow(key),
+            #[cfg(feature = "redis")]
+            RateLimiter::Redis(limiter) => limiter.allow(key).await,
+        }
+    }
+}
+
+// Sliding window over a sorted set of request timestamps (milliseconds). Trimming, counting
+// and recording run atomically inside Redis, so concurrent instances cannot overshoot.
+#[cfg(feature = "redis")]
+const SLIDING_WINDOW_SCRIPT: &str = r#"
+local key = KEYS[1]
+local now = tonumber(ARGV[1])
+local window = tonumber(ARGV[2])
+local limit = tonumber(ARGV[3])
+redis.call('ZREMRANGEBYSCORE', key, '-inf', now - window)
+if redis.call('ZCARD', key) >= limit then
+  return 0
+end
+redis.call('ZADD', key, now, ARGV[4])
+redis.call('PEXPIRE', key, window)
+return 1
+"#;
+
+#[cfg(feature = "redis")]
+#[derive(Clone)]
+pub struct RedisRateLimiter {
+    connection: redis::aio::ConnectionManager,
+    script: Arc<redis::Script>,
+    namespace: String,
+    window: Duration,
+    max_requests: usize,
+    // Used while Redis is unreachable so an outage degrades to per-instance limits instead
+    // of disabling rate limiting.
+    fallback: IpRateLimiter,
+}
+
+#[cfg(feature = "redis")]
+impl RedisRateLimiter {
+    pub fn new(
+        connection: redis::aio::ConnectionManager,
+        namespace: &str,
+        window: Duration,
+        max_requests: usize,
+    ) -> Self {
+        Self {
+            connection,
+            script: Arc::new(redis::Script::new(SLIDING_WINDOW_SCRIPT)),
+            namespace: namespace.to_string(),
+            window,
+            max_requests,
+            fallback: IpRateLimiter::new(window, max_requests),
+        }
+    }
+
+    pub async fn allow(&self, key: &str) -> bool {
+        let now_ms = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_millis() as u64)
+            .unwrap_or_default();
+        let mut connection = self.connection.clone();
+        let allowed: redis::RedisResult<i64> = self
+            .script
+            .key(format!("atlas:rl:{}:{key}", self.namespace))
+            .arg(now_ms)
+            .arg(self.window.as_millis() as u64)
+            .arg(self.max_requests as u64)
+            .arg(format!("{now_ms}-{}", uuid::Uuid::new_v4()))
+            .invoke_async(&mut connection)
+            .await;
+        match allowed {
+            Ok(value) => value == 1,
+            Err(err) => {
+                tracing::warn!(error = %err, "redis rate limiter unavailable; using local limits");
+                self.fallback.allow(key)
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct TrustedProxies {
+    ranges: Vec<(IpAddr, u8)>,
+}
+
+impl TrustedProxies {
+    /// Parses a comma-separated CIDR list such as `10.0.0.0/8,fd00::/8`. A bare address is
+    /// treated as a single host; malformed entries are dropped.
+    pub fn parse(raw: &str) -> Self {
+        let ranges = raw
+            .split(',')
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+            .filter_map(|entry| {
+                let (address, prefix) = match entry.split_once('/') {
+                    Some((address, prefix)) => (address, Some(prefix)),
+                    None => (entry, None),
+                };
+                let address = address.trim().parse::<IpAddr>().ok()?;
+                let max_prefix = if address.is_ipv4() { 32 } else { 128 };
+                let prefix = match prefix {
+                    Some(prefix) => prefix.trim().parse::<u8>().ok()?,
+                    None => max_prefix,
+                };
+                (prefix <= max_prefix).then_some((address, prefix))
+            })
+            .collect();
+        Self { ranges }
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        let ip = canonical_ip(ip);
+        self.ranges
+            .iter()
+            .any(|(network, prefix)| match (canonical_ip(*network), ip) {
+                (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                    prefix_matches(&network.octets(), &ip.octets(), *prefix)
+                }
+                (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                    prefix_matches(&network.octets(), &ip.octets(), *prefix)
+                }
+                _ => false,
+            })
+    }
+}
+
+/// Resolves the client address used as the rate-limit key. Forwarding headers are only
+/// honoured when the socket peer is a trusted proxy; otherwise any client could pick its own
+/// bucket by sending `X-Forwarded-For`.
+pub fn resolve_client_ip(
+    peer: Option<IpAddr>,
+    headers: &HeaderMap,
+    trusted: &TrustedProxies,
+) -> Option<IpAddr> {
+    let peer = peer?;
+    if !trusted.contains(peer) {
+        return Some(peer);
+    }
+
+    if let Some(forwarded) = headers
+        .get("x-forwarded-for")
+        .and_then(|value| value.to_str().ok())
+    {
+        // Walk right to left: each trusted hop appended the address it saw, so the first
+        // untrusted entry is the real client.
+        let mut hops = forwarded.rsplit(',').map(str::trim);
+        let mut client = None;
+        for hop in hops.by_ref() {
+            let Ok(ip) = hop.parse::<IpAddr>() else {
+                return Some(peer);
+            };
+            client = Some(ip);
+            if !trusted.contains(ip) {
+                break;
+            }
+        }
+        return client.or(Some(peer));
+    }
+
+    headers
+        .get("x-real-ip")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().parse::<IpAddr>().ok())
+        .or(Some(peer))
+}
+
+fn canonical_ip(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => v6
+            .to_ipv4_mapped()
+            .map(IpAddr::V4)
+            .unwrap_or(IpAddr::V6(v6)),
+        other => other,
+    }
+}
+
+fn prefix_matches(network: &[u8], ip: &[u8], prefix: u8) -> bool {
+    let full_bytes = usize::from(prefix / 8);
+    let remaining_bits = prefix % 8;
+    if network[..full_bytes] != ip[..full_bytes] {
+        return false;
+    }
+    if remaining_bits == 0 {
+        return true;
+    }
+    let mask = 0xFF_u8 << (8 - remaining_bits);
+    network[full_bytes] & mask == ip[full_bytes] & mask
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+    use std::time::Duration;
+
+    use axum::http::{HeaderMap, HeaderValue};
+
+    use super::{
+        resolve_client_ip, EmailLoginLockouts, IpRateLimiter, RateLimiter, TrustedProxies,
+        MAX_TRACKED_LOGIN_EMAILS,
+    };
+
+    fn ip(value: &str) -> IpAddr {
+        value.parse().unwrap()
+    }
+
+    fn forwarded(value: &'static str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-forwarded-for", HeaderValue::from_static(value));
+        headers
+    }
+
+    #[tokio::test]
+    async fn local_rate_limiter_counts_each_key_separately() {
+        let limiter = RateLimiter::Local(IpRateLimiter::new(Duration::from_secs(60), 2));
+        assert!(limiter.allow("203.0.113.1").await);
+        assert!(limiter.allow("203.0.113.1").await);
+        assert!(!limiter.allow("203.0.113.1").await);
+        assert!(limiter.allow("203.0.113.2").await);
+    }
+
+    #[test]
+    fn trusted_proxy_ranges_match_cidr_prefixes() {
+        let trusted = TrustedProxies::parse("10.0.0.0/8, 192.168.1.7, fd00::/8, bogus, 1.2.3.4/40");
+        assert!(trusted.contains(ip("10.20.30.40")));
+        assert!(trusted.contains(ip("192.168.1.7")));
+        assert!(!trusted.contains(ip("192.168.1.8")));
+        assert!(trusted.contains(ip("fd12::1")));
+        assert!(trusted.contains(ip("::ffff:10.1.1.1")));
+        assert!(!trusted.contains(ip("1.2.3.4")));
+    }
+
+    #[test]
+    fn spoofed_forwarded_for_from_untrusted_peer_is_ignored() {
+        let trusted = TrustedProxies::parse("10.0.0.0/8");
+        let resolved = resolve_client_ip(
+            Some(ip("198.51.100.9")),
+            &forwarded("203.0.113.1"),
+            &trusted,
+        );
+        assert_eq!(resolved, Some(ip("198.51.100.9")));
+        assert_eq!(
+            resolve_client_ip(None, &forwarded("203.0.113.1"), &trusted),
+            None
+        );
+    }
+
+    #[test]
+    fn trusted_proxy_forwarded_for_resolves_first_untrusted_hop() {
+        let trusted = TrustedProxies::parse("10.0.0.0/8");
+        let resolved = resolve_client_ip(
+            Some(ip("10.0.0.2")),
+            &forwarded("6.6.6.6, 203.0.113.1, 10.0.0.5"),
+            &trusted,
+        );
+        assert_eq!(resolved, Some(ip("203.0.113.1")));
+
+        let mut real_ip = HeaderMap::new();
+        real_ip.insert("x-real-ip", HeaderValue::from_static("203.0.113.7"));
+        assert_eq!(
+            resolve_client_ip(Some(ip("10.0.0.2")), &real_ip, &trusted),
+            Some(ip("203.0.113.7"))
+        );
+
+        let garbage = resolve_client_ip(Some(ip("10.0.0.2")), &forwarded("not-an-ip"), &trusted);
+        assert_eq!(garbage, Some(ip("10.0.0.2")));
+    }
+
+    #[test]
+    fn email_lockouts_back_off_and_reset_on_success() {
+        let lockouts = EmailLoginLockouts::new(
+            2,
+            Duration::from_secs(60),
+            Duration::from_secs(30),
+            Duration::from_secs(100),
+        );
+        let start = std::time::Instant::now();
+        let at = |seconds: u64| start + Duration::from_secs(seconds);
+
+        assert_eq!(lockouts.record_attempt("dana@example.com", at(0)), None);
+        assert_eq!(lockouts.record_attempt("dana@example.com", at(1)), None);
+        assert_eq!(
+            lockouts.record_attempt("dana@example.com", at(2)),
+            Some(Duration::from_secs(30))
+        );
+        assert_eq!(
+            lockouts.locked_for("dana@example.com", at(12)),
+            Some(Duration::from_secs(20))
+        );
+        assert_eq!(lockouts.locked_for("other@example.com", at(12)), None);
+
+        for second in 40..42 {
+            assert_eq!(
+                lockouts.record_attempt("dana@example.com", at(second)),
+                None
+            );
+        }
+        assert_eq!(
+            lockouts.record_attempt("dana@example.com", at(42)),
+            Some(Duration::from_secs(60))
+        );
+        for second in 110..112 {
+            assert_eq!(
+                lockouts.record_attempt("dana@example.com", at(second)),
+                None
+            );
+        }
+        assert_eq!(
+            lockouts.record_attempt("dana@example.com", at(112)),
+            Some(Duration::from_secs(100))
+        );
+
+        lockouts.reset("dana@example.com");
+        assert_eq!(lockouts.locked_for("dana@example.com", at(113)), None);
+    }
+
+    #[test]
+    fn email_lockouts_stay_bounded_during_a_spray() {
+        let lockouts = EmailLoginLockouts::new(
+            1,
+            Duration::from_secs(600),
+            Duration::from_secs(60),
+            Duration::from_secs(60),
+        );
+        let start = std::time::Instant::now();
+        lockouts.record_attempt("dana@example.com", start);
+        lockouts.record_attempt("dana@example.com", start);
+        for index in 0..MAX_TRACKED_LOGIN_EMAILS + 50 {
+            lockouts.record_attempt(format!("spray-{index}@example.com").as_str(), start);
+        }
+
+        assert_eq!(lockouts.inner.lock().len(), MAX_TRACKED_LOGIN_EMAILS);
+        assert!(lockouts.locked_for("dana@example.com", start).is_some());
+    }
+}
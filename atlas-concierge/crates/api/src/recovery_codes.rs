@@ -0,0 +1,105 @@
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use rand::{rng, Rng, RngCore};
+use sha2::Sha256;
+
+// Crockford-style alphabet without 0/O and 1/I so codes survive being read aloud or copied
+// from paper.
+const ALPHABET: &[u8] = b"ABCDEFGHJKLMNPQRSTUVWXYZ23456789";
+const CODE_LEN: usize = 10;
+const GROUP_LEN: usize = 5;
+
+/// Returns a fresh code formatted as `XXXXX-XXXXX`.
+pub fn generate_recovery_code() -> String {
+    let mut generator = rng();
+    let mut code = String::with_capacity(CODE_LEN + 1);
+    for index in 0..CODE_LEN {
+        if index == GROUP_LEN {
+            code.push('-');
+        }
+        code.push(ALPHABET[generator.random_range(0..ALPHABET.len())] as char);
+    }
+    code
+}
+
+/// Uppercases and drops separators so `abcde fghjk` and `ABCDE-FGHJK` redeem the same code.
+pub fn normalize_recovery_code(input: &str) -> String {
+    input
+        .chars()
+        .filter(|ch| ch.is_ascii_alphanumeric())
+        .map(|ch| ch.to_ascii_uppercase())
+        .collect()
+}
+
+pub fn hash_recovery_code(code: &str) -> Option<String> {
+    let mut salt = [0_u8; 16];
+    rng().fill_bytes(&mut salt);
+    let salt = SaltString::encode_b64(&salt).ok()?;
+    Argon2::default()
+        .hash_password(normalize_recovery_code(code).as_bytes(), &salt)
+        .ok()
+        .map(|hash| hash.to_string())
+}
+
+/// Keyed lookup id for a code, so redeeming runs one argon2 verify instead of one per stored
+/// code. Without the key, a leaked fingerprint is no shortcut to the code.
+pub fn fingerprint_recovery_code(key: &str, code: &str) -> Option<String> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key.as_bytes()).ok()?;
+    mac.update(b"atlas-recovery-code");
+    mac.update(normalize_recovery_code(code).as_bytes());
+    Some(URL_SAFE_NO_PAD.encode(mac.finalize().into_bytes()))
+}
+
+/// Argon2 compares the derived output in constant time; a malformed stored hash never matches.
+pub fn verify_recovery_code(code: &str, stored_hash: &str) -> bool {
+    let Ok(parsed) = PasswordHash::new(stored_hash) else {
+        return false;
+    };
+    Argon2::default()
+        .verify_password(normalize_recovery_code(code).as_bytes(), &parsed)
+        .is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        fingerprint_recovery_code, generate_recovery_code, hash_recovery_code,
+        normalize_recovery_code, verify_recovery_code,
+    };
+
+    #[test]
+    fn recovery_codes_verify_only_against_their_own_hash() {
+        let code = generate_recovery_code();
+        assert_eq!(code.len(), 11);
+        assert_eq!(code.chars().nth(5), Some('-'));
+
+        let hash = hash_recovery_code(code.as_str()).expect("hash should be produced");
+        assert!(!hash.contains(normalize_recovery_code(code.as_str()).as_str()));
+        assert!(verify_recovery_code(
+            code.to_lowercase().replace('-', " ").as_str(),
+            &hash
+        ));
+        assert!(!verify_recovery_code("AAAAA-AAAAA", &hash));
+        assert!(!verify_recovery_code(code.as_str(), "not-a-hash"));
+    }
+
+    #[test]
+    fn fingerprints_ignore_formatting_and_depend_on_the_key() {
+        let code = generate_recovery_code();
+        let fingerprint = fingerprint_recovery_code("key", code.as_str());
+        assert_eq!(
+            fingerprint,
+            fingerprint_recovery_code("key", code.to_lowercase().replace('-', " ").as_str())
+        );
+        assert_ne!(
+            fingerprint,
+            fingerprint_recovery_code("other", code.as_str())
+        );
+        assert!(!fingerprint
+            .unwrap()
+            .contains(normalize_recovery_code(code.as_str()).as_str()));
+    }
+}
@@ -0,0 +1,450 @@
+use anyhow::{Context, Result};
+use sqlx::{Row, Sqlite, SqlitePool, Transaction};
+
+/// A single change inside a migration.
+pub enum MigrationStep {
+    /// Plain SQL, run as-is against a database at the previous version.
+    Sql(&'static str),
+    /// `ALTER TABLE ... ADD COLUMN`, skipped when the column already exists. Databases that
+    /// predate versioning were patched with best-effort ALTERs, so some of them already carry
+    /// columns that a numbered migration adds.
+    AddColumn {
+        table: &'static str,
+        column: &'static str,
+        definition: &'static str,
+    },
+}
+
+pub struct Migration {
+    pub version: i64,
+    pub description: &'static str,
+    pub steps: &'static [MigrationStep],
+}
+
+/// Ordered schema changes applied on top of the baseline tables created in
+/// `ensure_app_schema`. Append new entries with the next version number; never edit or
+/// reorder an entry that has shipped.
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        description: "session location hints",
+        steps: &[
+            MigrationStep::AddColumn {
+                table: "auth_sessions",
+                column: "client_network",
+                definition: "TEXT",
+            },
+            MigrationStep::AddColumn {
+                table: "auth_sessions",
+                column: "region",
+                definition: "TEXT",
+            },
+        ],
+    },
+    Migration {
+        version: 2,
+        description: "user login tracking",
+        steps: &[
+            MigrationStep::AddColumn {
+                table: "auth_users",
+                column: "last_login_at",
+                definition: "TEXT",
+            },
+            MigrationStep::AddColumn {
+                table: "auth_users",
+                column: "login_count",
+                definition: "INTEGER NOT NULL DEFAULT 0",
+            },
+        ],
+    },
+    // Per-user tables are rewritten with `DELETE ... WHERE user_id = ?`. `billing_subscriptions`,
+    // `studio_preferences`, `survey_states`, `execution_controls` and `usage_counters` are keyed
+    // by `user_id` already.
+    Migration {
+        version: 3,
+        description: "user_id indexes on per-user tables",
+        steps: &[
+            MigrationStep::Sql(
+                "CREATE INDEX IF NOT EXISTS idx_auth_sessions_user_id ON auth_sessions (user_id)",
+            ),
+            MigrationStep::Sql(
+                "CREATE INDEX IF NOT EXISTS idx_user_notes_user_id ON user_notes (user_id)",
+            ),
+            MigrationStep::Sql(
+                "CREATE INDEX IF NOT EXISTS idx_user_memories_user_id ON user_memories (user_id)",
+            ),
+            MigrationStep::Sql(
+                "CREATE INDEX IF NOT EXISTS idx_chat_turns_user_id ON chat_turns (user_id)",
+            ),
+            MigrationStep::Sql(
+                "CREATE INDEX IF NOT EXISTS idx_execution_checkins_user_id ON execution_checkins (user_id)",
+            ),
+            MigrationStep::Sql(
+                "CREATE INDEX IF NOT EXISTS idx_passkeys_user_id ON passkeys (user_id)",
+            ),
+            MigrationStep::Sql(
+                "CREATE INDEX IF NOT EXISTS idx_recovery_codes_user_id ON recovery_codes (user_id)",
+            ),
+            MigrationStep::Sql(
+                "CREATE INDEX IF NOT EXISTS idx_linked_identities_user_id ON linked_identities (user_id)",
+            ),
+        ],
+    },
+    Migration {
+        version: 4,
+        description: "shared auth challenges",
+        steps: &[MigrationStep::Sql(
+            r#"
+            CREATE TABLE IF NOT EXISTS auth_challenges (
+              kind TEXT NOT NULL,
+              challenge_id TEXT NOT NULL,
+              data_json TEXT NOT NULL,
+              expires_at TEXT NOT NULL,
+              PRIMARY KEY (kind, challenge_id)
+            );
+            "#,
+        )],
+    },
+    Migration {
+        version: 5,
+        description: "note version history",
+        steps: &[
+            MigrationStep::Sql(
+                r#"
+                CREATE TABLE IF NOT EXISTS note_versions (
+                  version_id TEXT PRIMARY KEY,
+                  note_id TEXT NOT NULL,
+                  user_id TEXT NOT NULL,
+                  data_json TEXT NOT NULL
+                );
+                "#,
+            ),
+            MigrationStep::Sql(
+                "CREATE INDEX IF NOT EXISTS idx_note_versions_user_id ON note_versions (user_id)",
+            ),
+        ],
+    },
+    // Generated once and shared by every instance on the same database, so keyed lookups keep
+    // working across restarts.
+    Migration {
+        version: 6,
+        description: "server-generated secrets",
+        steps: &[MigrationStep::Sql(
+            r#"
+            CREATE TABLE IF NOT EXISTS app_secrets (
+              name TEXT PRIMARY KEY,
+              value TEXT NOT NULL
+            );
+            "#,
+        )],
+    },
+    Migration {
+        version: 7,
+        description: "memory trash",
+        steps: &[
+            MigrationStep::Sql(
+                r#"
+                CREATE TABLE IF NOT EXISTS deleted_memories (
+                  restore_id TEXT NOT NULL,
+                  memory_id TEXT NOT NULL,
+                  user_id TEXT NOT NULL,
+                  deleted_at TEXT NOT NULL,
+                  data_json TEXT NOT NULL,
+                  PRIMARY KEY (restore_id, memory_id)
+                );
+                "#,
+            ),
+            MigrationStep::Sql(
+                "CREATE INDEX IF NOT EXISTS idx_deleted_memories_user_id ON deleted_memories (user_id)",
+            ),
+        ],
+    },
+    Migration {
+        version: 8,
+        description: "notes revisions",
+        steps: &[MigrationStep::Sql(
+            r#"
+            CREATE TABLE IF NOT EXISTS notes_revisions (
+              user_id TEXT PRIMARY KEY,
+              revision INTEGER NOT NULL,
+              modified_at TEXT NOT NULL
+            );
+            "#,
+        )],
+    },
+];
+
+/// Brings the database up to the latest migration and returns the resulting version. Each
+/// migration runs in its own transaction together with its `schema_version` row, so a failed
+/// step leaves the database at the last fully applied version.
+pub async fn apply_migrations(pool: &SqlitePool, migrations: &[Migration]) -> Result<i64> {
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS schema_version (
+          version INTEGER PRIMARY KEY,
+          description TEXT NOT NULL,
+          applied_at TEXT NOT NULL
+        );
+        "#,
+    )
+    .execute(pool)
+    .await?;
+
+    let mut current = current_version(pool).await?;
+    for migration in migrations {
+        if migration.version <= current {
+            continue;
+        }
+        anyhow::ensure!(
+            migration.version == current + 1,
+            "schema migration {} does not follow version {current}",
+            migration.version
+        );
+
+        let mut tx = pool.begin().await?;
+        for step in migration.steps {
+            apply_step(&mut tx, step).await.with_context(|| {
+                format!(
+                    "schema migration {} ({}) failed",
+                    migration.version, migration.description
+                )
+            })?;
+        }
+        sqlx::query(
+            "INSERT INTO schema_version (version, description, applied_at) VALUES (?1, ?2, ?3)",
+        )
+        .bind(migration.version)
+        .bind(migration.description)
+        .bind(chrono::Utc::now().to_rfc3339())
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+
+        tracing::info!(
+            version = migration.version,
+            description = migration.description,
+            "applied schema migration"
+        );
+        current = migration.version;
+    }
+    Ok(current)
+}
+
+pub async fn current_version(pool: &SqlitePool) -> Result<i64> {
+    let row = sqlx::query("SELECT COALESCE(MAX(version), 0) AS version FROM schema_version")
+        .fetch_one(pool)
+        .await?;
+    Ok(row.get("version"))
+}
+
+/// Postgres counterpart of [`apply_migrations`], with the same `schema_version` bookkeeping.
+#[cfg(feature = "postgres")]
+pub async fn apply_postgres_migrations(
+    pool: &sqlx::PgPool,
+    migrations: &[Migration],
+) -> Result<i64> {
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS schema_version (
+          version BIGINT PRIMARY KEY,
+          description TEXT NOT NULL,
+          applied_at TEXT NOT NULL
+        );
+        "#,
+    )
+    .execute(pool)
+    .await?;
+
+    let mut current: i64 =
+        sqlx::query("SELECT COALESCE(MAX(version), 0) AS version FROM schema_version")
+            .fetch_one(pool)
+            .await?
+            .get("version");
+    for migration in migrations {
+        if migration.version <= current {
+            continue;
+        }
+        anyhow::ensure!(
+            migration.version == current + 1,
+            "schema migration {} does not follow version {current}",
+            migration.version
+        );
+
+        let mut tx = pool.begin().await?;
+        for step in migration.steps {
+            let sql = match step {
+                MigrationStep::Sql(sql) => sql.to_string(),
+                MigrationStep::AddColumn {
+                    table,
+                    column,
+                    definition,
+                } => format!("ALTER TABLE {table} ADD COLUMN IF NOT EXISTS {column} {definition}"),
+            };
+            sqlx::query(sql.as_str())
+                .execute(&mut *tx)
+                .await
+                .with_context(|| {
+                    format!(
+                        "schema migration {} ({}) failed",
+                        migration.version, migration.description
+                    )
+                })?;
+        }
+        sqlx::query(
+            "INSERT INTO schema_version (version, description, applied_at) VALUES ($1, $2, $3)",
+        )
+        .bind(migration.version)
+        .bind(migration.description)
+        .bind(chrono::Utc::now().to_rfc3339())
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+
+        tracing::info!(
+            version = migration.version,
+            description = migration.description,
+            "applied postgres schema migration"
+        );
+        current = migration.version;
+    }
+    Ok(current)
+}
+
+async fn apply_step(tx: &mut Transaction<'_, Sqlite>, step: &MigrationStep) -> Result<()> {
+    match step {
+        MigrationStep::Sql(sql) => {
+            sqlx::query(sql).execute(&mut **tx).await?;
+        }
+        MigrationStep::AddColumn {
+            table,
+            column,
+            definition,
+        } => {
+            let existing =
+                sqlx::query("SELECT COUNT(*) AS count FROM pragma_table_info(?1) WHERE name = ?2")
+                    .bind(table)
+                    .bind(column)
+                    .fetch_one(&mut **tx)
+                    .await?
+                    .get::<i64, _>("count");
+            if existing == 0 {
+                sqlx::query(
+                    format!("ALTER TABLE {table} ADD COLUMN {column} {definition}").as_str(),
+                )
+                .execute(&mut **tx)
+                .await?;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use sqlx::sqlite::SqlitePoolOptions;
+    use sqlx::{Row, SqlitePool};
+
+    use super::{apply_migrations, current_version, Migration, MigrationStep};
+
+    async fn memory_pool() -> SqlitePool {
+        SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect("sqlite::memory:")
+            .await
+            .unwrap()
+    }
+
+    const TEST_MIGRATIONS: &[Migration] = &[
+        Migration {
+            version: 1,
+            description: "widgets",
+            steps: &[MigrationStep::Sql(
+                "CREATE TABLE widgets (widget_id TEXT PRIMARY KEY)",
+            )],
+        },
+        Migration {
+            version: 2,
+            description: "widget names",
+            steps: &[MigrationStep::AddColumn {
+                table: "widgets",
+                column: "name",
+                definition: "TEXT",
+            }],
+        },
+    ];
+
+    #[tokio::test]
+    async fn migrations_apply_in_order_once() {
+        let pool = memory_pool().await;
+        assert_eq!(apply_migrations(&pool, TEST_MIGRATIONS).await.unwrap(), 2);
+        assert_eq!(apply_migrations(&pool, TEST_MIGRATIONS).await.unwrap(), 2);
+
+        let applied = sqlx::query("SELECT COUNT(*) AS count FROM schema_version")
+            .fetch_one(&pool)
+            .await
+            .unwrap()
+            .get::<i64, _>("count");
+        assert_eq!(applied, 2);
+        sqlx::query("INSERT INTO widgets (widget_id, name) VALUES ('w1', 'first')")
+            .execute(&pool)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn add_column_skips_columns_patched_in_before_versioning() {
+        let pool = memory_pool().await;
+        apply_migrations(&pool, &TEST_MIGRATIONS[..1])
+            .await
+            .unwrap();
+        sqlx::query("ALTER TABLE widgets ADD COLUMN name TEXT")
+            .execute(&pool)
+            .await
+            .unwrap();
+        assert_eq!(apply_migrations(&pool, TEST_MIGRATIONS).await.unwrap(), 2);
+    }
+
+    #[tokio::test]
+    async fn migrations_with_version_gaps_are_rejected() {
+        let pool = memory_pool().await;
+        assert_eq!(
+            apply_migrations(&pool, &TEST_MIGRATIONS[1..])
+                .await
+                .unwrap_err()
+                .to_string(),
+            "schema migration 2 does not follow version 0"
+        );
+    }
+
+    #[tokio::test]
+    async fn failed_migration_rolls_back_to_previous_version() {
+        let pool = memory_pool().await;
+        let broken = [
+            Migration {
+                version: 1,
+                description: "widgets",
+                steps: &[MigrationStep::Sql(
+                    "CREATE TABLE widgets (widget_id TEXT PRIMARY KEY)",
+                )],
+            },
+            Migration {
+                version: 2,
+                description: "broken",
+                steps: &[
+                    MigrationStep::Sql("CREATE TABLE gadgets (gadget_id TEXT PRIMARY KEY)"),
+                    MigrationStep::Sql("ALTER TABLE missing_table ADD COLUMN name TEXT"),
+                ],
+            },
+        ];
+        assert!(apply_migrations(&pool, &broken).await.is_err());
+        assert_eq!(current_version(&pool).await.unwrap(), 1);
+        let gadgets = sqlx::query(
+            "SELECT COUNT(*) AS count FROM sqlite_master WHERE type = 'table' AND name = 'gadgets'",
+        )
+        .fetch_one(&pool)
+        .await
+        .unwrap()
+        .get::<i64, _>("count");
+        assert_eq!(gadgets, 0);
+    }
+}
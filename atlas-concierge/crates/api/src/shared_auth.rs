@@ -0,0 +1,334 @@
+//! Database-backed auth state for multi-instance deployments. When enabled, the `auth_sessions`
+//! table is the source of truth for sessions and the in-process map is only a per-request
+//! cache; OAuth states and passkey challenges are written to `auth_challenges` so a flow
+//! started on one instance can finish on another. Queries use `$N` placeholders, which both
+//! SQLite and Postgres accept.
+
+use anyhow::Result;
+use sqlx::{Row, SqlitePool};
+
+use crate::{SessionRecord, UserRecord};
+
+pub(crate) const CHALLENGE_OAUTH: &str = "oauth";
+pub(crate) const CHALLENGE_PASSKEY_REGISTRATION: &str = "passkey_registration";
+pub(crate) const CHALLENGE_PASSKEY_AUTHENTICATION: &str = "passkey_authentication";
+
+#[derive(Clone)]
+pub(crate) enum SharedAuthStore {
+    Sqlite(SqlitePool),
+    #[cfg(feature = "postgres")]
+    Postgres(sqlx::PgPool),
+}
+
+// The query text is identical for both backends; only the pool type differs.
+macro_rules! with_pool {
+    ($store:expr, $pool:ident => $body:expr) => {
+        match $store {
+            SharedAuthStore::Sqlite($pool) => $body,
+            #[cfg(feature = "postgres")]
+            SharedAuthStore::Postgres($pool) => $body,
+        }
+    };
+}
+
+fn session_from_parts(
+    user_id: String,
+    expires_at: String,
+    created_at: String,
+    client_network: Option<String>,
+    region: Option<String>,
+) -> SessionRecord {
+    SessionRecord {
+        user_id,
+        expires_at: expires_at.parse().unwrap_or_else(|_| chrono::Utc::now()),
+        created_at: created_at.parse().unwrap_or_else(|_| chrono::Utc::now()),
+        client_network,
+        region,
+    }
+}
+
+impl SharedAuthStore {
+    pub(crate) async fn load_session(&self, session_id: &str) -> Result<Option<SessionRecord>> {
+        let sql = "SELECT user_id, expires_at, created_at, client_network, region FROM auth_sessions WHERE session_id = $1";
+        let session = with_pool!(self, pool => sqlx::query(sql)
+        .bind(session_id)
+        .fetch_optional(pool)
+        .await?
+        .map(|row| {
+            session_from_parts(
+                row.get("user_id"),
+                row.get("expires_at"),
+                row.get("created_at"),
+                row.get("client_network"),
+                row.get("region"),
+            )
+        }));
+        Ok(session)
+    }
+
+    pub(crate) async fn sessions_for_user(
+        &self,
+        user_id: &str,
+    ) -> Result<Vec<(String, SessionRecord)>> {
+        let sql = "SELECT session_id, user_id, expires_at, created_at, client_network, region FROM auth_sessions WHERE user_id = $1";
+        let sessions = with_pool!(self, pool => sqlx::query(sql)
+            .bind(user_id)
+            .fetch_all(pool)
+            .await?
+            .into_iter()
+            .map(|row| {
+                (
+                    row.get("session_id"),
+                    session_from_parts(
+                        row.get("user_id"),
+                        row.get("expires_at"),
+                        row.get("created_at"),
+                        row.get("client_network"),
+                        row.get("region"),
+                    ),
+                )
+            })
+            .collect());
+        Ok(sessions)
+    }
+
+    pub(crate) async fn upsert_session(
+        &self,
+        session_id: &str,
+        session: &SessionRecord,
+    ) -> Result<()> {
+        let sql = r#"
+            INSERT INTO auth_sessions (session_id, user_id, expires_at, created_at, client_network, region)
+            VALUES ($1, $2, $3, $4, $5, $6)
+            ON CONFLICT(session_id) DO UPDATE SET expires_at=excluded.expires_at
+        "#;
+        with_pool!(self, pool => {
+            sqlx::query(sql)
+                .bind(session_id)
+                .bind(session.user_id.as_str())
+                .bind(session.expires_at.to_rfc3339())
+                .bind(session.created_at.to_rfc3339())
+                .bind(session.client_network.as_deref())
+                .bind(session.region.as_deref())
+                .execute(pool)
+                .await?;
+        });
+        Ok(())
+    }
+
+    pub(crate) async fn delete_session(&self, session_id: &str) -> Result<()> {
+        let sql = "DELETE FROM auth_sessions WHERE session_id = $1";
+        with_pool!(self, pool => {
+            sqlx::query(sql).bind(session_id).execute(pool).await?;
+        });
+        Ok(())
+    }
+
+    /// Loads a user created on another instance after this one started. `memory_opt_in` is an
+    /// INTEGER in SQLite and a BOOLEAN in Postgres, so it is normalised in SQL.
+    pub(crate) async fn load_user(&self, user_id: &str) -> Result<Option<UserRecord>> {
+        let sql = r#"
+            SELECT user_id, provider, email, name, locale, trip_style, risk_preference,
+              CAST(CASE WHEN memory_opt_in THEN 1 ELSE 0 END AS BIGINT) AS memory_opt_in,
+              passkey_user_handle, created_at, updated_at, last_login_at, login_count
+            FROM auth_users
+            WHERE user_id = $1
+        "#;
+        let user = with_pool!(self, pool => sqlx::query(sql)
+        .bind(user_id)
+        .fetch_optional(pool)
+        .await?
+        .map(|row| UserRecord {
+            user_id: row.get("user_id"),
+            provider: row.get("provider"),
+            email: row.get("email"),
+            name: row.get("name"),
+            locale: row.get("locale"),
+            trip_style: row.get("trip_style"),
+            risk_preference: row.get("risk_preference"),
+            memory_opt_in: row.get::<i64, _>("memory_opt_in") > 0,
+            passkey_user_handle: row.get("passkey_user_handle"),
+            created_at: row.get("created_at"),
+            updated_at: row.get("updated_at"),
+            last_login_at: row.get("last_login_at"),
+            login_count: row.get::<i64, _>("login_count").max(0) as u64,
+        }));
+        Ok(user)
+    }
+
+    /// Ids of every account carrying `email`, including ones created on other instances.
+    pub(crate) async fn user_ids_for_email(&self, email: &str) -> Result<Vec<String>> {
+        let sql = "SELECT user_id FROM auth_users WHERE email = $1 ORDER BY created_at";
+        let user_ids = with_pool!(self, pool => sqlx::query(sql)
+            .bind(email)
+            .fetch_all(pool)
+            .await?
+            .into_iter()
+            .map(|row| row.get::<String, _>("user_id"))
+            .collect());
+        Ok(user_ids)
+    }
+
+    /// Serialized passkey records of one user, as written by `persist_passkeys_if_configured`.
+    pub(crate) async fn load_passkeys(&self, user_id: &str) -> Result<Vec<String>> {
+        let sql = "SELECT data_json FROM passkeys WHERE user_id = $1";
+        let passkeys = with_pool!(self, pool => sqlx::query(sql)
+            .bind(user_id)
+            .fetch_all(pool)
+            .await?
+            .into_iter()
+            .map(|row| row.get::<String, _>("data_json"))
+            .collect());
+        Ok(passkeys)
+    }
+
+    /// Serialized recovery-code records of one user, as written by
+    /// `persist_recovery_codes_if_configured`.
+    pub(crate) async fn load_recovery_codes(&self, user_id: &str) -> Result<Vec<String>> {
+        let sql = "SELECT data_json FROM recovery_codes WHERE user_id = $1";
+        let codes = with_pool!(self, pool => sqlx::query(sql)
+            .bind(user_id)
+            .fetch_all(pool)
+            .await?
+            .into_iter()
+            .map(|row| row.get::<String, _>("data_json"))
+            .collect());
+        Ok(codes)
+    }
+
+    pub(crate) async fn put_challenge(
+        &self,
+        kind: &str,
+        challenge_id: &str,
+        data_json: &str,
+        expires_at: chrono::DateTime<chrono::Utc>,
+    ) -> Result<()> {
+        let now = chrono::Utc::now().to_rfc3339();
+        let purge = "DELETE FROM auth_challenges WHERE expires_at < $1";
+        let insert = "INSERT INTO auth_challenges (kind, challenge_id, data_json, expires_at) VALUES ($1, $2, $3, $4)";
+        with_pool!(self, pool => {
+            sqlx::query(purge).bind(now.as_str()).execute(pool).await?;
+            sqlx::query(insert)
+                .bind(kind)
+                .bind(challenge_id)
+                .bind(data_json)
+                .bind(expires_at.to_rfc3339())
+                .execute(pool)
+                .await?;
+        });
+        Ok(())
+    }
+
+    /// Removes and returns a challenge in one statement, so concurrent finishes on different
+    /// instances cannot both consume it.
+    pub(crate) async fn take_challenge(
+        &self,
+        kind: &str,
+        challenge_id: &str,
+    ) -> Result<Option<String>> {
+        let sql =
+            "DELETE FROM auth_challenges WHERE kind = $1 AND challenge_id = $2 RETURNING data_json";
+        let data = with_pool!(self, pool => sqlx::query(sql)
+            .bind(kind)
+            .bind(challenge_id)
+            .fetch_optional(pool)
+            .await?
+            .map(|row| row.get::<String, _>("data_json")));
+        Ok(data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use sqlx::sqlite::SqlitePoolOptions;
+
+    use super::{SharedAuthStore, CHALLENGE_OAUTH};
+    use crate::SessionRecord;
+
+    async fn store() -> SharedAuthStore {
+        let pool = SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect("sqlite::memory:")
+            .await
+            .unwrap();
+        crate::ensure_app_schema(&pool).await.unwrap();
+        SharedAuthStore::Sqlite(pool)
+    }
+
+    #[tokio::test]
+    async fn sessions_round_trip_and_refresh_only_moves_expiry() {
+        let store = store().await;
+        let created_at = chrono::Utc::now();
+        let mut session = SessionRecord {
+            user_id: "u1".to_string(),
+            expires_at: created_at + chrono::Duration::hours(1),
+            created_at,
+            client_network: Some("203.0.113.0/24".to_string()),
+            region: Some("IL".to_string()),
+        };
+        store.upsert_session("s1", &session).await.unwrap();
+        session.expires_at = created_at + chrono::Duration::hours(5);
+        session.user_id = "someone-else".to_string();
+        store.upsert_session("s1", &session).await.unwrap();
+
+        let loaded = store.load_session("s1").await.unwrap().unwrap();
+        assert_eq!(loaded.user_id, "u1");
+        assert_eq!(
+            loaded.expires_at.timestamp(),
+            session.expires_at.timestamp()
+        );
+        assert_eq!(loaded.region.as_deref(), Some("IL"));
+        assert_eq!(store.sessions_for_user("u1").await.unwrap().len(), 1);
+
+        store.delete_session("s1").await.unwrap();
+        assert!(store.load_session("s1").await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn challenges_are_single_use_and_expired_ones_are_purged() {
+        let store = store().await;
+        let now = chrono::Utc::now();
+        store
+            .put_challenge(
+                CHALLENGE_OAUTH,
+                "stale",
+                "{}",
+                now - chrono::Duration::minutes(1),
+            )
+            .await
+            .unwrap();
+        store
+            .put_challenge(
+                CHALLENGE_OAUTH,
+                "fresh",
+                "{\"a\":1}",
+                now + chrono::Duration::minutes(5),
+            )
+            .await
+            .unwrap();
+
+        assert!(store
+            .take_challenge("passkey_registration", "fresh")
+            .await
+            .unwrap()
+            .is_none());
+        assert_eq!(
+            store
+                .take_challenge(CHALLENGE_OAUTH, "fresh")
+                .await
+                .unwrap()
+                .as_deref(),
+            Some("{\"a\":1}")
+        );
+        assert!(store
+            .take_challenge(CHALLENGE_OAUTH, "fresh")
+            .await
+            .unwrap()
+            .is_none());
+        assert!(store
+            .take_challenge(CHALLENGE_OAUTH, "stale")
+            .await
+            .unwrap()
+            .is_none());
+    }
+}
@@ -0,0 +1,26 @@
+/// True when `needle` occurs in `haystack` at the start of a word, so "now" does not match
+/// "know" and "plan" does not match "airplane".
+pub fn contains_word_prefix(haystack: &str, needle: &str) -> bool {
+    haystack.match_indices(needle).any(|(index, _)| {
+        haystack[..index]
+            .chars()
+            .next_back()
+            .map(|previous| !previous.is_alphanumeric())
+            .unwrap_or(true)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::contains_word_prefix;
+
+    #[test]
+    fn word_prefixes_match_only_at_word_starts() {
+        assert!(contains_word_prefix("plan my trip", "plan"));
+        assert!(contains_word_prefix("my plans, mostly", "plan"));
+        assert!(contains_word_prefix("(now)", "now"));
+        assert!(!contains_word_prefix("the airplane", "plan"));
+        assert!(!contains_word_prefix("i know", "now"));
+        assert!(contains_word_prefix("i know, now", "now"));
+    }
+}
@@ -155,6 +155,7 @@ async fn run_chat(agent: ConciergeAgent<Store>) -> Result<()> {
                 text: message.to_string(),
                 locale: None,
                 user_id: None,
+                context_turns: Vec::new(),
             })
             .await?;
 
@@ -191,7 +192,7 @@ async fn build_agent(kb_root: &PathBuf) -> Result<ConciergeAgent<Store>> {
     );
 
     let store = if let Ok(database_url) = env::var("ATLAS_DATABASE_URL") {
-        Store::sqlite(&database_url).await?
+        Store::connect(&database_url).await?
     } else {
         Store::memory()
     };
@@ -1,4 +1,4 @@
-use crate::models::{Intent, Locale};
+use crate::models::{ChatContextTurn, Intent, Locale};
 
 pub fn normalize_text(input: &str) -> String {
     input
@@ -170,6 +170,26 @@ pub fn needs_clarification(intent: Intent, text: &str) -> bool {
     }
 }
 
+/// Folds the most recent prior user turn into a follow-up message that carries
+/// no intent of its own, so "and the second option?" resolves against the
+/// earlier request.
+pub fn contextualize_follow_up(text: &str, context_turns: &[ChatContextTurn]) -> String {
+    let Some(previous) = context_turns
+        .iter()
+        .rev()
+        .map(|turn| normalize_text(&turn.user_text))
+        .find(|value| !value.is_empty())
+    else {
+        return text.to_string();
+    };
+
+    if classify_intent_rules(text) != Intent::Unknown {
+        return text.to_string();
+    }
+
+    format!("{previous} {text}")
+}
+
 fn contains_any(input: &str, needles: &[&str]) -> bool {
     needles.iter().any(|needle| input.contains(needle))
 }
@@ -183,6 +203,25 @@ mod tests {
         assert_eq!(detect_locale(None, "אני רוצה מסלול לחוף"), Locale::He);
     }
 
+    #[test]
+    fn follow_up_inherits_previous_turn_context() {
+        let context = vec![ChatContextTurn {
+            user_text: "plan a beach trip for the weekend".to_string(),
+            assistant_text: "Option 1: Haifa coast. Option 2: Dor beach.".to_string(),
+        }];
+
+        let resolved = contextualize_follow_up("and the second option?", &context);
+        assert_eq!(classify_intent_rules(&resolved), Intent::TripPlanning);
+        assert_eq!(
+            contextualize_follow_up("how much does it cost?", &context),
+            "how much does it cost?"
+        );
+        assert_eq!(
+            contextualize_follow_up("and the second option?", &[]),
+            "and the second option?"
+        );
+    }
+
     #[test]
     fn classifies_pricing() {
         assert_eq!(classify_intent_rules("כמה עולה מנוי מסע?"), Intent::Pricing);
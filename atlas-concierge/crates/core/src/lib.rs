@@ -3,7 +3,7 @@ pub mod models;
 pub mod planner;
 pub mod policy;
 
-pub use intent::{classify_intent_rules, detect_locale, normalize_text};
+pub use intent::{classify_intent_rules, contextualize_follow_up, detect_locale, normalize_text};
 pub use models::*;
 pub use planner::{build_ops_checklist, build_trip_plan, compose_chat_reply};
 pub use policy::{PolicyEngine, PolicyGateResult, PolicyViolation};
@@ -172,12 +172,20 @@ pub struct ConversationSession {
     pub turns: Vec<ConversationTurn>,
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChatContextTurn {
+    pub user_text: String,
+    pub assistant_text: String,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ChatInput {
     pub session_id: Option<String>,
     pub text: String,
     pub locale: Option<String>,
     pub user_id: Option<String>,
+    #[serde(default)]
+    pub context_turns: Vec<ChatContextTurn>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -42,6 +42,7 @@ impl IntentClassifier for RuleIntentClassifier {
 pub struct AtlasMlStack {
     pub embedder: Arc<dyn EmbeddingModel>,
     pub classifier: Arc<dyn IntentClassifier>,
+    pub classifier_model: &'static str,
     pub burn_enabled: bool,
 }
 
@@ -53,20 +54,28 @@ impl AtlasMlStack {
         #[cfg(feature = "burn-ml")]
         {
             let embedder = Arc::new(burn_impl::BurnHashEmbeddingModel::new(192));
-            let classifier: Arc<dyn IntentClassifier> = if Path::new(&dataset_path).exists() {
-                CentroidIntentClassifier::from_jsonl(
-                    &dataset_path,
-                    embedder.clone(),
-                    "burn-centroid-intent",
-                )
-                .map(|clf| Arc::new(clf) as Arc<dyn IntentClassifier>)
-                .unwrap_or_else(|_| Arc::new(burn_impl::BurnKeywordIntentClassifier::new(192)))
-            } else {
-                Arc::new(burn_impl::BurnKeywordIntentClassifier::new(192))
+            let centroid = Path::new(&dataset_path)
+                .exists()
+                .then(|| {
+                    CentroidIntentClassifier::from_jsonl(
+                        &dataset_path,
+                        embedder.clone(),
+                        "burn-centroid-intent",
+                    )
+                    .ok()
+                })
+                .flatten();
+            let (classifier, classifier_model): (Arc<dyn IntentClassifier>, _) = match centroid {
+                Some(clf) => (Arc::new(clf), "burn-centroid-intent"),
+                None => (
+                    Arc::new(burn_impl::BurnKeywordIntentClassifier::new(192)),
+                    "burn-keyword-intent",
+                ),
             };
             Self {
                 embedder,
                 classifier,
+                classifier_model,
                 burn_enabled: true,
             }
         }
@@ -74,20 +83,25 @@ impl AtlasMlStack {
         #[cfg(not(feature = "burn-ml"))]
         {
             let embedder = Arc::new(HashEmbeddingModel::new(192));
-            let classifier: Arc<dyn IntentClassifier> = if Path::new(&dataset_path).exists() {
-                CentroidIntentClassifier::from_jsonl(
-                    &dataset_path,
-                    embedder.clone(),
-                    "fallback-centroid-intent",
-                )
-                .map(|clf| Arc::new(clf) as Arc<dyn IntentClassifier>)
-                .unwrap_or_else(|_| Arc::new(RuleIntentClassifier))
-            } else {
-                Arc::new(RuleIntentClassifier)
+            let centroid = Path::new(&dataset_path)
+                .exists()
+                .then(|| {
+                    CentroidIntentClassifier::from_jsonl(
+                        &dataset_path,
+                        embedder.clone(),
+                        "fallback-centroid-intent",
+                    )
+                    .ok()
+                })
+                .flatten();
+            let (classifier, classifier_model): (Arc<dyn IntentClassifier>, _) = match centroid {
+                Some(clf) => (Arc::new(clf), "fallback-centroid-intent"),
+                None => (Arc::new(RuleIntentClassifier), "rules"),
             };
             Self {
                 embedder,
                 classifier,
+                classifier_model,
                 burn_enabled: false,
             }
         }
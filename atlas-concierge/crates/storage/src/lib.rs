@@ -247,10 +247,185 @@ impl InventoryRepository for SqliteStore {
     }
 }
 
+#[cfg(feature = "postgres")]
+#[derive(Clone)]
+pub struct PostgresStore {
+    pool: sqlx::PgPool,
+}
+
+#[cfg(feature = "postgres")]
+impl PostgresStore {
+    pub async fn connect(database_url: &str) -> Result<Self> {
+        let pool = sqlx::PgPool::connect(database_url)
+            .await
+            .context("failed connecting to postgres")?;
+
+        let store = Self { pool };
+        store.ensure_schema().await?;
+        Ok(store)
+    }
+
+    pub fn pool(&self) -> &sqlx::PgPool {
+        &self.pool
+    }
+
+    async fn ensure_schema(&self) -> Result<()> {
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS sessions (
+              session_id TEXT PRIMARY KEY,
+              user_id TEXT,
+              locale TEXT NOT NULL,
+              expires_at TEXT NOT NULL,
+              turns_json TEXT NOT NULL
+            );
+            "#,
+        )
+        .execute(&self.pool)
+        .await?;
+
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS inventory_items (
+              sku TEXT PRIMARY KEY,
+              name TEXT NOT NULL,
+              quantity INTEGER NOT NULL,
+              minimum_required INTEGER NOT NULL
+            );
+            "#,
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+}
+
+#[cfg(feature = "postgres")]
+impl SessionRepository for PostgresStore {
+    async fn load_session(&self, session_id: &str) -> Result<Option<ConversationSession>> {
+        let row = sqlx::query(
+            r#"
+            SELECT session_id, user_id, locale, expires_at, turns_json
+            FROM sessions
+            WHERE session_id = $1
+            "#,
+        )
+        .bind(session_id)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        let Some(row) = row else {
+            return Ok(None);
+        };
+
+        let locale = Locale::from_optional_str(Some(row.get::<String, _>("locale").as_str()));
+        let turns_json: String = row.get("turns_json");
+        let turns = serde_json::from_str(&turns_json).unwrap_or_default();
+
+        let session = ConversationSession {
+            session_id: row.get("session_id"),
+            user_id: row.get("user_id"),
+            locale,
+            expires_at: row
+                .get::<String, _>("expires_at")
+                .parse()
+                .unwrap_or_else(|_| Utc::now()),
+            turns,
+        };
+
+        Ok(Some(session))
+    }
+
+    async fn upsert_session(&self, session: &ConversationSession) -> Result<()> {
+        let turns_json = serde_json::to_string(&session.turns)?;
+
+        sqlx::query(
+            r#"
+            INSERT INTO sessions (session_id, user_id, locale, expires_at, turns_json)
+            VALUES ($1, $2, $3, $4, $5)
+            ON CONFLICT(session_id) DO UPDATE SET
+              user_id=excluded.user_id,
+              locale=excluded.locale,
+              expires_at=excluded.expires_at,
+              turns_json=excluded.turns_json
+            "#,
+        )
+        .bind(&session.session_id)
+        .bind(&session.user_id)
+        .bind(session.locale.as_code())
+        .bind(session.expires_at.to_rfc3339())
+        .bind(turns_json)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    async fn purge_expired(&self, now: DateTime<Utc>) -> Result<u64> {
+        let result = sqlx::query("DELETE FROM sessions WHERE expires_at < $1")
+            .bind(now.to_rfc3339())
+            .execute(&self.pool)
+            .await?;
+
+        Ok(result.rows_affected())
+    }
+}
+
+#[cfg(feature = "postgres")]
+impl InventoryRepository for PostgresStore {
+    async fn list_inventory(&self) -> Result<Vec<GearInventoryItem>> {
+        let rows = sqlx::query(
+            r#"
+            SELECT sku, name, quantity, minimum_required
+            FROM inventory_items
+            ORDER BY sku
+            "#,
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        let items = rows
+            .into_iter()
+            .map(|row| GearInventoryItem {
+                sku: row.get("sku"),
+                name: row.get("name"),
+                quantity: row.get("quantity"),
+                minimum_required: row.get("minimum_required"),
+            })
+            .collect();
+
+        Ok(items)
+    }
+
+    async fn upsert_inventory_item(&self, item: GearInventoryItem) -> Result<()> {
+        sqlx::query(
+            r#"
+            INSERT INTO inventory_items (sku, name, quantity, minimum_required)
+            VALUES ($1, $2, $3, $4)
+            ON CONFLICT(sku) DO UPDATE SET
+              name=excluded.name,
+              quantity=excluded.quantity,
+              minimum_required=excluded.minimum_required
+            "#,
+        )
+        .bind(&item.sku)
+        .bind(&item.name)
+        .bind(item.quantity)
+        .bind(item.minimum_required)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+}
+
 #[derive(Clone)]
 pub enum Store {
     Memory(MemoryStore),
     Sqlite(SqliteStore),
+    #[cfg(feature = "postgres")]
+    Postgres(PostgresStore),
 }
 
 impl Store {
@@ -262,6 +437,33 @@ impl Store {
         let sqlite = SqliteStore::connect(database_url).await?;
         Ok(Self::Sqlite(sqlite))
     }
+
+    #[cfg(feature = "postgres")]
+    pub async fn postgres(database_url: &str) -> Result<Self> {
+        let postgres = PostgresStore::connect(database_url).await?;
+        Ok(Self::Postgres(postgres))
+    }
+
+    /// Picks the backend from the URL scheme: `postgres://` and `postgresql://` need the
+    /// `postgres` feature, anything else is treated as SQLite.
+    pub async fn connect(database_url: &str) -> Result<Self> {
+        if !is_postgres_url(database_url) {
+            return Self::sqlite(database_url).await;
+        }
+        #[cfg(feature = "postgres")]
+        {
+            Self::postgres(database_url).await
+        }
+        #[cfg(not(feature = "postgres"))]
+        {
+            anyhow::bail!("postgres database URLs require building with the `postgres` feature")
+        }
+    }
+}
+
+pub fn is_postgres_url(database_url: &str) -> bool {
+    let url = database_url.trim_start();
+    url.starts_with("postgres://") || url.starts_with("postgresql://")
 }
 
 impl SessionRepository for Store {
@@ -269,6 +471,8 @@ impl SessionRepository for Store {
         match self {
             Store::Memory(store) => store.load_session(session_id).await,
             Store::Sqlite(store) => store.load_session(session_id).await,
+            #[cfg(feature = "postgres")]
+            Store::Postgres(store) => store.load_session(session_id).await,
         }
     }
 
@@ -276,6 +480,8 @@ impl SessionRepository for Store {
         match self {
             Store::Memory(store) => store.upsert_session(session).await,
             Store::Sqlite(store) => store.upsert_session(session).await,
+            #[cfg(feature = "postgres")]
+            Store::Postgres(store) => store.upsert_session(session).await,
         }
     }
 
@@ -283,6 +489,8 @@ impl SessionRepository for Store {
         match self {
             Store::Memory(store) => store.purge_expired(now).await,
             Store::Sqlite(store) => store.purge_expired(now).await,
+            #[cfg(feature = "postgres")]
+            Store::Postgres(store) => store.purge_expired(now).await,
         }
     }
 }
@@ -292,6 +500,8 @@ impl InventoryRepository for Store {
         match self {
             Store::Memory(store) => store.list_inventory().await,
             Store::Sqlite(store) => store.list_inventory().await,
+            #[cfg(feature = "postgres")]
+            Store::Postgres(store) => store.list_inventory().await,
         }
     }
 
@@ -299,6 +509,8 @@ impl InventoryRepository for Store {
         match self {
             Store::Memory(store) => store.upsert_inventory_item(item).await,
             Store::Sqlite(store) => store.upsert_inventory_item(item).await,
+            #[cfg(feature = "postgres")]
+            Store::Postgres(store) => store.upsert_inventory_item(item).await,
         }
     }
 }
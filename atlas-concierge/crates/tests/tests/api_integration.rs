@@ -29,6 +29,42 @@ async fn health_is_public() {
         .unwrap();
 
     assert_eq!(response.status(), StatusCode::OK);
+    let body = to_bytes(response.into_body(), 1024 * 1024).await.unwrap();
+    let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    let ml = &json["capabilities"]["ml"];
+    assert_eq!(ml["embedder_loaded"], true);
+    assert_eq!(ml["vector_search"], true);
+    assert!(ml["retriever_docs"].as_u64().unwrap_or(0) > 0);
+    assert!(ml["intent_classifier"].is_string());
+}
+
+#[tokio::test]
+async fn responses_carry_an_exposed_server_time_header() {
+    let app = build_app(kb_root()).await.expect("app should build");
+    let before = chrono::Utc::now() - chrono::Duration::seconds(1);
+    let response = app
+        .oneshot(
+            Request::builder()
+                .uri("/health")
+                .header("origin", allowed_origin())
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    let server_time = response
+        .headers()
+        .get("x-server-time")
+        .and_then(|value| value.to_str().ok())
+        .expect("server time header");
+    let server_time = chrono::DateTime::parse_from_rfc3339(server_time).unwrap();
+    assert!(server_time >= before);
+    assert!(response
+        .headers()
+        .get("access-control-expose-headers")
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.contains("x-server-time")));
 }
 
 #[tokio::test]
@@ -118,12 +154,175 @@ async fn chat_returns_structured_payload() {
 
     let response = app.oneshot(request).await.unwrap();
     assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()["content-language"], "he");
 
     let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
     let parsed: serde_json::Value = serde_json::from_slice(&body).unwrap();
 
     assert!(parsed.get("reply_text").is_some());
     assert!(parsed.get("json_payload").is_some());
+    assert!(parsed["json_payload"]["spoken_summary"]
+        .as_str()
+        .is_some_and(|summary| !summary.is_empty()));
+    let reminder_actions = parsed
+        .get("suggested_actions")
+        .and_then(|value| value.as_array())
+        .map(|actions| {
+            actions
+                .iter()
+                .filter(|action| {
+                    action.get("action_type").and_then(|value| value.as_str())
+                        == Some("create_reminder")
+                })
+                .count()
+        })
+        .unwrap_or_default();
+    assert!(
+        reminder_actions <= 1,
+        "chat response should not repeat create_reminder actions"
+    );
+}
+
+#[tokio::test]
+async fn json_preferred_format_returns_an_object_payload() {
+    let app = build_app(kb_root()).await.expect("app should build");
+    let request = Request::builder()
+        .method("POST")
+        .uri("/v1/chat")
+        .header("content-type", "application/json")
+        .header("x-api-key", "dev-atlas-key")
+        .body(Body::from(
+            json!({
+                "text": "plan a beach weekend",
+                "preferred_format": "json"
+            })
+            .to_string(),
+        ))
+        .unwrap();
+    let response = app.oneshot(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    let parsed: serde_json::Value = serde_json::from_slice(&body).unwrap();
+
+    let formatted = &parsed["json_payload"]["formatted_response"];
+    assert!(formatted.is_object(), "expected an object, got {formatted}");
+    assert_eq!(formatted["mode"], "json");
+    assert!(formatted["response"].as_str().is_some());
+    assert_eq!(parsed["reply_text"], "[formatted_response]");
+}
+
+#[tokio::test]
+async fn chat_reply_is_capped_by_max_reply_chars() {
+    let app = build_app(kb_root()).await.expect("app should build");
+    let request = Request::builder()
+        .method("POST")
+        .uri("/v1/chat")
+        .header("content-type", "application/json")
+        .header("x-api-key", "dev-atlas-key")
+        .body(Body::from(
+            json!({
+                "text": "plan a beach weekend",
+                "max_reply_chars": 1
+            })
+            .to_string(),
+        ))
+        .unwrap();
+    let response = app.oneshot(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    let parsed: serde_json::Value = serde_json::from_slice(&body).unwrap();
+
+    let reply = parsed["reply_text"].as_str().expect("reply text");
+    assert!(
+        reply.chars().count() <= 81,
+        "reply was not clamped: {reply}"
+    );
+    assert!(reply.ends_with('…'));
+    assert_eq!(parsed["json_payload"]["reply_truncated"], true);
+    assert_eq!(parsed["json_payload"]["full_reply_available"], false);
+}
+
+#[tokio::test]
+async fn chat_validate_reports_the_interpreted_request() {
+    let app = build_app(kb_root()).await.expect("app should build");
+    let request = Request::builder()
+        .method("POST")
+        .uri("/v1/chat/validate")
+        .header("content-type", "application/json")
+        .header("x-api-key", "dev-atlas-key")
+        .body(Body::from(
+            json!({
+                "text": "  plan   a beach weekend ",
+                "locale": "he",
+                "preferred_format": "haiku",
+                "response_depth": "Quick",
+                "max_reply_chars": 1,
+                "reasoning_effort": "extreme"
+            })
+            .to_string(),
+        ))
+        .unwrap();
+    let response = app.oneshot(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    let parsed: serde_json::Value = serde_json::from_slice(&body).unwrap();
+
+    assert_eq!(parsed["valid"], false);
+    assert_eq!(parsed["errors"][0]["error"], "invalid_reasoning_effort");
+    let coerced: Vec<&str> = parsed["coerced_fields"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .filter_map(|entry| entry["field"].as_str())
+        .collect();
+    assert_eq!(coerced, ["preferred_format", "max_reply_chars"]);
+
+    let interpreted = &parsed["interpreted"];
+    assert_eq!(interpreted["locale"], "he");
+    assert_eq!(interpreted["text_chars"], 20);
+    assert_eq!(interpreted["max_reply_chars"], 80);
+    assert_eq!(interpreted["include_proactive"], true);
+    assert_eq!(
+        interpreted["studio_preferences"]["preferred_format"],
+        "structured_plan"
+    );
+    assert_eq!(interpreted["studio_preferences"]["response_depth"], "quick");
+}
+
+#[tokio::test]
+async fn large_responses_are_compressed_when_accepted() {
+    let app = build_app(kb_root()).await.expect("app should build");
+
+    let chat_request = Request::builder()
+        .method("POST")
+        .uri("/v1/chat")
+        .header("content-type", "application/json")
+        .header("x-api-key", "dev-atlas-key")
+        .header("accept-encoding", "gzip")
+        .body(Body::from(
+            json!({ "text": "Plan a two day desert route" }).to_string(),
+        ))
+        .unwrap();
+    let chat_response = app.clone().oneshot(chat_request).await.unwrap();
+    assert_eq!(chat_response.status(), StatusCode::OK);
+    assert_eq!(
+        chat_response
+            .headers()
+            .get("content-encoding")
+            .and_then(|value| value.to_str().ok()),
+        Some("gzip")
+    );
+    assert!(chat_response.headers().get("x-request-id").is_some());
+
+    let health_request = Request::builder()
+        .method("GET")
+        .uri("/health")
+        .header("accept-encoding", "gzip, br")
+        .body(Body::empty())
+        .unwrap();
+    let health_response = app.oneshot(health_request).await.unwrap();
+    assert_eq!(health_response.status(), StatusCode::OK);
+    assert!(health_response.headers().get("content-encoding").is_none());
 }
 
 #[tokio::test]
@@ -147,7 +346,7 @@ async fn legacy_social_login_is_retired() {
         parsed.get("error").and_then(|value| value.as_str()),
         Some("legacy_auth_retired")
     );
-    assert!(parsed
+    assert!(parsed["details"]
         .get("allowed_methods")
         .and_then(|value| value.as_array())
         .map(|value| !value.is_empty())
@@ -217,6 +416,37 @@ async fn auth_endpoints_are_rate_limited_under_abuse() {
     assert!(blocked, "auth abuse should eventually be rate limited");
 }
 
+#[tokio::test]
+async fn service_key_requests_skip_the_ip_rate_limiter() {
+    let app = build_app(kb_root()).await.expect("app should build");
+    let request = |with_key: bool| {
+        let mut builder = Request::builder()
+            .method("GET")
+            .uri("/v1/feedback/employee/product_team");
+        if with_key {
+            builder = builder.header("x-api-key", "dev-atlas-key");
+        } else {
+            builder = builder.header("origin", allowed_origin());
+        }
+        builder.body(Body::empty()).unwrap()
+    };
+
+    for _ in 0..120 {
+        let response = app.clone().oneshot(request(true)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    let mut blocked = false;
+    for _ in 0..120 {
+        let response = app.clone().oneshot(request(false)).await.unwrap();
+        if response.status() == StatusCode::TOO_MANY_REQUESTS {
+            blocked = true;
+            break;
+        }
+    }
+    assert!(blocked, "keyless requests should still be rate limited");
+}
+
 #[tokio::test]
 async fn survey_feed_and_actions_flow_in_guest_mode() {
     let app = build_app(kb_root()).await.expect("app should build");
@@ -229,6 +459,7 @@ async fn survey_feed_and_actions_flow_in_guest_mode() {
         .unwrap();
     let survey_next_response = app.clone().oneshot(survey_next_request).await.unwrap();
     assert_eq!(survey_next_response.status(), StatusCode::OK);
+    assert_eq!(survey_next_response.headers()["content-language"], "en");
 
     let survey_answer_request = Request::builder()
         .method("POST")
@@ -256,6 +487,8 @@ async fn survey_feed_and_actions_flow_in_guest_mode() {
         .unwrap();
     let feed_response = app.clone().oneshot(feed_request).await.unwrap();
     assert_eq!(feed_response.status(), StatusCode::OK);
+    assert_eq!(feed_response.headers()["content-language"], "en");
+    assert_eq!(feed_response.headers()["vary"], "accept-language");
 
     let reminder_request = Request::builder()
         .method("POST")
@@ -263,6 +496,7 @@ async fn survey_feed_and_actions_flow_in_guest_mode() {
         .header("content-type", "application/json")
         .header("x-api-key", "dev-atlas-key")
         .header("origin", allowed_origin())
+        .header("accept-language", "he-IL,en;q=0.8")
         .body(Body::from(
             json!({
                 "title": "Atlas reminder",
@@ -273,6 +507,17 @@ async fn survey_feed_and_actions_flow_in_guest_mode() {
         .unwrap();
     let reminder_response = app.clone().oneshot(reminder_request).await.unwrap();
     assert_eq!(reminder_response.status(), StatusCode::OK);
+    assert_eq!(reminder_response.headers()["content-language"], "he");
+    // The CORS layer adds its own `Vary` values; the locale one must survive alongside them.
+    let vary = reminder_response
+        .headers()
+        .get_all("vary")
+        .iter()
+        .flat_map(|value| value.to_str().unwrap().split(','))
+        .map(|value| value.trim().to_ascii_lowercase())
+        .collect::<Vec<_>>();
+    assert!(vary.contains(&"accept-language".to_string()));
+    assert!(vary.contains(&"origin".to_string()));
 
     let alarm_request = Request::builder()
         .method("POST")
@@ -293,6 +538,181 @@ async fn survey_feed_and_actions_flow_in_guest_mode() {
     assert_eq!(alarm_response.status(), StatusCode::OK);
 }
 
+#[tokio::test]
+async fn guests_get_isolated_cookie_backed_sessions() {
+    let app = build_app(kb_root()).await.expect("app should build");
+
+    let answer_request = Request::builder()
+        .method("POST")
+        .uri("/v1/survey/answer")
+        .header("content-type", "application/json")
+        .header("x-api-key", "dev-atlas-key")
+        .header("origin", allowed_origin())
+        .body(Body::from(
+            json!({
+                "question_id": "primary_goal",
+                "answer": "wealth",
+                "locale": "en"
+            })
+            .to_string(),
+        ))
+        .unwrap();
+    let answer_response = app.clone().oneshot(answer_request).await.unwrap();
+    assert_eq!(answer_response.status(), StatusCode::OK);
+    let guest_cookie = answer_response
+        .headers()
+        .get("set-cookie")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.split(';').next())
+        .expect("guest cookie should be issued")
+        .to_string();
+    assert!(guest_cookie.starts_with("atlas_guest=guest-"));
+
+    let returning_request = Request::builder()
+        .method("GET")
+        .uri("/v1/survey/next?locale=en")
+        .header("x-api-key", "dev-atlas-key")
+        .header("cookie", guest_cookie.as_str())
+        .body(Body::empty())
+        .unwrap();
+    let returning_response = app.clone().oneshot(returning_request).await.unwrap();
+    assert_eq!(returning_response.status(), StatusCode::OK);
+    assert!(returning_response.headers().get("set-cookie").is_none());
+    let returning_body = to_bytes(returning_response.into_body(), 1024 * 1024)
+        .await
+        .unwrap();
+    let returning_json: serde_json::Value = serde_json::from_slice(&returning_body).unwrap();
+    assert_eq!(returning_json["progress"]["answered"], 1);
+
+    let other_guest_request = Request::builder()
+        .method("GET")
+        .uri("/v1/survey/next?locale=en")
+        .header("x-api-key", "dev-atlas-key")
+        .body(Body::empty())
+        .unwrap();
+    let other_guest_response = app.oneshot(other_guest_request).await.unwrap();
+    assert_eq!(other_guest_response.status(), StatusCode::OK);
+    let other_cookie = other_guest_response
+        .headers()
+        .get("set-cookie")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.split(';').next())
+        .expect("new guest should receive its own cookie")
+        .to_string();
+    assert_ne!(other_cookie, guest_cookie);
+    let other_body = to_bytes(other_guest_response.into_body(), 1024 * 1024)
+        .await
+        .unwrap();
+    let other_json: serde_json::Value = serde_json::from_slice(&other_body).unwrap();
+    assert_eq!(other_json["progress"]["answered"], 0);
+}
+
+#[tokio::test]
+async fn request_body_limits_depend_on_route() {
+    let app = build_app(kb_root()).await.expect("app should build");
+    let padded_body = |size: usize| json!({ "padding": "x".repeat(size) }).to_string();
+
+    let auth_request = Request::builder()
+        .method("POST")
+        .uri("/v1/auth/passkey/login/start")
+        .header("content-type", "application/json")
+        .body(Body::from(padded_body(20 * 1024)))
+        .unwrap();
+    let auth_response = app.clone().oneshot(auth_request).await.unwrap();
+    assert_eq!(auth_response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+
+    let feedback_request = Request::builder()
+        .method("POST")
+        .uri("/v1/feedback/submit")
+        .header("content-type", "application/json")
+        .header("x-api-key", "dev-atlas-key")
+        .body(Body::from(padded_body(80 * 1024)))
+        .unwrap();
+    let feedback_response = app.clone().oneshot(feedback_request).await.unwrap();
+    assert_eq!(feedback_response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+
+    // Imports get the 1MB bulk limit. A signed-in import well past the 64KB default is covered by
+    // the lib test `memory_import_accepts_bodies_up_to_the_bulk_limit`.
+    let envelope = padded_body(0).len();
+    let oversized_import = padded_body(1024 * 1024 + 1 - envelope);
+    assert_eq!(oversized_import.len(), 1024 * 1024 + 1);
+    let oversized_request = Request::builder()
+        .method("POST")
+        .uri("/v1/memory/import")
+        .header("content-type", "application/json")
+        .header("x-api-key", "dev-atlas-key")
+        .body(Body::from(oversized_import))
+        .unwrap();
+    let oversized_response = app.oneshot(oversized_request).await.unwrap();
+    assert_eq!(oversized_response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+}
+
+#[tokio::test]
+async fn company_status_is_editable_with_service_key() {
+    let app = build_app(kb_root()).await.expect("app should build");
+    let status_body = |focus_items: usize| {
+        json!({
+            "phase": "Pilot operations",
+            "current_focus": vec!["Fleet onboarding"; focus_items],
+            "upcoming": ["Northern routes"],
+            "open_for_investment": false,
+            "message": "Pilot cohort is live."
+        })
+        .to_string()
+    };
+
+    let invalid_request = Request::builder()
+        .method("POST")
+        .uri("/v1/admin/company_status")
+        .header("content-type", "application/json")
+        .header("x-api-key", "dev-atlas-key")
+        .body(Body::from(status_body(13)))
+        .unwrap();
+    let invalid_response = app.clone().oneshot(invalid_request).await.unwrap();
+    assert_eq!(invalid_response.status(), StatusCode::BAD_REQUEST);
+
+    let update_request = Request::builder()
+        .method("POST")
+        .uri("/v1/admin/company_status")
+        .header("content-type", "application/json")
+        .header("x-api-key", "dev-atlas-key")
+        .body(Body::from(status_body(1)))
+        .unwrap();
+    let update_response = app.clone().oneshot(update_request).await.unwrap();
+    assert_eq!(update_response.status(), StatusCode::OK);
+
+    let status_request = Request::builder()
+        .method("GET")
+        .uri("/v1/company/status")
+        .header("x-api-key", "dev-atlas-key")
+        .body(Body::empty())
+        .unwrap();
+    let status_response = app.clone().oneshot(status_request).await.unwrap();
+    assert_eq!(status_response.status(), StatusCode::OK);
+    let etag = status_response
+        .headers()
+        .get("etag")
+        .and_then(|value| value.to_str().ok())
+        .expect("status should carry an etag")
+        .to_string();
+    let body = to_bytes(status_response.into_body(), 1024 * 1024)
+        .await
+        .unwrap();
+    let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(json["phase"], "Pilot operations");
+    assert_eq!(json["open_for_investment"], false);
+
+    let cached_request = Request::builder()
+        .method("GET")
+        .uri("/v1/company/status")
+        .header("x-api-key", "dev-atlas-key")
+        .header("if-none-match", etag.as_str())
+        .body(Body::empty())
+        .unwrap();
+    let cached_response = app.oneshot(cached_request).await.unwrap();
+    assert_eq!(cached_response.status(), StatusCode::NOT_MODIFIED);
+}
+
 #[tokio::test]
 async fn reminder_action_supports_each_app_path() {
     let app = build_app(kb_root()).await.expect("app should build");
@@ -332,6 +752,10 @@ async fn reminder_action_supports_each_app_path() {
         let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
         let parsed: serde_json::Value = serde_json::from_slice(&body).unwrap();
 
+        assert_eq!(
+            parsed["telemetry"]["scheduled_start_utc"],
+            "2026-03-01T08:30:00+00:00"
+        );
         assert_eq!(
             parsed.get("app").and_then(|value| value.as_str()),
             Some(reminders_app)
@@ -476,6 +900,85 @@ async fn alarm_action_supports_each_app_path() {
     }
 }
 
+#[tokio::test]
+async fn action_plan_returns_reminder_and_alarm_with_shared_trace() {
+    let app = build_app(kb_root()).await.expect("app should build");
+    let plan_request = |alarm_time: &str| {
+        Request::builder()
+            .method("POST")
+            .uri("/v1/actions/plan")
+            .header("content-type", "application/json")
+            .header("x-api-key", "dev-atlas-key")
+            .header("origin", allowed_origin())
+            .body(Body::from(
+                json!({
+                    "reminder": { "title": "Pack the van", "details": "water + gas" },
+                    "alarm": { "label": "Leave", "time_local": alarm_time, "days": ["Fri"] }
+                })
+                .to_string(),
+            ))
+            .unwrap()
+    };
+
+    let response = app.clone().oneshot(plan_request("06:45")).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let request_id = response
+        .headers()
+        .get("x-request-id")
+        .and_then(|value| value.to_str().ok())
+        .expect("request id header")
+        .to_string();
+    let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    let trace_id = json["trace_id"].as_str().expect("trace id");
+    assert_eq!(trace_id, request_id);
+    assert_eq!(json["reminder"]["telemetry"]["trace_id"], trace_id);
+    assert_eq!(json["alarm"]["telemetry"]["trace_id"], trace_id);
+    assert_eq!(json["reminder"]["telemetry"]["action"], "reminder");
+    assert_eq!(json["alarm"]["telemetry"]["action"], "alarm");
+
+    let mut invalid_request = plan_request("25:00");
+    invalid_request
+        .headers_mut()
+        .insert("x-request-id", "client-trace-0042".parse().unwrap());
+    let invalid_response = app.oneshot(invalid_request).await.unwrap();
+    assert_eq!(invalid_response.status(), StatusCode::BAD_REQUEST);
+    let invalid_body = to_bytes(invalid_response.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    let invalid_json: serde_json::Value = serde_json::from_slice(&invalid_body).unwrap();
+    assert_eq!(invalid_json["error"], "invalid_time");
+    assert_eq!(
+        invalid_json["details"]["telemetry"]["trace_id"],
+        "client-trace-0042"
+    );
+}
+
+#[tokio::test]
+async fn studio_preference_defaults_are_published() {
+    let app = build_app(kb_root()).await.expect("app should build");
+    let request = Request::builder()
+        .method("GET")
+        .uri("/v1/studio/preferences/defaults")
+        .header("x-api-key", "dev-atlas-key")
+        .body(Body::empty())
+        .unwrap();
+    let response = app.oneshot(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    let depth = json["fields"]
+        .as_array()
+        .and_then(|fields| {
+            fields
+                .iter()
+                .find(|field| field["field"] == "response_depth")
+        })
+        .expect("response_depth option");
+    assert_eq!(depth["default"], "deep");
+    assert_eq!(depth["allowed"], json!(["quick", "balanced", "deep"]));
+}
+
 #[tokio::test]
 async fn reminder_error_response_contains_failure_telemetry() {
     let app = build_app(kb_root()).await.expect("app should build");
@@ -499,14 +1002,14 @@ async fn reminder_error_response_contains_failure_telemetry() {
     let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
     let parsed: serde_json::Value = serde_json::from_slice(&body).unwrap();
     assert_eq!(
-        parsed
+        parsed["details"]
             .get("telemetry")
             .and_then(|value| value.get("success"))
             .and_then(|value| value.as_bool()),
         Some(false)
     );
     assert_eq!(
-        parsed
+        parsed["details"]
             .get("telemetry")
             .and_then(|value| value.get("action"))
             .and_then(|value| value.as_str()),
@@ -538,17 +1041,574 @@ async fn alarm_error_response_contains_failure_telemetry() {
     let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
     let parsed: serde_json::Value = serde_json::from_slice(&body).unwrap();
     assert_eq!(
-        parsed
+        parsed["details"]
             .get("telemetry")
             .and_then(|value| value.get("success"))
             .and_then(|value| value.as_bool()),
         Some(false)
     );
     assert_eq!(
-        parsed
+        parsed["details"]
             .get("telemetry")
             .and_then(|value| value.get("action"))
             .and_then(|value| value.as_str()),
         Some("alarm")
     );
 }
+
+#[tokio::test]
+async fn chat_memory_ingestion_is_throttled_per_user() {
+    let app = build_app(kb_root()).await.expect("app should build");
+    let mut last_throttled = None;
+
+    for _ in 0..31 {
+        let request = Request::builder()
+            .method("POST")
+            .uri("/v1/chat")
+            .header("content-type", "application/json")
+            .header("x-api-key", "dev-atlas-key")
+            .body(Body::from(
+                json!({
+                    "text": "Build a daily execution plan",
+                    "user_id": "throttle-test-user"
+                })
+                .to_string(),
+            ))
+            .unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let parsed: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        last_throttled = parsed
+            .get("json_payload")
+            .and_then(|value| value.get("chat_memory_ingest"))
+            .and_then(|value| value.get("throttled"))
+            .and_then(|value| value.as_bool());
+    }
+
+    assert_eq!(last_throttled, Some(true));
+}
+
+#[tokio::test]
+async fn employee_feedback_is_service_key_only_and_redacts_emails() {
+    let app = build_app(kb_root()).await.expect("app should build");
+
+    let submit = Request::builder()
+        .method("POST")
+        .uri("/v1/feedback/submit")
+        .header("content-type", "application/json")
+        .header("x-api-key", "dev-atlas-key")
+        .body(Body::from(
+            json!({
+                "category": "service",
+                "message": "Pickup was late, reach me at guest@example.com",
+                "target_employee": "night_shift"
+            })
+            .to_string(),
+        ))
+        .unwrap();
+    let submit_response = app.clone().oneshot(submit).await.unwrap();
+    assert_eq!(submit_response.status(), StatusCode::OK);
+
+    let anonymous = Request::builder()
+        .method("GET")
+        .uri("/v1/feedback/employee/night_shift")
+        .header("origin", allowed_origin())
+        .body(Body::empty())
+        .unwrap();
+    let anonymous_response = app.clone().oneshot(anonymous).await.unwrap();
+    assert_eq!(anonymous_response.status(), StatusCode::UNAUTHORIZED);
+
+    let request = Request::builder()
+        .method("GET")
+        .uri("/v1/feedback/employee/night_shift")
+        .header("x-api-key", "dev-atlas-key")
+        .body(Body::empty())
+        .unwrap();
+    let response = app.oneshot(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    let parsed: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(
+        parsed
+            .get("items")
+            .and_then(|value| value.get(0))
+            .and_then(|value| value.get("message"))
+            .and_then(|value| value.as_str()),
+        Some("Pickup was late, reach me at [redacted email]")
+    );
+}
+
+#[tokio::test]
+async fn feedback_can_be_resolved_and_reopened_with_a_service_key() {
+    let app = build_app(kb_root()).await.expect("app should build");
+
+    let submit = Request::builder()
+        .method("POST")
+        .uri("/v1/feedback/submit")
+        .header("content-type", "application/json")
+        .header("x-api-key", "dev-atlas-key")
+        .body(Body::from(
+            json!({
+                "category": "bug",
+                "message": "Reminder fired twice",
+                "target_employee": "sla_desk"
+            })
+            .to_string(),
+        ))
+        .unwrap();
+    let submit_response = app.clone().oneshot(submit).await.unwrap();
+    let body = to_bytes(submit_response.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    let parsed: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    let feedback_id = parsed["feedback"]["feedback_id"]
+        .as_str()
+        .expect("feedback id")
+        .to_string();
+
+    let set_status = |status: serde_json::Value| {
+        Request::builder()
+            .method("POST")
+            .uri(format!("/v1/feedback/{feedback_id}/status"))
+            .header("content-type", "application/json")
+            .header("x-api-key", "dev-atlas-key")
+            .body(Body::from(status.to_string()))
+            .unwrap()
+    };
+    let resolved = app
+        .clone()
+        .oneshot(set_status(
+            json!({ "status": "resolved", "resolved_by": "dana" }),
+        ))
+        .await
+        .unwrap();
+    assert_eq!(resolved.status(), StatusCode::OK);
+
+    let list = Request::builder()
+        .method("GET")
+        .uri("/v1/feedback/employee/sla_desk")
+        .header("x-api-key", "dev-atlas-key")
+        .body(Body::empty())
+        .unwrap();
+    let list_response = app.clone().oneshot(list).await.unwrap();
+    let body = to_bytes(list_response.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    let parsed: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(parsed["items"][0]["status"], "resolved");
+    assert_eq!(parsed["items"][0]["resolved_by"], "dana");
+    assert!(parsed["items"][0]["resolved_at"].is_string());
+
+    let reopened = app
+        .clone()
+        .oneshot(set_status(json!({ "status": "triaged" })))
+        .await
+        .unwrap();
+    let body = to_bytes(reopened.into_body(), usize::MAX).await.unwrap();
+    let parsed: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(parsed["feedback"]["status"], "triaged");
+    assert!(parsed["feedback"]["resolved_at"].is_null());
+
+    let missing = app
+        .oneshot(
+            Request::builder()
+                .method("POST")
+                .uri("/v1/feedback/no-such-id/status")
+                .header("content-type", "application/json")
+                .header("x-api-key", "dev-atlas-key")
+                .body(Body::from(json!({ "status": "new" }).to_string()))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(missing.status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn resolve_all_closes_matching_open_feedback_for_an_employee() {
+    let app = build_app(kb_root()).await.expect("app should build");
+    for (category, message) in [
+        ("bug", "Map tiles missing"),
+        ("bug", "Map tiles blurry"),
+        ("ux", "Button too small"),
+    ] {
+        let submit = Request::builder()
+            .method("POST")
+            .uri("/v1/feedback/submit")
+            .header("content-type", "application/json")
+            .header("x-api-key", "dev-atlas-key")
+            .body(Body::from(
+                json!({
+                    "category": category,
+                    "message": message,
+                    "target_employee": "maps_team"
+                })
+                .to_string(),
+            ))
+            .unwrap();
+        let response = app.clone().oneshot(submit).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    let resolve_all = |body: serde_json::Value, key: Option<&str>| {
+        let mut builder = Request::builder()
+            .method("POST")
+            .uri("/v1/feedback/employee/maps_team/resolve_all")
+            .header("content-type", "application/json")
+            .header("origin", allowed_origin());
+        if let Some(key) = key {
+            builder = builder.header("x-api-key", key);
+        }
+        builder.body(Body::from(body.to_string())).unwrap()
+    };
+    let anonymous = app
+        .clone()
+        .oneshot(resolve_all(json!({ "resolved_by": "ops" }), None))
+        .await
+        .unwrap();
+    assert_ne!(anonymous.status(), StatusCode::OK);
+
+    let response = app
+        .clone()
+        .oneshot(resolve_all(
+            json!({ "resolved_by": "ops", "note": "Tile CDN fixed", "category": "bug" }),
+            Some("dev-atlas-key"),
+        ))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    let parsed: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(parsed["resolved"], 2);
+
+    let list = Request::builder()
+        .method("GET")
+        .uri("/v1/feedback/employee/maps_team")
+        .header("x-api-key", "dev-atlas-key")
+        .body(Body::empty())
+        .unwrap();
+    let list_response = app.oneshot(list).await.unwrap();
+    let body = to_bytes(list_response.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    let parsed: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    let items = parsed["items"].as_array().expect("items");
+    for item in items {
+        if item["category"] == "bug" {
+            assert_eq!(item["status"], "resolved");
+            assert_eq!(item["resolution_note"], "Tile CDN fixed");
+        } else {
+            assert_eq!(item["status"], "new");
+        }
+    }
+}
+
+#[tokio::test]
+async fn expired_memory_prune_is_service_key_only() {
+    let app = build_app(kb_root()).await.expect("app should build");
+    let prune = |key: Option<&str>| {
+        let mut builder = Request::builder()
+            .method("POST")
+            .uri("/v1/admin/memory/prune_expired")
+            .header("origin", allowed_origin());
+        if let Some(key) = key {
+            builder = builder.header("x-api-key", key);
+        }
+        builder.body(Body::empty()).unwrap()
+    };
+
+    let anonymous = app.clone().oneshot(prune(None)).await.unwrap();
+    assert_ne!(anonymous.status(), StatusCode::OK);
+
+    let response = app.oneshot(prune(Some("dev-atlas-key"))).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    let parsed: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(parsed["ok"], true);
+    assert!(parsed["per_user"].is_array());
+}
+
+#[tokio::test]
+async fn execution_candidates_expose_pre_selection_tasks_to_service_keys() {
+    let app = build_app(kb_root()).await.expect("app should build");
+    let candidates = |query: &str| {
+        Request::builder()
+            .method("GET")
+            .uri(format!("/v1/admin/execution/candidates?{query}"))
+            .header("x-api-key", "dev-atlas-key")
+            .body(Body::empty())
+            .unwrap()
+    };
+
+    let response = app
+        .clone()
+        .oneshot(candidates("user_id=candidates-user"))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    let company = json["candidates"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|entry| entry["candidate"]["task_id"] == "company-awareness")
+        .expect("company awareness candidate");
+    assert_eq!(company["candidate"]["source"], "company");
+    assert_eq!(company["candidate"]["horizon"], "mid_term");
+    assert!(company["priority_score"].as_f64().unwrap() > 0.0);
+    assert!(company["rank"].is_u64());
+
+    let filtered = app
+        .clone()
+        .oneshot(candidates(
+            "user_id=candidates-user&source=company&horizon=daily",
+        ))
+        .await
+        .unwrap();
+    let body = to_bytes(filtered.into_body(), usize::MAX).await.unwrap();
+    let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(json["total"], 0);
+
+    let paged = app
+        .oneshot(candidates("user_id=candidates-user&offset=50&limit=500"))
+        .await
+        .unwrap();
+    let body = to_bytes(paged.into_body(), usize::MAX).await.unwrap();
+    let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(json["limit"], 200);
+    assert!(json["candidates"].as_array().unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn execution_digest_returns_weekly_summary_in_guest_mode() {
+    let app = build_app(kb_root()).await.expect("app should build");
+    let request = Request::builder()
+        .method("GET")
+        .uri("/v1/execution/digest?locale=en")
+        .header("x-api-key", "dev-atlas-key")
+        .body(Body::empty())
+        .unwrap();
+
+    let response = app.oneshot(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()["content-language"], "en");
+    let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    let parsed: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(
+        parsed.get("checkin_count").and_then(|value| value.as_u64()),
+        Some(0)
+    );
+    assert_eq!(
+        parsed
+            .get("energy")
+            .and_then(|value| value.get("trend"))
+            .and_then(|value| value.as_str()),
+        Some("insufficient_data")
+    );
+    assert!(parsed
+        .get("top_tasks")
+        .is_some_and(|value| value.is_array()));
+    assert!(parsed.get("narrative").is_some_and(|value| value.is_null()));
+}
+
+#[tokio::test]
+async fn account_linking_requires_a_signed_in_session() {
+    let app = build_app(kb_root()).await.expect("app should build");
+    let request = Request::builder()
+        .method("POST")
+        .uri("/v1/auth/link/google/start")
+        .header("x-api-key", "dev-atlas-key")
+        .header("origin", allowed_origin())
+        .body(Body::empty())
+        .unwrap();
+
+    let response = app.oneshot(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    let parsed: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(
+        parsed.get("error").and_then(|value| value.as_str()),
+        Some("not_authenticated")
+    );
+}
+
+#[tokio::test]
+async fn delete_method_aliases_are_routed_and_require_a_session() {
+    let app = build_app(kb_root()).await.expect("app should build");
+    for uri in [
+        "/v1/notes/note-1",
+        "/v1/memory/memory-1",
+        "/v1/memory/memory-1?user_id=user-1",
+    ] {
+        let request = Request::builder()
+            .method("DELETE")
+            .uri(uri)
+            .header("x-api-key", "dev-atlas-key")
+            .header("origin", allowed_origin())
+            .body(Body::empty())
+            .unwrap();
+
+        let response = app.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED, "{uri}");
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let parsed: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            parsed.get("error").and_then(|value| value.as_str()),
+            Some("not_authenticated"),
+            "{uri}"
+        );
+    }
+}
+
+#[tokio::test]
+async fn recovery_codes_require_a_session_and_reject_unknown_codes() {
+    let app = build_app(kb_root()).await.expect("app should build");
+
+    let generate = Request::builder()
+        .method("POST")
+        .uri("/v1/auth/recovery_codes/generate")
+        .header("x-api-key", "dev-atlas-key")
+        .header("origin", allowed_origin())
+        .body(Body::empty())
+        .unwrap();
+    let generate_response = app.clone().oneshot(generate).await.unwrap();
+    assert_eq!(generate_response.status(), StatusCode::UNAUTHORIZED);
+
+    let redeem = Request::builder()
+        .method("POST")
+        .uri("/v1/auth/recovery_codes/redeem")
+        .header("content-type", "application/json")
+        .header("origin", allowed_origin())
+        .body(Body::from(
+            json!({
+                "email": "nobody@example.com",
+                "code": "ABCDE-FGHJK"
+            })
+            .to_string(),
+        ))
+        .unwrap();
+    let redeem_response = app.oneshot(redeem).await.unwrap();
+    assert_eq!(redeem_response.status(), StatusCode::UNAUTHORIZED);
+    let body = to_bytes(redeem_response.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    let parsed: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(
+        parsed.get("error").and_then(|value| value.as_str()),
+        Some("invalid_recovery_code")
+    );
+}
+
+#[tokio::test]
+async fn passkey_login_start_does_not_reveal_unknown_emails() {
+    let app = build_app(kb_root()).await.expect("app should build");
+    let start = |email: &str| {
+        Request::builder()
+            .method("POST")
+            .uri("/v1/auth/passkey/login/start")
+            .header("content-type", "application/json")
+            .body(Body::from(json!({ "email": email }).to_string()))
+            .unwrap()
+    };
+
+    // With no passkeys registered anywhere every email gets the same answer; the decoy shape
+    // once passkeys exist is covered by the api crate's unit tests.
+    let mut answers = Vec::new();
+    for email in ["never-registered@example.com", "someone-else@example.com"] {
+        let response = app.clone().oneshot(start(email)).await.unwrap();
+        let status = response.status();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let parsed: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        answers.push((status, parsed["error"].clone(), parsed["message"].clone()));
+    }
+    assert_eq!(answers[0], answers[1]);
+    assert_eq!(answers[0].0, StatusCode::BAD_REQUEST);
+    assert_eq!(answers[0].1, "no_passkeys_registered");
+}
+
+#[tokio::test]
+async fn openapi_spec_is_public_and_matches_the_router() {
+    let app = build_app(kb_root()).await.expect("app should build");
+
+    let response = app
+        .clone()
+        .oneshot(
+            Request::builder()
+                .uri("/openapi.json")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    let spec: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(spec["openapi"], "3.0.3");
+
+    // Every documented path/method must be routed; a typo or a removed route shows up as 404/405.
+    let paths = spec["paths"].as_object().unwrap();
+    assert!(paths.contains_key("/v1/chat"));
+    for (path, operations) in paths {
+        for method in operations.as_object().unwrap().keys() {
+            let response = app
+                .clone()
+                .oneshot(
+                    Request::builder()
+                        .method(method.to_uppercase().as_str())
+                        .uri(path.as_str())
+                        .header("x-api-key", "dev-atlas-key")
+                        .header("content-type", "application/json")
+                        .body(Body::from("{}"))
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            assert_ne!(response.status(), StatusCode::NOT_FOUND, "{method} {path}");
+            assert_ne!(
+                response.status(),
+                StatusCode::METHOD_NOT_ALLOWED,
+                "{method} {path}"
+            );
+        }
+    }
+}
+
+#[tokio::test]
+async fn reminder_snooze_recomputes_the_due_time() {
+    let app = build_app(kb_root()).await.expect("app should build");
+    let snooze = |option: &str| {
+        Request::builder()
+            .method("POST")
+            .uri("/v1/actions/reminder/snooze")
+            .header("content-type", "application/json")
+            .header("x-api-key", "dev-atlas-key")
+            .body(Body::from(
+                json!({
+                    "reminder": { "title": "Call the venue", "reminders_app": "google_calendar" },
+                    "snooze": option,
+                    "utc_offset_minutes": 120
+                })
+                .to_string(),
+            ))
+            .unwrap()
+    };
+
+    let response = app.clone().oneshot(snooze("plus_1h")).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    let parsed: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    let scheduled = parsed["telemetry"]["scheduled_start_utc"]
+        .as_str()
+        .and_then(|value| chrono::DateTime::parse_from_rfc3339(value).ok())
+        .expect("scheduled start");
+    let minutes_ahead = (scheduled.with_timezone(&chrono::Utc) - chrono::Utc::now()).num_minutes();
+    assert!((58..=60).contains(&minutes_ahead), "{minutes_ahead}");
+
+    let response = app.oneshot(snooze("someday")).await.unwrap();
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    let parsed: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(parsed["error"], "invalid_snooze_option");
+}
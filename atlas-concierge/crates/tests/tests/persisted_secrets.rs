@@ -0,0 +1,111 @@
+// Lives in its own test binary because it configures the API through process-wide env vars.
+use std::path::PathBuf;
+
+use atlas_api::build_app;
+use axum::body::{to_bytes, Body};
+use axum::http::{Request, StatusCode};
+use axum::Router;
+use tower::ServiceExt;
+
+fn kb_root() -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("../../kb")
+}
+
+fn passkey_json() -> String {
+    serde_json::json!({
+        "passkey_id": "p1",
+        "user_id": "u1",
+        "credential": { "cred": {
+            "cred_id": "BwcHBwcHBwcHBwcHBwcHBwcHBwcHBwcHBwcHBwcHBwc",
+            "cred": {
+                "type_": "ES256",
+                "key": { "EC_EC2": {
+                    "curve": "SECP256R1",
+                    "x": "AQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQE",
+                    "y": "AgICAgICAgICAgICAgICAgICAgICAgICAgICAgICAgI"
+                } }
+            },
+            "counter": 0,
+            "transports": null,
+            "user_verified": true,
+            "backup_eligible": false,
+            "backup_state": false,
+            "registration_policy": "required",
+            "extensions": {
+                "cred_protect": "Ignored",
+                "hmac_create_secret": "NotRequested",
+                "appid": "NotRequested",
+                "cred_props": "Ignored"
+            },
+            "attestation": { "data": "None", "metadata": "None" },
+            "attestation_format": "none"
+        } },
+        "created_at": "2026-01-01T00:00:00+00:00",
+        "last_used_at": null
+    })
+    .to_string()
+}
+
+async fn decoy_credential_ids(app: &Router, email: &str) -> serde_json::Value {
+    let response = app
+        .clone()
+        .oneshot(
+            Request::builder()
+                .method("POST")
+                .uri("/v1/auth/passkey/login/start")
+                .header("x-api-key", "dev-atlas-key")
+                .header("origin", "http://localhost:5500")
+                .header("content-type", "application/json")
+                .body(Body::from(
+                    serde_json::json!({ "email": email }).to_string(),
+                ))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    let status = response.status();
+    let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    assert_eq!(status, StatusCode::OK, "{}", String::from_utf8_lossy(&body));
+    let value: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    value["options"]["publicKey"]["allowCredentials"].clone()
+}
+
+#[tokio::test]
+async fn decoy_passkey_ids_survive_a_restart_on_the_same_store() {
+    let db_path = std::env::temp_dir().join(format!("atlas-secrets-{}.db", uuid::Uuid::new_v4()));
+    let database_url = format!("sqlite://{}?mode=rwc", db_path.display());
+    std::env::set_var("ATLAS_DATABASE_URL", database_url.as_str());
+    std::env::remove_var("ATLAS_PASSKEY_DECOY_SECRET");
+
+    // Creates the schema and the stored secrets.
+    let _ = build_app(kb_root())
+        .await
+        .expect("initial instance should build");
+    // Decoys are only handed out once some account has a passkey to model them on.
+    let pool = sqlx::SqlitePool::connect(database_url.as_str())
+        .await
+        .unwrap();
+    sqlx::query("INSERT INTO passkeys (passkey_id, user_id, data_json) VALUES ('p1', 'u1', ?1)")
+        .bind(passkey_json())
+        .execute(&pool)
+        .await
+        .unwrap();
+    pool.close().await;
+
+    let first = build_app(kb_root())
+        .await
+        .expect("first instance should build");
+    let before = decoy_credential_ids(&first, "nobody@example.com").await;
+    assert!(before.as_array().is_some_and(|ids| !ids.is_empty()));
+    drop(first);
+
+    let restarted = build_app(kb_root())
+        .await
+        .expect("restarted instance should build");
+    assert_eq!(
+        decoy_credential_ids(&restarted, "nobody@example.com").await,
+        before
+    );
+
+    let _ = std::fs::remove_file(&db_path);
+}
@@ -0,0 +1,85 @@
+// Lives in its own test binary because it configures the API through process-wide env vars.
+use std::path::PathBuf;
+
+use atlas_api::build_app;
+use axum::body::Body;
+use axum::http::{Request, StatusCode};
+use axum::Router;
+use tower::ServiceExt;
+
+fn kb_root() -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("../../kb")
+}
+
+async fn auth_me_status(app: &Router, session_id: &str) -> StatusCode {
+    app.clone()
+        .oneshot(
+            Request::builder()
+                .uri("/v1/auth/me")
+                .header("x-api-key", "dev-atlas-key")
+                .header("cookie", format!("atlas_session={session_id}"))
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap()
+        .status()
+}
+
+#[tokio::test]
+async fn sessions_are_shared_between_instances() {
+    let db_path = std::env::temp_dir().join(format!("atlas-shared-{}.db", uuid::Uuid::new_v4()));
+    let database_url = format!("sqlite://{}?mode=rwc", db_path.display());
+    std::env::set_var("ATLAS_DATABASE_URL", database_url.as_str());
+    std::env::set_var("ATLAS_SHARED_AUTH_STATE", "1");
+
+    let first = build_app(kb_root())
+        .await
+        .expect("first instance should build");
+    let second = build_app(kb_root())
+        .await
+        .expect("second instance should build");
+
+    // Simulates a sign-in handled by a third instance after both of these started.
+    let pool = sqlx::SqlitePool::connect(database_url.as_str())
+        .await
+        .unwrap();
+    sqlx::query(
+        "INSERT INTO auth_users (user_id, provider, email, name, locale, memory_opt_in, created_at, updated_at) VALUES ('u1', 'google', 'dana@example.com', 'Dana', 'en', 1, '2026-01-01T00:00:00+00:00', '2026-01-01T00:00:00+00:00')",
+    )
+    .execute(&pool)
+    .await
+    .unwrap();
+    sqlx::query(
+        "INSERT INTO auth_sessions (session_id, user_id, expires_at, created_at) VALUES ('s1', 'u1', '2999-01-01T00:00:00+00:00', '2026-01-01T00:00:00+00:00')",
+    )
+    .execute(&pool)
+    .await
+    .unwrap();
+
+    assert_eq!(auth_me_status(&first, "s1").await, StatusCode::OK);
+    assert_eq!(auth_me_status(&second, "s1").await, StatusCode::OK);
+
+    let logout = first
+        .clone()
+        .oneshot(
+            Request::builder()
+                .method("POST")
+                .uri("/v1/auth/logout")
+                .header("x-api-key", "dev-atlas-key")
+                .header("origin", "http://localhost:5500")
+                .header("cookie", "atlas_session=s1")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(logout.status(), StatusCode::OK);
+    assert_eq!(
+        auth_me_status(&second, "s1").await,
+        StatusCode::UNAUTHORIZED
+    );
+
+    pool.close().await;
+    let _ = std::fs::remove_file(&db_path);
+}